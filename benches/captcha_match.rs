@@ -0,0 +1,19 @@
+// The captcha solver's template matching, with its ink-ratio prefilter and
+// early exit, against the exhaustive comparison it must agree with.
+use bhcli::lechatphp::captcha::bench;
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::hint::black_box;
+
+fn template_match(c: &mut Criterion) {
+    let fixture = bench::fixture(64);
+    // Faster only counts if the answers are the same
+    assert!(bench::same_results(&fixture), "pruned matching differs from the exhaustive one");
+
+    let mut group = c.benchmark_group("template_match");
+    group.bench_function("exhaustive", |b| b.iter(|| bench::exhaustive(black_box(&fixture))));
+    group.bench_function("pruned", |b| b.iter(|| bench::pruned(black_box(&fixture))));
+    group.finish();
+}
+
+criterion_group!(benches, template_match);
+criterion_main!(benches);
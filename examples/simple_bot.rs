@@ -0,0 +1,22 @@
+//! Log in, post one message, log out.
+//!
+//!     cargo run --example simple_bot -- http://<chat>.onion/chat index.php nick password "hello"
+//!
+//! Goes through the local Tor daemon. The captcha is left to the solver,
+//! the login fails when it can't read it, see `LeChatClient::set_prompt`.
+use bhcli::lechatphp::chat::{Credentials, LeChatClient, LoginOptions};
+use bhcli::lechatphp::client::ClientConfig;
+use std::env;
+
+fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let [base_url, page_php, nick, password, message] = args.as_slice() else {
+        anyhow::bail!("usage: simple_bot <base url> <page.php> <nick> <password> <message>");
+    };
+
+    let config = ClientConfig::builder().target_url(Some(base_url.to_owned())).build()?;
+    let mut chat = LeChatClient::new(config, base_url, page_php)?;
+    chat.login(&Credentials::new(nick, password), &LoginOptions::default())?;
+    chat.post_message(message.as_str())?;
+    Ok(chat.logout()?)
+}
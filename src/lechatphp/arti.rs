@@ -0,0 +1,201 @@
+use super::error_kind::{Classify, ErrorKind};
+use super::onion_auth::ClientAuthKey;
+use arti_client::{HsClientDescEncKey, HsId, IsolationToken, KeystoreSelector, StreamPrefs, TorClient, TorClientConfig};
+use futures::StreamExt;
+use std::str::FromStr;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tor_hscrypto::pk::HsClientDescEncSecretKey;
+use tor_llcrypto::pk::curve25519::StaticSecret;
+use tor_rtcompat::PreferredRuntime;
+use thiserror::Error;
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const USER_PASS_AUTH: u8 = 2;
+const CMD_CONNECT: u8 = 1;
+const REPLY_OK: u8 = 0;
+const REPLY_FAILURE: u8 = 1;
+const REPLY_CMD_UNSUPPORTED: u8 = 7;
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ArtiErr {
+    #[error("embedded tor: {0}")]
+    Io(#[from] io::Error),
+    #[error("embedded tor: {0}")]
+    Tor(#[from] arti_client::Error),
+    #[error("embedded tor: onion client auth key: {0}")]
+    ClientAuth(String),
+}
+
+impl Classify for ArtiErr {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            ArtiErr::Tor(_) => ErrorKind::Transient,
+            ArtiErr::ClientAuth(_) => ErrorKind::InvalidInput,
+            ArtiErr::Io(_) => ErrorKind::Fatal,
+        }
+    }
+}
+
+/// An embedded Tor client reachable through a local SOCKS5 listener, so the
+/// regular reqwest client can use it exactly like an external tor daemon.
+/// Tor stops when this is dropped.
+pub struct EmbeddedTor {
+    socks_url: String,
+    _runtime: tokio::runtime::Runtime,
+}
+
+impl EmbeddedTor {
+    pub fn socks_url(&self) -> &str {
+        &self.socks_url
+    }
+}
+
+/// Bootstrap the embedded Tor client, reporting progress in percent.
+/// `client_auth` keys are stored in arti's keystore for restricted onions.
+pub fn start<F>(progress: F, client_auth: &[ClientAuthKey]) -> Result<EmbeddedTor, ArtiErr>
+where
+    F: Fn(u8) + Send + 'static,
+{
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+    let socks_url = runtime.block_on(async {
+        let tor_client = TorClient::builder()
+            .config(TorClientConfig::default())
+            .create_unbootstrapped()?;
+        for key in client_auth {
+            add_client_auth(&tor_client, key)?;
+        }
+
+        let mut events = tor_client.bootstrap_events();
+        tokio::spawn(async move {
+            while let Some(status) = events.next().await {
+                progress((status.as_frac() * 100.0) as u8);
+                if status.ready_for_traffic() {
+                    break;
+                }
+            }
+        });
+        tor_client.bootstrap().await?;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let socks_url = format!("socks5h://{}", listener.local_addr()?);
+        let isolation = Arc::new(Mutex::new(HashMap::new()));
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let tor_client = tor_client.clone();
+                let isolation = Arc::clone(&isolation);
+                tokio::spawn(async move {
+                    if let Err(e) = serve_socks(stream, tor_client, isolation).await {
+                        log::error!("embedded tor socks: {}", e);
+                    }
+                });
+            }
+        });
+        Ok::<String, ArtiErr>(socks_url)
+    })?;
+    Ok(EmbeddedTor { socks_url, _runtime: runtime })
+}
+
+// Like the control port ONION_CLIENT_AUTH_ADD, replacing a different key.
+fn add_client_auth(tor_client: &TorClient<PreferredRuntime>, key: &ClientAuthKey) -> Result<(), ArtiErr> {
+    let hsid = HsId::from_str(&format!("{}.onion", key.onion())).map_err(|e| ArtiErr::ClientAuth(e.to_string()))?;
+    let secret = HsClientDescEncSecretKey::from(StaticSecret::from(key.secret()));
+    let public = HsClientDescEncKey::from(&secret);
+    match tor_client.get_service_discovery_key(hsid)? {
+        Some(stored) if stored == public => return Ok(()),
+        Some(_) => {
+            tor_client.remove_service_discovery_key(KeystoreSelector::Primary, hsid)?;
+        }
+        None => {}
+    }
+    tor_client.insert_service_discovery_key(KeystoreSelector::Primary, hsid, secret)?;
+    Ok(())
+}
+
+// Streams sharing SOCKS credentials share an isolation token, like tor's
+// IsolateSOCKSAuth.
+type IsolationMap = Arc<Mutex<HashMap<(String, String), IsolationToken>>>;
+
+// Minimal SOCKS5 server: CONNECT only, no auth or username/password auth.
+async fn serve_socks(
+    mut stream: TcpStream,
+    tor_client: Arc<TorClient<PreferredRuntime>>,
+    isolation: IsolationMap,
+) -> Result<(), ArtiErr> {
+    let mut header = [0u8; 2];
+    stream.read_exact(&mut header).await?;
+    let mut methods = vec![0u8; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+    let mut prefs = StreamPrefs::new();
+    if methods.contains(&USER_PASS_AUTH) {
+        stream.write_all(&[SOCKS_VERSION, USER_PASS_AUTH]).await?;
+        let credentials = read_credentials(&mut stream).await?;
+        let token = *isolation.lock().unwrap().entry(credentials).or_insert_with(IsolationToken::new);
+        prefs.set_isolation(token);
+        stream.write_all(&[1, REPLY_OK]).await?;
+    } else {
+        stream.write_all(&[SOCKS_VERSION, NO_AUTH]).await?;
+    }
+
+    let mut request = [0u8; 4];
+    stream.read_exact(&mut request).await?;
+    let host = match request[3] {
+        1 => {
+            let mut ip = [0u8; 4];
+            stream.read_exact(&mut ip).await?;
+            std::net::Ipv4Addr::from(ip).to_string()
+        }
+        3 => {
+            let len = stream.read_u8().await? as usize;
+            let mut name = vec![0u8; len];
+            stream.read_exact(&mut name).await?;
+            String::from_utf8_lossy(&name).into_owned()
+        }
+        4 => {
+            let mut ip = [0u8; 16];
+            stream.read_exact(&mut ip).await?;
+            std::net::Ipv6Addr::from(ip).to_string()
+        }
+        _ => return reply(&mut stream, REPLY_CMD_UNSUPPORTED).await,
+    };
+    let port = stream.read_u16().await?;
+    if request[1] != CMD_CONNECT {
+        return reply(&mut stream, REPLY_CMD_UNSUPPORTED).await;
+    }
+
+    let mut tor_stream = match tor_client.connect_with_prefs((host.as_str(), port), &prefs).await {
+        Ok(s) => s,
+        Err(e) => {
+            reply(&mut stream, REPLY_FAILURE).await?;
+            return Err(e.into());
+        }
+    };
+    reply(&mut stream, REPLY_OK).await?;
+    tokio::io::copy_bidirectional(&mut stream, &mut tor_stream).await?;
+    Ok(())
+}
+
+// RFC 1929 username/password sub-negotiation.
+async fn read_credentials(stream: &mut TcpStream) -> Result<(String, String), ArtiErr> {
+    let _version = stream.read_u8().await?;
+    let len = stream.read_u8().await? as usize;
+    let mut username = vec![0u8; len];
+    stream.read_exact(&mut username).await?;
+    let len = stream.read_u8().await? as usize;
+    let mut password = vec![0u8; len];
+    stream.read_exact(&mut password).await?;
+    Ok((
+        String::from_utf8_lossy(&username).into_owned(),
+        String::from_utf8_lossy(&password).into_owned(),
+    ))
+}
+
+async fn reply(stream: &mut TcpStream, code: u8) -> Result<(), ArtiErr> {
+    stream.write_all(&[SOCKS_VERSION, code, 0, 1, 0, 0, 0, 0, 0, 0]).await?;
+    Ok(())
+}
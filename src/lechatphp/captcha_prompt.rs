@@ -0,0 +1,96 @@
+// The captcha as the login page has it, and who to ask for the answers the
+// solver doesn't give. Built with or without the `solver` feature.
+use super::settings::Settings;
+use std::io;
+
+pub(crate) type Prompt = dyn Fn(&CaptchaImage) -> io::Result<String> + Send + Sync;
+
+/// Largest captcha image taken once decoded, well under the page's own
+/// limit. Real ones are a few KB.
+pub const MAX_IMAGE_SIZE: usize = 256 * 1024;
+
+/// Whether the base64 image would be over `MAX_IMAGE_SIZE` decoded.
+pub fn image_too_large(base64_str: &str) -> bool {
+    base64_str.len() / 4 * 3 > MAX_IMAGE_SIZE
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Png,
+    Gif,
+}
+
+/// A captcha image as sent, not decoded. Save `bytes` as they are to show
+/// it, `decode` it for the pixels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptchaImage {
+    pub format: ImageFormat,
+    pub bytes: Vec<u8>,
+}
+
+impl CaptchaImage {
+    pub fn new(format: ImageFormat, bytes: Vec<u8>) -> Self {
+        Self { format, bytes }
+    }
+
+    /// Width and height from the header, `None` when the bytes aren't the
+    /// start of an image of their format.
+    pub fn dimensions(&self) -> Option<(u32, u32)> {
+        let b = &self.bytes;
+        match self.format {
+            ImageFormat::Png if b.len() >= 24 && b.starts_with(b"\x89PNG\r\n\x1a\n") && &b[12..16] == b"IHDR" => {
+                let be = |i: usize| u32::from_be_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+                Some((be(16), be(20)))
+            }
+            ImageFormat::Gif if b.len() >= 10 && (b.starts_with(b"GIF87a") || b.starts_with(b"GIF89a")) => {
+                let le = |i: usize| u16::from_le_bytes([b[i], b[i + 1]]) as u32;
+                Some((le(6), le(8)))
+            }
+            _ => None,
+        }
+    }
+
+    /// The file extension of its format, to save it.
+    pub fn extension(&self) -> &'static str {
+        match self.format {
+            ImageFormat::Png => "png",
+            ImageFormat::Gif => "gif",
+        }
+    }
+
+    #[cfg(feature = "solver")]
+    pub fn decode(&self) -> image::ImageResult<image::DynamicImage> {
+        image::load_from_memory(&self.bytes)
+    }
+}
+
+/// The answer for `img`, see `Transport::set_prompt`.
+pub(super) fn prompt(settings: &Settings, img: &CaptchaImage) -> io::Result<String> {
+    match settings.prompt.get() {
+        Some(prompt) => prompt(img),
+        None => Err(io::Error::new(io::ErrorKind::Unsupported, "no captcha prompt set")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose;
+    use base64::Engine;
+
+    #[test]
+    fn dimensions_test() {
+        // The login fixture's 60x24 PNG, and GIF headers of both versions
+        let page = include_str!("fixtures/login.html");
+        let b64 = page.split("data:image/png;base64,").nth(1).unwrap().split('"').next().unwrap();
+        let png = CaptchaImage::new(ImageFormat::Png, general_purpose::STANDARD.decode(b64).unwrap());
+        assert_eq!(png.dimensions(), Some((60, 24)));
+        let gif = |version: &[u8]| CaptchaImage::new(ImageFormat::Gif, [version, &[0x78, 0x00, 0x1E, 0x00]].concat());
+        assert_eq!(gif(b"GIF89a").dimensions(), Some((120, 30)));
+        assert_eq!(gif(b"GIF87a").dimensions(), Some((120, 30)));
+        // Right bytes, wrong format, or cut short
+        assert_eq!(CaptchaImage { format: ImageFormat::Gif, ..png.clone() }.dimensions(), None);
+        assert_eq!(CaptchaImage::new(ImageFormat::Png, png.bytes[..20].to_vec()).dimensions(), None);
+        assert_eq!(CaptchaImage::new(ImageFormat::Gif, b"not an image".to_vec()).dimensions(), None);
+    }
+}
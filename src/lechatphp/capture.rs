@@ -0,0 +1,277 @@
+use super::charset;
+use super::error_kind::{Classify, ErrorKind};
+use super::http_log::redact;
+use super::retry::SendErr;
+use chrono::Local;
+use http::header::{HeaderMap, CONTENT_TYPE};
+use http::StatusCode;
+use reqwest::blocking::Request;
+use serde_derive::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::{fs, io};
+use thiserror::Error;
+
+/// Where to record the requests of this module, to debug a fork the
+/// login or the parsing doesn't work with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptureConfig {
+    /// Each run records in its own timestamped directory under this one.
+    pub dir: PathBuf,
+    /// Once this much is written, later requests aren't recorded.
+    pub max_bytes: u64,
+}
+
+/// One request, `<n>.json`. Redacted like the http log, form values are
+/// left out entirely.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub method: String,
+    pub url: String,
+    pub form_fields: Vec<String>,
+    /// `None` when the request failed, see `error`.
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    pub headers: Vec<(String, String)>,
+    /// Name of the file with the decoded body, `<n>.html`.
+    pub body: Option<String>,
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum CaptureErr {
+    #[error("capture {}: {}", .0.display(), .1)]
+    Io(PathBuf, #[source] io::Error),
+    #[error("capture {}: {}", .0.display(), .1)]
+    Json(PathBuf, #[source] serde_json::Error),
+}
+
+impl Classify for CaptureErr {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            CaptureErr::Io(..) | CaptureErr::Json(..) => ErrorKind::Fatal,
+        }
+    }
+}
+
+struct Session {
+    config: CaptureConfig,
+    started: String,
+    /// Created with the first record.
+    dir: Option<PathBuf>,
+    count: usize,
+    written: u64,
+    /// Whether a failure or the size limit was already reported.
+    stopped: bool,
+}
+
+impl Session {
+    fn new(config: CaptureConfig) -> Self {
+        let started = Local::now().format("%Y%m%d-%H%M%S").to_string();
+        Self { config, started, dir: None, count: 0, written: 0, stopped: false }
+    }
+
+    // A run started in the same second gets a suffix
+    fn create_dir(&self) -> Result<PathBuf, CaptureErr> {
+        let root = &self.config.dir;
+        fs::create_dir_all(root).map_err(|e| CaptureErr::Io(root.clone(), e))?;
+        let mut builder = fs::DirBuilder::new();
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        let mut n = 1;
+        loop {
+            let name = if n == 1 { self.started.clone() } else { format!("{}-{}", self.started, n) };
+            let dir = root.join(name);
+            match builder.create(&dir) {
+                Ok(()) => return Ok(dir),
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => n += 1,
+                Err(e) => return Err(CaptureErr::Io(dir, e)),
+            }
+        }
+    }
+
+    fn write(&mut self, record: Record, body: Option<String>) -> Result<(), CaptureErr> {
+        if self.stopped {
+            return Ok(());
+        }
+        let name = format!("{:04}", self.count + 1);
+        let record = Record { body: body.as_ref().map(|_| format!("{}.html", name)), ..record };
+        let json = serde_json::to_string_pretty(&record).map_err(|e| CaptureErr::Json(PathBuf::from(&name), e))?;
+        let size = (json.len() + body.as_ref().map_or(0, String::len)) as u64;
+        if self.written + size > self.config.max_bytes {
+            if !self.stopped {
+                log::warn!("capture limit of {} bytes reached, requests are no longer recorded", self.config.max_bytes);
+                self.stopped = true;
+            }
+            return Ok(());
+        }
+        let dir = match &self.dir {
+            Some(dir) => dir.clone(),
+            None => {
+                let dir = self.create_dir()?;
+                log::info!("capturing requests in {}", dir.display());
+                self.dir.insert(dir).clone()
+            }
+        };
+        if let Some(body) = body {
+            let path = dir.join(format!("{}.html", name));
+            fs::write(&path, body).map_err(|e| CaptureErr::Io(path, e))?;
+        }
+        let path = dir.join(format!("{}.json", name));
+        fs::write(&path, json).map_err(|e| CaptureErr::Io(path, e))?;
+        self.count += 1;
+        self.written += size;
+        Ok(())
+    }
+}
+
+/// The capture of one client, see `ClientConfig::capture`.
+#[derive(Default)]
+pub struct Capture(Mutex<Option<Session>>);
+
+impl Capture {
+    /// Records in a directory of its own once a request is sent, nothing
+    /// with `None`.
+    pub fn new(config: Option<CaptureConfig>) -> Self {
+        Self(Mutex::new(config.map(Session::new)))
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.0.lock().unwrap().is_some()
+    }
+
+    /// Record `request` and what came of it, `(status, headers, body)` or
+    /// the error. Everything is redacted before it is written, and a failure
+    /// to write is logged once and otherwise ignored: the request goes on.
+    pub fn record(&self, request: &Request, response: Result<(StatusCode, &HeaderMap, &[u8]), &SendErr>) {
+        let mut session = self.0.lock().unwrap();
+        let Some(session) = session.as_mut() else {
+            return;
+        };
+        let mut record = Record {
+            method: request.method().to_string(),
+            url: redact(request.url().as_str()),
+            form_fields: form_fields(request),
+            status: None,
+            error: None,
+            headers: vec![],
+            body: None,
+        };
+        let body = match response {
+            Ok((status, headers, body)) => {
+                record.status = Some(status.as_u16());
+                record.headers = headers
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), redact(&String::from_utf8_lossy(value.as_bytes()))))
+                    .collect();
+                let content_type = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok());
+                Some(redact(&charset::decode(body, content_type)))
+            }
+            Err(e) => {
+                record.error = Some(redact(&e.to_string()));
+                None
+            }
+        };
+        if let Err(e) = session.write(record, body) {
+            if !session.stopped {
+                log::warn!("{}, requests are no longer recorded", e);
+                session.stopped = true;
+            }
+        }
+    }
+}
+
+impl std::fmt::Debug for Capture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let config = self.0.lock().unwrap().as_ref().map(|s| s.config.clone());
+        f.debug_tuple("Capture").field(&config).finish()
+    }
+}
+
+// Names of an urlencoded body, multipart ones can't be looked at
+fn form_fields(request: &Request) -> Vec<String> {
+    let Some(body) = request.body().and_then(|b| b.as_bytes()) else {
+        return vec![];
+    };
+    String::from_utf8_lossy(body)
+        .split('&')
+        .filter_map(|pair| pair.split('=').next())
+        .filter(|name| !name.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// The records of a capture directory in order, each with its body.
+#[cfg(test)]
+pub fn load(dir: &std::path::Path) -> Result<Vec<(Record, Option<String>)>, CaptureErr> {
+    let mut paths = fs::read_dir(dir)
+        .map_err(|e| CaptureErr::Io(dir.to_owned(), e))?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths
+        .into_iter()
+        .map(|path| {
+            let json = fs::read_to_string(&path).map_err(|e| CaptureErr::Io(path.clone(), e))?;
+            let record: Record = serde_json::from_str(&json).map_err(|e| CaptureErr::Json(path.clone(), e))?;
+            let body = match &record.body {
+                Some(name) => Some(fs::read_to_string(dir.join(name)).map_err(|e| CaptureErr::Io(dir.join(name), e))?),
+                None => None,
+            };
+            Ok((record, body))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lechatphp::mock::{chat_server, replay};
+    use crate::lechatphp::settings::Settings;
+    use crate::lechatphp::transport::Transport;
+    use crate::lechatphp::{login, logout};
+
+    #[test]
+    fn capture_test() {
+        let root = std::env::temp_dir().join(format!("bhcli-capture-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let capturing = |max_bytes| {
+            let mut transport = Transport::direct();
+            let capture = Capture::new(Some(CaptureConfig { dir: root.clone(), max_bytes }));
+            transport.set_settings(Settings { capture, ..Default::default() });
+            transport
+        };
+        let server = chat_server("s3cr3tsession");
+        let transport = capturing(1 << 20);
+        let session = login(&transport, &server.url, "capture.php", "nick", "hunter2", "", true).unwrap();
+        logout(&transport, &server.url, "capture.php", &session).unwrap();
+
+        let dirs = fs::read_dir(&root).unwrap().map(|e| e.unwrap().path()).collect::<Vec<_>>();
+        assert_eq!(dirs.len(), 1);
+        let dir = &dirs[0];
+        let records = load(dir).unwrap().into_iter().filter(|(r, _)| r.url.contains("/capture.php")).collect::<Vec<_>>();
+        let methods = records.iter().map(|(r, _)| r.method.as_str()).collect::<Vec<_>>();
+        assert_eq!(methods, ["GET", "POST", "POST"]);
+        let (login_post, body) = &records[1];
+        assert_eq!(login_post.status, Some(200));
+        assert!(login_post.form_fields.iter().any(|f| f == "pass"));
+        assert!(body.as_ref().unwrap().contains("session=***"));
+        for entry in fs::read_dir(dir).unwrap() {
+            let contents = fs::read_to_string(entry.unwrap().path()).unwrap();
+            assert!(!contents.contains("hunter2") && !contents.contains("s3cr3tsession"));
+        }
+
+        // The report turned into a test
+        let server = replay(dir);
+        let transport = Transport::direct();
+        assert_eq!(login(&transport, &server.url, "capture.php", "nick", "pass", "", true).unwrap(), "***");
+        let resp = transport.send(transport.get(format!("{}/capture.php?action=nope", server.url))).unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // Nothing fits, nothing is written
+        login(&capturing(1), &chat_server("x").url, "capture.php", "nick", "pass", "", true).unwrap();
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 1);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+// The strings shown to whoever uses the client, by key, in English unless
+// a catalog file says otherwise. Log messages don't go through here.
+use super::error_kind::{Classify, ErrorKind};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::Path;
+use std::{fs, io};
+use thiserror::Error;
+
+/// The English strings, by key. `{name}` is replaced by the argument of
+/// that name.
+const ENGLISH: &[(&str, &str)] = &[
+    ("login-waitroom", "waitroom enabled, wait {seconds}sec"),
+    ("login-interstitial", "{page} at {url}, retry in {wait}"),
+    ("login-new-circuit", "server down, new tor circuit requested, retry in {wait}"),
+    ("login-retry", "retry login in {wait}, attempt: {attempt}"),
+    ("login-retry-max", "retry login in {wait}, attempt: {attempt}/{max}"),
+    ("captcha-prompt", "Please enter the CAPTCHA: "),
+    ("proxy-waiting", "{error}\nWaiting for the proxy to come back..."),
+    ("proxy-back", "Proxy is back"),
+    ("error-login", "Login error: {error}"),
+    ("error-config", "Config error: {error}"),
+    ("error-timeout", "Timeout error: {error}"),
+    ("error-blocked", "Login blocked: {error}"),
+    ("error-captcha", "Captcha error: {error}"),
+    ("error-response", "Bad response: {error}"),
+    ("error-server-down", "Server is down: {error}"),
+    ("error-connection", "Connection error: {error}"),
+    ("error-no-proxy", "Connection error: {error}\nIs tor proxy enabled ?"),
+    ("error-request", "Reqwest error: {error}"),
+];
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum CatalogErr {
+    #[error("can't read the catalog: {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid catalog: {0}")]
+    Toml(#[from] toml::de::Error),
+    /// Every value is a string, in one flat table.
+    #[error("catalog key {0} is not a string")]
+    NotText(String),
+}
+
+impl Classify for CatalogErr {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            CatalogErr::Io(_) => ErrorKind::Fatal,
+            CatalogErr::Toml(_) | CatalogErr::NotText(_) => ErrorKind::InvalidInput,
+        }
+    }
+}
+
+/// Strings that replace some of the English ones, read from a TOML file of
+/// `key = "text"` lines, e.g. `login-waitroom = "salle d'attente, {seconds}s"`.
+/// A key left out stays English.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Catalog {
+    messages: HashMap<String, String>,
+}
+
+impl Catalog {
+    pub fn parse(toml: &str) -> Result<Self, CatalogErr> {
+        let table: toml::Table = toml.parse()?;
+        let mut messages = HashMap::new();
+        for (key, value) in table {
+            let toml::Value::String(text) = value else {
+                return Err(CatalogErr::NotText(key));
+            };
+            if !ENGLISH.iter().any(|(k, _)| *k == key) {
+                log::warn!("catalog key {} is not used", key);
+            }
+            messages.insert(key, text);
+        }
+        Ok(Self { messages })
+    }
+
+    pub fn load(path: &Path) -> Result<Self, CatalogErr> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// The string of `key` with `args` filled in: this catalog's, or the
+    /// English one, or the key itself when neither has it.
+    pub fn get(&self, key: &str, args: &[(&str, &dyn Display)]) -> String {
+        match self.messages.get(key) {
+            Some(text) => fill(text, args),
+            None => english(key, args),
+        }
+    }
+}
+
+/// The English string of `key`, whatever the catalog.
+pub fn english(key: &str, args: &[(&str, &dyn Display)]) -> String {
+    match ENGLISH.iter().find(|(k, _)| *k == key) {
+        Some((_, text)) => fill(text, args),
+        None => key.to_owned(),
+    }
+}
+
+// One pass over the text, so braces in the arguments are kept as they are.
+// A placeholder without argument stays too.
+fn fill(text: &str, args: &[(&str, &dyn Display)]) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let arg = after
+            .find('}')
+            .and_then(|end| args.iter().find(|(name, _)| *name == &after[..end]).map(|(_, value)| (end, value)));
+        match arg {
+            Some((end, value)) => {
+                out.push_str(&value.to_string());
+                rest = &after[end + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catalog_test() {
+        let catalog = Catalog::parse(
+            r#"
+            login-waitroom = "salle d'attente, encore {seconds} s"
+            login-retry-max = "nouvel essai dans {wait} ({attempt} sur {max}, {unknown})"
+            error-login = "{error}"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(catalog.get("login-waitroom", &[("seconds", &30)]), "salle d'attente, encore 30 s");
+        assert_eq!(
+            catalog.get("login-retry-max", &[("wait", &"2s"), ("attempt", &3), ("max", &5)]),
+            "nouvel essai dans 2s (3 sur 5, {unknown})"
+        );
+        // Arguments are not filled in again
+        assert_eq!(catalog.get("error-login", &[("error", &"{error} {seconds}")]), "{error} {seconds}");
+        // Left out, English
+        assert_eq!(catalog.get("proxy-back", &[]), "Proxy is back");
+        assert_eq!(catalog.get("login-retry", &[("wait", &"2s"), ("attempt", &1)]), "retry login in 2s, attempt: 1");
+        assert_eq!(catalog.get("no-such-key", &[]), "no-such-key");
+
+        assert!(matches!(Catalog::parse("error-login = 3"), Err(CatalogErr::NotText(key)) if key == "error-login"));
+        assert!(matches!(Catalog::parse("error-login = "), Err(CatalogErr::Toml(_))));
+    }
+
+    #[test]
+    fn fill_test() {
+        assert_eq!(fill("{a}{b} {a", &[("a", &1), ("b", &"}")]), "1} {a");
+        assert_eq!(fill("{}{{a}}", &[("a", &2)]), "{}{2}");
+        assert_eq!(fill("wait {seconds}sec", &[]), "wait {seconds}sec");
+    }
+}
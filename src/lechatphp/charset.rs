@@ -0,0 +1,95 @@
+use encoding_rs::{Encoding, UTF_8};
+use lazy_static::lazy_static;
+use regex::bytes::Regex;
+
+/// How far into the body a `<meta>` charset declaration is looked for, as
+/// browsers do.
+const SNIFF_LEN: usize = 1024;
+
+lazy_static! {
+    static ref CHARSET_RGX: Regex = Regex::new(r#"(?i)charset\s*=\s*["']?\s*([\w.:-]+)"#).unwrap();
+    static ref META_RGX: Regex = Regex::new(r#"(?i)<meta\s[^>]*charset[^>]*>"#).unwrap();
+}
+
+fn encoding_of(declaration: &[u8]) -> Option<&'static Encoding> {
+    let label = CHARSET_RGX.captures(declaration)?.get(1)?.as_bytes();
+    Encoding::for_label(label)
+}
+
+/// The encoding of a body: the Content-Type charset, else a `<meta>`
+/// declaration near the start, else UTF-8. A byte order mark wins over all
+/// of them when decoding.
+pub fn detect(body: &[u8], content_type: Option<&str>) -> &'static Encoding {
+    content_type
+        .and_then(|ct| encoding_of(ct.as_bytes()))
+        .or_else(|| {
+            let head = &body[..body.len().min(SNIFF_LEN)];
+            META_RGX.find_iter(head).find_map(|meta| encoding_of(meta.as_bytes()))
+        })
+        .unwrap_or(UTF_8)
+}
+
+/// Decode a body to text, invalid sequences become replacement characters.
+pub fn decode(body: &[u8], content_type: Option<&str>) -> String {
+    let (text, encoding, had_errors) = detect(body, content_type).decode(body);
+    if had_errors {
+        log::debug!("invalid {} sequences in response body", encoding.name());
+    }
+    text.into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_test() {
+        let latin1 = b"<html><body>J\xfcrgen: Gr\xfc\xdfe</body></html>";
+        let want = "<html><body>Jürgen: Grüße</body></html>";
+        let utf8 = want.as_bytes();
+
+        assert_eq!(decode(latin1, Some("text/html; charset=ISO-8859-1")), want);
+        assert_eq!(decode(latin1, Some("text/html;charset=\"latin1\"")), want);
+        assert_eq!(decode(utf8, Some("text/html; charset=utf-8")), want);
+        assert_eq!(decode(utf8, Some("text/html")), want);
+        assert_eq!(decode(utf8, None), want);
+
+        // No header charset, sniffed from the page
+        let meta = [b"<html><head><meta charset=\"iso-8859-1\"></head>".as_slice(), &latin1[6..]].concat();
+        assert!(decode(&meta, Some("text/html")).contains("Jürgen: Grüße"));
+        let http_equiv = [
+            b"<meta http-equiv=\"Content-Type\" content=\"text/html; charset=windows-1252\">".as_slice(),
+            latin1,
+        ]
+        .concat();
+        assert!(decode(&http_equiv, None).contains("Jürgen: Grüße"));
+        // An unknown label falls back to UTF-8
+        assert_eq!(detect(latin1, Some("text/html; charset=bogus")), UTF_8);
+        assert_eq!(decode(latin1, None), "<html><body>J\u{fffd}rgen: Gr\u{fffd}\u{fffd}e</body></html>");
+        // BOM over everything
+        let bom = [b"\xef\xbb\xbf".as_slice(), utf8].concat();
+        assert_eq!(decode(&bom, Some("text/html; charset=ISO-8859-1")), want);
+    }
+
+    #[test]
+    fn transport_test() {
+        use crate::lechatphp::mock::{MockResponse, MockServer};
+        use crate::lechatphp::transport::Transport;
+
+        let server = MockServer::start(|req| match req.path.as_str() {
+            "/latin1" => MockResponse::bytes(200, include_bytes!("fixtures/view_latin1.html").to_vec())
+                .with_header("Content-Type", "text/html; charset=ISO-8859-1"),
+            // Charset from the page only, and gzipped
+            "/gzip" => MockResponse::bytes(200, include_bytes!("fixtures/view_latin1.html.gz").to_vec())
+                .with_header("Content-Type", "text/html")
+                .with_header("Content-Encoding", "gzip"),
+            _ => MockResponse::ok(include_str!("fixtures/view_utf8.html")).with_header("Content-Type", "text/html; charset=UTF-8"),
+        });
+        let client = Transport::direct();
+        for path in ["/latin1", "/gzip", "/utf8"] {
+            let text = client.text(client.send(client.get(format!("{}{}", server.url, path))).unwrap()).unwrap();
+            assert!(text.contains("Grüße aus Köln, ça va?"), "{}", path);
+            assert!(text.contains("Zoë"), "{}", path);
+        }
+    }
+}
@@ -0,0 +1,555 @@
+use super::captcha_prompt::CaptchaImage;
+use super::client::{self, BuildErr, ClientConfig, ConfigError};
+use super::command::ChatCommand;
+use super::error_kind::{Classify, ErrorKind};
+use super::filter::Filter;
+use super::messages::{self, FetchErr, Message, Topic};
+use super::moderation::{self, Applicant, Ban, CleanTarget, ModErr, NewBan};
+use super::post::{self, PostErr};
+use super::profile::{self, Profile, ProfileErr};
+use super::rooms::{self, RoomErr};
+use super::settings::Settings;
+use super::transport::Transport;
+use super::users::{self, User, UserInfo};
+use super::{check_server, login, logout, LoginErr, LoginProgress, ServerHealth};
+use serde_derive::{Deserialize, Serialize};
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::path::Path;
+use thiserror::Error;
+
+/// Who logs in.
+#[derive(Clone)]
+pub struct Credentials {
+    pub nick: String,
+    pub password: String,
+}
+
+impl Credentials {
+    pub fn new(nick: &str, password: &str) -> Self {
+        Self { nick: nick.to_owned(), password: password.to_owned() }
+    }
+}
+
+impl Debug for Credentials {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Credentials {{ nick: {:?}, .. }}", self.nick)
+    }
+}
+
+/// How to log in. Profiles read from a file go through
+/// `LoginOptionsBuilder` too, so they are checked the same way.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "LoginOptionsBuilder")]
+#[non_exhaustive]
+pub struct LoginOptions {
+    /// The nick's color as the login form takes it, six hex digits, the
+    /// chat's choice when empty.
+    pub color: String,
+    /// Ask `LeChatClient::set_prompt`'s prompt even for captchas the solver reads.
+    pub manual_captcha: bool,
+    /// The language to log in and chat in, `ClientConfig::lang` when `None`.
+    pub lang: Option<String>,
+}
+
+impl LoginOptions {
+    /// A builder starting from the defaults.
+    ///
+    /// ```
+    /// use bhcli::lechatphp::chat::LoginOptions;
+    ///
+    /// # fn main() -> Result<(), bhcli::lechatphp::client::ConfigError> {
+    /// let opts = LoginOptions::builder().color("9ACD32").lang("fr").build()?;
+    /// assert_eq!(opts.lang.as_deref(), Some("fr"));
+    /// assert!(LoginOptions::builder().color("green").build().is_err());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn builder() -> LoginOptionsBuilder {
+        LoginOptionsBuilder::default()
+    }
+}
+
+/// Builds `LoginOptions`, see their fields.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct LoginOptionsBuilder {
+    color: String,
+    manual_captcha: bool,
+    lang: Option<String>,
+}
+
+impl LoginOptionsBuilder {
+    pub fn color(mut self, color: impl Into<String>) -> Self {
+        self.color = color.into();
+        self
+    }
+
+    pub fn manual_captcha(mut self, manual_captcha: bool) -> Self {
+        self.manual_captcha = manual_captcha;
+        self
+    }
+
+    pub fn lang(mut self, lang: impl Into<String>) -> Self {
+        self.lang = Some(lang.into());
+        self
+    }
+
+    pub fn build(self) -> Result<LoginOptions, ConfigError> {
+        let hex = self.color.len() == 6 && self.color.chars().all(|c| c.is_ascii_hexdigit());
+        if !self.color.is_empty() && !hex {
+            return Err(ConfigError::InvalidColor(self.color));
+        }
+        if self.lang.as_deref().is_some_and(|lang| lang.trim().is_empty()) {
+            return Err(ConfigError::EmptyLang);
+        }
+        Ok(LoginOptions { color: self.color, manual_captcha: self.manual_captcha, lang: self.lang })
+    }
+}
+
+impl TryFrom<LoginOptionsBuilder> for LoginOptions {
+    type Error = ConfigError;
+
+    fn try_from(builder: LoginOptionsBuilder) -> Result<Self, ConfigError> {
+        builder.build()
+    }
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ChatErr {
+    #[error("not logged in")]
+    NotLoggedIn,
+    #[error("{0}")]
+    Login(#[from] LoginErr),
+    #[error("logout failed: {0}")]
+    Logout(#[source] anyhow::Error),
+    #[error("{0}")]
+    Fetch(#[from] FetchErr),
+    #[error("{0}")]
+    Post(#[from] PostErr),
+    #[error("{0}")]
+    Mod(#[from] ModErr),
+    #[error("{0}")]
+    Profile(#[from] ProfileErr),
+    #[error("{0}")]
+    Room(#[from] RoomErr),
+}
+
+impl Classify for ChatErr {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            ChatErr::NotLoggedIn => ErrorKind::AuthExpired,
+            ChatErr::Logout(_) => ErrorKind::Fatal,
+            ChatErr::Login(e) => e.kind(),
+            ChatErr::Fetch(e) => e.kind(),
+            ChatErr::Post(e) => e.kind(),
+            ChatErr::Mod(e) => e.kind(),
+            ChatErr::Profile(e) => e.kind(),
+            ChatErr::Room(e) => e.kind(),
+        }
+    }
+}
+
+/// A chat on one server, and our session on it once logged in.
+///
+/// Each has its own connections, cookies, endpoint and session, so clients
+/// of different servers or accounts don't mix. So are the settings it was
+/// built with, like the retry policy or the rate limit, see `settings`.
+///
+/// ```no_run
+/// use bhcli::lechatphp::chat::{Credentials, LeChatClient, LoginOptions};
+/// use bhcli::lechatphp::client::ClientConfig;
+///
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let base_url = "http://blkhatjxlrvc5aevqzz5t6kxldayog6jlx5h7glnu44euzongl4fh5ad.onion";
+/// let mut chat = LeChatClient::new(ClientConfig::default(), base_url, "index.php")?;
+/// chat.login(&Credentials::new("nick", "password"), &LoginOptions::default())?;
+/// chat.post_message("hello")?;
+/// for message in chat.fetch_messages()? {
+///     println!("{}: {}", message.from.as_deref().unwrap_or("system"), message.text);
+/// }
+/// chat.logout()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct LeChatClient {
+    config: ClientConfig,
+    transport: Transport,
+    base_url: String,
+    page_php: String,
+    nick: Option<String>,
+    session: Option<String>,
+}
+
+impl LeChatClient {
+    /// A client of the chat at `base_url`, its page `page_php`, built from
+    /// `config`, whose `target_url` defaults to `base_url`. Not logged in yet.
+    pub fn new(config: ClientConfig, base_url: &str, page_php: &str) -> Result<Self, BuildErr> {
+        let config = ClientConfig { target_url: config.target_url.or_else(|| Some(base_url.to_owned())), ..config };
+        let transport = client::build(&config)?;
+        Ok(Self::with_transport(config, transport, base_url, page_php))
+    }
+
+    /// Like `new`, over a transport already built from `config`.
+    pub fn with_transport(config: ClientConfig, transport: Transport, base_url: &str, page_php: &str) -> Self {
+        Self {
+            config,
+            transport,
+            base_url: base_url.trim_end_matches('/').to_owned(),
+            page_php: page_php.to_owned(),
+            nick: None,
+            session: None,
+        }
+    }
+
+    pub fn config(&self) -> &ClientConfig {
+        &self.config
+    }
+
+    pub fn transport(&self) -> &Transport {
+        &self.transport
+    }
+
+    /// What the client was built with, and its state.
+    pub fn settings(&self) -> &Settings {
+        self.transport.settings()
+    }
+
+    /// Who to ask the captchas the solver doesn't read, see
+    /// `Transport::set_prompt`.
+    pub fn set_prompt(&self, prompt: impl Fn(&CaptchaImage) -> io::Result<String> + Send + Sync + 'static) {
+        self.transport.set_prompt(prompt);
+    }
+
+    /// Called with what the login waits for, see `Transport::set_progress`.
+    pub fn set_progress(&self, progress: impl Fn(&LoginProgress) + Send + Sync + 'static) {
+        self.transport.set_progress(progress);
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    pub fn page_php(&self) -> &str {
+        &self.page_php
+    }
+
+    /// The language pages are asked in.
+    pub fn lang(&self) -> &str {
+        self.transport.lang()
+    }
+
+    /// The nick logged in with.
+    pub fn nick(&self) -> Option<&str> {
+        self.nick.as_deref()
+    }
+
+    pub fn session(&self) -> Option<&str> {
+        self.session.as_deref()
+    }
+
+    pub fn is_logged_in(&self) -> bool {
+        self.session.is_some()
+    }
+
+    fn logged_in(&self) -> Result<&str, ChatErr> {
+        self.session.as_deref().ok_or(ChatErr::NotLoggedIn)
+    }
+
+    pub fn check_server(&self) -> ServerHealth {
+        check_server(&self.transport, &self.base_url, &self.page_php)
+    }
+
+    /// Log in, in place of the session there was.
+    pub fn login(&mut self, creds: &Credentials, opts: &LoginOptions) -> Result<&str, ChatErr> {
+        if let Some(lang) = &opts.lang {
+            self.transport.set_lang(lang);
+        }
+        let session =
+            login(&self.transport, &self.base_url, &self.page_php, &creds.nick, &creds.password, &opts.color, opts.manual_captcha)?;
+        self.nick = Some(creds.nick.clone());
+        Ok(self.session.insert(session))
+    }
+
+    /// Log out. The session is forgotten even when the chat couldn't be
+    /// told.
+    pub fn logout(&mut self) -> Result<(), ChatErr> {
+        let session = self.session.take().ok_or(ChatErr::NotLoggedIn)?;
+        self.nick = None;
+        logout(&self.transport, &self.base_url, &self.page_php, &session).map_err(ChatErr::Logout)
+    }
+
+    pub fn fetch_messages(&self) -> Result<Vec<Message>, ChatErr> {
+        Ok(messages::fetch_messages(&self.transport, &self.base_url, &self.page_php, self.logged_in()?)?)
+    }
+
+    pub fn fetch_messages_in(&self, room: &str) -> Result<Vec<Message>, ChatErr> {
+        Ok(messages::fetch_messages_in(&self.transport, &self.base_url, &self.page_php, self.logged_in()?, room)?)
+    }
+
+    /// The messages after `last_id` and the new last id, see
+    /// `messages::fetch_messages_since`.
+    pub fn fetch_messages_since(&self, last_id: u64) -> Result<(Vec<Message>, u64), ChatErr> {
+        Ok(messages::fetch_messages_since(&self.transport, &self.base_url, &self.page_php, self.logged_in()?, last_id)?)
+    }
+
+    pub fn fetch_topic(&self) -> Result<Option<Topic>, ChatErr> {
+        Ok(messages::fetch_topic(&self.transport, &self.base_url, &self.page_php, self.logged_in()?)?)
+    }
+
+    pub fn fetch_online_users(&self) -> Result<Vec<User>, ChatErr> {
+        Ok(users::fetch_online_users(&self.transport, &self.base_url, &self.page_php, self.logged_in()?)?)
+    }
+
+    pub fn fetch_user_info(&self, nick: &str) -> Result<UserInfo, ChatErr> {
+        Ok(users::fetch_user_info(&self.transport, &self.base_url, &self.page_php, self.logged_in()?, nick)?)
+    }
+
+    pub fn post_message(&self, command: impl Into<ChatCommand>) -> Result<(), ChatErr> {
+        Ok(post::post_message(&self.transport, &self.base_url, &self.page_php, self.logged_in()?, command)?)
+    }
+
+    pub fn post_message_in(&self, room: &str, command: impl Into<ChatCommand>) -> Result<(), ChatErr> {
+        Ok(post::post_message_in(&self.transport, &self.base_url, &self.page_php, self.logged_in()?, room, command)?)
+    }
+
+    pub fn post_private(&self, to_nick: &str, text: &str) -> Result<(), ChatErr> {
+        Ok(post::post_private(&self.transport, &self.base_url, &self.page_php, self.logged_in()?, to_nick, text)?)
+    }
+
+    pub fn post_with_upload(&self, text: &str, file_path: &Path) -> Result<(), ChatErr> {
+        Ok(post::post_with_upload(&self.transport, &self.base_url, &self.page_php, self.logged_in()?, text, file_path)?)
+    }
+
+    pub fn delete_last_message(&self) -> Result<(), ChatErr> {
+        Ok(post::delete_last_message(&self.transport, &self.base_url, &self.page_php, self.logged_in()?)?)
+    }
+
+    pub fn delete_all_my_messages(&self) -> Result<(), ChatErr> {
+        Ok(post::delete_all_my_messages(&self.transport, &self.base_url, &self.page_php, self.logged_in()?)?)
+    }
+
+    pub fn switch_room(&self, room: &str) -> Result<(), ChatErr> {
+        Ok(rooms::switch_room(&self.transport, &self.base_url, &self.page_php, self.logged_in()?, room)?)
+    }
+
+    pub fn get_profile(&self) -> Result<Profile, ChatErr> {
+        Ok(profile::get_profile(&self.transport, &self.base_url, &self.page_php, self.logged_in()?)?)
+    }
+
+    pub fn kick_user(&self, nick: &str, message: Option<&str>) -> Result<(), ChatErr> {
+        Ok(moderation::kick_user(&self.transport, &self.base_url, &self.page_php, self.logged_in()?, nick, message)?)
+    }
+
+    pub fn logout_user(&self, nick: &str) -> Result<(), ChatErr> {
+        Ok(moderation::logout_user(&self.transport, &self.base_url, &self.page_php, self.logged_in()?, nick)?)
+    }
+
+    pub fn delete_message(&self, id: u64, confirm: bool) -> Result<(), ChatErr> {
+        Ok(moderation::delete_message(&self.transport, &self.base_url, &self.page_php, self.logged_in()?, id, confirm)?)
+    }
+
+    pub fn clean_messages(&self, target: CleanTarget, confirm: bool) -> Result<(), ChatErr> {
+        Ok(moderation::clean_messages(&self.transport, &self.base_url, &self.page_php, self.logged_in()?, target, confirm)?)
+    }
+
+    pub fn set_topic(&self, text: &str) -> Result<(), ChatErr> {
+        Ok(moderation::set_topic(&self.transport, &self.base_url, &self.page_php, self.logged_in()?, text)?)
+    }
+
+    pub fn fetch_applicants(&self) -> Result<Vec<Applicant>, ChatErr> {
+        Ok(moderation::fetch_applicants(&self.transport, &self.base_url, &self.page_php, self.logged_in()?)?)
+    }
+
+    pub fn approve_applicant(&self, nick: &str) -> Result<(), ChatErr> {
+        Ok(moderation::approve_applicant(&self.transport, &self.base_url, &self.page_php, self.logged_in()?, nick)?)
+    }
+
+    pub fn reject_applicant(&self, nick: &str, message: Option<&str>) -> Result<(), ChatErr> {
+        Ok(moderation::reject_applicant(&self.transport, &self.base_url, &self.page_php, self.logged_in()?, nick, message)?)
+    }
+
+    pub fn fetch_filters(&self) -> Result<Vec<Filter>, ChatErr> {
+        Ok(moderation::fetch_filters(&self.transport, &self.base_url, &self.page_php, self.logged_in()?)?)
+    }
+
+    pub fn fetch_bans(&self) -> Result<Vec<Ban>, ChatErr> {
+        Ok(moderation::fetch_bans(&self.transport, &self.base_url, &self.page_php, self.logged_in()?)?)
+    }
+
+    pub fn add_ban(&self, ban: &NewBan, verify: bool, confirm: bool) -> Result<(), ChatErr> {
+        Ok(moderation::add_ban(&self.transport, &self.base_url, &self.page_php, self.logged_in()?, ban, verify, confirm)?)
+    }
+
+    pub fn remove_ban(&self, target: &str, verify: bool, confirm: bool) -> Result<(), ChatErr> {
+        Ok(moderation::remove_ban(&self.transport, &self.base_url, &self.page_php, self.logged_in()?, target, verify, confirm)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lechatphp::client::ProxySetting;
+    use crate::lechatphp::filter::FilterCheck;
+    use crate::lechatphp::mock::{MockResponse, MockServer};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    // Bodies and cookies sent
+    type Seen = Arc<Mutex<Vec<(String, Option<String>)>>>;
+
+    // A chat that logs `session` in with a cookie of its own, shows a
+    // message of spammer, and records what it is sent
+    fn chat(session: &'static str) -> (MockServer, Seen) {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let record = Arc::clone(&seen);
+        let server = MockServer::start(move |req| {
+            let resp = match (req.method.as_str(), req.body.contains("action=login")) {
+                ("POST", true) => MockResponse::ok(&format!(
+                    r#"<html><body><iframe name="view" src="chat.php?action=view&session={}"></iframe></body></html>"#,
+                    session
+                ))
+                .with_header("Set-Cookie", &format!("server={}", session)),
+                ("POST", _) => MockResponse::ok(include_str!("fixtures/post_ok.html")),
+                _ if req.path.contains("action=post") => MockResponse::ok(include_str!("fixtures/post_ok.html")),
+                _ if req.path.contains("action=view") => MockResponse::ok(
+                    r#"<html><body><div id="messages"><div class="msg"><small>10-17 19:40:02 - </small><span class="usermsg"><span style="color:#FF0000;">spammer</span> - buy darn pills</span></div></div></body></html>"#,
+                ),
+                _ => MockResponse::ok("<form></form>"),
+            };
+            record.lock().unwrap().push((req.body.clone(), req.header("Cookie")));
+            resp
+        });
+        (server, seen)
+    }
+
+    #[test]
+    fn lechat_client_test() {
+        fn assert_send<T: Send>() {}
+        assert_send::<LeChatClient>();
+
+        let config = ClientConfig {
+            proxy: ProxySetting::Direct,
+            allow_clearnet: Some(true),
+            rate_limit: None,
+            filter_check: FilterCheck::Refuse,
+            ..Default::default()
+        };
+        let (a, seen_a) = chat("s3ss1on-a");
+        let (b, seen_b) = chat("s3ss1on-b");
+        let mut chat_a = LeChatClient::new(config.clone(), &a.url, "chat.php").unwrap();
+        let mut chat_b = LeChatClient::new(config, &format!("{}/", b.url), "chat.php").unwrap();
+        assert_eq!(chat_b.base_url(), b.url);
+        assert!(matches!(chat_a.post_message("too early"), Err(ChatErr::NotLoggedIn)));
+
+        assert_eq!(chat_a.login(&Credentials::new("alice", "pw-a"), &LoginOptions::default()).unwrap(), "s3ss1on-a");
+        assert_eq!(chat_b.login(&Credentials::new("bob", "pw-b"), &LoginOptions::default()).unwrap(), "s3ss1on-b");
+        assert_eq!((chat_a.session(), chat_a.nick()), (Some("s3ss1on-a"), Some("alice")));
+        chat_a.post_message("from a").unwrap();
+        chat_b.post_message("from b").unwrap();
+
+        // Each server only ever saw its own client: session, cookie, posts
+        for (seen, own, other) in [(&seen_a, "a", "s3ss1on-b"), (&seen_b, "b", "s3ss1on-a")] {
+            let seen = seen.lock().unwrap();
+            let (post, cookie) = seen.iter().find(|(body, _)| body.contains("message=from")).unwrap();
+            assert!(post.contains(&format!("session=s3ss1on-{}", own)), "{}", post);
+            assert!(post.contains(&format!("message=from+{}", own)), "{}", post);
+            assert_eq!(cookie.as_deref(), Some(format!("server=s3ss1on-{}", own)).as_deref());
+            assert!(seen.iter().all(|(body, cookie)| !body.contains(other) && cookie.as_ref().is_none_or(|c| !c.contains(other))));
+        }
+
+        // Nor the ignore list and filters of the other
+        messages::set_ignored(chat_a.settings(), vec!["spammer".to_owned()]);
+        let darn = Filter { pattern: "darn".to_owned(), replacement: "d**n".to_owned(), regex: false, case_sensitive: false, kick: false, allow_in_pm: false };
+        chat_a.settings().filters.set(vec![darn]);
+        assert!(chat_a.fetch_messages().unwrap().is_empty());
+        assert_eq!(chat_b.fetch_messages().unwrap()[0].text, "buy darn pills");
+        assert!(matches!(chat_a.post_message("darn"), Err(ChatErr::Post(PostErr::Filtered(_)))));
+        chat_b.post_message("darn").unwrap();
+
+        chat_a.logout().unwrap();
+        assert!(!chat_a.is_logged_in());
+        assert!(seen_a.lock().unwrap().last().unwrap().0.contains("action=logout"));
+        assert!(matches!(chat_a.logout(), Err(ChatErr::NotLoggedIn)));
+        assert!(chat_b.is_logged_in());
+    }
+
+    #[test]
+    fn settings_test() {
+        use crate::lechatphp::rate_limit::{Budget, Kind, RateLimit};
+        const A: &str = "https://a.example:443";
+        const B: &str = "https://b.example:443";
+        let pin = |url: &str, spki: &str| format!("{}=sha256/{}", url, spki).parse().unwrap();
+        let config = |rate_limit, max_message_len, spki| ClientConfig {
+            proxy: ProxySetting::Direct,
+            allow_clearnet: Some(true),
+            rate_limit,
+            max_message_len,
+            tls_pins: vec![pin(if max_message_len == 100 { A } else { B }, spki)],
+            ..Default::default()
+        };
+        let slow = RateLimit { writes: Budget { rate: 0.01, burst: 1 }, ..Default::default() };
+        let chat_a = LeChatClient::new(config(Some(slow), 100, "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE="), A, "chat.php").unwrap();
+        let chat_b = LeChatClient::new(config(None, 300, "AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI="), B, "chat.php").unwrap();
+
+        // Built last, `b` changed nothing of `a`
+        let (a, b) = (chat_a.settings(), chat_b.settings());
+        assert_eq!((a.max_message_len, b.max_message_len), (100, 300));
+        assert_eq!(a.pins.get(A).as_deref(), Some("AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQE="));
+        assert_eq!(b.pins.get(B).as_deref(), Some("AgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgI="));
+        assert_eq!((a.pins.get(B), b.pins.get(A)), (None, None));
+        a.rate_limit.acquire(Kind::Write);
+        assert!(a.rate_limit.wait_time(Kind::Write) > Duration::from_secs(60));
+        b.rate_limit.acquire(Kind::Write);
+        assert_eq!(b.rate_limit.wait_time(Kind::Write), Duration::ZERO);
+    }
+
+    #[test]
+    fn lang_test() {
+        let config = ClientConfig {
+            proxy: ProxySetting::Direct,
+            allow_clearnet: Some(true),
+            rate_limit: None,
+            lang: "de".to_owned(),
+            ..Default::default()
+        };
+        let (server, seen) = chat("s3ss1on-de");
+        let mut chat = LeChatClient::new(config, &server.url, "chat.php").unwrap();
+        assert_eq!(chat.lang(), "de");
+        chat.login(&Credentials::new("alice", "pw"), &LoginOptions::default()).unwrap();
+        chat.logout().unwrap();
+        // Overridden for one login, and what follows it
+        let opts = LoginOptions { lang: Some("fr".to_owned()), ..Default::default() };
+        chat.login(&Credentials::new("alice", "pw"), &opts).unwrap();
+        chat.logout().unwrap();
+        assert_eq!(chat.lang(), "fr");
+        assert_eq!(serde_json::from_str::<LoginOptions>(&serde_json::to_string(&opts).unwrap()).unwrap(), opts);
+
+        let seen = seen.lock().unwrap();
+        let forms: Vec<_> =
+            seen.iter().map(|(body, _)| body).filter(|b| b.contains("action=login") || b.contains("action=logout")).collect();
+        assert_eq!(forms.len(), 4, "{:?}", forms);
+        for (form, lang) in forms.iter().zip(["de", "de", "fr", "fr"]) {
+            assert!(form.contains(&format!("lang={}", lang)), "{}", form);
+        }
+    }
+
+    #[test]
+    fn login_options_test() {
+        assert_eq!(LoginOptions::builder().build().unwrap(), LoginOptions::default());
+        let opts = LoginOptions::builder().color("9acd32").manual_captcha(true).lang("fr").build().unwrap();
+        assert_eq!((opts.color.as_str(), opts.manual_captcha, opts.lang.as_deref()), ("9acd32", true, Some("fr")));
+        for color in ["green", "#9ACD32", "9ACD3", "9ACD32F"] {
+            assert!(matches!(LoginOptions::builder().color(color).build(), Err(ConfigError::InvalidColor(_))), "{}", color);
+        }
+        assert!(matches!(LoginOptions::builder().lang(" ").build(), Err(ConfigError::EmptyLang)));
+
+        // Read from a file, the same checks apply
+        assert_eq!(serde_json::from_str::<LoginOptions>(r#"{"color":"FFFFFF"}"#).unwrap().color, "FFFFFF");
+        let err = serde_json::from_str::<LoginOptions>(r#"{"color":"white"}"#).unwrap_err();
+        assert!(err.to_string().contains("invalid nick color"), "{}", err);
+        assert!(serde_json::from_str::<LoginOptions>(r#"{"lang":""}"#).is_err());
+    }
+}
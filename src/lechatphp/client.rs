@@ -0,0 +1,1278 @@
+use rand::distributions::Alphanumeric;
+use rand::seq::SliceRandom;
+use rand::{thread_rng, Rng};
+use super::capture::CaptureConfig;
+use super::catalog::{Catalog, CatalogErr};
+use super::display::DisplayRule;
+use super::emoji::EmojiConfig;
+use super::error_kind::{Classify, ErrorKind};
+use super::feed::{self, FeedEndpoint};
+use super::filter::FilterCheck;
+use super::history::HistoryConfig;
+use super::http_log::HttpLog;
+use super::mention::MentionConfig;
+use super::onion::{self, UrlErr};
+use super::post::{self, MultiLine, ReplayGuard};
+use super::purge::PurgeConfig;
+use super::rate_limit::RateLimit;
+use super::retry::RetryPolicy;
+use super::settings::Settings;
+use super::share::ShareTarget;
+use super::tls::{self, LoadedPin, TlsErr, TlsPin};
+use super::transport::{self, Transport};
+use super::LANG;
+use chrono::FixedOffset;
+use reqwest::blocking::{Client, ClientBuilder};
+use reqwest::cookie::Jar;
+use reqwest::redirect::Policy;
+use reqwest::Url;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use std::{env, fs, io};
+use thiserror::Error;
+
+// Current Tor Browser, so we blend in with regular visitors
+pub const DEFAULT_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; rv:128.0) Gecko/20100101 Firefox/128.0";
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_DEADLINE: Duration = Duration::from_secs(180);
+const PROXY_SCHEMES: [&str; 4] = ["socks5", "socks5h", "http", "https"];
+const ACCEPTED_PROXIES: &str = "expected socks5h://host:port, or http(s)://host:port for clearnet targets only";
+// tor daemon, then Tor Browser
+const DETECT_ADDRS: [&str; 2] = ["127.0.0.1:9050", "127.0.0.1:9150"];
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+// Well above the 5s refresh, so the fetch loop never reconnects
+const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+// Fetch, post and the odd captcha or mirror probe at the same time
+const DEFAULT_POOL_MAX_IDLE: usize = 4;
+const DEFAULT_TCP_KEEPALIVE: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RedirectPolicy {
+    None,
+    Limited(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProxySetting {
+    /// Connect directly, without any proxy.
+    Direct,
+    /// `ALL_PROXY`, or else the first local Tor SOCKS port that answers.
+    Auto,
+    Url(String),
+    /// Proxies in order from the client outwards: optionally an upstream
+    /// proxy, then Tor's SOCKS proxy. Onion hosts always go through Tor
+    /// alone, clearnet hosts through the upstream proxy when there is one.
+    /// Each request uses one of them, they are not nested.
+    Chain(Vec<String>),
+}
+
+/// HTTP protocol options, the defaults are reqwest's.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Protocol {
+    /// Never negotiate HTTP/2. Some onion front-ends reset the connection
+    /// when h2 is offered, which then looks like the server being down.
+    /// Keep this an explicit setting, reqwest offers h2 by default.
+    pub http1_only: bool,
+    pub tcp_nodelay: bool,
+    /// Keep idle connections around for later requests.
+    pub reuse_connections: bool,
+}
+
+impl Default for Protocol {
+    fn default() -> Self {
+        Self { http1_only: false, tcp_nodelay: true, reuse_connections: true }
+    }
+}
+
+impl Protocol {
+    fn apply(&self, mut builder: ClientBuilder) -> ClientBuilder {
+        if self.http1_only {
+            builder = builder.http1_only();
+        }
+        if !self.reuse_connections {
+            builder = builder.pool_max_idle_per_host(0);
+        }
+        builder.tcp_nodelay(self.tcp_nodelay)
+    }
+}
+
+/// Connection pool settings. A reused connection skips the SOCKS handshake
+/// and the new Tor stream, which is most of the cost of a request.
+///
+/// Pooled connections keep the stream, and so the circuit, they were opened
+/// on: with stream isolation (`socks_auth`) a client only ever reuses its
+/// own connections, but a new identity only applies to connections opened
+/// after it. A long idle timeout keeps a session on one circuit for longer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pool {
+    /// How long an idle connection is kept, `None` keeps it until the server
+    /// closes it.
+    pub idle_timeout: Option<Duration>,
+    pub max_idle_per_host: usize,
+    /// Interval of TCP keep-alive probes, `None` disables them. Over Tor
+    /// these only reach the local SOCKS port.
+    pub tcp_keepalive: Option<Duration>,
+}
+
+impl Default for Pool {
+    fn default() -> Self {
+        Self {
+            idle_timeout: Some(DEFAULT_POOL_IDLE_TIMEOUT),
+            max_idle_per_host: DEFAULT_POOL_MAX_IDLE,
+            tcp_keepalive: Some(DEFAULT_TCP_KEEPALIVE),
+        }
+    }
+}
+
+impl Pool {
+    fn apply(&self, builder: ClientBuilder) -> ClientBuilder {
+        builder
+            .pool_idle_timeout(self.idle_timeout)
+            .pool_max_idle_per_host(self.max_idle_per_host)
+            .tcp_keepalive(self.tcp_keepalive)
+    }
+
+    /// The settings a client built with `protocol` actually uses.
+    pub fn effective(&self, protocol: &Protocol) -> Self {
+        if protocol.reuse_connections {
+            *self
+        } else {
+            Self { max_idle_per_host: 0, ..*self }
+        }
+    }
+}
+
+/// SOCKS credentials. Tor puts streams with different credentials on
+/// different circuits, so each account gets its own.
+#[derive(Clone, PartialEq)]
+pub struct SocksAuth {
+    pub username: String,
+    pub password: String,
+}
+
+// Never print the credentials
+impl Debug for SocksAuth {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SocksAuth {{ .. }}")
+    }
+}
+
+impl SocksAuth {
+    /// Credentials for `profile`, derived from the profile name and a random
+    /// salt kept in `salt_file` (created on first use), so a profile keeps
+    /// the same credentials across restarts.
+    pub fn for_profile(profile: &str, salt_file: &Path) -> io::Result<Self> {
+        let salt = match fs::read_to_string(salt_file) {
+            Ok(salt) if !salt.trim().is_empty() => salt.trim().to_owned(),
+            _ => {
+                let salt: String = thread_rng().sample_iter(&Alphanumeric).take(32).map(char::from).collect();
+                if let Some(dir) = salt_file.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                fs::write(salt_file, &salt)?;
+                salt
+            }
+        };
+        Ok(Self {
+            username: format!("bhcli-{}", profile),
+            password: salt,
+        })
+    }
+}
+
+/// Everything needed to build the HTTP client used to talk to the chat.
+/// The defaults go through a local Tor daemon. Built with `ClientConfig::builder`.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct ClientConfig {
+    pub proxy: ProxySetting,
+    /// The chat base url. When `Auto` finds no proxy, an onion or unknown
+    /// target is an error rather than a direct connection.
+    pub target_url: Option<String>,
+    /// Time to connect, over Tor this includes building the circuit.
+    pub connect_timeout: Duration,
+    /// Time to wait for the response once connected.
+    pub read_timeout: Duration,
+    /// Overall limit for a request, retries included.
+    pub deadline: Option<Duration>,
+    /// User-Agent pool. One is picked when the client is built and used for
+    /// every request of that session.
+    pub user_agents: Vec<String>,
+    pub redirect: RedirectPolicy,
+    pub cookie_store: bool,
+    /// Refuse `socks5://` proxies instead of upgrading them to `socks5h://`.
+    pub strict_proxy: bool,
+    /// Stream isolation credentials, `None` shares circuits with everything else.
+    pub socks_auth: Option<SocksAuth>,
+    /// Retry policy for idempotent requests, shared by every call site.
+    pub retry: RetryPolicy,
+    /// Debug logging of requests, credentials are always redacted.
+    pub http_log: HttpLog,
+    /// Request pacing, `None` sends as fast as possible.
+    pub rate_limit: Option<RateLimit>,
+    /// Certificates or keys https mirrors must present. A mismatch fails
+    /// the handshake of every request to that mirror, before it is sent.
+    pub tls_pins: Vec<TlsPin>,
+    /// Whether clearnet urls may be requested at all. When false, the
+    /// transport refuses them before connecting. `None` allows them only
+    /// when `target_url` or one of the mirrors is a clearnet url.
+    pub allow_clearnet: Option<bool>,
+    pub protocol: Protocol,
+    /// Shared by every request of the session, mirrors included.
+    pub pool: Pool,
+    /// Largest response body read, in bytes.
+    pub max_body_size: usize,
+    /// Largest file `post::post_with_upload` sends, in bytes.
+    pub max_upload_size: u64,
+    /// Longest message posted at once, in characters, see `post::split_message`.
+    pub max_message_len: usize,
+    /// Most parts a long message is split into.
+    pub max_message_parts: usize,
+    /// How messages with line breaks are posted.
+    pub multi_line: MultiLine,
+    /// When a post whose answer timed out is sent again, see
+    /// `post::send_post`.
+    pub replay_guard: ReplayGuard,
+    /// Shortcodes expanded in posts, and emoji shown as shortcodes.
+    pub emoji: EmojiConfig,
+    /// The offset the server prints message times in, UTC by default.
+    pub server_utc_offset: FixedOffset,
+    /// What counts as a mention of the session's nick.
+    pub mention: MentionConfig,
+    /// What posting does with messages the chat's known filters would
+    /// change, see `Filters::set`.
+    pub filter_check: FilterCheck,
+    /// Record request counts and latencies, see `Metrics::snapshot`.
+    pub metrics: bool,
+    /// Other base urls of the chat the client is used with, see
+    /// `allow_clearnet`.
+    pub mirrors: Vec<String>,
+    /// Protocol options for some mirrors, by base url.
+    pub mirror_protocols: HashMap<String, Protocol>,
+    /// Record every request and response of the client, redacted, see
+    /// `capture`.
+    pub capture: Option<CaptureConfig>,
+    /// Keep the messages streamed, searchable with `history::search`.
+    pub history: Option<HistoryConfig>,
+    /// Where the read markers of the profile are kept across restarts, see
+    /// `unread::mark_read`. Without, they last as long as the client.
+    pub read_markers: Option<PathBuf>,
+    /// The lighter message feeds probed after login, see `feed::probe`.
+    pub feed_endpoints: Vec<FeedEndpoint>,
+    /// Old nicks and the one their private messages are listed under, see
+    /// `conversations::conversation`.
+    pub pm_aliases: HashMap<String, String>,
+    /// Where the profile's messages that failed to send are kept until
+    /// sent again, see `outbox`. Without, a failed post is only an error.
+    pub outbox: Option<PathBuf>,
+    /// Where the profile's scheduled messages are kept, see
+    /// `schedule::schedule_message`. Without, nothing can be scheduled.
+    pub schedule: Option<PathBuf>,
+    /// Delete our own messages as they get old, see `purge`. Off by
+    /// default.
+    pub auto_purge: Option<PurgeConfig>,
+    /// Aliases and colors the profile shows nicks with, see
+    /// `display::DisplayOverrides`. Rules that don't compile are left out.
+    pub display: Vec<DisplayRule>,
+    /// The image host `share::share_file` uploads to. One that can't be
+    /// used is left out.
+    pub share: Option<ShareTarget>,
+    /// Where the captcha cache, templates and stats are kept, and the pages
+    /// the login couldn't read saved. Without, the
+    /// library writes no file of its own.
+    pub data_dir: Option<PathBuf>,
+    /// The language of the chat's pages, sent with every form and page
+    /// asked. Their messages are read in English, see `LANG`.
+    pub lang: String,
+    /// A TOML file of the strings shown to the user in another language,
+    /// see `catalog::Catalog`. Without, they are English.
+    pub catalog: Option<PathBuf>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            proxy: ProxySetting::Auto,
+            target_url: None,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            deadline: Some(DEFAULT_DEADLINE),
+            user_agents: vec![DEFAULT_USER_AGENT.to_owned()],
+            redirect: RedirectPolicy::None,
+            cookie_store: true,
+            strict_proxy: false,
+            socks_auth: None,
+            retry: RetryPolicy::default(),
+            http_log: HttpLog::Off,
+            rate_limit: Some(RateLimit::default()),
+            tls_pins: vec![],
+            allow_clearnet: None,
+            protocol: Protocol::default(),
+            pool: Pool::default(),
+            max_body_size: transport::DEFAULT_MAX_BODY_SIZE,
+            max_upload_size: post::DEFAULT_MAX_UPLOAD_SIZE,
+            max_message_len: post::DEFAULT_MAX_MESSAGE_LEN,
+            max_message_parts: post::DEFAULT_MAX_MESSAGE_PARTS,
+            multi_line: MultiLine::default(),
+            replay_guard: ReplayGuard::default(),
+            emoji: EmojiConfig::default(),
+            server_utc_offset: FixedOffset::east_opt(0).unwrap(),
+            mention: MentionConfig::default(),
+            filter_check: FilterCheck::default(),
+            metrics: true,
+            mirrors: vec![],
+            mirror_protocols: HashMap::new(),
+            capture: None,
+            history: None,
+            read_markers: None,
+            feed_endpoints: feed::default_endpoints(),
+            pm_aliases: HashMap::new(),
+            outbox: None,
+            schedule: None,
+            auto_purge: None,
+            display: vec![],
+            share: None,
+            data_dir: None,
+            lang: LANG.to_owned(),
+            catalog: None,
+        }
+    }
+}
+
+impl ClientConfig {
+    /// A builder starting from the defaults.
+    ///
+    /// ```
+    /// use bhcli::lechatphp::client::{ClientConfig, ProxySetting};
+    /// use std::time::Duration;
+    ///
+    /// # fn main() -> Result<(), bhcli::lechatphp::client::ConfigError> {
+    /// let config = ClientConfig::builder()
+    ///     .proxy(ProxySetting::Url("socks5h://127.0.0.1:9050".to_owned()))
+    ///     .timeout(Duration::from_secs(60))
+    ///     .build()?;
+    /// assert_eq!(config.read_timeout, Duration::from_secs(60));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn builder() -> ClientConfigBuilder {
+        ClientConfigBuilder::default()
+    }
+
+    /// A builder starting from this config.
+    pub fn to_builder(&self) -> ClientConfigBuilder {
+        ClientConfigBuilder { config: self.clone() }
+    }
+
+    /// `allow_clearnet`, or when unset whether a configured url is clearnet.
+    /// With only onion urls, or none at all, clearnet is refused.
+    pub fn clearnet_allowed(&self) -> bool {
+        self.allow_clearnet.unwrap_or_else(|| self.urls().any(onion::is_clearnet))
+    }
+
+    // The target, the mirrors and those with their own protocol
+    fn urls(&self) -> impl Iterator<Item = &str> {
+        self.target_url.iter().chain(&self.mirrors).chain(self.mirror_protocols.keys()).map(String::as_str)
+    }
+}
+
+/// Builds a `ClientConfig`, checked by `validate`. Each setter sets the
+/// field of the same name.
+#[derive(Debug, Clone, Default)]
+pub struct ClientConfigBuilder {
+    config: ClientConfig,
+}
+
+impl ClientConfigBuilder {
+    /// Both the connect and the read timeout.
+    pub fn timeout(self, timeout: Duration) -> Self {
+        self.connect_timeout(timeout).read_timeout(timeout)
+    }
+
+    pub fn proxy(mut self, proxy: ProxySetting) -> Self {
+        self.config.proxy = proxy;
+        self
+    }
+
+    pub fn target_url(mut self, target_url: Option<String>) -> Self {
+        self.config.target_url = target_url;
+        self
+    }
+
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.config.connect_timeout = connect_timeout;
+        self
+    }
+
+    pub fn read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.config.read_timeout = read_timeout;
+        self
+    }
+
+    pub fn deadline(mut self, deadline: Option<Duration>) -> Self {
+        self.config.deadline = deadline;
+        self
+    }
+
+    pub fn user_agents(mut self, user_agents: Vec<String>) -> Self {
+        self.config.user_agents = user_agents;
+        self
+    }
+
+    pub fn redirect(mut self, redirect: RedirectPolicy) -> Self {
+        self.config.redirect = redirect;
+        self
+    }
+
+    pub fn cookie_store(mut self, cookie_store: bool) -> Self {
+        self.config.cookie_store = cookie_store;
+        self
+    }
+
+    pub fn strict_proxy(mut self, strict_proxy: bool) -> Self {
+        self.config.strict_proxy = strict_proxy;
+        self
+    }
+
+    pub fn socks_auth(mut self, socks_auth: Option<SocksAuth>) -> Self {
+        self.config.socks_auth = socks_auth;
+        self
+    }
+
+    pub fn retry(mut self, retry: RetryPolicy) -> Self {
+        self.config.retry = retry;
+        self
+    }
+
+    pub fn http_log(mut self, http_log: HttpLog) -> Self {
+        self.config.http_log = http_log;
+        self
+    }
+
+    pub fn rate_limit(mut self, rate_limit: Option<RateLimit>) -> Self {
+        self.config.rate_limit = rate_limit;
+        self
+    }
+
+    pub fn tls_pins(mut self, tls_pins: Vec<TlsPin>) -> Self {
+        self.config.tls_pins = tls_pins;
+        self
+    }
+
+    pub fn allow_clearnet(mut self, allow_clearnet: Option<bool>) -> Self {
+        self.config.allow_clearnet = allow_clearnet;
+        self
+    }
+
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.config.protocol = protocol;
+        self
+    }
+
+    pub fn pool(mut self, pool: Pool) -> Self {
+        self.config.pool = pool;
+        self
+    }
+
+    pub fn max_body_size(mut self, max_body_size: usize) -> Self {
+        self.config.max_body_size = max_body_size;
+        self
+    }
+
+    pub fn max_upload_size(mut self, max_upload_size: u64) -> Self {
+        self.config.max_upload_size = max_upload_size;
+        self
+    }
+
+    pub fn max_message_len(mut self, max_message_len: usize) -> Self {
+        self.config.max_message_len = max_message_len;
+        self
+    }
+
+    pub fn max_message_parts(mut self, max_message_parts: usize) -> Self {
+        self.config.max_message_parts = max_message_parts;
+        self
+    }
+
+    pub fn multi_line(mut self, multi_line: MultiLine) -> Self {
+        self.config.multi_line = multi_line;
+        self
+    }
+
+    pub fn replay_guard(mut self, replay_guard: ReplayGuard) -> Self {
+        self.config.replay_guard = replay_guard;
+        self
+    }
+
+    pub fn emoji(mut self, emoji: EmojiConfig) -> Self {
+        self.config.emoji = emoji;
+        self
+    }
+
+    pub fn server_utc_offset(mut self, server_utc_offset: FixedOffset) -> Self {
+        self.config.server_utc_offset = server_utc_offset;
+        self
+    }
+
+    pub fn mention(mut self, mention: MentionConfig) -> Self {
+        self.config.mention = mention;
+        self
+    }
+
+    pub fn filter_check(mut self, filter_check: FilterCheck) -> Self {
+        self.config.filter_check = filter_check;
+        self
+    }
+
+    pub fn metrics(mut self, metrics: bool) -> Self {
+        self.config.metrics = metrics;
+        self
+    }
+
+    pub fn mirrors(mut self, mirrors: Vec<String>) -> Self {
+        self.config.mirrors = mirrors;
+        self
+    }
+
+    pub fn mirror_protocols(mut self, mirror_protocols: HashMap<String, Protocol>) -> Self {
+        self.config.mirror_protocols = mirror_protocols;
+        self
+    }
+
+    pub fn capture(mut self, capture: Option<CaptureConfig>) -> Self {
+        self.config.capture = capture;
+        self
+    }
+
+    pub fn history(mut self, history: Option<HistoryConfig>) -> Self {
+        self.config.history = history;
+        self
+    }
+
+    pub fn read_markers(mut self, read_markers: Option<PathBuf>) -> Self {
+        self.config.read_markers = read_markers;
+        self
+    }
+
+    pub fn feed_endpoints(mut self, feed_endpoints: Vec<FeedEndpoint>) -> Self {
+        self.config.feed_endpoints = feed_endpoints;
+        self
+    }
+
+    pub fn pm_aliases(mut self, pm_aliases: HashMap<String, String>) -> Self {
+        self.config.pm_aliases = pm_aliases;
+        self
+    }
+
+    pub fn outbox(mut self, outbox: Option<PathBuf>) -> Self {
+        self.config.outbox = outbox;
+        self
+    }
+
+    pub fn schedule(mut self, schedule: Option<PathBuf>) -> Self {
+        self.config.schedule = schedule;
+        self
+    }
+
+    pub fn auto_purge(mut self, auto_purge: Option<PurgeConfig>) -> Self {
+        self.config.auto_purge = auto_purge;
+        self
+    }
+
+    pub fn display(mut self, display: Vec<DisplayRule>) -> Self {
+        self.config.display = display;
+        self
+    }
+
+    pub fn share(mut self, share: Option<ShareTarget>) -> Self {
+        self.config.share = share;
+        self
+    }
+
+    pub fn data_dir(mut self, data_dir: Option<PathBuf>) -> Self {
+        self.config.data_dir = data_dir;
+        self
+    }
+
+    pub fn lang(mut self, lang: impl Into<String>) -> Self {
+        self.config.lang = lang.into();
+        self
+    }
+
+    pub fn catalog(mut self, catalog: Option<PathBuf>) -> Self {
+        self.config.catalog = catalog;
+        self
+    }
+
+    pub fn build(self) -> Result<ClientConfig, ConfigError> {
+        validate(&self.config)?;
+        Ok(self.config)
+    }
+}
+
+/// A config its builder, or `validate`, refused.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ConfigError {
+    #[error("invalid proxy url: {0}, {ACCEPTED_PROXIES}")]
+    InvalidProxyUrl(String),
+    /// `socks5://` resolves hostnames locally, outside Tor.
+    #[error("{0} resolves hostnames outside Tor, use socks5h:// instead ({ACCEPTED_PROXIES})")]
+    LocalDnsProxy(String),
+    /// An http(s) proxy for an onion target.
+    #[error("{0} can't reach onion addresses, use a socks5h:// proxy ({ACCEPTED_PROXIES})")]
+    HttpProxyForOnion(String),
+    #[error("invalid proxy chain: {0}")]
+    InvalidProxyChain(&'static str),
+    #[error("{0} timeout must be greater than zero")]
+    ZeroTimeout(&'static str),
+    #[error("retry attempts must be greater than zero")]
+    ZeroRetryAttempts,
+    #[error("max body size must be greater than zero")]
+    ZeroMaxBodySize,
+    #[error("at least one non empty user agent is required")]
+    NoUserAgent,
+    #[error("rate limit rate and burst must be greater than zero")]
+    InvalidRateLimit,
+    #[error("invalid mirror url: {0}")]
+    InvalidMirrorUrl(String),
+    #[error("{0}")]
+    Clearnet(#[from] UrlErr),
+    /// Not a six digit hex color, see `LoginOptions::color`.
+    #[error("invalid nick color: {0:?}, expected six hex digits like 9ACD32")]
+    InvalidColor(String),
+    #[error("the language must not be empty")]
+    EmptyLang,
+    /// The captcha solver was given no character to answer with.
+    #[error("the captcha alphabet must not be empty")]
+    EmptyAlphabet,
+    #[error("captcha ink margin must be a number of at least zero, not {0}")]
+    InvalidInkMargin(f32),
+}
+
+impl Classify for ConfigError {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            ConfigError::Clearnet(e) => e.kind(),
+            ConfigError::InvalidProxyUrl(_)
+            | ConfigError::LocalDnsProxy(_)
+            | ConfigError::HttpProxyForOnion(_)
+            | ConfigError::InvalidProxyChain(_)
+            | ConfigError::ZeroTimeout(_)
+            | ConfigError::ZeroRetryAttempts
+            | ConfigError::ZeroMaxBodySize
+            | ConfigError::NoUserAgent
+            | ConfigError::InvalidRateLimit
+            | ConfigError::InvalidMirrorUrl(_)
+            | ConfigError::InvalidColor(_)
+            | ConfigError::EmptyLang
+            | ConfigError::EmptyAlphabet
+            | ConfigError::InvalidInkMargin(_) => ErrorKind::InvalidInput,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum BuildErr {
+    #[error("{0}")]
+    Config(#[from] ConfigError),
+    #[error("no Tor SOCKS proxy found (checked ALL_PROXY, {}), is tor running? Or pass --socks-proxy-url", .0.join(", "))]
+    ProxyNotFound(Vec<String>),
+    #[error("{0}")]
+    Tls(#[from] TlsErr),
+    #[error("{0}")]
+    Catalog(#[from] CatalogErr),
+    #[error("{0}")]
+    Reqwest(#[from] reqwest::Error),
+}
+
+impl Classify for BuildErr {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            BuildErr::Config(e) => e.kind(),
+            BuildErr::Tls(e) => e.kind(),
+            BuildErr::Catalog(e) => e.kind(),
+            BuildErr::ProxyNotFound(_) | BuildErr::Reqwest(_) => ErrorKind::Fatal,
+        }
+    }
+}
+
+/// Check the config without building anything.
+pub fn validate(config: &ClientConfig) -> Result<(), ConfigError> {
+    if config.connect_timeout.is_zero() {
+        return Err(ConfigError::ZeroTimeout("connect"));
+    }
+    if config.read_timeout.is_zero() {
+        return Err(ConfigError::ZeroTimeout("read"));
+    }
+    if config.deadline.is_some_and(|d| d.is_zero()) {
+        return Err(ConfigError::ZeroTimeout("deadline"));
+    }
+    if config.pool.idle_timeout.is_some_and(|d| d.is_zero()) {
+        return Err(ConfigError::ZeroTimeout("pool idle"));
+    }
+    if config.pool.tcp_keepalive.is_some_and(|d| d.is_zero()) {
+        return Err(ConfigError::ZeroTimeout("tcp keep-alive"));
+    }
+    if config.retry.max_attempts == 0 {
+        return Err(ConfigError::ZeroRetryAttempts);
+    }
+    if config.max_body_size == 0 {
+        return Err(ConfigError::ZeroMaxBodySize);
+    }
+    if let Some(limit) = config.rate_limit {
+        if [limit.reads, limit.writes].iter().any(|b| b.rate.is_nan() || b.rate <= 0.0 || b.burst == 0) {
+            return Err(ConfigError::InvalidRateLimit);
+        }
+    }
+    if config.user_agents.is_empty() || config.user_agents.iter().any(|ua| ua.trim().is_empty()) {
+        return Err(ConfigError::NoUserAgent);
+    }
+    match &config.proxy {
+        ProxySetting::Url(proxy_url) => {
+            normalize_proxy_url(proxy_url, config.strict_proxy, is_known_onion(config.target_url.as_deref()))?;
+        }
+        ProxySetting::Chain(chain) => {
+            split_chain(chain, config)?;
+        }
+        ProxySetting::Direct | ProxySetting::Auto => {}
+    }
+    for url in config.urls() {
+        onion::check_clearnet(url, config.clearnet_allowed())?;
+    }
+    if let Some(url) = config.mirror_protocols.keys().find(|url| Transport::host_key(url).is_none()) {
+        return Err(ConfigError::InvalidMirrorUrl(url.to_owned()));
+    }
+    Ok(())
+}
+
+/// The proxy url to connect with. `socks5://` becomes `socks5h://` so
+/// hostnames are resolved by Tor, unless `strict_proxy` refuses it, and
+/// http(s) proxies are refused for onion targets.
+fn normalize_proxy_url(proxy_url: &str, strict_proxy: bool, for_onion: bool) -> Result<String, ConfigError> {
+    let mut url = Url::parse(proxy_url)
+        .ok()
+        .filter(|url| PROXY_SCHEMES.contains(&url.scheme()) && url.host_str().is_some())
+        .ok_or_else(|| ConfigError::InvalidProxyUrl(proxy_url.to_owned()))?;
+    let scheme = url.scheme().to_owned();
+    match scheme.as_str() {
+        "socks5" if strict_proxy => Err(ConfigError::LocalDnsProxy(proxy_url.to_owned())),
+        "socks5" => {
+            let _ = url.set_scheme("socks5h");
+            Ok(url.to_string())
+        }
+        "http" | "https" if for_onion => {
+            Err(ConfigError::HttpProxyForOnion(proxy_url.to_owned()))
+        }
+        _ => Ok(proxy_url.to_owned()),
+    }
+}
+
+/// Whether a SOCKS5 server answers on `addr`. This sends a real greeting, so
+/// another service on the port doesn't count.
+pub fn probe_socks5(addr: &str, timeout: Duration) -> bool {
+    let addr = match addr.to_socket_addrs().ok().and_then(|mut addrs| addrs.next()) {
+        Some(addr) => addr,
+        None => return false,
+    };
+    let mut stream = match TcpStream::connect_timeout(&addr, timeout) {
+        Ok(stream) => stream,
+        Err(_) => return false,
+    };
+    let _ = stream.set_read_timeout(Some(timeout));
+    let _ = stream.set_write_timeout(Some(timeout));
+    // Version 5, offering no auth and username/password
+    if stream.write_all(&[5, 2, 0, 2]).is_err() {
+        return false;
+    }
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).is_ok() && reply[0] == 5 && reply[1] != 0xff
+}
+
+fn env_proxy() -> Option<String> {
+    env::var("ALL_PROXY").or_else(|_| env::var("all_proxy")).ok().filter(|url| !url.trim().is_empty())
+}
+
+fn is_known_onion(target_url: Option<&str>) -> bool {
+    target_url.is_some() && is_onion_target(target_url)
+}
+
+fn is_onion_target(target_url: Option<&str>) -> bool {
+    target_url
+        .and_then(|url| Url::parse(url).ok())
+        .and_then(|url| url.host_str().map(|host| host.to_ascii_lowercase()))
+        .is_none_or(|host| host.ends_with(".onion"))
+}
+
+fn upgrade_notice(proxy_url: &str, normalized: &str) {
+    if normalized != proxy_url {
+        log::warn!("proxy {} would resolve hostnames outside Tor, using {} instead", proxy_url, normalized);
+    }
+}
+
+fn normalize_with_notice(proxy_url: &str, strict_proxy: bool, for_onion: bool) -> Result<String, ConfigError> {
+    let normalized = normalize_proxy_url(proxy_url, strict_proxy, for_onion)?;
+    upgrade_notice(proxy_url, &normalized);
+    Ok(normalized)
+}
+
+/// Tor's SOCKS proxy and the upstream proxy of a chain.
+fn split_chain(chain: &[String], config: &ClientConfig) -> Result<(String, Option<String>), ConfigError> {
+    let (tor, upstream) = match chain {
+        [tor] => (tor, None),
+        [upstream, tor] => (tor, Some(upstream)),
+        _ => return Err(ConfigError::InvalidProxyChain("expected Tor's SOCKS proxy, optionally after one upstream proxy")),
+    };
+    let tor = normalize_proxy_url(tor, config.strict_proxy, true)?;
+    if !tor.starts_with("socks5h://") {
+        return Err(ConfigError::InvalidProxyChain("the last proxy must be Tor's SOCKS proxy"));
+    }
+    let upstream = match upstream {
+        Some(_) if !config.clearnet_allowed() => {
+            return Err(ConfigError::InvalidProxyChain("the upstream proxy only carries clearnet requests, which are not allowed"));
+        }
+        // Never used for onion hosts, so http is fine
+        Some(upstream) => Some(normalize_proxy_url(upstream, config.strict_proxy, false)?),
+        None => None,
+    };
+    if upstream.as_ref() == Some(&tor) {
+        return Err(ConfigError::InvalidProxyChain("the upstream proxy is Tor's SOCKS proxy"));
+    }
+    Ok((tor, upstream))
+}
+
+/// Which proxy of a chain carries a request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Hop {
+    Tor,
+    Upstream,
+}
+
+fn hop(url: &Url, has_upstream: bool) -> Hop {
+    if has_upstream && onion::is_clearnet(url.as_str()) {
+        Hop::Upstream
+    } else {
+        Hop::Tor
+    }
+}
+
+/// The proxy to use, `None` for a direct connection. An explicit proxy
+/// always wins over detection. For a chain, this is its Tor proxy.
+fn resolve_proxy(config: &ClientConfig, env_proxy: Option<String>, detect_addrs: &[&str]) -> Result<Option<String>, BuildErr> {
+    let for_onion = is_known_onion(config.target_url.as_deref());
+    match &config.proxy {
+        ProxySetting::Direct => Ok(None),
+        ProxySetting::Url(url) => Ok(Some(normalize_with_notice(url, config.strict_proxy, for_onion)?)),
+        ProxySetting::Chain(chain) => {
+            let (tor, upstream) = split_chain(chain, config)?;
+            for (configured, normalized) in chain.iter().rev().zip(std::iter::once(&tor).chain(upstream.as_ref())) {
+                upgrade_notice(configured, normalized);
+            }
+            Ok(Some(tor))
+        }
+        ProxySetting::Auto => {
+            if let Some(url) = env_proxy {
+                return Ok(Some(normalize_with_notice(&url, config.strict_proxy, for_onion)?));
+            }
+            if let Some(addr) = detect_addrs.iter().find(|addr| probe_socks5(addr, PROBE_TIMEOUT)) {
+                return Ok(Some(format!("socks5h://{}", addr)));
+            }
+            if is_onion_target(config.target_url.as_deref()) {
+                return Err(BuildErr::ProxyNotFound(detect_addrs.iter().map(|a| a.to_string()).collect()));
+            }
+            log::warn!("no proxy found, connecting directly");
+            Ok(None)
+        }
+    }
+}
+
+fn redirect_policy(config: &ClientConfig) -> Policy {
+    match config.redirect {
+        RedirectPolicy::None => Policy::none(),
+        RedirectPolicy::Limited(max) if config.clearnet_allowed() => Policy::limited(max),
+        // Redirects must not lead out of onion-only mode either
+        RedirectPolicy::Limited(max) => Policy::custom(move |attempt| {
+            if onion::is_clearnet(attempt.url().as_str()) {
+                let err = UrlErr::Clearnet(attempt.url().to_string());
+                attempt.error(err)
+            } else if attempt.previous().len() > max {
+                attempt.error("too many redirects")
+            } else {
+                attempt.follow()
+            }
+        }),
+    }
+}
+
+/// Build a blocking client from the config, validating it first.
+/// The rest of the config, its retry policy, limits, TLS pins and stores,
+/// goes with the client, see `Transport::settings`.
+/// Each client is one session, so it gets a single User-Agent from the pool.
+pub fn build(config: &ClientConfig) -> Result<Transport, BuildErr> {
+    validate(config)?;
+    let pins = config.tls_pins.iter().map(tls::load).collect::<Result<Vec<_>, _>>()?;
+    let catalog = config.catalog.as_deref().map(Catalog::load).transpose()?;
+    let proxy_url = resolve_proxy(config, env_proxy(), &DETECT_ADDRS)?;
+    let upstream = match &config.proxy {
+        ProxySetting::Chain(chain) => split_chain(chain, config)?.1,
+        _ => None,
+    };
+    let user_agent = config.user_agents.choose(&mut thread_rng()).unwrap();
+    // One jar for every route, so cookies follow the session across mirrors
+    let jar = config.cookie_store.then(|| Arc::new(Jar::default()));
+
+    // Mirrors with their own protocol options or a pin get their own client,
+    // the rest is the same for all
+    let build_client = |protocol: &Protocol, pin: Option<&LoadedPin>| -> Result<Client, BuildErr> {
+        // Protocol last, it can turn reuse off
+        let mut builder = protocol
+            .apply(config.pool.apply(reqwest::blocking::ClientBuilder::new()))
+            .redirect(redirect_policy(config))
+            .user_agent(user_agent.as_str())
+            .connect_timeout(config.connect_timeout)
+            .timeout(config.read_timeout)
+            // The peer certificate is needed to check the pins
+            .tls_info(!pins.is_empty());
+        if let Some(pin) = pin {
+            builder = builder.use_preconfigured_tls(pin.tls_config(protocol.http1_only));
+        }
+        if let Some(jar) = &jar {
+            builder = builder.cookie_provider(Arc::clone(jar));
+        }
+        match &proxy_url {
+            Some(proxy_url) => {
+                // Checked first, it takes the clearnet hosts
+                if let Some(upstream) = &upstream {
+                    let upstream = Url::parse(upstream).map_err(|_| ConfigError::InvalidProxyUrl(upstream.to_owned()))?;
+                    let proxy = reqwest::Proxy::custom(move |url| {
+                        (hop(url, true) == Hop::Upstream).then(|| upstream.clone())
+                    });
+                    builder = builder.proxy(proxy);
+                }
+                let mut proxy = reqwest::Proxy::all(proxy_url)?;
+                if let Some(auth) = &config.socks_auth {
+                    proxy = proxy.basic_auth(&auth.username, &auth.password);
+                }
+                builder = builder.proxy(proxy);
+            }
+            // Not even the system proxy settings
+            None => builder = builder.no_proxy(),
+        }
+        Ok(builder.build()?)
+    };
+    let mut transport = Transport::new(build_client(&config.protocol, None)?, config.protocol, !config.clearnet_allowed());
+    for (base_url, protocol) in &config.mirror_protocols {
+        transport.add_mirror(base_url, build_client(protocol, None)?, *protocol);
+    }
+    // Last, a pinned mirror's client replaces the one for its protocol
+    for (pin, loaded) in config.tls_pins.iter().zip(&pins) {
+        let host = Transport::host_key(&pin.base_url);
+        let protocol = config
+            .mirror_protocols
+            .iter()
+            .find(|(base_url, _)| Transport::host_key(base_url) == host)
+            .map_or(config.protocol, |(_, protocol)| *protocol);
+        transport.add_mirror(&pin.base_url, build_client(&protocol, Some(loaded))?, protocol);
+    }
+    transport.set_proxy(proxy_url.as_deref());
+    transport.set_jar(jar);
+    transport.set_lang(&config.lang);
+    transport.set_settings(Settings::new(config, &pins, catalog));
+    Ok(transport)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lechatphp::mock::{MockResponse, MockServer};
+    use crate::lechatphp::rate_limit::Budget;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn validate_test() {
+        assert!(validate(&ClientConfig::default()).is_ok());
+
+        let config = ClientConfig { proxy: ProxySetting::Url("127.0.0.1:9050".to_owned()), ..Default::default() };
+        assert!(matches!(validate(&config), Err(ConfigError::InvalidProxyUrl(_))));
+
+        let config = ClientConfig { read_timeout: Duration::ZERO, ..Default::default() };
+        assert!(matches!(validate(&config), Err(ConfigError::ZeroTimeout("read"))));
+
+        let config = ClientConfig { proxy: ProxySetting::Direct, ..Default::default() };
+        assert!(build(&config).is_ok());
+
+        let config = ClientConfig {
+            target_url: Some("https://example.com".to_owned()),
+            allow_clearnet: Some(false),
+            ..Default::default()
+        };
+        assert!(matches!(validate(&config), Err(ConfigError::Clearnet(_))));
+        let config = ClientConfig { mirrors: vec!["http://example.com".to_owned()], ..config };
+        assert!(matches!(validate(&config), Err(ConfigError::Clearnet(_))));
+    }
+
+    #[test]
+    fn clearnet_allowed_test() {
+        let onion = Some("http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion".to_owned());
+        let clearnet = "https://example.com".to_owned();
+        let allowed = |config: ClientConfig| config.clearnet_allowed();
+        assert!(!allowed(ClientConfig::default()));
+        assert!(!allowed(ClientConfig { target_url: onion.clone(), ..Default::default() }));
+        assert!(allowed(ClientConfig { target_url: Some(clearnet.clone()), ..Default::default() }));
+        assert!(allowed(ClientConfig { target_url: onion.clone(), mirrors: vec![clearnet.clone()], ..Default::default() }));
+        let mirror_protocols = HashMap::from([(clearnet.clone(), Protocol::default())]);
+        assert!(allowed(ClientConfig { target_url: onion.clone(), mirror_protocols, ..Default::default() }));
+        assert!(allowed(ClientConfig { target_url: onion, allow_clearnet: Some(true), ..Default::default() }));
+        assert!(!allowed(ClientConfig { target_url: Some(clearnet), allow_clearnet: Some(false), ..Default::default() }));
+    }
+
+    #[test]
+    fn builder_test() {
+        let config = ClientConfig::builder().build().unwrap();
+        assert_eq!(format!("{:?}", config), format!("{:?}", ClientConfig::default()));
+        let config = ClientConfig::builder().timeout(Duration::from_secs(7)).lang("de").build().unwrap();
+        assert_eq!((config.connect_timeout, config.read_timeout), (Duration::from_secs(7), Duration::from_secs(7)));
+        assert_eq!(config.to_builder().build().unwrap().lang, "de");
+
+        let onion = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion".to_owned();
+        let proxy = |url: &str| ProxySetting::Url(url.to_owned());
+        let zero = Some(Duration::ZERO);
+        let no_rate = RateLimit { reads: Budget { rate: 0.0, burst: 1 }, ..Default::default() };
+        // A builder and the error it must fail with
+        type Case = (ClientConfigBuilder, fn(&ConfigError) -> bool);
+        let cases: Vec<Case> = vec![
+            (ClientConfig::builder().proxy(proxy("127.0.0.1:9050")), |e| matches!(e, ConfigError::InvalidProxyUrl(_))),
+            (
+                ClientConfig::builder().proxy(proxy("socks5://127.0.0.1:9050")).strict_proxy(true),
+                |e| matches!(e, ConfigError::LocalDnsProxy(_)),
+            ),
+            (
+                ClientConfig::builder().proxy(proxy("http://127.0.0.1:8118")).target_url(Some(onion.clone())),
+                |e| matches!(e, ConfigError::HttpProxyForOnion(_)),
+            ),
+            (ClientConfig::builder().proxy(ProxySetting::Chain(vec![])), |e| matches!(e, ConfigError::InvalidProxyChain(_))),
+            (ClientConfig::builder().connect_timeout(Duration::ZERO), |e| matches!(e, ConfigError::ZeroTimeout("connect"))),
+            (ClientConfig::builder().timeout(Duration::ZERO), |e| matches!(e, ConfigError::ZeroTimeout("connect"))),
+            (ClientConfig::builder().read_timeout(Duration::ZERO), |e| matches!(e, ConfigError::ZeroTimeout("read"))),
+            (ClientConfig::builder().deadline(zero), |e| matches!(e, ConfigError::ZeroTimeout("deadline"))),
+            (
+                ClientConfig::builder().pool(Pool { idle_timeout: zero, ..Default::default() }),
+                |e| matches!(e, ConfigError::ZeroTimeout("pool idle")),
+            ),
+            (
+                ClientConfig::builder().pool(Pool { tcp_keepalive: zero, ..Default::default() }),
+                |e| matches!(e, ConfigError::ZeroTimeout("tcp keep-alive")),
+            ),
+            (
+                ClientConfig::builder().retry(RetryPolicy { max_attempts: 0, ..Default::default() }),
+                |e| matches!(e, ConfigError::ZeroRetryAttempts),
+            ),
+            (ClientConfig::builder().max_body_size(0), |e| matches!(e, ConfigError::ZeroMaxBodySize)),
+            (ClientConfig::builder().rate_limit(Some(no_rate)), |e| matches!(e, ConfigError::InvalidRateLimit)),
+            (ClientConfig::builder().user_agents(vec![" ".to_owned()]), |e| matches!(e, ConfigError::NoUserAgent)),
+            (
+                ClientConfig::builder().target_url(Some("https://example.com".to_owned())).allow_clearnet(Some(false)),
+                |e| matches!(e, ConfigError::Clearnet(_)),
+            ),
+            (
+                ClientConfig::builder().mirror_protocols(HashMap::from([("not a url".to_owned(), Protocol::default())])),
+                |e| matches!(e, ConfigError::InvalidMirrorUrl(_)),
+            ),
+        ];
+        for (i, (builder, expected)) in cases.into_iter().enumerate() {
+            match builder.build() {
+                Err(e) => assert!(expected(&e), "case {}: unexpected {:?}", i, e),
+                Ok(config) => panic!("case {}: accepted {:?}", i, config),
+            }
+        }
+    }
+
+    #[test]
+    fn user_agent_test() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_clone = Arc::clone(&seen);
+        let server = MockServer::start(move |req| {
+            seen_clone.lock().unwrap().push(req.header("user-agent").unwrap_or_default());
+            if req.method == "POST" {
+                MockResponse::ok(r#"<iframe name="view" src="chat.php?action=view&session=ua-test&lang=en"></iframe>"#)
+            } else {
+                MockResponse::ok("<form></form>")
+            }
+        });
+        let pool = vec!["ua-one".to_owned(), "ua-two".to_owned()];
+        let config = ClientConfig { proxy: ProxySetting::Direct, target_url: Some(server.url.clone()), user_agents: pool.clone(), ..Default::default() };
+        let transport = build(&config).unwrap();
+
+        crate::lechatphp::login(&transport, &server.url, "chat.php", "nick", "pass", "", true).unwrap();
+        crate::lechatphp::logout(&transport, &server.url, "chat.php", "ua-test").unwrap();
+        transport.send(transport.get(&server.url)).unwrap();
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.len(), 4);
+        assert!(pool.contains(&seen[0]));
+        assert!(seen.iter().all(|ua| *ua == seen[0]));
+
+        let config = ClientConfig { user_agents: vec![], ..Default::default() };
+        assert!(matches!(validate(&config), Err(ConfigError::NoUserAgent)));
+    }
+
+    #[test]
+    fn resolve_proxy_test() {
+        use std::net::TcpListener;
+        use std::thread;
+
+        // Answers the SOCKS5 greeting like tor does
+        let socks = TcpListener::bind("127.0.0.1:0").unwrap();
+        let socks_addr = socks.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for mut stream in socks.incoming().flatten() {
+                let mut greeting = [0u8; 4];
+                let _ = stream.read_exact(&mut greeting);
+                let _ = stream.write_all(&[5, 0]);
+            }
+        });
+        // Something else listening, not a SOCKS server
+        let http = TcpListener::bind("127.0.0.1:0").unwrap();
+        let http_addr = http.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for mut stream in http.incoming().flatten() {
+                let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+            }
+        });
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+
+        let auto = ClientConfig::default();
+        let found = resolve_proxy(&auto, None, &[&closed, &http_addr, &socks_addr]).unwrap();
+        assert_eq!(found, Some(format!("socks5h://{}", socks_addr)));
+        let env = Some("socks5h://10.0.0.1:9050".to_owned());
+        assert_eq!(resolve_proxy(&auto, env.clone(), &[&socks_addr]).unwrap(), env);
+
+        // Explicit settings skip detection
+        let explicit = ClientConfig { proxy: ProxySetting::Url("socks5h://10.0.0.2:9050".to_owned()), ..Default::default() };
+        assert_eq!(resolve_proxy(&explicit, env, &[&socks_addr]).unwrap(), Some("socks5h://10.0.0.2:9050".to_owned()));
+        let direct = ClientConfig { proxy: ProxySetting::Direct, ..Default::default() };
+        assert_eq!(resolve_proxy(&direct, None, &[&socks_addr]).unwrap(), None);
+
+        let err = resolve_proxy(&auto, None, &[&closed, &http_addr]).unwrap_err();
+        assert!(matches!(&err, BuildErr::ProxyNotFound(checked) if checked.len() == 2));
+        assert!(err.to_string().contains(&http_addr));
+        let clearnet = ClientConfig { target_url: Some("https://example.com".to_owned()), ..Default::default() };
+        assert_eq!(resolve_proxy(&clearnet, None, &[&closed]).unwrap(), None);
+    }
+
+    #[test]
+    fn proxy_url_test() {
+        let onion = Some("http://example.onion".to_owned());
+        let clearnet = Some("https://example.com".to_owned());
+        // Proxy url, target, strict, normalized url or the kind of error
+        type Case<'a> = (&'a str, &'a Option<String>, bool, Result<&'a str, &'a str>);
+        let cases: [Case; 11] = [
+            ("socks5h://127.0.0.1:9050", &onion, false, Ok("socks5h://127.0.0.1:9050")),
+            ("socks5://127.0.0.1:9050", &onion, false, Ok("socks5h://127.0.0.1:9050")),
+            ("SOCKS5://user:pw@localhost:9150", &clearnet, false, Ok("socks5h://user:pw@localhost:9150")),
+            ("socks5://127.0.0.1:9050", &onion, true, Err("local dns")),
+            ("socks5h://127.0.0.1:9050", &onion, true, Ok("socks5h://127.0.0.1:9050")),
+            ("http://127.0.0.1:8118", &onion, false, Err("http")),
+            ("https://proxy.example:443", &onion, false, Err("http")),
+            ("http://127.0.0.1:8118", &clearnet, false, Ok("http://127.0.0.1:8118")),
+            ("http://127.0.0.1:8118", &None, false, Ok("http://127.0.0.1:8118")),
+            ("socks4://127.0.0.1:9050", &onion, false, Err("invalid")),
+            ("127.0.0.1:9050", &None, false, Err("invalid")),
+        ];
+        for (proxy_url, target_url, strict_proxy, expected) in cases {
+            let got = normalize_proxy_url(proxy_url, strict_proxy, is_known_onion(target_url.as_deref()));
+            match (expected, &got) {
+                (Ok(want), Ok(url)) => assert_eq!(url, want),
+                (Err("local dns"), Err(ConfigError::LocalDnsProxy(_)))
+                | (Err("http"), Err(ConfigError::HttpProxyForOnion(_)))
+                | (Err("invalid"), Err(ConfigError::InvalidProxyUrl(_))) => {}
+                _ => panic!("{} (strict {}): unexpected {:?}", proxy_url, strict_proxy, got),
+            }
+        }
+        let err = normalize_proxy_url("ftp://x", false, false).unwrap_err();
+        assert!(err.to_string().contains("socks5h://host:port"));
+
+        // ALL_PROXY goes through the same checks
+        let auto = ClientConfig { target_url: onion, ..Default::default() };
+        let env = Some("socks5://10.0.0.1:9050".to_owned());
+        assert_eq!(resolve_proxy(&auto, env, &[]).unwrap(), Some("socks5h://10.0.0.1:9050".to_owned()));
+        let env = Some("http://10.0.0.1:8118".to_owned());
+        assert!(matches!(resolve_proxy(&auto, env, &[]), Err(BuildErr::Config(ConfigError::HttpProxyForOnion(_)))));
+    }
+
+    #[test]
+    fn proxy_chain_test() {
+        let tor = "socks5h://127.0.0.1:9050";
+        let chain = |urls: &[&str]| urls.iter().map(|u| u.to_string()).collect::<Vec<_>>();
+        // The upstream proxy is for clearnet targets
+        let config = ClientConfig { target_url: Some("https://example.com".to_owned()), ..Default::default() };
+        assert_eq!(split_chain(&chain(&[tor]), &config).unwrap(), (tor.to_owned(), None));
+        let (_, upstream) = split_chain(&chain(&["http://proxy.corp:3128", tor]), &config).unwrap();
+        assert_eq!(upstream.as_deref(), Some("http://proxy.corp:3128"));
+        let (tor_entry, upstream) = split_chain(&chain(&["socks5://10.0.0.1:1080", "socks5://127.0.0.1:9050"]), &config).unwrap();
+        assert_eq!((tor_entry.as_str(), upstream.as_deref()), (tor, Some("socks5h://10.0.0.1:1080")));
+
+        let invalid: [(&[&str], ClientConfig); 6] = [
+            (&[], config.clone()),
+            (&["http://a:1", "http://b:2", tor], config.clone()),
+            (&[tor, "http://proxy.corp:3128"], config.clone()),
+            (&[tor, tor], config.clone()),
+            // Only onion urls, or none, refuse clearnet by default
+            (&["http://proxy.corp:3128", tor], ClientConfig::default()),
+            (&["socks5://10.0.0.1:1080", tor], ClientConfig { strict_proxy: true, ..config.clone() }),
+        ];
+        for (urls, config) in invalid {
+            let config = ClientConfig { proxy: ProxySetting::Chain(chain(urls)), ..config };
+            assert!(validate(&config).is_err(), "{:?} accepted", urls);
+        }
+
+        let url = |u: &str| Url::parse(u).unwrap();
+        let onion = url("http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion/chat.php");
+        assert_eq!(hop(&onion, true), Hop::Tor);
+        assert_eq!(hop(&url("HTTP://X.ONION/"), true), Hop::Tor);
+        assert_eq!(hop(&url("https://example.com/chat.php"), true), Hop::Upstream);
+        assert_eq!(hop(&url("http://10.1.2.3:8080/"), true), Hop::Upstream);
+        assert_eq!(hop(&url("https://example.com/chat.php"), false), Hop::Tor);
+
+        // Clearnet through the upstream http proxy, onion through the (dead) Tor proxy
+        let upstream = MockServer::start(|req| MockResponse::ok(&req.path));
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let proxy = ProxySetting::Chain(vec![upstream.url.clone(), format!("socks5h://{}", closed)]);
+        let transport = build(&ClientConfig { proxy, rate_limit: None, ..config }).unwrap();
+        let resp = transport.send(transport.get("http://example.com/chat.php")).unwrap();
+        assert_eq!(transport.text(resp).unwrap(), "http://example.com/chat.php");
+        assert!(transport.send(transport.get(onion.as_str())).is_err());
+        assert_eq!(upstream.hits(), 1);
+        assert!(!transport.proxy_up());
+    }
+
+    #[test]
+    fn socks_auth_test() {
+        let salt_file = std::env::temp_dir().join(format!("bhcli_salt_test_{}", std::process::id()));
+        let _ = fs::remove_file(&salt_file);
+
+        let alice = SocksAuth::for_profile("alice", &salt_file).unwrap();
+        let bob = SocksAuth::for_profile("bob", &salt_file).unwrap();
+        assert_ne!(alice, bob);
+        // Same profile after a restart reads back the same salt
+        assert_eq!(SocksAuth::for_profile("alice", &salt_file).unwrap(), alice);
+        assert!(!format!("{:?}", ClientConfig { socks_auth: Some(alice.clone()), ..Default::default() }).contains(&alice.password));
+
+        let config = ClientConfig {
+            proxy: ProxySetting::Url("socks5h://127.0.0.1:9050".to_owned()),
+            socks_auth: Some(alice),
+            ..Default::default()
+        };
+        assert!(build(&config).is_ok());
+        let _ = fs::remove_file(&salt_file);
+    }
+}
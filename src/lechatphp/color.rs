@@ -0,0 +1,158 @@
+use serde_derive::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+
+/// The names skins use instead of a hex colour, HTML's basic ones and a few
+/// more.
+const NAMED: &[(&str, [u8; 3])] = &[
+    ("black", [0x00, 0x00, 0x00]),
+    ("silver", [0xC0, 0xC0, 0xC0]),
+    ("gray", [0x80, 0x80, 0x80]),
+    ("grey", [0x80, 0x80, 0x80]),
+    ("white", [0xFF, 0xFF, 0xFF]),
+    ("maroon", [0x80, 0x00, 0x00]),
+    ("red", [0xFF, 0x00, 0x00]),
+    ("purple", [0x80, 0x00, 0x80]),
+    ("fuchsia", [0xFF, 0x00, 0xFF]),
+    ("magenta", [0xFF, 0x00, 0xFF]),
+    ("green", [0x00, 0x80, 0x00]),
+    ("lime", [0x00, 0xFF, 0x00]),
+    ("olive", [0x80, 0x80, 0x00]),
+    ("yellow", [0xFF, 0xFF, 0x00]),
+    ("navy", [0x00, 0x00, 0x80]),
+    ("blue", [0x00, 0x00, 0xFF]),
+    ("teal", [0x00, 0x80, 0x80]),
+    ("aqua", [0x00, 0xFF, 0xFF]),
+    ("cyan", [0x00, 0xFF, 0xFF]),
+    ("orange", [0xFF, 0xA5, 0x00]),
+    ("pink", [0xFF, 0xC0, 0xCB]),
+    ("brown", [0xA5, 0x2A, 0x2A]),
+    ("gold", [0xFF, 0xD7, 0x00]),
+];
+
+/// A colour of the chat, a nick's or a message's. Serialized as `#RRGGBB`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ChatColor([u8; 3]);
+
+impl ChatColor {
+    pub fn new(r: u8, g: u8, b: u8) -> Self {
+        Self([r, g, b])
+    }
+
+    /// `#FF0000`, `#f00`, `FF0000` or a name like `red`, as styles and
+    /// `<font color>` have them. `None` for anything else.
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        if let Some((_, rgb)) = NAMED.iter().find(|(name, _)| name.eq_ignore_ascii_case(s)) {
+            return Some(Self(*rgb));
+        }
+        let hex = s.strip_prefix('#').unwrap_or(s);
+        if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        let digit = |i: usize| u8::from_str_radix(&hex[i..i + 1], 16).unwrap();
+        match hex.len() {
+            3 => Some(Self([digit(0) * 17, digit(1) * 17, digit(2) * 17])),
+            6 => Some(Self([0, 2, 4].map(|i| digit(i) * 16 + digit(i + 1)))),
+            _ => None,
+        }
+    }
+
+    pub fn to_rgb(self) -> (u8, u8, u8) {
+        let [r, g, b] = self.0;
+        (r, g, b)
+    }
+
+    /// The closest of the 256-colour palette's cube and grays. The 16 first
+    /// are left out, each terminal shows them its own way.
+    pub fn to_ansi256(self) -> u8 {
+        // The levels of each channel in the 6x6x6 cube
+        const CUBE_LEVELS: [u8; 6] = [0x00, 0x5F, 0x87, 0xAF, 0xD7, 0xFF];
+        let distance = |[r, g, b]: [u8; 3]| {
+            let [r0, g0, b0] = self.0;
+            [(r, r0), (g, g0), (b, b0)].iter().map(|&(a, b)| (a as i32 - b as i32).pow(2)).sum::<i32>()
+        };
+        let level = |c: u8| (0..CUBE_LEVELS.len()).min_by_key(|&i| (CUBE_LEVELS[i] as i32 - c as i32).abs()).unwrap();
+        let [r, g, b] = self.0.map(level);
+        let cube = [r, g, b].map(|i| CUBE_LEVELS[i]);
+        let cube_index = 16 + 36 * r + 6 * g + b;
+        // 24 grays from 8 to 238, by 10
+        let average = self.0.iter().map(|&c| c as usize).sum::<usize>() / 3;
+        let gray_step = (average.saturating_sub(3) / 10).min(23);
+        let gray = 8 + 10 * gray_step as u8;
+        if distance([gray; 3]) < distance(cube) {
+            (232 + gray_step) as u8
+        } else {
+            cube_index as u8
+        }
+    }
+}
+
+impl Display for ChatColor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let [r, g, b] = self.0;
+        write!(f, "#{:02X}{:02X}{:02X}", r, g, b)
+    }
+}
+
+impl TryFrom<String> for ChatColor {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::parse(&s).ok_or_else(|| format!("not a colour: {:?}", s))
+    }
+}
+
+impl From<ChatColor> for String {
+    fn from(color: ChatColor) -> Self {
+        color.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        let red = Some(ChatColor::new(0xFF, 0, 0));
+        assert_eq!(ChatColor::parse("#FF0000"), red);
+        assert_eq!(ChatColor::parse("#ff0000"), red);
+        assert_eq!(ChatColor::parse("FF0000"), red);
+        assert_eq!(ChatColor::parse("#f00"), red);
+        assert_eq!(ChatColor::parse(" Red "), red);
+        assert_eq!(ChatColor::parse("#a1b"), Some(ChatColor::new(0xAA, 0x11, 0xBB)));
+        assert_eq!(ChatColor::parse("#ABCDEF").unwrap().to_string(), "#ABCDEF");
+        for bad in ["", "#", "inherit", "#12345", "#GG0000", "#FF00001", "#é00"] {
+            assert_eq!(ChatColor::parse(bad), None, "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn to_ansi256_test() {
+        let ansi = |s| ChatColor::parse(s).unwrap().to_ansi256();
+        let cases = [
+            ("#FF0000", 196),
+            ("#800000", 88),
+            ("#C04040", 131),
+            ("#FF6060", 203),
+            ("#00FF00", 46),
+            ("#008000", 28),
+            ("#0000FF", 21),
+            ("#000080", 18),
+            ("orange", 214),
+            ("#FFFF00", 226),
+            ("#FF00FF", 201),
+            ("#00FFFF", 51),
+            ("#000000", 16),
+            ("#FFFFFF", 231),
+            // Grays that aren't in the cube
+            ("#808080", 244),
+            ("#303030", 236),
+            ("#EEEEEE", 255),
+        ];
+        for (color, expected) in cases {
+            assert_eq!(ansi(color), expected, "{}", color);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+/// Everyone in the chat, the post box's default recipient.
+pub(super) const SEND_TO_ALL: &str = "s *";
+/// What the server turns into an action, `* nick waves`.
+pub(super) const ACTION_PREFIX: &str = "/me ";
+/// Input prefixes whispering to the nick that follows.
+const WHISPER_PREFIXES: &[&str] = &["/pm ", "/msg ", "/w "];
+
+/// A line as typed in the post box, with what the server makes of it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChatCommand {
+    /// Text for everyone.
+    Plain(String),
+    /// `/me waves`, shown as `nick waves`.
+    Action(String),
+    /// Only for `to`, the post box's recipient rather than a prefix.
+    Whisper { to: String, text: String },
+    /// Sent to everyone as it is, for prefixes only some forks know of
+    /// like `/away`.
+    Raw(String),
+}
+
+impl ChatCommand {
+    /// `/me`, `/pm`, `/msg` and `/w` are known, other lines starting with
+    /// `/` are left to the server as `Raw`.
+    pub fn parse(line: &str) -> Self {
+        if let Some(text) = line.strip_prefix(ACTION_PREFIX) {
+            return ChatCommand::Action(text.to_owned());
+        }
+        let whisper = WHISPER_PREFIXES.iter().find_map(|prefix| line.strip_prefix(prefix)?.split_once(' '));
+        if let Some((to, text)) = whisper.filter(|(to, _)| !to.is_empty()) {
+            return ChatCommand::Whisper { to: to.to_owned(), text: text.to_owned() };
+        }
+        if line.starts_with('/') {
+            return ChatCommand::Raw(line.to_owned());
+        }
+        ChatCommand::Plain(line.to_owned())
+    }
+
+    /// The line `parse` reads back as this command.
+    pub fn to_line(&self) -> String {
+        match self {
+            ChatCommand::Plain(text) | ChatCommand::Raw(text) => text.clone(),
+            ChatCommand::Action(text) => format!("{}{}", ACTION_PREFIX, text),
+            ChatCommand::Whisper { to, text } => format!("{}{} {}", WHISPER_PREFIXES[0], to, text),
+        }
+    }
+
+    /// The post box's `sendto` and `message`.
+    pub fn wire(&self) -> (&str, String) {
+        match self {
+            ChatCommand::Plain(text) | ChatCommand::Raw(text) => (SEND_TO_ALL, text.clone()),
+            ChatCommand::Action(text) => (SEND_TO_ALL, format!("{}{}", ACTION_PREFIX, text)),
+            ChatCommand::Whisper { to, text } => (to, text.clone()),
+        }
+    }
+
+    /// Whether there is nothing to send besides the prefix.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            ChatCommand::Plain(text) | ChatCommand::Raw(text) | ChatCommand::Action(text) | ChatCommand::Whisper { text, .. } => {
+                text.trim().is_empty()
+            }
+        }
+    }
+}
+
+impl From<&str> for ChatCommand {
+    fn from(value: &str) -> Self {
+        ChatCommand::Plain(value.to_owned())
+    }
+}
+
+impl From<String> for ChatCommand {
+    fn from(value: String) -> Self {
+        ChatCommand::Plain(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chat_command_test() {
+        let commands = [
+            ChatCommand::Plain("hello everyone".to_owned()),
+            ChatCommand::Action("waves at alice".to_owned()),
+            ChatCommand::Whisper { to: "bob".to_owned(), text: "psst, over here".to_owned() },
+            ChatCommand::Raw("/away back in 5".to_owned()),
+        ];
+        for command in &commands {
+            assert_eq!(&ChatCommand::parse(&command.to_line()), command);
+        }
+        let wire: Vec<_> = commands.iter().map(|c| c.wire()).collect();
+        assert_eq!(
+            wire,
+            [
+                ("s *", "hello everyone".to_owned()),
+                ("s *", "/me waves at alice".to_owned()),
+                ("bob", "psst, over here".to_owned()),
+                ("s *", "/away back in 5".to_owned()),
+            ]
+        );
+
+        assert_eq!(ChatCommand::parse("/w bob hi"), ChatCommand::parse("/msg bob hi"));
+        // Not a whisper without a nick and a text
+        assert_eq!(ChatCommand::parse("/pm bob"), ChatCommand::Raw("/pm bob".to_owned()));
+        assert_eq!(ChatCommand::parse("/me"), ChatCommand::Raw("/me".to_owned()));
+        assert!(ChatCommand::Action(" ".to_owned()).is_empty());
+    }
+}
@@ -0,0 +1,258 @@
+use super::history::{self, Entry, EntryKind};
+use super::messages::{Message, MessageKind};
+use super::settings::Settings;
+use super::unread::{self, ReadScope, UnreadCounts, UnreadErr};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Private messages kept in memory, the oldest are dropped past it.
+const MAX_KEPT: usize = 10_000;
+
+/// The private messages with one nick, as `conversations` lists them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConversationSummary {
+    /// As last seen, or the nick an alias maps to.
+    pub counterpart: String,
+    pub last: Entry,
+    /// From `unread::unread_counts`, for every nick of the conversation.
+    pub unread: usize,
+}
+
+// The nick an old one is listed under, the same one without an alias.
+// Chains are followed, a loop stops where it started.
+fn canonical(nick: &str, aliases: &HashMap<String, String>) -> String {
+    let mut nick = nick.to_owned();
+    for _ in 0..aliases.len() {
+        match aliases.get(&nick.to_lowercase()) {
+            Some(to) => nick = to.clone(),
+            None => break,
+        }
+    }
+    nick
+}
+
+// The nick on the other side, `None` for whispers between others. Without
+// our nick every message counts as the sender's.
+fn counterpart<'a>(entry: &'a Entry, me: Option<&str>) -> Option<&'a str> {
+    let from = entry.from.as_deref()?;
+    let is_me = |nick: &str| me.is_some_and(|me| nick.eq_ignore_ascii_case(me));
+    match me {
+        Some(_) if is_me(from) => entry.to.as_deref(),
+        Some(_) => entry.to.as_deref().filter(|to| is_me(to)).map(|_| from),
+        None => Some(from),
+    }
+}
+
+// The private messages of `entries`, oldest first, by the lowercase nick
+// they are listed under
+fn group<'a>(entries: &'a [Entry], me: Option<&str>, aliases: &HashMap<String, String>) -> HashMap<String, Vec<&'a Entry>> {
+    let mut groups: HashMap<String, Vec<&Entry>> = HashMap::new();
+    for entry in entries.iter().filter(|e| e.kind == EntryKind::Private) {
+        if let Some(nick) = counterpart(entry, me) {
+            groups.entry(canonical(nick, aliases).to_lowercase()).or_default().push(entry);
+        }
+    }
+    groups
+}
+
+/// A summary per conversation of `entries`, the latest first. `counts`
+/// are the unread ones, by the nick they came from.
+pub fn summaries(
+    entries: &[Entry],
+    me: Option<&str>,
+    aliases: &HashMap<String, String>,
+    counts: &UnreadCounts,
+) -> Vec<ConversationSummary> {
+    let mut summaries: Vec<_> = group(entries, me, aliases)
+        .into_iter()
+        .filter_map(|(key, thread)| {
+            let last = (*thread.last()?).clone();
+            let counterpart = canonical(counterpart(&last, me)?, aliases);
+            let unread = counts
+                .private
+                .iter()
+                .filter(|(nick, _)| canonical(nick, aliases).to_lowercase() == key)
+                .map(|(_, count)| count)
+                .sum();
+            Some(ConversationSummary { counterpart, last, unread })
+        })
+        .collect();
+    summaries.sort_by(|a, b| b.last.time().cmp(&a.last.time()).then_with(|| a.counterpart.cmp(&b.counterpart)));
+    summaries
+}
+
+/// The private messages of `entries` with `nick` both ways, oldest first.
+pub fn thread(entries: &[Entry], nick: &str, me: Option<&str>, aliases: &HashMap<String, String>) -> Vec<Entry> {
+    let key = canonical(nick, aliases).to_lowercase();
+    group(entries, me, aliases).remove(&key).unwrap_or_default().into_iter().cloned().collect()
+}
+
+/// A client's private messages streamed, for when history doesn't keep
+/// them, and its nick aliases.
+#[derive(Debug, Default)]
+pub struct Conversations {
+    kept: Mutex<Vec<Entry>>,
+    // By lowercase old nick
+    aliases: HashMap<String, String>,
+}
+
+impl Conversations {
+    /// Merge the conversations of old nicks into the ones they map to, like
+    /// `{"old nick": "new nick"}`.
+    pub fn new(aliases: &HashMap<String, String>) -> Self {
+        let aliases = aliases.iter().map(|(old, new)| (old.to_lowercase(), new.clone())).collect();
+        Conversations { kept: Mutex::new(vec![]), aliases }
+    }
+}
+
+/// The private messages new to the stream from `server`, oldest first.
+pub(super) fn record(settings: &Settings, server: &str, messages: &[Message]) {
+    let mut kept = settings.conversations.kept.lock().unwrap();
+    kept.extend(messages.iter().filter(|m| m.kind == MessageKind::Private).map(|m| Entry::new(server, m)));
+    let over = kept.len().saturating_sub(MAX_KEPT);
+    kept.drain(..over);
+}
+
+// The history's when it keeps private messages, else what was streamed
+fn entries(settings: &Settings) -> Vec<Entry> {
+    let kept = || settings.conversations.kept.lock().unwrap().clone();
+    match history::private_entries(settings) {
+        Some(Ok(entries)) => entries,
+        Some(Err(e)) => {
+            log::warn!("{}, conversations from this session only", e);
+            kept()
+        }
+        None => kept(),
+    }
+}
+
+/// The conversations so far, the latest first. Our own messages are
+/// told apart by the session's nick.
+pub fn conversations(settings: &Settings) -> Vec<ConversationSummary> {
+    let aliases = &settings.conversations.aliases;
+    summaries(&entries(settings), settings.mention.nick().as_deref(), aliases, &unread::unread_counts(settings))
+}
+
+/// The private messages with `nick`, or one of its aliases, oldest first.
+pub fn conversation(settings: &Settings, nick: &str) -> Vec<Entry> {
+    thread(&entries(settings), nick, settings.mention.nick().as_deref(), &settings.conversations.aliases)
+}
+
+/// Mark the conversation with `nick` read, under each of its nicks, see
+/// `unread::mark_read`.
+pub fn mark_read(settings: &Settings, nick: &str) -> Result<(), UnreadErr> {
+    let aliases = &settings.conversations.aliases;
+    let key = canonical(nick, aliases).to_lowercase();
+    for nick in unread::unread_counts(settings).private.into_keys() {
+        if canonical(&nick, aliases).to_lowercase() == key {
+            unread::mark_read(settings, ReadScope::Private(nick))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lechatphp::history::{History, HistoryConfig};
+    use crate::lechatphp::unread::Tracker;
+    use chrono::DateTime;
+
+    const BASE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+
+    fn message(id: u64, kind: MessageKind, from: &str, to: Option<&str>, text: &str) -> Message {
+        let time = format!("2026-10-17T10:00:{:02}+00:00", id);
+        Message {
+            id: Some(id),
+            timestamp: format!("10-17 10:00:{:02}", id),
+            time: Some(DateTime::parse_from_rfc3339(&time).unwrap()),
+            from: Some(from.to_owned()),
+            to: to.map(str::to_owned),
+            text: text.to_owned(),
+            spans: vec![],
+            color: None,
+            display_from: None,
+            display_color: None,
+            kind,
+            mentions_me: false,
+            room: None,
+        }
+    }
+
+    fn whisper(id: u64, from: &str, to: &str, text: &str) -> Message {
+        message(id, MessageKind::Private, from, Some(to), text)
+    }
+
+    fn said(id: u64, from: &str, text: &str) -> Message {
+        message(id, MessageKind::Normal, from, None, text)
+    }
+
+    fn texts(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|e| e.text.as_str()).collect()
+    }
+
+    // Two conversations interleaved with the chat, oldest first
+    fn streamed() -> Vec<Message> {
+        vec![
+            whisper(1, "alice", "zed", "hi zed"),
+            said(2, "carol", "hello all"),
+            whisper(3, "bob", "zed", "got a minute?"),
+            whisper(4, "zed", "Alice", "hi alice"),
+            said(5, "alice", "public answer"),
+            whisper(6, "zed", "bob", "sure"),
+            whisper(7, "ALICE", "zed", "how are you"),
+        ]
+    }
+
+    fn brief(summaries: &[ConversationSummary]) -> Vec<(&str, &str, usize)> {
+        summaries.iter().map(|s| (s.counterpart.as_str(), s.last.text.as_str(), s.unread)).collect()
+    }
+
+    #[test]
+    fn conversations_test() {
+        let me = Some("zed");
+        let mut messages = streamed();
+        let entries: Vec<_> = messages.iter().map(|m| Entry::new(BASE_URL, m)).collect();
+        let mut tracker = Tracker::default();
+        tracker.record(&messages, me);
+        let none = HashMap::new();
+
+        assert_eq!(brief(&summaries(&entries, me, &none, &tracker.counts())), [("ALICE", "how are you", 2), ("bob", "sure", 1)]);
+        assert_eq!(texts(&thread(&entries, "alice", me, &none)), ["hi zed", "hi alice", "how are you"]);
+        assert_eq!(texts(&thread(&entries, "Bob", me, &none)), ["got a minute?", "sure"]);
+        assert!(thread(&entries, "carol", me, &none).is_empty());
+        // The counts are the tracker's, read under one spelling of the nick
+        tracker.mark_read(&ReadScope::Private("ALICE".to_owned()));
+        assert_eq!(brief(&summaries(&entries, me, &none, &tracker.counts()))[0], ("ALICE", "how are you", 1));
+
+        // Renamed, merged by hand
+        messages.push(whisper(8, "bobby", "zed", "new nick"));
+        tracker.record(&messages[7..], me);
+        let entries: Vec<_> = messages.iter().map(|m| Entry::new(BASE_URL, m)).collect();
+        let aliases = HashMap::from([("Bobby".to_lowercase(), "bob".to_owned())]);
+        assert_eq!(brief(&summaries(&entries, me, &aliases, &tracker.counts()))[0], ("bob", "new nick", 2));
+        assert_eq!(texts(&thread(&entries, "bobby", me, &aliases)), ["got a minute?", "sure", "new nick"]);
+        // Whispers between others, seen by staff, aren't ours
+        let others = [Entry::new(BASE_URL, &whisper(9, "bob", "carol", "not for zed"))];
+        assert!(summaries(&others, me, &none, &UnreadCounts::default()).is_empty());
+    }
+
+    #[test]
+    fn history_conversations_test() {
+        let dir = std::env::temp_dir().join(format!("bhcli-conversations-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = HistoryConfig { dir: dir.clone(), profile: None, max_bytes: 1 << 20, include_private: true };
+        let store = History::open(config.clone()).unwrap();
+        store.append(BASE_URL, &streamed()[..4]);
+        store.append("http://other.onion", &[whisper(5, "alice", "zed", "from elsewhere")]);
+        store.append(BASE_URL, &streamed()[4..]);
+        let entries = store.private_entries().unwrap().unwrap();
+        assert_eq!(texts(&thread(&entries, "alice", Some("zed"), &HashMap::new())), ["hi zed", "hi alice", "from elsewhere", "how are you"]);
+        drop(store);
+        // Without private messages kept, the stream's are used
+        let store = History::open(HistoryConfig { include_private: false, ..config }).unwrap();
+        assert!(store.private_entries().is_none());
+        drop(store);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,224 @@
+use super::color::ChatColor;
+use super::error_kind::{Classify, ErrorKind};
+use super::messages::Message;
+use super::users::User;
+use regex::{Regex, RegexBuilder};
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// How the profile shows a nick, whatever the server says.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DisplayRule {
+    /// A nick, case-insensitive, or a regular expression with `regex`.
+    pub nick: String,
+    pub regex: bool,
+    /// Shown instead of the nick. With `regex`, `$1` or `${name}` are the
+    /// groups of the match.
+    pub alias: Option<String>,
+    /// `#FF0000` or a name like `red`, shown instead of the nick's color.
+    pub color: Option<String>,
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum DisplayErr {
+    #[error("display rule {}: no nick", .0 + 1)]
+    EmptyNick(usize),
+    #[error("display rule {}: {}", .0 + 1, .1)]
+    Regex(usize, #[source] regex::Error),
+    #[error("display rule {}: {:?} is not a color", .0 + 1, .1)]
+    Color(usize, String),
+}
+
+impl Classify for DisplayErr {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            DisplayErr::EmptyNick(_) | DisplayErr::Regex(..) | DisplayErr::Color(..) => ErrorKind::InvalidInput,
+        }
+    }
+}
+
+/// What the rules make of a nick, `None` where none says.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Shown {
+    pub alias: Option<String>,
+    pub color: Option<ChatColor>,
+}
+
+#[derive(Debug, Clone)]
+enum Pattern {
+    /// Lowercase.
+    Nick(String),
+    Regex(Regex),
+}
+
+#[derive(Debug, Clone)]
+struct Compiled {
+    pattern: Pattern,
+    alias: Option<String>,
+    color: Option<ChatColor>,
+}
+
+impl Compiled {
+    // The alias for `nick` when the rule matches it, `Some(None)` for a
+    // rule without one
+    fn matches(&self, nick: &str) -> Option<Option<String>> {
+        match &self.pattern {
+            Pattern::Nick(n) => (*n == nick.to_lowercase()).then(|| self.alias.clone()),
+            Pattern::Regex(rgx) => {
+                let caps = rgx.captures(nick)?;
+                Some(self.alias.as_ref().map(|alias| {
+                    let mut expanded = String::new();
+                    caps.expand(alias, &mut expanded);
+                    expanded
+                }))
+            }
+        }
+    }
+}
+
+/// The rules of a profile, ready to apply.
+#[derive(Debug, Clone, Default)]
+pub struct DisplayOverrides {
+    rules: Vec<Compiled>,
+    /// The aliases without groups, the names they give.
+    targets: Vec<String>,
+}
+
+impl DisplayOverrides {
+    pub fn new(rules: &[DisplayRule]) -> Result<Self, DisplayErr> {
+        let mut compiled = vec![];
+        for (i, rule) in rules.iter().enumerate() {
+            if rule.nick.trim().is_empty() {
+                return Err(DisplayErr::EmptyNick(i));
+            }
+            let pattern = match rule.regex {
+                true => Pattern::Regex(RegexBuilder::new(&rule.nick).case_insensitive(true).build().map_err(|e| DisplayErr::Regex(i, e))?),
+                false => Pattern::Nick(rule.nick.trim().to_lowercase()),
+            };
+            let color = match &rule.color {
+                Some(color) => Some(ChatColor::parse(color).ok_or_else(|| DisplayErr::Color(i, color.clone()))?),
+                None => None,
+            };
+            compiled.push(Compiled { pattern, alias: rule.alias.clone().filter(|a| !a.trim().is_empty()), color });
+        }
+        // Nicks before patterns, each in the order given
+        compiled.sort_by_key(|c| matches!(c.pattern, Pattern::Regex(_)));
+        let targets = rules
+            .iter()
+            .filter_map(|r| r.alias.as_deref())
+            // With groups it is a different name each time
+            .filter(|a| !a.contains('$') && !a.trim().is_empty())
+            .map(|a| a.trim().to_owned())
+            .collect();
+        Ok(Self { rules: compiled, targets })
+    }
+
+    /// The alias and color for `nick`. A rule for the nick itself goes
+    /// before the patterns, and patterns go in the order given. Each of
+    /// the two comes from the first rule that has one.
+    pub fn shown(&self, nick: &str) -> Shown {
+        let mut shown = Shown::default();
+        for rule in &self.rules {
+            let Some(alias) = rule.matches(nick) else {
+                continue;
+            };
+            shown.alias = shown.alias.or(alias);
+            shown.color = shown.color.or(rule.color);
+            if shown.alias.is_some() && shown.color.is_some() {
+                break;
+            }
+        }
+        shown
+    }
+
+    /// The names the rules give others, never a mention of us.
+    pub fn targets(&self) -> &[String] {
+        &self.targets
+    }
+
+    /// `display_from` and `display_color` of `message` from its sender.
+    pub fn apply(&self, message: &mut Message) {
+        let shown = message.from.as_deref().map(|from| self.shown(from)).unwrap_or_default();
+        message.display_from = shown.alias.or_else(|| message.from.clone());
+        message.display_color = shown.color.or(message.color);
+    }
+
+    pub fn apply_user(&self, user: &mut User) {
+        let shown = self.shown(&user.nick);
+        user.display_nick = shown.alias.unwrap_or_else(|| user.nick.clone());
+        user.display_color = shown.color.or(user.color);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(nick: &str, regex: bool, alias: Option<&str>, color: Option<&str>) -> DisplayRule {
+        DisplayRule { nick: nick.to_owned(), regex, alias: alias.map(str::to_owned), color: color.map(str::to_owned) }
+    }
+
+    #[test]
+    fn precedence_test() {
+        let overrides = DisplayOverrides::new(&[
+            rule(r"^anon(\d+)$", true, Some("anon #$1"), Some("#808080")),
+            rule(r"^anon", true, Some("someone"), Some("white")),
+            rule("Anon7", false, Some("the regular"), None),
+            rule("^x", true, None, Some("yellow")),
+        ])
+        .unwrap();
+        let shown = |nick: &str| overrides.shown(nick);
+        let gray = ChatColor::parse("#808080");
+        assert_eq!(shown("anon42"), Shown { alias: Some("anon #42".to_owned()), color: gray });
+        // The nick's own rule first, the color from the first pattern
+        assert_eq!(shown("anon7"), Shown { alias: Some("the regular".to_owned()), color: gray });
+        assert_eq!(shown("ANONYMOUS"), Shown { alias: Some("someone".to_owned()), color: ChatColor::parse("white") });
+        assert_eq!(shown("xena"), Shown { alias: None, color: ChatColor::parse("yellow") });
+        assert_eq!(shown("bob"), Shown::default());
+        // Only aliases without groups are names
+        assert_eq!(overrides.targets, ["someone", "the regular"]);
+
+        assert!(matches!(DisplayOverrides::new(&[rule("(", true, None, None)]), Err(DisplayErr::Regex(0, _))));
+        assert!(matches!(DisplayOverrides::new(&[rule("a", false, None, Some("#zz"))]), Err(DisplayErr::Color(0, _))));
+        assert!(matches!(DisplayOverrides::new(&[rule(" ", false, Some("b"), None)]), Err(DisplayErr::EmptyNick(0))));
+    }
+
+    #[test]
+    fn apply_test() {
+        use crate::lechatphp::messages::MessageKind;
+        use crate::lechatphp::users::Role;
+
+        let overrides = DisplayOverrides::new(&[rule("bob_v2", false, Some("bob"), Some("#00FF00"))]).unwrap();
+        let mut message = Message {
+            id: None,
+            timestamp: String::new(),
+            time: None,
+            from: Some("Bob_V2".to_owned()),
+            to: Some("zed".to_owned()),
+            text: "psst".to_owned(),
+            spans: vec![],
+            color: ChatColor::parse("#000001"),
+            display_from: None,
+            display_color: None,
+            kind: MessageKind::Private,
+            mentions_me: false,
+            room: None,
+        };
+        overrides.apply(&mut message);
+        // The original stays, for replies and conversations
+        assert_eq!(message.from.as_deref(), Some("Bob_V2"));
+        assert_eq!((message.display_from.as_deref(), message.display_color), (Some("bob"), ChatColor::parse("#00FF00")));
+        let mut user = User {
+            nick: "carol".to_owned(),
+            color: ChatColor::parse("red"),
+            role: Role::Member,
+            idle_minutes: None,
+            display_nick: String::new(),
+            display_color: None,
+        };
+        overrides.apply_user(&mut user);
+        assert_eq!((user.display_nick.as_str(), user.display_color), ("carol", ChatColor::parse("red")));
+    }
+}
@@ -0,0 +1,204 @@
+use lazy_static::lazy_static;
+use regex::{Captures, Regex};
+use std::collections::HashMap;
+
+lazy_static! {
+    // Code spans and links are matched first and kept as they are
+    static ref SHORTCODE_RGX: Regex = Regex::new(r"(?i)(`[^`]*`)|(\S+://\S*|\bwww\.\S+)|(\\?):([a-z0-9_+\-]+):").unwrap();
+}
+
+/// The shortcodes known without configuration.
+const BUILTIN: &[(&str, &str)] = &[
+    ("+1", "👍"),
+    ("thumbsup", "👍"),
+    ("-1", "👎"),
+    ("thumbsdown", "👎"),
+    ("smile", "😄"),
+    ("grin", "😁"),
+    ("joy", "😂"),
+    ("rofl", "🤣"),
+    ("wink", "😉"),
+    ("blush", "😊"),
+    ("heart_eyes", "😍"),
+    ("kiss", "😘"),
+    ("thinking", "🤔"),
+    ("neutral_face", "😐"),
+    ("unamused", "😒"),
+    ("roll_eyes", "🙄"),
+    ("sweat_smile", "😅"),
+    ("sob", "😭"),
+    ("cry", "😢"),
+    ("angry", "😠"),
+    ("rage", "😡"),
+    ("scream", "😱"),
+    ("sunglasses", "😎"),
+    ("skull", "💀"),
+    ("ghost", "👻"),
+    ("clown", "🤡"),
+    ("eyes", "👀"),
+    ("wave", "👋"),
+    ("clap", "👏"),
+    ("pray", "🙏"),
+    ("ok_hand", "👌"),
+    ("muscle", "💪"),
+    ("facepalm", "🤦"),
+    ("shrug", "¯\\_(ツ)_/¯"),
+    ("tableflip", "(╯°□°)╯︵ ┻━┻"),
+    ("heart", "❤"),
+    ("broken_heart", "💔"),
+    ("fire", "🔥"),
+    ("100", "💯"),
+    ("tada", "🎉"),
+    ("star", "⭐"),
+    ("sparkles", "✨"),
+    ("rocket", "🚀"),
+    ("coffee", "☕"),
+    ("beer", "🍺"),
+    ("pizza", "🍕"),
+    ("check", "✅"),
+    ("x", "❌"),
+    ("warning", "⚠"),
+    ("zzz", "💤"),
+    ("onion", "🧅"),
+];
+
+/// Shortcodes like `:shrug:` in posts, and the way back in the view.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmojiConfig {
+    /// Expand the shortcodes of posts. `\:name:` is posted as `:name:`.
+    pub expand: bool,
+    /// More shortcodes, or other values for built-in ones, by name with or
+    /// without the colons. Any text goes, kaomoji too.
+    pub shortcodes: HashMap<String, String>,
+    /// Show the emoji of messages as their shortcode, for terminals
+    /// without emoji fonts.
+    pub shorten: bool,
+}
+
+impl Default for EmojiConfig {
+    fn default() -> Self {
+        Self { expand: true, shortcodes: HashMap::new(), shorten: false }
+    }
+}
+
+// Only pictographs are shortened back, the terminal shows the rest
+fn is_emoji(value: &str) -> bool {
+    value.chars().any(|c| matches!(c as u32, 0x2600..=0x27BF | 0x2B00..=0x2BFF | 0x1F000..=0x1FAFF))
+}
+
+/// The shortcodes with their values, and the way back.
+#[derive(Debug, Clone)]
+pub struct Table {
+    expand: bool,
+    codes: HashMap<String, String>,
+    /// The emoji and a name of each, `None` when not shortening.
+    names: Option<(Regex, HashMap<String, String>)>,
+}
+
+impl Table {
+    pub fn new(config: &EmojiConfig) -> Self {
+        let mut codes: HashMap<_, _> = BUILTIN.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        for (name, value) in &config.shortcodes {
+            codes.insert(name.trim_matches(':').to_lowercase(), value.clone());
+        }
+        let names = config.shorten.then(|| {
+            // The first name of the table for a value, `+1` rather than
+            // `thumbsup`, then the configured ones
+            let mut names: HashMap<String, String> = HashMap::new();
+            let configured = config.shortcodes.keys().map(|name| name.trim_matches(':').to_lowercase());
+            for name in BUILTIN.iter().map(|(name, _)| name.to_string()).chain(configured) {
+                let value = &codes[&name];
+                if is_emoji(value) {
+                    names.entry(value.clone()).or_insert(name);
+                }
+            }
+            // Longest first, so a sequence isn't taken for its first emoji
+            let mut values: Vec<_> = names.keys().collect();
+            values.sort_by_key(|v| std::cmp::Reverse(v.len()));
+            let alternatives: Vec<_> = values.iter().map(|v| regex::escape(v)).collect();
+            let rgx = format!("(?:{})\u{FE0F}?", alternatives.join("|"));
+            (Regex::new(&rgx).unwrap(), names)
+        });
+        Self { expand: config.expand, codes, names }
+    }
+
+    /// `text` with its known shortcodes replaced, except in links and
+    /// `` `code` ``. An escaped one, `\:name:`, loses the backslash.
+    pub fn expand(&self, text: &str) -> String {
+        if !self.expand {
+            return text.to_owned();
+        }
+        let expanded = SHORTCODE_RGX.replace_all(text, |caps: &Captures| {
+            let whole = caps[0].to_owned();
+            if caps.get(1).is_some() || caps.get(2).is_some() {
+                return whole;
+            }
+            let name = caps[4].to_lowercase();
+            match self.codes.get(&name) {
+                Some(_) if !caps[3].is_empty() => whole[1..].to_owned(),
+                Some(value) => value.clone(),
+                // Unknown, `\` and all
+                None => whole,
+            }
+        });
+        expanded.into_owned()
+    }
+
+    /// `text` with the emoji it has as `:name:`, when shortening.
+    pub fn shorten(&self, text: &str) -> String {
+        match &self.names {
+            Some((rgx, names)) => rgx
+                .replace_all(text, |caps: &Captures| {
+                    let emoji = caps[0].trim_end_matches('\u{FE0F}');
+                    format!(":{}:", names[emoji])
+                })
+                .into_owned(),
+            None => text.to_owned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(shortcodes: &[(&str, &str)], shorten: bool) -> Table {
+        let shortcodes = shortcodes.iter().map(|(n, v)| (n.to_string(), v.to_string())).collect();
+        Table::new(&EmojiConfig { expand: true, shortcodes, shorten })
+    }
+
+    #[test]
+    fn expand_test() {
+        let table = table(&[(":lenny:", "( ͡° ͜ʖ ͡°)"), ("fire", "🔥🔥")], false);
+        let expand = |text| table.expand(text);
+        assert_eq!(expand(":+1: nice :SHRUG:"), "👍 nice ¯\\_(ツ)_/¯");
+        assert_eq!(expand("hi :lenny: :fire:"), "hi ( ͡° ͜ʖ ͡°) 🔥🔥");
+        assert_eq!(expand("no:smile:space"), "no😄space");
+        // Unknown ones and times stay
+        assert_eq!(expand(":notme: at 10:30:45"), ":notme: at 10:30:45");
+
+        // Escaped
+        assert_eq!(expand(r"type \:smile: for 😄"), "type :smile: for 😄");
+        assert_eq!(expand(r"\:smile::smile:"), ":smile:😄");
+        assert_eq!(expand(r"a \:notme: stays"), r"a \:notme: stays");
+
+        // Links and code
+        assert_eq!(expand("http://x.onion/:smile:/a :smile:"), "http://x.onion/:smile:/a 😄");
+        assert_eq!(expand("see www.example.com/:wave: :wave:"), "see www.example.com/:wave: 👋");
+        assert_eq!(expand("run `echo :smile:` :smile:"), "run `echo :smile:` 😄");
+        assert_eq!(expand("a ` b :smile:"), "a ` b 😄");
+
+        let off = Table::new(&EmojiConfig { expand: false, ..EmojiConfig::default() });
+        assert_eq!(off.expand(r":smile: \:smile:"), r":smile: \:smile:");
+    }
+
+    #[test]
+    fn shorten_test() {
+        let table = table(&[("party_parrot", "🦜🎉"), ("lenny", "( ͡° ͜ʖ ͡°)")], true);
+        assert_eq!(table.shorten("nice 👍 and 👍 ❤\u{FE0F}"), "nice :+1: and :+1: :heart:");
+        // The longest emoji first, kaomoji are left alone
+        assert_eq!(table.shorten("🦜🎉 🎉 ¯\\_(ツ)_/¯ ( ͡° ͜ʖ ͡°)"), ":party_parrot: :tada: ¯\\_(ツ)_/¯ ( ͡° ͜ʖ ͡°)");
+        assert_eq!(table.shorten(&table.expand(":fire: :100:")), ":fire: :100:");
+        assert_eq!(Table::new(&EmojiConfig::default()).shorten("👍"), "👍");
+    }
+}
@@ -0,0 +1,372 @@
+// What to do about a failure, whichever error enum it comes in: retry,
+// log in again, or give up.
+
+/// The class of an error, see `Classify`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The server, the circuit or the proxy failed on the way, the same
+    /// call may work later.
+    Transient,
+    /// Kicked, or the session is gone. Log in again.
+    AuthExpired,
+    /// Not allowed to the session's role, or refused by the server for
+    /// good.
+    PermissionDenied,
+    /// The captcha answer was wrong or already used. Worth another login
+    /// with a new captcha.
+    Captcha,
+    /// Something given was wrong: a nick, a message, a file, a setting.
+    /// Not worth a retry as it is.
+    InvalidInput,
+    /// The rest: a page without what the protocol needs, a pin mismatch,
+    /// a local file that can't be written.
+    Fatal,
+}
+
+/// Implemented by every error of the crate.
+pub trait Classify {
+    fn kind(&self) -> ErrorKind;
+
+    /// Whether the same call is worth trying again, `ErrorKind::Transient`.
+    fn is_retryable(&self) -> bool {
+        self.kind() == ErrorKind::Transient
+    }
+}
+
+/// The kind of a request failing, transient unless it couldn't be built or
+/// sent at all.
+pub(crate) fn reqwest_kind(err: &reqwest::Error) -> ErrorKind {
+    if err.is_timeout() || err.is_connect() || err.is_request() || err.is_body() {
+        ErrorKind::Transient
+    } else {
+        ErrorKind::Fatal
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ErrorKind::*;
+    use super::*;
+    use crate::lechatphp::capture::CaptureErr;
+    use crate::lechatphp::chat::ChatErr;
+    use crate::lechatphp::catalog::CatalogErr;
+    use crate::lechatphp::client::{BuildErr, ConfigError};
+    use crate::lechatphp::display::DisplayErr;
+    use crate::lechatphp::filter::FilterHit;
+    use crate::lechatphp::history::HistoryErr;
+    use crate::lechatphp::interstitial::{Gate, Interstitial};
+    use crate::lechatphp::login_page::ParseErr;
+    use crate::lechatphp::messages::FetchErr;
+    use crate::lechatphp::moderation::ModErr;
+    use crate::lechatphp::notes::NotesErr;
+    use crate::lechatphp::onion::UrlErr;
+    use crate::lechatphp::onion_auth::OnionAuthErr;
+    use crate::lechatphp::outbox::OutboxErr;
+    use crate::lechatphp::post::PostErr;
+    use crate::lechatphp::profile::ProfileErr;
+    use crate::lechatphp::purge::PurgeErr;
+    use crate::lechatphp::responder::ResponderErr;
+    use crate::lechatphp::retry::SendErr;
+    use crate::lechatphp::rooms::RoomErr;
+    use crate::lechatphp::schedule::ScheduleErr;
+    use crate::lechatphp::share::ShareErr;
+    use crate::lechatphp::storage::StorageError;
+    use crate::lechatphp::tls::{PinMismatch, TlsErr};
+    use crate::lechatphp::tor::TorCtlErr;
+    use crate::lechatphp::unread::UnreadErr;
+    use crate::lechatphp::{LoginContext, LoginErr, LoginStage};
+    use http::StatusCode;
+    use std::fmt::Debug;
+    use std::io;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn check<E: Classify + Debug>(cases: Vec<(E, ErrorKind)>) {
+        for (err, kind) in cases {
+            assert_eq!(err.kind(), kind, "{:?}", err);
+        }
+    }
+
+    fn io_err() -> io::Error {
+        io::Error::other("io")
+    }
+
+    fn json_err() -> serde_json::Error {
+        serde_json::from_str::<u8>("x").unwrap_err()
+    }
+
+    fn regex_err() -> regex::Error {
+        let unclosed = "(".to_owned();
+        regex::Regex::new(&unclosed).unwrap_err()
+    }
+
+    // A request that can't be built, and one refused by a closed port
+    fn builder_err() -> reqwest::Error {
+        reqwest::blocking::Client::new().get("not a url").build().unwrap_err()
+    }
+
+    fn connect_err() -> reqwest::Error {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        reqwest::blocking::get(format!("http://127.0.0.1:{}", port)).unwrap_err()
+    }
+
+    fn context() -> LoginContext {
+        LoginContext::new(LoginStage::LoginPage, "http://x.onion/chat.php")
+    }
+
+    fn pin() -> PinMismatch {
+        PinMismatch { host: "x.onion".to_owned(), expected: "sha256/a".to_owned(), got: None }
+    }
+
+    fn path() -> PathBuf {
+        PathBuf::from("file")
+    }
+
+    #[test]
+    fn kind_test() {
+        check(vec![
+            (SendErr::Reqwest(connect_err()), Transient),
+            (SendErr::Reqwest(builder_err()), Fatal),
+            (SendErr::PinMismatch(pin()), Fatal),
+            (SendErr::Clearnet(UrlErr::Clearnet("x.com".to_owned())), InvalidInput),
+            (SendErr::ResponseTooLarge { limit: 1 }, Fatal),
+            (SendErr::Body(io_err()), Transient),
+            (SendErr::Truncated { expected: 2, got: 1 }, Transient),
+        ]);
+        let interstitial = Interstitial { gate: Gate::CookieRefresh, wait: Duration::ZERO, cookies: vec![] };
+        check(vec![
+            (LoginErr::ServerDownErr(context()), Transient),
+            (LoginErr::ServerDown500Err(context()), Transient),
+            (LoginErr::CaptchaUsedErr, Captcha),
+            (LoginErr::CaptchaWgErr, Captcha),
+            (LoginErr::RegErr, InvalidInput),
+            (LoginErr::NicknameErr, InvalidInput),
+            (LoginErr::KickedErr, AuthExpired),
+            (LoginErr::UnknownErr, Fatal),
+            (LoginErr::NoChatFrame(context()), Fatal),
+            (LoginErr::SessionNotFound { context: context(), dump: None }, Fatal),
+            (LoginErr::MalformedResponse { context: context(), missing: "form" }, Fatal),
+            (LoginErr::CaptchaPrompt(io_err()), Fatal),
+            (LoginErr::InvalidUrl(UrlErr::Parse("x".to_owned())), InvalidInput),
+            (LoginErr::PinMismatch(context(), pin()), Fatal),
+            (LoginErr::ResponseTooLarge { context: context(), limit: 1 }, Fatal),
+            (LoginErr::InterstitialBlocked { context: context(), page: interstitial, passes: 1 }, Transient),
+            (LoginErr::Body(context(), io_err()), Transient),
+            (LoginErr::TruncatedResponse(context()), Transient),
+            (LoginErr::ConnectTimeout(context(), connect_err()), Transient),
+            (LoginErr::ReadTimeout(context(), connect_err()), Transient),
+            (LoginErr::ProxyDown(context(), connect_err()), Transient),
+            (LoginErr::CircuitFailed(context(), connect_err()), Transient),
+            (LoginErr::Reqwest(context(), connect_err()), Transient),
+            (LoginErr::Reqwest(context(), builder_err()), Fatal),
+        ]);
+        let fetch = || {
+            vec![
+                (FetchErr::Kicked { reason: None }, AuthExpired),
+                (FetchErr::SessionExpired, AuthExpired),
+                (FetchErr::ServerDown(StatusCode::BAD_GATEWAY), Transient),
+                (FetchErr::NoMessages, Fatal),
+                (FetchErr::NoUserList, Fatal),
+                (FetchErr::NotFound, InvalidInput),
+                (FetchErr::PermissionDenied, PermissionDenied),
+                (FetchErr::Send(SendErr::Body(io_err())), Transient),
+            ]
+        };
+        check(fetch());
+        let post = || {
+            vec![
+                (PostErr::EmptyMessage, InvalidInput),
+                (PostErr::Flood { wait: None }, Transient),
+                (PostErr::Kicked { reason: None }, AuthExpired),
+                (PostErr::SessionExpired, AuthExpired),
+                (PostErr::RecipientOffline, InvalidInput),
+                (PostErr::TooLong { max: None }, InvalidInput),
+                (PostErr::MessageTooLong { max_len: 1, max_parts: 1 }, InvalidInput),
+                (PostErr::ServerDown(StatusCode::INTERNAL_SERVER_ERROR), Transient),
+                (PostErr::NoPostForm, Fatal),
+                (PostErr::NotAccepted, Fatal),
+                (PostErr::Filtered(FilterHit::Kick { pattern: "x".to_owned(), message: "x".to_owned() }), InvalidInput),
+                (PostErr::StaleForm, Transient),
+                (PostErr::UploadsDisabled, PermissionDenied),
+                (PostErr::UploadTooLarge { max: None }, InvalidInput),
+                (PostErr::UploadType { mime: "x".to_owned() }, InvalidInput),
+                (PostErr::File(io_err()), InvalidInput),
+                (PostErr::PermissionDenied, PermissionDenied),
+                (PostErr::Send(SendErr::ResponseTooLarge { limit: 1 }), Fatal),
+            ]
+        };
+        check(post());
+        let mods = || {
+            let nick = || "nick".to_owned();
+            vec![
+                (ModErr::Fetch(FetchErr::SessionExpired), AuthExpired),
+                (ModErr::NotStaff, PermissionDenied),
+                (ModErr::Refused { nick: nick() }, PermissionDenied),
+                (ModErr::NoSuchUser { nick: nick() }, InvalidInput),
+                (ModErr::NoSuchRoom { room: nick() }, InvalidInput),
+                (ModErr::Unsupported("kick"), Fatal),
+                (ModErr::NotCleaned, Fatal),
+                (ModErr::InvalidTarget { target: nick() }, InvalidInput),
+                (ModErr::BanNotApplied { target: nick() }, Fatal),
+                (ModErr::ConfirmationRequired { description: nick() }, InvalidInput),
+                (ModErr::ConfirmationLoop, Fatal),
+            ]
+        };
+        check(mods());
+        let profile = || {
+            vec![
+                (ProfileErr::Fetch(FetchErr::NoMessages), Fatal),
+                (ProfileErr::NoProfileForm, Fatal),
+                (ProfileErr::NoField("bgcolour"), InvalidInput),
+                (ProfileErr::NotSaved, InvalidInput),
+                (ProfileErr::CannotIgnore { nick: "x".to_owned() }, PermissionDenied),
+                (ProfileErr::WrongPassword, InvalidInput),
+                (ProfileErr::NickTaken { nick: "x".to_owned() }, InvalidInput),
+                (ProfileErr::Rejected { reason: "x".to_owned() }, InvalidInput),
+                (ProfileErr::Relogin(LoginErr::CaptchaWgErr), Captcha),
+            ]
+        };
+        check(profile());
+        let rooms = || {
+            vec![
+                (RoomErr::Fetch(FetchErr::Kicked { reason: None }), AuthExpired),
+                (RoomErr::Unknown { room: "x".to_owned(), rooms: vec![] }, InvalidInput),
+            ]
+        };
+        check(rooms());
+        // The chat's errors are the ones they wrap
+        let mut chat = vec![
+            (ChatErr::NotLoggedIn, AuthExpired),
+            (ChatErr::Login(LoginErr::KickedErr), AuthExpired),
+            (ChatErr::Logout(anyhow::anyhow!("x")), Fatal),
+        ];
+        chat.extend(fetch().into_iter().map(|(e, kind)| (ChatErr::Fetch(e), kind)));
+        chat.extend(post().into_iter().map(|(e, kind)| (ChatErr::Post(e), kind)));
+        chat.extend(mods().into_iter().map(|(e, kind)| (ChatErr::Mod(e), kind)));
+        chat.extend(profile().into_iter().map(|(e, kind)| (ChatErr::Profile(e), kind)));
+        chat.extend(rooms().into_iter().map(|(e, kind)| (ChatErr::Room(e), kind)));
+        check(chat);
+        check(vec![
+            (PurgeErr::NoDelete, PermissionDenied),
+            (PurgeErr::Delete(ModErr::NotStaff), PermissionDenied),
+            (PurgeErr::DeleteAll(PostErr::Flood { wait: None }), Transient),
+        ]);
+        check(vec![
+            (NotesErr::Fetch(FetchErr::ServerDown(StatusCode::BAD_GATEWAY)), Transient),
+            (NotesErr::AccessDenied, PermissionDenied),
+            (NotesErr::NoNotesForm, Fatal),
+            (NotesErr::Changed { last_edited: None }, Fatal),
+            (NotesErr::NotSaved, Fatal),
+        ]);
+        check(vec![
+            (ShareErr::NotConfigured, InvalidInput),
+            (ShareErr::Config("x".to_owned()), InvalidInput),
+            (ShareErr::File(io_err()), InvalidInput),
+            (ShareErr::TooLarge { max: 1 }, InvalidInput),
+            (ShareErr::Type { mime: "x".to_owned() }, InvalidInput),
+            (ShareErr::Send(SendErr::Reqwest(connect_err())), Transient),
+            (ShareErr::Status(StatusCode::SERVICE_UNAVAILABLE), Transient),
+            (ShareErr::Status(StatusCode::PAYLOAD_TOO_LARGE), InvalidInput),
+            (ShareErr::NoUrl, Fatal),
+            (ShareErr::Post { url: "x".to_owned(), error: PostErr::SessionExpired }, AuthExpired),
+        ]);
+        check(vec![
+            (ConfigError::InvalidProxyUrl("x".to_owned()), InvalidInput),
+            (ConfigError::LocalDnsProxy("x".to_owned()), InvalidInput),
+            (ConfigError::HttpProxyForOnion("x".to_owned()), InvalidInput),
+            (ConfigError::InvalidProxyChain("x"), InvalidInput),
+            (ConfigError::ZeroTimeout("read"), InvalidInput),
+            (ConfigError::ZeroRetryAttempts, InvalidInput),
+            (ConfigError::ZeroMaxBodySize, InvalidInput),
+            (ConfigError::NoUserAgent, InvalidInput),
+            (ConfigError::InvalidRateLimit, InvalidInput),
+            (ConfigError::InvalidMirrorUrl("x".to_owned()), InvalidInput),
+            (ConfigError::Clearnet(UrlErr::Clearnet("x.com".to_owned())), InvalidInput),
+            (ConfigError::InvalidColor("x".to_owned()), InvalidInput),
+            (ConfigError::EmptyLang, InvalidInput),
+            (ConfigError::EmptyAlphabet, InvalidInput),
+            (ConfigError::InvalidInkMargin(-1.0), InvalidInput),
+        ]);
+        check(vec![
+            (BuildErr::Config(ConfigError::ZeroMaxBodySize), InvalidInput),
+            (BuildErr::ProxyNotFound(vec![]), Fatal),
+            (BuildErr::Tls(TlsErr::InvalidPin("x".to_owned())), InvalidInput),
+            (BuildErr::Catalog(CatalogErr::NotText("x".to_owned())), InvalidInput),
+            (BuildErr::Reqwest(builder_err()), Fatal),
+        ]);
+        check(vec![
+            (CatalogErr::Io(io::Error::other("x")), Fatal),
+            (CatalogErr::Toml(toml::from_str::<toml::Table>("x =").unwrap_err()), InvalidInput),
+            (CatalogErr::NotText("x".to_owned()), InvalidInput),
+        ]);
+        let x = || "x".to_owned();
+        check(vec![
+            (UrlErr::Parse(x()), InvalidInput),
+            (UrlErr::MissingHost(x()), InvalidInput),
+            (UrlErr::OnionV2(x()), InvalidInput),
+            (UrlErr::OnionLength(x(), 1), InvalidInput),
+            (UrlErr::OnionCharset(x()), InvalidInput),
+            (UrlErr::OnionVersion(x(), 2), InvalidInput),
+            (UrlErr::OnionChecksum(x()), InvalidInput),
+            (UrlErr::Clearnet(x()), InvalidInput),
+        ]);
+        check(vec![(TlsErr::InvalidPin(x()), InvalidInput), (TlsErr::InvalidCertificate(path(), x()), InvalidInput)]);
+        check(vec![
+            (OnionAuthErr::Invalid(x()), InvalidInput),
+            (OnionAuthErr::Onion(UrlErr::OnionV2(x())), InvalidInput),
+            (OnionAuthErr::Io(path(), io_err()), InvalidInput),
+        ]);
+        check(vec![
+            (TorCtlErr::Io(io_err()), Transient),
+            (TorCtlErr::Auth(x()), InvalidInput),
+            (TorCtlErr::Protocol(x()), Fatal),
+        ]);
+        check(vec![(ResponderErr::EmptyTrigger(0), InvalidInput), (ResponderErr::Regex(0, regex_err()), InvalidInput)]);
+        check(vec![
+            (DisplayErr::EmptyNick(0), InvalidInput),
+            (DisplayErr::Regex(0, regex_err()), InvalidInput),
+            (DisplayErr::Color(0, x()), InvalidInput),
+        ]);
+        check(vec![
+            (HistoryErr::Disabled, Fatal),
+            (HistoryErr::Regex(regex_err()), InvalidInput),
+            (HistoryErr::Io(path(), io_err()), Fatal),
+            (HistoryErr::Write(io_err()), Fatal),
+        ]);
+        check(vec![
+            (ScheduleErr::NotConfigured, InvalidInput),
+            (ScheduleErr::Past, InvalidInput),
+            (ScheduleErr::EmptyMessage, InvalidInput),
+            (ScheduleErr::Io(path(), io_err()), Fatal),
+            (ScheduleErr::Json(path(), json_err()), Fatal),
+        ]);
+        check(vec![(CaptureErr::Io(path(), io_err()), Fatal), (CaptureErr::Json(path(), json_err()), Fatal)]);
+        check(vec![(OutboxErr::Io(path(), io_err()), Fatal), (OutboxErr::Json(path(), json_err()), Fatal)]);
+        check(vec![(UnreadErr::Io(path(), io_err()), Fatal), (UnreadErr::Json(path(), json_err()), Fatal)]);
+        check(vec![(StorageError { what: "x", path: path(), source: io_err() }, Fatal)]);
+        check(vec![
+            (ParseErr::Missing("x"), Fatal),
+            (ParseErr::ImageTooLarge, Fatal),
+            (ParseErr::NoChatFrame, Fatal),
+            (ParseErr::NoSession, Fatal),
+            (ParseErr::Truncated("body"), Transient),
+        ]);
+        #[cfg(feature = "arti")]
+        check(vec![
+            (crate::lechatphp::arti::ArtiErr::Io(io_err()), Fatal),
+            (crate::lechatphp::arti::ArtiErr::ClientAuth(x()), InvalidInput),
+        ]);
+    }
+
+    #[test]
+    fn is_retryable_test() {
+        assert!(LoginErr::ServerDownErr(context()).is_retryable());
+        assert!(PostErr::Send(SendErr::Body(io_err())).is_retryable());
+        // A new captcha or a new login is not the same call again
+        assert!(!LoginErr::CaptchaWgErr.is_retryable());
+        assert!(!FetchErr::SessionExpired.is_retryable());
+        assert!(!ChatErr::Post(PostErr::EmptyMessage).is_retryable());
+    }
+}
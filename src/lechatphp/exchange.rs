@@ -0,0 +1,263 @@
+use super::metrics::Operation;
+use super::retry::SendErr;
+use super::settings::Settings;
+use super::transport::Transport;
+use http::header::HeaderMap;
+use http::StatusCode;
+use reqwest::blocking::{multipart, Response};
+use std::fs::File;
+use std::future::Future;
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::Duration;
+
+/// A response as the protocol code sees it.
+#[derive(Debug)]
+pub struct Page {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    /// Read within the maximum body size and decoded, see `Transport::text`.
+    /// Kept apart so the status can be looked at when reading fails.
+    pub body: Result<String, SendErr>,
+}
+
+impl Page {
+    fn read(transport: &Transport, resp: Response) -> Self {
+        Self { status: resp.status(), headers: resp.headers().clone(), body: transport.text(resp) }
+    }
+}
+
+/// A file for `Exchange::post_multipart`, opened by the caller so it can
+/// tell what is wrong with it before anything is sent.
+#[derive(Debug)]
+pub struct Upload {
+    /// The form's name for it.
+    pub field: String,
+    pub file_name: String,
+    pub mime: String,
+    pub len: u64,
+    pub file: File,
+}
+
+/// What the protocol code (`login`, `logout`, interstitials) needs from
+/// HTTP. It is written once against this, and runs over the blocking
+/// `Transport`, the async one and the tests' mock alike.
+// Only implemented in this crate, no Send bound is promised
+#[allow(async_fn_in_trait)]
+pub trait Exchange {
+    async fn get(&self, operation: Operation, url: &str) -> Result<Page, SendErr>;
+
+    async fn post_form(&self, operation: Operation, url: &str, params: &[(&str, String)]) -> Result<Page, SendErr>;
+
+    /// A `multipart/form-data` POST of `params` then `upload`, sent once.
+    async fn post_multipart(&self, operation: Operation, url: &str, params: &[(&str, String)], upload: Upload) -> Result<Page, SendErr>;
+
+    /// Wait as the server asked, without holding up an async runtime.
+    async fn sleep(&self, duration: Duration);
+
+    /// See `Transport::add_cookie`.
+    fn add_cookie(&self, cookie: &str, url: &str);
+
+    /// The language pages are asked in, see `Transport::lang`.
+    fn lang(&self) -> &str;
+
+    /// See `Transport::settings`.
+    fn settings(&self) -> &Settings;
+}
+
+impl Exchange for Transport {
+    async fn get(&self, operation: Operation, url: &str) -> Result<Page, SendErr> {
+        Ok(Page::read(self, self.send_as(operation, Transport::get(self, url))?))
+    }
+
+    async fn post_form(&self, operation: Operation, url: &str, params: &[(&str, String)]) -> Result<Page, SendErr> {
+        Ok(Page::read(self, self.send_as(operation, self.post(url).form(params))?))
+    }
+
+    async fn post_multipart(&self, operation: Operation, url: &str, params: &[(&str, String)], upload: Upload) -> Result<Page, SendErr> {
+        let form = params.iter().fold(multipart::Form::new(), |form, (name, value)| form.text(name.to_string(), value.clone()));
+        // Streamed from the file, not read in memory first
+        let part = multipart::Part::reader_with_length(upload.file, upload.len).file_name(upload.file_name).mime_str(&upload.mime)?;
+        Ok(Page::read(self, self.send_multipart_as(operation, url, form.part(upload.field, part))?))
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+
+    fn add_cookie(&self, cookie: &str, url: &str) {
+        Transport::add_cookie(self, cookie, url);
+    }
+
+    fn lang(&self) -> &str {
+        Transport::lang(self)
+    }
+
+    fn settings(&self) -> &Settings {
+        Transport::settings(self)
+    }
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+/// Run protocol code on the current thread. Over the blocking `Transport`
+/// every await is ready at once, so this is a plain call.
+pub fn block_on<F: Future>(fut: F) -> F::Output {
+    let mut fut = pin!(fut);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        match fut.as_mut().poll(&mut cx) {
+            Poll::Ready(out) => return out,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+pub use self::async_transport::AsyncTransport;
+
+#[cfg(feature = "async")]
+mod async_transport {
+    use super::{Exchange, Page, Upload};
+    use crate::lechatphp::charset;
+    use crate::lechatphp::metrics::Operation;
+    use crate::lechatphp::retry::SendErr;
+    use crate::lechatphp::settings::Settings;
+    use crate::lechatphp::transport::cut_short;
+    use crate::lechatphp::LANG;
+    use reqwest::cookie::Jar;
+    use reqwest::header::CONTENT_TYPE;
+    use reqwest::{multipart, Client, Response, Url};
+    use std::io::Read;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    /// `Exchange` over an async `reqwest::Client`, for callers already on a
+    /// tokio runtime. Bodies get the same size limit and decoding as the
+    /// blocking transport, but there are no retries, rate limits, metrics
+    /// nor TLS pin checks yet: pinned mirrors need the blocking one.
+    pub struct AsyncTransport {
+        client: Client,
+        jar: Option<Arc<Jar>>,
+        lang: String,
+        settings: Arc<Settings>,
+    }
+
+    impl AsyncTransport {
+        /// `jar` should be the client's cookie provider, for `add_cookie`.
+        pub fn new(client: Client, jar: Option<Arc<Jar>>) -> Self {
+            Self { client, jar, lang: LANG.to_owned(), settings: Arc::default() }
+        }
+
+        /// Pages in `lang` instead of `LANG`.
+        pub fn with_lang(mut self, lang: &str) -> Self {
+            self.lang = lang.to_owned();
+            self
+        }
+
+        /// The settings of a blocking client, e.g. `Transport::settings`,
+        /// instead of the defaults.
+        pub fn with_settings(mut self, settings: Arc<Settings>) -> Self {
+            self.settings = settings;
+            self
+        }
+
+        async fn read(&self, resp: Response) -> Page {
+            let (status, headers) = (resp.status(), resp.headers().clone());
+            Page { status, headers, body: text(resp, self.settings.max_body_size).await }
+        }
+    }
+
+    async fn text(mut resp: Response, limit: usize) -> Result<String, SendErr> {
+        if resp.content_length().is_some_and(|len| len > limit as u64) {
+            return Err(SendErr::ResponseTooLarge { limit });
+        }
+        let content_type = resp.headers().get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).map(str::to_owned);
+        let expected = resp.content_length();
+        let mut body = Vec::new();
+        loop {
+            let chunk = match (resp.chunk().await, expected) {
+                (Ok(Some(chunk)), _) => chunk,
+                (Ok(None), _) => break,
+                (Err(e), Some(expected)) if cut_short(&e) => return Err(SendErr::Truncated { expected, got: body.len() }),
+                (Err(e), _) => return Err(e.into()),
+            };
+            body.extend_from_slice(&chunk);
+            if body.len() > limit {
+                return Err(SendErr::ResponseTooLarge { limit });
+            }
+        }
+        if let Some(expected) = expected.filter(|&expected| (body.len() as u64) < expected) {
+            return Err(SendErr::Truncated { expected, got: body.len() });
+        }
+        Ok(charset::decode(&body, content_type.as_deref()))
+    }
+
+    impl Exchange for AsyncTransport {
+        async fn get(&self, _operation: Operation, url: &str) -> Result<Page, SendErr> {
+            Ok(self.read(self.client.get(url).send().await?).await)
+        }
+
+        async fn post_form(&self, _operation: Operation, url: &str, params: &[(&str, String)]) -> Result<Page, SendErr> {
+            Ok(self.read(self.client.post(url).form(params).send().await?).await)
+        }
+
+        // Read in memory, reqwest only streams async bodies with its `stream`
+        // feature
+        async fn post_multipart(&self, _operation: Operation, url: &str, params: &[(&str, String)], upload: Upload) -> Result<Page, SendErr> {
+            let mut bytes = Vec::with_capacity(upload.len as usize);
+            (&upload.file).read_to_end(&mut bytes).map_err(SendErr::Body)?;
+            let form = params.iter().fold(multipart::Form::new(), |form, (name, value)| form.text(name.to_string(), value.clone()));
+            let part = multipart::Part::bytes(bytes).file_name(upload.file_name).mime_str(&upload.mime)?;
+            Ok(self.read(self.client.post(url).multipart(form.part(upload.field, part)).send().await?).await)
+        }
+
+        async fn sleep(&self, duration: Duration) {
+            tokio::time::sleep(duration).await;
+        }
+
+        fn add_cookie(&self, cookie: &str, url: &str) {
+            match (&self.jar, Url::parse(url)) {
+                (Some(jar), Ok(url)) => jar.add_cookie_str(cookie, &url),
+                _ => log::warn!("cookie for {} dropped, the cookie store is off", url),
+            }
+        }
+
+        fn lang(&self) -> &str {
+            &self.lang
+        }
+
+        fn settings(&self) -> &Settings {
+            &self.settings
+        }
+    }
+}
+
+#[cfg(all(test, feature = "async"))]
+mod tests {
+    use super::*;
+    use crate::lechatphp::mock::chat_server;
+    use crate::lechatphp::{login, login_with};
+
+    #[test]
+    fn async_transport_test() {
+        let server = chat_server("s3ss10n");
+        let jar = Arc::new(reqwest::cookie::Jar::default());
+        let client = reqwest::Client::builder().no_proxy().cookie_provider(Arc::clone(&jar)).build().unwrap();
+        let http = AsyncTransport::new(client, Some(jar));
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let session = runtime.block_on(login_with(&http, &server.url, "chat.php", "nick", "pass", "", true));
+        assert_eq!(session.unwrap(), "s3ss10n");
+        // Same protocol code as the blocking path
+        assert_eq!(login(&Transport::direct(), &server.url, "chat.php", "nick", "pass", "", true).unwrap(), "s3ss10n");
+    }
+}
@@ -0,0 +1,194 @@
+use super::exchange::{self, Exchange, Page};
+use super::messages::{parse_messages, read_page, FetchErr, Message};
+use super::metrics::Operation;
+use super::page_url;
+use super::rooms;
+use super::settings::Settings;
+use super::transport::Transport;
+use select::document::Document;
+use select::predicate::{Attr, Class, Name};
+use serde_derive::{Deserialize, Serialize};
+
+/// What a message feed answers with. Either way the messages are the
+/// view's `<div class="msg">` elements, newest first, and are parsed like
+/// the view's.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FeedFormat {
+    /// The elements alone, without the page around them.
+    Html,
+    /// `{"messages": ["<div class=\"msg\">...</div>", ...]}`
+    Json,
+}
+
+/// A fork's lighter alternative to the view for new messages.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FeedEndpoint {
+    /// The query after the chat page, with `{session}`, `{lang}` and
+    /// `{last_id}` filled in. The room goes after it on forks with rooms.
+    pub query: String,
+    pub format: FeedFormat,
+}
+
+impl FeedEndpoint {
+    fn url(&self, base_url: &str, page_php: &str, session: &str, lang: &str, room: Option<&str>, last_id: u64) -> String {
+        let query = self.query.replace("{session}", session).replace("{lang}", lang).replace("{last_id}", &last_id.to_string());
+        format!("{}?{}{}", page_url(base_url, page_php), query, rooms::query(room))
+    }
+}
+
+/// The endpoints of the forks known to have one, tried in order.
+pub fn default_endpoints() -> Vec<FeedEndpoint> {
+    vec![
+        FeedEndpoint { query: "action=ajax&do=messages&session={session}&lang={lang}&id={last_id}".to_owned(), format: FeedFormat::Json },
+        FeedEndpoint { query: "action=view&ajax=1&session={session}&lang={lang}&id={last_id}".to_owned(), format: FeedFormat::Html },
+    ]
+}
+
+/// Where `messages::fetch_messages_since` reads a session's messages.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Feed {
+    /// The chat's view, every fork has it.
+    #[default]
+    View,
+    Endpoint(FeedEndpoint),
+}
+
+#[derive(Deserialize)]
+struct JsonFeed {
+    messages: Vec<String>,
+}
+
+// The messages of a feed's answer, `NoMessages` when it isn't shaped like
+// one, a page the fork sends for actions it doesn't know for instance
+fn parse_feed(settings: &Settings, page: Page, format: FeedFormat) -> Result<Vec<Message>, FetchErr> {
+    let Page { status, headers, body } = page;
+    if status.is_client_error() {
+        return Err(FetchErr::NoMessages);
+    }
+    let fragment = match format {
+        FeedFormat::Html => body?,
+        FeedFormat::Json => serde_json::from_str::<JsonFeed>(&body?).map_err(|_| FetchErr::NoMessages)?.messages.concat(),
+    };
+    // Kicked, expired, or a whole page rather than the messages alone
+    let doc = read_page(Page { status, headers, body: Ok(fragment.clone()) })?;
+    let whole_page = doc.find(Attr("id", "messages")).next().is_some() || doc.find(Name("form")).next().is_some();
+    let empty = doc.find(Class("msg")).next().is_none();
+    if whole_page || (format == FeedFormat::Html && empty) {
+        return Err(FetchErr::NoMessages);
+    }
+    parse_messages(settings, &Document::from(format!(r#"<div id="messages">{}</div>"#, fragment).as_str()))
+}
+
+/// The messages after `last_id` from `endpoint`, newest first.
+pub(super) async fn fetch_endpoint_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    room: Option<&str>,
+    endpoint: &FeedEndpoint,
+    last_id: u64,
+) -> Result<Vec<Message>, FetchErr> {
+    let page = http.get(Operation::Fetch, &endpoint.url(base_url, page_php, session, http.lang(), room, last_id)).await?;
+    parse_feed(http.settings(), page, endpoint.format)
+}
+
+/// The first of the endpoints answering like a feed, or the view. Errors
+/// only leave an endpoint out.
+pub fn probe(transport: &Transport, base_url: &str, page_php: &str, session: &str) -> Feed {
+    exchange::block_on(probe_with(transport, base_url, page_php, session))
+}
+
+/// `probe` over any `Exchange`.
+pub async fn probe_with<E: Exchange>(http: &E, base_url: &str, page_php: &str, session: &str) -> Feed {
+    for endpoint in &http.settings().feed_endpoints {
+        match fetch_endpoint_with(http, base_url, page_php, session, rooms::room(http.settings(), session).as_deref(), endpoint, 0).await {
+            Ok(_) => {
+                log::info!("messages from {}", endpoint.query);
+                return Feed::Endpoint(endpoint.clone());
+            }
+            Err(e) => log::debug!("no feed at {}: {}", endpoint.query, e),
+        }
+    }
+    Feed::View
+}
+
+/// Read `session`'s messages from `feed` from now on, chosen after login.
+pub fn set_feed(settings: &Settings, session: &str, feed: Feed) {
+    settings.feeds.lock().unwrap().insert(session.to_owned(), feed);
+}
+
+pub fn feed(settings: &Settings, session: &str) -> Feed {
+    settings.feeds.lock().unwrap().get(session).cloned().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lechatphp::messages;
+    use crate::lechatphp::mock::{MockResponse, MockServer};
+
+    const VIEW: &str = include_str!("fixtures/view_private.html");
+    const FEED: &str = include_str!("fixtures/feed_private.json");
+
+    // Answers the view, and the feed when it has one. Anything else gets
+    // the login page, like le-chat for an action it doesn't know.
+    fn server(feed: bool) -> MockServer {
+        MockServer::start(move |req| {
+            if req.path.contains("action=view&session=") {
+                MockResponse::ok(VIEW)
+            } else if feed && req.path.contains("action=ajax&do=messages&") {
+                MockResponse::ok(FEED).with_header("content-type", "application/json")
+            } else {
+                MockResponse::ok(r#"<html><body><form><input name="nick"><input name="pass"></form></body></html>"#)
+            }
+        })
+    }
+
+    #[test]
+    fn probe_test() {
+        let transport = Transport::direct();
+        let html_only = server(false);
+        assert_eq!(probe(&transport, &html_only.url, "chat.php", "abc"), Feed::View);
+        let ajax = server(true);
+        let found = probe(&transport, &ajax.url, "chat.php", "abc");
+        assert_eq!(found, Feed::Endpoint(default_endpoints()[0].clone()));
+
+        // The same messages from either
+        let Feed::Endpoint(endpoint) = found else { unreachable!() };
+        let from_feed = exchange::block_on(fetch_endpoint_with(&transport, &ajax.url, "chat.php", "abc", None, &endpoint, 0)).unwrap();
+        let from_view = messages::parse_messages(&Settings::default(), &Document::from(VIEW)).unwrap();
+        assert_eq!(from_feed.len(), 5);
+        assert_eq!(from_feed, from_view);
+
+        // An endpoint that breaks later, the view takes over
+        let broken = MockServer::start(|req| match req.path.contains("action=view&session=") {
+            true => MockResponse::ok(VIEW),
+            false => MockResponse::new(500, "oops"),
+        });
+        set_feed(transport.settings(), "feed-test", Feed::Endpoint(endpoint));
+        let (fetched, _) = messages::fetch_messages_since(&transport, &broken.url, "chat.php", "feed-test", 0).unwrap();
+        assert_eq!(fetched.len(), 5);
+        assert_eq!(feed(transport.settings(), "feed-test"), Feed::View);
+    }
+
+    #[test]
+    fn parse_feed_test() {
+        let page = |body: &str| Page { status: http::StatusCode::OK, headers: Default::default(), body: Ok(body.to_owned()) };
+        let html = |body| parse_feed(&Settings::default(), page(body), FeedFormat::Html);
+        let json = |body| parse_feed(&Settings::default(), page(body), FeedFormat::Json);
+        assert_eq!(json(FEED).unwrap().len(), 5);
+        assert!(json(r#"{"messages": []}"#).unwrap().is_empty());
+        assert!(matches!(json(r#"{"ok": true}"#), Err(FetchErr::NoMessages)));
+        assert!(matches!(json(VIEW), Err(FetchErr::NoMessages)));
+
+        let divs: String = VIEW.lines().filter(|l| l.starts_with(r#"<div class="msg">"#)).collect();
+        assert_eq!(html(&divs).unwrap(), json(FEED).unwrap());
+        // The whole view, nothing, or a session gone aren't a feed
+        assert!(matches!(html(VIEW), Err(FetchErr::NoMessages)));
+        assert!(matches!(html(""), Err(FetchErr::NoMessages)));
+        assert!(matches!(html(r#"<h2>Error: Invalid/expired session</h2>"#), Err(FetchErr::SessionExpired)));
+    }
+}
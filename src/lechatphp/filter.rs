@@ -0,0 +1,218 @@
+use super::hook::Hook;
+use regex::{NoExpand, Regex, RegexBuilder};
+use std::sync::{Arc, Mutex, PoisonError};
+
+type Warning = dyn Fn(&str, &FilterHit) + Send + Sync;
+
+/// Compiled patterns past this size are taken literally, like invalid ones.
+const MAX_REGEX_SIZE: usize = 1 << 20;
+
+/// A word filter of the chat, as its admin page lists them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    /// The text matched, a regular expression when `regex`.
+    pub pattern: String,
+    /// What replaces a match, the kick message for `kick` filters.
+    pub replacement: String,
+    pub regex: bool,
+    pub case_sensitive: bool,
+    /// A match kicks the poster instead of being replaced.
+    pub kick: bool,
+    /// Private messages are left alone.
+    pub allow_in_pm: bool,
+}
+
+impl Filter {
+    /// The server's PHP patterns mostly read the same here. One that
+    /// doesn't compile, lookarounds for instance, is matched literally.
+    fn compile(&self) -> Regex {
+        let build = |pattern: &str| RegexBuilder::new(pattern).case_insensitive(!self.case_sensitive).size_limit(MAX_REGEX_SIZE).build();
+        let literal = regex::escape(&self.pattern);
+        let compiled = if self.regex { build(&self.pattern).or_else(|_| build(&literal)) } else { build(&literal) };
+        // Too big even as text, matches nothing
+        compiled.unwrap_or_else(|_| Regex::new("$^").unwrap())
+    }
+}
+
+/// What the chat's filters would do to a post.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterHit {
+    /// Posted as `text`, changed by the filters of `patterns`.
+    Replaced { text: String, patterns: Vec<String> },
+    /// Not posted, the poster is kicked with `message`.
+    Kick { pattern: String, message: String },
+}
+
+/// What the posting path does with a post the known filters would touch.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum FilterCheck {
+    /// Post without looking.
+    #[default]
+    Off,
+    /// Tell the warning callback, then post.
+    Warn,
+    /// Don't post it, see `PostErr::Filtered`.
+    Refuse,
+}
+
+/// Apply `filters` to `text` in order as the server does, `None` when it
+/// would be posted as is. A kick stops at its filter.
+pub fn apply(filters: &[Filter], text: &str, private: bool) -> Option<FilterHit> {
+    let compiled: Vec<_> = filters.iter().map(|f| (f.clone(), f.compile())).collect();
+    apply_compiled(&compiled, text, private)
+}
+
+fn apply_compiled(filters: &[(Filter, Regex)], text: &str, private: bool) -> Option<FilterHit> {
+    let mut text = text.to_owned();
+    let mut patterns = vec![];
+    for (filter, rgx) in filters.iter().filter(|(f, _)| !(private && f.allow_in_pm)) {
+        if !rgx.is_match(&text) {
+            continue;
+        }
+        if filter.kick {
+            return Some(FilterHit::Kick { pattern: filter.pattern.clone(), message: filter.replacement.clone() });
+        }
+        // `$1` refers to a group in regex filters only
+        text = if filter.regex {
+            rgx.replace_all(&text, filter.replacement.as_str()).into_owned()
+        } else {
+            rgx.replace_all(&text, NoExpand(&filter.replacement)).into_owned()
+        };
+        patterns.push(filter.pattern.clone());
+    }
+    (!patterns.is_empty()).then_some(FilterHit::Replaced { text, patterns })
+}
+
+// `Err` when the post must not be sent, `Ok` with what to warn about
+fn verdict(check: FilterCheck, filters: &[(Filter, Regex)], text: &str, private: bool) -> Result<Option<FilterHit>, FilterHit> {
+    if check == FilterCheck::Off {
+        return Ok(None);
+    }
+    match apply_compiled(filters, text, private) {
+        Some(hit) if check == FilterCheck::Refuse => Err(hit),
+        hit => Ok(hit),
+    }
+}
+
+/// The filters a client's posts are checked against, and who to warn.
+/// Both are cloned out of their lock before the filters run or the warning
+/// is called, see `pre_check`.
+#[derive(Default)]
+pub struct Filters {
+    compiled: Mutex<Arc<Vec<(Filter, Regex)>>>,
+    warn: Hook<Warning>,
+}
+
+impl Filters {
+    /// From `moderation::fetch_filters` for staff, or as configured.
+    pub fn set(&self, filters: Vec<Filter>) {
+        let compiled = filters.into_iter().map(|f| {
+            let rgx = f.compile();
+            (f, rgx)
+        });
+        *self.compiled.lock().unwrap_or_else(PoisonError::into_inner) = Arc::new(compiled.collect());
+    }
+
+    /// Called with the post and what the filters would do, under
+    /// `FilterCheck::Warn`.
+    pub fn set_warning(&self, warn: impl Fn(&str, &FilterHit) + Send + Sync + 'static) {
+        self.warn.set(Arc::new(warn));
+    }
+
+    /// The known filters' verdict on a post under the client's `check`:
+    /// `Err` with what they would do when it must not be posted.
+    pub(super) fn pre_check(&self, check: FilterCheck, text: &str, private: bool) -> Result<(), FilterHit> {
+        let filters = self.compiled.lock().unwrap_or_else(PoisonError::into_inner).clone();
+        if let Some(hit) = verdict(check, &filters, text, private)? {
+            if let Some(warn) = self.warn.get() {
+                warn(text, &hit);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(pattern: &str, replacement: &str) -> Filter {
+        Filter {
+            pattern: pattern.to_owned(),
+            replacement: replacement.to_owned(),
+            regex: false,
+            case_sensitive: false,
+            kick: false,
+            allow_in_pm: false,
+        }
+    }
+
+    fn replaced(text: &str, patterns: &[&str]) -> Option<FilterHit> {
+        Some(FilterHit::Replaced { text: text.to_owned(), patterns: patterns.iter().map(|p| p.to_string()).collect() })
+    }
+
+    #[test]
+    fn apply_test() {
+        let filters = [
+            filter("darn", "d**n"),
+            Filter { regex: true, ..filter(r"(\d{3})-\d{4}", "$1-XXXX") },
+            Filter { case_sensitive: true, ..filter("BOB", "bob") },
+            Filter { allow_in_pm: true, ..filter("secret", "[redacted]") },
+        ];
+        assert_eq!(apply(&filters, "hello there", false), None);
+        assert_eq!(apply(&filters, "DARN it, darn", false), replaced("d**n it, d**n", &["darn"]));
+        assert_eq!(apply(&filters, "call 555-1234", false), replaced("call 555-XXXX", &[r"(\d{3})-\d{4}"]));
+        assert_eq!(apply(&filters, "bob and Bob", false), None);
+        assert_eq!(apply(&filters, "BOB's secret", false), replaced("bob's [redacted]", &["BOB", "secret"]));
+        assert_eq!(apply(&filters, "a secret", true), None);
+
+        // In order, a kick stops there
+        let filters = [filter("spam", "ham"), Filter { kick: true, ..filter("ham", "No ham here") }];
+        assert_eq!(apply(&filters, "spam", false), Some(FilterHit::Kick { pattern: "ham".to_owned(), message: "No ham here".to_owned() }));
+
+        // Literal text and replacements, and PHP patterns that don't compile
+        assert_eq!(apply(&[filter("a.b", "$0")], "axb a.b", false), replaced("axb $0", &["a.b"]));
+        let lookahead = Filter { regex: true, ..filter("foo(?=bar)", "x") };
+        assert_eq!(apply(std::slice::from_ref(&lookahead), "foobar", false), None);
+        assert_eq!(apply(&[lookahead], "foo(?=bar)", false), replaced("x", &["foo(?=bar)"]));
+    }
+
+    #[test]
+    fn verdict_test() {
+        let filters: Vec<_> = [filter("darn", "d**n"), Filter { kick: true, ..filter("spam", "bye") }]
+            .into_iter()
+            .map(|f| {
+                let rgx = f.compile();
+                (f, rgx)
+            })
+            .collect();
+        let hit = replaced("d**n you", &["darn"]).unwrap();
+        let verdict = |check, text| verdict(check, &filters, text, false);
+
+        assert_eq!(verdict(FilterCheck::Off, "darn you"), Ok(None));
+        assert_eq!(verdict(FilterCheck::Warn, "darn you"), Ok(Some(hit.clone())));
+        assert_eq!(verdict(FilterCheck::Warn, "fine"), Ok(None));
+        assert_eq!(verdict(FilterCheck::Refuse, "darn you"), Err(hit));
+        assert!(matches!(verdict(FilterCheck::Refuse, "spam"), Err(FilterHit::Kick { .. })));
+        assert_eq!(verdict(FilterCheck::Refuse, "fine"), Ok(None));
+    }
+
+    #[test]
+    fn reentrant_warning_test() {
+        // The warning replacing the filters and itself, which deadlocked
+        // while it was called under their locks
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        let calls = Arc::new(AtomicUsize::new(0));
+        let filters = Arc::new(Filters::default());
+        filters.set(vec![filter("darn", "d**n")]);
+        let (inner, counted) = (filters.clone(), calls.clone());
+        filters.set_warning(move |_, _| {
+            counted.fetch_add(1, Ordering::SeqCst);
+            inner.set(vec![]);
+            inner.set_warning(|_, _| panic!("not called, the filters are gone"));
+        });
+        assert_eq!(filters.pre_check(FilterCheck::Warn, "darn", false), Ok(()));
+        assert_eq!(filters.pre_check(FilterCheck::Warn, "darn", false), Ok(()));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,751 @@
+use super::error_kind::{Classify, ErrorKind};
+use super::messages::{Message, MessageKind};
+use super::settings::Settings;
+use chrono::{DateTime, FixedOffset};
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use lazy_static::lazy_static;
+use regex::{Captures, Regex, RegexBuilder};
+use reqwest::Url;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+use std::io;
+use thiserror::Error;
+
+lazy_static! {
+    // What may be a nick in a text, for anonymized exports
+    static ref NICK_TOKEN_RGX: Regex = Regex::new(r#"[^\s,.:;!?()<>"'@]+"#).unwrap();
+}
+
+/// Default for `HistoryConfig::max_bytes`.
+pub const DEFAULT_MAX_HISTORY_BYTES: u64 = 50 * 1024 * 1024;
+
+/// Where and how much of the chat to keep.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryConfig {
+    /// One store per server and profile in there, `<host>[+<profile>].jsonl`.
+    pub dir: PathBuf,
+    /// Kept apart from the other profiles on the same server.
+    pub profile: Option<String>,
+    /// Most a store takes on disk. Past half of it the file is rotated,
+    /// the one rotated before is dropped.
+    pub max_bytes: u64,
+    /// Private messages are left out unless this is set.
+    pub include_private: bool,
+}
+
+/// `MessageKind` without what it carries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    #[default]
+    Normal,
+    Members,
+    Staff,
+    Private,
+    System,
+    Action,
+}
+
+impl From<&MessageKind> for EntryKind {
+    fn from(kind: &MessageKind) -> Self {
+        match kind {
+            MessageKind::Normal => EntryKind::Normal,
+            MessageKind::Members => EntryKind::Members,
+            MessageKind::Staff => EntryKind::Staff,
+            MessageKind::Private => EntryKind::Private,
+            MessageKind::System(_) => EntryKind::System,
+            MessageKind::Action => EntryKind::Action,
+        }
+    }
+}
+
+/// A message as kept, and as `export` writes it in JSONL.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub server: String,
+    pub id: Option<u64>,
+    /// As shown, the parsed time is `time()`.
+    pub timestamp: String,
+    /// RFC 3339, when the timestamp could be read.
+    time: Option<String>,
+    /// Lines kept before it was there read as `Normal`.
+    #[serde(default)]
+    pub kind: EntryKind,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub text: String,
+    /// The room, on forks with several.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+}
+
+impl Entry {
+    pub(super) fn new(server: &str, message: &Message) -> Self {
+        Self {
+            server: server.to_owned(),
+            id: message.id,
+            timestamp: message.timestamp.clone(),
+            time: message.time.map(|t| t.to_rfc3339()),
+            kind: EntryKind::from(&message.kind),
+            from: message.from.clone(),
+            to: message.to.clone(),
+            text: message.text.clone(),
+            room: message.room.clone(),
+        }
+    }
+
+    pub fn time(&self) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc3339(self.time.as_deref()?).ok()
+    }
+}
+
+/// What `search` looks for besides the text.
+#[derive(Debug, Clone, Default)]
+pub struct SearchFilter {
+    /// The query is a regular expression, else a substring. Both ignore case.
+    pub regex: bool,
+    /// Only this server's store, a base url.
+    pub server: Option<String>,
+    /// Only messages of this nick.
+    pub from: Option<String>,
+    /// Only messages of this room, `Some("")` for the chat's default room.
+    pub room: Option<String>,
+    pub since: Option<DateTime<FixedOffset>>,
+    pub until: Option<DateTime<FixedOffset>>,
+    /// Messages kept around each match, before and after, of its room.
+    pub context: usize,
+}
+
+/// A message found, with its neighbours in the store, oldest first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchHit {
+    pub entry: Entry,
+    pub before: Vec<Entry>,
+    pub after: Vec<Entry>,
+}
+
+/// The messages `export` writes, by time. Messages without a readable
+/// time are only left out when a bound is set.
+#[derive(Debug, Clone, Default)]
+pub struct ExportRange {
+    pub since: Option<DateTime<FixedOffset>>,
+    pub until: Option<DateTime<FixedOffset>>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ExportFormat {
+    /// One `Entry` as JSON per line, for other tools.
+    #[default]
+    Jsonl,
+    /// `2026-10-17 19:40:02 <alice> hello`, to be read.
+    Text,
+}
+
+/// What `export` leaves out or hides.
+#[derive(Debug, Clone, Default)]
+pub struct ExportOptions {
+    pub include_private: bool,
+    pub include_system: bool,
+    /// Nicks become `user1`, `user2`… in their order of appearance, the
+    /// same throughout the export. In the text, only the nicks that sent or
+    /// received a message before are replaced.
+    pub anonymize: bool,
+}
+
+/// Where `export` reads the messages.
+#[derive(Debug, Clone)]
+pub enum ExportSource<'a> {
+    /// The enabled history, one server's store or all.
+    Store { server: Option<String> },
+    /// Messages fetched from `server`, newest first as they come.
+    Messages { server: &'a str, messages: &'a [Message] },
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum HistoryErr {
+    /// History isn't enabled, see `ClientConfig::history`.
+    #[error("history is disabled")]
+    Disabled,
+    #[error("{0}")]
+    Regex(#[source] regex::Error),
+    #[error("history {}: {}", .0.display(), .1)]
+    Io(PathBuf, #[source] io::Error),
+    /// Writing an export.
+    #[error("failed to write export: {0}")]
+    Write(#[source] io::Error),
+}
+
+impl Classify for HistoryErr {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            HistoryErr::Regex(_) => ErrorKind::InvalidInput,
+            HistoryErr::Disabled | HistoryErr::Io(..) | HistoryErr::Write(_) => ErrorKind::Fatal,
+        }
+    }
+}
+
+enum Command {
+    Append(String, Vec<Entry>),
+    Flush(Sender<()>),
+}
+
+/// A history directory, written by a thread of its own so fetching never
+/// waits on the disk.
+pub struct History {
+    config: HistoryConfig,
+    writer: Option<Sender<Command>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+fn sanitize(name: &str) -> String {
+    name.chars().map(|c| if c.is_alphanumeric() || "._-".contains(c) { c } else { '_' }).collect()
+}
+
+// `http://x.onion/chat.php` and profile `alice`: `x.onion+alice`, no host
+// has a `+`
+fn store_name(server: &str, profile: Option<&str>) -> String {
+    let host = Url::parse(server).ok().and_then(|u| u.host_str().map(str::to_owned)).unwrap_or_else(|| server.to_owned());
+    match profile {
+        Some(profile) => format!("{}+{}", sanitize(&host), sanitize(profile)),
+        None => sanitize(&host),
+    }
+}
+
+fn rotated(path: &Path) -> PathBuf {
+    path.with_extension("1.jsonl")
+}
+
+fn append_lines(path: &Path, lines: &str) -> io::Result<()> {
+    OpenOptions::new().create(true).append(true).open(path)?.write_all(lines.as_bytes())
+}
+
+// Append each store's entries in one write, rotating before the file would
+// grow past half the limit
+fn write_batch(dir: &Path, max_bytes: u64, batch: HashMap<String, Vec<Entry>>) -> Result<(), HistoryErr> {
+    for (store, entries) in batch {
+        let path = dir.join(format!("{}.jsonl", store));
+        let io_err = |e| HistoryErr::Io(path.clone(), e);
+        let mut len = fs::metadata(&path).map_or(0, |m| m.len());
+        let mut lines = String::new();
+        for entry in entries {
+            let line = serde_json::to_string(&entry).expect("entries serialize") + "\n";
+            if len > 0 && len + line.len() as u64 > max_bytes / 2 {
+                append_lines(&path, &lines).map_err(io_err)?;
+                fs::rename(&path, rotated(&path)).map_err(io_err)?;
+                lines.clear();
+                len = 0;
+            }
+            len += line.len() as u64;
+            lines.push_str(&line);
+        }
+        append_lines(&path, &lines).map_err(io_err)?;
+    }
+    Ok(())
+}
+
+fn run_writer(dir: PathBuf, max_bytes: u64, commands: Receiver<Command>) {
+    let mut failed = false;
+    while let Ok(command) = commands.recv() {
+        // Whatever else is waiting goes in the same batch
+        let mut batch: HashMap<String, Vec<Entry>> = HashMap::new();
+        let mut flushed = vec![];
+        for command in std::iter::once(command).chain(commands.try_iter()) {
+            match command {
+                Command::Append(store, entries) => batch.entry(store).or_default().extend(entries),
+                Command::Flush(done) => flushed.push(done),
+            }
+        }
+        if let Err(e) = write_batch(&dir, max_bytes, batch) {
+            // Once, the chat goes on without
+            if !failed {
+                log::warn!("{}, messages are not kept", e);
+                failed = true;
+            }
+        }
+        for done in flushed {
+            let _ = done.send(());
+        }
+    }
+}
+
+// The entries of a store oldest first, read a line at a time
+fn for_each_entry(path: &Path, mut f: impl FnMut(Entry) -> Result<(), HistoryErr>) -> Result<(), HistoryErr> {
+    for path in [rotated(path), path.to_owned()] {
+        let file = match fs::File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(HistoryErr::Io(path, e)),
+        };
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| HistoryErr::Io(path.clone(), e))?;
+            // A line cut short by a crash is skipped
+            if let Ok(entry) = serde_json::from_str(&line) {
+                f(entry)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_store(path: &Path) -> Result<Vec<Entry>, HistoryErr> {
+    let mut entries = vec![];
+    for_each_entry(path, |entry| {
+        entries.push(entry);
+        Ok(())
+    })?;
+    Ok(entries)
+}
+
+// Writes the entries given to it one at a time
+struct Exporter<'a, W: Write> {
+    range: &'a ExportRange,
+    format: ExportFormat,
+    options: &'a ExportOptions,
+    /// By lowercase nick.
+    pseudonyms: HashMap<String, String>,
+    writer: W,
+    written: usize,
+}
+
+impl<'a, W: Write> Exporter<'a, W> {
+    fn new(range: &'a ExportRange, format: ExportFormat, options: &'a ExportOptions, writer: W) -> Self {
+        Self { range, format, options, pseudonyms: HashMap::new(), writer, written: 0 }
+    }
+
+    fn pseudonym(&mut self, nick: &str) -> String {
+        let next = format!("user{}", self.pseudonyms.len() + 1);
+        self.pseudonyms.entry(nick.to_lowercase()).or_insert(next).clone()
+    }
+
+    fn anonymize(&mut self, entry: &mut Entry) {
+        entry.from = entry.from.as_deref().map(|nick| self.pseudonym(nick));
+        entry.to = entry.to.as_deref().map(|nick| self.pseudonym(nick));
+        let text = NICK_TOKEN_RGX.replace_all(&entry.text, |caps: &Captures| {
+            self.pseudonyms.get(&caps[0].to_lowercase()).cloned().unwrap_or_else(|| caps[0].to_owned())
+        });
+        entry.text = text.into_owned();
+    }
+
+    fn write(&mut self, mut entry: Entry) -> Result<(), HistoryErr> {
+        let time = entry.time();
+        let in_range = self.range.since.is_none_or(|since| time.is_some_and(|t| t >= since))
+            && self.range.until.is_none_or(|until| time.is_some_and(|t| t <= until));
+        let wanted = match entry.kind {
+            EntryKind::Private => self.options.include_private,
+            EntryKind::System => self.options.include_system,
+            _ => true,
+        };
+        if !in_range || !wanted {
+            return Ok(());
+        }
+        if self.options.anonymize {
+            self.anonymize(&mut entry);
+        }
+        let line = match self.format {
+            ExportFormat::Jsonl => serde_json::to_string(&entry).unwrap(),
+            ExportFormat::Text => text_line(&entry),
+        };
+        writeln!(self.writer, "{}", line).map_err(HistoryErr::Write)?;
+        self.written += 1;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<usize, HistoryErr> {
+        self.writer.flush().map_err(HistoryErr::Write)?;
+        Ok(self.written)
+    }
+}
+
+// An entry of the text export, on one line
+fn text_line(entry: &Entry) -> String {
+    let when = entry.time().map_or_else(|| entry.timestamp.clone(), |t| t.format("%Y-%m-%d %H:%M:%S").to_string());
+    let from = entry.from.as_deref().unwrap_or("?");
+    let text = entry.text.replace('\n', " ");
+    match entry.kind {
+        EntryKind::System => format!("{} * {}", when, text),
+        EntryKind::Action => format!("{} * {} {}", when, from, text),
+        EntryKind::Private => format!("{} [{} → {}] {}", when, from, entry.to.as_deref().unwrap_or("?"), text),
+        EntryKind::Members => format!("{} [members] <{}> {}", when, from, text),
+        EntryKind::Staff => format!("{} [staff] <{}> {}", when, from, text),
+        EntryKind::Normal => format!("{} <{}> {}", when, from, text),
+    }
+}
+
+/// Write `messages` fetched from `server` to `writer`, oldest first, and
+/// tell how many were written.
+pub fn export_messages(
+    server: &str,
+    messages: &[Message],
+    range: &ExportRange,
+    format: ExportFormat,
+    options: &ExportOptions,
+    writer: impl Write,
+) -> Result<usize, HistoryErr> {
+    let mut exporter = Exporter::new(range, format, options, writer);
+    for message in messages.iter().rev() {
+        exporter.write(Entry::new(server, message))?;
+    }
+    exporter.finish()
+}
+
+impl History {
+    pub fn open(config: HistoryConfig) -> Result<Self, HistoryErr> {
+        fs::create_dir_all(&config.dir).map_err(|e| HistoryErr::Io(config.dir.clone(), e))?;
+        let (writer, commands) = unbounded();
+        let (dir, max_bytes) = (config.dir.clone(), config.max_bytes);
+        let thread = thread::spawn(move || run_writer(dir, max_bytes, commands));
+        Ok(Self { config, writer: Some(writer), thread: Some(thread) })
+    }
+
+    /// Keep `messages` fetched from `server`, in the background.
+    pub fn append(&self, server: &str, messages: &[Message]) {
+        let entries: Vec<_> = messages
+            .iter()
+            .filter(|m| self.config.include_private || m.kind != MessageKind::Private)
+            .map(|m| Entry::new(server, m))
+            .collect();
+        if entries.is_empty() {
+            return;
+        }
+        let store = store_name(server, self.config.profile.as_deref());
+        if let Some(writer) = &self.writer {
+            let _ = writer.send(Command::Append(store, entries));
+        }
+    }
+
+    /// Wait for what was appended so far to be written.
+    pub fn flush(&self) {
+        let (done, written) = unbounded();
+        if self.writer.as_ref().is_some_and(|w| w.send(Command::Flush(done)).is_ok()) {
+            let _ = written.recv();
+        }
+    }
+
+    // The stores of the profile, of one server or all
+    fn stores(&self, server: Option<&str>) -> Result<Vec<PathBuf>, HistoryErr> {
+        let profile = self.config.profile.as_deref().map(sanitize);
+        let server = server.map(|s| store_name(s, self.config.profile.as_deref()));
+        let mut stores = vec![];
+        for entry in fs::read_dir(&self.config.dir).map_err(|e| HistoryErr::Io(self.config.dir.clone(), e))? {
+            let path = entry.map_err(|e| HistoryErr::Io(self.config.dir.clone(), e))?.path();
+            let Some(name) = path.file_name().and_then(|n| n.to_str()).and_then(|n| n.strip_suffix(".jsonl")) else {
+                continue;
+            };
+            // The rotated files are read along with theirs
+            if name.ends_with(".1") {
+                continue;
+            }
+            let ours = name.split_once('+').map(|(_, p)| p) == profile.as_deref();
+            if ours && server.as_ref().is_none_or(|s| s == name) {
+                stores.push(path);
+            }
+        }
+        stores.sort();
+        Ok(stores)
+    }
+
+    /// The kept messages with `query` in their text, newest first.
+    pub fn search(&self, query: &str, filter: &SearchFilter) -> Result<Vec<SearchHit>, HistoryErr> {
+        self.flush();
+        let pattern = if filter.regex { query.to_owned() } else { regex::escape(query) };
+        let query: Regex = RegexBuilder::new(&pattern).case_insensitive(true).build().map_err(HistoryErr::Regex)?;
+        let stores = self.stores(filter.server.as_deref())?;
+
+        let mut hits = vec![];
+        for path in stores {
+            // By room, so the context around a hit is of its room
+            let mut rooms: BTreeMap<String, Vec<Entry>> = BTreeMap::new();
+            for entry in read_store(&path)? {
+                rooms.entry(entry.room.clone().unwrap_or_default()).or_default().push(entry);
+            }
+            for (room, entries) in rooms {
+                if filter.room.as_ref().is_some_and(|r| *r != room) {
+                    continue;
+                }
+                for (i, entry) in entries.iter().enumerate() {
+                    let time = entry.time();
+                    let matches = query.is_match(&entry.text)
+                        && filter.from.as_ref().is_none_or(|from| entry.from.as_ref() == Some(from))
+                        && filter.since.is_none_or(|since| time.is_some_and(|t| t >= since))
+                        && filter.until.is_none_or(|until| time.is_some_and(|t| t <= until));
+                    if matches {
+                        let before = entries[i.saturating_sub(filter.context)..i].to_vec();
+                        let after = entries[i + 1..(i + 1 + filter.context).min(entries.len())].to_vec();
+                        hits.push(SearchHit { entry: entry.clone(), before, after });
+                    }
+                }
+            }
+        }
+        hits.sort_by_key(|hit| std::cmp::Reverse(hit.entry.time()));
+        Ok(hits)
+    }
+
+    /// The private messages kept of every server, oldest first. `None`
+    /// when the config leaves them out.
+    pub fn private_entries(&self) -> Option<Result<Vec<Entry>, HistoryErr>> {
+        if !self.config.include_private {
+            return None;
+        }
+        self.flush();
+        let read = || {
+            let mut entries = vec![];
+            for path in self.stores(None)? {
+                for_each_entry(&path, |entry| {
+                    if entry.kind == EntryKind::Private {
+                        entries.push(entry);
+                    }
+                    Ok(())
+                })?;
+            }
+            // The servers' stores one after the other, merged by time
+            entries.sort_by_key(Entry::time);
+            Ok(entries)
+        };
+        Some(read())
+    }
+
+    /// Write what is kept of `server`, or of all servers one after the
+    /// other, to `writer` a line at a time, oldest first. Tells how many
+    /// messages were written.
+    pub fn export(
+        &self,
+        server: Option<&str>,
+        range: &ExportRange,
+        format: ExportFormat,
+        options: &ExportOptions,
+        writer: impl Write,
+    ) -> Result<usize, HistoryErr> {
+        self.flush();
+        let mut exporter = Exporter::new(range, format, options, writer);
+        for path in self.stores(server)? {
+            for_each_entry(&path, |entry| exporter.write(entry))?;
+        }
+        exporter.finish()
+    }
+}
+
+impl Drop for History {
+    // What was appended is written before the store is let go
+    fn drop(&mut self) {
+        self.writer.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The store `ClientConfig::history` keeps the messages delivered in,
+/// `None` without one or when it can't be opened.
+pub(super) fn open(config: Option<HistoryConfig>) -> Option<History> {
+    History::open(config?).map_err(|e| log::warn!("{}, messages are not kept", e)).ok()
+}
+
+/// Keep `messages`, new ones from `server`, when history is enabled.
+pub(super) fn record(settings: &Settings, server: &str, messages: &[Message]) {
+    if let Some(history) = &settings.history {
+        history.append(server, messages);
+    }
+}
+
+/// `History::private_entries` of the enabled history, `None` without one.
+pub(super) fn private_entries(settings: &Settings) -> Option<Result<Vec<Entry>, HistoryErr>> {
+    settings.history.as_ref()?.private_entries()
+}
+
+/// `History::search` in the enabled history.
+pub fn search(settings: &Settings, query: &str, filter: &SearchFilter) -> Result<Vec<SearchHit>, HistoryErr> {
+    settings.history.as_ref().ok_or(HistoryErr::Disabled)?.search(query, filter)
+}
+
+/// Export the enabled history, or messages fetched, see `History::export`.
+pub fn export(
+    settings: &Settings,
+    source: ExportSource,
+    range: &ExportRange,
+    format: ExportFormat,
+    options: &ExportOptions,
+    writer: impl Write,
+) -> Result<usize, HistoryErr> {
+    match source {
+        ExportSource::Store { server } => {
+            settings.history.as_ref().ok_or(HistoryErr::Disabled)?.export(server.as_deref(), range, format, options, writer)
+        }
+        ExportSource::Messages { server, messages } => export_messages(server, messages, range, format, options, writer),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lechatphp::messages::SystemEvent;
+
+    const BASE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+
+    fn config(name: &str) -> HistoryConfig {
+        let dir = std::env::temp_dir().join(format!("bhcli-history-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        HistoryConfig { dir, profile: None, max_bytes: 1 << 20, include_private: false }
+    }
+
+    fn at(time: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(&format!("2026-10-17T{}+02:00", time)).unwrap()
+    }
+
+    fn msg(time: &str, from: &str, text: &str) -> Message {
+        Message {
+            id: None,
+            timestamp: format!("10-17 {}", time),
+            time: Some(at(time)),
+            from: Some(from.to_owned()),
+            to: None,
+            text: text.to_owned(),
+            spans: vec![],
+            color: None,
+            display_from: None,
+            display_color: None,
+            kind: MessageKind::Normal,
+            mentions_me: false,
+            room: None,
+        }
+    }
+
+    fn texts(hits: &[SearchHit]) -> Vec<&str> {
+        hits.iter().map(|h| h.entry.text.as_str()).collect()
+    }
+
+    #[test]
+    fn history_test() {
+        let config = config("search");
+        let joined = Message { from: None, kind: MessageKind::System(SystemEvent::Joined("alice".to_owned())), ..msg("19:39:01", "", "alice entered the chat.") };
+        let whisper = Message { to: Some("bob".to_owned()), kind: MessageKind::Private, ..msg("19:39:30", "alice", "see you at 9") };
+        let history = History::open(config.clone()).unwrap();
+        history.append(BASE_URL, &[joined, whisper.clone(), msg("19:39:55", "bob", "hi alice")]);
+        history.append("http://other.onion", &[msg("19:40:00", "carol", "hello from elsewhere")]);
+        // Reopened, like after a restart
+        drop(history);
+        let history = History::open(config.clone()).unwrap();
+        history.append(BASE_URL, &[msg("19:40:02", "alice", "hello everyone"), msg("19:41:00", "bob", "Hello again, http://x.onion")]);
+
+        let search = |query: &str, filter: &SearchFilter| history.search(query, filter).unwrap();
+        let all = SearchFilter::default();
+        assert_eq!(texts(&search("HELLO", &all)), ["Hello again, http://x.onion", "hello everyone", "hello from elsewhere"]);
+        // Whispers are left out
+        assert!(search("see you at 9", &all).is_empty());
+
+        let regex = SearchFilter { regex: true, ..all.clone() };
+        assert_eq!(texts(&search(r"https?://\S+\.onion", &regex)), ["Hello again, http://x.onion"]);
+        assert!(matches!(history.search("(", &regex), Err(HistoryErr::Regex(_))));
+        let bob = SearchFilter { from: Some("bob".to_owned()), ..all.clone() };
+        assert_eq!(texts(&search("", &bob)), ["Hello again, http://x.onion", "hi alice"]);
+        let other = SearchFilter { server: Some("http://other.onion/chat.php".to_owned()), ..all.clone() };
+        assert_eq!(texts(&search("hello", &other)), ["hello from elsewhere"]);
+
+        // Times, and the messages around
+        let hit = &search("hi alice", &SearchFilter { context: 1, ..all.clone() })[0];
+        assert_eq!((hit.before[0].text.as_str(), hit.after[0].text.as_str()), ("alice entered the chat.", "hello everyone"));
+        let since = SearchFilter { since: Some(at("19:39:55")), ..all.clone() };
+        assert_eq!(texts(&search("alice", &since)), ["hi alice"]);
+        let until = SearchFilter { until: Some(at("19:39:54")), ..all.clone() };
+        assert_eq!(texts(&search("alice", &until)), ["alice entered the chat."]);
+
+        // Whispers on request, kept apart from the other profile
+        let private = History::open(HistoryConfig { include_private: true, profile: Some("pm".to_owned()), ..config.clone() }).unwrap();
+        private.append(BASE_URL, &[whisper]);
+        assert_eq!(texts(&private.search("", &all).unwrap()), ["see you at 9"]);
+        assert!(search("see you", &all).is_empty());
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[test]
+    fn rotation_test() {
+        let config = HistoryConfig { max_bytes: 4096, ..config("rotation") };
+        let history = History::open(config.clone()).unwrap();
+        for i in 0..100 {
+            history.append(BASE_URL, &[msg("19:40:02", "alice", &format!("message {}", i))]);
+        }
+        history.flush();
+        let size = |path: &Path| fs::metadata(path).map_or(0, |m| m.len());
+        let path = config.dir.join(format!("{}.jsonl", store_name(BASE_URL, None)));
+        assert!(size(&path) + size(&rotated(&path)) <= config.max_bytes);
+        // The newest are still there, the oldest are gone
+        let hits = history.search("message", &SearchFilter::default()).unwrap();
+        assert!(hits.iter().any(|h| h.entry.text == "message 99"));
+        assert!(!hits.iter().any(|h| h.entry.text == "message 0"));
+        drop(history);
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+
+    #[test]
+    fn export_test() {
+        let joined = Message { from: None, kind: MessageKind::System(SystemEvent::Joined("alice".to_owned())), ..msg("19:39:01", "", "alice entered the chat.") };
+        let whisper = Message { to: Some("bob".to_owned()), kind: MessageKind::Private, ..msg("19:39:30", "alice", "see you at 9") };
+        let waves = Message { kind: MessageKind::Action, ..msg("19:39:40", "carol", "waves") };
+        // Newest first, as fetched
+        let batch = [msg("19:40:00", "bob", "Alice: hi, carol"), waves, whisper, joined];
+        let export = |range: &ExportRange, format, options: &ExportOptions| {
+            let mut out = vec![];
+            let n = export_messages(BASE_URL, &batch, range, format, options, &mut out).unwrap();
+            (n, String::from_utf8(out).unwrap())
+        };
+        let all = ExportOptions { include_private: true, include_system: true, anonymize: false };
+
+        // Back to the same entries
+        let (n, jsonl) = export(&ExportRange::default(), ExportFormat::Jsonl, &all);
+        let parsed: Vec<Entry> = jsonl.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        let expected: Vec<_> = batch.iter().rev().map(|m| Entry::new(BASE_URL, m)).collect();
+        assert_eq!((n, &parsed), (4, &expected));
+        assert_eq!(parsed[1].kind, EntryKind::Private);
+        assert!(jsonl.lines().next().unwrap().contains(r#""kind":"system","from":null"#));
+
+        let (_, text) = export(&ExportRange::default(), ExportFormat::Text, &all);
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "2026-10-17 19:39:01 * alice entered the chat.",
+                "2026-10-17 19:39:30 [alice → bob] see you at 9",
+                "2026-10-17 19:39:40 * carol waves",
+                "2026-10-17 19:40:00 <bob> Alice: hi, carol",
+            ]
+        );
+
+        // Left out by default, and by time
+        let (n, text) = export(&ExportRange::default(), ExportFormat::Text, &ExportOptions::default());
+        assert_eq!((n, text.lines().count()), (2, 2));
+        let range = ExportRange { since: Some(at("19:39:30")), until: Some(at("19:39:59")) };
+        assert_eq!(export(&range, ExportFormat::Jsonl, &all).0, 2);
+
+        let (_, text) = export(&ExportRange::default(), ExportFormat::Text, &ExportOptions { anonymize: true, ..all.clone() });
+        let lines: Vec<_> = text.lines().collect();
+        // alice had sent nothing yet when joining
+        assert_eq!(lines[0], "2026-10-17 19:39:01 * alice entered the chat.");
+        assert_eq!(lines[1..], ["2026-10-17 19:39:30 [user1 → user2] see you at 9", "2026-10-17 19:39:40 * user3 waves", "2026-10-17 19:40:00 <user2> user1: hi, user3"]);
+    }
+
+    #[test]
+    fn export_store_test() {
+        let config = config("export");
+        let history = History::open(HistoryConfig { include_private: true, ..config.clone() }).unwrap();
+        let whisper = Message { to: Some("bob".to_owned()), kind: MessageKind::Private, ..msg("19:39:30", "alice", "see you at 9") };
+        let batch = [msg("19:40:02", "alice", "hello everyone"), whisper, msg("19:39:00", "bob", "first")];
+        history.append(BASE_URL, &batch);
+        history.append("http://other.onion", &[msg("19:41:00", "carol", "hello from elsewhere")]);
+
+        let options = ExportOptions { include_private: true, ..ExportOptions::default() };
+        let mut out = vec![];
+        let n = history.export(Some(BASE_URL), &ExportRange::default(), ExportFormat::Jsonl, &options, &mut out).unwrap();
+        let parsed: Vec<Entry> = String::from_utf8(out).unwrap().lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        let expected: Vec<_> = batch.iter().map(|m| Entry::new(BASE_URL, m)).collect();
+        assert_eq!((n, parsed), (3, expected));
+
+        let n = history.export(None, &ExportRange::default(), ExportFormat::Text, &ExportOptions::default(), io::sink()).unwrap();
+        assert_eq!(n, 3);
+        drop(history);
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+}
@@ -0,0 +1,74 @@
+// A callback the caller sets for the library to call, like the captcha
+// prompt. It is cloned out of its lock before being called, so it may block,
+// set another callback or panic without holding up or poisoning the others.
+use std::sync::{Arc, Mutex, PoisonError};
+
+pub(crate) struct Hook<F: ?Sized>(Mutex<Option<Arc<F>>>);
+
+impl<F: ?Sized> Hook<F> {
+    pub(crate) const fn new() -> Self {
+        Self(Mutex::new(None))
+    }
+
+    pub(crate) fn set(&self, hook: Arc<F>) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = Some(hook);
+    }
+
+    /// The callback set last, to call once the lock is released.
+    pub(crate) fn get(&self) -> Option<Arc<F>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+}
+
+impl<F: ?Sized> Default for Hook<F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+
+    type Clb = dyn Fn() -> usize + Send + Sync;
+
+    #[test]
+    fn reentrant_test() {
+        // Setting another callback from the one being called
+        let hook: Arc<Hook<Clb>> = Arc::new(Hook::new());
+        let inner = hook.clone();
+        hook.set(Arc::new(move || {
+            inner.set(Arc::new(|| 2));
+            1
+        }));
+        assert_eq!(hook.get().unwrap()(), 1);
+        assert_eq!(hook.get().unwrap()(), 2);
+    }
+
+    #[test]
+    fn panic_test() {
+        // A callback panicking doesn't take the hook down with it
+        let hook: Hook<Clb> = Hook::new();
+        hook.set(Arc::new(|| panic!("callback")));
+        let clb = hook.get().unwrap();
+        assert!(panic::catch_unwind(AssertUnwindSafe(|| clb())).is_err());
+        hook.set(Arc::new(|| 1));
+        assert_eq!(hook.get().unwrap()(), 1);
+    }
+
+    #[test]
+    fn poisoned_test() {
+        // A thread panicking with the lock held
+        let hook: Arc<Hook<Clb>> = Arc::new(Hook::new());
+        let poisoner = hook.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = poisoner.0.lock().unwrap();
+            panic!("poison");
+        })
+        .join();
+        assert!(hook.0.is_poisoned());
+        hook.set(Arc::new(|| 3));
+        assert_eq!(hook.get().unwrap()(), 3);
+    }
+}
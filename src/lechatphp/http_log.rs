@@ -0,0 +1,185 @@
+use super::retry::SendErr;
+use super::settings::Settings;
+use super::transport;
+use lazy_static::lazy_static;
+use regex::Regex;
+use reqwest::blocking::{RequestBuilder, Response};
+use reqwest::tls::TlsInfo;
+use reqwest::ResponseBuilderExt;
+use std::time::Instant;
+
+/// Log target, so the log config can enable it without the noise of
+/// reqwest/hyper debug logs.
+pub const TARGET: &str = "http";
+const REDACTED: &str = "***";
+const MAX_BODY_LEN: usize = 2048;
+
+lazy_static! {
+    // `pass=..` in forms and query strings, and hidden inputs in pages.
+    // The profile's password change has `oldpass`, `newpass`, `confirmpass`.
+    static ref FIELD_RGX: Regex = Regex::new(r#"\b((?:old|new|confirm)?pass|captcha|session)=[^&"'\s<>)]*"#).unwrap();
+    static ref INPUT_RGX: Regex = Regex::new(r#"(name="(?:(?:old|new|confirm)?pass|captcha|session)"\s+value=")[^"]*"#).unwrap();
+}
+
+/// What to log about each request made by this module.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HttpLog {
+    Off,
+    /// Method, redacted url, status and duration at debug level.
+    Requests,
+    /// Also the redacted request body and the truncated response body at
+    /// trace level.
+    Bodies,
+}
+
+/// Replace the values of the password, `captcha` and `session` fields in
+/// urls, urlencoded forms and html.
+pub fn redact(text: &str) -> String {
+    let text = FIELD_RGX.replace_all(text, format!("${{1}}={}", REDACTED).as_str());
+    INPUT_RGX.replace_all(&text, format!("${{1}}{}", REDACTED).as_str()).into_owned()
+}
+
+fn truncate(body: &str) -> &str {
+    match body.char_indices().nth(MAX_BODY_LEN) {
+        Some((idx, _)) => &body[..idx],
+        None => body,
+    }
+}
+
+/// Send the request, logging it at the client's level and recording it
+/// when its capture is running. The body is read here when it is logged or
+/// recorded, so its errors come from here too, as `transport::read_body`'s.
+pub fn send(settings: &Settings, req: RequestBuilder) -> Result<Response, SendErr> {
+    let level = settings.http_log;
+    let capture = &settings.capture;
+    let capturing = capture.enabled();
+    if level == HttpLog::Off && !capturing {
+        return Ok(req.send()?);
+    }
+    // Inspect a copy, streaming bodies can't be cloned and aren't logged
+    let request = match req.try_clone().and_then(|r| r.build().ok()) {
+        Some(request) => request,
+        None => return Ok(req.send()?),
+    };
+    let method = request.method().clone();
+    let url = redact(request.url().as_str());
+    if level == HttpLog::Bodies {
+        if let Some(body) = request.body().and_then(|b| b.as_bytes()) {
+            log::trace!(target: TARGET, "{} {} body: {}", method, url, truncate(&redact(&String::from_utf8_lossy(body))));
+        }
+    }
+
+    let start = Instant::now();
+    let res = req.send();
+    let elapsed = start.elapsed();
+    let resp = match res {
+        Ok(resp) => resp,
+        Err(e) => {
+            let e = SendErr::from(e);
+            if level != HttpLog::Off {
+                log::debug!(target: TARGET, "{} {} failed after {:?}: {}", method, url, elapsed, e);
+            }
+            if capturing {
+                capture.record(&request, Err(&e));
+            }
+            return Err(e);
+        }
+    };
+    if level != HttpLog::Off {
+        log::debug!(target: TARGET, "{} {} -> {} in {:?}", method, url, resp.status(), elapsed);
+    }
+    if level != HttpLog::Bodies && !capturing {
+        return Ok(resp);
+    }
+
+    // Reading the body consumes the response, rebuild it for the caller.
+    // The rebuilt one has no `Content-Length` of its own to be cut short of,
+    // so a truncated or oversized body is refused here.
+    let status = resp.status();
+    let resp_url = resp.url().clone();
+    let headers = resp.headers().clone();
+    // Kept for the TLS pin check
+    let tls_info = resp.extensions().get::<TlsInfo>().cloned();
+    let body = match transport::read_body(resp, settings.max_body_size) {
+        Ok(body) => body,
+        Err(e) => {
+            log::debug!(target: TARGET, "{} {} response body failed: {}", method, url, e);
+            if capturing {
+                capture.record(&request, Err(&e));
+            }
+            return Err(e);
+        }
+    };
+    if level == HttpLog::Bodies {
+        log::trace!(target: TARGET, "{} {} response: {}", method, url, truncate(&redact(&String::from_utf8_lossy(&body))));
+    }
+    if capturing {
+        capture.record(&request, Ok((status, &headers, &body)));
+    }
+    let mut builder = http::Response::builder().status(status).url(resp_url);
+    if let Some(h) = builder.headers_mut() {
+        *h = headers;
+    }
+    if let (Some(ext), Some(tls_info)) = (builder.extensions_mut(), tls_info) {
+        ext.insert(tls_info);
+    }
+    Ok(Response::from(builder.body(body).unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lechatphp::mock::chat_server;
+    use log::{Log, Metadata, Record};
+    use crate::lechatphp::transport::Transport;
+    use std::sync::Mutex;
+
+    struct Capture;
+
+    lazy_static! {
+        static ref CAPTURED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    }
+
+    impl Log for Capture {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.target() == TARGET
+        }
+
+        fn log(&self, record: &Record) {
+            if self.enabled(record.metadata()) {
+                CAPTURED.lock().unwrap().push(record.args().to_string());
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn redact_test() {
+        assert_eq!(redact("nick=a&pass=b%26c&lang=en"), "nick=a&pass=***&lang=en");
+        assert_eq!(redact("/chat.php?session=abc&lang=en"), "/chat.php?session=***&lang=en");
+        assert_eq!(redact("for url (http://x/chat.php?session=abc)"), "for url (http://x/chat.php?session=***)");
+        assert_eq!(redact(r#"<input name="session" value="abc">"#), r#"<input name="session" value="***">"#);
+        assert_eq!(redact("passphrase=x&nopass=y"), "passphrase=x&nopass=y");
+        assert_eq!(redact("oldpass=a&newpass=b&confirmpass=b&x=1"), "oldpass=***&newpass=***&confirmpass=***&x=1");
+    }
+
+    #[test]
+    fn redaction_test() {
+        log::set_logger(&Capture).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let server = chat_server("s3cr3tsession");
+        let mut transport = Transport::direct();
+        transport.set_settings(Settings { http_log: HttpLog::Bodies, ..Default::default() });
+        let session = crate::lechatphp::login(&transport, &server.url, "chat.php", "nick", "hunter2", "", true).unwrap();
+        crate::lechatphp::logout(&transport, &server.url, "chat.php", &session).unwrap();
+        send(transport.settings(), transport.get(format!("{}/chat.php?action=view&session={}", server.url, session))).unwrap();
+
+        let captured = CAPTURED.lock().unwrap();
+        assert!(captured.iter().any(|l| l.starts_with("POST") && l.contains("pass=***")));
+        assert!(captured.iter().any(|l| l.contains("session=***")));
+        // The login response carries the session in the iframe src
+        assert!(captured.iter().all(|l| !l.contains("hunter2") && !l.contains("s3cr3tsession")));
+    }
+}
@@ -0,0 +1,233 @@
+use super::exchange::Exchange;
+use super::metrics::Operation;
+use super::{progress, server_down_err, LoginContext, LoginErr, LoginProgress, LoginStage};
+use lazy_static::lazy_static;
+use regex::Regex;
+use reqwest::header::SET_COOKIE;
+use reqwest::Url;
+use select::document::Document;
+use select::predicate::{Attr, Name};
+use std::fmt::{Display, Formatter};
+use std::time::Duration;
+
+/// Interstitials passed before giving up on a page.
+const MAX_PASSES: usize = 3;
+/// When a queue page doesn't say how long to wait.
+const DEFAULT_QUEUE_WAIT: Duration = Duration::from_secs(10);
+/// Longest wait honored, some gates ask for minutes.
+const MAX_WAIT: Duration = Duration::from_secs(60);
+/// Text of anti-DDoS queue and browser check pages, matched ignoring case.
+const QUEUE_MARKERS: &[&str] = &[
+    "you are in the queue",
+    "your position in the queue",
+    "checking your browser",
+    "ddos protection",
+    "please wait while we verify",
+];
+
+lazy_static! {
+    static ref JS_COOKIE_RGX: Regex = Regex::new(r#"document\.cookie\s*=\s*["']([^"']+)["']"#).unwrap();
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Gate {
+    /// Refreshes to the same page once the cookie it sets is back.
+    CookieRefresh,
+    /// A queue or browser check page, with the marker it was found by.
+    Queue(&'static str),
+}
+
+/// An anti-DDoS page served instead of the one asked for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Interstitial {
+    pub gate: Gate,
+    /// How long the page asks to wait before coming back.
+    pub wait: Duration,
+    /// Cookies the page sets itself, from `<meta http-equiv="set-cookie">`
+    /// or a script. Set-Cookie headers go to the cookie store as usual.
+    pub cookies: Vec<String>,
+}
+
+impl Display for Interstitial {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.gate {
+            Gate::CookieRefresh => write!(f, "cookie-setting refresh page"),
+            Gate::Queue(marker) => write!(f, "queue page (\"{}\")", marker),
+        }
+    }
+}
+
+// `5; url=/chat.php` -> (5s, Some("/chat.php"))
+fn parse_refresh(content: &str) -> (Option<Duration>, Option<String>) {
+    let (secs, rest) = content.split_once([';', ',']).unwrap_or((content, ""));
+    let wait = secs.trim().parse::<f64>().ok().filter(|s| s.is_finite() && *s >= 0.0).map(Duration::from_secs_f64);
+    let rest = rest.trim();
+    let url = rest
+        .get(..4)
+        .filter(|p| p.eq_ignore_ascii_case("url="))
+        .map(|_| rest[4..].trim().trim_matches(['"', '\'']).to_owned())
+        .filter(|u| !u.is_empty());
+    (wait, url)
+}
+
+// A missing target is the page itself, fragments don't count
+fn same_page(page_url: &str, target: Option<&str>) -> bool {
+    let Ok(mut page) = Url::parse(page_url) else {
+        return false;
+    };
+    page.set_fragment(None);
+    match target.map(|t| page.join(t)) {
+        None => true,
+        Some(Ok(mut target)) => {
+            target.set_fragment(None);
+            target == page
+        }
+        Some(Err(_)) => false,
+    }
+}
+
+/// Recognize an interstitial in the page fetched from `page_url`.
+/// `set_cookie` tells whether the response had a Set-Cookie header. A page
+/// with a login form is never one.
+pub fn detect(body: &str, set_cookie: bool, page_url: &str) -> Option<Interstitial> {
+    let doc = Document::from(body);
+    if doc.find(Attr("name", "nick")).next().is_some() || doc.find(Attr("name", "challenge")).next().is_some() {
+        return None;
+    }
+    let meta = |equiv: &str| {
+        doc.find(Name("meta"))
+            .filter(|m| m.attr("http-equiv").is_some_and(|e| e.eq_ignore_ascii_case(equiv)))
+            .filter_map(|m| m.attr("content").map(str::to_owned))
+            .collect::<Vec<_>>()
+    };
+    let refresh = meta("refresh").first().map(|c| parse_refresh(c));
+    let mut cookies = meta("set-cookie");
+    cookies.extend(JS_COOKIE_RGX.captures_iter(body).map(|c| c[1].to_owned()));
+
+    let lower = body.to_lowercase();
+    if let Some(marker) = QUEUE_MARKERS.iter().find(|m| lower.contains(*m)) {
+        let wait = refresh.and_then(|(wait, _)| wait).unwrap_or(DEFAULT_QUEUE_WAIT);
+        return Some(Interstitial { gate: Gate::Queue(marker), wait, cookies });
+    }
+    match refresh {
+        Some((wait, url)) if (set_cookie || !cookies.is_empty()) && same_page(page_url, url.as_deref()) => {
+            Some(Interstitial { gate: Gate::CookieRefresh, wait: wait.unwrap_or_default(), cookies })
+        }
+        _ => None,
+    }
+}
+
+/// GET `page_url` and its body, waiting through interstitials like a
+/// browser would: keep the cookies, wait as asked and ask again, up to
+/// `MAX_PASSES` times.
+pub async fn get_page<E: Exchange>(http: &E, operation: Operation, page_url: &str) -> Result<String, LoginErr> {
+    let context = LoginContext::new(LoginStage::LoginPage, page_url);
+    let mut passes = 0;
+    loop {
+        let resp = http.get(operation, page_url).await.map_err(|e| context.err(e))?;
+        let status = resp.status;
+        let set_cookie = resp.headers.contains_key(SET_COOKIE);
+        // Queue pages may come with an error status, read them anyway
+        let body = match resp.body {
+            Ok(body) => body,
+            Err(e) => return Err(server_down_err(status, &context).unwrap_or_else(|| context.err(e))),
+        };
+        let page = match detect(&body, set_cookie, page_url) {
+            Some(page) => page,
+            None => return server_down_err(status, &context).map_or(Ok(body), Err),
+        };
+        if passes == MAX_PASSES {
+            return Err(LoginErr::InterstitialBlocked { context, page, passes });
+        }
+        passes += 1;
+        for cookie in &page.cookies {
+            http.add_cookie(cookie, page_url);
+        }
+        let wait = page.wait.min(MAX_WAIT);
+        progress(http.settings(), LoginProgress::Interstitial { page: page.clone(), url: page_url.to_owned(), wait });
+        http.sleep(wait).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lechatphp::exchange::block_on;
+    use crate::lechatphp::mock::{MockResponse, MockServer};
+    use crate::lechatphp::transport::Transport;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const COOKIE_PAGE: &str = include_str!("fixtures/interstitial_cookie.html");
+    const QUEUE_PAGE: &str = include_str!("fixtures/interstitial_queue.html");
+    const LOGIN_PAGE: &str = r#"<html><body><form><input name="nick"></form></body></html>"#;
+
+    #[test]
+    fn detect_test() {
+        let url = "http://example.onion/chat.php";
+        let page = detect(COOKIE_PAGE, false, url).unwrap();
+        assert_eq!(page.gate, Gate::CookieRefresh);
+        assert_eq!(page.wait, Duration::ZERO);
+        assert_eq!(page.cookies, vec!["dcap=7f3a9c; path=/".to_owned()]);
+
+        let page = detect(QUEUE_PAGE, false, url).unwrap();
+        assert_eq!(page.gate, Gate::Queue("you are in the queue"));
+        assert_eq!(page.wait, Duration::from_secs(1));
+        assert!(page.cookies.is_empty());
+
+        // A refresh elsewhere is the waitroom, without a cookie just a refresh
+        let waitroom = r#"<meta http-equiv="refresh" content="10; URL=/chat.php?action=wait">"#;
+        assert_eq!(detect(waitroom, false, url), None);
+        let elsewhere = r#"<meta http-equiv="Refresh" content="0;url=http://other.onion/chat.php">"#;
+        assert_eq!(detect(elsewhere, true, url), None);
+        let same = r#"<meta http-equiv="Refresh" content="2;url='/chat.php#top'">"#;
+        assert_eq!(detect(same, false, url), None);
+        assert_eq!(detect(same, true, url).unwrap().wait, Duration::from_secs(2));
+        assert_eq!(detect(LOGIN_PAGE, true, url), None);
+    }
+
+    fn get(transport: &Transport, url: &str) -> Result<String, LoginErr> {
+        block_on(get_page(transport, Operation::LoginPage, url))
+    }
+
+    #[test]
+    fn get_page_test() {
+        let transport = Transport::direct();
+
+        // The pass is set by the page's script
+        let server = MockServer::start(|req| match req.header("cookie") {
+            Some(c) if c.contains("dcap=7f3a9c") => MockResponse::ok(LOGIN_PAGE),
+            _ => MockResponse::ok(COOKIE_PAGE),
+        });
+        let url = format!("{}/chat.php", server.url);
+        assert_eq!(get(&transport, &url).unwrap(), LOGIN_PAGE);
+        assert_eq!(server.hits(), 2);
+
+        // Set-Cookie header with a bare refresh, and a queue answering 503
+        let queued = AtomicUsize::new(0);
+        let server = MockServer::start(move |req| match (req.path.as_str(), req.header("cookie")) {
+            ("/chat.php", Some(c)) if c.contains("pass=1") => MockResponse::ok(LOGIN_PAGE),
+            ("/chat.php", _) => MockResponse::ok(r#"<meta http-equiv="refresh" content="0">"#)
+                .with_header("Set-Cookie", "pass=1; Path=/"),
+            ("/queue.php", _) if queued.fetch_add(1, Ordering::SeqCst) == 0 => MockResponse::new(503, QUEUE_PAGE),
+            ("/queue.php", _) => MockResponse::ok(LOGIN_PAGE),
+            _ => MockResponse::new(503, "Service Unavailable"),
+        });
+        for path in ["/chat.php", "/queue.php"] {
+            let url = format!("{}{}", server.url, path);
+            assert_eq!(get(&transport, &url).unwrap(), LOGIN_PAGE);
+        }
+        let err = get(&transport, &format!("{}/down.php", server.url));
+        assert!(matches!(err, Err(LoginErr::ServerDownErr(_))), "unexpected {:?}", err);
+
+        // Never let through
+        let server = MockServer::start(|_| MockResponse::ok(COOKIE_PAGE));
+        let err = get(&Transport::direct(), &format!("{}/chat.php", server.url));
+        match err {
+            Err(LoginErr::InterstitialBlocked { page, passes, .. }) => {
+                assert_eq!((page.gate, passes), (Gate::CookieRefresh, MAX_PASSES));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(server.hits(), MAX_PASSES + 1);
+    }
+}
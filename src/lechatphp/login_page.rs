@@ -0,0 +1,336 @@
+// What the login's pages say, read from their HTML alone. `login_with`
+// asks for the pages and acts on what these return.
+use super::captcha_prompt::{self, CaptchaImage, ImageFormat};
+use super::error_kind::{Classify, ErrorKind};
+use base64::engine::general_purpose;
+use base64::Engine;
+use http::header::HeaderMap;
+use lazy_static::lazy_static;
+use regex::Regex;
+use reqwest::Url;
+use select::document::Document;
+use select::node::Node;
+use select::predicate::{And, Attr, Class, Name};
+use std::fmt::{Debug, Formatter};
+use thiserror::Error;
+
+lazy_static! {
+    static ref REFRESH_URL_RGX: Regex = Regex::new(r#"URL=(.+)"#).unwrap();
+    static ref SESSION_RGX: Regex = Regex::new(r#"session=([^&]+)"#).unwrap();
+    // The tag name alone, `<bodyx>` is no body
+    static ref BODY_OPEN_RGX: Regex = Regex::new(r#"(?i)<body[\s>]"#).unwrap();
+    static ref BODY_CLOSE_RGX: Regex = Regex::new(r#"(?i)</body\s*>"#).unwrap();
+    static ref HTML_OPEN_RGX: Regex = Regex::new(r#"(?i)<html[\s>]"#).unwrap();
+    static ref HTML_CLOSE_RGX: Regex = Regex::new(r#"(?i)</html\s*>"#).unwrap();
+    static ref PAGE_RGX: Regex = Regex::new(r#"(?i)<(html|body|frameset)[\s>]"#).unwrap();
+}
+
+pub(super) const KICKED_ERR: &str = "You have been kicked";
+pub(super) const REG_ERR: &str = "This nickname is a registered member";
+pub(super) const NICKNAME_ERR: &str = "Invalid nickname";
+pub(super) const CAPTCHA_WG_ERR: &str = "Wrong Captcha";
+pub(super) const CAPTCHA_USED_ERR: &str = "Captcha already used or timed out";
+
+#[derive(Debug, Clone, PartialEq, Error)]
+#[non_exhaustive]
+pub enum ParseErr {
+    /// Something the page must have to go on, e.g. "captcha image".
+    #[error("no {0} in the page")]
+    Missing(&'static str),
+    /// The captcha image is bigger than `captcha_prompt::MAX_IMAGE_SIZE`.
+    #[error("captcha image over {} bytes", captcha_prompt::MAX_IMAGE_SIZE)]
+    ImageTooLarge,
+    /// Neither an error nor the chat, it has no view frame.
+    #[error("no chat frame in the page")]
+    NoChatFrame,
+    /// The view frame is there, without a session in its url.
+    #[error("no session in the chat frame")]
+    NoSession,
+    /// The page ends before the closing tag of its `body` or `html`, the
+    /// connection was cut: it says nothing about the login.
+    #[error("page cut short, no closing {0} tag")]
+    Truncated(&'static str),
+}
+
+impl Classify for ParseErr {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            ParseErr::Truncated(_) => ErrorKind::Transient,
+            ParseErr::Missing(_) | ParseErr::ImageTooLarge | ParseErr::NoChatFrame | ParseErr::NoSession => ErrorKind::Fatal,
+        }
+    }
+}
+
+/// Whether `html` is a whole page. The chat closes every `body` and `html`
+/// it opens, one left open is a page cut short.
+pub fn check_complete(html: &str) -> Result<(), ParseErr> {
+    let tags = [("body", &*BODY_OPEN_RGX, &*BODY_CLOSE_RGX), ("html", &*HTML_OPEN_RGX, &*HTML_CLOSE_RGX)];
+    match tags.into_iter().find(|(_, open, close)| open.is_match(html) && !close.is_match(html)) {
+        Some((tag, ..)) => Err(ParseErr::Truncated(tag)),
+        None => Ok(()),
+    }
+}
+
+/// The login form.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoginPage {
+    /// `None` when the chat asks no captcha.
+    pub captcha: Option<Captcha>,
+}
+
+/// The captcha of the login form, sent back with its answer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Captcha {
+    pub challenge: String,
+    /// The image as the page has it, a `data:` url.
+    pub src: String,
+}
+
+impl Captcha {
+    /// The image to show, checked for its format, size and header.
+    pub fn image(&self) -> Result<CaptchaImage, ParseErr> {
+        let (format, base64_str) = if let Some(base64) = self.src.strip_prefix("data:image/png;base64,") {
+            (ImageFormat::Png, base64)
+        } else if let Some(base64) = self.src.strip_prefix("data:image/gif;base64,") {
+            (ImageFormat::Gif, base64)
+        } else {
+            return Err(ParseErr::Missing("PNG or GIF captcha image"));
+        };
+        if captcha_prompt::image_too_large(base64_str) {
+            return Err(ParseErr::ImageTooLarge);
+        }
+        let bytes = general_purpose::STANDARD.decode(base64_str).map_err(|_| ParseErr::Missing("base64 captcha image"))?;
+        let img = CaptchaImage::new(format, bytes);
+        if img.dimensions().is_none() {
+            return Err(ParseErr::Missing("decodable captcha image"));
+        }
+        Ok(img)
+    }
+}
+
+/// The captcha of the login form, if any.
+pub fn parse_login_page(html: &str) -> Result<LoginPage, ParseErr> {
+    check_complete(html)?;
+    let doc = Document::from(html);
+    let Some(challenge) = doc.find(And(Name("input"), Attr("name", "challenge"))).next() else {
+        return Ok(LoginPage { captcha: None });
+    };
+    let challenge = challenge.attr("value").ok_or(ParseErr::Missing("captcha challenge value"))?;
+    let src = doc
+        .find(Name("img"))
+        .next()
+        .and_then(|img| img.attr("src"))
+        .ok_or(ParseErr::Missing("captcha image"))?;
+    Ok(LoginPage { captcha: Some(Captcha { challenge: challenge.to_owned(), src: src.to_owned() }) })
+}
+
+/// What the chat answered the login form, or the waitroom, with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoginResponse {
+    CaptchaUsed,
+    CaptchaWrong,
+    /// The nick is a member's, a password is needed.
+    Registered,
+    InvalidNick,
+    Kicked,
+    /// Any other error page, with its message.
+    Error(Option<String>),
+    /// Failed logins happened since the last one. Posting `nonce` back
+    /// goes on with the login.
+    FailedNotice { notice: String, nonce: Option<String> },
+    /// None of the above: the chat, see `extract_session`.
+    Chat,
+}
+
+/// Whether `html` is one of the login's errors or notices. The message
+/// checks come first, forks put them in other markup, but only in the
+/// page's error regions, see `error_text`: chat messages can say anything.
+pub fn classify_login_response(html: &str) -> Result<LoginResponse, ParseErr> {
+    check_complete(html)?;
+    let doc = Document::from(html);
+    let text = error_text(&doc, html);
+    let messages = [
+        (CAPTCHA_USED_ERR, LoginResponse::CaptchaUsed),
+        (CAPTCHA_WG_ERR, LoginResponse::CaptchaWrong),
+        (REG_ERR, LoginResponse::Registered),
+        (NICKNAME_ERR, LoginResponse::InvalidNick),
+        (KICKED_ERR, LoginResponse::Kicked),
+    ];
+    if let Some((_, response)) = messages.into_iter().find(|(message, _)| text.contains(message)) {
+        return Ok(response);
+    }
+    let Some(body) = doc.find(Name("body")).next() else {
+        return Ok(LoginResponse::Chat);
+    };
+    let response = match body.attr("class") {
+        Some("error") => LoginResponse::Error(doc.find(Name("h2")).next().map(|h2| h2.text())),
+        Some("failednotice") => LoginResponse::FailedNotice {
+            notice: body.text().trim().to_owned(),
+            nonce: doc.find(Attr("name", "nc")).next().and_then(|nc| nc.attr("value")).map(str::to_owned),
+        },
+        _ => LoginResponse::Chat,
+    };
+    Ok(response)
+}
+
+// Where the login's errors are: the whole body of an error page, otherwise
+// its headings and whatever is classed as an error. A bare fragment, a
+// fork's message without a page around it, is all message.
+fn error_text(doc: &Document, html: &str) -> String {
+    if !PAGE_RGX.is_match(html) {
+        return html.to_owned();
+    }
+    if let Some(body) = doc.find(And(Name("body"), Class("error"))).next() {
+        return body.text();
+    }
+    let is_error = |node: &Node| ["class", "id"].iter().any(|attr| node.attr(attr).is_some_and(|v| v.contains("error")));
+    doc.find(Name("h2")).chain(doc.find(is_error)).map(|node| node.text()).collect::<Vec<_>>().join("\n")
+}
+
+/// The session of the chat page, from its view frame.
+#[derive(Clone, PartialEq)]
+pub struct SessionInfo {
+    pub session: String,
+    /// The view frame's url as the page has it.
+    pub view_src: String,
+}
+
+// Never print the session
+impl Debug for SessionInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionInfo").field("session", &"***").finish_non_exhaustive()
+    }
+}
+
+/// The session in the chat page `html`, served for `page_url`.
+pub fn extract_session(html: &str, page_url: &str) -> Result<SessionInfo, ParseErr> {
+    check_complete(html)?;
+    let doc = Document::from(html);
+    let view = doc.find(Attr("name", "view")).next().ok_or(ParseErr::NoChatFrame)?;
+    let view_src = view.attr("src").ok_or(ParseErr::NoSession)?;
+    let session = session_from_src(page_url, view_src).ok_or(ParseErr::NoSession)?;
+    Ok(SessionInfo { session, view_src: view_src.to_owned() })
+}
+
+/// The session in `src` of the chat frame, relative to `page_url`. The
+/// query is decoded when the plain match fails, and the fragment is read
+/// like a query for the forks that put the session there.
+pub(super) fn session_from_src(page_url: &str, src: &str) -> Option<String> {
+    if let Some(session) = SESSION_RGX.captures(src).and_then(|c| c.get(1)) {
+        return Some(session.as_str().to_owned());
+    }
+    let url = Url::parse(page_url).ok()?.join(src).ok()?;
+    let mut fragment = url.clone();
+    fragment.set_query(url.fragment());
+    let session = url.query_pairs().chain(fragment.query_pairs()).find(|(k, v)| k == "session" && !v.is_empty());
+    session.map(|(_, v)| v.into_owned())
+}
+
+/// Waitroom redirect path from the `refresh` header, if the waitroom is on.
+pub fn waitroom_refresh(headers: &HeaderMap) -> Option<String> {
+    let header = headers.get("refresh")?.to_str().ok()?;
+    REFRESH_URL_RGX.captures(header).map(|c| c[1].to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion/chat.php";
+    const SESSION: &str = "0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn parse_login_page_test() {
+        let page = parse_login_page(include_str!("fixtures/login.html")).unwrap();
+        let captcha = page.captcha.unwrap();
+        assert_eq!(captcha.challenge, "5e1b4a90f7c2d3e8");
+        assert!(captcha.src.starts_with("data:image/png;base64,"));
+        assert_eq!(captcha.image().unwrap().dimensions(), Some((60, 24)));
+
+        // Forks without captcha, or with a GIF one
+        assert_eq!(parse_login_page(include_str!("fixtures/login_no_captcha.html")).unwrap().captcha, None);
+        let gif = parse_login_page(include_str!("fixtures/login_gif_captcha.html")).unwrap().captcha.unwrap();
+        assert_eq!(gif.image().unwrap().format, ImageFormat::Gif);
+
+        let login = include_str!("fixtures/login.html");
+        let no_value = login.replace(r#"name="challenge" value="5e1b4a90f7c2d3e8""#, r#"name="challenge""#);
+        assert_eq!(parse_login_page(&no_value), Err(ParseErr::Missing("captcha challenge value")));
+        let no_img = login.replace("<img", "<span");
+        assert_eq!(parse_login_page(&no_img), Err(ParseErr::Missing("captcha image")));
+
+        let image = |src: &str| Captcha { challenge: "x".to_owned(), src: src.to_owned() }.image();
+        assert_eq!(image("data:image/webp;base64,AAAA"), Err(ParseErr::Missing("PNG or GIF captcha image")));
+        assert_eq!(image("data:image/png;base64,!!!!"), Err(ParseErr::Missing("base64 captcha image")));
+        assert_eq!(image("data:image/png;base64,bm90IGFuIGltYWdl"), Err(ParseErr::Missing("decodable captcha image")));
+        let huge = format!("data:image/png;base64,{}", "A".repeat(captcha_prompt::MAX_IMAGE_SIZE * 2));
+        assert_eq!(image(&huge), Err(ParseErr::ImageTooLarge));
+
+        assert_eq!(parse_login_page(&login[..login.len() / 2]), Err(ParseErr::Truncated("body")));
+    }
+
+    #[test]
+    fn classify_login_response_test() {
+        let cases = [
+            (include_str!("fixtures/login_captcha_used.html"), LoginResponse::CaptchaUsed),
+            (include_str!("fixtures/login_wrong_captcha.html"), LoginResponse::CaptchaWrong),
+            (include_str!("fixtures/login_registered.html"), LoginResponse::Registered),
+            (include_str!("fixtures/login_invalid_nick.html"), LoginResponse::InvalidNick),
+            (include_str!("fixtures/login_kicked.html"), LoginResponse::Kicked),
+            (
+                include_str!("fixtures/login_error.html"),
+                LoginResponse::Error(Some("Error: No access at the moment, please try again later.".to_owned())),
+            ),
+            (include_str!("fixtures/login_frameset.html"), LoginResponse::Chat),
+            (include_str!("fixtures/login_iframe.html"), LoginResponse::Chat),
+            // Without its refresh header, nothing tells it apart
+            (include_str!("fixtures/login_waitroom.html"), LoginResponse::Chat),
+            // A fork's message in plain text, and an error page without one
+            ("<p>Wrong Captcha, try again</p>", LoginResponse::CaptchaWrong),
+            (r#"<html><body><div class="errors">Wrong Captcha</div></body></html>"#, LoginResponse::CaptchaWrong),
+            (r#"<html><body class="error"></body></html>"#, LoginResponse::Error(None)),
+            // An error's words in a chat message
+            (include_str!("fixtures/login_kicked_message.html"), LoginResponse::Chat),
+        ];
+        for (page, expected) in cases {
+            assert_eq!(classify_login_response(page), Ok(expected), "{}", page);
+        }
+        // The same page cut short, in the middle of that message
+        let truncated = include_str!("fixtures/login_truncated.html");
+        assert_eq!(classify_login_response(truncated), Err(ParseErr::Truncated("body")));
+        assert_eq!(ParseErr::Truncated("body").kind(), ErrorKind::Transient);
+
+        let notice = include_str!("fixtures/login_failednotice.html");
+        let Ok(LoginResponse::FailedNotice { notice: text, nonce }) = classify_login_response(notice) else {
+            panic!("not a failed logins notice");
+        };
+        assert!(text.starts_with("There have been 3 failed login attempts"), "{}", text);
+        assert_eq!(nonce.as_deref(), Some("718204"));
+        let no_nonce = notice.replace(r#"name="nc""#, r#"name="other""#);
+        assert!(matches!(classify_login_response(&no_nonce), Ok(LoginResponse::FailedNotice { nonce: None, .. })));
+    }
+
+    #[test]
+    fn extract_session_test() {
+        let info = extract_session(include_str!("fixtures/login_frameset.html"), PAGE_URL).unwrap();
+        assert_eq!(info.session, SESSION);
+        assert_eq!(info.view_src, format!("chat.php?action=view&session={}&lang=en", SESSION));
+        assert!(!format!("{:?}", info).contains(SESSION));
+        // A fork's iframe, the session in the fragment
+        assert_eq!(extract_session(include_str!("fixtures/login_iframe.html"), PAGE_URL).unwrap().session, SESSION);
+        assert_eq!(extract_session(include_str!("fixtures/login_kicked_message.html"), PAGE_URL).unwrap().session, SESSION);
+
+        let waitroom = include_str!("fixtures/login_waitroom.html");
+        assert_eq!(extract_session(waitroom, PAGE_URL), Err(ParseErr::NoChatFrame));
+        let frameset = include_str!("fixtures/login_frameset.html");
+        assert_eq!(extract_session(&frameset.replace(SESSION, ""), PAGE_URL), Err(ParseErr::NoSession));
+        // Cut before its noframes body, only the html is left open
+        let cut = &frameset[..frameset.find("<noframes>").unwrap()];
+        assert_eq!(extract_session(cut, PAGE_URL), Err(ParseErr::Truncated("html")));
+        assert_eq!(check_complete("<HTML><BODY>done</BODY></HTML>"), Ok(()));
+        assert_eq!(check_complete("<html lang=\"en\">\n<body\nclass=\"x\">cut"), Err(ParseErr::Truncated("body")));
+        // Not a body nor an html, whatever follows
+        assert_eq!(check_complete("<bodyx><htmlish>"), Ok(()));
+        let no_src = r#"<html><body><iframe name="view"></iframe></body></html>"#;
+        assert_eq!(extract_session(no_src, PAGE_URL), Err(ParseErr::NoSession));
+    }
+}
@@ -0,0 +1,189 @@
+use super::messages::{Message, MessageKind};
+use regex::Regex;
+use std::sync::Mutex;
+
+/// What counts as addressing us, besides a private message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MentionConfig {
+    /// Other names we answer to, matched like the nick.
+    pub aliases: Vec<String>,
+    /// The nick anywhere in the text, as a whole word.
+    pub whole_word: bool,
+    /// `@nick` anywhere in the text.
+    pub at_prefix: bool,
+    /// `nick:` or `nick,` starting the text.
+    pub colon_prefix: bool,
+}
+
+impl Default for MentionConfig {
+    fn default() -> Self {
+        Self { aliases: vec![], whole_word: true, at_prefix: true, colon_prefix: true }
+    }
+}
+
+/// Tells the messages addressed to a nick or its aliases, case-insensitive.
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    names: Vec<String>,
+    text: Option<Regex>,
+}
+
+impl Matcher {
+    pub fn new(nick: &str, config: &MentionConfig) -> Self {
+        let names: Vec<_> = std::iter::once(nick).chain(config.aliases.iter().map(String::as_str)).map(str::trim).filter(|n| !n.is_empty()).collect();
+        let alternatives = names.iter().map(|n| regex::escape(n)).collect::<Vec<_>>().join("|");
+        // No lookaround in `regex`: the neighbours are matched instead, a
+        // letter, digit or `_` next to the name makes it part of a longer
+        // word. Nicks ending in symbols still need a boundary after them.
+        let mut patterns = vec![];
+        if config.whole_word {
+            patterns.push(format!(r"(?:^|[^\w])(?:{})(?:[^\w]|$)", alternatives));
+        }
+        if config.at_prefix {
+            patterns.push(format!(r"@(?:{})(?:[^\w]|$)", alternatives));
+        }
+        if config.colon_prefix {
+            patterns.push(format!(r"^\s*(?:{})\s*[:,]", alternatives));
+        }
+        let text = (!names.is_empty() && !patterns.is_empty()).then(|| Regex::new(&format!("(?i){}", patterns.join("|"))).unwrap());
+        Self { names: names.into_iter().map(str::to_lowercase).collect(), text }
+    }
+
+    fn is_us(&self, nick: &str) -> bool {
+        self.names.contains(&nick.to_lowercase())
+    }
+
+    /// A private message to us, or our name in the text. What we send
+    /// ourselves doesn't count.
+    pub fn mentions(&self, message: &Message) -> bool {
+        if message.from.as_deref().is_some_and(|from| self.is_us(from)) {
+            return false;
+        }
+        if message.kind == MessageKind::Private && message.to.as_deref().is_some_and(|to| self.is_us(to)) {
+            return true;
+        }
+        !matches!(message.kind, MessageKind::System(_)) && self.text.as_ref().is_some_and(|rgx| rgx.is_match(&message.text))
+    }
+}
+
+// Our aliases that the display rules give someone else are theirs
+fn without_targets(config: &MentionConfig, targets: &[String]) -> MentionConfig {
+    let mut config = config.clone();
+    config.aliases.retain(|alias| !targets.iter().any(|t| t.eq_ignore_ascii_case(alias.trim())));
+    config
+}
+
+/// A client's session nick and what mentions it.
+#[derive(Debug, Default)]
+pub struct Mentions {
+    config: MentionConfig,
+    matcher: Mutex<Option<(String, Matcher)>>,
+}
+
+impl Mentions {
+    /// Mentions of the nick with `config`, less the aliases that are
+    /// `targets` of the display rules.
+    pub fn new(config: &MentionConfig, targets: &[String]) -> Self {
+        Self { config: without_targets(config, targets), matcher: Mutex::new(None) }
+    }
+
+    /// The session's nick, set by `login`.
+    pub fn set_nick(&self, nick: &str) {
+        *self.matcher.lock().unwrap() = Some((nick.to_owned(), Matcher::new(nick, &self.config)));
+    }
+
+    pub fn nick(&self) -> Option<String> {
+        self.matcher.lock().unwrap().as_ref().map(|(nick, _)| nick.clone())
+    }
+
+    /// Against the session's nick, never before one is set.
+    pub fn mentions_me(&self, message: &Message) -> bool {
+        self.matcher.lock().unwrap().as_ref().is_some_and(|(_, m)| m.mentions(message))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lechatphp::messages::SystemEvent;
+
+    fn message(kind: MessageKind, from: &str, to: Option<&str>, text: &str) -> Message {
+        Message {
+            id: None,
+            timestamp: String::new(),
+            time: None,
+            from: Some(from.to_owned()),
+            to: to.map(str::to_owned),
+            text: text.to_owned(),
+            spans: vec![],
+            color: None,
+            display_from: None,
+            display_color: None,
+            kind,
+            mentions_me: false,
+            room: None,
+        }
+    }
+
+    fn said(matcher: &Matcher, text: &str) -> bool {
+        matcher.mentions(&message(MessageKind::Normal, "bob", None, text))
+    }
+
+    #[test]
+    fn mentions_test() {
+        let mart = Matcher::new("mart", &MentionConfig::default());
+        assert!(said(&mart, "mart"));
+        assert!(said(&mart, "hey Mart, you there?"));
+        assert!(said(&mart, "@MART look"));
+        assert!(said(&mart, "mart: ping"));
+        assert!(said(&mart, "(mart)"));
+        assert!(!said(&mart, "that's smart"));
+        assert!(!said(&mart, "martin is here"));
+        assert!(!said(&mart, "mart_2 is here"));
+
+        // Private messages, and ours
+        assert!(mart.mentions(&message(MessageKind::Private, "bob", Some("Mart"), "psst")));
+        assert!(!mart.mentions(&message(MessageKind::Normal, "mart", None, "I am mart")));
+        assert!(!mart.mentions(&message(MessageKind::System(SystemEvent::Joined("mart".to_owned())), "bob", None, "mart entered the chat")));
+
+        // Only the prefixes
+        let config = MentionConfig { whole_word: false, ..MentionConfig::default() };
+        let mart = Matcher::new("mart", &config);
+        assert!(said(&mart, "hi @mart"));
+        assert!(said(&mart, " Mart , ping"));
+        assert!(!said(&mart, "hi mart"));
+        let mart = Matcher::new("mart", &MentionConfig { whole_word: false, at_prefix: false, colon_prefix: false, aliases: vec![] });
+        assert!(!said(&mart, "@mart: hi"));
+        assert!(mart.mentions(&message(MessageKind::Private, "bob", Some("mart"), "psst")));
+
+        // An alias of ours the display rules give someone else
+        let config = MentionConfig { aliases: vec!["boss".to_owned(), "m".to_owned()], ..MentionConfig::default() };
+        let mart = Matcher::new("mart", &without_targets(&config, &["Boss".to_owned()]));
+        assert!(!said(&mart, "boss: hi") && said(&mart, "m: hi") && said(&mart, "mart: hi"));
+    }
+
+    #[test]
+    fn tricky_nicks_test() {
+        // Regex metacharacters, and symbols at either end
+        let nick = Matcher::new("c++.dev(1)", &MentionConfig::default());
+        assert!(said(&nick, "ask c++.dev(1) about it"));
+        assert!(said(&nick, "C++.DEV(1): hi"));
+        assert!(!said(&nick, "ask cXX.dev(1) about it"));
+        assert!(!said(&nick, "ask c++.dev(12) about it"));
+        let nick = Matcher::new("[ghost]", &MentionConfig::default());
+        assert!(said(&nick, "hi [ghost]!"));
+        assert!(!said(&nick, "hi ghost"));
+        assert!(!said(&nick, "hi [ghost]s"));
+
+        // Unicode, cased and as word characters
+        let nick = Matcher::new("Ärger", &MentionConfig::default());
+        assert!(said(&nick, "ärger, komm"));
+        assert!(!said(&nick, "großärger"));
+        let nick = Matcher::new("дмитрий", &MentionConfig { aliases: vec!["dima".to_owned(), " ".to_owned()], ..MentionConfig::default() });
+        assert!(said(&nick, "Привет, ДМИТРИЙ"));
+        assert!(said(&nick, "@Dima ping"));
+        assert!(!said(&nick, "дмитрийович"));
+        assert!(!said(&nick, "a b c"), "blank aliases are left out");
+        assert!(nick.mentions(&message(MessageKind::Private, "bob", Some("DIMA"), "psst")));
+    }
+}
@@ -0,0 +1,1080 @@
+use super::color::ChatColor;
+use super::error_kind::{Classify, ErrorKind};
+use super::exchange::{self, Exchange, Page};
+use super::feed::{self, Feed};
+use super::metrics::Operation;
+use super::page_url;
+use super::post::{check_post_response, PostErr};
+use super::retry::SendErr;
+use super::rooms;
+use super::sent;
+use super::settings::Settings;
+use super::spans;
+use super::timestamp;
+use super::transport::Transport;
+use chrono::{DateTime, FixedOffset};
+use http::StatusCode;
+use lazy_static::lazy_static;
+use regex::Regex;
+use select::document::Document;
+use select::node::Node;
+use select::predicate::{Attr, Class, Name, Or};
+use serde_derive::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::field::Empty;
+
+/// Prefixes of messages sent to a group rather than to everyone.
+const MEMBERS_TAGS: &[&str] = &["[M]", "[Members]"];
+const STAFF_TAGS: &[&str] = &["[Staff]", "[S]", "[Admin]", "[A]"];
+const DELIMITER: &str = " - ";
+/// Asks the view for the messages after this id only.
+const LAST_ID_PARAM: &str = "id";
+
+lazy_static! {
+    static ref COLOR_RGX: Regex = Regex::new(r"color:\s*([#\w]+)").unwrap();
+    // le-chat's English defaults, and what some forks say instead
+    static ref JOINED_RGX: Regex = Regex::new(r"^(.+?) (entered|has entered|has joined|joined) the chat\.?$").unwrap();
+    static ref LEFT_RGX: Regex = Regex::new(r"^(.+?) (left|has left) the chat\.?$").unwrap();
+    static ref ALL_KICKED_RGX: Regex = Regex::new(r"^All chatters have been kicked\.?$").unwrap();
+    static ref KICKED_RGX: Regex = Regex::new(r"^(.+?) (has been|have been|was|were) kicked(?: by (.+?))?\.?$").unwrap();
+    static ref CLEANED_RGX: Regex = Regex::new(r"^(.+?) (has|have) been cleaned\.?$").unwrap();
+    static ref TOPIC_LABEL_RGX: Regex = Regex::new(r"(?i)^\s*topic(?:\s+set\s+by\s+(.+?))?\s*:\s*").unwrap();
+    static ref TOPIC_SET_BY_RGX: Regex = Regex::new(r"(?i)\s*\(set by ([^)]+)\)\s*$").unwrap();
+}
+
+// A session, and the room of its requests. The topics are kept by it,
+// `None` for a view without one.
+pub(crate) type SessionRoom = (String, Option<String>);
+
+/// Nicks whose messages the fetched messages leave out, for forks without
+/// an ignore feature of their own. The profile's ignore list is kept
+/// here by `profile::ignore` and the like.
+pub fn set_ignored(settings: &Settings, nicks: Vec<String>) {
+    *settings.ignored.lock().unwrap() = nicks;
+}
+
+pub fn ignored(settings: &Settings) -> Vec<String> {
+    settings.ignored.lock().unwrap().clone()
+}
+
+// Messages from ignored nicks, system messages mentioning them are kept
+fn without_ignored(settings: &Settings, mut messages: Vec<Message>) -> Vec<Message> {
+    let ignored = settings.ignored.lock().unwrap();
+    messages.retain(|m| !m.from.as_ref().is_some_and(|from| ignored.contains(from)));
+    messages
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum MessageKind {
+    Normal,
+    /// Members only, `[M]`.
+    Members,
+    /// Staff or admins only, `[Staff]`.
+    Staff,
+    /// From one nick to another.
+    Private,
+    /// Entered, left, kicked... No sender.
+    System(SystemEvent),
+    /// `/me waves`, shown as `nick waves` without the ` - `.
+    Action,
+}
+
+/// What a system message says happened.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum SystemEvent {
+    Joined(String),
+    Left(String),
+    /// Several nicks at once are kept as one, like `alice, bob`.
+    Kicked { nick: String, by: Option<String> },
+    Cleaned,
+    /// Anything else, or the chat's language isn't English.
+    Other(String),
+}
+
+/// A message of the chat view.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Message {
+    /// Only shown to those who can delete messages.
+    pub id: Option<u64>,
+    /// As the server prints it, empty when it has timestamps disabled.
+    pub timestamp: String,
+    /// `timestamp` in the server's offset, `ClientConfig::server_utc_offset`.
+    pub time: Option<DateTime<FixedOffset>>,
+    pub from: Option<String>,
+    /// The recipient of a private message.
+    pub to: Option<String>,
+    /// As a browser shows it: entities decoded, `<br>` as newlines, links
+    /// by their label and images by their address.
+    pub text: String,
+    /// The text with its links and images.
+    pub spans: Vec<Span>,
+    /// The sender's color, `None` without one or one that isn't a color.
+    pub color: Option<ChatColor>,
+    /// `from` as the profile shows it, see `ClientConfig::display`. Only for
+    /// showing, replies and conversations go by `from`.
+    pub display_from: Option<String>,
+    /// `color` as the profile shows it.
+    pub display_color: Option<ChatColor>,
+    pub kind: MessageKind,
+    /// A private message to us, or our nick in the text, see `mention`.
+    pub mentions_me: bool,
+    /// The room it was fetched from, on forks with several, see `rooms`.
+    pub room: Option<String>,
+}
+
+/// A piece of a message's text, nested like the HTML's styling. Colors,
+/// sizes and the elements without a span of their own are left out, their
+/// text stays.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Span {
+    Text(String),
+    /// `<b>` or `<strong>`.
+    Bold(Vec<Span>),
+    /// `<i>` or `<em>`.
+    Italic(Vec<Span>),
+    Link { href: String, label: String },
+    /// An image shown in the message, not one inside a link.
+    Image { src: String, alt: Option<String> },
+    /// A line starting with `>`, the chat's way of quoting, the `>`
+    /// included.
+    Greentext(Vec<Span>),
+}
+
+impl Span {
+    fn push_text_to(&self, out: &mut String) {
+        match self {
+            Span::Text(text) => out.push_str(text),
+            Span::Bold(spans) | Span::Italic(spans) | Span::Greentext(spans) => spans.iter().for_each(|s| s.push_text_to(out)),
+            Span::Link { label, .. } => out.push_str(label),
+            Span::Image { src, .. } => out.push_str(src),
+        }
+    }
+
+    fn ends_with_newline(&self) -> bool {
+        match self {
+            Span::Text(text) => text.ends_with('\n'),
+            Span::Bold(spans) | Span::Italic(spans) | Span::Greentext(spans) => spans.last().is_some_and(Span::ends_with_newline),
+            Span::Link { label, .. } => label.ends_with('\n'),
+            Span::Image { .. } => false,
+        }
+    }
+
+    // The spans inside, for those that have some
+    fn children_mut(&mut self) -> Option<&mut Vec<Span>> {
+        match self {
+            Span::Bold(spans) | Span::Italic(spans) | Span::Greentext(spans) => Some(spans),
+            _ => None,
+        }
+    }
+}
+
+/// The links of `spans`, nested ones too, in order.
+pub fn links(spans: &[Span]) -> Vec<&str> {
+    let mut links = vec![];
+    for span in spans {
+        match span {
+            Span::Link { href, .. } => links.push(href.as_str()),
+            Span::Bold(spans) | Span::Italic(spans) | Span::Greentext(spans) => links.extend(self::links(spans)),
+            _ => {}
+        }
+    }
+    links
+}
+
+/// The topic or announcement line above the messages.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Topic {
+    /// Without its `Topic:` label.
+    pub text: String,
+    /// The text with its links.
+    pub spans: Vec<Span>,
+    /// When the chat tells.
+    pub set_by: Option<String>,
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum FetchErr {
+    #[error("kicked{}", .reason.as_ref().map(|reason| format!(": {}", reason)).unwrap_or_default())]
+    Kicked { reason: Option<String> },
+    #[error("session expired")]
+    SessionExpired,
+    #[error("{0}, server down")]
+    ServerDown(StatusCode),
+    /// No `#messages` in the page, not a view.
+    #[error("no messages in the page")]
+    NoMessages,
+    /// No `#chatters` in the page.
+    #[error("no user list in the page")]
+    NoUserList,
+    /// No such nick, see `users::fetch_user_info`.
+    #[error("not found")]
+    NotFound,
+    /// The session's role may not see the page.
+    #[error("permission denied")]
+    PermissionDenied,
+    #[error("{0}")]
+    Send(#[from] SendErr),
+}
+
+impl Classify for FetchErr {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            FetchErr::ServerDown(_) => ErrorKind::Transient,
+            FetchErr::Kicked { .. } | FetchErr::SessionExpired => ErrorKind::AuthExpired,
+            FetchErr::PermissionDenied => ErrorKind::PermissionDenied,
+            FetchErr::NotFound => ErrorKind::InvalidInput,
+            FetchErr::NoMessages | FetchErr::NoUserList => ErrorKind::Fatal,
+            FetchErr::Send(e) => e.kind(),
+        }
+    }
+}
+
+// Nick elements carry the sender's style, `<span style>` or `<font color>`
+fn nick_color(node: &Node) -> Option<ChatColor> {
+    match node.name()? {
+        "font" => node.attr("color").and_then(ChatColor::parse),
+        "span" => node.attr("style").and_then(|s| COLOR_RGX.captures(s)).and_then(|c| ChatColor::parse(&c[1])),
+        _ => None,
+    }
+}
+
+fn push_text(spans: &mut Vec<Span>, text: &str) {
+    match spans.last_mut() {
+        Some(Span::Text(last)) => last.push_str(text),
+        _ => spans.push(Span::Text(text.to_owned())),
+    }
+}
+
+// A block of a staff member's HTML post starts on its own line
+fn break_line(spans: &mut Vec<Span>) {
+    if spans.last().is_some_and(|s| !s.ends_with_newline()) {
+        push_text(spans, "\n");
+    }
+}
+
+// The text of `node` as spans, entities are decoded by the parser already
+fn push_spans(node: Node, spans: &mut Vec<Span>) {
+    if let Some(text) = node.as_text() {
+        push_text(spans, text);
+        return;
+    }
+    match node.name() {
+        Some("br") => push_text(spans, "\n"),
+        Some("a") if node.attr("href").is_some() => {
+            let href = node.attr("href").unwrap_or_default().to_owned();
+            let mut label = plain_text(&spans_of(node.children()));
+            // An embedded image links to itself
+            if label.trim().is_empty() {
+                let alt = node.find(Name("img")).next().and_then(|i| i.attr("alt")).filter(|a| !a.trim().is_empty());
+                label = alt.unwrap_or(&href).to_owned();
+            }
+            spans.push(Span::Link { href, label: label.trim().to_owned() });
+        }
+        Some("img") => {
+            if let Some(src) = node.attr("src") {
+                let alt = node.attr("alt").map(str::trim).filter(|a| !a.is_empty()).map(str::to_owned);
+                spans.push(Span::Image { src: src.to_owned(), alt });
+            }
+        }
+        Some("b" | "strong") => spans.push(Span::Bold(spans_of(node.children()))),
+        Some("i" | "em") => spans.push(Span::Italic(spans_of(node.children()))),
+        Some("p" | "div" | "li" | "tr" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "pre" | "blockquote") => {
+            break_line(spans);
+            node.children().for_each(|child| push_spans(child, spans));
+            break_line(spans);
+        }
+        Some("script" | "style" | "iframe") | None => {}
+        Some(_) => node.children().for_each(|child| push_spans(child, spans)),
+    }
+}
+
+fn spans_of<'a>(nodes: impl Iterator<Item = Node<'a>>) -> Vec<Span> {
+    let mut spans = vec![];
+    nodes.for_each(|node| push_spans(node, &mut spans));
+    spans
+}
+
+// Without the blanks around the whole text, nor empty spans
+fn trim_spans(mut spans: Vec<Span>) -> Vec<Span> {
+    prune(&mut spans);
+    if let Some(first) = edge_text(&mut spans, true) {
+        *first = first.trim_start().to_owned();
+    }
+    if let Some(last) = edge_text(&mut spans, false) {
+        *last = last.trim_end().to_owned();
+    }
+    prune(&mut spans);
+    spans
+}
+
+// The text the spans start with, or end with, however deep
+fn edge_text(spans: &mut [Span], first: bool) -> Option<&mut String> {
+    let span = if first { spans.first_mut()? } else { spans.last_mut()? };
+    match span {
+        Span::Text(text) => Some(text),
+        span => edge_text(span.children_mut()?, first),
+    }
+}
+
+// Left out: empty text, and styling of nothing
+fn prune(spans: &mut Vec<Span>) {
+    for span in spans.iter_mut() {
+        if let Some(children) = span.children_mut() {
+            prune(children);
+        }
+    }
+    spans.retain(|s| match s {
+        Span::Text(text) => !text.is_empty(),
+        Span::Bold(spans) | Span::Italic(spans) | Span::Greentext(spans) => !spans.is_empty(),
+        _ => true,
+    });
+}
+
+// `f` on every text of the spans, nested ones too
+fn map_texts(spans: &mut [Span], f: &impl Fn(&str) -> String) {
+    for span in spans {
+        match span {
+            Span::Text(text) => *text = f(text),
+            span => {
+                if let Some(children) = span.children_mut() {
+                    map_texts(children, f);
+                }
+            }
+        }
+    }
+}
+
+// The lines starting with `>` as `Greentext`. Only breaks between the
+// spans count, a line inside a styled span stays whole.
+fn greentext(spans: Vec<Span>) -> Vec<Span> {
+    let mut lines: Vec<Vec<Span>> = vec![vec![]];
+    for span in spans {
+        let Span::Text(text) = span else {
+            lines.last_mut().unwrap().push(span);
+            continue;
+        };
+        for (i, line) in text.split('\n').enumerate() {
+            if i > 0 {
+                lines.push(vec![]);
+            }
+            if !line.is_empty() {
+                push_text(lines.last_mut().unwrap(), line);
+            }
+        }
+    }
+    let mut spans = vec![];
+    for (i, line) in lines.into_iter().enumerate() {
+        if i > 0 {
+            push_text(&mut spans, "\n");
+        }
+        match line.first() {
+            Some(Span::Text(text)) if text.starts_with('>') => spans.push(Span::Greentext(line)),
+            _ => line.into_iter().for_each(|span| match span {
+                Span::Text(text) => push_text(&mut spans, &text),
+                span => spans.push(span),
+            }),
+        }
+    }
+    spans
+}
+
+/// The spans as a browser shows them.
+pub fn plain_text(spans: &[Span]) -> String {
+    let mut text = String::new();
+    spans.iter().for_each(|span| span.push_text_to(&mut text));
+    text
+}
+
+// For nicks without a color, the one of the text
+fn text_color(span: Node) -> Option<ChatColor> {
+    span.descendants().find_map(|n| nick_color(&n))
+}
+
+struct UserMsg {
+    nicks: Vec<(String, Option<ChatColor>)>,
+    /// The text around the nicks, `[M]`, `[`, ` to `...
+    head: String,
+    text: Vec<Span>,
+    /// Without a ` - ` after the first nick, everything after it: an action.
+    action: Option<Vec<Span>>,
+}
+
+// `[M] <nick> - text`, `[<nick> to <nick>] - text`: the nicks are the
+// elements before the first ` - ` text, whatever they contain
+fn parse_usermsg(span: Node) -> UserMsg {
+    let mut msg = UserMsg { nicks: vec![], head: String::new(), text: vec![], action: None };
+    let mut after_nick = vec![];
+    let mut in_text = false;
+    for child in span.children() {
+        if !msg.nicks.is_empty() {
+            push_spans(child, &mut after_nick);
+        }
+        if in_text {
+            push_spans(child, &mut msg.text);
+        } else if let Some(t) = child.as_text() {
+            match t.find(DELIMITER) {
+                Some(idx) => {
+                    msg.head += &t[..idx];
+                    push_text(&mut msg.text, &t[idx + DELIMITER.len()..]);
+                    in_text = true;
+                }
+                None => msg.head += t,
+            }
+        } else if child.name().is_some() {
+            msg.nicks.push((child.text(), nick_color(&child)));
+        }
+    }
+    // `<nick> waves`, some forks start it with `* `
+    let before_nick = span.children().next().and_then(|c| c.as_text().map(str::trim)).unwrap_or_default();
+    let after_text = plain_text(&after_nick);
+    let own_line = !after_text.starts_with(DELIMITER) && !after_text.trim().is_empty();
+    if own_line && (before_nick.is_empty() || before_nick == "*") {
+        msg.action = Some(after_nick);
+    }
+    msg
+}
+
+// Stands for a styled nick in the text a system message is matched on
+const NICK_MARK: char = '\u{1}';
+
+// le-chat styles the nicks of its system messages, those are taken as they
+// are. Without styling, the text alone tells where a nick ends.
+fn system_event(span: Node, text: &str) -> SystemEvent {
+    let mut nicks = vec![];
+    let mut marked = String::new();
+    for child in span.children() {
+        match child.name() {
+            Some("span" | "font") => {
+                nicks.push(child.text().trim().to_owned());
+                marked.push(NICK_MARK);
+            }
+            Some(_) => {}
+            None => marked.push_str(&child.text()),
+        }
+    }
+    let marked = marked.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mut nicks = nicks.into_iter();
+    // Captures come left to right, as the marks do
+    let mut nick = |capture: Option<regex::Match>| {
+        let capture = capture?.as_str();
+        Some(capture.chars().fold(String::new(), |mut nick, c| {
+            match c {
+                NICK_MARK => nick.push_str(&nicks.next().unwrap_or_default()),
+                c => nick.push(c),
+            }
+            nick
+        }))
+    };
+    if let Some(c) = JOINED_RGX.captures(&marked) {
+        SystemEvent::Joined(nick(c.get(1)).unwrap_or_default())
+    } else if let Some(c) = LEFT_RGX.captures(&marked) {
+        SystemEvent::Left(nick(c.get(1)).unwrap_or_default())
+    } else if ALL_KICKED_RGX.is_match(&marked) {
+        SystemEvent::Other(text.to_owned())
+    } else if let Some(c) = KICKED_RGX.captures(&marked) {
+        SystemEvent::Kicked { nick: nick(c.get(1)).unwrap_or_default(), by: nick(c.get(3)) }
+    } else if CLEANED_RGX.is_match(&marked) {
+        SystemEvent::Cleaned
+    } else {
+        SystemEvent::Other(text.to_owned())
+    }
+}
+
+fn parse_message(settings: &Settings, div: Node) -> Option<Message> {
+    let id = div.find(Attr("name", "mid[]")).next().and_then(|i| i.attr("value")).and_then(|v| v.parse().ok());
+    let timestamp = div
+        .find(Name("small"))
+        .next()
+        .map(|s| s.text().trim().trim_end_matches('-').trim_end().to_owned())
+        .unwrap_or_default();
+    let span = div.find(Or(Class("usermsg"), Class("sysmsg"))).next()?;
+    let mut message =
+        Message {
+            id,
+            timestamp,
+            time: None,
+            from: None,
+            to: None,
+            text: String::new(),
+            spans: vec![],
+            color: None,
+            display_from: None,
+            display_color: None,
+            kind: MessageKind::System(SystemEvent::Other(String::new())),
+            mentions_me: false,
+            room: None,
+        };
+    if span.attr("class") == Some("sysmsg") {
+        message.spans = trim_spans(spans_of(span.children()));
+        message.text = plain_text(&message.spans);
+        message.kind = MessageKind::System(system_event(span, &message.text));
+        return Some(message);
+    }
+    let UserMsg { mut nicks, head, text, action } = parse_usermsg(span);
+    let head = head.trim();
+    message.spans = text;
+    message.kind = if let Some(action) = action {
+        message.spans = action;
+        MessageKind::Action
+    } else if head.starts_with('[') && nicks.len() == 2 {
+        MessageKind::Private
+    } else if MEMBERS_TAGS.contains(&head) {
+        MessageKind::Members
+    } else if STAFF_TAGS.contains(&head) {
+        MessageKind::Staff
+    } else {
+        MessageKind::Normal
+    };
+    if message.kind == MessageKind::Private {
+        message.to = nicks.pop().map(|(nick, _)| nick);
+    }
+    let (from, color) = nicks.into_iter().next()?;
+    message.from = Some(from);
+    message.color = color.or_else(|| text_color(span));
+    message.spans = trim_spans(message.spans);
+    // Line breaks another client of ours posted with the separator, and
+    // emoji the terminal may not show
+    map_texts(&mut message.spans, &|text| settings.emoji.shorten(&settings.multi_line.restore(text)));
+    if message.kind != MessageKind::Action {
+        message.spans = greentext(message.spans);
+    }
+    message.text = plain_text(&message.spans);
+    settings.display.apply(&mut message);
+    Some(message)
+}
+
+/// The messages of a view page, newest first like the page, read with the
+/// client's `settings`.
+pub fn parse_messages(settings: &Settings, doc: &Document) -> Result<Vec<Message>, FetchErr> {
+    parse_messages_at(settings, doc, timestamp::server_now(settings.server_offset))
+}
+
+// Dates the page leaves out are the latest before `now`
+fn parse_messages_at(settings: &Settings, doc: &Document, now: DateTime<FixedOffset>) -> Result<Vec<Message>, FetchErr> {
+    let messages = doc.find(Attr("id", "messages")).next().ok_or(FetchErr::NoMessages)?;
+    let mut messages: Vec<_> = messages.find(Class("msg")).filter_map(|div| parse_message(settings, div)).collect();
+    let stamps: Vec<_> = messages.iter().map(|m| m.timestamp.as_str()).collect();
+    let times = timestamp::message_times(&stamps, now);
+    for (message, time) in messages.iter_mut().zip(times) {
+        message.time = time;
+        message.mentions_me = settings.mention.mentions_me(message);
+    }
+    Ok(messages)
+}
+
+/// The topic of a view page, `None` when the chat has none set.
+pub fn parse_topic(doc: &Document) -> Option<Topic> {
+    let div = doc.find(Or(Attr("id", "topic"), Class("topic"))).next()?;
+    let mut spans = trim_spans(spans_of(div.children()));
+    let mut set_by = None;
+    // The label is often bold
+    if let Some(first) = edge_text(&mut spans, true) {
+        if let Some(caps) = TOPIC_LABEL_RGX.captures(first) {
+            set_by = caps.get(1).map(|nick| nick.as_str().trim().to_owned());
+            *first = first[caps[0].len()..].to_owned();
+        }
+    }
+    // Or `Rules... (set by bob)`
+    if let Some(last) = edge_text(&mut spans, false) {
+        if let Some(caps) = TOPIC_SET_BY_RGX.captures(last) {
+            set_by = set_by.or_else(|| Some(caps[1].trim().to_owned()));
+            last.truncate(last.len() - caps[0].len());
+        }
+    }
+    let spans = trim_spans(spans);
+    if spans.is_empty() {
+        return None;
+    }
+    Some(Topic { text: plain_text(&spans), spans, set_by })
+}
+
+/// The topic the last view fetched for `session` showed, in the room it is
+/// in. Views from the message feed don't tell, the topic stays the one
+/// before.
+pub fn latest_topic(settings: &Settings, session: &str) -> Option<Topic> {
+    topic_in(settings, session, rooms::room(settings, session).as_deref())
+}
+
+pub(super) fn topic_in(settings: &Settings, session: &str, room: Option<&str>) -> Option<Topic> {
+    settings.topics.lock().unwrap().get(&(session.to_owned(), room.map(str::to_owned))).cloned().flatten()
+}
+
+/// The topic of the chat view, kept for `latest_topic` too.
+pub fn fetch_topic(transport: &Transport, base_url: &str, page_php: &str, session: &str) -> Result<Option<Topic>, FetchErr> {
+    exchange::block_on(fetch_topic_with(transport, base_url, page_php, session))
+}
+
+/// `fetch_topic` over any `Exchange`.
+pub async fn fetch_topic_with<E: Exchange>(http: &E, base_url: &str, page_php: &str, session: &str) -> Result<Option<Topic>, FetchErr> {
+    let room = rooms::room(http.settings(), session);
+    fetch_session_view(http, base_url, page_php, session, room.as_deref(), None).await?;
+    Ok(topic_in(http.settings(), session, room.as_deref()))
+}
+
+/// The messages currently in the chat view.
+pub fn fetch_messages(transport: &Transport, base_url: &str, page_php: &str, session: &str) -> Result<Vec<Message>, FetchErr> {
+    exchange::block_on(fetch_messages_with(transport, base_url, page_php, session))
+}
+
+/// `fetch_messages` over any `Exchange`.
+pub async fn fetch_messages_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+) -> Result<Vec<Message>, FetchErr> {
+    fetch_room_messages(http, base_url, page_php, session, rooms::room(http.settings(), session).as_deref()).await
+}
+
+/// The messages of `room` rather than the session's room, which stays the
+/// same.
+pub fn fetch_messages_in(transport: &Transport, base_url: &str, page_php: &str, session: &str, room: &str) -> Result<Vec<Message>, FetchErr> {
+    exchange::block_on(fetch_messages_in_with(transport, base_url, page_php, session, room))
+}
+
+/// `fetch_messages_in` over any `Exchange`.
+pub async fn fetch_messages_in_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    room: &str,
+) -> Result<Vec<Message>, FetchErr> {
+    fetch_room_messages(http, base_url, page_php, session, Some(room)).await
+}
+
+#[tracing::instrument(name = "fetch", skip_all, fields(mirror = %base_url, room = ?room, status = Empty, elapsed_ms = Empty))]
+async fn fetch_room_messages<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    room: Option<&str>,
+) -> Result<Vec<Message>, FetchErr> {
+    let _timer = spans::timer();
+    let messages = fetch_session_view(http, base_url, page_php, session, room, None).await?;
+    sent::correlate(http.settings(), session, &messages);
+    Ok(without_ignored(http.settings(), messages))
+}
+
+/// The messages posted after `last_id`, newest first, with the id to ask
+/// from next time. Messages are only numbered for those who can delete
+/// them, without ids nothing can be left out.
+pub fn fetch_messages_since(
+    transport: &Transport,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    last_id: u64,
+) -> Result<(Vec<Message>, u64), FetchErr> {
+    exchange::block_on(fetch_messages_since_with(transport, base_url, page_php, session, last_id))
+}
+
+/// `fetch_messages_since` over any `Exchange`.
+pub async fn fetch_messages_since_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    last_id: u64,
+) -> Result<(Vec<Message>, u64), FetchErr> {
+    fetch_since_with(http, base_url, page_php, session, rooms::room(http.settings(), session).as_deref(), last_id).await
+}
+
+/// The messages of `room` posted after `last_id`, see
+/// `fetch_messages_since`. The session's room stays the same.
+pub fn fetch_messages_since_in(
+    transport: &Transport,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    room: &str,
+    last_id: u64,
+) -> Result<(Vec<Message>, u64), FetchErr> {
+    exchange::block_on(fetch_since_with(transport, base_url, page_php, session, Some(room), last_id))
+}
+
+// `fetch_messages_since` in `room`, `None` for the chat's default room
+#[tracing::instrument(name = "fetch", skip_all, fields(mirror = %base_url, room = ?room, last_id, status = Empty, elapsed_ms = Empty))]
+pub(super) async fn fetch_since_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    room: Option<&str>,
+    last_id: u64,
+) -> Result<(Vec<Message>, u64), FetchErr> {
+    let _timer = spans::timer();
+    let mut fetched = None;
+    if let Feed::Endpoint(endpoint) = feed::feed(http.settings(), session) {
+        match feed::fetch_endpoint_with(http, base_url, page_php, session, room, &endpoint, last_id).await {
+            Ok(messages) => fetched = Some(messages),
+            Err(e @ (FetchErr::SessionExpired | FetchErr::Kicked { .. })) => return Err(e),
+            // The view from now on
+            Err(e) => {
+                log::warn!("message feed {} failed, back to the view: {}", endpoint.query, e);
+                feed::set_feed(http.settings(), session, Feed::View);
+            }
+        }
+    }
+    let messages = match fetched {
+        Some(messages) => in_room(messages, room),
+        None => fetch_session_view(http, base_url, page_php, session, room, Some(last_id)).await?,
+    };
+    let (messages, high) = since(messages, last_id);
+    sent::correlate(http.settings(), session, &messages);
+    Ok((without_ignored(http.settings(), messages), high))
+}
+
+// Some forks ignore `LAST_ID_PARAM` and send the whole page. Newest first,
+// everything from the first message already seen on is old.
+fn since(mut messages: Vec<Message>, last_id: u64) -> (Vec<Message>, u64) {
+    if let Some(seen) = messages.iter().position(|m| m.id.is_some_and(|id| id <= last_id)) {
+        log::debug!("the view sent {} messages already seen", messages.len() - seen);
+        messages.truncate(seen);
+    }
+    let high = messages.iter().filter_map(|m| m.id).max().map_or(last_id, |id| id.max(last_id));
+    (messages, high)
+}
+
+/// The view of the room `session` is in.
+pub(super) fn view_url<E: Exchange>(http: &E, base_url: &str, page_php: &str, session: &str) -> String {
+    view_url_in(base_url, page_php, session, http.lang(), rooms::room(http.settings(), session).as_deref())
+}
+
+pub(super) fn view_url_in(base_url: &str, page_php: &str, session: &str, lang: &str, room: Option<&str>) -> String {
+    page_view_url(&page_url(base_url, page_php), session, lang, room)
+}
+
+/// `view_url_in` from the chat page's url.
+pub(super) fn page_view_url(full_url: &str, session: &str, lang: &str, room: Option<&str>) -> String {
+    format!("{}?action=view&session={}&lang={}{}", full_url, session, lang, rooms::query(room))
+}
+
+pub(super) async fn fetch_view<E: Exchange>(http: &E, url: &str) -> Result<Vec<Message>, FetchErr> {
+    parse_messages(http.settings(), &fetch_page(http, url).await?)
+}
+
+// The view of `session` in `room`, from `last_id` on when given, its topic
+// kept for `latest_topic`
+async fn fetch_session_view<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    room: Option<&str>,
+    last_id: Option<u64>,
+) -> Result<Vec<Message>, FetchErr> {
+    let mut url = view_url_in(base_url, page_php, session, http.lang(), room);
+    if let Some(last_id) = last_id {
+        url = format!("{}&{}={}", url, LAST_ID_PARAM, last_id);
+    }
+    let doc = fetch_page(http, &url).await?;
+    let messages = parse_messages(http.settings(), &doc)?;
+    http.settings().topics.lock().unwrap().insert((session.to_owned(), room.map(str::to_owned)), parse_topic(&doc));
+    Ok(in_room(messages, room))
+}
+
+// Tagged as fetched from `room`
+fn in_room(mut messages: Vec<Message>, room: Option<&str>) -> Vec<Message> {
+    for message in &mut messages {
+        message.room = room.map(str::to_owned);
+    }
+    messages
+}
+
+/// A page of the chat, checked for what says the session is over.
+pub(super) async fn fetch_page<E: Exchange>(http: &E, url: &str) -> Result<Document, FetchErr> {
+    read_page(http.get(Operation::Fetch, url).await?)
+}
+
+// A page of the session, or why the session is gone
+pub(super) fn read_page(page: Page) -> Result<Document, FetchErr> {
+    spans::record_status(page.status);
+    if page.status.is_server_error() {
+        return Err(FetchErr::ServerDown(page.status));
+    }
+    let doc = Document::from(page.body?.as_str());
+    match check_post_response(&doc) {
+        Err(PostErr::Kicked { reason }) => return Err(FetchErr::Kicked { reason }),
+        Err(PostErr::SessionExpired) => return Err(FetchErr::SessionExpired),
+        _ => {}
+    }
+    // Some forks send the login form instead
+    if doc.find(Attr("name", "nick")).next().is_some() && doc.find(Attr("name", "pass")).next().is_some() {
+        return Err(FetchErr::SessionExpired);
+    }
+    Ok(doc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lechatphp::mock::{MockExchange, MockResponse};
+
+    // The fixtures' times are of that evening
+    fn parse(html: &str) -> Vec<Message> {
+        parse_messages_at(&Settings::default(), &Document::from(html), at("2026-10-17 23:00:00")).unwrap()
+    }
+
+    fn at(time: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_str(&format!("{} +0000", time), "%Y-%m-%d %H:%M:%S %z").unwrap()
+    }
+
+    fn msg(from: &str, text: &str, color: &str, kind: MessageKind) -> Message {
+        let (color, spans) = (ChatColor::parse(color), vec![Span::Text(text.to_owned())]);
+        Message { id: None, timestamp: String::new(), time: None, from: Some(from.to_owned()), to: None, text: text.to_owned(), spans, color, display_from: Some(from.to_owned()), display_color: color, kind, mentions_me: false, room: None }
+    }
+
+    #[test]
+    fn parse_messages_test() {
+        let messages = parse(include_str!("fixtures/view.html"));
+        assert_eq!(messages.len(), 3);
+        let (timestamp, time) = ("10-17 19:40:02".to_owned(), Some(at("2026-10-17 19:40:02")));
+        let alice = Message { timestamp, time, ..msg("alice", "hello everyone", "#FF0000", MessageKind::Normal) };
+        assert_eq!(messages[0], alice);
+        assert_eq!(messages[2].kind, MessageKind::System(SystemEvent::Joined("alice".to_owned())));
+        assert_eq!((messages[2].from.as_deref(), messages[2].text.as_str()), (None, "alice entered the chat."));
+
+        // Moderator view: ids, groups, private messages and a nick with a space
+        let messages = parse(include_str!("fixtures/view_moderator.html"));
+        let kinds = messages.iter().map(|m| m.kind.clone()).collect::<Vec<_>>();
+        use MessageKind::*;
+        assert_eq!(kinds, [Private, Staff, Members, Normal, System(SystemEvent::Joined("dark knight".to_owned()))]);
+        assert_eq!(messages[0].id, Some(4821));
+        assert_eq!(messages[0].timestamp, "10-17 19:41:10");
+        assert_eq!((messages[0].from.as_deref(), messages[0].to.as_deref()), (Some("alice"), Some("dark knight")));
+        assert_eq!(messages[0].text, "see you at 9");
+        assert_eq!(messages[1].from.as_deref(), Some("mod one"));
+        // Only the first delimiter splits
+        assert_eq!(messages[2].text, "members only - ok?");
+        assert_eq!(messages[3].text, "look here");
+        assert_eq!(messages[4].id, None);
+
+        // Timestamps disabled, and an older skin with <font>
+        let messages = parse(include_str!("fixtures/view_no_timestamps.html"));
+        assert_eq!(messages[0], msg("old timer", "no clock here", "#FFA500", Normal));
+        assert_eq!(messages[1], Message { to: Some("bob".to_owned()), ..msg("old timer", "psst", "#FFA500", Private) });
+        assert_eq!(messages[2].kind, System(SystemEvent::Left("bob".to_owned())));
+
+        // `/me` as rendered, with a link and in a fork's `* nick` style
+        let messages = parse(include_str!("fixtures/view_actions.html"));
+        let link = Span::Link { href: "https://example.com/".to_owned(), label: "everyone".to_owned() };
+        let spans = vec![Span::Text("waves at ".to_owned()), link];
+        let bob = msg("bob", "waves at everyone", "#00FF00", Action);
+        let (timestamp, time) = ("10-17 20:05:41".to_owned(), Some(at("2026-10-17 20:05:41")));
+        assert_eq!(messages[0], Message { timestamp, time, spans, ..bob });
+        assert_eq!((messages[1].kind.clone(), messages[1].text.as_str()), (Action, "is away - back soon"));
+        assert_eq!((messages[2].kind.clone(), messages[2].text.as_str()), (Normal, "/me is not parsed here"));
+        assert_eq!(messages[3].kind, System(SystemEvent::Joined("bob".to_owned())));
+
+        assert!(matches!(parse_messages(&Settings::default(), &Document::from("<html></html>")), Err(FetchErr::NoMessages)));
+    }
+
+    #[test]
+    fn system_events_test() {
+        use SystemEvent::*;
+        let messages = parse(include_str!("fixtures/view_system.html"));
+        let events = messages
+            .iter()
+            .map(|m| match &m.kind {
+                MessageKind::System(event) => event.clone(),
+                kind => panic!("{:?} for {:?}", kind, m.text),
+            })
+            .collect::<Vec<_>>();
+        let kicked = |nick: &str, by: Option<&str>| Kicked { nick: nick.to_owned(), by: by.map(str::to_owned) };
+        assert_eq!(
+            events,
+            [
+                Joined("alice".to_owned()),
+                Joined("dark knight".to_owned()),
+                // The styled nick is taken whole, whatever it says
+                Joined("Tom left the chat".to_owned()),
+                Left("bob".to_owned()),
+                kicked("spammer", Some("mod one")),
+                kicked("troll", None),
+                kicked("eve, mallory", None),
+                Other("All chatters have been kicked.".to_owned()),
+                Cleaned,
+                // Other languages aren't guessed at
+                Other("alice hat den Chat betreten.".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn private_messages_test() {
+        use MessageKind::*;
+        let messages = parse(include_str!("fixtures/view_private.html"));
+        let summary = messages
+            .iter()
+            .map(|m| (m.kind.clone(), m.from.as_deref(), m.to.as_deref(), m.text.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (Private, Some("night owl"), Some("dark knight"), "are you [still] there?"),
+                (Normal, Some("alice"), None, "anyone up to [Staff] duty?"),
+                (Private, Some("dark knight"), Some("night owl"), "yes - one sec"),
+                (Normal, Some("dark knight"), None, "[alice to bob] is not a pm"),
+                (System(SystemEvent::Joined("night owl".to_owned())), None, None, "night owl entered the chat."),
+            ]
+        );
+    }
+
+    #[test]
+    fn message_markup_test() {
+        let messages = parse(include_str!("fixtures/view_markup.html"));
+        assert_eq!(messages[0].text, "Tom & Jerry say \"hi\" \u{2014} \u{263A} <3");
+        assert_eq!(messages[1].text, "line one\nline two\nline three");
+        let link = |href: &str, label: &str| Span::Link { href: href.to_owned(), label: label.to_owned() };
+        let spans = vec![
+            Span::Text("look ".to_owned()),
+            link("http://example.onion/cat.png", "http://example.onion/cat.png"),
+            Span::Text(" and ".to_owned()),
+            link("http://example.onion/", "the site"),
+        ];
+        assert_eq!(messages[2].spans, spans);
+        assert_eq!(messages[2].text, "look http://example.onion/cat.png and the site");
+        // A nick without style, the color is the text's
+        assert_eq!((messages[3].from.as_deref(), messages[3].color), (Some("carol"), ChatColor::parse("#ABCDEF")));
+        assert_eq!(messages[3].spans[1], Span::Image { src: "smiley.gif".to_owned(), alt: None });
+        assert_eq!(messages[4].text, "Tom & Jerry entered the chat.");
+        // Raw HTML from a staff member, blocks on their own lines
+        assert_eq!(messages[5].text, "Rules:\nread them\nbe nice");
+        assert_eq!(messages[5].spans[1], link("http://example.onion/rules", "read them"));
+        assert!(messages.iter().all(|m| !m.text.contains("<b") && !m.text.contains("&amp;")));
+    }
+
+    #[test]
+    fn styled_spans_test() {
+        let messages = parse(include_str!("fixtures/view_styled.html"));
+        let text = |t: &str| Span::Text(t.to_owned());
+        assert_eq!(
+            messages[0].spans,
+            [Span::Bold(vec![text("bold "), Span::Italic(vec![text("both")])]), text(" and "), Span::Italic(vec![text("emphasis")]), text(", "), Span::Bold(vec![text("strong")])]
+        );
+        assert_eq!(messages[0].text, "bold both and emphasis, strong");
+        let sun = Span::Image { src: "http://example.onion/sun.png".to_owned(), alt: Some("sun".to_owned()) };
+        assert_eq!(messages[1].spans, [sun, text(" at "), Span::Link { href: "http://example.onion/beach".to_owned(), label: "the beach".to_owned() }]);
+        // Quoted lines, a `>` further in doesn't count
+        let green = [
+            Span::Greentext(vec![text(">be me")]),
+            text("\n"),
+            Span::Greentext(vec![text(">implying "), Span::Bold(vec![text("this")])]),
+            text("\nnot quoted > here"),
+        ];
+        assert_eq!(messages[2].spans, green);
+        assert_eq!(messages[2].text, ">be me\n>implying this\nnot quoted > here");
+        // Tags left open are closed with the message, the next one is whole
+        let link = Span::Link { href: "http://example.onion/x".to_owned(), label: "link".to_owned() };
+        assert_eq!(messages[3].spans, [Span::Bold(vec![text("never closed "), Span::Italic(vec![text("nor this "), link])])]);
+        assert_eq!(links(&messages[3].spans), ["http://example.onion/x"]);
+        // Elements without a span are their text, empty styling is dropped
+        assert_eq!((messages[4].from.as_deref(), messages[4].spans.as_slice()), (Some("carol"), [text("scrolling text under")].as_slice()));
+    }
+
+    #[test]
+    fn serde_test() {
+        // Every kind, event and span the fixtures have, through JSON and back
+        for html in [
+            include_str!("fixtures/view_moderator.html"),
+            include_str!("fixtures/view_system.html"),
+            include_str!("fixtures/view_actions.html"),
+            include_str!("fixtures/view_markup.html"),
+            include_str!("fixtures/view_styled.html"),
+        ] {
+            let messages = parse(html);
+            let json = serde_json::to_string(&messages).unwrap();
+            assert_eq!(serde_json::from_str::<Vec<Message>>(&json).unwrap(), messages);
+        }
+        let topic = parse_topic(&Document::from(include_str!("fixtures/view_topic_links.html"))).unwrap();
+        assert_eq!(serde_json::from_str::<Topic>(&serde_json::to_string(&topic).unwrap()).unwrap(), topic);
+
+        // The names are the API, and fields from newer versions are skipped
+        let json = r##"{"id":7,"timestamp":"10-17 19:40:02","time":"2026-10-17T19:40:02+00:00","from":null,"to":null,
+            "text":"bob was kicked by mod.","spans":[{"text":"bob was kicked by mod."}],"color":"#ff0000","display_from":null,
+            "display_color":null,"kind":{"system":{"kicked":{"nick":"bob","by":"mod"}}},"mentions_me":false,"room":null,"new":1}"##;
+        let kicked = serde_json::from_str::<Message>(json).unwrap();
+        assert_eq!(kicked.kind, MessageKind::System(SystemEvent::Kicked { nick: "bob".to_owned(), by: Some("mod".to_owned()) }));
+        assert_eq!((kicked.time, kicked.color), (Some(at("2026-10-17 19:40:02")), ChatColor::parse("red")));
+        assert!(serde_json::to_string(&kicked).unwrap().contains(r##""color":"#FF0000""##));
+        assert!(serde_json::from_str::<Message>(&json.replace("#ff0000", "inherit")).is_err());
+    }
+
+    #[test]
+    fn fetch_messages_test() {
+        const BASE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+        let fetch = |http: &MockExchange| exchange::block_on(fetch_messages_with(http, BASE_URL, "chat.php", "abc"));
+
+        let http = MockExchange::new(|_| Ok(MockResponse::ok(include_str!("fixtures/view.html"))));
+        assert_eq!(fetch(&http).unwrap().len(), 3);
+        assert_eq!(http.requests.borrow()[0].path, "/chat.php?action=view&session=abc&lang=en");
+
+        let http = MockExchange::new(|_| Ok(MockResponse::ok(r#"<body class="error"><h2>Error: Invalid/expired session</h2></body>"#)));
+        assert!(matches!(fetch(&http), Err(FetchErr::SessionExpired)));
+        let http = MockExchange::new(|_| Ok(MockResponse::new(503, "")));
+        assert!(matches!(fetch(&http), Err(FetchErr::ServerDown(StatusCode::SERVICE_UNAVAILABLE))));
+    }
+
+    #[test]
+    fn topic_test() {
+        let topic = |html: &str| parse_topic(&Document::from(html));
+        assert_eq!(topic(include_str!("fixtures/view.html")), None);
+        let plain = topic(include_str!("fixtures/view_topic.html")).unwrap();
+        assert_eq!((plain.text.as_str(), plain.set_by.as_deref()), ("Welcome! Be nice & stay on topic.", None));
+        let linked = topic(include_str!("fixtures/view_topic_links.html")).unwrap();
+        assert_eq!(linked.text, "Rules at the wiki, event on http://events.onion/friday\nsee you there");
+        assert_eq!(linked.set_by.as_deref(), Some("dark knight"));
+        assert_eq!(links(&linked.spans), ["http://rules.onion/", "http://events.onion/friday"]);
+        let after = topic(r#"<div id="topic">No spam (set by bob)</div>"#).unwrap();
+        assert_eq!((after.text.as_str(), after.set_by.as_deref()), ("No spam", Some("bob")));
+
+        // Kept from the view fetched, until the next one
+        const BASE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+        // The topic, then a view without one
+        let with_topic = std::cell::Cell::new(true);
+        let http = MockExchange::new(move |_| match with_topic.replace(false) {
+            true => Ok(MockResponse::ok(include_str!("fixtures/view_topic.html"))),
+            false => Ok(MockResponse::ok(include_str!("fixtures/view.html"))),
+        });
+        exchange::block_on(fetch_messages_with(&http, BASE_URL, "chat.php", "topic-test")).unwrap();
+        assert_eq!(latest_topic(&http.settings, "topic-test"), Some(plain));
+        // Another client's
+        assert_eq!(latest_topic(&Settings::default(), "topic-test"), None);
+        assert_eq!(exchange::block_on(fetch_topic_with(&http, BASE_URL, "chat.php", "topic-test")).unwrap(), None);
+        assert_eq!(latest_topic(&http.settings, "topic-test"), None);
+    }
+
+    #[test]
+    fn fetch_messages_since_test() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        const BASE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+        const VIEW: &str = include_str!("fixtures/view_moderator.html");
+        const NEW: &str = r#"<div class="msg"><label><input type="checkbox" name="mid[]" value="4822"></label><small>10-17 19:42:00 - </small><span class="usermsg"><span style="color:#00FF00;">dark knight</span> - <span>back</span></span></div>"#;
+        // The whole page every time, one more message the second time
+        let calls = AtomicUsize::new(0);
+        let http = MockExchange::new(move |_| match calls.fetch_add(1, Ordering::SeqCst) {
+            0 => Ok(MockResponse::ok(VIEW)),
+            _ => Ok(MockResponse::ok(&VIEW.replace(r#"<div id="messages">"#, &format!(r#"<div id="messages">{}"#, NEW)))),
+        });
+        let fetch = |last_id| exchange::block_on(fetch_messages_since_with(&http, BASE_URL, "chat.php", "abc", last_id));
+
+        let (messages, last_id) = fetch(0).unwrap();
+        assert_eq!((messages.len(), last_id), (5, 4821));
+        let (messages, last_id) = fetch(last_id).unwrap();
+        assert_eq!(last_id, 4822);
+        assert_eq!(messages.iter().map(|m| m.text.as_str()).collect::<Vec<_>>(), ["back"]);
+        // Nothing new, nothing twice
+        let (messages, last_id) = fetch(last_id).unwrap();
+        assert_eq!((messages.len(), last_id), (0, 4822));
+        assert_eq!(http.requests.borrow()[1].path, "/chat.php?action=view&session=abc&lang=en&id=4821");
+    }
+}
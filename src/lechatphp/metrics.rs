@@ -0,0 +1,294 @@
+use super::client::Pool;
+use super::retry::{self, SendErr};
+use reqwest::blocking::Response;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Durations kept per operation for the percentiles.
+const WINDOW: usize = 256;
+
+/// Logical operations requests are recorded under.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    LoginPage,
+    LoginPost,
+    Fetch,
+    Post,
+    Logout,
+    Other,
+}
+
+impl Operation {
+    const ALL: [Operation; 6] = [
+        Operation::LoginPage,
+        Operation::LoginPost,
+        Operation::Fetch,
+        Operation::Post,
+        Operation::Logout,
+        Operation::Other,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Operation::LoginPage => "login page",
+            Operation::LoginPost => "login post",
+            Operation::Fetch => "fetch",
+            Operation::Post => "post",
+            Operation::Logout => "logout",
+            Operation::Other => "other",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    Ok,
+    /// A 4xx or 5xx response.
+    Status,
+    Timeout,
+    Connect,
+    Other,
+}
+
+fn outcome(res: &Result<Response, SendErr>) -> Outcome {
+    match res {
+        Ok(resp) if resp.status().is_client_error() || resp.status().is_server_error() => Outcome::Status,
+        Ok(_) => Outcome::Ok,
+        Err(SendErr::Reqwest(e)) if retry::classify_timeout(e).is_some() => Outcome::Timeout,
+        Err(SendErr::Reqwest(e)) if e.is_connect() => Outcome::Connect,
+        Err(_) => Outcome::Other,
+    }
+}
+
+// Last WINDOW durations, in microseconds
+struct Ring {
+    samples: Vec<u64>,
+    next: usize,
+}
+
+#[derive(Default)]
+struct OpMetrics {
+    requests: AtomicU64,
+    ok: AtomicU64,
+    status_errors: AtomicU64,
+    timeouts: AtomicU64,
+    connect_errors: AtomicU64,
+    other_errors: AtomicU64,
+    // Recording never waits on it, a busy lock loses the sample
+    durations: Mutex<Option<Ring>>,
+}
+
+impl OpMetrics {
+    fn record(&self, elapsed: Duration, outcome: Outcome) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let counter = match outcome {
+            Outcome::Ok => &self.ok,
+            Outcome::Status => &self.status_errors,
+            Outcome::Timeout => &self.timeouts,
+            Outcome::Connect => &self.connect_errors,
+            Outcome::Other => &self.other_errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        if let Ok(mut ring) = self.durations.try_lock() {
+            let ring = ring.get_or_insert_with(|| Ring { samples: Vec::with_capacity(WINDOW), next: 0 });
+            let micros = elapsed.as_micros() as u64;
+            if ring.samples.len() < WINDOW {
+                ring.samples.push(micros);
+            } else {
+                ring.samples[ring.next] = micros;
+            }
+            ring.next = (ring.next + 1) % WINDOW;
+        }
+    }
+
+    fn snapshot(&self, operation: Operation) -> OperationStats {
+        let mut samples = self.durations.lock().unwrap().as_ref().map(|r| r.samples.clone()).unwrap_or_default();
+        samples.sort_unstable();
+        let percentile = |p: usize| {
+            (!samples.is_empty()).then(|| Duration::from_micros(samples[(samples.len() - 1) * p / 100]))
+        };
+        OperationStats {
+            operation,
+            requests: self.requests.load(Ordering::Relaxed),
+            ok: self.ok.load(Ordering::Relaxed),
+            status_errors: self.status_errors.load(Ordering::Relaxed),
+            timeouts: self.timeouts.load(Ordering::Relaxed),
+            connect_errors: self.connect_errors.load(Ordering::Relaxed),
+            other_errors: self.other_errors.load(Ordering::Relaxed),
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+        }
+    }
+}
+
+/// What one client's transport records, see `ClientConfig::metrics`.
+#[derive(Default)]
+pub struct Metrics {
+    enabled: AtomicBool,
+    /// Pool settings of the default client.
+    pool: Option<Pool>,
+    login_page: OpMetrics,
+    login_post: OpMetrics,
+    fetch: OpMetrics,
+    post: OpMetrics,
+    logout: OpMetrics,
+    other: OpMetrics,
+}
+
+impl Metrics {
+    /// Recording when `enabled`, off by default.
+    pub fn new(enabled: bool, pool: Option<Pool>) -> Self {
+        Self { enabled: AtomicBool::new(enabled), pool, ..Default::default() }
+    }
+
+    /// Turn recording on or off.
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Record the outcome of a request, if enabled.
+    pub fn record(&self, operation: Operation, elapsed: Duration, res: &Result<Response, SendErr>) {
+        if self.enabled.load(Ordering::Relaxed) {
+            self.op(operation).record(elapsed, outcome(res));
+        }
+    }
+
+    pub fn snapshot(&self) -> TransportMetrics {
+        TransportMetrics {
+            operations: Operation::ALL.iter().map(|&op| self.op(op).snapshot(op)).collect(),
+            pool: self.pool,
+        }
+    }
+
+    fn op(&self, operation: Operation) -> &OpMetrics {
+        match operation {
+            Operation::LoginPage => &self.login_page,
+            Operation::LoginPost => &self.login_post,
+            Operation::Fetch => &self.fetch,
+            Operation::Post => &self.post,
+            Operation::Logout => &self.logout,
+            Operation::Other => &self.other,
+        }
+    }
+}
+
+/// Counters and latency percentiles of one operation. Percentiles are over
+/// the last requests only, `None` before the first one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OperationStats {
+    pub operation: Operation,
+    pub requests: u64,
+    pub ok: u64,
+    pub status_errors: u64,
+    pub timeouts: u64,
+    pub connect_errors: u64,
+    pub other_errors: u64,
+    pub p50: Option<Duration>,
+    pub p90: Option<Duration>,
+    pub p99: Option<Duration>,
+}
+
+impl OperationStats {
+    pub fn errors(&self) -> u64 {
+        self.status_errors + self.timeouts + self.connect_errors + self.other_errors
+    }
+}
+
+/// What the transport recorded so far.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransportMetrics {
+    pub operations: Vec<OperationStats>,
+    /// Pool settings of the default client, `None` for a transport not
+    /// built by `client::build`.
+    pub pool: Option<Pool>,
+}
+
+impl TransportMetrics {
+    pub fn get(&self, operation: Operation) -> Option<&OperationStats> {
+        self.operations.iter().find(|s| s.operation == operation)
+    }
+}
+
+impl Display for TransportMetrics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let ms = |d: Option<Duration>| d.map_or("-".to_owned(), |d| format!("{}ms", d.as_millis()));
+        if let Some(pool) = &self.pool {
+            let secs = |d: Option<Duration>| d.map_or("off".to_owned(), |d| format!("{}s", d.as_secs()));
+            writeln!(
+                f,
+                "pool: idle timeout {}, {} idle per host, tcp keep-alive {}",
+                secs(pool.idle_timeout),
+                pool.max_idle_per_host,
+                secs(pool.tcp_keepalive)
+            )?;
+        }
+        for s in self.operations.iter().filter(|s| s.requests > 0) {
+            writeln!(
+                f,
+                "{}: {} requests, {} ok, {} http errors, {} timeouts, {} connect errors, {} other, p50 {} p90 {} p99 {}",
+                s.operation.name(),
+                s.requests,
+                s.ok,
+                s.status_errors,
+                s.timeouts,
+                s.connect_errors,
+                s.other_errors,
+                ms(s.p50),
+                ms(s.p90),
+                ms(s.p99)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Metrics").field("enabled", &self.enabled).field("pool", &self.pool).finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lechatphp::mock::{MockResponse, MockServer};
+    use crate::lechatphp::transport::Transport;
+    use std::thread;
+
+    #[test]
+    fn metrics_test() {
+        let server = MockServer::start(|req| {
+            if req.path.contains("slow") {
+                thread::sleep(Duration::from_millis(200));
+            }
+            if req.path.contains("missing") {
+                MockResponse::new(404, "Not Found")
+            } else {
+                MockResponse::ok("ok")
+            }
+        });
+        let transport = Transport::direct();
+        transport.settings().metrics.set_enabled(true);
+        for _ in 0..8 {
+            transport.send_as(Operation::Post, transport.get(&server.url)).unwrap();
+        }
+        for _ in 0..2 {
+            transport.send_as(Operation::Post, transport.get(format!("{}/slow", server.url))).unwrap();
+        }
+        transport.send_as(Operation::Post, transport.get(format!("{}/missing", server.url))).unwrap();
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let _ = transport.send_as(Operation::Post, transport.post(format!("http://{}", closed)));
+
+        let after = transport.settings().metrics.snapshot();
+        let post = after.get(Operation::Post).unwrap();
+        assert_eq!((post.requests, post.ok), (12, 10));
+        assert_eq!((post.status_errors, post.connect_errors), (1, 1));
+        // Most requests are fast, the slowest ones aren't
+        assert!(post.p50.unwrap() < Duration::from_millis(150));
+        assert!(post.p99.unwrap() >= Duration::from_millis(200));
+        assert!(after.to_string().contains("post: "));
+    }
+}
@@ -0,0 +1,307 @@
+use super::feed::{self, Feed};
+use super::rooms::{self, RoomErr};
+use super::transport::Transport;
+use super::{check_server, login, LoginErr, ServerHealth};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::{Debug, Display, Formatter};
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(5 * 60);
+
+/// A logged in session, and the mirror it belongs to. Every later request for
+/// this session must go to `base_url`.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    pub base_url: String,
+    /// Where its messages are fetched from, probed after login.
+    pub feed: Feed,
+    /// The room it is in on forks with several, `None` for the default.
+    pub room: Option<String>,
+    /// The language it logged in with, for the pages asked with it.
+    #[serde(default = "default_lang")]
+    pub lang: String,
+}
+
+fn default_lang() -> String {
+    super::LANG.to_owned()
+}
+
+// Never the id, anyone with it is logged in as us
+impl Debug for Session {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Session")
+            .field("id", &"***")
+            .field("base_url", &self.base_url)
+            .field("feed", &self.feed)
+            .field("room", &self.room)
+            .field("lang", &self.lang)
+            .finish()
+    }
+}
+
+impl Session {
+    /// Move to `room`, for every later request of the session, see
+    /// `rooms::switch_room`.
+    pub fn switch_room(&mut self, transport: &Transport, page_php: &str, room: &str) -> Result<(), RoomErr> {
+        rooms::switch_room(transport, &self.base_url, page_php, &self.id, room)?;
+        self.room = Some(room.to_owned());
+        Ok(())
+    }
+}
+
+/// Ordered list of base urls for the same chat. A mirror that looked down is
+/// skipped for `cooldown` so we don't flap back to a dead primary.
+pub struct Mirrors {
+    urls: Vec<String>,
+    cooldown: Duration,
+    dead_until: Mutex<HashMap<String, Instant>>,
+}
+
+impl Mirrors {
+    pub fn new(urls: Vec<String>) -> Self {
+        Self::with_cooldown(urls, DEFAULT_COOLDOWN)
+    }
+
+    pub fn with_cooldown(urls: Vec<String>, cooldown: Duration) -> Self {
+        Self { urls, cooldown, dead_until: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn mark_dead(&self, url: &str) {
+        self.dead_until.lock().unwrap().insert(url.to_owned(), Instant::now() + self.cooldown);
+    }
+
+    /// Mirrors in preference order, the ones cooling down last.
+    pub fn candidates(&self) -> Vec<String> {
+        let now = Instant::now();
+        let dead_until = self.dead_until.lock().unwrap();
+        let (alive, cooling): (Vec<&String>, Vec<&String>) = self
+            .urls
+            .iter()
+            .partition(|url| dead_until.get(*url).is_none_or(|until| *until <= now));
+        alive.into_iter().chain(cooling).cloned().collect()
+    }
+}
+
+/// One mirror's answer to the startup probe, `None` when it didn't answer
+/// before the deadline.
+#[derive(Debug, Clone)]
+pub struct Probe {
+    pub base_url: String,
+    pub health: Option<ServerHealth>,
+}
+
+impl Probe {
+    pub fn reachable(&self) -> bool {
+        self.health.as_ref().is_some_and(|h| h.reachable)
+    }
+}
+
+impl Display for Probe {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.health {
+            Some(h) if h.reachable => write!(f, "{} up {}ms", self.base_url, h.latency.as_millis()),
+            Some(h) => match h.status {
+                Some(status) => write!(f, "{} down ({})", self.base_url, status.as_u16()),
+                None => write!(f, "{} down", self.base_url),
+            },
+            None => write!(f, "{} no answer", self.base_url),
+        }
+    }
+}
+
+impl Mirrors {
+    /// Health check every mirror at once, then put the reachable ones first,
+    /// fastest first, for this session. The others go last and cool down.
+    /// Answers arriving after `deadline` count as unreachable.
+    pub fn probe(&mut self, transport: &Transport, page_php: &str, deadline: Duration) -> Vec<Probe> {
+        let (tx, rx) = mpsc::channel();
+        for base_url in self.urls.iter().cloned() {
+            let (tx, transport, page_php) = (tx.clone(), transport.clone(), page_php.to_owned());
+            // Not joined, a mirror that hangs only holds up its own thread
+            thread::spawn(move || {
+                let health = check_server(&transport, &base_url, &page_php);
+                let _ = tx.send((base_url, health));
+            });
+        }
+        drop(tx);
+        let mut answers = HashMap::new();
+        let end = Instant::now() + deadline;
+        while answers.len() < self.urls.len() {
+            match rx.recv_timeout(end.saturating_duration_since(Instant::now())) {
+                Ok((base_url, health)) => {
+                    answers.insert(base_url, health);
+                }
+                Err(_) => break,
+            }
+        }
+        let mut probes: Vec<Probe> =
+            self.urls.iter().map(|url| Probe { base_url: url.clone(), health: answers.remove(url) }).collect();
+        // Stable, unreachable ones keep their configured order
+        probes.sort_by_key(|p| match &p.health {
+            Some(h) if h.reachable => (false, h.latency),
+            _ => (true, Duration::ZERO),
+        });
+        for probe in &probes {
+            log::info!("mirror probe: {}", probe);
+            if !probe.reachable() {
+                self.mark_dead(&probe.base_url);
+            }
+        }
+        self.urls = probes.iter().map(|p| p.base_url.clone()).collect();
+        probes
+    }
+}
+
+/// Whether the error means "this mirror is unreachable or serving junk" rather than a
+/// problem with our credentials or captcha.
+pub fn is_mirror_failure(err: &LoginErr) -> bool {
+    match err {
+        LoginErr::ServerDownErr(_)
+        | LoginErr::ServerDown500Err(_)
+        | LoginErr::ConnectTimeout(..)
+        | LoginErr::ReadTimeout(..)
+        | LoginErr::ResponseTooLarge { .. }
+        | LoginErr::MalformedResponse { .. }
+        | LoginErr::InterstitialBlocked { .. }
+        | LoginErr::Body(..)
+        | LoginErr::TruncatedResponse(_) => true,
+        LoginErr::Reqwest(_, e) => e.is_connect(),
+        _ => false,
+    }
+}
+
+/// Like `login`, but tries each mirror in turn on server-down, connection and
+/// timeout errors. A proxy that is down fails every mirror, so that stops
+/// right away.
+pub fn login_with_mirrors(
+    transport: &Transport,
+    mirrors: &Mirrors,
+    page_php: &str,
+    username: &str,
+    password: &str,
+    color: &str,
+    manual_captcha: bool,
+) -> Result<Session, LoginErr> {
+    let mut last_err = LoginErr::UnknownErr;
+    for (attempt, base_url) in mirrors.candidates().into_iter().enumerate() {
+        let _span = tracing::info_span!("mirror", mirror = %base_url, attempt = attempt + 1).entered();
+        match login(transport, &base_url, page_php, username, password, color, manual_captcha) {
+            Ok(id) => {
+                let feed = feed::probe(transport, &base_url, page_php, &id);
+                feed::set_feed(transport.settings(), &id, feed.clone());
+                return Ok(Session { id, base_url, feed, room: None, lang: transport.lang().to_owned() });
+            }
+            // Likely our circuit rather than the mirror, no cooldown
+            Err(e @ LoginErr::CircuitFailed(..)) => {
+                tracing::error!(mirror = %base_url, error = %e, "mirror failed, trying the next one");
+                last_err = e;
+            }
+            Err(e) if is_mirror_failure(&e) => {
+                tracing::error!(mirror = %base_url, error = %e, "mirror failed, trying the next one");
+                mirrors.mark_dead(&base_url);
+                last_err = e;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Err(last_err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lechatphp::mock::{chat_server, MockResponse, MockServer};
+    use crate::lechatphp::feed::{FeedEndpoint, FeedFormat};
+    use crate::lechatphp::LANG;
+
+    #[test]
+    fn probe_test() {
+        let slow = MockServer::start(|_| {
+            thread::sleep(Duration::from_millis(300));
+            MockResponse::ok("<form></form>")
+        });
+        let fast = MockServer::start(|_| MockResponse::ok("<form></form>"));
+        let down = MockServer::start(|_| MockResponse::new(503, "Service Unavailable"));
+        // Accepts but never answers
+        let hung = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let hung_url = format!("http://{}", hung.local_addr().unwrap());
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let dead_url = format!("http://{}", closed);
+
+        let urls = vec![dead_url.clone(), hung_url.clone(), slow.url.clone(), down.url.clone(), fast.url.clone()];
+        let mut mirrors = Mirrors::new(urls);
+        let start = Instant::now();
+        let probes = mirrors.probe(&Transport::direct(), "chat.php", Duration::from_secs(1));
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        let order = vec![fast.url.clone(), slow.url.clone(), dead_url, hung_url, down.url.clone()];
+        assert_eq!(probes.iter().map(|p| p.base_url.clone()).collect::<Vec<_>>(), order);
+        assert!(probes[0].reachable() && probes[1].reachable());
+        assert!(probes[3].health.is_none());
+        assert!(probes[4].to_string().ends_with("down (503)"));
+        assert_eq!(mirrors.candidates(), order);
+        // The unreachable ones are cooling down
+        assert_eq!(mirrors.dead_until.lock().unwrap().len(), 3);
+        drop(hung);
+    }
+
+    #[test]
+    fn login_with_mirrors_test() {
+        let down = MockServer::start(|_| MockResponse::new(502, "Bad Gateway"));
+        let up = chat_server("mirror-test");
+        let mirrors = Mirrors::new(vec![down.url.clone(), up.url.clone()]);
+        let transport = Transport::direct();
+
+        let session = login_with_mirrors(&transport, &mirrors, "chat.php", "nick", "pass", "", true).unwrap();
+        assert_eq!(session, Session { id: "mirror-test".to_owned(), base_url: up.url.clone(), feed: Feed::View, room: None, lang: LANG.to_owned() });
+        // The login page fetch may be retried before the mirror is given up
+        let down_hits = down.hits();
+        assert!(down_hits >= 1);
+
+        // The dead primary is cooling down, the next login goes straight to the mirror
+        assert_eq!(mirrors.candidates(), vec![up.url.clone(), down.url.clone()]);
+        let session = login_with_mirrors(&transport, &mirrors, "chat.php", "nick", "pass", "", true).unwrap();
+        assert_eq!(session.base_url, up.url);
+        assert_eq!(down.hits(), down_hits);
+    }
+
+    #[test]
+    fn session_serde_test() {
+        let query = "action=ajax&session={session}&id={last_id}".to_owned();
+        let feed = Feed::Endpoint(FeedEndpoint { query, format: FeedFormat::Json });
+        let session =
+            Session { id: "s3cr3t".to_owned(), base_url: "http://a.onion".to_owned(), feed, room: Some("lounge".to_owned()), lang: "de".to_owned() };
+        let json = serde_json::to_string(&session).unwrap();
+        assert_eq!(serde_json::from_str::<Session>(&json).unwrap(), session);
+        assert!(json.contains(r#""feed":{"endpoint":{"query":"#) && json.contains(r#""format":"json""#), "{}", json);
+        // Kept by an older version, and with fields of a newer one
+        let old = r#"{"id":"s3cr3t","base_url":"http://a.onion","feed":"view","room":null,"since":3}"#;
+        assert_eq!(serde_json::from_str::<Session>(old).unwrap().lang, LANG);
+        // The id is a login, it stays out of logs
+        let debug = format!("{:?}", session);
+        assert!(!debug.contains("s3cr3t") && debug.contains("lounge"), "{}", debug);
+    }
+
+    #[test]
+    fn proxy_down_test() {
+        use crate::lechatphp::client::{self, ClientConfig, ProxySetting};
+
+        let up = chat_server("abc");
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let config = ClientConfig { proxy: ProxySetting::Url(format!("socks5h://{}", closed)), ..Default::default() };
+        let transport = client::build(&config).unwrap();
+        assert!(!transport.proxy_up());
+        assert!(Transport::direct().proxy_up());
+
+        // Every mirror would fail the same, none is tried or blamed
+        let mirrors = Mirrors::new(vec!["http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion".to_owned(), up.url.clone()]);
+        let err = login_with_mirrors(&transport, &mirrors, "chat.php", "nick", "pass", "", true).unwrap_err();
+        assert!(matches!(err, LoginErr::ProxyDown(..)), "unexpected {:?}", err);
+        assert_eq!(up.hits(), 0);
+        assert!(mirrors.dead_until.lock().unwrap().is_empty());
+    }
+}
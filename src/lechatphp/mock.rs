@@ -0,0 +1,422 @@
+// Tiny blocking HTTP server for offline tests of the protocol helpers, and
+// an in-process `Exchange` for the protocol code itself.
+use super::capture;
+use super::charset;
+use super::exchange::{Exchange, Page, Upload};
+use super::http_log::redact;
+use super::metrics::Operation;
+use super::retry::SendErr;
+use super::settings::Settings;
+use super::LANG;
+use http::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use http::StatusCode;
+use reqwest::Url;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing_core::span::Current;
+use tracing::{Event, Metadata, Subscriber};
+
+pub struct MockRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl MockRequest {
+    pub fn header(&self, name: &str) -> Option<String> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.clone())
+    }
+}
+
+#[derive(Clone)]
+pub struct MockResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl MockResponse {
+    pub fn new(status: u16, body: &str) -> Self {
+        Self::bytes(status, body.as_bytes().to_vec())
+    }
+
+    pub fn bytes(status: u16, body: Vec<u8>) -> Self {
+        Self { status, headers: vec![], body }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn ok(body: &str) -> Self {
+        Self::new(200, body)
+    }
+}
+
+pub struct MockServer {
+    pub url: String,
+    hits: Arc<AtomicUsize>,
+    connections: Arc<AtomicUsize>,
+}
+
+fn read_request<R: BufRead>(reader: &mut R) -> Option<MockRequest> {
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+        return None;
+    }
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("").to_owned();
+    let path = parts.next().unwrap_or("").to_owned();
+    let mut headers = Vec::new();
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).is_err() || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+            headers.push((name.trim().to_owned(), value.trim().to_owned()));
+        }
+    }
+    let mut body = vec![0u8; content_length];
+    let _ = reader.read_exact(&mut body);
+    Some(MockRequest { method, path, headers, body: String::from_utf8_lossy(&body).into_owned() })
+}
+
+fn write_response<W: Write>(stream: &mut W, resp: &MockResponse, keep_alive: bool) -> bool {
+    let connection = if keep_alive { "keep-alive" } else { "close" };
+    let mut out = format!(
+        "HTTP/1.1 {} MOCK\r\nContent-Length: {}\r\nConnection: {}\r\n",
+        resp.status,
+        resp.body.len(),
+        connection
+    );
+    for (name, value) in resp.headers.iter() {
+        out += &format!("{}: {}\r\n", name, value);
+    }
+    out += "\r\n";
+    stream.write_all(out.as_bytes()).is_ok() && stream.write_all(&resp.body).is_ok()
+}
+
+impl MockServer {
+    /// One request per connection, handled in turn.
+    pub fn start<F>(handler: F) -> Self
+    where
+        F: Fn(&MockRequest) -> MockResponse + Send + Sync + 'static,
+    {
+        Self::serve(handler, false)
+    }
+
+    /// Keeps connections open for more requests, see `connections`.
+    pub fn start_keep_alive<F>(handler: F) -> Self
+    where
+        F: Fn(&MockRequest) -> MockResponse + Send + Sync + 'static,
+    {
+        Self::serve(handler, true)
+    }
+
+    fn serve<F>(handler: F, keep_alive: bool) -> Self
+    where
+        F: Fn(&MockRequest) -> MockResponse + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let hits = Arc::new(AtomicUsize::new(0));
+        let connections = Arc::new(AtomicUsize::new(0));
+        let (hits_clone, connections_clone) = (Arc::clone(&hits), Arc::clone(&connections));
+        let handler = Arc::new(handler);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(s) => s,
+                    Err(_) => return,
+                };
+                connections_clone.fetch_add(1, Ordering::SeqCst);
+                let (hits, handler) = (Arc::clone(&hits_clone), Arc::clone(&handler));
+                let mut serve = move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    while let Some(req) = read_request(&mut reader) {
+                        hits.fetch_add(1, Ordering::SeqCst);
+                        if !write_response(&mut stream, &handler(&req), keep_alive) || !keep_alive {
+                            break;
+                        }
+                    }
+                };
+                if keep_alive {
+                    thread::spawn(serve);
+                } else {
+                    serve();
+                }
+            }
+        });
+        Self { url, hits, connections }
+    }
+
+    pub fn hits(&self) -> usize {
+        self.hits.load(Ordering::SeqCst)
+    }
+
+    /// Connections accepted so far.
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+}
+
+/// Minimal le-chat-php: login page without captcha, login answers with the
+/// chat frameset carrying `session`.
+pub fn chat_server(session: &str) -> MockServer {
+    let session = session.to_owned();
+    MockServer::start(move |req| {
+        if req.method == "POST" {
+            MockResponse::ok(&format!(r#"<html><body><iframe name="view" src="chat.php?action=view&session={}&lang=en"></iframe></body></html>"#, session))
+        } else {
+            MockResponse::ok(r#"<html><body><form><input name="nick"></form></body></html>"#)
+        }
+    })
+}
+
+/// A SOCKS5 proxy that answers any connect request with `reply`, 4 for a
+/// host unreachable. Returns its url.
+pub fn socks_proxy(reply: u8) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("socks5h://{}", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for mut stream in listener.incoming().map_while(Result::ok) {
+            // The greeting, then the request up to its port
+            let mut greeting = [0u8; 2];
+            let _ = stream.read_exact(&mut greeting);
+            let _ = stream.read_exact(&mut vec![0u8; greeting[1] as usize]);
+            let _ = stream.write_all(&[5, 0]);
+            let mut head = [0u8; 5];
+            let _ = stream.read_exact(&mut head);
+            let address = match head[3] {
+                1 => 3 + 2,
+                3 => head[4] as usize + 2,
+                _ => 15 + 2,
+            };
+            let _ = stream.read_exact(&mut vec![0u8; address]);
+            let _ = stream.write_all(&[5, reply, 0, 1, 0, 0, 0, 0, 0, 0]);
+        }
+    });
+    url
+}
+
+// Path and query of a captured url
+fn url_path(url: &str) -> String {
+    Url::parse(url).map_or(url.to_owned(), |u| match u.query() {
+        Some(query) => format!("{}?{}", u.path(), query),
+        None => u.path().to_owned(),
+    })
+}
+
+/// Serves a session recorded with `capture` back, to turn a report into a
+/// test. A request gets the first record not served yet with the same
+/// method, path and query, redacted values count as equal. Failed requests
+/// aren't replayed, anything else not in the capture is a 404.
+pub fn replay(dir: &Path) -> MockServer {
+    let records = capture::load(dir).unwrap().into_iter().filter(|(r, _)| r.status.is_some()).map(Some).collect();
+    let records: Mutex<Vec<_>> = Mutex::new(records);
+    MockServer::start(move |req| {
+        let path = redact(&req.path);
+        let mut records = records.lock().unwrap();
+        let found = records
+            .iter_mut()
+            .find(|r| r.as_ref().is_some_and(|(r, _)| r.method == req.method && url_path(&r.url) == path));
+        let Some((record, body)) = found.and_then(Option::take) else {
+            return MockResponse::new(404, "not in the capture");
+        };
+        let mut resp = MockResponse::new(record.status.unwrap(), &body.unwrap_or_default());
+        for (name, value) in record.headers {
+            match name.as_str() {
+                // The server sets its own, and the body was saved decoded
+                "content-length" | "connection" | "transfer-encoding" | "content-encoding" => {}
+                "content-type" => resp = resp.with_header(&name, "text/html; charset=utf-8"),
+                _ => resp = resp.with_header(&name, &value),
+            }
+        }
+        resp
+    })
+}
+
+type Handler = Box<dyn Fn(&MockRequest) -> Result<MockResponse, SendErr>>;
+
+/// `Exchange` answered by `handler` without any socket, so every outcome,
+/// failed requests included, can be scripted. Sleeps are only added up.
+pub struct MockExchange {
+    handler: Handler,
+    pub requests: RefCell<Vec<MockRequest>>,
+    pub slept: Cell<Duration>,
+    cookies: RefCell<Vec<String>>,
+    /// What the client was built with, the defaults unless a test sets some.
+    pub settings: Settings,
+}
+
+impl MockExchange {
+    pub fn new<F>(handler: F) -> Self
+    where
+        F: Fn(&MockRequest) -> Result<MockResponse, SendErr> + 'static,
+    {
+        Self { handler: Box::new(handler), requests: RefCell::new(vec![]), slept: Cell::new(Duration::ZERO), cookies: RefCell::new(vec![]), settings: Settings::default() }
+    }
+
+    /// Answers each request with the next of `answers`, whatever it asks.
+    /// Running out of them fails the test.
+    pub fn script(answers: Vec<Result<MockResponse, SendErr>>) -> Self {
+        let answers = RefCell::new(VecDeque::from(answers));
+        Self::new(move |req| answers.borrow_mut().pop_front().unwrap_or_else(|| panic!("nothing left to answer {} {}", req.method, req.path)))
+    }
+
+    fn answer(&self, method: &str, url: &str, body: String) -> Result<Page, SendErr> {
+        let path = url_path(url);
+        let mut headers = vec![];
+        if !self.cookies.borrow().is_empty() {
+            headers.push(("Cookie".to_owned(), self.cookies.borrow().join("; ")));
+        }
+        let req = MockRequest { method: method.to_owned(), path, headers, body };
+        let resp = (self.handler)(&req);
+        self.requests.borrow_mut().push(req);
+        let resp = resp?;
+        let mut headers = HeaderMap::new();
+        for (name, value) in &resp.headers {
+            headers.append(HeaderName::try_from(name.as_str()).unwrap(), HeaderValue::try_from(value.as_str()).unwrap());
+        }
+        let content_type = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok());
+        let body = Ok(charset::decode(&resp.body, content_type));
+        Ok(Page { status: StatusCode::from_u16(resp.status).unwrap(), headers, body })
+    }
+}
+
+impl Exchange for MockExchange {
+    async fn get(&self, _operation: Operation, url: &str) -> Result<Page, SendErr> {
+        self.answer("GET", url, String::new())
+    }
+
+    async fn post_form(&self, _operation: Operation, url: &str, params: &[(&str, String)]) -> Result<Page, SendErr> {
+        let body = params.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join("&");
+        self.answer("POST", url, body)
+    }
+
+    // The file goes last as `field=<file_name;mime>content`
+    async fn post_multipart(&self, _operation: Operation, url: &str, params: &[(&str, String)], upload: Upload) -> Result<Page, SendErr> {
+        let mut content = String::new();
+        (&upload.file).read_to_string(&mut content).map_err(SendErr::Body)?;
+        let file = format!("{}=<{};{}>{}", upload.field, upload.file_name, upload.mime, content);
+        let body = params.iter().map(|(k, v)| format!("{}={}", k, v)).chain([file]).collect::<Vec<_>>().join("&");
+        self.answer("POST", url, body)
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        self.slept.set(self.slept.get() + duration);
+    }
+
+    fn add_cookie(&self, cookie: &str, _url: &str) {
+        let pair = cookie.split(';').next().unwrap_or_default().trim();
+        self.cookies.borrow_mut().push(pair.to_owned());
+    }
+
+    fn lang(&self) -> &str {
+        LANG
+    }
+
+    fn settings(&self) -> &Settings {
+        &self.settings
+    }
+}
+
+/// A span opened while a `SpanRecorder` was the subscriber, with the
+/// fields it got by the time it was looked at.
+#[derive(Debug, Clone)]
+pub struct RecordedSpan {
+    pub name: &'static str,
+    pub fields: HashMap<&'static str, String>,
+    /// Index in `SpanRecorder::spans` of the span it was opened in.
+    pub parent: Option<usize>,
+    metadata: &'static Metadata<'static>,
+}
+
+/// Tracing subscriber keeping every span and event message, for
+/// `tracing::subscriber::with_default`. Knows the current span, for
+/// `Span::current`.
+#[derive(Clone, Default)]
+pub struct SpanRecorder {
+    pub spans: Arc<Mutex<Vec<RecordedSpan>>>,
+    pub events: Arc<Mutex<Vec<String>>>,
+    entered: Arc<Mutex<Vec<Id>>>,
+}
+
+struct Fields<'a>(&'a mut HashMap<&'static str, String>);
+
+impl Visit for Fields<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name(), value.to_owned());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0.insert(field.name(), format!("{:?}", value));
+    }
+}
+
+impl Subscriber for SpanRecorder {
+    fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, attrs: &Attributes<'_>) -> Id {
+        let parent = match attrs.parent() {
+            Some(id) => Some(id.clone()),
+            None if attrs.is_contextual() => self.entered.lock().unwrap().last().cloned(),
+            None => None,
+        };
+        let mut spans = self.spans.lock().unwrap();
+        let mut fields = HashMap::new();
+        attrs.record(&mut Fields(&mut fields));
+        let parent = parent.map(|id| id.into_u64() as usize - 1);
+        spans.push(RecordedSpan { name: attrs.metadata().name(), fields, parent, metadata: attrs.metadata() });
+        Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, span: &Id, values: &Record<'_>) {
+        let mut spans = self.spans.lock().unwrap();
+        values.record(&mut Fields(&mut spans[span.into_u64() as usize - 1].fields));
+    }
+
+    fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = HashMap::new();
+        event.record(&mut Fields(&mut fields));
+        self.events.lock().unwrap().push(fields.remove("message").unwrap_or_default());
+    }
+
+    fn enter(&self, span: &Id) {
+        self.entered.lock().unwrap().push(span.clone());
+    }
+
+    fn exit(&self, span: &Id) {
+        let mut entered = self.entered.lock().unwrap();
+        if let Some(i) = entered.iter().rposition(|id| id == span) {
+            entered.remove(i);
+        }
+    }
+
+    fn current_span(&self) -> Current {
+        match self.entered.lock().unwrap().last() {
+            Some(id) => Current::new(id.clone(), self.spans.lock().unwrap()[id.into_u64() as usize - 1].metadata),
+            None => Current::none(),
+        }
+    }
+}
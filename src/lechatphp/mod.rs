@@ -1,243 +1,1183 @@
 
-use base64::engine::general_purpose;
-use base64::Engine;
 use http::StatusCode;
+use lazy_static::lazy_static;
 use regex::Regex;
-use reqwest::blocking::Client;
-use select::document::Document;
-use select::predicate::{And, Attr, Name};
-use std::fmt::{Display, Formatter};
-use std::io::Write;
-use std::process::{Command, Stdio};
-use std::time::Duration;
-use std::{error, fs, io, thread};
-use crate::LANG;
-use crate::trim_newline;
-use crate::SESSION_RGX;
+use std::fmt::{Debug, Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::{fs, io};
+use thiserror::Error;
+use tracing::field::Empty;
+use tracing::Span;
+use error_kind::{Classify, ErrorKind};
+use login_page::{classify_login_response, extract_session, parse_login_page, waitroom_refresh, LoginResponse, ParseErr};
+use login_page::{CAPTCHA_USED_ERR, CAPTCHA_WG_ERR, KICKED_ERR, NICKNAME_ERR, REG_ERR};
+use catalog::Catalog;
+use exchange::{Exchange, Page};
+use metrics::Operation;
+use mirrors::Session;
+use settings::Settings;
+use transport::Transport;
+
+#[cfg(feature = "arti")]
+pub mod arti;
+#[cfg(feature = "solver")]
+pub mod captcha;
+pub mod captcha_prompt;
+pub mod capture;
+pub mod catalog;
+pub mod charset;
+pub mod chat;
+pub mod client;
+pub mod color;
+pub mod command;
+pub mod conversations;
+pub mod display;
+pub mod emoji;
+pub mod error_kind;
+pub mod exchange;
+pub mod feed;
+pub mod filter;
+pub mod history;
+pub mod http_log;
+mod hook;
+pub mod interstitial;
+pub mod login_page;
+pub mod mention;
+pub mod messages;
+pub mod metrics;
+pub mod mirrors;
+pub mod moderation;
+pub mod notes;
+pub mod retry;
+pub mod sent;
+pub mod settings;
+pub mod share;
+#[cfg(test)]
+pub(crate) mod mock;
+pub mod onion;
+pub mod onion_auth;
+pub mod outbox;
+pub mod post;
+pub mod preview;
+pub mod profile;
+pub mod purge;
+pub mod rate_limit;
+pub mod reply;
+pub mod responder;
+pub mod rooms;
+pub mod schedule;
+pub(crate) mod spans;
+pub mod storage;
+pub mod stream;
+pub mod timestamp;
+pub mod tls;
+pub mod tor;
+pub mod transport;
+pub mod unread;
+pub mod users;
+
+/// The language pages are asked in, the one their text is read in.
+pub const LANG: &str = "en";
 
 const SERVER_DOWN_500_ERR: &str = "500 Internal Server Error, server down";
 const SERVER_DOWN_ERR: &str = "502 Bad Gateway, server down";
-const KICKED_ERR: &str = "You have been kicked";
-const REG_ERR: &str = "This nickname is a registered member";
-const NICKNAME_ERR: &str = "Invalid nickname";
-const CAPTCHA_WG_ERR: &str = "Wrong Captcha";
-const CAPTCHA_USED_ERR: &str = "Captcha already used or timed out";
 const UNKNOWN_ERR: &str = "Unknown error";
 
+lazy_static! {
+    static ref META_REFRESH_RGX: Regex = Regex::new(r#"(?i)<meta[^>]+http-equiv=["']?refresh"#).unwrap();
+}
+
+pub(crate) type Progress = dyn Fn(&LoginProgress) + Send + Sync;
+
+/// Between two looks at the waitroom.
+const WAITROOM_WAIT: Duration = Duration::from_secs(10);
+
+/// The page the login couldn't make sense of, in the data dir.
+const DUMP_FILE: &str = "dump_login_err.html";
+
+
+/// What a login is waiting for, to show whoever waits for it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoginProgress {
+    /// The chat's waitroom, the login goes on after `wait`.
+    Waitroom { wait: Duration },
+    /// An anti-DDoS page served for `url`, asked again after `wait`.
+    Interstitial { page: interstitial::Interstitial, url: String, wait: Duration },
+    /// The server looked down, a new Tor circuit is tried after `wait`.
+    NewCircuit { wait: Duration },
+}
+
+impl LoginProgress {
+    /// What to show, from `catalog`. `Display` is the English one.
+    pub fn localized(&self, catalog: &Catalog) -> String {
+        self.message(|key, args| catalog.get(key, args))
+    }
+
+    fn message(&self, lookup: impl Fn(&str, &[(&str, &dyn Display)]) -> String) -> String {
+        match self {
+            LoginProgress::Waitroom { wait } => lookup("login-waitroom", &[("seconds", &wait.as_secs())]),
+            LoginProgress::Interstitial { page, url, wait } => {
+                lookup("login-interstitial", &[("page", page), ("url", url), ("wait", &format!("{:?}", wait))])
+            }
+            LoginProgress::NewCircuit { wait } => lookup("login-new-circuit", &[("wait", &format!("{:?}", wait))]),
+        }
+    }
+}
+
+impl Display for LoginProgress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message(catalog::english))
+    }
+}
+
+// Called with what the login waits for, on top of the log, see
+// `Transport::set_progress`
+fn progress(settings: &Settings, progress: LoginProgress) {
+    log::info!("{}", progress);
+    if let Some(clb) = settings.progress.get() {
+        clb(&progress);
+    }
+}
+
+/// How far a login got when it failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoginStage {
+    /// Getting the login page, through anti-DDoS pages.
+    LoginPage,
+    /// Posting the login form, with the captcha when there is one.
+    CaptchaSubmit,
+    Waitroom,
+    /// Reading the session from the page with the chat's frames.
+    ChatFrame,
+}
 
-#[derive(Debug)]
+impl Display for LoginStage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            LoginStage::LoginPage => "fetching the login page",
+            LoginStage::CaptchaSubmit => "submitting the login form",
+            LoginStage::Waitroom => "waiting in the waitroom",
+            LoginStage::ChatFrame => "reading the chat frame",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Where a login failed: the stage and the URL asked.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LoginContext {
+    pub stage: LoginStage,
+    pub url: String,
+}
+
+// The waitroom's url has the session in it
+impl Display for LoginContext {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.stage, http_log::redact(&self.url))
+    }
+}
+
+impl LoginContext {
+    fn new(stage: LoginStage, url: &str) -> Self {
+        Self { stage, url: url.to_owned() }
+    }
+
+    // The error of a request at this point of the login
+    fn err(&self, value: retry::SendErr) -> LoginErr {
+        let context = self.clone();
+        match value {
+            retry::SendErr::Reqwest(e) => self.reqwest_err(e),
+            retry::SendErr::PinMismatch(e) => LoginErr::PinMismatch(context, e),
+            retry::SendErr::Clearnet(e) => LoginErr::InvalidUrl(e),
+            retry::SendErr::ResponseTooLarge { limit } => LoginErr::ResponseTooLarge { context, limit },
+            retry::SendErr::Body(e) => LoginErr::Body(context, e),
+            retry::SendErr::Truncated { .. } => LoginErr::TruncatedResponse(context),
+        }
+    }
+
+    // The error of a page at this point of the login that didn't parse.
+    // Pages without the chat are left in the data dir for a report upstream.
+    fn parse_err(&self, value: ParseErr, page: &str, data_dir: Option<&Path>) -> LoginErr {
+        let context = self.clone();
+        match value {
+            ParseErr::Missing(missing) => LoginErr::MalformedResponse { context, missing },
+            ParseErr::ImageTooLarge => LoginErr::ResponseTooLarge { context, limit: captcha_prompt::MAX_IMAGE_SIZE },
+            ParseErr::NoChatFrame => {
+                dump_page(data_dir, page);
+                LoginErr::NoChatFrame(context)
+            }
+            ParseErr::NoSession => LoginErr::SessionNotFound { context, dump: dump_page(data_dir, page) },
+            ParseErr::Truncated(_) => LoginErr::TruncatedResponse(context),
+        }
+    }
+
+    // The url is in the context already, redacted
+    fn reqwest_err(&self, value: reqwest::Error) -> LoginErr {
+        let context = self.clone();
+        let value = value.without_url();
+        match retry::classify_timeout(&value) {
+            Some(retry::Timeout::Connect) => LoginErr::ConnectTimeout(context, value),
+            Some(retry::Timeout::Read) => LoginErr::ReadTimeout(context, value),
+            None => match retry::classify_connect(&value) {
+                Some(retry::ConnectFailure::ProxyDown) => LoginErr::ProxyDown(context, value),
+                Some(retry::ConnectFailure::Circuit) => LoginErr::CircuitFailed(context, value),
+                None => LoginErr::Reqwest(context, value),
+            },
+        }
+    }
+}
+
+/// Why a login failed. The variants with a `LoginContext` tell the stage
+/// and the URL at the end of their message, in parentheses, after the
+/// message they always had; the error they wrap is their `source()`.
+#[derive(Debug, Error)]
+#[non_exhaustive]
 pub enum LoginErr {
-    ServerDownErr,
-    ServerDown500Err,
+    #[error("{} ({})", SERVER_DOWN_ERR, .0)]
+    ServerDownErr(LoginContext),
+    #[error("{} ({})", SERVER_DOWN_500_ERR, .0)]
+    ServerDown500Err(LoginContext),
+    #[error("{}", CAPTCHA_USED_ERR)]
     CaptchaUsedErr,
+    #[error("{}", CAPTCHA_WG_ERR)]
     CaptchaWgErr,
+    #[error("{}", REG_ERR)]
     RegErr,
+    #[error("{}", NICKNAME_ERR)]
     NicknameErr,
+    #[error("{}", KICKED_ERR)]
     KickedErr,
+    #[error("{}", UNKNOWN_ERR)]
     UnknownErr,
-    Reqwest(reqwest::Error),
+    /// The page after the login form has no chat frame to take the session
+    /// from.
+    #[error("{} ({})", UNKNOWN_ERR, .0)]
+    NoChatFrame(LoginContext),
+    /// The chat frame has no session in its src. `dump` is the copy of the
+    /// page, to report the markup, if it could be saved.
+    #[error("no session in the chat frame{} ({context})", .dump.as_ref().map(|p| format!(", page saved to {}", p.display())).unwrap_or_default())]
+    SessionNotFound { context: LoginContext, dump: Option<PathBuf> },
+    /// A page of the login lacks what the protocol needs from it, a fork
+    /// with other markup.
+    #[error("malformed response, no {missing} ({context})")]
+    MalformedResponse { context: LoginContext, missing: &'static str },
+    /// Asking the user for the captcha failed, or nobody to ask, see
+    /// `Transport::set_prompt`.
+    #[error("can't ask for the captcha: {0}")]
+    CaptchaPrompt(#[source] io::Error),
+    #[error(transparent)]
+    InvalidUrl(#[from] onion::UrlErr),
+    #[error("{} ({})", .1, .0)]
+    PinMismatch(LoginContext, #[source] tls::PinMismatch),
+    #[error("response larger than {limit} bytes, refused ({context})")]
+    ResponseTooLarge { context: LoginContext, limit: usize },
+    /// Still behind an anti-DDoS page after passing it `passes` times.
+    #[error("blocked by an anti-DDoS {page}, still there after {} attempts ({context})", .passes + 1)]
+    InterstitialBlocked { context: LoginContext, page: interstitial::Interstitial, passes: usize },
+    #[error("error reading response: {} ({})", .1, .0)]
+    Body(LoginContext, #[source] io::Error),
+    /// The connection closed mid-page: shorter than its `Content-Length`,
+    /// or without its closing tags. Nothing in it is trusted.
+    #[error("response cut short ({0})")]
+    TruncatedResponse(LoginContext),
+    #[error("connect timeout: {} ({})", .1, .0)]
+    ConnectTimeout(LoginContext, #[source] reqwest::Error),
+    #[error("read timeout: {} ({})", .1, .0)]
+    ReadTimeout(LoginContext, #[source] reqwest::Error),
+    /// The SOCKS proxy didn't answer, no mirror will work until it does.
+    #[error("proxy unreachable, is tor running? ({}) ({})", .1, .0)]
+    ProxyDown(LoginContext, #[source] reqwest::Error),
+    /// The proxy couldn't reach the server, a new circuit may.
+    #[error("tor couldn't reach the server: {} ({})", .1, .0)]
+    CircuitFailed(LoginContext, #[source] reqwest::Error),
+    #[error("{} ({})", .1, .0)]
+    Reqwest(LoginContext, #[source] reqwest::Error),
 }
 
-impl From<reqwest::Error> for LoginErr {
-    fn from(value: reqwest::Error) -> Self {
-        LoginErr::Reqwest(value)
+impl Classify for LoginErr {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            LoginErr::ServerDownErr(_)
+            | LoginErr::ServerDown500Err(_)
+            | LoginErr::InterstitialBlocked { .. }
+            | LoginErr::Body(..)
+            | LoginErr::TruncatedResponse(_)
+            | LoginErr::ConnectTimeout(..)
+            | LoginErr::ReadTimeout(..)
+            | LoginErr::ProxyDown(..)
+            | LoginErr::CircuitFailed(..) => ErrorKind::Transient,
+            LoginErr::Reqwest(_, e) => error_kind::reqwest_kind(e),
+            LoginErr::KickedErr => ErrorKind::AuthExpired,
+            LoginErr::CaptchaUsedErr | LoginErr::CaptchaWgErr => ErrorKind::Captcha,
+            LoginErr::RegErr | LoginErr::NicknameErr | LoginErr::InvalidUrl(_) => ErrorKind::InvalidInput,
+            LoginErr::UnknownErr
+            | LoginErr::NoChatFrame(_)
+            | LoginErr::SessionNotFound { .. }
+            | LoginErr::MalformedResponse { .. }
+            | LoginErr::CaptchaPrompt(_)
+            | LoginErr::PinMismatch(..)
+            | LoginErr::ResponseTooLarge { .. } => ErrorKind::Fatal,
+        }
     }
 }
 
-impl Display for LoginErr {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let s = match self {
-            LoginErr::ServerDownErr => SERVER_DOWN_ERR.to_owned(),
-            LoginErr::ServerDown500Err => SERVER_DOWN_500_ERR.to_owned(),
-            LoginErr::CaptchaUsedErr => CAPTCHA_USED_ERR.to_owned(),
-            LoginErr::CaptchaWgErr => CAPTCHA_WG_ERR.to_owned(),
-            LoginErr::RegErr => REG_ERR.to_owned(),
-            LoginErr::NicknameErr => NICKNAME_ERR.to_owned(),
-            LoginErr::KickedErr => KICKED_ERR.to_owned(),
-            LoginErr::UnknownErr => UNKNOWN_ERR.to_owned(),
-            LoginErr::Reqwest(e) => e.to_string(),
-        };
-        write!(f, "{}", s)
+impl LoginErr {
+    /// Where the login failed, for the errors of a request.
+    pub fn context(&self) -> Option<&LoginContext> {
+        match self {
+            LoginErr::ServerDownErr(context)
+            | LoginErr::ServerDown500Err(context)
+            | LoginErr::NoChatFrame(context)
+            | LoginErr::MalformedResponse { context, .. }
+            | LoginErr::SessionNotFound { context, .. }
+            | LoginErr::PinMismatch(context, _)
+            | LoginErr::ResponseTooLarge { context, .. }
+            | LoginErr::InterstitialBlocked { context, .. }
+            | LoginErr::Body(context, _)
+            | LoginErr::TruncatedResponse(context)
+            | LoginErr::ConnectTimeout(context, _)
+            | LoginErr::ReadTimeout(context, _)
+            | LoginErr::ProxyDown(context, _)
+            | LoginErr::CircuitFailed(context, _)
+            | LoginErr::Reqwest(context, _) => Some(context),
+            _ => None,
+        }
+    }
+}
+
+/// The solver's answer, `None` when it can't read the captcha or isn't
+/// built in.
+#[cfg(feature = "solver")]
+fn solve(data_dir: Option<&Path>, captcha_img: &str) -> Option<String> {
+    let answer = captcha::solve_b64(data_dir, captcha_img);
+    if answer.is_none() {
+        tracing::info!("the solver couldn't read the captcha, asking for it");
+        captcha::stats().record_manual_fallback();
+    }
+    answer
+}
+
+#[cfg(not(feature = "solver"))]
+fn solve(_data_dir: Option<&Path>, _captcha_img: &str) -> Option<String> {
+    None
+}
+
+/// Whether the chat took the solver's answer, for its stats.
+#[cfg(feature = "solver")]
+fn record_solved(data_dir: Option<&Path>, accepted: bool) {
+    if accepted {
+        captcha::stats().record_confirmed();
+    } else {
+        captcha::stats().record_rejected();
+    }
+    captcha::save_stats(data_dir);
+}
+
+#[cfg(not(feature = "solver"))]
+fn record_solved(_data_dir: Option<&Path>, _accepted: bool) {}
+
+/// Url of the chat page on `base_url`.
+fn page_url(base_url: &str, page_php: &str) -> String {
+    format!("{}/{}", base_url.trim_end_matches('/'), page_php)
+}
+
+/// Save `page` to `DUMP_FILE` in `dir`, `None` when it can't be.
+fn dump_page(dir: Option<&Path>, page: &str) -> Option<PathBuf> {
+    let path = dir?.join(DUMP_FILE);
+    storage::write_optional("login page dump", &path, |path| fs::write(path, page)).then_some(path)
+}
+
+/// The login error for a status that means the server is down.
+fn server_down_err(status: StatusCode, context: &LoginContext) -> Option<LoginErr> {
+    match status {
+        StatusCode::INTERNAL_SERVER_ERROR => Some(LoginErr::ServerDown500Err(context.clone())),
+        s if s.is_server_error() => Some(LoginErr::ServerDownErr(context.clone())),
+        _ => None,
     }
 }
 
-impl error::Error for LoginErr {}
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerHealth {
+    pub reachable: bool,
+    pub status: Option<StatusCode>,
+    /// Time until the response headers arrived, or until the request failed.
+    pub latency: Duration,
+    pub waitroom_active: bool,
+}
+
+/// Probe the chat with a single, never retried, GET of the chat page.
+pub fn check_server(transport: &Transport, base_url: &str, page_php: &str) -> ServerHealth {
+    let start = Instant::now();
+    let resp = match transport.send_once(transport.get(page_url(base_url, page_php))) {
+        Ok(resp) => resp,
+        Err(e) => {
+            log::debug!("{} unreachable: {}", base_url, e);
+            return ServerHealth { reachable: false, status: None, latency: start.elapsed(), waitroom_active: false };
+        }
+    };
+    let latency = start.elapsed();
+    let status = resp.status();
+    let mut waitroom_active = waitroom_refresh(resp.headers()).is_some();
+    if !waitroom_active {
+        waitroom_active = transport.text(resp).is_ok_and(|body| META_REFRESH_RGX.is_match(&body));
+    }
+    ServerHealth { reachable: !status.is_server_error(), status: Some(status), latency, waitroom_active }
+}
 
 pub fn login(
-    client: &Client,
+    transport: &Transport,
     base_url: &str,
     page_php: &str,
     username: &str,
     password: &str,
     color: &str,
+    manual_captcha: bool,
 ) -> Result<String, LoginErr> {
-    // Get login page
-    let login_url = format!("{}/{}", &base_url, &page_php);
-    let resp = client.get(&login_url).send()?;
-    if resp.status() == StatusCode::BAD_GATEWAY {
-        return Err(LoginErr::ServerDownErr);
+    exchange::block_on(login_with(transport, base_url, page_php, username, password, color, manual_captcha))
+}
+
+/// `login` over any `Exchange`, sleeping through the waitroom. The login
+/// protocol itself is `start_login_with` and `resume_login_with`.
+#[tracing::instrument(name = "login", skip_all, fields(mirror = %base_url, elapsed_ms = Empty))]
+pub async fn login_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    username: &str,
+    password: &str,
+    color: &str,
+    manual_captcha: bool,
+) -> Result<String, LoginErr> {
+    let _timer = spans::timer();
+    let mut outcome = start_login_with(http, base_url, page_php, username, password, color, manual_captcha).await?;
+    loop {
+        match outcome {
+            LoginOutcome::Success(session) => return Ok(session.id),
+            LoginOutcome::Waitroom { retry_after, state, .. } => {
+                progress(http.settings(), LoginProgress::Waitroom { wait: retry_after });
+                http.sleep(retry_after).await;
+                outcome = resume_login_with(http, state).await?;
+            }
+        }
     }
-    let resp = resp.text()?;
-    let doc = Document::from(resp.as_str());
+}
+
+/// Where a login stands after a step that never sleeps.
+#[non_exhaustive]
+pub enum LoginOutcome {
+    /// Logged in, the feed is `Feed::View` until probed.
+    Success(Session),
+    /// The server put the login in its waitroom. `resume_login` asks
+    /// `poll_url` again, after `retry_after` or whenever the caller likes.
+    Waitroom { poll_url: String, retry_after: Duration, state: LoginState },
+}
+
+// The poll url has the session in it
+impl Debug for LoginOutcome {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoginOutcome::Success(session) => f.debug_tuple("Success").field(session).finish(),
+            LoginOutcome::Waitroom { poll_url, retry_after, state } => f
+                .debug_struct("Waitroom")
+                .field("poll_url", &http_log::redact(poll_url))
+                .field("retry_after", retry_after)
+                .field("state", state)
+                .finish(),
+        }
+    }
+}
+
+/// What a login in the waitroom needs to go on, see `resume_login`. No
+/// password nor captcha answer, those were sent already.
+#[derive(Clone)]
+pub struct LoginState {
+    base_url: String,
+    login_url: String,
+    username: String,
+    auto_solved: bool,
+    context: LoginContext,
+    poll_url: String,
+    hop: u32,
+}
+
+impl Debug for LoginState {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LoginState").field("base_url", &self.base_url).field("username", &self.username).field("hop", &self.hop).finish()
+    }
+}
+
+/// The login up to the waitroom, without sleeping: the login page, then
+/// the form with the captcha.
+pub fn start_login(
+    transport: &Transport,
+    base_url: &str,
+    page_php: &str,
+    username: &str,
+    password: &str,
+    color: &str,
+    manual_captcha: bool,
+) -> Result<LoginOutcome, LoginErr> {
+    exchange::block_on(start_login_with(transport, base_url, page_php, username, password, color, manual_captcha))
+}
+
+/// `start_login` over any `Exchange`.
+#[tracing::instrument(name = "login_form", skip_all, fields(mirror = %base_url, status = Empty, elapsed_ms = Empty))]
+pub async fn start_login_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    username: &str,
+    password: &str,
+    color: &str,
+    manual_captcha: bool,
+) -> Result<LoginOutcome, LoginErr> {
+    let _timer = spans::timer();
+    onion::validate_base_url(base_url)?;
+
+    // Get login page
+    let login_url = page_url(base_url, page_php);
+    let resp = interstitial::get_page(http, Operation::LoginPage, &login_url).await?;
+    let submit = LoginContext::new(LoginStage::CaptchaSubmit, &login_url);
+    let login_page = LoginContext::new(LoginStage::LoginPage, &login_url);
+    let data_dir = http.settings().data_dir.as_deref();
+    let form = parse_login_page(&resp).map_err(|e| login_page.parse_err(e, &resp, data_dir))?;
 
     // Post login form
     let mut params = vec![
         ("action", "login".to_owned()),
-        ("lang", LANG.to_owned()),
+        ("lang", http.lang().to_owned()),
         ("nick", username.to_owned()),
         ("pass", password.to_owned()),
         ("colour", color.to_owned()),
     ];
 
-    if let Some(captcha_node) = doc
-        .find(And(Name("input"), Attr("name", "challenge")))
-        .next()
-    {
-        let captcha_value = captcha_node.attr("value").unwrap();
-        let captcha_img = doc.find(Name("img")).next().unwrap().attr("src").unwrap();
-
-        let mut captcha_input = String::new();
-        
-        // Attempt to strip the appropriate prefix based on the MIME type
-        let base64_str =
-            if let Some(base64) = captcha_img.strip_prefix("data:image/png;base64,") {
-                base64
-            } else if let Some(base64) = captcha_img.strip_prefix("data:image/gif;base64,") {
-                base64
-            } else {
-                panic!("Unexpected captcha image format. Expected PNG or GIF.");
-            };
-
-        // Decode the base64 string into binary image data
-        let img_decoded = general_purpose::STANDARD.decode(base64_str).unwrap();
-
-        let img = image::load_from_memory(&img_decoded).unwrap();
-        let img_buf = image::imageops::resize(
-            &img,
-            img.width() * 4,
-            img.height() * 4,
-            image::imageops::FilterType::Nearest,
-        );
-        // Save captcha as file on disk
-        img_buf.save("captcha.gif").unwrap();
-
-        let mut sxiv_process = Command::new("sxiv")
-        .arg("captcha.gif")
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn()
-        .expect("Failed to open image with sxiv");
-
-    // Prompt the user to enter the CAPTCHA
-    print!("Please enter the CAPTCHA: ");
-    io::stdout().flush().unwrap();
-    io::stdin().read_line(&mut captcha_input).unwrap();
-    trim_newline(&mut captcha_input);
-
-    // Close the sxiv window
-    sxiv_process.kill().expect("Failed to close sxiv");
-
-    println!("Captcha input: {}", captcha_input);
-            
+    let mut auto_solved = false;
+    if let Some(captcha) = form.captcha {
+        let _captcha = tracing::info_span!("captcha", answered_by = Empty, elapsed_ms = Empty).entered();
+        let _timer = spans::timer();
+        // Try the auto-solver first, fall back to asking the user
+        let solved = if manual_captcha { None } else { solve(data_dir, &captcha.src) };
+        auto_solved = solved.is_some();
+        Span::current().record("answered_by", if auto_solved { "solver" } else { "prompt" });
+        let captcha_input = match solved {
+            Some(answer) => answer,
+            None => {
+                let img = captcha.image().map_err(|e| login_page.parse_err(e, &resp, data_dir))?;
+                captcha_prompt::prompt(http.settings(), &img).map_err(LoginErr::CaptchaPrompt)?
+            }
+        };
+
+        log::debug!("captcha answered, {} characters", captcha_input.chars().count());
 
         params.extend(vec![
-            ("challenge", captcha_value.to_owned()),
-            ("captcha", captcha_input.clone()),
+            ("challenge", captcha.challenge),
+            ("captcha", captcha_input),
         ]);
     }
 
-    let mut resp = client.post(&login_url).form(&params).send()?;
-    match resp.status() {
-        StatusCode::BAD_GATEWAY => return Err(LoginErr::ServerDownErr),
-        StatusCode::INTERNAL_SERVER_ERROR => return Err(LoginErr::ServerDown500Err),
-        _ => {}
+    let page = http.post_form(Operation::LoginPost, &login_url, &params).await.map_err(|e| submit.err(e))?;
+    spans::record_status(page.status);
+    if let Some(err) = server_down_err(page.status, &submit) {
+        return Err(err);
     }
+    let state = LoginState {
+        base_url: base_url.to_owned(),
+        login_url,
+        username: username.to_owned(),
+        auto_solved,
+        context: submit,
+        poll_url: String::new(),
+        hop: 0,
+    };
+    next_step(http, state, page).await
+}
 
-    let mut refresh_header = resp
-        .headers()
-        .get("refresh")
-        .map(|v| v.to_str().unwrap())
-        .unwrap_or("");
-    while refresh_header != "" {
-        let rgx = Regex::new(r#"URL=(.+)"#).unwrap();
-        let refresh_url = format!(
-            "{}{}",
-            base_url,
-            rgx.captures(&refresh_header)
-                .unwrap()
-                .get(1)
-                .unwrap()
-                .as_str()
-        );
-        println!("waitroom enabled, wait 10sec");
-        thread::sleep(Duration::from_secs(10));
-        resp = client.get(refresh_url.clone()).send()?;
-        refresh_header = resp
-            .headers()
-            .get("refresh")
-            .map(|v| v.to_str().unwrap())
-            .unwrap_or("");
-    }
-
-    let mut resp = resp.text()?;
-    if resp.contains(CAPTCHA_USED_ERR) {
-        return Err(LoginErr::CaptchaUsedErr);
-    } else if resp.contains(CAPTCHA_WG_ERR) {
-        return Err(LoginErr::CaptchaWgErr);
-    } else if resp.contains(REG_ERR) {
-        return Err(LoginErr::RegErr);
-    } else if resp.contains(NICKNAME_ERR) {
-        return Err(LoginErr::NicknameErr);
-    } else if resp.contains(KICKED_ERR) {
-        return Err(LoginErr::KickedErr);
-    }
-
-    let mut doc = Document::from(resp.as_str());
-    if let Some(body) = doc.find(Name("body")).next() {
-        if let Some(body_class) = body.attr("class") {
-            if body_class == "error" {
-                if let Some(h2) = doc.find(Name("h2")).next() {
-                    log::error!("{}", h2.text());
-                }
-                return Err(LoginErr::UnknownErr);
-            } else if body_class == "failednotice" {
-                log::error!("failed logins: {}", body.text());
-                let nc = doc.find(Attr("name", "nc")).next().unwrap();
-                let nc_value = nc.attr("value").unwrap().to_owned();
-                let params: Vec<(&str, String)> = vec![
-                    ("lang", LANG.to_owned()),
-                    ("nc", nc_value.to_owned()),
-                    ("action", "login".to_owned()),
-                ];
-                resp = client.post(&login_url).form(&params).send()?.text()?;
-                doc = Document::from(resp.as_str());
-            }
+/// Ask the waitroom again, never sleeping. The next waitroom, or the end
+/// of the login.
+pub fn resume_login(transport: &Transport, state: LoginState) -> Result<LoginOutcome, LoginErr> {
+    exchange::block_on(resume_login_with(transport, state))
+}
+
+/// `resume_login` over any `Exchange`.
+#[tracing::instrument(name = "waitroom", skip_all, fields(mirror = %state.base_url, hop = state.hop + 1, status = Empty, elapsed_ms = Empty))]
+pub async fn resume_login_with<E: Exchange>(http: &E, mut state: LoginState) -> Result<LoginOutcome, LoginErr> {
+    let _timer = spans::timer();
+    state.hop += 1;
+    state.context = LoginContext::new(LoginStage::Waitroom, &state.poll_url);
+    let page = http.get(Operation::LoginPage, &state.poll_url).await.map_err(|e| state.context.err(e))?;
+    spans::record_status(page.status);
+    next_step(http, state, page).await
+}
+
+// The waitroom when `page` sends there, otherwise the end of the login
+async fn next_step<E: Exchange>(http: &E, mut state: LoginState, page: Page) -> Result<LoginOutcome, LoginErr> {
+    match waitroom_refresh(&page.headers) {
+        Some(refresh_path) => {
+            state.poll_url = format!("{}{}", state.base_url, refresh_path);
+            Ok(LoginOutcome::Waitroom { poll_url: state.poll_url.clone(), retry_after: WAITROOM_WAIT, state })
         }
+        None => finish_login(http, state, page).await.map(LoginOutcome::Success),
     }
+}
 
-    let iframe = match doc.find(Attr("name", "view")).next() {
-        Some(view) => view,
-        None => {
-            fs::write("./dump_login_err.html", resp.as_str()).unwrap();
-            return Err(LoginErr::UnknownErr); // Ubah panic menjadi return Err
+// The page after the form or the waitroom: why the login failed, or the
+// session
+async fn finish_login<E: Exchange>(http: &E, state: LoginState, page: Page) -> Result<Session, LoginErr> {
+    let LoginState { base_url, login_url, username, auto_solved, mut context, .. } = state;
+    let data_dir = http.settings().data_dir.as_deref();
+    let mut resp = page.body.map_err(|e| context.err(e))?;
+    let mut response = classify_login_response(&resp).map_err(|e| context.parse_err(e, &resp, data_dir))?;
+    match response {
+        LoginResponse::CaptchaUsed => return Err(LoginErr::CaptchaUsedErr),
+        LoginResponse::CaptchaWrong => {
+            if auto_solved {
+                record_solved(data_dir, false);
+            }
+            return Err(LoginErr::CaptchaWgErr);
         }
-    };
-    let iframe_src = iframe.attr("src").unwrap();
+        _ if auto_solved => record_solved(data_dir, true),
+        _ => {}
+    }
+    if let LoginResponse::FailedNotice { notice, nonce } = response {
+        log::error!("failed logins: {}", notice);
+        let nonce = nonce.ok_or_else(|| context.parse_err(ParseErr::Missing("failed logins nonce"), &resp, data_dir))?;
+        let params: Vec<(&str, String)> = vec![
+            ("lang", http.lang().to_owned()),
+            ("nc", nonce),
+            ("action", "login".to_owned()),
+        ];
+        context = LoginContext::new(LoginStage::CaptchaSubmit, &login_url);
+        let page = http.post_form(Operation::LoginPost, &login_url, &params).await.map_err(|e| context.err(e))?;
+        spans::record_status(page.status);
+        resp = page.body.map_err(|e| context.err(e))?;
+        response = LoginResponse::Chat;
+    }
+    match response {
+        LoginResponse::Registered => return Err(LoginErr::RegErr),
+        LoginResponse::InvalidNick => return Err(LoginErr::NicknameErr),
+        LoginResponse::Kicked => return Err(LoginErr::KickedErr),
+        LoginResponse::Error(message) => {
+            if let Some(message) = message {
+                log::error!("{}", message);
+            }
+            return Err(LoginErr::UnknownErr);
+        }
+        _ => {}
+    }
 
-    let session_captures = SESSION_RGX.captures(iframe_src).unwrap();
-    let session = session_captures.get(1).unwrap().as_str();
-    Ok(session.to_owned())
+    let context = LoginContext { stage: LoginStage::ChatFrame, ..context };
+    let session = extract_session(&resp, &login_url).map_err(|e| context.parse_err(e, &resp, data_dir))?.session;
+    http.settings().mention.set_nick(&username);
+    sent::track(http.settings(), &session, &username);
+    post::track_post_box(http.settings(), &session);
+    Ok(Session { id: session, base_url, feed: Default::default(), room: None, lang: http.lang().to_owned() })
 }
 
 
 pub fn logout(
-    client: &Client,
+    transport: &Transport,
     base_url: &str,
     page_php: &str,
     session: &str,
 ) -> anyhow::Result<()> {
-    let full_url = format!("{}/{}", &base_url, &page_php);
-    let params = [("action", "logout"), ("session", &session), ("lang", LANG)];
-    client.post(&full_url).form(&params).send()?;
+    exchange::block_on(logout_with(transport, base_url, page_php, session))
+}
+
+/// `logout` over any `Exchange`.
+#[tracing::instrument(name = "logout", skip_all, fields(mirror = %base_url, status = Empty, elapsed_ms = Empty))]
+pub async fn logout_with<E: Exchange>(http: &E, base_url: &str, page_php: &str, session: &str) -> anyhow::Result<()> {
+    let _timer = spans::timer();
+    let full_url = page_url(base_url, page_php);
+    let params = [("action", "logout".to_owned()), ("session", session.to_owned()), ("lang", http.lang().to_owned())];
+    let page = http.post_form(Operation::Logout, &full_url, &params).await?;
+    spans::record_status(page.status);
+    sent::untrack(http.settings(), session);
+    post::untrack_post_box(http.settings(), session);
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use captcha_prompt::CaptchaImage;
+    use mock::{MockExchange, MockResponse, MockServer};
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn check_server_test() {
+        let transport = Transport::direct();
+
+        let up = MockServer::start(|_| MockResponse::ok("<form></form>"));
+        let health = check_server(&transport, &up.url, "chat.php");
+        assert!(health.reachable && !health.waitroom_active);
+        assert_eq!(health.status, Some(StatusCode::OK));
+
+        let down = MockServer::start(|_| MockResponse::new(503, "Service Unavailable"));
+        let health = check_server(&transport, &down.url, "chat.php");
+        assert!(!health.reachable);
+        assert_eq!(health.status, Some(StatusCode::SERVICE_UNAVAILABLE));
+        assert_eq!(down.hits(), 1);
+
+        let waitroom = MockServer::start(|_| {
+            let mut resp = MockResponse::ok("");
+            resp.headers.push(("Refresh".to_owned(), "10; URL=/chat.php?action=wait".to_owned()));
+            resp
+        });
+        assert!(check_server(&transport, &waitroom.url, "chat.php").waitroom_active);
+        let waitroom = MockServer::start(|_| MockResponse::ok(r#"<META HTTP-EQUIV="Refresh" content="10">"#));
+        assert!(check_server(&transport, &waitroom.url, "chat.php").waitroom_active);
+
+        // Nothing listens on a port we just released
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let health = check_server(&transport, &format!("http://{}", closed), "chat.php");
+        assert!(!health.reachable);
+        assert_eq!(health.status, None);
+    }
+
+    #[test]
+    fn login_test() {
+        const BASE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+        const FORM: &str = r#"<html><body><form><input name="nick"></form></body></html>"#;
+        const FRAMESET: &str = r#"<html><body><iframe name="view" src="chat.php?action=view&session=s3ss10n&lang=en"></iframe></body></html>"#;
+        // The login form on GET, `post` answers it
+        let chat = |post: fn(&mock::MockRequest) -> Result<MockResponse, retry::SendErr>| {
+            MockExchange::new(move |req| if req.method == "GET" { Ok(MockResponse::ok(FORM)) } else { post(req) })
+        };
+        let login = |http: &MockExchange| exchange::block_on(login_with(http, BASE_URL, "chat.php", "nick", "pass", "", true));
+
+        // What answers, what it must fail with
+        type Case = (MockExchange, fn(&LoginErr) -> bool);
+        let cases: Vec<Case> = vec![
+            (MockExchange::new(|_| Ok(MockResponse::new(502, "Bad Gateway"))), |e| matches!(e, LoginErr::ServerDownErr(c) if c.stage == LoginStage::LoginPage)),
+            (chat(|_| Ok(MockResponse::new(500, ""))), |e| matches!(e, LoginErr::ServerDown500Err(c) if c.stage == LoginStage::CaptchaSubmit)),
+            (chat(|_| Ok(MockResponse::ok(CAPTCHA_USED_ERR))), |e| matches!(e, LoginErr::CaptchaUsedErr)),
+            (chat(|_| Ok(MockResponse::ok(CAPTCHA_WG_ERR))), |e| matches!(e, LoginErr::CaptchaWgErr)),
+            (chat(|_| Ok(MockResponse::ok(REG_ERR))), |e| matches!(e, LoginErr::RegErr)),
+            (chat(|_| Ok(MockResponse::ok(NICKNAME_ERR))), |e| matches!(e, LoginErr::NicknameErr)),
+            (chat(|_| Ok(MockResponse::ok(KICKED_ERR))), |e| matches!(e, LoginErr::KickedErr)),
+            (
+                chat(|_| Ok(MockResponse::ok(r#"<body class="error"><h2>Nope</h2></body>"#))),
+                |e| matches!(e, LoginErr::UnknownErr),
+            ),
+            (
+                MockExchange::new(|_| Err(retry::SendErr::ResponseTooLarge { limit: 1 })),
+                |e| matches!(e, LoginErr::ResponseTooLarge { limit: 1, .. }),
+            ),
+            (
+                chat(|_| Err(retry::SendErr::Body(io::Error::new(io::ErrorKind::UnexpectedEof, "eof")))),
+                |e| matches!(e, LoginErr::Body(c, _) if c.stage == LoginStage::CaptchaSubmit),
+            ),
+            (
+                MockExchange::new(|_| {
+                    Ok(MockResponse::ok(r#"<meta http-equiv="refresh" content="0"><script>document.cookie="a=b"</script>"#))
+                }),
+                |e| matches!(e, LoginErr::InterstitialBlocked { .. }),
+            ),
+        ];
+        for (i, (http, expected)) in cases.iter().enumerate() {
+            let err = login(http).unwrap_err();
+            assert!(expected(&err), "case {}: unexpected {:?}", i, err);
+        }
+        let http = chat(|_| Ok(MockResponse::ok(FRAMESET)));
+        let err = exchange::block_on(login_with(&http, "http://nope.onion", "chat.php", "nick", "pass", "", true));
+        assert!(matches!(err, Err(LoginErr::InvalidUrl(_))));
+        assert!(http.requests.borrow().is_empty());
+
+        // Through the waitroom and the failed logins notice
+        let http = MockExchange::new(|req| {
+            Ok(match (req.method.as_str(), req.path.as_str()) {
+                ("GET", "/chat.php") => MockResponse::ok(FORM),
+                ("POST", _) if req.body.contains("nc=") => MockResponse::ok(FRAMESET),
+                ("POST", _) => MockResponse::ok("").with_header("Refresh", "10; URL=/chat.php?action=wait"),
+                _ => MockResponse::ok(r#"<body class="failednotice">3 failed logins<input name="nc" value="42"></body>"#),
+            })
+        });
+        assert_eq!(login(&http).unwrap(), "s3ss10n");
+        assert_eq!(http.slept.get(), Duration::from_secs(10));
+        let requests = http.requests.borrow();
+        assert_eq!(requests.len(), 4);
+        assert!(requests[1].body.contains("action=login&lang=en&nick=nick&pass=pass"));
+        assert_eq!(requests[2].path, "/chat.php?action=wait");
+        assert_eq!(requests[3].body, "lang=en&nc=42&action=login");
+    }
+
+    // A le-chat-php 1.24 login as served, the session and the challenge
+    // scrubbed. The captcha is a 60x24 PNG.
+    const LOGIN_PAGE: &str = include_str!("fixtures/login.html");
+    const FRAMESET: &str = include_str!("fixtures/login_frameset.html");
+    const SESSION: &str = "0123456789abcdef0123456789abcdef";
+
+    fn ok(page: &str) -> Result<MockResponse, retry::SendErr> {
+        Ok(MockResponse::ok(page))
+    }
+
+    // The waitroom page sending to its `hop`th refresh
+    fn waitroom(hop: u32) -> Result<MockResponse, retry::SendErr> {
+        let refresh = format!("10; URL=/chat.php?action=wait&session={}&lang=en&hop={}", SESSION, hop);
+        Ok(MockResponse::ok(include_str!("fixtures/login_waitroom.html")).with_header("Refresh", &refresh))
+    }
+
+    #[test]
+    fn login_fixtures_test() {
+        const BASE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+        let login = |answers| {
+            let http = MockExchange::script(answers);
+            http.settings.prompt.set(Arc::new(size_prompt));
+            (exchange::block_on(login_with(&http, BASE_URL, "chat.php", "nick", "pass", "", true)), http)
+        };
+        let failednotice = include_str!("fixtures/login_failednotice.html");
+
+        // The captcha to the prompt and its answer back, then the session
+        let (session, http) = login(vec![ok(LOGIN_PAGE), ok(FRAMESET)]);
+        assert_eq!(session.unwrap(), SESSION);
+        let body = &http.requests.borrow()[1].body;
+        assert_eq!(body, "action=login&lang=en&nick=nick&pass=pass&colour=&challenge=5e1b4a90f7c2d3e8&captcha=60x24");
+
+        // Two hops through the waitroom, then the failed logins notice
+        let (session, http) = login(vec![ok(LOGIN_PAGE), waitroom(1), waitroom(2), ok(failednotice), ok(FRAMESET)]);
+        assert_eq!(session.unwrap(), SESSION);
+        assert_eq!(http.slept.get(), WAITROOM_WAIT * 2);
+        let requests = http.requests.borrow();
+        let asked = requests.iter().map(|r| format!("{} {}", r.method, r.path)).collect::<Vec<_>>();
+        let wait = |hop| format!("GET /chat.php?action=wait&session={}&lang=en&hop={}", SESSION, hop);
+        assert_eq!(asked, ["GET /chat.php".to_owned(), "POST /chat.php".to_owned(), wait(1), wait(2), "POST /chat.php".to_owned()]);
+        assert_eq!(requests[4].body, "lang=en&nc=718204&action=login");
+
+        // "You have been kicked" said in the chat is no kick
+        let (session, _) = login(vec![ok(LOGIN_PAGE), ok(include_str!("fixtures/login_kicked_message.html"))]);
+        assert_eq!(session.unwrap(), SESSION);
+
+        // Every way it fails, with what answers up to the failure
+        let after_login = |page| vec![ok(LOGIN_PAGE), ok(page)];
+        let eof = || retry::SendErr::Body(io::Error::new(io::ErrorKind::UnexpectedEof, "eof"));
+        let mismatch = tls::PinMismatch { host: "chat.onion".to_owned(), expected: "sha256/AAAA".to_owned(), got: None };
+        let clearnet = onion::UrlErr::Clearnet("http://example.com".to_owned());
+        let interstitial = (0..10).map(|_| ok(include_str!("fixtures/interstitial_cookie.html"))).collect();
+        type Case = (Vec<Result<MockResponse, retry::SendErr>>, fn(&LoginErr) -> bool);
+        let cases: Vec<Case> = vec![
+            (vec![Ok(MockResponse::new(500, ""))], |e| matches!(e, LoginErr::ServerDown500Err(c) if c.stage == LoginStage::LoginPage)),
+            (vec![ok(LOGIN_PAGE), Ok(MockResponse::new(502, ""))], |e| matches!(e, LoginErr::ServerDownErr(c) if c.stage == LoginStage::CaptchaSubmit)),
+            (after_login(include_str!("fixtures/login_captcha_used.html")), |e| matches!(e, LoginErr::CaptchaUsedErr)),
+            (after_login(include_str!("fixtures/login_wrong_captcha.html")), |e| matches!(e, LoginErr::CaptchaWgErr)),
+            (after_login(include_str!("fixtures/login_registered.html")), |e| matches!(e, LoginErr::RegErr)),
+            (after_login(include_str!("fixtures/login_invalid_nick.html")), |e| matches!(e, LoginErr::NicknameErr)),
+            (after_login(include_str!("fixtures/login_kicked.html")), |e| matches!(e, LoginErr::KickedErr)),
+            (after_login(include_str!("fixtures/login_error.html")), |e| matches!(e, LoginErr::UnknownErr)),
+            // The waitroom page without its refresh header
+            (after_login(include_str!("fixtures/login_waitroom.html")), |e| matches!(e, LoginErr::NoChatFrame(_))),
+            (after_login(&FRAMESET.replace(SESSION, "")), |e| matches!(e, LoginErr::SessionNotFound { .. })),
+            (
+                after_login(include_str!("fixtures/login_truncated.html")),
+                |e| matches!(e, LoginErr::TruncatedResponse(c) if c.stage == LoginStage::CaptchaSubmit) && e.kind() == ErrorKind::Transient,
+            ),
+            (
+                vec![ok(LOGIN_PAGE), Err(retry::SendErr::Truncated { expected: 100, got: 12 })],
+                |e| matches!(e, LoginErr::TruncatedResponse(c) if c.stage == LoginStage::CaptchaSubmit),
+            ),
+            (
+                vec![ok(&LOGIN_PAGE.replace("data:image/png", "data:image/webp"))],
+                |e| matches!(e, LoginErr::MalformedResponse { missing: "PNG or GIF captcha image", .. }),
+            ),
+            (vec![Err(retry::SendErr::Clearnet(clearnet))], |e| matches!(e, LoginErr::InvalidUrl(_))),
+            (
+                vec![ok(LOGIN_PAGE), ok(failednotice), Err(retry::SendErr::PinMismatch(mismatch))],
+                |e| matches!(e, LoginErr::PinMismatch(c, _) if c.stage == LoginStage::CaptchaSubmit),
+            ),
+            (vec![Err(retry::SendErr::ResponseTooLarge { limit: 1 })], |e| matches!(e, LoginErr::ResponseTooLarge { limit: 1, .. })),
+            (interstitial, |e| matches!(e, LoginErr::InterstitialBlocked { passes: 3, .. })),
+            (vec![ok(LOGIN_PAGE), waitroom(1), Err(eof())], |e| matches!(e, LoginErr::Body(c, _) if c.stage == LoginStage::Waitroom)),
+        ];
+        for (i, (answers, expected)) in cases.into_iter().enumerate() {
+            let err = login(answers).0.unwrap_err();
+            assert!(expected(&err), "case {}: unexpected {:?}", i, err);
+        }
+    }
+
+    #[test]
+    fn login_over_http_test() {
+        use client::{ClientConfig, ProxySetting};
+        let transport = |proxy| {
+            let timeout = Duration::from_millis(300);
+            let config = ClientConfig { proxy, allow_clearnet: Some(true), rate_limit: None, connect_timeout: timeout, read_timeout: timeout, ..Default::default() };
+            let transport = client::build(&config).unwrap();
+            transport.set_prompt(size_prompt);
+            transport
+        };
+        let login = |transport: &Transport, base_url: &str| super::login(transport, base_url, "chat.php", "nick", "pass", "", true);
+
+        // The same pages through a socket
+        let chat = MockServer::start(|req| MockResponse::ok(if req.method == "POST" { FRAMESET } else { LOGIN_PAGE }));
+        let direct = transport(ProxySetting::Direct);
+        assert_eq!(login(&direct, &chat.url).unwrap(), SESSION);
+        assert_eq!(chat.hits(), 2);
+
+        // The errors only a connection has
+        let slow = MockServer::start(|_| {
+            std::thread::sleep(Duration::from_secs(1));
+            MockResponse::ok(LOGIN_PAGE)
+        });
+        assert!(matches!(login(&direct, &slow.url), Err(LoginErr::ReadTimeout(..))));
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let proxy_down = transport(ProxySetting::Url(format!("socks5h://{}", closed)));
+        assert!(matches!(login(&proxy_down, &chat.url), Err(LoginErr::ProxyDown(..))));
+        let unreachable = transport(ProxySetting::Url(mock::socks_proxy(4)));
+        assert!(matches!(login(&unreachable, &chat.url), Err(LoginErr::CircuitFailed(..))));
+        assert_eq!(chat.hits(), 2);
+    }
+
+    #[test]
+    fn login_err_test() {
+        use std::error::Error;
+        const BASE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+        let login = |http: &MockExchange| exchange::block_on(login_with(http, BASE_URL, "chat.php", "nick", "pass", "", true)).unwrap_err();
+        let eof = || retry::SendErr::Body(io::Error::new(io::ErrorKind::UnexpectedEof, "eof"));
+
+        // The stage and the URL after the message of before, the cause as source
+        let err = login(&MockExchange::new(move |req| if req.method == "GET" { Ok(MockResponse::ok("<form></form>")) } else { Err(eof()) }));
+        let url = format!("{}/chat.php", BASE_URL);
+        assert_eq!(err.to_string(), format!("error reading response: eof (submitting the login form {})", url));
+        assert_eq!(err.context(), Some(&LoginContext { stage: LoginStage::CaptchaSubmit, url: url.clone() }));
+        assert_eq!(err.source().unwrap().to_string(), "eof");
+        assert!(err.source().unwrap().downcast_ref::<io::Error>().is_some());
+
+        let err = login(&MockExchange::new(move |req| match req.method.as_str() {
+            "POST" => Ok(MockResponse::ok("").with_header("Refresh", "10; URL=/chat.php?action=wait")),
+            _ if req.path.contains("action=wait") => Err(eof()),
+            _ => Ok(MockResponse::ok("<form></form>")),
+        }));
+        assert_eq!(err.to_string(), format!("error reading response: eof (waiting in the waitroom {}?action=wait)", url));
+
+        let err = login(&MockExchange::new(|_| Ok(MockResponse::new(502, "Bad Gateway"))));
+        assert_eq!(err.to_string(), format!("{} (fetching the login page {})", SERVER_DOWN_ERR, url));
+        assert!(err.source().is_none());
+
+        let err = login(&MockExchange::new(|req| match req.method.as_str() {
+            "POST" => Ok(MockResponse::ok(r#"<html><body><iframe name="view" src="chat.php?action=view"></iframe></body></html>"#)),
+            _ => Ok(MockResponse::ok("<form></form>")),
+        }));
+        assert!(matches!(&err, LoginErr::SessionNotFound { context, .. } if context.stage == LoginStage::ChatFrame));
+        assert_eq!(err.to_string(), format!("no session in the chat frame (reading the chat frame {})", url));
+
+        // Down to reqwest's own error, for anyhow's chain
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let base_url = format!("http://{}", closed);
+        let err = super::login(&Transport::direct(), &base_url, "chat.php", "nick", "pass", "", true).unwrap_err();
+        assert!(err.to_string().ends_with(&format!("(fetching the login page {}/chat.php)", base_url)), "{}", err);
+        assert!(err.source().unwrap().downcast_ref::<reqwest::Error>().is_some());
+        assert!(anyhow::Error::from(err).chain().count() > 2);
+
+        // The waitroom's url has the session, the error only the context's redacted one
+        let context = LoginContext::new(LoginStage::Waitroom, &format!("{}/chat.php?action=wait&session=s3cr3t", base_url));
+        let err = context.err(retry::SendErr::from(reqwest::blocking::get(&context.url).unwrap_err()));
+        assert!(err.to_string().ends_with(&format!("(waiting in the waitroom {}/chat.php?action=wait&session=***)", base_url)), "{}", err);
+        assert!(anyhow::Error::from(err).chain().all(|e| !e.to_string().contains("s3cr3t")));
+
+        // Unchanged where the stage goes without saying
+        assert_eq!(LoginErr::CaptchaWgErr.to_string(), CAPTCHA_WG_ERR);
+        assert!(LoginErr::CaptchaWgErr.context().is_none());
+        let err = post::PostErr::from(eof());
+        assert_eq!(err.to_string(), "error reading response body: eof");
+        assert_eq!(err.source().unwrap().source().unwrap().to_string(), "eof");
+    }
+
+    #[test]
+    fn malformed_login_test() {
+        const BASE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+        let login = |page: &'static str, after: MockResponse| {
+            let http = MockExchange::new(move |req| Ok(if req.method == "GET" { MockResponse::ok(page) } else { after.clone() }));
+            exchange::block_on(login_with(&http, BASE_URL, "chat.php", "nick", "pass", "", true))
+        };
+        let frame = || MockResponse::ok(r#"<html><body><iframe name="view" src="chat.php?action=view&session=abc"></iframe></body></html>"#);
+        let missing = |err: LoginErr| match err {
+            LoginErr::MalformedResponse { missing, .. } => missing,
+            other => panic!("unexpected {:?}", other),
+        };
+
+        // The login page, each part of the captcha broken in turn
+        let captcha_err = |page| missing(login(page, frame()).unwrap_err());
+        assert_eq!(captcha_err(r#"<form><input name="challenge"><img src="data:image/png;base64,AA=="></form>"#), "captcha challenge value");
+        assert_eq!(captcha_err(r#"<form><input name="challenge" value="1"></form>"#), "captcha image");
+        assert_eq!(captcha_err(r#"<form><input name="challenge" value="1"><img alt="captcha"></form>"#), "captcha image");
+        assert_eq!(captcha_err(r#"<form><input name="challenge" value="1"><img src="/captcha.png"></form>"#), "PNG or GIF captcha image");
+        assert_eq!(captcha_err(r#"<form><input name="challenge" value="1"><img src="data:image/png;base64,!!!"></form>"#), "base64 captcha image");
+        assert_eq!(captcha_err(r#"<form><input name="challenge" value="1"><img src="data:image/gif;base64,bm90IGFuIGltYWdl"></form>"#), "decodable captcha image");
+        let err = login(r#"<form><input name="challenge"></form>"#, frame()).unwrap_err();
+        assert_eq!(err.context().unwrap().stage, LoginStage::LoginPage);
+        assert!(err.to_string().starts_with("malformed response, no captcha challenge value (fetching the login page"), "{}", err);
+
+        // The failed logins notice without its nonce
+        let notice = |page: &'static str| missing(login("<form></form>", MockResponse::ok(page)).unwrap_err());
+        assert_eq!(notice(r#"<html><body class="failednotice">3 failed logins</body></html>"#), "failed logins nonce");
+        assert_eq!(notice(r#"<html><body class="failednotice"><input name="nc"></body></html>"#), "failed logins nonce");
+
+        // A refresh header we can't read is no waitroom
+        let refresh = frame().with_header("Refresh", "10; URL=/chat.php?\u{e9}");
+        assert_eq!(login("<form></form>", refresh).unwrap(), "abc");
+        let refresh = MockResponse::ok("").with_header("Refresh", "soon");
+        assert!(matches!(login("<form></form>", refresh), Err(LoginErr::NoChatFrame(_))));
+
+        // The chat frame without a src, or without a session in it
+        let no_src = MockResponse::ok(r#"<html><body><iframe name="view"></iframe></body></html>"#);
+        assert!(matches!(login("<form></form>", no_src), Err(LoginErr::SessionNotFound { .. })));
+        let no_session = MockResponse::ok(r#"<html><body><iframe name="view" src="chat.php?session="></iframe></body></html>"#);
+        assert!(matches!(login("<form></form>", no_session), Err(LoginErr::SessionNotFound { .. })));
+    }
+
+    #[test]
+    fn session_from_src_test() {
+        let page = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion/chat.php";
+        let session = |src| login_page::session_from_src(page, src);
+        assert_eq!(session("chat.php?action=view&session=abc&lang=en").as_deref(), Some("abc"));
+        assert_eq!(session("chat.php?action=view#session=abc").as_deref(), Some("abc"));
+        // Only found once decoded
+        assert_eq!(session("chat.php?action=view&%73ession=abc").as_deref(), Some("abc"));
+        assert_eq!(session("/chat.php#action=view&%73ession=abc").as_deref(), Some("abc"));
+        assert_eq!(session("chat.php?action=view"), None);
+        assert_eq!(session("chat.php?session=&action=view"), None);
+        assert_eq!(session("chat.php#%73ession="), None);
+
+        // The page is left in the data dir for a report upstream
+        let frame = r#"<html><body><iframe name="view" src="chat.php?action=view#sid=abc"></iframe></body></html>"#;
+        let http = MockExchange::new(move |req| Ok(MockResponse::ok(if req.method == "GET" { "<form></form>" } else { frame })));
+        let err = exchange::block_on(login_with(&http, "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion", "chat.php", "nick", "pass", "", true)).unwrap_err();
+        assert!(matches!(&err, LoginErr::SessionNotFound { .. }), "{:?}", err);
+        let dir = std::env::temp_dir().join(format!("bhcli-dump-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let dump = dump_page(Some(&dir), frame).unwrap();
+        assert_eq!(dump, dir.join(DUMP_FILE));
+        assert!(fs::read_to_string(&dump).unwrap().contains("sid=abc"));
+        assert_eq!(dump_page(None, frame), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // A login form with a blank 3x2 captcha the solver can't read
+    fn captcha_form() -> String {
+        const BLANK_PNG: &str = "iVBORw0KGgoAAAANSUhEUgAAAAMAAAACCAAAAAC4HznGAAAAC0lEQVR42mNggAAAAAgAAST8BHIAAAAASUVORK5CYII=";
+        format!(r#"<form><input name="challenge" value="c1"><img src="data:image/png;base64,{}"></form>"#, BLANK_PNG)
+    }
+
+    // Answers with the captcha's size, the same for every test setting it
+    fn size_prompt(img: &CaptchaImage) -> io::Result<String> {
+        let (width, height) = img.dimensions().unwrap();
+        Ok(format!("{}x{}", width, height))
+    }
+
+    #[test]
+    fn captcha_prompt_test() {
+        const BASE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+        let form = captcha_form();
+        let http = MockExchange::new(move |req| {
+            Ok(MockResponse::ok(if req.method == "GET" {
+                &form
+            } else {
+                r#"<html><body><iframe name="view" src="chat.php?action=view&session=pr0mpt"></iframe></body></html>"#
+            }))
+        });
+
+        // The library asks whoever is set, never the terminal
+        http.settings.prompt.set(Arc::new(size_prompt));
+        let session = exchange::block_on(login_with(&http, BASE_URL, "chat.php", "nick", "pass", "", true)).unwrap();
+        assert_eq!(session, "pr0mpt");
+        let requests = http.requests.borrow();
+        assert!(requests[1].body.contains("challenge=c1&captcha=3x2"), "{}", requests[1].body);
+    }
+
+    #[test]
+    fn quiet_login_test() {
+        const BASE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+        // Run again alone and uncaptured, to see what reaches the real stdout
+        if std::env::var_os("BHCLI_QUIET_LOGIN").is_none() {
+            let out = std::process::Command::new(std::env::current_exe().unwrap())
+                .args(["--exact", "lechatphp::tests::quiet_login_test", "--nocapture", "--test-threads=1"])
+                .env("BHCLI_QUIET_LOGIN", "1")
+                .output()
+                .unwrap();
+            let stdout = String::from_utf8_lossy(&out.stdout);
+            assert!(out.status.success(), "{}", stdout);
+            assert!(stdout.contains("[login]\n[done]\n"), "{}", stdout);
+            return;
+        }
+
+        // The waitroom, the solver, then the prompt
+        let form = captcha_form();
+        let http = MockExchange::new(move |req| match req.method.as_str() {
+            "POST" => Ok(MockResponse::ok("").with_header("Refresh", "10; URL=/chat.php?action=wait")),
+            _ if req.path.contains("action=wait") => {
+                Ok(MockResponse::ok(r#"<html><body><iframe name="view" src="chat.php?action=view&session=qu13t"></iframe></body></html>"#))
+            }
+            _ => Ok(MockResponse::ok(&form)),
+        });
+        let waits = std::sync::Arc::new(Mutex::new(vec![]));
+        let seen = std::sync::Arc::clone(&waits);
+        http.settings.progress.set(Arc::new(move |progress: &LoginProgress| seen.lock().unwrap().push(progress.clone())));
+        http.settings.prompt.set(Arc::new(|_: &CaptchaImage| Ok("answer".to_owned())));
+        println!("[login]");
+        let session = exchange::block_on(login_with(&http, BASE_URL, "chat.php", "nick", "pass", "", false));
+        println!("[done]");
+        assert_eq!(session.unwrap(), "qu13t");
+        assert_eq!(*waits.lock().unwrap(), [LoginProgress::Waitroom { wait: WAITROOM_WAIT }]);
+    }
+
+    #[test]
+    fn resumable_login_test() {
+        const BASE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+
+        // Two hops through the waitroom, each handed back instead of slept
+        let http = MockExchange::script(vec![ok(LOGIN_PAGE), waitroom(1), waitroom(2), ok(FRAMESET)]);
+        http.settings.prompt.set(Arc::new(size_prompt));
+        let mut outcome = exchange::block_on(start_login_with(&http, BASE_URL, "chat.php", "nick", "pass", "", true)).unwrap();
+        for hop in 1..=2 {
+            let LoginOutcome::Waitroom { poll_url, retry_after, state } = outcome else { panic!("{:?}", outcome) };
+            assert_eq!(poll_url, format!("{}/chat.php?action=wait&session={}&lang=en&hop={}", BASE_URL, SESSION, hop));
+            assert_eq!(retry_after, WAITROOM_WAIT);
+            outcome = exchange::block_on(resume_login_with(&http, state)).unwrap();
+            assert!(!format!("{:?}", outcome).contains(SESSION), "{:?}", outcome);
+        }
+        let LoginOutcome::Success(session) = outcome else { panic!("{:?}", outcome) };
+        assert_eq!((session.id.as_str(), session.base_url.as_str()), (SESSION, BASE_URL));
+        assert_eq!(http.slept.get(), Duration::ZERO);
+        assert_eq!(http.requests.borrow().len(), 4);
+
+        // The page after a hop is read like the one after the form
+        let http = MockExchange::script(vec![ok(LOGIN_PAGE), waitroom(1), ok(include_str!("fixtures/login_wrong_captcha.html"))]);
+        http.settings.prompt.set(Arc::new(size_prompt));
+        let outcome = exchange::block_on(start_login_with(&http, BASE_URL, "chat.php", "nick", "pass", "", true)).unwrap();
+        let LoginOutcome::Waitroom { state, .. } = outcome else { panic!("{:?}", outcome) };
+        assert!(matches!(exchange::block_on(resume_login_with(&http, state)), Err(LoginErr::CaptchaWgErr)));
+    }
+
+    #[test]
+    fn spans_test() {
+        const BASE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+        let recorder = mock::SpanRecorder::default();
+        let http = MockExchange::script(vec![ok(LOGIN_PAGE), waitroom(1), waitroom(2), ok(FRAMESET), ok("")]);
+        http.settings.prompt.set(Arc::new(size_prompt));
+        tracing::subscriber::with_default(recorder.clone(), || {
+            let session = exchange::block_on(login_with(&http, BASE_URL, "chat.php", "nick", "s3cr3t", "", true)).unwrap();
+            exchange::block_on(logout_with(&http, BASE_URL, "chat.php", &session)).unwrap();
+        });
+
+        let spans = recorder.spans.lock().unwrap();
+        let names = spans.iter().map(|s| s.name).collect::<Vec<_>>();
+        assert_eq!(names, ["login", "login_form", "captcha", "waitroom", "waitroom", "logout"]);
+        // The whole login is one span, its steps under it
+        let parents = spans.iter().map(|s| s.parent).collect::<Vec<_>>();
+        assert_eq!(parents, [None, Some(0), Some(1), Some(0), Some(0), None]);
+        let field = |i: usize, name| spans[i].fields.get(name).map(String::as_str);
+        assert_eq!((field(0, "mirror"), field(1, "mirror")), (Some(BASE_URL), Some(BASE_URL)));
+        assert_eq!(field(2, "answered_by"), Some("prompt"));
+        assert_eq!((field(3, "hop"), field(4, "hop")), (Some("1"), Some("2")));
+        assert_eq!(field(5, "mirror"), Some(BASE_URL));
+        for i in 0..spans.len() {
+            assert!(field(i, "elapsed_ms").is_some(), "{:?}", spans[i]);
+            assert!(i == 0 || i == 2 || field(i, "status") == Some("200"), "{:?}", spans[i]);
+        }
+        // Nothing of the credentials, the captcha answer or the session
+        let login = &http.requests.borrow()[1].body;
+        let answer = login.rsplit("captcha=").next().unwrap();
+        for value in spans.iter().flat_map(|s| s.fields.values()) {
+            assert!(!value.contains("s3cr3t") && !value.contains(SESSION) && value != answer, "{}", value);
+        }
+    }
+}
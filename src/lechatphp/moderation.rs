@@ -0,0 +1,1110 @@
+use super::error_kind::{Classify, ErrorKind};
+use super::exchange::{self, Exchange};
+use super::filter::Filter;
+use super::messages::{fetch_page, fetch_view, read_page, view_url, FetchErr};
+use super::metrics::Operation;
+use super::page_url;
+use super::sent;
+use super::timestamp;
+use super::transport::Transport;
+use chrono::{DateTime, FixedOffset};
+use lazy_static::lazy_static;
+use regex::Regex;
+use select::document::Document;
+use select::node::Node;
+use select::predicate::{Attr, Name};
+use std::net::IpAddr;
+use std::time::Duration;
+use thiserror::Error;
+
+lazy_static! {
+    static ref REFUSED_RGX: Regex =
+        Regex::new(r"(?i)(can ?not|can't|cannot|not allowed to|may not) (kick|log ?out|clean|delete|ban|unban)|higher (rank|role|status)").unwrap();
+    static ref UNKNOWN_RGX: Regex =
+        Regex::new(r"(?i)no such (user|nick|chatter)|unknown (user|nick)|(user|nick|chatter) not found|is not online").unwrap();
+    static ref INVALID_RGX: Regex = Regex::new(r"(?i)invalid (ip|address|nick|nickname|name|target)|malformed").unwrap();
+    static ref NOBODY_WAITING_RGX: Regex = Regex::new(r"(?i)no more entries|nobody (is )?waiting|waiting room is empty").unwrap();
+    /// The field a confirmation page adds to the form it sends back.
+    static ref CONFIRM_FIELD_RGX: Regex = Regex::new(r"(?i)^(confirm\w*|sure|really)$").unwrap();
+}
+
+/// The waiting room's checkboxes, one per applicant.
+const APPLICANT_FIELD: &str = "alls[]";
+/// The ban list's checkboxes, one per ban.
+const BAN_FIELD: &str = "unban[]";
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ModErr {
+    #[error("{0}")]
+    Fetch(#[from] FetchErr),
+    /// The session has no admin functions, or not this one.
+    #[error("not a staff session")]
+    NotStaff,
+    /// The nick has the same or a higher role.
+    #[error("not allowed to moderate {nick}")]
+    Refused { nick: String },
+    /// Not in the chat, or for applicants not waiting anymore.
+    #[error("no such user {nick}")]
+    NoSuchUser { nick: String },
+    #[error("no such room {room}")]
+    NoSuchRoom { room: String },
+    /// The form has no such option.
+    #[error("no {0} option in the form")]
+    Unsupported(&'static str),
+    /// Messages of the nick are still in the view.
+    #[error("messages still shown after cleaning")]
+    NotCleaned,
+    /// Not a nick or an IP address the chat could ban.
+    #[error("cannot ban {target:?}")]
+    InvalidTarget { target: String },
+    /// The ban list after doesn't show the change.
+    #[error("the ban list doesn't show the change for {target}")]
+    BanNotApplied { target: String },
+    /// The server asks "Are you sure?" first, `description` is what the
+    /// page says. Done again with `confirm` to go on.
+    #[error("confirmation required: {description}")]
+    ConfirmationRequired { description: String },
+    /// Asked to confirm again after confirming, nothing more is sent.
+    #[error("the server asked to confirm again")]
+    ConfirmationLoop,
+}
+
+impl Classify for ModErr {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            ModErr::Fetch(e) => e.kind(),
+            ModErr::NotStaff | ModErr::Refused { .. } => ErrorKind::PermissionDenied,
+            // Confirming is a new call with `confirm`, not the same one again
+            ModErr::NoSuchUser { .. }
+            | ModErr::NoSuchRoom { .. }
+            | ModErr::InvalidTarget { .. }
+            | ModErr::ConfirmationRequired { .. } => ErrorKind::InvalidInput,
+            ModErr::Unsupported(_) | ModErr::NotCleaned | ModErr::BanNotApplied { .. } | ModErr::ConfirmationLoop => {
+                ErrorKind::Fatal
+            }
+        }
+    }
+}
+
+/// The admin functions of the web UI this module submits, by their `do`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Action {
+    Kick,
+    Logout,
+    Clean,
+    /// The waiting room's allow or deny.
+    Approve,
+    /// A word filter's form, one per filter.
+    Filter,
+    Ban,
+    /// The ban list's form, there when someone is banned.
+    Unban,
+    Topic,
+}
+
+impl Action {
+    fn name(self) -> &'static str {
+        match self {
+            Action::Kick => "kick",
+            Action::Logout => "logout",
+            Action::Clean => "clean",
+            Action::Approve => "approve",
+            Action::Filter => "filter",
+            Action::Ban => "ban",
+            Action::Unban => "unban",
+            Action::Topic => "topic",
+        }
+    }
+
+    // The query of the page with the form, the waiting room and the
+    // filters have their own
+    fn page(self, session: &str, lang: &str) -> String {
+        let page = match self {
+            Action::Approve => "&do=approve",
+            Action::Filter => "&do=filter",
+            Action::Ban | Action::Unban => "&do=ban",
+            _ => "",
+        };
+        format!("?action=admin{}&session={}&lang={}", page, session, lang)
+    }
+}
+
+/// Someone in the waiting room of a members-only chat.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Applicant {
+    pub nick: String,
+    /// When the page shows it.
+    pub waiting_since: Option<DateTime<FixedOffset>>,
+    /// The other columns as shown, like the browser's user agent.
+    pub info: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BanKind {
+    Nick,
+    /// An address, or a range like `10.0.0.*` or `10.0.0.0/24`.
+    Ip,
+}
+
+impl BanKind {
+    fn name(self) -> &'static str {
+        match self {
+            BanKind::Nick => "nick",
+            BanKind::Ip => "ip",
+        }
+    }
+}
+
+/// A ban of the chat's ban list.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ban {
+    pub target: String,
+    pub kind: BanKind,
+    /// Who banned, when the list shows it.
+    pub by: Option<String>,
+    pub reason: Option<String>,
+    /// As shown, `None` for a ban without end.
+    pub expires: Option<String>,
+    /// The columns of the fork not above, by their heading.
+    pub extra: Vec<(String, String)>,
+}
+
+/// A ban for `add_ban`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewBan {
+    pub target: String,
+    pub kind: BanKind,
+    /// One of the durations the form offers, in minutes. `None` leaves it
+    /// to the form, often for good.
+    pub duration: Option<Duration>,
+    pub reason: Option<String>,
+}
+
+/// Whose messages `clean_messages` deletes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CleanTarget {
+    Nick(String),
+    /// Every room of the chat, or the only one.
+    All,
+    /// One room, on forks with several.
+    Room(String),
+}
+
+impl CleanTarget {
+    // The `what` and the fields going with it, as the clean form offers
+    fn fields(&self, form: &Node) -> Result<Vec<(&'static str, String)>, ModErr> {
+        let whats = choices(form, "what").unwrap_or_default();
+        let offered = |what: &str| whats.iter().any(|w| w == what);
+        let rooms = choices(form, "room");
+        match self {
+            CleanTarget::Nick(nick) => {
+                if !offered("nick") {
+                    return Err(ModErr::Unsupported("nick"));
+                }
+                // Forks listing the nicks to choose from only list those with messages
+                if choices(form, "nickname").is_some_and(|nicks| !nicks.contains(nick)) {
+                    return Err(ModErr::NoSuchUser { nick: nick.clone() });
+                }
+                Ok(vec![("what", "nick".to_owned()), ("nickname", nick.clone())])
+            }
+            CleanTarget::All if offered("allrooms") => Ok(vec![("what", "allrooms".to_owned())]),
+            CleanTarget::All if offered("room") && rooms.is_none() => Ok(vec![("what", "room".to_owned())]),
+            CleanTarget::All => Err(ModErr::Unsupported("all")),
+            CleanTarget::Room(room) => {
+                let Some(rooms) = rooms.filter(|_| offered("room")) else {
+                    return Err(ModErr::Unsupported("room"));
+                };
+                if !rooms.contains(room) {
+                    return Err(ModErr::NoSuchRoom { room: room.clone() });
+                }
+                Ok(vec![("what", "room".to_owned()), ("room", room.clone())])
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            CleanTarget::Nick(name) | CleanTarget::Room(name) => name,
+            CleanTarget::All => "",
+        }
+    }
+}
+
+fn has_hidden(form: &Node, name: &str, value: &str) -> bool {
+    form.find(Attr("name", name)).any(|i| i.attr("value") == Some(value))
+}
+
+// The admin form for `action`, `None` when the page doesn't offer it
+fn admin_form(doc: &Document, action: Action) -> Option<Node<'_>> {
+    doc.find(Name("form")).find(|f| has_hidden(f, "action", "admin") && has_hidden(f, "do", action.name()))
+}
+
+// The form of an "Are you sure?" page, the one sent with `action` again
+// and a field to confirm
+fn confirmation_form(doc: &Document, action: Action) -> Option<Node<'_>> {
+    doc.find(Name("form")).find(|f| {
+        has_hidden(f, "action", "admin")
+            && has_hidden(f, "do", action.name())
+            && f.find(Name("input")).any(|i| i.attr("name").is_some_and(|n| CONFIRM_FIELD_RGX.is_match(n)))
+    })
+}
+
+// What the confirmation form sends: its fields and the confirm button or
+// box, not the one going back
+fn confirmation_fields<'a>(form: &Node<'a>) -> Vec<(&'a str, String)> {
+    form.find(Name("input"))
+        .filter_map(|i| {
+            let name = i.attr("name")?;
+            let confirms = CONFIRM_FIELD_RGX.is_match(name);
+            match i.attr("type").unwrap_or("text").to_ascii_lowercase().as_str() {
+                "submit" | "button" | "image" | "checkbox" | "radio" if !confirms => None,
+                "checkbox" | "radio" => Some((name, i.attr("value").unwrap_or("on").to_owned())),
+                _ => Some((name, i.attr("value").unwrap_or(if confirms { "yes" } else { "" }).to_owned())),
+            }
+        })
+        .collect()
+}
+
+// What the page says besides its forms
+fn description(doc: &Document) -> String {
+    let body = doc.find(Name("body")).next();
+    let texts: Vec<_> = body.into_iter().flat_map(|b| b.children()).filter(|n| n.name() != Some("form")).map(|n| n.text()).collect();
+    texts.join(" ").split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn body_text(doc: &Document) -> String {
+    doc.find(Name("body")).next().map(|b| b.text()).unwrap_or_default()
+}
+
+// The waiting room has no form when nobody waits
+fn nobody_waiting(doc: &Document) -> bool {
+    admin_form(doc, Action::Approve).is_none() && NOBODY_WAITING_RGX.is_match(&body_text(doc))
+}
+
+// The ban list has no form when nobody is banned, the ban form is there
+fn nobody_banned(doc: &Document) -> bool {
+    admin_form(doc, Action::Unban).is_none() && admin_form(doc, Action::Ban).is_some()
+}
+
+// An address, a prefix of one or a wildcard range
+fn is_ip_target(target: &str) -> bool {
+    let (addr, prefix) = target.split_once('/').map_or((target, None), |(a, p)| (a, Some(p)));
+    if prefix.is_some_and(|p| p.parse::<u8>().is_err()) {
+        return false;
+    }
+    let octets: Vec<_> = addr.split('.').collect();
+    let wildcard = octets.len() == 4 && octets.iter().all(|o| *o == "*" || o.parse::<u8>().is_ok());
+    addr.parse::<IpAddr>().is_ok() || (prefix.is_none() && wildcard)
+}
+
+fn parse_bans(doc: &Document) -> Result<Vec<Ban>, ModErr> {
+    let Some(form) = admin_form(doc, Action::Unban) else {
+        return if nobody_banned(doc) { Ok(vec![]) } else { Err(ModErr::NotStaff) };
+    };
+    let headings: Vec<_> = form.find(Name("tr")).find(|tr| tr.find(Name("th")).next().is_some()).map_or(vec![], |tr| {
+        tr.find(Name("th")).map(|th| th.text().trim().to_owned()).collect()
+    });
+    let bans = form
+        .find(Name("tr"))
+        .filter_map(|tr| {
+            let target = tr.find(Attr("name", BAN_FIELD)).next()?.attr("value")?.to_owned();
+            let mut ban = Ban { target, kind: BanKind::Nick, by: None, reason: None, expires: None, extra: vec![] };
+            let mut kind = None;
+            for (heading, td) in headings.iter().zip(tr.find(Name("td"))) {
+                let text = td.text().trim().to_owned();
+                let value = (!text.is_empty() && text != "-").then(|| text.clone());
+                match heading.to_lowercase().as_str() {
+                    "" | "target" | "nick" | "nickname" | "name" | "ip" => {}
+                    "type" | "kind" => kind = Some(text.to_lowercase().starts_with("ip")),
+                    "by" | "banned by" | "moderator" => ban.by = value,
+                    "reason" => ban.reason = value,
+                    "expires" | "until" | "expiry" => ban.expires = value.filter(|v| !v.eq_ignore_ascii_case("never")),
+                    _ => ban.extra.push((heading.clone(), text)),
+                }
+            }
+            // Forks with one list per kind tell it by the target alone
+            if kind.unwrap_or_else(|| is_ip_target(&ban.target)) {
+                ban.kind = BanKind::Ip;
+            }
+            Some(ban)
+        })
+        .collect();
+    Ok(bans)
+}
+
+fn parse_applicants(offset: FixedOffset, doc: &Document) -> Result<Vec<Applicant>, ModErr> {
+    let Some(form) = admin_form(doc, Action::Approve) else {
+        return if nobody_waiting(doc) { Ok(vec![]) } else { Err(ModErr::NotStaff) };
+    };
+    let now = timestamp::server_now(offset);
+    let time = |cell: &str| timestamp::message_times(&[cell], now)[0];
+    let applicants = form
+        .find(Attr("name", APPLICANT_FIELD))
+        .filter_map(|check| {
+            let nick = check.attr("value")?.to_owned();
+            let row = std::iter::successors(check.parent(), |n| n.parent()).find(|n| n.name() == Some("tr"));
+            let mut info: Vec<_> = row
+                .into_iter()
+                .flat_map(|row| row.find(Name("td")))
+                .map(|td| td.text().trim().to_owned())
+                .filter(|cell| !cell.is_empty() && *cell != nick)
+                .collect();
+            let since = info.iter().position(|cell| time(cell).is_some());
+            let waiting_since = since.and_then(|i| time(&info.remove(i)));
+            Some(Applicant { nick, waiting_since, info })
+        })
+        .collect();
+    Ok(applicants)
+}
+
+// The filter forms, and the empty one for a new filter with id `+`
+fn parse_filters(doc: &Document) -> Result<Vec<Filter>, ModErr> {
+    if admin_form(doc, Action::Filter).is_none() {
+        return Err(ModErr::NotStaff);
+    }
+    let filters = doc
+        .find(Name("form"))
+        .filter(|f| has_hidden(f, "action", "admin") && has_hidden(f, "do", Action::Filter.name()))
+        .filter(|f| f.find(Attr("name", "id")).next().and_then(|i| i.attr("value")).is_some_and(|id| id != "+"))
+        .filter_map(|form| {
+            let value = |name| form.find(Attr("name", name)).next().and_then(|i| i.attr("value")).unwrap_or_default().to_owned();
+            let checked = |name| form.find(Attr("name", name)).next().is_some_and(|i| i.attr("checked").is_some());
+            let pattern = value("match");
+            (!pattern.is_empty()).then(|| Filter {
+                pattern,
+                replacement: value("replace"),
+                regex: checked("regex"),
+                case_sensitive: checked("cs"),
+                kick: checked("kick"),
+                allow_in_pm: checked("allowinpm"),
+            })
+        })
+        .collect();
+    Ok(filters)
+}
+
+// Allow or deny `nick`, who must still be waiting
+fn applicant_fields(form: &Node, nick: &str, what: &'static str) -> Result<Vec<(&'static str, String)>, ModErr> {
+    if !form.find(Attr("name", APPLICANT_FIELD)).any(|c| c.attr("value") == Some(nick)) {
+        return Err(ModErr::NoSuchUser { nick: nick.to_owned() });
+    }
+    if !choices(form, "what").unwrap_or_default().iter().any(|w| w == what) {
+        return Err(ModErr::Unsupported(what));
+    }
+    Ok(vec![("what", what.to_owned()), (APPLICANT_FIELD, nick.to_owned())])
+}
+
+// The `value`s the form's field `name` can take, of radio buttons or a select
+fn choices(form: &Node, name: &str) -> Option<Vec<String>> {
+    let fields: Vec<_> = form.find(Attr("name", name)).collect();
+    let radios: Vec<_> = fields.iter().filter(|n| n.attr("type") == Some("radio")).filter_map(|n| n.attr("value")).collect();
+    if !radios.is_empty() {
+        return Some(radios.into_iter().map(str::to_owned).collect());
+    }
+    let select = fields.iter().find(|n| n.name() == Some("select"))?;
+    Some(select.find(Name("option")).map(|o| o.attr("value").map(str::to_owned).unwrap_or_else(|| o.text())).collect())
+}
+
+// Fetch the admin page for the form of `action`, submit it with what `fill`
+// adds to its hidden fields, and tell what came of it. `target` is the nick
+// or room acted on, for the errors. With `confirm` an "Are you sure?" page
+// is answered, once.
+#[allow(clippy::too_many_arguments)]
+async fn submit<E, F>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    action: Action,
+    target: &str,
+    confirm: bool,
+    fill: F,
+) -> Result<(), ModErr>
+where
+    E: Exchange,
+    F: FnOnce(&Node) -> Result<Vec<(&'static str, String)>, ModErr>,
+{
+    let full_url = page_url(base_url, page_php);
+    let doc = fetch_page(http, &format!("{}{}", full_url, action.page(session, http.lang()))).await?;
+    let Some(form) = admin_form(&doc, action) else {
+        // Nobody waiting or banned, the applicant or ban is gone
+        if empty_list(&doc, action) {
+            return Err(ModErr::NoSuchUser { nick: target.to_owned() });
+        }
+        return Err(ModErr::NotStaff);
+    };
+    let mut params: Vec<_> = form
+        .find(Name("input"))
+        .filter(|i| i.attr("type") == Some("hidden"))
+        .filter_map(|i| Some((i.attr("name")?, i.attr("value").unwrap_or_default().to_owned())))
+        .collect();
+    params.extend(fill(&form)?);
+    let mut doc = read_page(http.post_form(Operation::Post, &full_url, &params).await.map_err(FetchErr::from)?)?;
+    if let Some(form) = confirmation_form(&doc, action) {
+        if !confirm {
+            return Err(ModErr::ConfirmationRequired { description: description(&doc) });
+        }
+        let fields = confirmation_fields(&form);
+        let confirmed = read_page(http.post_form(Operation::Post, &full_url, &fields).await.map_err(FetchErr::from)?)?;
+        doc = confirmed;
+        // One hop, a server asking again isn't answered
+        if confirmation_form(&doc, action).is_some() {
+            return Err(ModErr::ConfirmationLoop);
+        }
+    }
+    let text = body_text(&doc);
+    if REFUSED_RGX.is_match(&text) {
+        return Err(ModErr::Refused { nick: target.to_owned() });
+    }
+    if UNKNOWN_RGX.is_match(&text) {
+        return Err(ModErr::NoSuchUser { nick: target.to_owned() });
+    }
+    if INVALID_RGX.is_match(&text) {
+        return Err(ModErr::InvalidTarget { target: target.to_owned() });
+    }
+    // Done, the server is back to the admin page, or to an empty list
+    if admin_form(&doc, action).is_none() && !empty_list(&doc, action) {
+        return Err(ModErr::NotStaff);
+    }
+    Ok(())
+}
+
+// The page of `action` without its form, as nothing is listed
+fn empty_list(doc: &Document, action: Action) -> bool {
+    match action {
+        Action::Approve => nobody_waiting(doc),
+        Action::Unban => nobody_banned(doc),
+        _ => false,
+    }
+}
+
+/// Kick `nick` out of the chat, with `message` as the reason shown.
+pub fn kick_user(
+    transport: &Transport,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    nick: &str,
+    message: Option<&str>,
+) -> Result<(), ModErr> {
+    exchange::block_on(kick_user_with(transport, base_url, page_php, session, nick, message))
+}
+
+/// `kick_user` over any `Exchange`.
+pub async fn kick_user_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    nick: &str,
+    message: Option<&str>,
+) -> Result<(), ModErr> {
+    let params = vec![("kickmessage", message.unwrap_or_default().to_owned()), ("name[]", nick.to_owned())];
+    submit(http, base_url, page_php, session, Action::Kick, nick, false, |_| Ok(params)).await
+}
+
+/// End `nick`'s session, without a kick.
+pub fn logout_user(transport: &Transport, base_url: &str, page_php: &str, session: &str, nick: &str) -> Result<(), ModErr> {
+    exchange::block_on(logout_user_with(transport, base_url, page_php, session, nick))
+}
+
+/// `logout_user` over any `Exchange`.
+pub async fn logout_user_with<E: Exchange>(http: &E, base_url: &str, page_php: &str, session: &str, nick: &str) -> Result<(), ModErr> {
+    let params = vec![("name[]", nick.to_owned())];
+    submit(http, base_url, page_php, session, Action::Logout, nick, false, |_| Ok(params)).await
+}
+
+/// Delete the messages of `target` for everyone. Cleaning a nick's is
+/// checked with a fetch of the view after, a nick still posting there
+/// meanwhile makes it `NotCleaned` too. Without `confirm` a server asking
+/// "Are you sure?" makes it `ConfirmationRequired`.
+// The TUI runs it from `/purge`
+pub fn clean_messages(
+    transport: &Transport,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    target: CleanTarget,
+    confirm: bool,
+) -> Result<(), ModErr> {
+    exchange::block_on(clean_messages_with(transport, base_url, page_php, session, target, confirm))
+}
+
+/// `clean_messages` over any `Exchange`.
+pub async fn clean_messages_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    target: CleanTarget,
+    confirm: bool,
+) -> Result<(), ModErr> {
+    submit(http, base_url, page_php, session, Action::Clean, target.name(), confirm, |form| target.fields(form)).await?;
+    if let CleanTarget::Nick(nick) = &target {
+        // Ignored nicks included
+        let messages = fetch_view(http, &view_url(http, base_url, page_php, session)).await?;
+        if messages.iter().any(|m| m.from.as_ref() == Some(nick)) {
+            return Err(ModErr::NotCleaned);
+        }
+    }
+    Ok(())
+}
+
+/// Delete one message for everyone, by the id the view shows those who
+/// can delete. See `sent::sent_messages` for the ids of our own posts.
+/// Checked with a fetch of the view after. `confirm` as for
+/// `clean_messages`.
+pub fn delete_message(transport: &Transport, base_url: &str, page_php: &str, session: &str, id: u64, confirm: bool) -> Result<(), ModErr> {
+    exchange::block_on(delete_message_with(transport, base_url, page_php, session, id, confirm))
+}
+
+/// `delete_message` over any `Exchange`.
+pub async fn delete_message_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    id: u64,
+    confirm: bool,
+) -> Result<(), ModErr> {
+    let fill = |form: &Node| {
+        if !choices(form, "what").is_some_and(|whats| whats.iter().any(|w| w == "choose")) {
+            return Err(ModErr::Unsupported("selection"));
+        }
+        Ok(vec![("what", "selected".to_owned()), ("mid[]", id.to_string())])
+    };
+    submit(http, base_url, page_php, session, Action::Clean, &id.to_string(), confirm, fill).await?;
+    let messages = fetch_view(http, &view_url(http, base_url, page_php, session)).await?;
+    if messages.iter().any(|m| m.id == Some(id)) {
+        return Err(ModErr::NotCleaned);
+    }
+    sent::forget(http.settings(), session, id);
+    Ok(())
+}
+
+/// Who is in the waiting room.
+pub fn fetch_applicants(transport: &Transport, base_url: &str, page_php: &str, session: &str) -> Result<Vec<Applicant>, ModErr> {
+    exchange::block_on(fetch_applicants_with(transport, base_url, page_php, session))
+}
+
+/// `fetch_applicants` over any `Exchange`.
+pub async fn fetch_applicants_with<E: Exchange>(http: &E, base_url: &str, page_php: &str, session: &str) -> Result<Vec<Applicant>, ModErr> {
+    let url = format!("{}{}", page_url(base_url, page_php), Action::Approve.page(session, http.lang()));
+    parse_applicants(http.settings().server_offset, &fetch_page(http, &url).await?)
+}
+
+/// Let `nick` into the chat.
+pub fn approve_applicant(transport: &Transport, base_url: &str, page_php: &str, session: &str, nick: &str) -> Result<(), ModErr> {
+    exchange::block_on(approve_applicant_with(transport, base_url, page_php, session, nick))
+}
+
+/// `approve_applicant` over any `Exchange`.
+pub async fn approve_applicant_with<E: Exchange>(http: &E, base_url: &str, page_php: &str, session: &str, nick: &str) -> Result<(), ModErr> {
+    submit(http, base_url, page_php, session, Action::Approve, nick, false, |form| applicant_fields(form, nick, "allowchecked")).await
+}
+
+/// Turn `nick` away, with `message` shown to them.
+pub fn reject_applicant(
+    transport: &Transport,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    nick: &str,
+    message: Option<&str>,
+) -> Result<(), ModErr> {
+    exchange::block_on(reject_applicant_with(transport, base_url, page_php, session, nick, message))
+}
+
+/// `reject_applicant` over any `Exchange`.
+pub async fn reject_applicant_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    nick: &str,
+    message: Option<&str>,
+) -> Result<(), ModErr> {
+    submit(http, base_url, page_php, session, Action::Approve, nick, false, |form| {
+        let mut params = applicant_fields(form, nick, "denychecked")?;
+        params.push(("kickmessage", message.unwrap_or_default().to_owned()));
+        Ok(params)
+    })
+    .await
+}
+
+/// The chat's word filters, for `Filters::set`.
+pub fn fetch_filters(transport: &Transport, base_url: &str, page_php: &str, session: &str) -> Result<Vec<Filter>, ModErr> {
+    exchange::block_on(fetch_filters_with(transport, base_url, page_php, session))
+}
+
+/// `fetch_filters` over any `Exchange`.
+pub async fn fetch_filters_with<E: Exchange>(http: &E, base_url: &str, page_php: &str, session: &str) -> Result<Vec<Filter>, ModErr> {
+    let url = format!("{}{}", page_url(base_url, page_php), Action::Filter.page(session, http.lang()));
+    parse_filters(&fetch_page(http, &url).await?)
+}
+
+/// The chat's ban list, on forks with one.
+pub fn fetch_bans(transport: &Transport, base_url: &str, page_php: &str, session: &str) -> Result<Vec<Ban>, ModErr> {
+    exchange::block_on(fetch_bans_with(transport, base_url, page_php, session))
+}
+
+/// `fetch_bans` over any `Exchange`.
+pub async fn fetch_bans_with<E: Exchange>(http: &E, base_url: &str, page_php: &str, session: &str) -> Result<Vec<Ban>, ModErr> {
+    let url = format!("{}{}", page_url(base_url, page_php), Action::Ban.page(session, http.lang()));
+    parse_bans(&fetch_page(http, &url).await?)
+}
+
+/// Ban a nick or an address. With `verify` the ban list is fetched again
+/// after, to see the ban in it. `confirm` as for `clean_messages`.
+pub fn add_ban(
+    transport: &Transport,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    ban: &NewBan,
+    verify: bool,
+    confirm: bool,
+) -> Result<(), ModErr> {
+    exchange::block_on(add_ban_with(transport, base_url, page_php, session, ban, verify, confirm))
+}
+
+/// `add_ban` over any `Exchange`.
+pub async fn add_ban_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    ban: &NewBan,
+    verify: bool,
+    confirm: bool,
+) -> Result<(), ModErr> {
+    let target = ban.target.trim();
+    let valid = match ban.kind {
+        BanKind::Nick => !target.is_empty() && !target.chars().any(char::is_control),
+        BanKind::Ip => is_ip_target(target),
+    };
+    if !valid {
+        return Err(ModErr::InvalidTarget { target: ban.target.clone() });
+    }
+    let fill = |form: &Node| {
+        if !choices(form, "kind").unwrap_or_default().iter().any(|k| k == ban.kind.name()) {
+            return Err(ModErr::Unsupported(ban.kind.name()));
+        }
+        let mut params = vec![("name", target.to_owned()), ("kind", ban.kind.name().to_owned())];
+        if let Some(duration) = ban.duration {
+            let minutes = (duration.as_secs() / 60).to_string();
+            if choices(form, "duration").is_some_and(|offered| !offered.contains(&minutes)) {
+                return Err(ModErr::Unsupported("duration"));
+            }
+            params.push(("duration", minutes));
+        }
+        params.push(("reason", ban.reason.clone().unwrap_or_default()));
+        Ok(params)
+    };
+    submit(http, base_url, page_php, session, Action::Ban, target, confirm, fill).await?;
+    if verify && !fetch_bans_with(http, base_url, page_php, session).await?.iter().any(|b| b.target == target) {
+        return Err(ModErr::BanNotApplied { target: target.to_owned() });
+    }
+    Ok(())
+}
+
+/// Lift the ban of `target`, as the list shows it. With `verify` the ban
+/// list is fetched again after, to see it gone. `confirm` as for
+/// `clean_messages`.
+pub fn remove_ban(
+    transport: &Transport,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    target: &str,
+    verify: bool,
+    confirm: bool,
+) -> Result<(), ModErr> {
+    exchange::block_on(remove_ban_with(transport, base_url, page_php, session, target, verify, confirm))
+}
+
+/// `remove_ban` over any `Exchange`.
+pub async fn remove_ban_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    target: &str,
+    verify: bool,
+    confirm: bool,
+) -> Result<(), ModErr> {
+    let fill = |form: &Node| {
+        if !form.find(Attr("name", BAN_FIELD)).any(|c| c.attr("value") == Some(target)) {
+            return Err(ModErr::NoSuchUser { nick: target.to_owned() });
+        }
+        Ok(vec![(BAN_FIELD, target.to_owned())])
+    };
+    submit(http, base_url, page_php, session, Action::Unban, target, confirm, fill).await?;
+    if verify && fetch_bans_with(http, base_url, page_php, session).await?.iter().any(|b| b.target == target) {
+        return Err(ModErr::BanNotApplied { target: target.to_owned() });
+    }
+    Ok(())
+}
+
+/// Set the topic shown above the messages, an empty `text` takes it
+/// away.
+pub fn set_topic(transport: &Transport, base_url: &str, page_php: &str, session: &str, text: &str) -> Result<(), ModErr> {
+    exchange::block_on(set_topic_with(transport, base_url, page_php, session, text))
+}
+
+/// `set_topic` over any `Exchange`.
+pub async fn set_topic_with<E: Exchange>(http: &E, base_url: &str, page_php: &str, session: &str, text: &str) -> Result<(), ModErr> {
+    let text = text.trim().to_owned();
+    submit(http, base_url, page_php, session, Action::Topic, "", false, |_| Ok(vec![("topic", text)])).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lechatphp::messages;
+    use crate::lechatphp::mock::{MockExchange, MockResponse};
+    use crate::lechatphp::settings::Settings;
+
+    const BASE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+    const ADMIN: &str = include_str!("fixtures/admin.html");
+
+    // The admin page, then `answer` to the submitted form
+    fn server(answer: String) -> MockExchange {
+        MockExchange::new(move |req| match req.method.as_str() {
+            "GET" => Ok(MockResponse::ok(ADMIN)),
+            _ => Ok(MockResponse::ok(&answer)),
+        })
+    }
+
+    fn notice(text: &str) -> String {
+        ADMIN.replace("<i></i>", &format!("<i>{}</i>", text))
+    }
+
+    #[test]
+    fn kick_user_test() {
+        let kick = |http: &MockExchange, nick, message| exchange::block_on(kick_user_with(http, BASE_URL, "chat.php", "abc", nick, message));
+
+        let http = server(ADMIN.to_owned());
+        kick(&http, "dark knight", Some("spam")).unwrap();
+        let requests = http.requests.borrow();
+        assert_eq!(requests[0].path, "/chat.php?action=admin&session=abc&lang=en");
+        assert_eq!(requests[1].body, "lang=en&nc=771204&action=admin&do=kick&session=abc&kickmessage=spam&name[]=dark knight");
+
+        let http = server(notice("You can't kick boss."));
+        assert!(matches!(kick(&http, "boss", None), Err(ModErr::Refused { nick }) if nick == "boss"));
+        let http = server(notice("No such user: ghost"));
+        assert!(matches!(kick(&http, "ghost", None), Err(ModErr::NoSuchUser { nick }) if nick == "ghost"));
+
+        // A member gets the chat instead of the admin page, nothing is sent
+        let http = MockExchange::new(|_| Ok(MockResponse::ok(include_str!("fixtures/view.html"))));
+        assert!(matches!(kick(&http, "dark knight", None), Err(ModErr::NotStaff)));
+        assert_eq!(http.requests.borrow().len(), 1);
+    }
+
+    #[test]
+    fn logout_user_test() {
+        let logout = |http: &MockExchange, nick| exchange::block_on(logout_user_with(http, BASE_URL, "chat.php", "abc", nick));
+
+        let http = server(ADMIN.to_owned());
+        logout(&http, "night owl").unwrap();
+        assert_eq!(http.requests.borrow()[1].body, "lang=en&nc=771204&action=admin&do=logout&session=abc&name[]=night owl");
+
+        let http = server(notice("You are not allowed to logout boss, they have a higher rank."));
+        assert!(matches!(logout(&http, "boss"), Err(ModErr::Refused { .. })));
+        let http = server(notice("ghost is not online."));
+        assert!(matches!(logout(&http, "ghost"), Err(ModErr::NoSuchUser { .. })));
+        let expired = MockExchange::new(|_| Ok(MockResponse::ok(r#"<body class="error"><h2>Error: Invalid/expired session</h2></body>"#)));
+        assert!(matches!(logout(&expired, "ghost"), Err(ModErr::Fetch(FetchErr::SessionExpired))));
+    }
+
+    #[test]
+    fn set_topic_test() {
+        let set = |http: &MockExchange, text| exchange::block_on(set_topic_with(http, BASE_URL, "chat.php", "abc", text));
+
+        let http = server(ADMIN.to_owned());
+        set(&http, " Movie night on friday ").unwrap();
+        assert_eq!(http.requests.borrow()[1].body, "lang=en&nc=771204&action=admin&do=topic&session=abc&topic=Movie night on friday");
+        // A member gets the chat, a fork without topics no form
+        let http = MockExchange::new(|_| Ok(MockResponse::ok(include_str!("fixtures/view.html"))));
+        assert!(matches!(set(&http, "mine now"), Err(ModErr::NotStaff)));
+        assert_eq!(http.requests.borrow().len(), 1);
+    }
+
+    #[test]
+    fn clean_messages_test() {
+        const CLEANED: &str = "lang=en&nc=771204&action=admin&do=clean&session=abc";
+        // The admin page `admin`, and the view for the check after
+        let chat = |admin: String| {
+            MockExchange::new(move |req| match req.method.as_str() {
+                "GET" if req.path.contains("action=view") => Ok(MockResponse::ok(include_str!("fixtures/view.html"))),
+                _ => Ok(MockResponse::ok(&admin)),
+            })
+        };
+        let clean = |http: &MockExchange, target| exchange::block_on(clean_messages_with(http, BASE_URL, "chat.php", "abc", target, false));
+
+        let http = chat(ADMIN.to_owned());
+        clean(&http, CleanTarget::Nick("dark knight".to_owned())).unwrap();
+        let requests = http.requests.borrow();
+        assert_eq!(requests[1].body, format!("{}&what=nick&nickname=dark knight", CLEANED));
+        assert!(requests[2].path.contains("action=view"));
+        let http = chat(ADMIN.to_owned());
+        clean(&http, CleanTarget::All).unwrap();
+        assert_eq!(http.requests.borrow()[1].body, format!("{}&what=allrooms", CLEANED));
+        let http = chat(ADMIN.to_owned());
+        clean(&http, CleanTarget::Room("lounge".to_owned())).unwrap();
+        assert_eq!(http.requests.borrow()[1].body, format!("{}&what=room&room=lounge", CLEANED));
+
+        // Nothing is sent for what the form doesn't offer
+        let http = chat(ADMIN.to_owned());
+        assert!(matches!(clean(&http, CleanTarget::Nick("ghost".to_owned())), Err(ModErr::NoSuchUser { .. })));
+        assert!(matches!(clean(&http, CleanTarget::Room("attic".to_owned())), Err(ModErr::NoSuchRoom { .. })));
+        let http = chat(ADMIN.replace(r#"<input type="radio" name="what" value="allrooms" id="allrooms">"#, ""));
+        assert!(matches!(clean(&http, CleanTarget::All), Err(ModErr::Unsupported("all"))));
+        assert!(http.requests.borrow().iter().all(|r| r.method == "GET"));
+        let http = MockExchange::new(|_| Ok(MockResponse::ok(include_str!("fixtures/view.html"))));
+        assert!(matches!(clean(&http, CleanTarget::All), Err(ModErr::NotStaff)));
+
+        // bob's messages are still there after
+        let http = chat(ADMIN.replace(r#"<option value="night owl">night owl</option></select> <input type="submit" value="Clean">"#, r#"<option value="bob">bob</option></select>"#));
+        assert!(matches!(clean(&http, CleanTarget::Nick("bob".to_owned())), Err(ModErr::NotCleaned)));
+        let http = chat(notice("You can't clean the messages of boss."));
+        assert!(matches!(clean(&http, CleanTarget::Nick("night owl".to_owned())), Err(ModErr::Refused { .. })));
+    }
+
+    #[test]
+    fn confirmation_test() {
+        const CONFIRM: &str = include_str!("fixtures/admin_confirm.html");
+        // The admin page, then the answers to the posts in turn
+        let chat = |answers: Vec<&'static str>| {
+            let posts = std::cell::Cell::new(0);
+            MockExchange::new(move |req| match req.method.as_str() {
+                "GET" if req.path.contains("action=view") => Ok(MockResponse::ok(include_str!("fixtures/view.html"))),
+                "GET" => Ok(MockResponse::ok(ADMIN)),
+                _ => {
+                    posts.set(posts.get() + 1);
+                    Ok(MockResponse::ok(answers[(posts.get() - 1).min(answers.len() - 1)]))
+                }
+            })
+        };
+        let clean = |http: &MockExchange, confirm| {
+            let target = CleanTarget::Nick("dark knight".to_owned());
+            exchange::block_on(clean_messages_with(http, BASE_URL, "chat.php", "abc", target, confirm))
+        };
+
+        // Asked first, nothing done without confirming
+        let http = chat(vec![CONFIRM, ADMIN]);
+        let Err(ModErr::ConfirmationRequired { description }) = clean(&http, false) else { panic!() };
+        assert_eq!(description, "Are you sure? All messages of dark knight will be deleted for everyone.");
+        assert_eq!(http.requests.borrow().len(), 2);
+        let http = chat(vec![CONFIRM, ADMIN]);
+        clean(&http, true).unwrap();
+        let requests = http.requests.borrow();
+        let confirmed = "lang=en&nc=771205&action=admin&do=clean&session=abc&what=nick&nickname=dark knight&confirm=Yes";
+        assert_eq!(requests[2].body, confirmed);
+        assert!(requests[3].path.contains("action=view"));
+        drop(requests);
+        // Once only
+        let http = chat(vec![CONFIRM]);
+        assert!(matches!(clean(&http, true), Err(ModErr::ConfirmationLoop)));
+        assert_eq!(http.requests.borrow().len(), 3);
+        // A server that doesn't ask
+        let http = chat(vec![ADMIN]);
+        clean(&http, false).unwrap();
+    }
+
+    #[test]
+    fn delete_message_test() {
+        let session = "delete-test";
+        let chat = |admin: String| {
+            MockExchange::new(move |req| match req.method.as_str() {
+                "GET" if req.path.contains("action=view") => Ok(MockResponse::ok(include_str!("fixtures/view_moderator.html"))),
+                _ => Ok(MockResponse::ok(&admin)),
+            })
+        };
+        let delete = |http: &MockExchange, id| exchange::block_on(delete_message_with(http, BASE_URL, "chat.php", session, id, false));
+        let http = chat(ADMIN.to_owned());
+        sent::track(&http.settings, session, "alice");
+        sent::record(&http.settings, session, "gone now");
+        let view = r#"<div id="messages"><div class="msg"><label><input type="checkbox" name="mid[]" value="4822"></label><span class="usermsg"><span>alice</span> - gone now</span></div></div>"#;
+        sent::correlate(&http.settings, session, &messages::parse_messages(&Settings::default(), &Document::from(view)).unwrap());
+        assert_eq!(sent::sent_messages(&http.settings, session).find("gone now").unwrap().id, Some(4822));
+
+        delete(&http, 4822).unwrap();
+        let body = "lang=en&nc=771204&action=admin&do=clean&session=abc&what=selected&mid[]=4822";
+        assert_eq!(http.requests.borrow()[1].body, body);
+        assert!(sent::sent_messages(&http.settings, session).find("gone now").is_none());
+        // Still in the view after
+        assert!(matches!(delete(&http, 4818), Err(ModErr::NotCleaned)));
+
+        let http = chat(ADMIN.replace(r#"<input type="radio" name="what" value="choose" id="choose" checked>"#, ""));
+        assert!(matches!(delete(&http, 4822), Err(ModErr::Unsupported("selection"))));
+        let http = MockExchange::new(|_| Ok(MockResponse::ok(include_str!("fixtures/view.html"))));
+        assert!(matches!(delete(&http, 4822), Err(ModErr::NotStaff)));
+    }
+
+    #[test]
+    fn fetch_filters_test() {
+        use crate::lechatphp::filter::{self, FilterHit};
+
+        let http = MockExchange::new(|_| Ok(MockResponse::ok(include_str!("fixtures/filters.html"))));
+        let filters = exchange::block_on(fetch_filters_with(&http, BASE_URL, "chat.php", "abc")).unwrap();
+        assert_eq!(http.requests.borrow()[0].path, "/chat.php?action=admin&do=filter&session=abc&lang=en");
+        let filter = |pattern: &str, replacement: &str| Filter {
+            pattern: pattern.to_owned(),
+            replacement: replacement.to_owned(),
+            regex: false,
+            case_sensitive: false,
+            kick: false,
+            allow_in_pm: false,
+        };
+        let darn = Filter { allow_in_pm: true, ..filter("darn", "d**n") };
+        let links = Filter { regex: true, ..filter(r"https?://\S+\.(ru|cn)\b", "[link removed]") };
+        let spam = Filter { kick: true, case_sensitive: true, ..filter(r#"BUY "CHEAP""#, "No spam <3") };
+        assert_eq!(filters, [darn, links, spam]);
+
+        // As the server would apply them
+        let hit = filter::apply(&filters, "darn, see http://x.ru now", false);
+        let text = "d**n, see [link removed] now".to_owned();
+        assert_eq!(hit, Some(FilterHit::Replaced { text, patterns: vec!["darn".to_owned(), r"https?://\S+\.(ru|cn)\b".to_owned()] }));
+        assert!(matches!(filter::apply(&filters, r#"BUY "CHEAP" pills"#, true), Some(FilterHit::Kick { message, .. }) if message == "No spam <3"));
+        assert_eq!(filter::apply(&filters, r#"buy "cheap" darn"#, true), None);
+
+        let member = MockExchange::new(|_| Ok(MockResponse::ok(include_str!("fixtures/view.html"))));
+        assert!(matches!(exchange::block_on(fetch_filters_with(&member, BASE_URL, "chat.php", "abc")), Err(ModErr::NotStaff)));
+    }
+
+    #[test]
+    fn applicants_test() {
+        const WAITROOM: &str = include_str!("fixtures/waitroom.html");
+        const EMPTY: &str = include_str!("fixtures/waitroom_empty.html");
+        let waitroom = |answer: &'static str| {
+            MockExchange::new(move |req| match req.method.as_str() {
+                "GET" => Ok(MockResponse::ok(WAITROOM)),
+                _ => Ok(MockResponse::ok(answer)),
+            })
+        };
+        let fetch = |http: &MockExchange| exchange::block_on(fetch_applicants_with(http, BASE_URL, "chat.php", "abc"));
+
+        let http = waitroom("");
+        let applicants = fetch(&http).unwrap();
+        assert_eq!(http.requests.borrow()[0].path, "/chat.php?action=admin&do=approve&session=abc&lang=en");
+        let nicks: Vec<_> = applicants.iter().map(|a| a.nick.as_str()).collect();
+        assert_eq!(nicks, ["new comer", "lurker"]);
+        assert_eq!(applicants[1].info, ["Lynx/2.9.0 libwww-FM/2.14"]);
+        let since = applicants[1].waiting_since.unwrap();
+        assert_eq!(since.format("%m-%d %H:%M:%S").to_string(), "10-17 22:01:40");
+        let empty = MockExchange::new(|_| Ok(MockResponse::ok(EMPTY)));
+        assert!(fetch(&empty).unwrap().is_empty());
+        let member = MockExchange::new(|_| Ok(MockResponse::ok(include_str!("fixtures/view.html"))));
+        assert!(matches!(fetch(&member), Err(ModErr::NotStaff)));
+
+        let approve = |http: &MockExchange, nick| exchange::block_on(approve_applicant_with(http, BASE_URL, "chat.php", "abc", nick));
+        let reject = |http: &MockExchange, nick, message| {
+            exchange::block_on(reject_applicant_with(http, BASE_URL, "chat.php", "abc", nick, message))
+        };
+        const SUBMITTED: &str = "lang=en&nc=661093&action=admin&do=approve&session=abc";
+        // The last one let in, the room is empty after
+        let http = waitroom(EMPTY);
+        approve(&http, "lurker").unwrap();
+        assert_eq!(http.requests.borrow()[1].body, format!("{}&what=allowchecked&alls[]=lurker", SUBMITTED));
+        let http = waitroom(WAITROOM);
+        reject(&http, "new comer", Some("members only")).unwrap();
+        let body = format!("{}&what=denychecked&alls[]=new comer&kickmessage=members only", SUBMITTED);
+        assert_eq!(http.requests.borrow()[1].body, body);
+
+        // Gave up waiting before, nothing is sent
+        let http = waitroom(WAITROOM);
+        assert!(matches!(approve(&http, "ghost"), Err(ModErr::NoSuchUser { nick }) if nick == "ghost"));
+        assert_eq!(http.requests.borrow().len(), 1);
+        assert!(matches!(reject(&empty, "lurker", None), Err(ModErr::NoSuchUser { .. })));
+        assert!(matches!(approve(&member, "lurker"), Err(ModErr::NotStaff)));
+    }
+
+    const BANS: &str = include_str!("fixtures/bans.html");
+    const NO_BANS: &str = include_str!("fixtures/bans_empty.html");
+
+    #[test]
+    fn fetch_bans_test() {
+        let fetch = |page: &'static str| {
+            let http = MockExchange::new(move |_| Ok(MockResponse::ok(page)));
+            let bans = exchange::block_on(fetch_bans_with(&http, BASE_URL, "chat.php", "abc"));
+            assert_eq!(http.requests.borrow()[0].path, "/chat.php?action=admin&do=ban&session=abc&lang=en");
+            bans
+        };
+        let bans = fetch(BANS).unwrap();
+        let hits = |n: &str| vec![("Hits".to_owned(), n.to_owned())];
+        let troll = Ban {
+            target: "troll 2000".to_owned(),
+            kind: BanKind::Nick,
+            by: Some("dark knight".to_owned()),
+            reason: Some("flooding".to_owned()),
+            expires: Some("10-18 21:02:13".to_owned()),
+            extra: hits("3"),
+        };
+        let range = Ban { target: "10.0.0.*".to_owned(), kind: BanKind::Ip, by: Some("night owl".to_owned()), reason: None, expires: None, extra: hits("41") };
+        let bot = Ban { target: "spam bot".to_owned(), kind: BanKind::Nick, by: None, reason: Some("ads & links".to_owned()), expires: None, extra: hits("0") };
+        assert_eq!(bans, [troll, range, bot]);
+        assert!(fetch(NO_BANS).unwrap().is_empty());
+        assert!(matches!(fetch(include_str!("fixtures/view.html")), Err(ModErr::NotStaff)));
+
+        assert!(is_ip_target("10.0.0.1") && is_ip_target("10.0.*.*") && is_ip_target("fd00::1") && is_ip_target("10.0.0.0/24"));
+        assert!(!is_ip_target("10.0.0") && !is_ip_target("10.0.0.256") && !is_ip_target("troll") && !is_ip_target("10.0.*.*/8"));
+    }
+
+    #[test]
+    fn ban_test() {
+        // The ban list as `after` shows it once the form is sent
+        let bans = |after: &'static str| {
+            let posted = std::cell::Cell::new(false);
+            MockExchange::new(move |req| match req.method.as_str() {
+                "GET" if posted.get() => Ok(MockResponse::ok(after)),
+                "GET" => Ok(MockResponse::ok(BANS)),
+                _ => {
+                    posted.set(true);
+                    Ok(MockResponse::ok(after))
+                }
+            })
+        };
+        let add = |http: &MockExchange, ban: &NewBan, verify| exchange::block_on(add_ban_with(http, BASE_URL, "chat.php", "abc", ban, verify, false));
+        let remove = |http: &MockExchange, target, verify| exchange::block_on(remove_ban_with(http, BASE_URL, "chat.php", "abc", target, verify, false));
+        const SUBMITTED: &str = "lang=en&nc=502817&action=admin";
+
+        let ban = NewBan { target: " 10.0.0.* ".to_owned(), kind: BanKind::Ip, duration: Some(Duration::from_secs(24 * 3600)), reason: Some("again".to_owned()) };
+        let http = bans(BANS);
+        add(&http, &ban, true).unwrap();
+        let requests = http.requests.borrow();
+        assert_eq!(requests[1].body, format!("{}&do=ban&session=abc&name=10.0.0.*&kind=ip&duration=1440&reason=again", SUBMITTED));
+        assert_eq!(requests.len(), 3);
+        drop(requests);
+        // Not in the list after
+        let http = bans(NO_BANS);
+        let gone = NewBan { target: "ghost".to_owned(), kind: BanKind::Nick, duration: None, reason: None };
+        assert!(matches!(add(&http, &gone, true), Err(ModErr::BanNotApplied { target }) if target == "ghost"));
+        assert!(add(&http, &gone, false).is_ok());
+
+        // Refused before sending anything, or by the form
+        let http = bans(BANS);
+        assert!(matches!(add(&http, &NewBan { target: "10.0.0".to_owned(), ..ban.clone() }, false), Err(ModErr::InvalidTarget { .. })));
+        assert!(matches!(add(&http, &NewBan { target: " ".to_owned(), ..gone.clone() }, false), Err(ModErr::InvalidTarget { .. })));
+        assert!(http.requests.borrow().is_empty());
+        let odd = NewBan { duration: Some(Duration::from_secs(5 * 60)), ..ban.clone() };
+        assert!(matches!(add(&http, &odd, false), Err(ModErr::Unsupported("duration"))));
+        let http = bans(r#"<html><body class="admin"><i>Invalid IP address.</i></body></html>"#);
+        assert!(matches!(add(&http, &ban, false), Err(ModErr::InvalidTarget { .. })));
+        let http = bans(r#"<html><body class="admin"><i>You can't ban staff.</i></body></html>"#);
+        assert!(matches!(add(&http, &gone, false), Err(ModErr::Refused { .. })));
+
+        // The last one lifted, the list is empty after
+        let http = bans(NO_BANS);
+        remove(&http, "spam bot", true).unwrap();
+        assert_eq!(http.requests.borrow()[1].body, format!("{}&do=unban&session=abc&unban[]=spam bot", SUBMITTED));
+        let http = bans(BANS);
+        assert!(matches!(remove(&http, "spam bot", true), Err(ModErr::BanNotApplied { .. })));
+        assert!(matches!(remove(&http, "nobody", false), Err(ModErr::NoSuchUser { .. })));
+        let empty = MockExchange::new(|_| Ok(MockResponse::ok(NO_BANS)));
+        assert!(matches!(remove(&empty, "spam bot", false), Err(ModErr::NoSuchUser { .. })));
+        let member = MockExchange::new(|_| Ok(MockResponse::ok(include_str!("fixtures/view.html"))));
+        assert!(matches!(remove(&member, "spam bot", false), Err(ModErr::NotStaff)));
+    }
+}
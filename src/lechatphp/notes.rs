@@ -0,0 +1,250 @@
+use super::error_kind::{Classify, ErrorKind};
+use super::exchange::{self, Exchange};
+use super::messages::{fetch_page, read_page, FetchErr};
+use super::metrics::Operation;
+use super::page_url;
+use super::transport::Transport;
+use lazy_static::lazy_static;
+use regex::Regex;
+use select::document::Document;
+use select::node::Node;
+use select::predicate::{Attr, Name};
+use thiserror::Error;
+
+lazy_static! {
+    static ref LAST_EDITED_RGX: Regex = Regex::new(r"(?i)last (edited|modified|changed|saved)[^\n]*").unwrap();
+    static ref DENIED_RGX: Regex = Regex::new(r"(?i)access denied|not allowed|permission denied|staff only|admins? only").unwrap();
+}
+
+/// The notes pages of le-chat.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NotesKind {
+    Admin,
+    Staff,
+    /// Only ours, every member has them.
+    Personal,
+}
+
+impl NotesKind {
+    // The `do` of the page, personal notes go without
+    fn what(self) -> Option<&'static str> {
+        match self {
+            NotesKind::Admin => Some("admin"),
+            NotesKind::Staff => Some("staff"),
+            NotesKind::Personal => None,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum NotesErr {
+    #[error("{0}")]
+    Fetch(#[from] FetchErr),
+    /// The session may not see these notes.
+    #[error("access denied to the notes")]
+    AccessDenied,
+    #[error("no notes form in the page")]
+    NoNotesForm,
+    /// Someone saved the notes since they were fetched.
+    #[error("notes changed meanwhile{}", .last_edited.as_ref().map(|last_edited| format!(": {}", last_edited)).unwrap_or_default())]
+    Changed { last_edited: Option<String> },
+    /// The page after saving shows other notes.
+    #[error("notes not saved")]
+    NotSaved,
+}
+
+impl Classify for NotesErr {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            NotesErr::Fetch(e) => e.kind(),
+            NotesErr::AccessDenied => ErrorKind::PermissionDenied,
+            // Saving again would overwrite the other edit
+            NotesErr::Changed { .. } | NotesErr::NoNotesForm | NotesErr::NotSaved => ErrorKind::Fatal,
+        }
+    }
+}
+
+/// Notes as fetched, to edit and save with `set_notes`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notes {
+    pub text: String,
+    /// The page's "Last edited by..." line, as it is, when it shows one.
+    /// `set_notes` compares it to tell whether someone saved meanwhile.
+    pub last_edited: Option<String>,
+}
+
+fn notes_form<'a>(doc: &'a Document, kind: NotesKind) -> Option<Node<'a>> {
+    doc.find(Name("form")).find(|f| {
+        let hidden = |name| f.find(Attr("name", name)).next().and_then(|i| i.attr("value"));
+        hidden("action") == Some("notes") && hidden("do") == kind.what() && f.find(Name("textarea")).next().is_some()
+    })
+}
+
+// The notes and the fields to save them with
+fn parse_notes(doc: &Document, kind: NotesKind) -> Result<(Notes, Vec<(String, String)>), NotesErr> {
+    let Some(form) = notes_form(doc, kind) else {
+        let text = doc.find(Name("body")).next().map(|b| b.text()).unwrap_or_default();
+        return Err(if DENIED_RGX.is_match(&text) { NotesErr::AccessDenied } else { NotesErr::NoNotesForm });
+    };
+    let fields = form
+        .find(Name("input"))
+        .filter(|i| i.attr("type") == Some("hidden"))
+        .filter_map(|i| Some((i.attr("name")?.to_owned(), i.attr("value").unwrap_or_default().to_owned())))
+        .collect();
+    let text = form.find(Name("textarea")).next().map(|t| t.text()).unwrap_or_default();
+    let last_edited = doc
+        .find(Name("body"))
+        .next()
+        .and_then(|b| LAST_EDITED_RGX.find(&b.text()).map(|m| m.as_str().trim().to_owned()));
+    Ok((Notes { text, last_edited }, fields))
+}
+
+fn notes_url(base_url: &str, page_php: &str, session: &str, lang: &str, kind: NotesKind) -> String {
+    let what = kind.what().map(|w| format!("&do={}", w)).unwrap_or_default();
+    format!("{}?action=notes{}&session={}&lang={}", page_url(base_url, page_php), what, session, lang)
+}
+
+async fn fetch_form<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    kind: NotesKind,
+) -> Result<(Notes, Vec<(String, String)>), NotesErr> {
+    let doc = fetch_page(http, &notes_url(base_url, page_php, session, http.lang(), kind)).await?;
+    parse_notes(&doc, kind)
+}
+
+// Browsers send textareas with CRLF
+fn same_text(a: &str, b: &str) -> bool {
+    a.replace("\r\n", "\n").trim_end() == b.replace("\r\n", "\n").trim_end()
+}
+
+/// The text of the notes.
+pub fn get_notes(transport: &Transport, base_url: &str, page_php: &str, session: &str, kind: NotesKind) -> Result<String, NotesErr> {
+    Ok(fetch_notes(transport, base_url, page_php, session, kind)?.text)
+}
+
+/// The notes with their "last edited" line, to save them back.
+pub fn fetch_notes(transport: &Transport, base_url: &str, page_php: &str, session: &str, kind: NotesKind) -> Result<Notes, NotesErr> {
+    exchange::block_on(fetch_notes_with(transport, base_url, page_php, session, kind))
+}
+
+/// `fetch_notes` over any `Exchange`.
+pub async fn fetch_notes_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    kind: NotesKind,
+) -> Result<Notes, NotesErr> {
+    Ok(fetch_form(http, base_url, page_php, session, kind).await?.0)
+}
+
+/// Save `notes.text`. Unless `force`, nothing is saved when the notes
+/// were edited since `notes` was fetched. Returns the notes as saved.
+pub fn set_notes(
+    transport: &Transport,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    kind: NotesKind,
+    notes: &Notes,
+    force: bool,
+) -> Result<Notes, NotesErr> {
+    exchange::block_on(set_notes_with(transport, base_url, page_php, session, kind, notes, force))
+}
+
+/// `set_notes` over any `Exchange`.
+pub async fn set_notes_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    kind: NotesKind,
+    notes: &Notes,
+    force: bool,
+) -> Result<Notes, NotesErr> {
+    // Fetched again for a fresh `nc`, and to see who saved meanwhile
+    let (current, fields) = fetch_form(http, base_url, page_php, session, kind).await?;
+    if !force && current.last_edited != notes.last_edited {
+        return Err(NotesErr::Changed { last_edited: current.last_edited });
+    }
+    let mut params: Vec<_> = fields.iter().map(|(name, value)| (name.as_str(), value.clone())).collect();
+    params.push(("text", notes.text.clone()));
+    let page = http.post_form(Operation::Post, &page_url(base_url, page_php), &params).await.map_err(FetchErr::from)?;
+    let (saved, _) = parse_notes(&read_page(page)?, kind)?;
+    if !same_text(&saved.text, &notes.text) {
+        return Err(NotesErr::NotSaved);
+    }
+    Ok(saved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lechatphp::mock::{MockExchange, MockResponse};
+
+    const BASE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+    const ADMIN: &str = include_str!("fixtures/notes_admin.html");
+    const STAFF: &str = include_str!("fixtures/notes_staff.html");
+    const PERSONAL: &str = include_str!("fixtures/notes_personal.html");
+
+    fn parse(html: &str, kind: NotesKind) -> Result<Notes, NotesErr> {
+        parse_notes(&Document::from(html), kind).map(|(notes, _)| notes)
+    }
+
+    #[test]
+    fn parse_notes_test() {
+        let admin = parse(ADMIN, NotesKind::Admin).unwrap();
+        assert_eq!(admin.text, "Ban list review on Sunday.\nDo not unban \"spammer42\" <again>.");
+        assert_eq!(admin.last_edited.as_deref(), Some("Last edited by boss at 10-17 19:40:02"));
+        let staff = parse(STAFF, NotesKind::Staff).unwrap();
+        assert_eq!(staff.text, "Shift plan:\n- mod one: mornings\n- mod two: nights");
+        assert_eq!(parse(PERSONAL, NotesKind::Personal).unwrap(), Notes { text: String::new(), last_edited: None });
+
+        // The form of another kind isn't taken for this one
+        assert!(matches!(parse(STAFF, NotesKind::Admin), Err(NotesErr::NoNotesForm)));
+        let denied = r#"<html><body><h2>Access denied</h2><p>Admin notes are for admins only.</p></body></html>"#;
+        assert!(matches!(parse(denied, NotesKind::Admin), Err(NotesErr::AccessDenied)));
+    }
+
+    #[test]
+    fn set_notes_test() {
+        let edited = "Shift plan:\n- mod one: mornings\n- mod two: nights\n- boss: weekends";
+        // The form, then the notes as saved by another staff or by us
+        let server = |after: String| {
+            MockExchange::new(move |req| match req.method.as_str() {
+                "GET" => Ok(MockResponse::ok(STAFF)),
+                _ => Ok(MockResponse::ok(&after)),
+            })
+        };
+        let saved_page = STAFF.replace("- mod two: nights", "- mod two: nights\n- boss: weekends").replace("mod two at 10-16 08:12:45", "boss at 10-17 20:01:10");
+        let set = |http: &MockExchange, notes: &Notes, force| {
+            exchange::block_on(set_notes_with(http, BASE_URL, "chat.php", "abc", NotesKind::Staff, notes, force))
+        };
+
+        let http = server(saved_page.clone());
+        let mut notes = exchange::block_on(fetch_notes_with(&http, BASE_URL, "chat.php", "abc", NotesKind::Staff)).unwrap();
+        notes.text = edited.to_owned();
+        let saved = set(&http, &notes, false).unwrap();
+        assert_eq!(saved.last_edited.as_deref(), Some("Last edited by boss at 10-17 20:01:10"));
+        let requests = http.requests.borrow();
+        assert_eq!(requests[0].path, "/chat.php?action=notes&do=staff&session=abc&lang=en");
+        let body = format!("lang=en&nc=339812&action=notes&do=staff&session=abc&text={}", edited);
+        assert_eq!(requests[2].body, body);
+
+        // Someone saved since, only `force` overwrites
+        let stale = Notes { last_edited: Some("Last edited by mod two at 10-15 23:59:59".to_owned()), ..notes.clone() };
+        let http = server(saved_page);
+        assert!(matches!(set(&http, &stale, false), Err(NotesErr::Changed { last_edited: Some(_) })));
+        assert_eq!(http.requests.borrow().len(), 1);
+        set(&http, &stale, true).unwrap();
+
+        let http = server(STAFF.to_owned());
+        assert!(matches!(set(&http, &notes, false), Err(NotesErr::NotSaved)));
+        let view = MockExchange::new(|_| Ok(MockResponse::ok(include_str!("fixtures/view.html"))));
+        assert!(matches!(set(&view, &notes, true), Err(NotesErr::NoNotesForm)));
+    }
+}
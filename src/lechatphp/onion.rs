@@ -0,0 +1,173 @@
+use super::error_kind::{Classify, ErrorKind};
+use reqwest::Url;
+use sha3::{Digest, Sha3_256};
+use thiserror::Error;
+
+const V3_LEN: usize = 56;
+const V2_LEN: usize = 16;
+const V3_VERSION: u8 = 3;
+const BASE32_ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+#[derive(Debug, Clone, PartialEq, Error)]
+#[non_exhaustive]
+pub enum UrlErr {
+    #[error("invalid url: {0}")]
+    Parse(String),
+    #[error("url has no host: {0}")]
+    MissingHost(String),
+    #[error("{0} is a v2 onion address, v2 onion services are retired and no longer reachable")]
+    OnionV2(String),
+    #[error("{} is not a valid v3 onion address: expected {} characters before .onion, got {}", .0, V3_LEN, .1)]
+    OnionLength(String, usize),
+    #[error("{0} is not a valid v3 onion address: only a-z and 2-7 are allowed")]
+    OnionCharset(String),
+    #[error("{0} is not a valid v3 onion address: version byte is {1}")]
+    OnionVersion(String, u8),
+    #[error("{0} is not a valid v3 onion address: checksum mismatch, check for typos")]
+    OnionChecksum(String),
+    #[error("{0} is not an onion address and clearnet is not allowed, see --allow-clearnet")]
+    Clearnet(String),
+}
+
+impl Classify for UrlErr {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            UrlErr::Parse(_)
+            | UrlErr::MissingHost(_)
+            | UrlErr::OnionV2(_)
+            | UrlErr::OnionLength(..)
+            | UrlErr::OnionCharset(_)
+            | UrlErr::OnionVersion(..)
+            | UrlErr::OnionChecksum(_)
+            | UrlErr::Clearnet(_) => ErrorKind::InvalidInput,
+        }
+    }
+}
+
+pub(super) fn base32_decode(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u64, 0);
+    for c in s.bytes() {
+        let value = BASE32_ALPHABET.iter().position(|&a| a == c)? as u64;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+pub(super) fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity((data.len() * 8).div_ceil(5));
+    let (mut buffer, mut bits) = (0u64, 0);
+    for &b in data {
+        buffer = (buffer << 8) | b as u64;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+/// Check a v3 onion host: base32(pubkey | checksum | version) where
+/// checksum = SHA3-256(".onion checksum" | pubkey | version)[..2].
+pub fn validate_onion_host(host: &str) -> Result<(), UrlErr> {
+    let host = host.to_ascii_lowercase();
+    // Subdomains are allowed, only the last label is the address
+    let label = host.trim_end_matches(".onion").rsplit('.').next().unwrap_or("").to_owned();
+    if label.len() == V2_LEN {
+        return Err(UrlErr::OnionV2(host));
+    }
+    if label.len() != V3_LEN {
+        return Err(UrlErr::OnionLength(host, label.len()));
+    }
+    let decoded = base32_decode(&label).ok_or_else(|| UrlErr::OnionCharset(host.clone()))?;
+    let (pubkey, checksum, version) = (&decoded[..32], &decoded[32..34], decoded[34]);
+    if version != V3_VERSION {
+        return Err(UrlErr::OnionVersion(host, version));
+    }
+    let mut hasher = Sha3_256::new();
+    hasher.update(b".onion checksum");
+    hasher.update(pubkey);
+    hasher.update([version]);
+    if hasher.finalize()[..2] != *checksum {
+        return Err(UrlErr::OnionChecksum(host));
+    }
+    Ok(())
+}
+
+/// Validate a chat base url before connecting to it. Onion hosts must be
+/// well formed v3 addresses, clearnet hosts are allowed with a notice.
+pub fn validate_base_url(base_url: &str) -> Result<(), UrlErr> {
+    let url = Url::parse(base_url).map_err(|_| UrlErr::Parse(base_url.to_owned()))?;
+    let host = url.host_str().ok_or_else(|| UrlErr::MissingHost(base_url.to_owned()))?;
+    if host.to_ascii_lowercase().ends_with(".onion") {
+        validate_onion_host(host)
+    } else {
+        log::warn!("{} is not an onion address", host);
+        Ok(())
+    }
+}
+
+/// Whether `base_url` is a well formed url outside of Tor's onion services.
+pub fn is_clearnet(base_url: &str) -> bool {
+    Url::parse(base_url)
+        .ok()
+        .and_then(|url| url.host_str().map(|host| !host.to_ascii_lowercase().ends_with(".onion")))
+        .unwrap_or(false)
+}
+
+/// Refuse clearnet urls unless they are allowed.
+pub fn check_clearnet(base_url: &str, allow_clearnet: bool) -> Result<(), UrlErr> {
+    if !allow_clearnet && is_clearnet(base_url) {
+        return Err(UrlErr::Clearnet(base_url.to_owned()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Check = fn(&UrlErr) -> bool;
+
+    #[test]
+    fn validate_base_url_test() {
+        let valid = "7ezcvo2wrozkrakhitpnloz2m3l6uqa33st6lyyylpe7ptzdghpsc4yd";
+        let cases: Vec<(String, Result<(), Check>)> = vec![
+            (format!("http://{}.onion/chat", valid), Ok(())),
+            (format!("http://{}.onion", valid.to_uppercase()), Ok(())),
+            ("http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion".to_owned(), Ok(())),
+            ("http://www.duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion".to_owned(), Ok(())),
+            ("https://example.com/chat".to_owned(), Ok(())),
+            ("http://127.0.0.1:8080".to_owned(), Ok(())),
+            (format!("http://{}.onion", &valid[1..]), Err(|e| matches!(e, UrlErr::OnionLength(_, 55)))),
+            ("http://expyuzz4wqqyqhjn.onion".to_owned(), Err(|e| matches!(e, UrlErr::OnionV2(_)))),
+            (format!("http://{}1.onion", &valid[1..]), Err(|e| matches!(e, UrlErr::OnionCharset(_)))),
+            (format!("http://{}.onion", valid.replacen('7', "a", 1)), Err(|e| matches!(e, UrlErr::OnionChecksum(_)))),
+            (format!("http://{}a.onion", &valid[..55]), Err(|e| matches!(e, UrlErr::OnionVersion(_, _)))),
+            ("not a url".to_owned(), Err(|e| matches!(e, UrlErr::Parse(_)))),
+        ];
+        for (url, expected) in cases {
+            match (validate_base_url(&url), expected) {
+                (Ok(()), Ok(())) => {}
+                (Err(e), Err(check)) => assert!(check(&e), "{}: unexpected {:?}", url, e),
+                (got, _) => panic!("{}: unexpected {:?}", url, got),
+            }
+        }
+
+        assert!(check_clearnet(&format!("http://{}.onion", valid), false).is_ok());
+        assert!(check_clearnet("https://example.com", true).is_ok());
+        assert_eq!(
+            check_clearnet("https://example.com", false),
+            Err(UrlErr::Clearnet("https://example.com".to_owned()))
+        );
+    }
+}
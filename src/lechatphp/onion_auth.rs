@@ -0,0 +1,237 @@
+use super::error_kind::{Classify, ErrorKind};
+use super::onion::{base32_decode, base32_encode, validate_onion_host, UrlErr};
+use base64::engine::general_purpose;
+use base64::Engine;
+use reqwest::Url;
+use std::fmt::{Debug, Formatter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{fs, io};
+use thiserror::Error;
+
+const KEY_TYPE: &str = "x25519";
+const DESCRIPTOR: &str = "descriptor";
+/// Consecutive connection failures to an authorized onion before the key is
+/// suspected. Tor can't decrypt the descriptor with a wrong key and reports
+/// the service as unreachable, like any other outage.
+const SUSPECT_AFTER: usize = 3;
+
+/// Client authorization key of a restricted (v3) onion service, the private
+/// half tor keeps in `ClientOnionAuthDir/<name>.auth_private`.
+#[derive(Clone, PartialEq)]
+pub struct ClientAuthKey {
+    /// The 56 characters before `.onion`.
+    onion: String,
+    secret: [u8; 32],
+}
+
+// Keep the secret out of logs
+impl Debug for ClientAuthKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientAuthKey").field("onion", &self.onion).finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum OnionAuthErr {
+    /// What was given, up to the key.
+    #[error("invalid onion client auth key {0}, expected <onion>:descriptor:x25519:<base32 key> or a .auth_private file")]
+    Invalid(String),
+    #[error("onion client auth key: {0}")]
+    Onion(#[source] UrlErr),
+    #[error("onion client auth key {}: {}", .0.display(), .1)]
+    Io(PathBuf, #[source] io::Error),
+}
+
+impl Classify for OnionAuthErr {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            OnionAuthErr::Invalid(_) | OnionAuthErr::Onion(_) | OnionAuthErr::Io(..) => ErrorKind::InvalidInput,
+        }
+    }
+}
+
+/// Parses a `.auth_private` line, `<onion>:descriptor:x25519:<base32 key>`.
+/// The onion may keep its `.onion` suffix.
+impl FromStr for ClientAuthKey {
+    type Err = OnionAuthErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let mut parts = s.split(':');
+        let onion = parts.next().unwrap_or("").to_ascii_lowercase();
+        let onion = onion.trim_end_matches(".onion");
+        let invalid = || OnionAuthErr::Invalid(format!("{}:...", onion));
+        let (Some(DESCRIPTOR), Some(KEY_TYPE), Some(key), None) = (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+        validate_onion_host(onion).map_err(OnionAuthErr::Onion)?;
+        let secret = base32_decode(&key.to_ascii_lowercase())
+            .filter(|_| key.len() == 52)
+            .and_then(|k| <[u8; 32]>::try_from(k).ok())
+            .ok_or_else(invalid)?;
+        Ok(Self { onion: onion.to_owned(), secret })
+    }
+}
+
+impl ClientAuthKey {
+    pub fn onion(&self) -> &str {
+        &self.onion
+    }
+
+    #[cfg(any(feature = "arti", test))]
+    pub fn secret(&self) -> [u8; 32] {
+        self.secret
+    }
+
+    /// Whether this key is for the onion serving `base_url`.
+    pub fn covers(&self, base_url: &str) -> bool {
+        Url::parse(base_url).ok().and_then(|u| u.host_str().map(str::to_ascii_lowercase)).is_some_and(|host| {
+            host.trim_end_matches(".onion").rsplit('.').next() == Some(self.onion.as_str())
+        })
+    }
+
+    /// Contents of `<onion>.auth_private`, as tor writes it.
+    pub fn auth_private(&self) -> String {
+        format!("{}:{}:{}:{}", self.onion, DESCRIPTOR, KEY_TYPE, base32_encode(&self.secret).to_ascii_uppercase())
+    }
+
+    /// The key as the control port spells it, `x25519:<base64>`.
+    pub fn control_blob(&self) -> String {
+        format!("{}:{}", KEY_TYPE, general_purpose::STANDARD.encode(self.secret))
+    }
+}
+
+/// A key given as a `.auth_private` line, or as the path of such a file.
+pub fn load(spec: &str) -> Result<ClientAuthKey, OnionAuthErr> {
+    if spec.contains(&format!(":{}:", DESCRIPTOR)) {
+        return spec.parse();
+    }
+    let path = Path::new(spec);
+    let contents = fs::read_to_string(path).map_err(|e| OnionAuthErr::Io(path.to_owned(), e))?;
+    contents
+        .lines()
+        .find(|l| !l.trim().is_empty() && !l.starts_with('#'))
+        .ok_or_else(|| OnionAuthErr::Invalid(path.display().to_string()))?
+        .parse()
+}
+
+/// Make sure `dir`, tor's `ClientOnionAuthDir`, holds `key`. Returns whether
+/// the file was written, tor only reads the directory when (re)loading its
+/// configuration.
+pub fn write_auth_private(dir: &Path, key: &ClientAuthKey) -> Result<bool, OnionAuthErr> {
+    let path = dir.join(format!("{}.auth_private", key.onion));
+    let line = key.auth_private();
+    if fs::read_to_string(&path).is_ok_and(|c| c.lines().any(|l| l.parse::<ClientAuthKey>().is_ok_and(|k| &k == key))) {
+        return Ok(false);
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(&path).map_err(|e| OnionAuthErr::Io(path.clone(), e))?;
+    io::Write::write_all(&mut file, format!("{}\n", line).as_bytes()).map_err(|e| OnionAuthErr::Io(path, e))?;
+    Ok(true)
+}
+
+/// Counts consecutive connection failures while some configured url is an
+/// authorized onion, to point at the key rather than at tor or the server.
+#[derive(Debug, Clone, Default)]
+pub struct FailureWatch {
+    onions: Vec<String>,
+    failures: usize,
+}
+
+impl FailureWatch {
+    pub fn new(keys: &[ClientAuthKey], urls: &[String]) -> Self {
+        let onions = keys
+            .iter()
+            .filter(|k| urls.iter().any(|url| k.covers(url)))
+            .map(|k| format!("{}.onion", k.onion))
+            .collect();
+        Self { onions, failures: 0 }
+    }
+
+    /// Record a failed connection. Returns a hint every `SUSPECT_AFTER`
+    /// failures in a row.
+    pub fn failed(&mut self) -> Option<String> {
+        if self.onions.is_empty() {
+            return None;
+        }
+        self.failures += 1;
+        self.failures.is_multiple_of(SUSPECT_AFTER).then(|| {
+            format!(
+                "{} failed connections in a row to {}, which needs client authorization: \
+                 the key may be wrong or revoked, check --onion-auth",
+                self.failures,
+                self.onions.join(", ")
+            )
+        })
+    }
+
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ONION: &str = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad";
+    const KEY: &str = "GBDD3ZFBWKZ5NKUKZRUDGR45JAQ7HJI6KBUS5SCV6SYDZUPTJLTA";
+
+    #[test]
+    fn parse_test() {
+        let line = format!("{}:descriptor:x25519:{}", ONION, KEY);
+        let key: ClientAuthKey = line.parse().unwrap();
+        assert_eq!(key.onion(), ONION);
+        assert_eq!(key.auth_private(), line);
+        assert_eq!(key.control_blob(), format!("x25519:{}", general_purpose::STANDARD.encode(key.secret())));
+        // Suffix and case don't matter
+        let suffixed = format!("{}.onion:descriptor:x25519:{}", ONION.to_uppercase(), KEY.to_lowercase());
+        assert_eq!(suffixed.parse::<ClientAuthKey>().unwrap(), key);
+        assert!(key.covers(&format!("http://{}.onion", ONION)));
+        assert!(key.covers(&format!("http://chat.{}.onion/", ONION)));
+        assert!(!key.covers("http://example.com"));
+        // No secret in Debug output
+        assert!(!format!("{:?}", key).contains(KEY));
+
+        for bad in [
+            format!("{}:descriptor:x25519:{}", ONION, &KEY[1..]),
+            format!("{}:descriptor:ed25519:{}", ONION, KEY),
+            format!("{}:x25519:{}", ONION, KEY),
+            format!("{}:descriptor:x25519:{}:extra", ONION, KEY),
+            format!("{}:descriptor:x25519:{}", ONION, KEY.replace('G', "1")),
+        ] {
+            assert!(matches!(bad.parse::<ClientAuthKey>(), Err(OnionAuthErr::Invalid(_))), "{}", bad);
+        }
+        let typo = format!("{}a:descriptor:x25519:{}", &ONION[1..], KEY);
+        assert!(matches!(typo.parse::<ClientAuthKey>(), Err(OnionAuthErr::Onion(_))));
+
+        // From a file, like the ones in ClientOnionAuthDir
+        let path = std::env::temp_dir().join(format!("bhcli-onion-auth-{}.auth_private", std::process::id()));
+        fs::write(&path, format!("# restricted chat\n{}\n", line)).unwrap();
+        assert_eq!(load(path.to_str().unwrap()).unwrap(), key);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(load(path.to_str().unwrap()), Err(OnionAuthErr::Io(..))));
+        assert_eq!(load(&line).unwrap(), key);
+
+        let dir = std::env::temp_dir().join(format!("bhcli-onion-auth-dir-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        assert!(write_auth_private(&dir, &key).unwrap());
+        assert!(!write_auth_private(&dir, &key).unwrap());
+        assert_eq!(fs::read_to_string(dir.join(format!("{}.auth_private", ONION))).unwrap(), format!("{}\n", line));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut watch = FailureWatch::new(std::slice::from_ref(&key), &[format!("http://{}.onion", ONION)]);
+        assert_eq!((watch.failed(), watch.failed()), (None, None));
+        assert!(watch.failed().unwrap().contains(ONION));
+        watch.reset();
+        assert_eq!(watch.failed(), None);
+        let mut unrelated = FailureWatch::new(&[key], &["http://example.com".to_owned()]);
+        assert!((0..6).all(|_| unrelated.failed().is_none()));
+    }
+}
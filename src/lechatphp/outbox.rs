@@ -0,0 +1,366 @@
+use super::emoji::Table;
+use super::error_kind::{Classify, ErrorKind};
+use super::exchange::{self, Exchange};
+use super::messages::{self, Message, MessageKind};
+use super::page_url;
+use super::post::{self, PostErr};
+use super::retry::{self, SendErr};
+use super::settings::Settings;
+use super::transport::Transport;
+use chrono::{DateTime, FixedOffset, Utc};
+use serde_derive::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::{fs, io};
+use thiserror::Error;
+
+/// Time between two rounds of resending that failed.
+pub const RETRY_DELAY: Duration = Duration::from_secs(15);
+
+/// A message that failed to send and waits to be sent again.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Pending {
+    id: u64,
+    /// RFC 3339, when it first failed.
+    pub queued: String,
+    /// A nick, or a group like `SEND_TO_ALL`.
+    pub send_to: String,
+    pub text: String,
+    /// The room it was posted to, on forks with several.
+    #[serde(default)]
+    pub room: Option<String>,
+    /// Sent again and failed since.
+    pub attempts: u32,
+}
+
+impl Pending {
+    pub fn queued(&self) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc3339(&self.queued).ok()
+    }
+}
+
+/// What the outbox holds, for the UI.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OutboxStatus {
+    pub pending: usize,
+    pub oldest: Option<DateTime<FixedOffset>>,
+    /// Why the last round of resending stopped.
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum OutboxErr {
+    #[error("outbox {}: {}", .0.display(), .1)]
+    Io(PathBuf, #[source] io::Error),
+    #[error("outbox {}: {}", .0.display(), .1)]
+    Json(PathBuf, #[source] serde_json::Error),
+}
+
+impl Classify for OutboxErr {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            OutboxErr::Io(..) | OutboxErr::Json(..) => ErrorKind::Fatal,
+        }
+    }
+}
+
+/// Errors a message is kept for: the circuit, the proxy or a gateway
+/// failing on the way. Everything else is the server's answer.
+pub fn is_transient(err: &PostErr) -> bool {
+    match err {
+        PostErr::Send(SendErr::Reqwest(e)) => e.is_timeout() || retry::is_transient_err(e),
+        PostErr::Send(SendErr::Body(_) | SendErr::Truncated { .. }) => true,
+        PostErr::ServerDown(status) => retry::is_transient_status(*status),
+        _ => false,
+    }
+}
+
+// Worth another round later, the message stays and the ones after it wait
+fn is_retried(err: &PostErr) -> bool {
+    is_transient(err) || matches!(err, PostErr::Flood { .. } | PostErr::SessionExpired)
+}
+
+/// The messages of a profile that failed to send, oldest first, saved
+/// after every change.
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    path: PathBuf,
+    pending: Vec<Pending>,
+    next_try: Option<Instant>,
+    last_error: Option<String>,
+}
+
+impl Outbox {
+    /// The outbox saved at `path`, empty when there is no file yet.
+    pub fn load(path: &Path) -> Result<Self, OutboxErr> {
+        let pending = match fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json).map_err(|e| OutboxErr::Json(path.to_owned(), e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(OutboxErr::Io(path.to_owned(), e)),
+        };
+        Ok(Self { path: path.to_owned(), pending, next_try: None, last_error: None })
+    }
+
+    pub fn save(&self) -> Result<(), OutboxErr> {
+        let json = serde_json::to_string(&self.pending).expect("outbox serializes");
+        fs::write(&self.path, json).map_err(|e| OutboxErr::Io(self.path.clone(), e))
+    }
+
+    // Saved, or the chat goes on with what is in memory
+    fn changed(&self) {
+        if let Err(e) = self.save() {
+            log::warn!("{}", e);
+        }
+    }
+
+    /// Keep `text` for `send_to` in `room` when `err` is transient, and
+    /// tell whether it was.
+    pub fn keep(&mut self, room: Option<&str>, send_to: &str, text: &str, err: &PostErr) -> bool {
+        if !is_transient(err) {
+            return false;
+        }
+        let id = self.pending.iter().map(|p| p.id + 1).max().unwrap_or_default();
+        let queued = Utc::now().fixed_offset().to_rfc3339();
+        self.pending.push(Pending { id, queued, send_to: send_to.to_owned(), text: text.to_owned(), room: room.map(str::to_owned), attempts: 0 });
+        self.changed();
+        log::info!("not sent ({}), kept in the outbox", err);
+        true
+    }
+
+    pub fn pending(&self) -> &[Pending] {
+        &self.pending
+    }
+
+    pub fn status(&self) -> OutboxStatus {
+        OutboxStatus {
+            pending: self.pending.len(),
+            oldest: self.pending.first().and_then(Pending::queued),
+            last_error: self.last_error.clone(),
+        }
+    }
+
+    fn is_due(&self, now: Instant) -> bool {
+        !self.pending.is_empty() && self.next_try.is_none_or(|next| now >= next)
+    }
+
+    // The outcome of a round of `resend_with`. Returns the messages given
+    // up on.
+    fn settle(&mut self, sent: Vec<(u64, Result<(), PostErr>)>, now: Instant) -> Vec<(Pending, PostErr)> {
+        let mut given_up = vec![];
+        self.next_try = None;
+        for (id, result) in sent {
+            let Some(i) = self.pending.iter().position(|p| p.id == id) else {
+                continue;
+            };
+            match result {
+                Ok(()) => {
+                    self.pending.remove(i);
+                }
+                Err(e) if is_retried(&e) => {
+                    self.pending[i].attempts += 1;
+                    self.next_try = Some(now + RETRY_DELAY);
+                    self.last_error = Some(e.to_string());
+                }
+                Err(e) => given_up.push((self.pending.remove(i), e)),
+            }
+        }
+        self.changed();
+        given_up
+    }
+}
+
+// Whether `pending` is in `view` already, the post having gone through
+// despite the error. Our identical message counts when it is from after
+// the post, give or take `window`, in its room. A match whose time can't be
+// read counts.
+fn delivered(pending: &Pending, view: &[Message], me: &str, window: Duration, emoji: &Table) -> bool {
+    let text = emoji.shorten(&emoji.expand(&pending.text));
+    let since = pending.queued().map(|q| q - chrono::Duration::from_std(window).unwrap_or(chrono::Duration::zero()));
+    view.iter().any(|m| {
+        let to_them = match m.kind {
+            MessageKind::Private => m.to.as_deref().is_some_and(|to| to.eq_ignore_ascii_case(&pending.send_to)),
+            MessageKind::System(_) => false,
+            _ => true,
+        };
+        m.from.as_deref().is_some_and(|from| from.eq_ignore_ascii_case(me))
+            && to_them
+            && m.room == pending.room
+            && m.text.trim() == text.trim()
+            && since.zip(m.time).is_none_or(|(since, time)| time >= since)
+    })
+}
+
+/// Send `pending` again, oldest first, and tell how each went. Stops at
+/// the first that fails for the connection or the session, which is kept,
+/// and returns `None` when the view can't be read. The view is read first
+/// so messages that went through anyway aren't sent twice, see
+/// `post::ReplayGuard`, and each is sent with `post::send_post`.
+pub async fn resend_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    pending: &[Pending],
+) -> Option<Vec<(u64, Result<(), PostErr>)>> {
+    let view = messages::fetch_messages_with(http, base_url, page_php, session).await.ok()?;
+    let guard = http.settings().replay_guard.window.zip(http.settings().mention.nick());
+    let full_url = page_url(base_url, page_php);
+    let mut sent = vec![];
+    for message in pending {
+        if guard.as_ref().is_some_and(|(window, me)| delivered(message, &view, me, *window, &http.settings().emoji)) {
+            log::warn!("a message of the outbox was delivered, not sending it again");
+            sent.push((message.id, Ok(())));
+            continue;
+        }
+        let result = post::post_to_in(http, &full_url, session, message.room.as_deref(), &message.send_to, &message.text).await;
+        let stop = result.as_ref().is_err_and(is_retried);
+        sent.push((message.id, result));
+        if stop {
+            break;
+        }
+    }
+    Some(sent)
+}
+
+/// The outbox keeping the messages that fail to send at `path`, what is
+/// saved there is sent at the next `retry`. `None` without a path.
+pub(super) fn open(path: Option<PathBuf>) -> Option<Outbox> {
+    path.map(|path| {
+        Outbox::load(&path).unwrap_or_else(|e| {
+            log::warn!("{}, starting with an empty outbox", e);
+            Outbox { path, pending: vec![], next_try: None, last_error: None }
+        })
+    })
+}
+
+/// `result`, after keeping the message when it failed transiently and the
+/// outbox is on.
+pub(super) fn keep(
+    settings: &Settings,
+    room: Option<&str>,
+    send_to: &str,
+    text: &str,
+    result: Result<(), PostErr>,
+) -> Result<(), PostErr> {
+    if let (Err(e), Some(outbox)) = (&result, settings.outbox.lock().unwrap().as_mut()) {
+        outbox.keep(room, send_to, text, e);
+    }
+    result
+}
+
+/// Whether a post failing with `err` is kept to be sent again.
+pub fn keeps(settings: &Settings, err: &PostErr) -> bool {
+    is_transient(err) && settings.outbox.lock().unwrap().is_some()
+}
+
+/// Send what the outbox holds again when it is due, done by the message
+/// stream after each fetch. Returns the messages given up on, which are
+/// out of the outbox.
+pub fn retry(transport: &Transport, base_url: &str, page_php: &str, session: &str) -> Vec<(Pending, PostErr)> {
+    exchange::block_on(retry_with(transport, base_url, page_php, session))
+}
+
+/// `retry` over any `Exchange`.
+pub async fn retry_with<E: Exchange>(http: &E, base_url: &str, page_php: &str, session: &str) -> Vec<(Pending, PostErr)> {
+    // Not locked while sending, new messages may be kept meanwhile
+    let pending = match http.settings().outbox.lock().unwrap().as_ref() {
+        Some(outbox) if outbox.is_due(Instant::now()) => outbox.pending.clone(),
+        _ => return vec![],
+    };
+    let sent = resend_with(http, base_url, page_php, session, &pending).await;
+    let mut outbox = http.settings().outbox.lock().unwrap();
+    let Some(outbox) = outbox.as_mut() else {
+        return vec![];
+    };
+    match sent {
+        Some(sent) => outbox.settle(sent, Instant::now()),
+        None => {
+            outbox.next_try = Some(Instant::now() + RETRY_DELAY);
+            vec![]
+        }
+    }
+}
+
+pub fn outbox_status(settings: &Settings) -> OutboxStatus {
+    settings.outbox.lock().unwrap().as_ref().map(Outbox::status).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lechatphp::command::SEND_TO_ALL;
+    use crate::lechatphp::mock::{MockExchange, MockResponse};
+
+    const BASE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+    const FORM: &str = include_str!("fixtures/post_ok.html");
+    const VIEW: &str = include_str!("fixtures/view.html");
+
+    fn outbox(name: &str) -> Outbox {
+        let path = std::env::temp_dir().join(format!("bhcli-outbox-{}-{}.json", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        Outbox::load(&path).unwrap()
+    }
+
+    fn texts(outbox: &Outbox) -> Vec<&str> {
+        outbox.pending().iter().map(|p| p.text.as_str()).collect()
+    }
+
+    #[test]
+    fn outbox_test() {
+        let mut outbox = outbox("keep");
+        let down = PostErr::ServerDown(http::StatusCode::BAD_GATEWAY);
+        assert!(outbox.keep(None, SEND_TO_ALL, "first", &down));
+        assert!(!outbox.keep(None, SEND_TO_ALL, "kicked for it", &PostErr::Kicked { reason: None }));
+        assert!(!outbox.keep(None, SEND_TO_ALL, "too long", &PostErr::TooLong { max: Some(10) }));
+        assert!(outbox.keep(None, "bob", "second", &down));
+        assert!(outbox.keep(None, SEND_TO_ALL, "third", &down));
+        // After a restart
+        let mut outbox = Outbox::load(&outbox.path).unwrap();
+        assert_eq!(texts(&outbox), ["first", "second", "third"]);
+        assert_eq!(outbox.status().pending, 3);
+        assert!(outbox.is_due(Instant::now()));
+
+        // Still down: the first is kept, the rest waits behind it
+        let (now, pending) = (Instant::now(), outbox.pending().to_vec());
+        let given_up = outbox.settle(vec![(pending[0].id, Err(PostErr::ServerDown(http::StatusCode::GATEWAY_TIMEOUT)))], now);
+        assert!(given_up.is_empty());
+        assert!(!outbox.is_due(now) && outbox.is_due(now + RETRY_DELAY));
+        assert_eq!((outbox.pending()[0].attempts, outbox.status().last_error.is_some()), (1, true));
+
+        // Back: one is sent, one refused for good and surfaced
+        let sent = vec![(pending[0].id, Ok(())), (pending[1].id, Err(PostErr::RecipientOffline)), (pending[2].id, Ok(()))];
+        let given_up = outbox.settle(sent, now);
+        assert_eq!(given_up.len(), 1);
+        assert!(matches!(&given_up[0], (p, PostErr::RecipientOffline) if p.text == "second"));
+        assert_eq!(Outbox::load(&outbox.path).unwrap().status(), OutboxStatus::default());
+        fs::remove_file(&outbox.path).unwrap();
+    }
+
+    #[test]
+    fn resend_test() {
+        let mut outbox = outbox("resend");
+        let down = PostErr::ServerDown(http::StatusCode::BAD_GATEWAY);
+        // Ours in the view, though the post failed
+        let view = VIEW.replace(">alice</span> - hello everyone", ">zed</span> - hello everyone");
+        let landed = messages::parse_messages(&Settings::default(), &select::document::Document::from(view.as_str())).unwrap().remove(0);
+        outbox.keep(None, SEND_TO_ALL, "in order", &down);
+        outbox.keep(None, SEND_TO_ALL, "hello everyone", &down);
+        outbox.keep(None, SEND_TO_ALL, "after it", &down);
+        outbox.pending[1].queued = landed.time.unwrap().to_rfc3339();
+        let http = MockExchange::new(move |req| Ok(MockResponse::ok(if req.path.contains("action=view") { &view } else { FORM })));
+        http.settings.mention.set_nick("zed");
+        let sent = exchange::block_on(resend_with(&http, BASE_URL, "chat.php", "outbox-test", outbox.pending())).unwrap();
+        assert_eq!(sent.len(), 3);
+        assert!(sent.iter().all(|(_, r)| r.is_ok()));
+        let posted: Vec<_> = http.requests.borrow().iter().filter(|r| r.method == "POST").map(|r| r.body.clone()).collect();
+        assert_eq!(posted.len(), 2);
+        assert!(posted[0].contains("&message=in order&") && posted[1].contains("&message=after it&"));
+
+        // Nothing sent while the view can't be read
+        let down = MockExchange::new(|_| Ok(MockResponse::new(502, "Bad Gateway")));
+        assert!(exchange::block_on(resend_with(&down, BASE_URL, "chat.php", "outbox-test", outbox.pending())).is_none());
+        assert_eq!(down.requests.borrow().len(), 1);
+        fs::remove_file(&outbox.path).unwrap();
+    }
+}
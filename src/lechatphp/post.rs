@@ -0,0 +1,1425 @@
+use super::command::{ChatCommand, ACTION_PREFIX, SEND_TO_ALL};
+use super::error_kind::{Classify, ErrorKind};
+use super::exchange::{self, Exchange, Page, Upload};
+use super::filter::FilterHit;
+use super::outbox;
+use super::messages::{self, FetchErr};
+use super::metrics::Operation;
+use super::page_url;
+use super::retry::SendErr;
+use super::rooms::{self, ROOM_PARAM};
+use super::sent;
+use super::settings::Settings;
+use super::spans;
+use super::transport::Transport;
+use super::users::{self, Role};
+use chrono::NaiveDateTime;
+use http::StatusCode;
+use lazy_static::lazy_static;
+use regex::Regex;
+use select::document::Document;
+use select::predicate::{Attr, Class, Name, Or};
+use std::fs::File;
+use std::mem;
+use std::path::Path;
+use std::io;
+use std::time::Duration;
+use tracing::field::Empty;
+use tracing::Span;
+use thiserror::Error;
+
+/// Longest flood delay worth waiting for in place, longer ones are
+/// rescheduled by the caller.
+pub const MAX_FLOOD_WAIT: Duration = Duration::from_secs(30);
+// When the notice doesn't say how long
+const DEFAULT_FLOOD_WAIT: Duration = Duration::from_secs(5);
+// Only the latest messages can be the one we just posted
+const RECENT_MESSAGES: usize = 20;
+/// Default for `ReplayGuard::window`.
+pub const DEFAULT_REPLAY_WINDOW: Duration = Duration::from_secs(10);
+/// Most times `send_post` sends a post again after a timeout.
+pub const MAX_RESENDS: usize = 2;
+const DEFAULT_DATETIME_FMT: &str = "%m-%d %H:%M:%S";
+// Timestamps leave the year out, a leap year lets Feb 29 parse
+const TIMESTAMP_YEAR: &str = "2000";
+
+lazy_static! {
+    static ref FLOOD_RGX: Regex =
+        Regex::new(r"(?i)wait\b.*\b(between|before)\b.*\bmessages?|posting too fast|flood").unwrap();
+    static ref WAIT_SECS_RGX: Regex = Regex::new(r"(?i)(\d+)\s*(seconds?|secs?)\b").unwrap();
+    static ref KICKED_RGX: Regex = Regex::new(r"(?is)you have been kicked!?(.*)").unwrap();
+    static ref EXPIRED_RGX: Regex = Regex::new(r"(?i)invalid/expired session|session (has )?expired|invalid session").unwrap();
+    static ref OFFLINE_RGX: Regex =
+        Regex::new(r"(?i)no longer (online|in the chat|logged in)|recipient .*(offline|not online)").unwrap();
+    static ref TOO_LONG_RGX: Regex = Regex::new(r"(?i)message (is )?too long|too many characters").unwrap();
+    static ref MAX_CHARS_RGX: Regex = Regex::new(r"(?i)(\d+)\s*(characters|chars)\b").unwrap();
+    static ref UPLOAD_SIZE_RGX: Regex =
+        Regex::new(r"(?i)(file|upload|attachment)\b.*\b(too (large|big)|exceeds)|max(imum)? (upload|file) size").unwrap();
+    static ref UPLOAD_TYPE_RGX: Regex = Regex::new(
+        r"(?i)(file ?type|type of file|extension|format)\b.*\b(not allowed|not supported|unsupported|invalid)|only images"
+    )
+    .unwrap();
+    static ref SIZE_RGX: Regex = Regex::new(r"(?i)(\d+)\s*(bytes|kb|kib|mb|mib)\b").unwrap();
+    // An element and what is in it, or a tag left open or alone
+    static ref UNSAFE_ELEMENTS_RGX: Vec<Regex> = UNSAFE_TAGS
+        .iter()
+        .map(|tag| Regex::new(&format!(r"(?is)<{0}\b[^>]*>.*?</{0}\s*>|</?{0}\b[^>]*>?", tag)).unwrap())
+        .collect();
+    static ref TOKEN_RGX: Regex = Regex::new(r"\s+|\S+").unwrap();
+    static ref STALE_FORM_RGX: Regex =
+        Regex::new(r"(?i)(invalid|expired|wrong|bad) (form|token|nonce|csrf|post ?id)|(form|token) (has )?expired").unwrap();
+}
+
+/// Fields of the post form `post_to_in` fills itself, the form's other hidden
+/// fields are sent back as they are.
+const OWN_FIELDS: &[&str] = &["action", "session", "lang", "nc", "postid", "message", "sendto"];
+
+/// Names forks give the post box's checkbox for raw HTML, only shown to
+/// staff.
+const HTML_FIELDS: &[&str] = &["html", "allowhtml", "rawhtml"];
+/// Elements `sanitize_html` takes out with what is in them.
+const UNSAFE_TAGS: &[&str] = &["script", "style", "iframe"];
+
+/// le-chat's default `maxmessage`, in characters. Longer messages are
+/// split, see `split_message`.
+pub const DEFAULT_MAX_MESSAGE_LEN: usize = 2000;
+/// Most parts a message is split into, a longer one isn't sent at all.
+pub const DEFAULT_MAX_MESSAGE_PARTS: usize = 10;
+
+/// How a message with line breaks is posted, many servers drop them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum MultiLine {
+    /// Each line its own post, a long one split like any message. Blank
+    /// lines are left out.
+    #[default]
+    Separate,
+    /// One post with the line breaks written as this, which the view
+    /// parsers of this client turn back into line breaks.
+    Separator(String),
+}
+
+impl MultiLine {
+    /// `text` as seen by others with this `Separator`, its line breaks back.
+    pub fn restore(&self, text: &str) -> String {
+        match self {
+            MultiLine::Separator(separator) if !separator.is_empty() => text.replace(separator.as_str(), "\n"),
+            _ => text.to_owned(),
+        }
+    }
+}
+
+/// Largest file sent by `post_with_upload` unless configured otherwise,
+/// the server may take less.
+pub const DEFAULT_MAX_UPLOAD_SIZE: u64 = 1024 * 1024;
+
+/// Extensions `post_with_upload` knows the type of, the rest is sent as
+/// `application/octet-stream`.
+const MIME_TYPES: &[(&str, &str)] = &[
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("bmp", "image/bmp"),
+    ("txt", "text/plain"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+];
+
+// Some only come from `post_message`, see there
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum PostErr {
+    /// Nothing but whitespace, never sent.
+    #[error("empty message, not sent")]
+    EmptyMessage,
+    /// The server refused the post for being too soon after the previous one.
+    #[error("flood notice{}", .wait.map(|wait| format!(", wait {}s", wait.as_secs())).unwrap_or_default())]
+    Flood { wait: Option<Duration> },
+    /// Kicked out of the chat, with the reason when the page gives one.
+    #[error("kicked{}", .reason.as_ref().map(|reason| format!(": {}", reason)).unwrap_or_default())]
+    Kicked { reason: Option<String> },
+    /// The session is gone, only a new login helps.
+    #[error("session expired")]
+    SessionExpired,
+    /// The nick a private message was for left the chat.
+    #[error("recipient no longer online")]
+    RecipientOffline,
+    /// Longer than the server takes, with its limit when the notice says.
+    #[error("message too long{}", .max.map(|max| format!(", {} characters at most", max)).unwrap_or_default())]
+    TooLong { max: Option<usize> },
+    /// Would take more than `max_parts` parts of `max_len` characters, or
+    /// has a link longer than a part. Nothing was sent.
+    #[error("message too long for {max_parts} parts of {max_len} characters")]
+    MessageTooLong { max_len: usize, max_parts: usize },
+    #[error("{0}, server down")]
+    ServerDown(StatusCode),
+    /// The page has no post form to take the `nc` and `postid` from.
+    #[error("no post form in the page")]
+    NoPostForm,
+    /// The server answered a delete with something else than the post box.
+    #[error("not accepted by the server")]
+    NotAccepted,
+    /// The chat's known filters would change the message or kick for it,
+    /// and `FilterCheck::Refuse` is set. Nothing was sent.
+    #[error("{}", filtered(.0))]
+    Filtered(FilterHit),
+    /// The server no longer takes the post form's fields, a fresh post box
+    /// is needed.
+    #[error("post form expired")]
+    StaleForm,
+    /// The post box has no file field, nothing was sent.
+    #[error("uploads are disabled on this server")]
+    UploadsDisabled,
+    /// Bigger than the client's limit or the server's, in bytes when known.
+    #[error("file too large{}", .max.map(|max| format!(", {} bytes at most", max)).unwrap_or_default())]
+    UploadTooLarge { max: Option<u64> },
+    /// A type the form or the server doesn't take.
+    #[error("{mime} files are not accepted")]
+    UploadType { mime: String },
+    /// The file to upload can't be read.
+    #[error("{0}")]
+    File(#[source] io::Error),
+    /// Not staff, or the post box has no HTML checkbox. Nothing was sent.
+    #[error("only staff can post HTML")]
+    PermissionDenied,
+    #[error("{0}")]
+    Send(#[from] SendErr),
+}
+
+impl Classify for PostErr {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            // A fresh post box, or waiting out the flood notice, is all it takes
+            PostErr::Flood { .. } | PostErr::ServerDown(_) | PostErr::StaleForm => ErrorKind::Transient,
+            PostErr::Kicked { .. } | PostErr::SessionExpired => ErrorKind::AuthExpired,
+            PostErr::UploadsDisabled | PostErr::PermissionDenied => ErrorKind::PermissionDenied,
+            PostErr::EmptyMessage
+            | PostErr::RecipientOffline
+            | PostErr::TooLong { .. }
+            | PostErr::MessageTooLong { .. }
+            | PostErr::Filtered(_)
+            | PostErr::UploadTooLarge { .. }
+            | PostErr::UploadType { .. }
+            | PostErr::File(_) => ErrorKind::InvalidInput,
+            PostErr::NoPostForm | PostErr::NotAccepted => ErrorKind::Fatal,
+            PostErr::Send(e) => e.kind(),
+        }
+    }
+}
+
+// What `PostErr::Filtered` says
+fn filtered(hit: &FilterHit) -> String {
+    match hit {
+        FilterHit::Replaced { text, .. } => format!("the chat's filters would post it as: {}", text),
+        FilterHit::Kick { pattern, .. } => format!("the chat's filter {:?} would kick for it", pattern),
+    }
+}
+
+/// Classify the page returned after posting a message. Only notices are
+/// looked at, never the form, which can echo the user's own text.
+pub fn check_post_response(doc: &Document) -> Result<(), PostErr> {
+    for notice in doc.find(Or(Or(Class("error"), Class("notice")), Name("h2"))) {
+        let text = notice.text();
+        if let Some(c) = KICKED_RGX.captures(&text) {
+            let reason = c[1].trim().trim_start_matches("Reason:").trim();
+            return Err(PostErr::Kicked { reason: (!reason.is_empty()).then(|| reason.to_owned()) });
+        } else if EXPIRED_RGX.is_match(&text) {
+            return Err(PostErr::SessionExpired);
+        } else if STALE_FORM_RGX.is_match(&text) {
+            return Err(PostErr::StaleForm);
+        } else if OFFLINE_RGX.is_match(&text) {
+            return Err(PostErr::RecipientOffline);
+        } else if TOO_LONG_RGX.is_match(&text) {
+            let max = MAX_CHARS_RGX.captures(&text).and_then(|c| c[1].parse().ok());
+            return Err(PostErr::TooLong { max });
+        } else if FLOOD_RGX.is_match(&text) {
+            let wait = WAIT_SECS_RGX
+                .captures(&text)
+                .and_then(|c| c[1].parse().ok())
+                .map(Duration::from_secs);
+            return Err(PostErr::Flood { wait });
+        }
+    }
+    Ok(())
+}
+
+// The body of a page that is neither an error status nor a notice
+fn classify(page: Page) -> Result<String, PostErr> {
+    if page.status.is_server_error() {
+        return Err(PostErr::ServerDown(page.status));
+    }
+    let body = page.body?;
+    check_post_response(&Document::from(body.as_str()))?;
+    Ok(body)
+}
+
+// What posting takes from a post box page
+#[derive(Debug, Clone)]
+pub(crate) struct PostBox {
+    nc: String,
+    postid: String,
+    /// The message field's `maxlength`, when the form has one.
+    max_len: Option<usize>,
+    /// Hidden fields of forks, like a per-session token.
+    hidden: Vec<(String, String)>,
+    /// The raw HTML checkbox's name and value, for staff.
+    html: Option<(String, String)>,
+}
+
+fn post_box_fields(page: &str) -> Option<PostBox> {
+    let doc = Document::from(page);
+    let attr = |name, attr| doc.find(Attr("name", name)).next().and_then(|n| n.attr(attr)).map(str::to_owned);
+    let max_len = attr("message", "maxlength").and_then(|m| m.parse().ok()).filter(|&m| m > 0);
+    let hidden = doc
+        .find(Attr("type", "hidden"))
+        .filter_map(|i| Some((i.attr("name")?.to_owned(), i.attr("value").unwrap_or_default().to_owned())))
+        .filter(|(name, _)| !OWN_FIELDS.contains(&name.as_str()))
+        .collect();
+    let html = doc
+        .find(Attr("type", "checkbox"))
+        .filter_map(|i| Some((i.attr("name")?, i.attr("value").unwrap_or("on"))))
+        .find(|(name, _)| HTML_FIELDS.contains(&name.to_ascii_lowercase().as_str()))
+        .map(|(name, value)| (name.to_owned(), value.to_owned()));
+    Some(PostBox { nc: attr("nc", "value")?, postid: attr("postid", "value")?, max_len, hidden, html })
+}
+
+/// Keep the post box between the posts of `session`, done by `login`. The
+/// first post fetches it, the next ones use the one the previous post got
+/// back, `None` until the first post of the session.
+pub fn track_post_box(settings: &Settings, session: &str) {
+    settings.post_boxes.lock().unwrap().insert(session.to_owned(), None);
+}
+
+pub fn untrack_post_box(settings: &Settings, session: &str) {
+    settings.post_boxes.lock().unwrap().remove(session);
+}
+
+// Taken out, a post that fails leaves none behind
+fn cached_post_box(settings: &Settings, session: &str) -> Option<PostBox> {
+    settings.post_boxes.lock().unwrap().get_mut(session).and_then(Option::take)
+}
+
+fn cache_post_box(settings: &Settings, session: &str, form: &PostBox) {
+    if let Some(cached) = settings.post_boxes.lock().unwrap().get_mut(session) {
+        *cached = Some(form.clone());
+    }
+}
+
+// Fetch the post box of `room`, every form of it needs its fields
+async fn post_box<E: Exchange>(http: &E, full_url: &str, session: &str, room: Option<&str>) -> Result<PostBox, PostErr> {
+    let form_url = format!("{}?action=post&session={}&lang={}{}", full_url, session, http.lang(), rooms::query(room));
+    let form = classify(http.get(Operation::Post, &form_url).await?)?;
+    post_box_fields(&form).ok_or(PostErr::NoPostForm)
+}
+
+/// Post `command`, plain text for everyone when given a string. See
+/// `ChatCommand::wire` for what is sent.
+pub fn post_message(
+    transport: &Transport,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    command: impl Into<ChatCommand>,
+) -> Result<(), PostErr> {
+    exchange::block_on(post_message_with(transport, base_url, page_php, session, command))
+}
+
+/// `post_message` over any `Exchange`.
+pub async fn post_message_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    command: impl Into<ChatCommand>,
+) -> Result<(), PostErr> {
+    post_in(http, base_url, page_php, session, rooms::room(http.settings(), session).as_deref(), command.into()).await
+}
+
+/// Post `command` to `room` rather than the session's room, which stays
+/// the same.
+pub fn post_message_in(
+    transport: &Transport,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    room: &str,
+    command: impl Into<ChatCommand>,
+) -> Result<(), PostErr> {
+    exchange::block_on(post_message_in_with(transport, base_url, page_php, session, room, command))
+}
+
+/// `post_message_in` over any `Exchange`.
+pub async fn post_message_in_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    room: &str,
+    command: impl Into<ChatCommand>,
+) -> Result<(), PostErr> {
+    post_in(http, base_url, page_php, session, Some(room), command.into()).await
+}
+
+async fn post_in<E: Exchange>(http: &E, base_url: &str, page_php: &str, session: &str, room: Option<&str>, command: ChatCommand) -> Result<(), PostErr> {
+    // Nothing to send after a `/me `
+    if command.is_empty() {
+        return Err(PostErr::EmptyMessage);
+    }
+    let (send_to, message) = command.wire();
+    let result = post_to_in(http, &page_url(base_url, page_php), session, room, send_to, &message).await;
+    outbox::keep(http.settings(), room, send_to, &message, result)
+}
+
+/// Whisper `text` to `to_nick`, spaces in nicks are fine.
+pub fn post_private(
+    transport: &Transport,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    to_nick: &str,
+    text: &str,
+) -> Result<(), PostErr> {
+    exchange::block_on(post_private_with(transport, base_url, page_php, session, to_nick, text))
+}
+
+/// `post_private` over any `Exchange`.
+pub async fn post_private_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    to_nick: &str,
+    text: &str,
+) -> Result<(), PostErr> {
+    let room = rooms::room(http.settings(), session);
+    let result = post_to_in(http, &page_url(base_url, page_php), session, room.as_deref(), to_nick, text).await;
+    outbox::keep(http.settings(), room.as_deref(), to_nick, text, result)
+}
+
+// The post form for its `nc` and `postid`, then the message. `send_to` is
+// the post box's recipient: a nick, or a group like `SEND_TO_ALL`. Split
+// when longer than our limit or the form's, the parts are paced by the
+// rate limiter like any post. A part that fails stops the rest. A part
+// refused for a stale form is sent again once, with a fresh one. In
+// `room`, `None` for the chat's default room.
+#[tracing::instrument(name = "post", skip_all, fields(url = %full_url, room = ?room, parts = Empty, status = Empty, elapsed_ms = Empty))]
+pub(super) async fn post_to_in<E: Exchange>(
+    http: &E,
+    full_url: &str,
+    session: &str,
+    room: Option<&str>,
+    send_to: &str,
+    text: &str,
+) -> Result<(), PostErr> {
+    let _timer = spans::timer();
+    if text.trim().is_empty() {
+        return Err(PostErr::EmptyMessage);
+    }
+    let settings = http.settings();
+    let text = &settings.emoji.expand(text);
+    settings.filters.pre_check(settings.filter_check, text, is_private(send_to)).map_err(PostErr::Filtered)?;
+    let mut form = match cached_post_box(settings, session) {
+        Some(form) => Some(form),
+        None => Some(post_box(http, full_url, session, room).await?),
+    };
+    let max_len = form.as_ref().and_then(|f| f.max_len).map_or(settings.max_message_len, |m| m.min(settings.max_message_len));
+    let parts = message_parts(text, &settings.multi_line, max_len, settings.max_message_parts)?;
+    Span::current().record("parts", parts.len());
+    for part in parts {
+        form = send_part(http, full_url, session, room, form, send_to, &part, false).await?;
+    }
+    if let Some(form) = &form {
+        cache_post_box(settings, session, form);
+    }
+    Ok(())
+}
+
+// One post with `form`'s fields, or a fresh post box's when `None`, sent
+// again once with a fresh one when refused for a stale form. `html` ticks
+// the post box's HTML checkbox. The post box the answer is, if it is one.
+#[allow(clippy::too_many_arguments)]
+async fn send_part<E: Exchange>(
+    http: &E,
+    full_url: &str,
+    session: &str,
+    room: Option<&str>,
+    mut form: Option<PostBox>,
+    send_to: &str,
+    part: &str,
+    html: bool,
+) -> Result<Option<PostBox>, PostErr> {
+    let mut refetched = false;
+    let view_url = messages::page_view_url(full_url, session, http.lang(), room);
+    let page = loop {
+        let PostBox { nc, postid, hidden, html: html_field, .. } = match form.take() {
+            Some(form) => form,
+            None => post_box(http, full_url, session, room).await?,
+        };
+        let mut params = vec![
+            ("action", "post".to_owned()),
+            ("session", session.to_owned()),
+            ("lang", http.lang().to_owned()),
+            ("nc", nc),
+            ("postid", postid),
+        ];
+        // A room of the form's own gives way to the one asked for
+        params.extend(hidden.iter().filter(|(name, _)| room.is_none() || name.as_str() != ROOM_PARAM).map(|(name, value)| (name.as_str(), value.clone())));
+        params.extend(room.map(|room| (ROOM_PARAM, room.to_owned())));
+        params.extend([("message", part.to_owned()), ("sendto", send_to.to_owned())]);
+        let html_field = html.then_some(html_field).flatten();
+        if let Some((name, value)) = &html_field {
+            params.push((name.as_str(), value.clone()));
+        } else if html {
+            return Err(PostErr::PermissionDenied);
+        }
+        let Some(page) = send_post_with(http, full_url, &view_url, part, &params).await? else {
+            // Its answer was lost, there is no post box to keep
+            sent::record(http.settings(), session, part);
+            return Ok(None);
+        };
+        spans::record_status(page.status);
+        match classify(page) {
+            Err(PostErr::StaleForm) if !refetched => refetched = true,
+            page => break page?,
+        }
+    };
+    sent::record(http.settings(), session, part);
+    // The answer is the post box again
+    Ok(post_box_fields(&page))
+}
+
+/// `html` without its `<script>`, `<style>` and `<iframe>` elements and
+/// what is in them. Not a full sanitizer: links and formatting, the rest of
+/// what staff post, are left as they are.
+pub fn sanitize_html(html: &str) -> String {
+    UNSAFE_ELEMENTS_RGX.iter().fold(html.to_owned(), |html, rgx| rgx.replace_all(&html, "").into_owned())
+}
+
+/// Post `html` to everyone as raw HTML, for staff sessions on servers
+/// whose post box has an HTML checkbox. Sanitized with `sanitize_html`
+/// unless `unsafe_raw`.
+pub fn post_html(transport: &Transport, base_url: &str, page_php: &str, session: &str, html: &str, unsafe_raw: bool) -> Result<(), PostErr> {
+    exchange::block_on(post_html_with(transport, base_url, page_php, session, html, unsafe_raw))
+}
+
+/// `post_html` over any `Exchange`. The session's nick must be listed as
+/// staff or admin and the post box must have the checkbox, otherwise
+/// nothing is sent. Never split, too long is `MessageTooLong`.
+pub async fn post_html_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    html: &str,
+    unsafe_raw: bool,
+) -> Result<(), PostErr> {
+    let html = if unsafe_raw { html.to_owned() } else { sanitize_html(html) };
+    if html.trim().is_empty() {
+        return Err(PostErr::EmptyMessage);
+    }
+    let nick = http.settings().mention.nick().ok_or(PostErr::PermissionDenied)?;
+    let users = users::fetch_online_users_with(http, base_url, page_php, session).await.map_err(|e| match e {
+        FetchErr::Kicked { reason } => PostErr::Kicked { reason },
+        FetchErr::SessionExpired => PostErr::SessionExpired,
+        FetchErr::ServerDown(status) => PostErr::ServerDown(status),
+        FetchErr::Send(e) => PostErr::Send(e),
+        _ => PostErr::PermissionDenied,
+    })?;
+    if !users.iter().any(|u| u.nick.eq_ignore_ascii_case(&nick) && u.role >= Role::Staff) {
+        return Err(PostErr::PermissionDenied);
+    }
+    let full_url = page_url(base_url, page_php);
+    let room = rooms::room(http.settings(), session);
+    // Always a fresh one, the checkbox decides
+    let form = post_box(http, &full_url, session, room.as_deref()).await?;
+    if form.html.is_none() {
+        return Err(PostErr::PermissionDenied);
+    }
+    let max_message_len = http.settings().max_message_len;
+    let max_len = form.max_len.map_or(max_message_len, |m| m.min(max_message_len));
+    if html.chars().count() > max_len {
+        return Err(PostErr::MessageTooLong { max_len, max_parts: 1 });
+    }
+    if let Some(form) = send_part(http, &full_url, session, room.as_deref(), Some(form), SEND_TO_ALL, &html, true).await? {
+        cache_post_box(http.settings(), session, &form);
+    }
+    Ok(())
+}
+
+// A nick, not one of the groups like `SEND_TO_ALL`
+fn is_private(send_to: &str) -> bool {
+    !(send_to.len() == SEND_TO_ALL.len() && send_to.starts_with("s "))
+}
+
+fn is_link(word: &str) -> bool {
+    word.contains("://") || word.to_ascii_lowercase().starts_with("www.")
+}
+
+// `text` in pieces of at most `room` characters, broken between words. A
+// longer word is cut between characters, `None` when it's a link.
+fn chunks(text: &str, room: usize) -> Option<Vec<String>> {
+    let mut chunks = vec![];
+    let mut chunk = String::new();
+    let mut len = 0;
+    let mut space = "";
+    for token in TOKEN_RGX.find_iter(text).map(|m| m.as_str()) {
+        if token.starts_with(char::is_whitespace) {
+            space = token;
+            continue;
+        }
+        let (space_len, word_len) = (space.chars().count(), token.chars().count());
+        if !chunk.is_empty() && len + space_len + word_len <= room {
+            chunk.push_str(space);
+            chunk.push_str(token);
+            len += space_len + word_len;
+            continue;
+        }
+        if !chunk.is_empty() {
+            chunks.push(mem::take(&mut chunk));
+        }
+        let mut word = token;
+        if word_len > room && is_link(word) {
+            return None;
+        }
+        while let Some((at, _)) = word.char_indices().nth(room) {
+            chunks.push(word[..at].to_owned());
+            word = &word[at..];
+        }
+        len = word.chars().count();
+        chunk = word.to_owned();
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    Some(chunks)
+}
+
+/// `text` as posts of at most `max_len` characters, itself when it fits.
+/// Otherwise numbered parts like `(1/3) ...`, broken between words and
+/// never inside a link, `/me ` kept in front of each.
+pub fn split_message(text: &str, max_len: usize, max_parts: usize) -> Result<Vec<String>, PostErr> {
+    if text.chars().count() <= max_len {
+        return Ok(vec![text.to_owned()]);
+    }
+    let too_long = || PostErr::MessageTooLong { max_len, max_parts };
+    let (lead, body) = text.strip_prefix(ACTION_PREFIX).map_or(("", text), |body| (ACTION_PREFIX, body));
+    // The numbering takes room from every part, as much as the widest
+    // count with as many digits
+    let mut widest = 9;
+    loop {
+        let taken = lead.len() + format!("({0}/{0}) ", widest).len();
+        let room = max_len.checked_sub(taken).filter(|&room| room > 0).ok_or_else(too_long)?;
+        let parts = chunks(body.trim(), room).ok_or_else(too_long)?;
+        if parts.len() > max_parts {
+            return Err(too_long());
+        }
+        if parts.len() <= widest {
+            let count = parts.len();
+            return Ok(parts.into_iter().enumerate().map(|(i, part)| format!("{}({}/{}) {}", lead, i + 1, count, part)).collect());
+        }
+        widest = widest * 10 + 9;
+    }
+}
+
+/// `text` as posts, its lines posted as `multi_line` says and each post
+/// split by `split_message`. `max_parts` caps the posts of the whole text,
+/// however many lines it has.
+pub fn message_parts(text: &str, multi_line: &MultiLine, max_len: usize, max_parts: usize) -> Result<Vec<String>, PostErr> {
+    let lines: Vec<_> = text.trim_end().lines().map(str::trim_end).collect();
+    if lines.len() <= 1 {
+        return split_message(text, max_len, max_parts);
+    }
+    let too_long = || PostErr::MessageTooLong { max_len, max_parts };
+    match multi_line {
+        MultiLine::Separator(separator) => split_message(&lines.join(separator), max_len, max_parts),
+        MultiLine::Separate => {
+            let mut parts = vec![];
+            for line in lines.into_iter().filter(|l| !l.trim().is_empty()) {
+                let left = max_parts.checked_sub(parts.len()).filter(|&left| left > 0).ok_or_else(too_long)?;
+                parts.extend(split_message(line, max_len, left).map_err(|_| too_long())?);
+            }
+            Ok(parts)
+        }
+    }
+}
+
+/// What the post box says about uploads: the file field's name, what it
+/// accepts and the form's own limit.
+#[derive(Debug, Clone, PartialEq)]
+struct UploadField {
+    name: String,
+    accept: Option<String>,
+    max_size: Option<u64>,
+    /// The form's hidden fields, `MAX_FILE_SIZE` included.
+    hidden: Vec<(String, String)>,
+}
+
+fn upload_field(page: &str) -> Option<UploadField> {
+    let doc = Document::from(page);
+    let form = doc.find(Name("form")).find(|f| f.find(Attr("type", "file")).next().is_some())?;
+    let file = form.find(Attr("type", "file")).next()?;
+    let hidden: Vec<_> = form
+        .find(Attr("type", "hidden"))
+        .filter_map(|i| Some((i.attr("name")?.to_owned(), i.attr("value").unwrap_or_default().to_owned())))
+        .collect();
+    let max_size = hidden.iter().find(|(name, _)| name == "MAX_FILE_SIZE").and_then(|(_, v)| v.parse().ok());
+    Some(UploadField { name: file.attr("name")?.to_owned(), accept: file.attr("accept").map(str::to_owned), max_size, hidden })
+}
+
+pub(super) fn mime_type(path: &Path) -> &'static str {
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_ascii_lowercase();
+    MIME_TYPES.iter().find(|(e, _)| *e == ext).map_or("application/octet-stream", |(_, mime)| mime)
+}
+
+// An `accept` attribute: `image/*`, `image/png` or `.png`, comma separated
+fn accepts(accept: &str, mime: &str, file_name: &str) -> bool {
+    accept.split(',').map(str::trim).filter(|a| !a.is_empty()).any(|a| {
+        if a.starts_with('.') {
+            file_name.to_ascii_lowercase().ends_with(&a.to_ascii_lowercase())
+        } else if let Some(kind) = a.strip_suffix("/*") {
+            mime.split('/').next() == Some(kind)
+        } else {
+            a.eq_ignore_ascii_case(mime)
+        }
+    })
+}
+
+// `1024 KB`, `2 MB`... in bytes
+fn size_in(text: &str) -> Option<u64> {
+    let c = SIZE_RGX.captures(text)?;
+    let n: u64 = c[1].parse().ok()?;
+    Some(match c[2].to_ascii_lowercase().as_str() {
+        "kb" | "kib" => n * 1024,
+        "mb" | "mib" => n * 1024 * 1024,
+        _ => n,
+    })
+}
+
+// The server's refusals of a file, in its notices like the others
+fn check_upload_response(doc: &Document, mime: &str) -> Result<(), PostErr> {
+    for notice in doc.find(Or(Or(Class("error"), Class("notice")), Name("h2"))) {
+        let text = notice.text();
+        if UPLOAD_SIZE_RGX.is_match(&text) {
+            return Err(PostErr::UploadTooLarge { max: size_in(&text) });
+        } else if UPLOAD_TYPE_RGX.is_match(&text) {
+            return Err(PostErr::UploadType { mime: mime.to_owned() });
+        }
+    }
+    Ok(())
+}
+
+/// Post `text` to everyone with the file at `file_path` attached, on
+/// servers whose post box takes files.
+pub fn post_with_upload(transport: &Transport, base_url: &str, page_php: &str, session: &str, text: &str, file_path: &Path) -> Result<(), PostErr> {
+    post_with_upload_to(transport, base_url, page_php, session, SEND_TO_ALL, text, file_path)
+}
+
+/// `post_with_upload` for `send_to`, a nick or a group.
+pub fn post_with_upload_to(
+    transport: &Transport,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    send_to: &str,
+    text: &str,
+    file_path: &Path,
+) -> Result<(), PostErr> {
+    exchange::block_on(post_with_upload_with(transport, base_url, page_php, session, send_to, text, file_path))
+}
+
+/// `post_with_upload_to` over any `Exchange`. Nothing is sent when the
+/// post box has no file field, or when the file is too large or of a type
+/// the field doesn't accept. The limit is the smaller of the client's, see
+/// `ClientConfig::max_upload_size`, and the form's `MAX_FILE_SIZE`.
+pub async fn post_with_upload_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    send_to: &str,
+    text: &str,
+    file_path: &Path,
+) -> Result<(), PostErr> {
+    let settings = http.settings();
+    let text = &settings.emoji.expand(text);
+    settings.filters.pre_check(settings.filter_check, text, is_private(send_to)).map_err(PostErr::Filtered)?;
+    let full_url = page_url(base_url, page_php);
+    let room = rooms::room(http.settings(), session);
+    let form_url = format!("{}?action=post&session={}&lang={}{}", full_url, session, http.lang(), rooms::query(room.as_deref()));
+    let form = classify(http.get(Operation::Post, &form_url).await?)?;
+    let field = upload_field(&form).ok_or(PostErr::UploadsDisabled)?;
+
+    let file = File::open(file_path).map_err(PostErr::File)?;
+    let len = file.metadata().map_err(PostErr::File)?.len();
+    let max = field.max_size.map_or(settings.max_upload_size, |m| m.min(settings.max_upload_size));
+    if len > max {
+        return Err(PostErr::UploadTooLarge { max: Some(max) });
+    }
+    let file_name = file_path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let mime = mime_type(file_path);
+    if field.accept.as_deref().is_some_and(|accept| !accepts(accept, mime, &file_name)) {
+        return Err(PostErr::UploadType { mime: mime.to_owned() });
+    }
+
+    let hidden = field.hidden.iter().filter(|(name, _)| room.is_none() || name.as_str() != ROOM_PARAM);
+    let mut params: Vec<_> = hidden.map(|(name, value)| (name.as_str(), value.clone())).collect();
+    params.extend(room.as_deref().map(|room| (ROOM_PARAM, room.to_owned())));
+    params.extend([("message", text.to_owned()), ("sendto", send_to.to_owned())]);
+    let upload = Upload { field: field.name, file_name, mime: mime.to_owned(), len, file };
+    let page = http.post_multipart(Operation::Post, &full_url, &params, upload).await?;
+    if page.status == StatusCode::PAYLOAD_TOO_LARGE {
+        return Err(PostErr::UploadTooLarge { max: None });
+    }
+    if page.status.is_server_error() {
+        return Err(PostErr::ServerDown(page.status));
+    }
+    let doc = Document::from(page.body?.as_str());
+    check_upload_response(&doc, mime)?;
+    check_post_response(&doc)
+}
+
+/// Which of our own messages to delete, the post box's two buttons.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Delete {
+    Last,
+    All,
+}
+
+impl Delete {
+    fn what(self) -> &'static str {
+        match self {
+            Delete::Last => "last",
+            Delete::All => "all",
+        }
+    }
+}
+
+// The fields of a form asking to confirm, answered yes
+fn confirmation(page: &str) -> Option<Vec<(String, String)>> {
+    let doc = Document::from(page);
+    let form = doc.find(Name("form")).find(|f| f.find(Attr("name", "confirm")).next().is_some())?;
+    let mut fields: Vec<_> = form
+        .find(Name("input"))
+        .filter_map(|i| Some((i.attr("name")?.to_owned(), i.attr("value").unwrap_or_default().to_owned())))
+        .filter(|(name, _)| name != "confirm")
+        .collect();
+    fields.push(("confirm".to_owned(), "yes".to_owned()));
+    Some(fields)
+}
+
+pub fn delete_last_message(transport: &Transport, base_url: &str, page_php: &str, session: &str) -> Result<(), PostErr> {
+    exchange::block_on(delete_messages_with(transport, base_url, page_php, session, Delete::Last))
+}
+
+pub fn delete_all_my_messages(transport: &Transport, base_url: &str, page_php: &str, session: &str) -> Result<(), PostErr> {
+    exchange::block_on(delete_messages_with(transport, base_url, page_php, session, Delete::All))
+}
+
+/// Delete our own messages like the post box's buttons. When the server
+/// asks to confirm, it is answered yes. Done once it's back to the post box.
+pub async fn delete_messages_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    which: Delete,
+) -> Result<(), PostErr> {
+    let full_url = page_url(base_url, page_php);
+    let PostBox { nc, .. } = post_box(http, &full_url, session, rooms::room(http.settings(), session).as_deref()).await?;
+    let params = [
+        ("action", "delete".to_owned()),
+        ("session", session.to_owned()),
+        ("lang", http.lang().to_owned()),
+        ("nc", nc),
+        ("what", which.what().to_owned()),
+    ];
+    let mut page = classify(http.post_form(Operation::Post, &full_url, &params).await?)?;
+    if let Some(fields) = confirmation(&page) {
+        let params: Vec<_> = fields.iter().map(|(name, value)| (name.as_str(), value.clone())).collect();
+        page = classify(http.post_form(Operation::Post, &full_url, &params).await?)?;
+    }
+    if post_box_fields(&page).is_none() || confirmation(&page).is_some() {
+        return Err(PostErr::NotAccepted);
+    }
+    if which == Delete::All {
+        sent::forget_all(http.settings(), session);
+    }
+    Ok(())
+}
+
+/// How long to wait before retrying a flooded post in place, `None` when
+/// it should be rescheduled instead.
+pub fn flood_retry_delay(err: &PostErr) -> Option<Duration> {
+    let PostErr::Flood { wait } = err else {
+        return None;
+    };
+    let wait = wait.unwrap_or(DEFAULT_FLOOD_WAIT);
+    (wait <= MAX_FLOOD_WAIT).then_some(wait)
+}
+
+// `nick - text` as the view prints a user message, `prefix` being `nick - `
+fn is_own(text: &str, prefix: &str, msg: &str) -> bool {
+    text.find(prefix).is_some_and(|idx| text[idx + prefix.len()..].trim() == msg.trim())
+}
+
+/// Whether `username` already posted `msg` among the latest messages of a
+/// view page, so a retried post isn't sent twice.
+pub fn message_delivered(view: &Document, username: &str, msg: &str) -> bool {
+    let messages = match view.find(Attr("id", "messages")).next() {
+        Some(messages) => messages,
+        None => return false,
+    };
+    let prefix = format!("{} - ", username);
+    messages
+        .find(Class("usermsg"))
+        .take(RECENT_MESSAGES)
+        .any(|span| is_own(&span.text(), &prefix, msg))
+}
+
+// `10-17 19:40:02 - ` with the default format
+fn parse_timestamp(text: &str, datetime_fmt: &str) -> Option<NaiveDateTime> {
+    let text = text.trim().trim_end_matches('-').trim_end();
+    NaiveDateTime::parse_from_str(&format!("{}-{}", TIMESTAMP_YEAR, text), &format!("%Y-{}", datetime_fmt)).ok()
+}
+
+/// Like `message_delivered`, but only when the message is at most `window`
+/// older than the newest one of the page. Times are the server's, which
+/// keeps its clock and time zone out of it. A match whose time can't be
+/// read counts.
+pub fn recently_posted(view: &Document, username: &str, msg: &str, window: Duration, datetime_fmt: &str) -> bool {
+    let messages = match view.find(Attr("id", "messages")).next() {
+        Some(messages) => messages,
+        None => return false,
+    };
+    let prefix = format!("{} - ", username);
+    let recent: Vec<_> = messages
+        .find(Class("msg"))
+        .take(RECENT_MESSAGES)
+        .map(|m| {
+            let time = m.find(Name("small")).next().and_then(|s| parse_timestamp(&s.text(), datetime_fmt));
+            let own = m.find(Class("usermsg")).next().is_some_and(|span| is_own(&span.text(), &prefix, msg));
+            (time, own)
+        })
+        .collect();
+    let newest = recent.iter().filter_map(|(time, _)| *time).max();
+    let window = chrono::Duration::from_std(window).unwrap_or(chrono::Duration::max_value());
+    recent.iter().any(|(time, own)| {
+        *own && match (newest, time) {
+            (Some(newest), Some(time)) => newest - *time <= window,
+            _ => true,
+        }
+    })
+}
+
+/// Looks at the view before resending a post whose response was lost, the
+/// server may have stored it anyway.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayGuard {
+    /// How recent our identical message must be to count as the lost post,
+    /// see `recently_posted`. `None` always resends.
+    pub window: Option<Duration>,
+    /// Timestamp format of the view, as `--datetime-fmt`.
+    pub datetime_fmt: String,
+}
+
+impl Default for ReplayGuard {
+    fn default() -> Self {
+        Self { window: Some(DEFAULT_REPLAY_WINDOW), datetime_fmt: DEFAULT_DATETIME_FMT.to_owned() }
+    }
+}
+
+impl ReplayGuard {
+    // Whether to send `msg` again after its post timed out: not when
+    // `view_url` shows the session's nick posted it
+    async fn should_resend<E: Exchange>(&self, http: &E, view_url: &str, msg: &str) -> bool {
+        let (Some(window), Some(me)) = (self.window, http.settings().mention.nick()) else {
+            return true;
+        };
+        let view = match http.get(Operation::Fetch, view_url).await.and_then(|page| page.body) {
+            Ok(view) => view,
+            // Can't tell, a double post beats a lost one
+            Err(e) => {
+                log::warn!("couldn't check for a delivered post: {}", e);
+                return true;
+            }
+        };
+        if recently_posted(&Document::from(view.as_str()), &me, msg, window, &self.datetime_fmt) {
+            log::warn!("post timed out but was delivered, not sending it again");
+            return false;
+        }
+        true
+    }
+}
+
+/// Send the post form `params` of `msg` to `full_url`. A post whose answer
+/// times out is sent again, at most `MAX_RESENDS` times, unless the view at
+/// `view_url` shows it went through, see `ReplayGuard`: `None` then, there
+/// is no answer to read.
+pub fn send_post(transport: &Transport, full_url: &str, view_url: &str, msg: &str, params: &[(&str, String)]) -> Result<Option<Page>, SendErr> {
+    exchange::block_on(send_post_with(transport, full_url, view_url, msg, params))
+}
+
+/// `send_post` over any `Exchange`.
+pub async fn send_post_with<E: Exchange>(
+    http: &E,
+    full_url: &str,
+    view_url: &str,
+    msg: &str,
+    params: &[(&str, String)],
+) -> Result<Option<Page>, SendErr> {
+    let mut resends = 0;
+    loop {
+        match http.post_form(Operation::Post, full_url, params).await {
+            Err(e) if e.is_timeout() && resends < MAX_RESENDS => {
+                if !http.settings().replay_guard.should_resend(http, view_url, msg).await {
+                    return Ok(None);
+                }
+                resends += 1;
+            }
+            page => return page.map(Some),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn check_post_response_test() {
+        let ok = Document::from(include_str!("fixtures/post_ok.html"));
+        assert!(check_post_response(&ok).is_ok());
+
+        let flood = Document::from(include_str!("fixtures/post_flood.html"));
+        let err = check_post_response(&flood).unwrap_err();
+        assert!(matches!(err, PostErr::Flood { wait: Some(wait) } if wait == Duration::from_secs(12)));
+        assert_eq!(flood_retry_delay(&err), Some(Duration::from_secs(12)));
+
+        let flood = Document::from(include_str!("fixtures/post_flood_no_number.html"));
+        let err = check_post_response(&flood).unwrap_err();
+        assert!(matches!(err, PostErr::Flood { wait: None }));
+        assert_eq!(flood_retry_delay(&err), Some(DEFAULT_FLOOD_WAIT));
+
+        let err = PostErr::Flood { wait: Some(Duration::from_secs(120)) };
+        assert_eq!(flood_retry_delay(&err), None);
+    }
+
+    #[test]
+    fn post_message_test() {
+        use crate::lechatphp::mock::{MockExchange, MockRequest, MockResponse};
+
+        const BASE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+        const FORM: &str = include_str!("fixtures/post_ok.html");
+        let notice = |text: &str| format!(r#"<html><body class="error"><h2>Error: {}</h2></body></html>"#, text);
+        // The post form on GET, `post` answers the message
+        type Answer = Box<dyn Fn(&MockRequest) -> Result<MockResponse, SendErr>>;
+        let chat = |post: Answer| {
+            MockExchange::new(move |req| if req.method == "GET" { Ok(MockResponse::ok(FORM)) } else { post(req) })
+        };
+        let post = |http: &MockExchange, text: &str| exchange::block_on(post_message_with(http, BASE_URL, "chat.php", "abc", text));
+
+        let http = chat(Box::new(|_| Ok(MockResponse::ok(FORM))));
+        post(&http, "hello there").unwrap();
+        let requests = http.requests.borrow();
+        assert_eq!(requests[0].path, "/chat.php?action=post&session=abc&lang=en");
+        assert_eq!(requests[1].path, "/chat.php");
+        assert_eq!(
+            requests[1].body,
+            "action=post&session=abc&lang=en&nc=123456&postid=a1b2c3&message=hello there&sendto=s *"
+        );
+        let http = chat(Box::new(|_| Ok(MockResponse::ok(FORM))));
+        let whisper = ChatCommand::parse("/pm bob /me is here");
+        exchange::block_on(post_message_with(&http, BASE_URL, "chat.php", "abc", ChatCommand::Action("waves".to_owned()))).unwrap();
+        exchange::block_on(post_message_with(&http, BASE_URL, "chat.php", "abc", whisper)).unwrap();
+        let bodies: Vec<_> = http.requests.borrow().iter().skip(1).step_by(2).map(|r| r.body.clone()).collect();
+        assert!(bodies[0].ends_with("&message=/me waves&sendto=s *"));
+        assert!(bodies[1].ends_with("&message=/me is here&sendto=bob"));
+
+        // What answers, what it must fail with
+        type Case = (MockExchange, fn(&PostErr) -> bool);
+        let kicked = notice("You have been kicked! Reason: spam");
+        let cases: Vec<Case> = vec![
+            (chat(Box::new(move |_| Ok(MockResponse::ok(&kicked)))), |e| {
+                matches!(e, PostErr::Kicked { reason: Some(r) } if r == "spam")
+            }),
+            (MockExchange::new(move |_| Ok(MockResponse::ok(&notice("You have been kicked!")))), |e| {
+                matches!(e, PostErr::Kicked { reason: None })
+            }),
+            (MockExchange::new(move |_| Ok(MockResponse::ok(&notice("Invalid/expired session")))), |e| {
+                matches!(e, PostErr::SessionExpired)
+            }),
+            (chat(Box::new(move |_| Ok(MockResponse::ok(&notice("Message too long, 1000 characters max"))))), |e| {
+                matches!(e, PostErr::TooLong { max: Some(1000) })
+            }),
+            (chat(Box::new(|_| Ok(MockResponse::ok(include_str!("fixtures/post_flood.html"))))), |e| {
+                matches!(e, PostErr::Flood { wait: Some(_) })
+            }),
+            (chat(Box::new(|_| Ok(MockResponse::new(502, "Bad Gateway")))), |e| {
+                matches!(e, PostErr::ServerDown(StatusCode::BAD_GATEWAY))
+            }),
+            (MockExchange::new(|_| Ok(MockResponse::ok("<html></html>"))), |e| matches!(e, PostErr::NoPostForm)),
+            (MockExchange::new(|_| Err(SendErr::ResponseTooLarge { limit: 1 })), |e| {
+                matches!(e, PostErr::Send(SendErr::ResponseTooLarge { limit: 1 }))
+            }),
+        ];
+        for (i, (http, expected)) in cases.iter().enumerate() {
+            let err = post(http, "hello").unwrap_err();
+            assert!(expected(&err), "case {}: unexpected {:?}", i, err);
+        }
+
+        // Whispers
+        let http = chat(Box::new(|_| Ok(MockResponse::ok(FORM))));
+        exchange::block_on(post_private_with(&http, BASE_URL, "chat.php", "abc", "dark knight", "psst")).unwrap();
+        assert!(http.requests.borrow()[1].body.ends_with("&message=psst&sendto=dark knight"));
+        let http = chat(Box::new(move |_| Ok(MockResponse::ok(&notice("dark knight is no longer online.")))));
+        let err = exchange::block_on(post_private_with(&http, BASE_URL, "chat.php", "abc", "dark knight", "psst"));
+        assert!(matches!(err, Err(PostErr::RecipientOffline)), "unexpected {:?}", err);
+
+        // Never sent
+        let http = chat(Box::new(|_| Ok(MockResponse::ok(FORM))));
+        assert!(matches!(post(&http, " \n\t"), Err(PostErr::EmptyMessage)));
+        assert!(http.requests.borrow().is_empty());
+
+        // Longer than the form's `maxlength`, in parts with the fields of
+        // the post box each answer is
+        let limited = FORM.replace(r#"<textarea name="message">"#, r#"<textarea name="message" maxlength="20">"#);
+        let http = MockExchange::new(move |_| Ok(MockResponse::ok(&limited)));
+        post(&http, "one two three four five six").unwrap();
+        let requests = http.requests.borrow();
+        let messages: Vec<_> = requests.iter().map(|r| r.body.split("&message=").nth(1).unwrap_or_default().to_owned()).collect();
+        assert_eq!(messages, ["", "(1/2) one two three&sendto=s *", "(2/2) four five six&sendto=s *"]);
+        assert!(requests[2].body.contains("&nc=123456&postid=a1b2c3&"));
+    }
+
+    #[test]
+    fn post_box_cache_test() {
+        use crate::lechatphp::mock::{MockExchange, MockResponse};
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        const BASE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+        const STALE: &str = r#"<html><body class="error"><h2>Error: Invalid token</h2></body></html>"#;
+        let session = "post-box-test";
+        // A fork's post box with a per-session token, refusing posts with
+        // any other than its current one
+        let token = Rc::new(Cell::new(1));
+        let server_token = token.clone();
+        let http = MockExchange::new(move |req| {
+            let token = server_token.get();
+            let field = format!(r#"<input type="hidden" name="token" value="t{}"><input type="hidden" name="postid""#, token);
+            let form = include_str!("fixtures/post_ok.html").replace(r#"<input type="hidden" name="postid""#, &field);
+            let valid = req.method == "GET" || req.body.contains(&format!("&token=t{}&", token));
+            Ok(MockResponse::ok(if valid { &form } else { STALE }))
+        });
+        let post = |text: &str| exchange::block_on(post_message_with(&http, BASE_URL, "chat.php", session, text));
+        let methods = || http.requests.borrow().iter().map(|r| r.method.clone()).collect::<Vec<_>>();
+
+        // Fetched once for the session
+        track_post_box(&http.settings, session);
+        for text in ["one", "two", "three"] {
+            post(text).unwrap();
+        }
+        assert_eq!(methods(), ["GET", "POST", "POST", "POST"]);
+        assert!(http.requests.borrow()[3].body.ends_with("&nc=123456&postid=a1b2c3&token=t1&message=three&sendto=s *"));
+
+        // Stale mid-session: fetched again, and the post sent again
+        token.set(2);
+        post("four").unwrap();
+        assert_eq!(methods()[4..], ["POST", "GET", "POST"]);
+        assert!(http.requests.borrow()[6].body.contains("&token=t2&message=four&"));
+        post("five").unwrap();
+        assert_eq!(methods().len(), 8);
+
+        // Only once, from the cached post box to a fresh one
+        let stale = MockExchange::new(|req| Ok(MockResponse::ok(if req.method == "GET" { include_str!("fixtures/post_ok.html") } else { STALE })));
+        track_post_box(&stale.settings, session);
+        cache_post_box(&stale.settings, session, &post_box_fields(include_str!("fixtures/post_ok.html")).unwrap());
+        let err = exchange::block_on(post_message_with(&stale, BASE_URL, "chat.php", session, "six"));
+        assert!(matches!(err, Err(PostErr::StaleForm)), "unexpected {:?}", err);
+        assert_eq!(stale.requests.borrow().iter().map(|r| r.method.as_str()).collect::<Vec<_>>(), ["POST", "GET", "POST"]);
+
+        // After logout, every post fetches it
+        untrack_post_box(&http.settings, session);
+        http.requests.borrow_mut().clear();
+        for _ in 0..2 {
+            post("seven").unwrap();
+        }
+        assert_eq!(methods(), ["GET", "POST", "GET", "POST"]);
+    }
+
+    #[test]
+    fn split_message_test() {
+        let split = |text: &str, max_len, max_parts| split_message(text, max_len, max_parts);
+        assert_eq!(split("hello", 5, 3).unwrap(), ["hello"]);
+        assert_eq!(split("aaa bbb ccc ddd", 13, 3).unwrap(), ["(1/2) aaa bbb", "(2/2) ccc ddd"]);
+        // Line breaks stay, the space at a break goes
+        assert_eq!(split("line one\nline two\nline three", 20, 3).unwrap(), ["(1/2) line one\nline", "(2/2) two\nline three"]);
+        assert_eq!(split("/me waves at everyone here", 23, 3).unwrap(), ["/me (1/2) waves at", "/me (2/2) everyone here"]);
+
+        // Links move to the next part whole, one that can't fit isn't sent
+        let parts = split("see http://example.onion/a/b now", 30, 3).unwrap();
+        assert_eq!(parts, ["(1/3) see", "(2/3) http://example.onion/a/b", "(3/3) now"]);
+        assert!(matches!(split("see www.example.com/a/b/c/d/e/f now", 20, 5), Err(PostErr::MessageTooLong { max_len: 20, max_parts: 5 })));
+        // Other words are cut
+        assert_eq!(split(&"x".repeat(20), 12, 5).unwrap(), ["(1/4) xxxxxx", "(2/4) xxxxxx", "(3/4) xxxxxx", "(4/4) xx"]);
+
+        // Characters, not bytes, and never half of one
+        assert_eq!(split("日本語テキストです", 9, 3).unwrap(), ["日本語テキストです"]);
+        let parts = split("日本語テキストです", 8, 5).unwrap();
+        assert_eq!(parts, ["(1/5) 日本", "(2/5) 語テ", "(3/5) キス", "(4/5) トで", "(5/5) す"]);
+        let text = "ça été très ünïcödé 🦀🦀🦀🦀🦀 déjà vu";
+        for max_len in 9..text.chars().count() {
+            let parts = split(text, max_len, 40).unwrap();
+            assert!(parts.iter().all(|p| p.chars().count() <= max_len), "{}: {:?}", max_len, parts);
+            let words: String = parts.iter().map(|p| p.split_once(") ").unwrap().1.replace(' ', "")).collect();
+            assert_eq!(words, text.replace(' ', ""), "{}", max_len);
+        }
+
+        // The numbering widens past 9 parts, up to the cap
+        let words = ["word"; 12].join(" ");
+        let parts = split(&words, 12, 20).unwrap();
+        assert_eq!((parts.len(), parts[0].as_str(), parts[11].as_str()), (12, "(1/12) word", "(12/12) word"));
+        assert!(matches!(split(&words, 12, 11), Err(PostErr::MessageTooLong { .. })));
+        assert!(matches!(split("too long", 6, 3), Err(PostErr::MessageTooLong { .. })));
+    }
+
+    #[test]
+    fn message_parts_test() {
+        let separate = |text: &str, max_len, max_parts| message_parts(text, &MultiLine::Separate, max_len, max_parts);
+        assert_eq!(separate("one line", 20, 3).unwrap(), ["one line"]);
+        assert_eq!(separate("line one\r\n\nline two\n", 20, 3).unwrap(), ["line one", "line two"]);
+        assert_eq!(separate("/me waves\nat you", 20, 3).unwrap(), ["/me waves", "at you"]);
+        // A long line is split and numbered on its own, the parts of all
+        // lines count against the cap
+        let text = "short\naaa bbb ccc ddd\nend";
+        assert_eq!(separate(text, 13, 4).unwrap(), ["short", "(1/2) aaa bbb", "(2/2) ccc ddd", "end"]);
+        assert!(matches!(separate(text, 13, 3), Err(PostErr::MessageTooLong { max_len: 13, max_parts: 3 })));
+        assert!(matches!(separate("a\nb\nc\nd", 13, 3), Err(PostErr::MessageTooLong { max_len: 13, max_parts: 3 })));
+
+        let separator = MultiLine::Separator(" ⏎ ".to_owned());
+        let joined = |text: &str, max_len, max_parts| message_parts(text, &separator, max_len, max_parts);
+        assert_eq!(joined("line one\n\nline two", 40, 3).unwrap(), ["line one ⏎  ⏎ line two"]);
+        // The separator counts toward the length, a part may end on it
+        assert_eq!(joined("aaa bbb\nccc ddd", 15, 3).unwrap(), ["(1/2) aaa bbb ⏎", "(2/2) ccc ddd"]);
+        assert!(matches!(joined("aaa bbb\nccc ddd\neee", 15, 2), Err(PostErr::MessageTooLong { .. })));
+    }
+
+    #[test]
+    fn delete_messages_test() {
+        use crate::lechatphp::mock::{MockExchange, MockResponse};
+
+        const BASE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+        const FORM: &str = include_str!("fixtures/post_ok.html");
+        const CONFIRM: &str = r#"<html><body><h2>Are you sure?</h2><form action="chat.php" method="post"><input type="hidden" name="action" value="delete"><input type="hidden" name="session" value="abc"><input type="hidden" name="lang" value="en"><input type="hidden" name="nc" value="654321"><input type="hidden" name="what" value="all"><input type="submit" name="confirm" value="Yes"></form></body></html>"#;
+        let chat = || {
+            MockExchange::new(|req| match req.method.as_str() {
+                "GET" => Ok(MockResponse::ok(FORM)),
+                _ if req.body.contains("what=all") && !req.body.contains("confirm=yes") => Ok(MockResponse::ok(CONFIRM)),
+                _ => Ok(MockResponse::ok(FORM)),
+            })
+        };
+        let delete = |http: &MockExchange, which| exchange::block_on(delete_messages_with(http, BASE_URL, "chat.php", "abc", which));
+
+        let http = chat();
+        delete(&http, Delete::Last).unwrap();
+        assert_eq!(http.requests.borrow()[1].body, "action=delete&session=abc&lang=en&nc=123456&what=last");
+
+        // The confirmation is followed, with its own fields
+        let http = chat();
+        delete(&http, Delete::All).unwrap();
+        let requests = http.requests.borrow();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[2].body, "action=delete&session=abc&lang=en&nc=654321&what=all&confirm=yes");
+
+        let kicked = MockExchange::new(|req| match req.method.as_str() {
+            "GET" => Ok(MockResponse::ok(FORM)),
+            _ => Ok(MockResponse::ok(r#"<body class="error"><h2>Error: You have been kicked!</h2></body>"#)),
+        });
+        assert!(matches!(delete(&kicked, Delete::Last), Err(PostErr::Kicked { reason: None })));
+        let expired = MockExchange::new(|_| Ok(MockResponse::ok(r#"<body class="error"><h2>Error: Invalid/expired session</h2></body>"#)));
+        assert!(matches!(delete(&expired, Delete::All), Err(PostErr::SessionExpired)));
+        let ignored = MockExchange::new(|req| match req.method.as_str() {
+            "GET" => Ok(MockResponse::ok(FORM)),
+            _ => Ok(MockResponse::ok("<html><body>?</body></html>")),
+        });
+        assert!(matches!(delete(&ignored, Delete::Last), Err(PostErr::NotAccepted)));
+    }
+
+    #[test]
+    fn post_html_test() {
+        use crate::lechatphp::mock::{MockExchange, MockResponse};
+
+        const BASE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+        const USERS: &str = include_str!("fixtures/users_grouped.html");
+        let staff_form = include_str!("fixtures/post_ok.html")
+            .replace(r#"<input type="submit""#, r#"<input type="checkbox" name="html" value="1"><input type="submit""#);
+        // The user list on the view, the post box on the rest
+        let chat = |users: String, form: String| {
+            let http = MockExchange::new(move |req| {
+                Ok(MockResponse::ok(if req.path.contains("action=view") { &users } else { &form }))
+            });
+            http.settings.mention.set_nick("zed");
+            http
+        };
+        let post = |http: &MockExchange, html: &str, unsafe_raw: bool| {
+            exchange::block_on(post_html_with(http, BASE_URL, "chat.php", "html-test", html, unsafe_raw))
+        };
+        let http = chat(USERS.replace("mod one", "zed"), staff_form.clone());
+        post(&http, r#"<b>hi</b><script>alert(1)</script>"#, false).unwrap();
+        let requests = http.requests.borrow();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[2].body.ends_with("&message=<b>hi</b>&sendto=s *&html=1"));
+        drop(requests);
+        post(&http, "<script>alert(1)</script>", true).unwrap();
+        assert!(http.requests.borrow()[5].body.contains("&message=<script>alert(1)</script>&"));
+        assert!(matches!(post(&http, "<style>b {}</style> ", false), Err(PostErr::EmptyMessage)));
+
+        // A guest, or staff without the checkbox: only looked, nothing posted
+        let guest = chat(USERS.replace("night owl", "zed"), staff_form);
+        let no_checkbox = chat(USERS.replace("mod one", "zed"), include_str!("fixtures/post_ok.html").to_owned());
+        for http in [guest, no_checkbox] {
+            assert!(matches!(post(&http, "<b>hi</b>", false), Err(PostErr::PermissionDenied)));
+            assert!(http.requests.borrow().iter().all(|r| r.method == "GET"));
+        }
+    }
+
+    #[test]
+    fn sanitize_html_test() {
+        let html = r#"<b>a</b><SCRIPT src="x.js"></SCRIPT><i>b</i><style>
+            body { display: none }
+        </style><iframe src="http://evil.onion/">frame</iframe> <a href="http://example.onion/">c</a><script>open"#;
+        assert_eq!(sanitize_html(html), r#"<b>a</b><i>b</i> <a href="http://example.onion/">c</a>open"#);
+        assert_eq!(sanitize_html("<p>scripts & styles</p>"), "<p>scripts & styles</p>");
+    }
+
+    #[test]
+    fn message_delivered_test() {
+        let view = Document::from(include_str!("fixtures/view.html"));
+        assert!(message_delivered(&view, "alice", "hello everyone"));
+        assert!(!message_delivered(&view, "alice", "hello"));
+        assert!(!message_delivered(&view, "bob", "hello everyone"));
+
+        // 7s older than the newest message
+        assert!(recently_posted(&view, "bob", "hi alice", DEFAULT_REPLAY_WINDOW, DEFAULT_DATETIME_FMT));
+        assert!(!recently_posted(&view, "bob", "hi alice", Duration::from_secs(5), DEFAULT_DATETIME_FMT));
+        assert!(!recently_posted(&view, "bob", "hello everyone", DEFAULT_REPLAY_WINDOW, DEFAULT_DATETIME_FMT));
+    }
+
+    #[test]
+    fn replay_guard_test() {
+        use crate::lechatphp::client::{self, ClientConfig, ProxySetting};
+        use crate::lechatphp::mock::{MockResponse, MockServer};
+        use std::sync::Arc;
+        use std::thread;
+
+        for (window, sent) in [(Some(DEFAULT_REPLAY_WINDOW), 1), (None, 2)] {
+            // Stores every post, but the first answer comes too late
+            let posted = Arc::new(Mutex::new(Vec::<String>::new()));
+            let server = MockServer::start_keep_alive({
+                let posted = Arc::clone(&posted);
+                move |req| {
+                    if req.method == "POST" {
+                        let first = {
+                            let mut posted = posted.lock().unwrap();
+                            posted.push(req.body.trim_start_matches("message=").to_owned());
+                            posted.len() == 1
+                        };
+                        if first {
+                            thread::sleep(Duration::from_millis(800));
+                        }
+                        return MockResponse::ok("");
+                    }
+                    let now = chrono::Local::now().format(DEFAULT_DATETIME_FMT);
+                    let messages: String = posted
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .rev()
+                        .map(|m| {
+                            format!(r#"<div class="msg"><small>{} - </small><span class="usermsg">alice - {}</span></div>"#, now, m)
+                        })
+                        .collect();
+                    MockResponse::ok(&format!(r#"<div id="messages">{}</div>"#, messages))
+                }
+            });
+            let config = ClientConfig {
+                proxy: ProxySetting::Direct,
+                target_url: Some(server.url.clone()),
+                read_timeout: Duration::from_millis(300),
+                rate_limit: None,
+                replay_guard: ReplayGuard { window, ..Default::default() },
+                ..Default::default()
+            };
+            let transport = client::build(&config).unwrap();
+            transport.settings().mention.set_nick("alice");
+            let full_url = format!("{}/chat.php", server.url);
+            let view_url = format!("{}?action=view", full_url);
+            let page = send_post(&transport, &full_url, &view_url, "hello", &[("message", "hello".to_owned())]).unwrap();
+            // Delivered the first time, its answer lost
+            assert_eq!(page.is_some(), window.is_none());
+            assert_eq!(posted.lock().unwrap().len(), sent, "{:?}", window);
+        }
+    }
+
+    #[test]
+    fn post_with_upload_test() {
+        use crate::lechatphp::mock::{MockResponse, MockServer};
+
+        const FORM: &str = include_str!("fixtures/post_upload.html");
+        let dir = std::env::temp_dir().join(format!("bhcli-upload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let shot = dir.join("shot.png");
+        std::fs::write(&shot, "\u{89}PNG fake image").unwrap();
+        // The post box `form`, then `answer` to the upload, which is kept
+        let server = |form: &'static str, answer: &'static str| {
+            let posted = std::sync::Arc::new(Mutex::new(None));
+            let kept = std::sync::Arc::clone(&posted);
+            let server = MockServer::start(move |req| match req.method.as_str() {
+                "GET" => MockResponse::ok(form),
+                _ => {
+                    *kept.lock().unwrap() = Some((req.header("content-type").unwrap_or_default(), req.body.clone()));
+                    MockResponse::ok(answer)
+                }
+            });
+            (server, posted)
+        };
+        let upload = |server: &MockServer, path: &Path| {
+            post_with_upload_to(&Transport::direct(), &server.url, "chat.php", "abc", "s *", "look", path)
+        };
+
+        let (ok, posted) = server(FORM, include_str!("fixtures/post_ok.html"));
+        upload(&ok, &shot).unwrap();
+        let (content_type, body) = posted.lock().unwrap().take().unwrap();
+        let boundary = content_type.strip_prefix("multipart/form-data; boundary=").unwrap();
+        let parts: Vec<_> = body.split(&format!("--{}", boundary)).map(str::trim).filter(|p| !p.is_empty() && *p != "--").collect();
+        let names: Vec<_> = parts.iter().map(|p| p.split('"').nth(1).unwrap()).collect();
+        assert_eq!(names, ["lang", "nc", "action", "session", "postid", "MAX_FILE_SIZE", "message", "sendto", "file"]);
+        assert!(parts[6].ends_with("look") && parts[7].ends_with("s *"));
+        let file = parts[8];
+        assert!(file.starts_with("Content-Disposition: form-data; name=\"file\"; filename=\"shot.png\""));
+        assert!(file.contains("Content-Type: image/png") && file.ends_with("PNG fake image"));
+
+        // Refused before anything is sent
+        let (disabled, _) = server(include_str!("fixtures/post_ok.html"), "");
+        assert!(matches!(upload(&disabled, &shot), Err(PostErr::UploadsDisabled)));
+        let notes = dir.join("notes.txt");
+        std::fs::write(&notes, "text").unwrap();
+        assert!(matches!(upload(&disabled, &notes), Err(PostErr::UploadsDisabled)));
+        let (typed, posted) = server(FORM, "");
+        assert!(matches!(upload(&typed, &notes), Err(PostErr::UploadType { mime }) if mime == "text/plain"));
+        let big = dir.join("big.png");
+        std::fs::write(&big, vec![0u8; 65537]).unwrap();
+        assert!(matches!(upload(&typed, &big), Err(PostErr::UploadTooLarge { max: Some(65536) })));
+        assert!(matches!(upload(&typed, &dir.join("missing.png")), Err(PostErr::File(_))));
+        assert!(posted.lock().unwrap().is_none());
+
+        // The server's own refusals
+        let (too_large, _) = server(FORM, r#"<body class="error"><h2>Error: File too large, maximum upload size is 32 KB</h2></body>"#);
+        assert!(matches!(upload(&too_large, &shot), Err(PostErr::UploadTooLarge { max: Some(32768) })));
+        let (wrong_type, _) = server(FORM, r#"<body class="error"><h2>Error: This file type is not allowed</h2></body>"#);
+        assert!(matches!(upload(&wrong_type, &shot), Err(PostErr::UploadType { .. })));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
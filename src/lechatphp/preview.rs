@@ -0,0 +1,205 @@
+use super::charset;
+use super::messages::{self, Message};
+use super::transport::Transport;
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use reqwest::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use reqwest::Url;
+use select::document::Document;
+use select::predicate::Name;
+use std::collections::HashMap;
+use std::io::Read;
+use std::thread;
+use std::time::Duration;
+
+/// Longest title told, in characters.
+const MAX_TITLE_LEN: usize = 200;
+
+/// Link previews of a `MessageStream`, see `StreamConfig::previews`. Each
+/// preview is a request to a site someone posted, through the session's
+/// proxy.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PreviewConfig {
+    /// Most of a page read for its title, in bytes.
+    pub max_bytes: usize,
+    pub timeout: Duration,
+    /// Only links to these domains and their subdomains when not empty.
+    pub allow: Vec<String>,
+    /// Never links to these domains and their subdomains.
+    pub deny: Vec<String>,
+}
+
+impl Default for PreviewConfig {
+    fn default() -> Self {
+        Self { max_bytes: 64 * 1024, timeout: Duration::from_secs(15), allow: vec![], deny: vec![] }
+    }
+}
+
+impl PreviewConfig {
+    /// Whether `url` may be fetched, an http(s) link to a domain allowed.
+    pub fn allows(&self, url: &str) -> bool {
+        let Some(host) = Url::parse(url).ok().filter(|u| matches!(u.scheme(), "http" | "https")).and_then(|u| u.host_str().map(str::to_lowercase)) else {
+            return false;
+        };
+        let listed = |domains: &[String]| {
+            domains.iter().map(|d| d.trim().trim_start_matches('.').to_lowercase()).any(|d| host == d || host.ends_with(&format!(".{}", d)))
+        };
+        !listed(&self.deny) && (self.allow.is_empty() || listed(&self.allow))
+    }
+}
+
+/// A link's preview, for the message it was in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkPreview {
+    pub message_id: Option<u64>,
+    pub url: String,
+    pub title: String,
+}
+
+// A page's title, or what the link is when not a page
+fn describe(content_type: Option<&str>, size: Option<u64>, body: &[u8]) -> Option<String> {
+    let mime = content_type.and_then(|c| c.split(';').next()).map(|m| m.trim().to_lowercase());
+    if mime.as_deref().is_none_or(|m| m == "text/html" || m == "application/xhtml+xml") {
+        let doc = Document::from(charset::decode(body, content_type).as_str());
+        let title = doc.find(Name("title")).next()?.text();
+        let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+        return match title.char_indices().nth(MAX_TITLE_LEN) {
+            _ if title.is_empty() => None,
+            Some((at, _)) => Some(format!("{}…", &title[..at])),
+            None => Some(title),
+        };
+    }
+    let mime = mime.unwrap_or_default();
+    Some(match size {
+        Some(size) if size >= 1024 * 1024 => format!("{}, {:.1} MB", mime, size as f64 / (1024.0 * 1024.0)),
+        Some(size) if size >= 1024 => format!("{}, {} KB", mime, size / 1024),
+        Some(size) => format!("{}, {} bytes", mime, size),
+        None => mime,
+    })
+}
+
+// Only as much of the page as needed, a title comes early
+fn fetch(transport: &Transport, config: &PreviewConfig, url: &str) -> Option<String> {
+    let resp = transport.send_once(transport.get(url).timeout(config.timeout)).map_err(|e| log::debug!("no preview of {}: {}", url, e)).ok()?;
+    if !resp.status().is_success() {
+        return None;
+    }
+    let header = |name| resp.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_owned);
+    let content_type = header(CONTENT_TYPE);
+    let size = header(CONTENT_LENGTH).and_then(|l| l.parse().ok());
+    let html = content_type.as_deref().is_none_or(|c| c.contains("html"));
+    let mut body = vec![];
+    if html {
+        resp.take(config.max_bytes as u64).read_to_end(&mut body).ok()?;
+    }
+    describe(content_type.as_deref(), size, &body)
+}
+
+enum Fetch {
+    /// The messages waiting for it.
+    Running(Vec<Option<u64>>),
+    Done(Option<String>),
+}
+
+/// Fetches the previews of a stream's links one at a time on a thread of
+/// its own, each url once for the stream.
+pub struct Previewer {
+    config: PreviewConfig,
+    fetches: HashMap<String, Fetch>,
+    urls: Sender<String>,
+    results: Receiver<(String, Option<String>)>,
+}
+
+impl Previewer {
+    pub fn new(transport: &Transport, config: PreviewConfig) -> Self {
+        let (urls, jobs) = unbounded::<String>();
+        let (done, results) = unbounded();
+        let (transport, worker_config) = (transport.clone(), config.clone());
+        // Ends with the previewer
+        thread::spawn(move || {
+            for url in jobs {
+                let title = fetch(&transport, &worker_config, &url);
+                if done.send((url, title)).is_err() {
+                    return;
+                }
+            }
+        });
+        Self { config, fetches: HashMap::new(), urls, results }
+    }
+
+    /// The previews of `message`'s links known already, the others are
+    /// fetched.
+    pub fn request(&mut self, message: &Message) -> Vec<LinkPreview> {
+        let mut ready = vec![];
+        for href in messages::links(&message.spans) {
+            if !self.config.allows(href) {
+                continue;
+            }
+            match self.fetches.get_mut(href) {
+                Some(Fetch::Done(Some(title))) => ready.push(LinkPreview { message_id: message.id, url: href.to_owned(), title: title.clone() }),
+                Some(Fetch::Done(None)) => {}
+                Some(Fetch::Running(waiting)) => waiting.push(message.id),
+                None => {
+                    self.fetches.insert(href.to_owned(), Fetch::Running(vec![message.id]));
+                    let _ = self.urls.send(href.to_owned());
+                }
+            }
+        }
+        ready
+    }
+
+    /// Where the fetched previews arrive, to be given to `finish`.
+    pub fn results(&self) -> &Receiver<(String, Option<String>)> {
+        &self.results
+    }
+
+    /// The previews for the messages that waited on `url`.
+    pub fn finish(&mut self, url: String, title: Option<String>) -> Vec<LinkPreview> {
+        let waiting = match self.fetches.insert(url.clone(), Fetch::Done(title.clone())) {
+            Some(Fetch::Running(waiting)) => waiting,
+            _ => vec![],
+        };
+        let Some(title) = title else {
+            return vec![];
+        };
+        waiting.into_iter().map(|message_id| LinkPreview { message_id, url: url.clone(), title: title.clone() }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_test() {
+        let config = PreviewConfig { deny: vec!["bad.onion".to_owned()], ..PreviewConfig::default() };
+        assert!(config.allows("http://good.onion/page"));
+        assert!(config.allows("https://Good.Onion"));
+        assert!(!config.allows("http://bad.onion/x"));
+        assert!(!config.allows("http://www.BAD.onion/x"));
+        assert!(config.allows("http://notbad.onion/x"));
+        assert!(!config.allows("ftp://good.onion/file"));
+        assert!(!config.allows("javascript:alert(1)"));
+
+        let config = PreviewConfig { allow: vec![".wiki.onion".to_owned()], ..config };
+        assert!(config.allows("http://wiki.onion/a"));
+        assert!(config.allows("http://en.wiki.onion/a"));
+        assert!(!config.allows("http://good.onion/a"));
+    }
+
+    #[test]
+    fn describe_test() {
+        let html = |body: &str| describe(Some("text/html; charset=utf-8"), None, body.as_bytes());
+        assert_eq!(html("<html><head><title>\n  Hidden &amp; Wiki </title></head></html>").as_deref(), Some("Hidden & Wiki"));
+        assert_eq!(html("<html><head><title></title></head></html>"), None);
+        assert_eq!(html("<html><body>no title</body></html>"), None);
+        // Cut short by the size cap, and too long
+        assert_eq!(html("<html><head><title>Cut").as_deref(), Some("Cut"));
+        let long = html(&format!("<title>{}</title>", "é".repeat(300))).unwrap();
+        assert_eq!(long.chars().count(), MAX_TITLE_LEN + 1);
+        assert_eq!(describe(None, None, b"<title>No type</title>").as_deref(), Some("No type"));
+
+        assert_eq!(describe(Some("image/png"), Some(12 * 1024 + 5), b"").as_deref(), Some("image/png, 12 KB"));
+        assert_eq!(describe(Some("application/zip"), Some(3 * 1024 * 1024 / 2), b"").as_deref(), Some("application/zip, 1.5 MB"));
+        assert_eq!(describe(Some("text/plain"), None, b"").as_deref(), Some("text/plain"));
+    }
+}
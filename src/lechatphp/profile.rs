@@ -0,0 +1,760 @@
+use super::error_kind::{Classify, ErrorKind};
+use super::exchange::{self, Exchange};
+use super::messages::{self, fetch_page, read_page, FetchErr};
+use super::metrics::Operation;
+use super::page_url;
+use super::transport::Transport;
+use super::{login_with, LoginErr};
+use lazy_static::lazy_static;
+use regex::Regex;
+use select::document::Document;
+use select::node::Node;
+use select::predicate::{Attr, Name, Text};
+use serde_derive::{Deserialize, Serialize};
+use std::fmt::{Debug, Formatter};
+use thiserror::Error;
+
+/// The fields `Profile` has its own members for.
+const KNOWN_FIELDS: &[&str] = &[
+    "refresh", "colour", "bgcolour", "font", "bold", "italic", "small", "timestamps", "embed", "hidechatters", "tz", IGNORE, UNIGNORE,
+];
+/// The select of nicks to ignore, those in the chat.
+const IGNORE: &str = "ignore";
+/// The select of the nicks ignored, to take one back.
+const UNIGNORE: &str = "unignore";
+
+lazy_static! {
+    static ref IGNORE_REFUSED_RGX: Regex = Regex::new(r"(?i)(cannot|can ?not|can't|not allowed to|may not) ignore").unwrap();
+    static ref WRONG_PASS_RGX: Regex =
+        Regex::new(r"(?i)wrong (old |current )?password|(old|current) password (is )?(wrong|incorrect)|incorrect password").unwrap();
+    static ref NICK_TAKEN_RGX: Regex = Regex::new(r"(?i)(nick(name)?|name) (is )?(already )?(taken|in use|registered)").unwrap();
+    static ref REJECTED_RGX: Regex = Regex::new(
+        r"(?i)password[^.\n]*(too short|at least|too weak|not strong|don't match|do not match|not match)|invalid nick(name)?|nick(name)? (is )?(invalid|too long|not allowed)"
+    )
+    .unwrap();
+    static ref LOGIN_AGAIN_RGX: Regex = Regex::new(r"(?i)log ?in again|re-?login").unwrap();
+}
+
+const OLD_PASS_FIELD: &str = "oldpass";
+const NEW_PASS_FIELD: &str = "newpass";
+/// The new password again, not every fork asks for it.
+const CONFIRM_PASS_FIELD: &str = "confirmpass";
+const NICK_FIELD: &str = "newnickname";
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ProfileErr {
+    #[error("{0}")]
+    Fetch(#[from] FetchErr),
+    /// No form saving the profile in the page.
+    #[error("no profile form in the page")]
+    NoProfileForm,
+    /// A change to a field this fork's form doesn't have.
+    #[error("the profile has no {0} field")]
+    NoField(&'static str),
+    /// The form came back without the change, e.g. a value out of range.
+    #[error("profile not saved")]
+    NotSaved,
+    /// The server wouldn't ignore this nick, staff can't be.
+    #[error("cannot ignore {nick}")]
+    CannotIgnore { nick: String },
+    /// The old password given to change it is not the one.
+    #[error("wrong password")]
+    WrongPassword,
+    #[error("nickname {nick} is taken")]
+    NickTaken { nick: String },
+    /// The server's notice, e.g. a password too short.
+    #[error("rejected: {reason}")]
+    Rejected { reason: String },
+    /// Changed, but logging in again after failed.
+    #[error("changed, but logging in again failed: {0}")]
+    Relogin(#[source] LoginErr),
+}
+
+impl Classify for ProfileErr {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            ProfileErr::Fetch(e) => e.kind(),
+            ProfileErr::Relogin(e) => e.kind(),
+            ProfileErr::CannotIgnore { .. } => ErrorKind::PermissionDenied,
+            ProfileErr::NoField(_)
+            | ProfileErr::NotSaved
+            | ProfileErr::WrongPassword
+            | ProfileErr::NickTaken { .. }
+            | ProfileErr::Rejected { .. } => ErrorKind::InvalidInput,
+            ProfileErr::NoProfileForm => ErrorKind::Fatal,
+        }
+    }
+}
+
+/// The settings of the profile form. Fields are `None` when the fork's
+/// form doesn't have them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Profile {
+    /// Seconds between reloads of the view.
+    pub refresh: Option<u32>,
+    /// `#RRGGBB`.
+    pub colour: Option<String>,
+    pub bgcolour: Option<String>,
+    /// The font's code, empty for the room's default.
+    pub font: Option<String>,
+    pub bold: Option<bool>,
+    pub italic: Option<bool>,
+    pub small: Option<bool>,
+    pub timestamps: Option<bool>,
+    /// Show images inline.
+    pub embed: Option<bool>,
+    pub hidechatters: Option<bool>,
+    pub tz: Option<String>,
+    /// The nicks ignored, `None` when the fork has no ignore feature.
+    pub ignored: Option<Vec<String>>,
+    /// The rest of the form as it would be sent, hidden and password
+    /// fields aside.
+    pub other: Vec<(String, String)>,
+}
+
+/// What to change with `update_profile`, everything left `None` keeps its
+/// current value.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProfileChanges {
+    pub refresh: Option<u32>,
+    pub colour: Option<String>,
+    pub bgcolour: Option<String>,
+    pub font: Option<String>,
+    pub bold: Option<bool>,
+    pub italic: Option<bool>,
+    pub small: Option<bool>,
+    pub timestamps: Option<bool>,
+    pub embed: Option<bool>,
+    pub hidechatters: Option<bool>,
+    pub tz: Option<String>,
+}
+
+enum Change {
+    Value(String),
+    Checked(bool),
+}
+
+impl ProfileChanges {
+    fn changes(&self) -> Vec<(&'static str, Change)> {
+        let values = [
+            ("refresh", self.refresh.map(|r| r.to_string())),
+            ("colour", self.colour.clone()),
+            ("bgcolour", self.bgcolour.clone()),
+            ("font", self.font.clone()),
+            ("tz", self.tz.clone()),
+        ];
+        let checks = [
+            ("bold", self.bold),
+            ("italic", self.italic),
+            ("small", self.small),
+            ("timestamps", self.timestamps),
+            ("embed", self.embed),
+            ("hidechatters", self.hidechatters),
+        ];
+        let values = values.into_iter().filter_map(|(name, v)| Some((name, Change::Value(v?))));
+        let checks = checks.into_iter().filter_map(|(name, c)| Some((name, Change::Checked(c?))));
+        values.chain(checks).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Kind {
+    /// Text, number, select... Always sent.
+    Value,
+    /// Hidden and password inputs, sent as they are.
+    Internal,
+    /// Checkboxes and radio buttons, sent when checked.
+    Check(bool),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Field {
+    name: String,
+    value: String,
+    kind: Kind,
+}
+
+// The value a select is submitted with
+fn selected(select: &Node) -> String {
+    let options: Vec<_> = select.find(Name("option")).collect();
+    let option = options.iter().find(|o| o.attr("selected").is_some()).or(options.first());
+    option.map(|o| o.attr("value").map(str::to_owned).unwrap_or_else(|| o.text())).unwrap_or_default()
+}
+
+fn find_form(doc: &Document) -> Option<Node<'_>> {
+    doc.find(Name("form")).find(|f| {
+        f.find(Attr("name", "action")).any(|i| i.attr("value") == Some("profile"))
+            && f.find(Attr("name", "do")).any(|i| i.attr("value") == Some("save"))
+    })
+}
+
+// The nicks the unignore select offers, its `(choose)` option aside
+fn ignored_nicks(doc: &Document) -> Option<Vec<String>> {
+    let select = find_form(doc)?.find(Name("select")).find(|s| s.attr("name") == Some(UNIGNORE))?;
+    let nicks = select.find(Name("option")).filter_map(|o| o.attr("value")).filter(|v| !v.is_empty()).map(str::to_owned).collect();
+    Some(nicks)
+}
+
+// The profile form's fields in order, as a browser would read them
+fn profile_form(doc: &Document) -> Option<Vec<Field>> {
+    let form = find_form(doc)?;
+    let fields = form
+        .descendants()
+        .filter_map(|node| {
+            let name = node.attr("name")?.to_owned();
+            let (value, kind) = match node.name()? {
+                "select" => (selected(&node), Kind::Value),
+                "textarea" => (node.text(), Kind::Value),
+                "input" => {
+                    let value = node.attr("value").unwrap_or_default().to_owned();
+                    match node.attr("type").unwrap_or("text").to_ascii_lowercase().as_str() {
+                        "submit" | "button" | "image" | "reset" | "file" => return None,
+                        "checkbox" | "radio" => {
+                            // Browsers send `on` for a checkbox without a value
+                            let value = node.attr("value").unwrap_or("on").to_owned();
+                            (value, Kind::Check(node.attr("checked").is_some()))
+                        }
+                        "hidden" | "password" => (value, Kind::Internal),
+                        _ => (value, Kind::Value),
+                    }
+                }
+                _ => return None,
+            };
+            Some(Field { name, value, kind })
+        })
+        .collect();
+    Some(fields)
+}
+
+// What the form submits: unchecked boxes are left out
+fn submitted(fields: &[Field]) -> impl Iterator<Item = &Field> {
+    fields.iter().filter(|f| f.kind != Kind::Check(false))
+}
+
+fn to_profile(fields: &[Field]) -> Profile {
+    let value = |name: &str| submitted(fields).find(|f| f.name == name && f.kind == Kind::Value).map(|f| f.value.clone());
+    let checked = |name: &str| {
+        fields.iter().find_map(|f| match f.kind {
+            Kind::Check(checked) if f.name == name => Some(checked),
+            _ => None,
+        })
+    };
+    let other = submitted(fields)
+        .filter(|f| f.kind != Kind::Internal && !KNOWN_FIELDS.contains(&f.name.as_str()))
+        .map(|f| (f.name.clone(), f.value.clone()))
+        .collect();
+    Profile {
+        refresh: value("refresh").and_then(|r| r.trim().parse().ok()),
+        colour: value("colour"),
+        bgcolour: value("bgcolour"),
+        font: value("font"),
+        bold: checked("bold"),
+        italic: checked("italic"),
+        small: checked("small"),
+        timestamps: checked("timestamps"),
+        embed: checked("embed"),
+        hidechatters: checked("hidechatters"),
+        tz: value("tz"),
+        ignored: None,
+        other,
+    }
+}
+
+fn apply(fields: &mut [Field], changes: &ProfileChanges) -> Result<(), ProfileErr> {
+    for (name, change) in changes.changes() {
+        let mut found = false;
+        for field in fields.iter_mut().filter(|f| f.name == name) {
+            match (&change, &mut field.kind) {
+                (Change::Value(value), Kind::Value) => field.value = value.clone(),
+                (Change::Checked(checked), Kind::Check(c)) => *c = *checked,
+                _ => continue,
+            }
+            found = true;
+        }
+        if !found {
+            return Err(ProfileErr::NoField(name));
+        }
+    }
+    Ok(())
+}
+
+fn profile_url(base_url: &str, page_php: &str, session: &str, lang: &str) -> String {
+    format!("{}?action=profile&session={}&lang={}", page_url(base_url, page_php), session, lang)
+}
+
+// The form's fields and the nicks ignored
+async fn fetch_form<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+) -> Result<(Vec<Field>, Option<Vec<String>>), ProfileErr> {
+    let doc = fetch_page(http, &profile_url(base_url, page_php, session, http.lang())).await?;
+    let fields = profile_form(&doc).ok_or(ProfileErr::NoProfileForm)?;
+    Ok((fields, ignored_nicks(&doc)))
+}
+
+// Submit `fields`, the server answers with the form again showing what
+// it kept
+async fn save_form<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    fields: &[Field],
+) -> Result<Document, ProfileErr> {
+    let params: Vec<_> = submitted(fields).map(|f| (f.name.as_str(), f.value.clone())).collect();
+    let page = http.post_form(Operation::Fetch, &page_url(base_url, page_php), &params).await.map_err(FetchErr::from)?;
+    Ok(read_page(page)?)
+}
+
+/// The profile settings as the form currently shows them.
+pub fn get_profile(transport: &Transport, base_url: &str, page_php: &str, session: &str) -> Result<Profile, ProfileErr> {
+    exchange::block_on(get_profile_with(transport, base_url, page_php, session))
+}
+
+/// `get_profile` over any `Exchange`.
+pub async fn get_profile_with<E: Exchange>(http: &E, base_url: &str, page_php: &str, session: &str) -> Result<Profile, ProfileErr> {
+    let (fields, ignored) = fetch_form(http, base_url, page_php, session).await?;
+    Ok(Profile { ignored, ..to_profile(&fields) })
+}
+
+/// Save `changes` by submitting the profile form with everything else,
+/// fields this module doesn't know of included, as it was. Returns the
+/// profile the server shows after saving.
+pub fn update_profile(
+    transport: &Transport,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    changes: ProfileChanges,
+) -> Result<Profile, ProfileErr> {
+    exchange::block_on(update_profile_with(transport, base_url, page_php, session, changes))
+}
+
+/// `update_profile` over any `Exchange`.
+pub async fn update_profile_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    changes: ProfileChanges,
+) -> Result<Profile, ProfileErr> {
+    let (mut fields, _) = fetch_form(http, base_url, page_php, session).await?;
+    apply(&mut fields, &changes)?;
+    let doc = save_form(http, base_url, page_php, &fields).await?;
+    let saved = profile_form(&doc).ok_or(ProfileErr::NoProfileForm)?;
+    let mut expected = saved.clone();
+    apply(&mut expected, &changes)?;
+    if submitted(&expected).ne(submitted(&saved)) {
+        return Err(ProfileErr::NotSaved);
+    }
+    Ok(Profile { ignored: ignored_nicks(&doc), ..to_profile(&saved) })
+}
+
+/// The nicks ignored. Also handed to `messages::set_ignored`, so fetched
+/// messages leave them out.
+pub fn get_ignored(transport: &Transport, base_url: &str, page_php: &str, session: &str) -> Result<Vec<String>, ProfileErr> {
+    exchange::block_on(get_ignored_with(transport, base_url, page_php, session))
+}
+
+/// `get_ignored` over any `Exchange`.
+pub async fn get_ignored_with<E: Exchange>(http: &E, base_url: &str, page_php: &str, session: &str) -> Result<Vec<String>, ProfileErr> {
+    let (_, ignored) = fetch_form(http, base_url, page_php, session).await?;
+    let ignored = ignored.ok_or(ProfileErr::NoField(UNIGNORE))?;
+    messages::set_ignored(http.settings(), ignored.clone());
+    Ok(ignored)
+}
+
+/// Ignore `nick`, the rest of the profile saved as it was. Returns the
+/// nicks now ignored.
+pub fn ignore(transport: &Transport, base_url: &str, page_php: &str, session: &str, nick: &str) -> Result<Vec<String>, ProfileErr> {
+    exchange::block_on(set_ignored_with(transport, base_url, page_php, session, nick, true))
+}
+
+/// Stop ignoring `nick`. Returns the nicks still ignored.
+pub fn unignore(transport: &Transport, base_url: &str, page_php: &str, session: &str, nick: &str) -> Result<Vec<String>, ProfileErr> {
+    exchange::block_on(set_ignored_with(transport, base_url, page_php, session, nick, false))
+}
+
+/// `ignore`, or `unignore` when not `ignored`, over any `Exchange`.
+pub async fn set_ignored_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    nick: &str,
+    ignored: bool,
+) -> Result<Vec<String>, ProfileErr> {
+    let (mut fields, current) = fetch_form(http, base_url, page_php, session).await?;
+    let current = current.ok_or(ProfileErr::NoField(UNIGNORE))?;
+    if current.iter().any(|n| n == nick) == ignored {
+        messages::set_ignored(http.settings(), current.clone());
+        return Ok(current);
+    }
+    let name = if ignored { IGNORE } else { UNIGNORE };
+    let field = fields.iter_mut().find(|f| f.name == name && f.kind == Kind::Value).ok_or(ProfileErr::NoField(name))?;
+    // Not only those in the chat can be ignored, the select's options aside
+    field.value = nick.to_owned();
+    let doc = save_form(http, base_url, page_php, &fields).await?;
+    let body = doc.find(Name("body")).next().map(|b| b.text()).unwrap_or_default();
+    if ignored && IGNORE_REFUSED_RGX.is_match(&body) {
+        return Err(ProfileErr::CannotIgnore { nick: nick.to_owned() });
+    }
+    let saved = ignored_nicks(&doc).ok_or(ProfileErr::NoProfileForm)?;
+    if saved.iter().any(|n| n == nick) != ignored {
+        // Staff can't be ignored, some forks just leave them out
+        return Err(if ignored { ProfileErr::CannotIgnore { nick: nick.to_owned() } } else { ProfileErr::NotSaved });
+    }
+    messages::set_ignored(http.settings(), saved.clone());
+    Ok(saved)
+}
+
+/// The session after a change of nickname or password.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionAfter {
+    Kept,
+    /// Ended by the server, to log in again with what was changed.
+    Ended,
+    /// Ended, and logged in again: the new session.
+    Renewed(String),
+}
+
+/// What to log in again with when a change ends the session, as it was
+/// before the change.
+#[derive(Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+    pub color: String,
+    pub manual_captcha: bool,
+}
+
+// Never the password
+impl Debug for Credentials {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials").field("username", &self.username).field("password", &"***").finish_non_exhaustive()
+    }
+}
+
+// The text on the page saying why
+fn notice(doc: &Document, rgx: &Regex) -> Option<String> {
+    doc.find(Text).map(|t| t.text()).find(|t| rgx.is_match(t)).map(|t| t.trim().to_owned())
+}
+
+// Submit the profile with `values` in the fields of these names, and tell
+// whether the server ended the session for it
+async fn save_credentials<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    values: &[(&'static str, &str)],
+) -> Result<bool, ProfileErr> {
+    let (mut fields, _) = fetch_form(http, base_url, page_php, session).await?;
+    for &(name, value) in values {
+        match fields.iter_mut().find(|f| f.name == name && f.kind != Kind::Check(false)) {
+            Some(field) => field.value = value.to_owned(),
+            None if name == CONFIRM_PASS_FIELD => {}
+            None => return Err(ProfileErr::NoField(name)),
+        }
+    }
+    let doc = match save_form(http, base_url, page_php, &fields).await {
+        Err(ProfileErr::Fetch(FetchErr::SessionExpired)) => return Ok(true),
+        doc => doc?,
+    };
+    let text = doc.find(Name("body")).next().map(|b| b.text()).unwrap_or_default();
+    if WRONG_PASS_RGX.is_match(&text) {
+        return Err(ProfileErr::WrongPassword);
+    }
+    if let Some(reason) = notice(&doc, &REJECTED_RGX).or_else(|| notice(&doc, &NICK_TAKEN_RGX)) {
+        return Err(ProfileErr::Rejected { reason });
+    }
+    if profile_form(&doc).is_some() {
+        return Ok(false);
+    }
+    // Some forks say so instead of the form
+    if LOGIN_AGAIN_RGX.is_match(&text) {
+        return Ok(true);
+    }
+    Err(ProfileErr::NoProfileForm)
+}
+
+async fn after_change<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    ended: bool,
+    relogin: Option<Credentials>,
+) -> Result<SessionAfter, ProfileErr> {
+    match (ended, relogin) {
+        (false, _) => Ok(SessionAfter::Kept),
+        (true, None) => Ok(SessionAfter::Ended),
+        (true, Some(c)) => {
+            let session = login_with(http, base_url, page_php, &c.username, &c.password, &c.color, c.manual_captcha).await;
+            Ok(SessionAfter::Renewed(session.map_err(ProfileErr::Relogin)?))
+        }
+    }
+}
+
+/// Change the password from `old` to `new`. Some forks end the session
+/// for it: with `relogin`, the login is done again with the new password.
+pub fn change_password(
+    transport: &Transport,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    old: &str,
+    new: &str,
+    relogin: Option<&Credentials>,
+) -> Result<SessionAfter, ProfileErr> {
+    exchange::block_on(change_password_with(transport, base_url, page_php, session, old, new, relogin))
+}
+
+/// `change_password` over any `Exchange`.
+pub async fn change_password_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    old: &str,
+    new: &str,
+    relogin: Option<&Credentials>,
+) -> Result<SessionAfter, ProfileErr> {
+    let values = [(OLD_PASS_FIELD, old), (NEW_PASS_FIELD, new), (CONFIRM_PASS_FIELD, new)];
+    let ended = save_credentials(http, base_url, page_php, session, &values).await?;
+    let relogin = relogin.map(|c| Credentials { password: new.to_owned(), ..c.clone() });
+    after_change(http, base_url, page_php, ended, relogin).await
+}
+
+/// Change the nickname, on forks whose profile allows it. Like
+/// `change_password`, logs in again with `relogin` if the session ends.
+pub fn change_nickname(
+    transport: &Transport,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    new_nick: &str,
+    relogin: Option<&Credentials>,
+) -> Result<SessionAfter, ProfileErr> {
+    exchange::block_on(change_nickname_with(transport, base_url, page_php, session, new_nick, relogin))
+}
+
+/// `change_nickname` over any `Exchange`.
+pub async fn change_nickname_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    new_nick: &str,
+    relogin: Option<&Credentials>,
+) -> Result<SessionAfter, ProfileErr> {
+    let ended = match save_credentials(http, base_url, page_php, session, &[(NICK_FIELD, new_nick)]).await {
+        Err(ProfileErr::Rejected { reason }) if NICK_TAKEN_RGX.is_match(&reason) => {
+            return Err(ProfileErr::NickTaken { nick: new_nick.to_owned() })
+        }
+        ended => ended?,
+    };
+    let relogin = relogin.map(|c| Credentials { username: new_nick.to_owned(), ..c.clone() });
+    after_change(http, base_url, page_php, ended, relogin).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lechatphp::mock::{MockExchange, MockResponse};
+
+    const BASE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+    const FORM: &str = include_str!("fixtures/profile.html");
+    const SUBMITTED: &str = "lang=en&nc=582910&action=profile&do=save&session=abc&refresh=20&colour=#FF8800&bgcolour=#000000&font=c&bold=on&timestamps=on&tz=Europe/Berlin&sortupdown=0&statusmsg=away, back soon & then some&signature=-- \nnight owl&autoscroll=1&oldpass=&newpass=&confirmpass=";
+
+    fn pairs(list: &[(&str, &str)]) -> Vec<(String, String)> {
+        list.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn get_profile_test() {
+        let http = MockExchange::new(|_| Ok(MockResponse::ok(FORM)));
+        let profile = exchange::block_on(get_profile_with(&http, BASE_URL, "chat.php", "abc")).unwrap();
+        let expected = Profile {
+            refresh: Some(20),
+            colour: Some("#FF8800".to_owned()),
+            bgcolour: Some("#000000".to_owned()),
+            font: Some("c".to_owned()),
+            bold: Some(true),
+            italic: Some(false),
+            small: Some(false),
+            timestamps: Some(true),
+            embed: Some(false),
+            hidechatters: Some(false),
+            tz: Some("Europe/Berlin".to_owned()),
+            ignored: None,
+            other: pairs(&[
+                ("sortupdown", "0"),
+                ("statusmsg", "away, back soon & then some"),
+                ("signature", "-- \nnight owl"),
+                ("autoscroll", "1"),
+            ]),
+        };
+        assert_eq!(profile, expected);
+        assert_eq!(http.requests.borrow()[0].path, "/chat.php?action=profile&session=abc&lang=en");
+        // Kept as settings, where fields may be left out
+        assert_eq!(serde_json::from_str::<Profile>(&serde_json::to_string(&profile).unwrap()).unwrap(), profile);
+        let changes = serde_json::from_str::<ProfileChanges>(r#"{"refresh":30,"bold":false}"#).unwrap();
+        assert_eq!(changes, ProfileChanges { refresh: Some(30), bold: Some(false), ..Default::default() });
+        assert_eq!(serde_json::from_str::<ProfileChanges>(&serde_json::to_string(&changes).unwrap()).unwrap(), changes);
+
+        let login = MockExchange::new(|_| Ok(MockResponse::ok(r#"<form><input name="nick"><input name="pass"></form>"#)));
+        let res = exchange::block_on(get_profile_with(&login, BASE_URL, "chat.php", "abc"));
+        assert!(matches!(res, Err(ProfileErr::Fetch(FetchErr::SessionExpired))));
+    }
+
+    #[test]
+    fn update_profile_test() {
+        // Shows `saved` after a save, like the server with what it kept
+        let server = |saved: String| {
+            MockExchange::new(move |req| match req.method.as_str() {
+                "GET" => Ok(MockResponse::ok(FORM)),
+                _ => Ok(MockResponse::ok(&saved)),
+            })
+        };
+        let update = |http: &MockExchange, changes| exchange::block_on(update_profile_with(http, BASE_URL, "chat.php", "abc", changes));
+
+        // Nothing is dropped nor blanked
+        let http = server(FORM.to_owned());
+        let profile = update(&http, ProfileChanges::default()).unwrap();
+        assert_eq!(http.requests.borrow()[1].body, SUBMITTED);
+        assert_eq!(profile.other.len(), 4);
+
+        let saved = FORM
+            .replace(r#"max="150" value="20""#, r#"max="150" value="30""#)
+            .replace(r#"id="timestamps" value="on" checked"#, r#"id="timestamps" value="on""#)
+            .replace(r#"id="embed" value="on""#, r#"id="embed" value="on" checked"#);
+        let http = server(saved);
+        let changes = ProfileChanges { refresh: Some(30), timestamps: Some(false), embed: Some(true), ..Default::default() };
+        let profile = update(&http, changes.clone()).unwrap();
+        let expected = SUBMITTED.replace("refresh=20", "refresh=30").replace("&timestamps=on", "").replace("&tz=", "&embed=on&tz=");
+        assert_eq!(http.requests.borrow()[1].body, expected);
+        assert_eq!((profile.refresh, profile.timestamps, profile.embed), (Some(30), Some(false), Some(true)));
+
+        // The server kept the old values
+        let http = server(FORM.to_owned());
+        assert!(matches!(update(&http, changes), Err(ProfileErr::NotSaved)));
+        // A fork without the field, nothing is sent
+        let http = MockExchange::new(|_| Ok(MockResponse::ok(&FORM.replace("name=\"tz\"", "name=\"zone\""))));
+        let changes = ProfileChanges { tz: Some("UTC".to_owned()), ..Default::default() };
+        assert!(matches!(update(&http, changes), Err(ProfileErr::NoField("tz"))));
+        assert_eq!(http.requests.borrow().len(), 1);
+    }
+
+    #[test]
+    fn ignore_test() {
+        const IGNORE_FORM: &str = include_str!("fixtures/profile_ignore.html");
+        let server = |saved: String| {
+            MockExchange::new(move |req| match req.method.as_str() {
+                "GET" => Ok(MockResponse::ok(IGNORE_FORM)),
+                _ => Ok(MockResponse::ok(&saved)),
+            })
+        };
+        let set = |http: &MockExchange, nick, ignored| {
+            exchange::block_on(set_ignored_with(http, BASE_URL, "chat.php", "abc", nick, ignored))
+        };
+
+        let http = server(String::new());
+        let ignored = exchange::block_on(get_ignored_with(&http, BASE_URL, "chat.php", "abc")).unwrap();
+        assert_eq!(ignored, ["spammer42"]);
+        assert_eq!(messages::ignored(&http.settings), ["spammer42"]);
+        let profile = exchange::block_on(get_profile_with(&http, BASE_URL, "chat.php", "abc")).unwrap();
+        assert_eq!(profile.ignored, Some(vec!["spammer42".to_owned()]));
+        assert_eq!(profile.other.len(), 4);
+
+        // The rest of the profile goes along unchanged
+        let saved = IGNORE_FORM.replace(r#"</option></select> Unignore"#, r#"</option><option value="troll">troll</option></select> Unignore"#);
+        let http = server(saved);
+        assert_eq!(set(&http, "troll", true).unwrap(), ["spammer42", "troll"]);
+        let body = SUBMITTED.replace("&sortupdown=0", "&sortupdown=0&unignore=&ignore=troll");
+        assert_eq!(http.requests.borrow()[1].body, body);
+        // Already ignored, nothing to save
+        let http = server(String::new());
+        set(&http, "spammer42", true).unwrap();
+        assert_eq!(http.requests.borrow().len(), 1);
+
+        let refused = IGNORE_FORM.replace("<i></i>", "<i>You cannot ignore staff members.</i>");
+        let http = server(refused);
+        assert!(matches!(set(&http, "mod one", true), Err(ProfileErr::CannotIgnore { nick }) if nick == "mod one"));
+
+        let saved = IGNORE_FORM.replace(r#"<option value="spammer42" style="color:#00FF00;">spammer42</option>"#, "");
+        let http = server(saved);
+        assert!(set(&http, "spammer42", false).unwrap().is_empty());
+        assert!(http.requests.borrow()[1].body.contains("&unignore=spammer42&ignore=&"));
+        assert!(messages::ignored(&http.settings).is_empty());
+
+        // A fork without the feature
+        let http = MockExchange::new(|_| Ok(MockResponse::ok(FORM)));
+        assert!(matches!(set(&http, "troll", true), Err(ProfileErr::NoField("unignore"))));
+    }
+    #[test]
+    fn change_password_test() {
+        let server = |saved: String| {
+            MockExchange::new(move |req| match (req.method.as_str(), req.body.contains("action=login")) {
+                ("GET", _) if req.path.contains("action=profile") => Ok(MockResponse::ok(FORM)),
+                ("GET", _) => Ok(MockResponse::ok(r#"<html><body><form><input name="nick"></form></body></html>"#)),
+                (_, true) => {
+                    Ok(MockResponse::ok(r#"<html><body><iframe name="view" src="chat.php?action=view&session=def&lang=en"></iframe></body></html>"#))
+                }
+                _ => Ok(MockResponse::ok(&saved)),
+            })
+        };
+        let change = |http: &MockExchange, relogin| {
+            exchange::block_on(change_password_with(http, BASE_URL, "chat.php", "abc", "old secret", "new secret", relogin))
+        };
+        let saved = FORM.replace("<i></i>", "<i>Your profile has successfully been saved.</i>");
+        let http = server(saved);
+        assert_eq!(change(&http, None).unwrap(), SessionAfter::Kept);
+        let body = SUBMITTED.replace("oldpass=&newpass=&confirmpass=", "oldpass=old secret&newpass=new secret&confirmpass=new secret");
+        assert_eq!(http.requests.borrow()[1].body, body);
+
+        let wrong = FORM.replace("<i></i>", "<i>Wrong password!</i>");
+        let http = server(wrong);
+        assert!(matches!(change(&http, None), Err(ProfileErr::WrongPassword)));
+        let short = FORM.replace("<i></i>", "<i>Your new password is too short.</i>");
+        let http = server(short);
+        assert!(matches!(change(&http, None), Err(ProfileErr::Rejected { reason }) if reason == "Your new password is too short."));
+
+        // The session ends with the change, logged in again with the new
+        // password when the credentials are given
+        let expired = r#"<body class="error"><h2>Error: Invalid/expired session</h2></body>"#;
+        let http = server(expired.to_owned());
+        assert_eq!(change(&http, None).unwrap(), SessionAfter::Ended);
+        let credentials = Credentials { username: "alice".to_owned(), password: "old secret".to_owned(), color: "#FF0000".to_owned(), manual_captcha: false };
+        assert!(!format!("{:?}", credentials).contains("secret"));
+        let http = server(expired.to_owned());
+        assert_eq!(change(&http, Some(&credentials)).unwrap(), SessionAfter::Renewed("def".to_owned()));
+        let login = http.requests.borrow().last().unwrap().body.clone();
+        assert!(login.contains("nick=alice") && login.contains("pass=new secret"), "{}", login);
+
+        // The nickname, on a fork that has the field
+        const NICK_FORM: &str = r#"<html><body><h2>Your profile</h2><i></i><form action="chat.php" method="post"><input type="hidden" name="action" value="profile"><input type="hidden" name="do" value="save"><input type="hidden" name="session" value="abc"><input type="text" name="newnickname" value="alice"></form></body></html>"#;
+        let nick_server = |saved: &str| {
+            let saved = saved.to_owned();
+            MockExchange::new(move |req| match (req.method.as_str(), req.body.contains("action=login")) {
+                ("GET", _) => Ok(MockResponse::ok(NICK_FORM)),
+                (_, true) => Ok(MockResponse::ok(r#"<html><body><iframe name="view" src="chat.php?action=view&session=ghi&lang=en"></iframe></body></html>"#)),
+                _ => Ok(MockResponse::ok(&saved)),
+            })
+        };
+        let rename = |http: &MockExchange, relogin| exchange::block_on(change_nickname_with(http, BASE_URL, "chat.php", "abc", "bob", relogin));
+        let http = nick_server(NICK_FORM);
+        assert_eq!(rename(&http, None).unwrap(), SessionAfter::Kept);
+        assert_eq!(http.requests.borrow()[1].body, "action=profile&do=save&session=abc&newnickname=bob");
+        let http = nick_server(r#"<html><body><h2>Your profile</h2><i>Nickname is already taken.</i><form><input name="newnickname" value="alice"></form></body></html>"#);
+        assert!(matches!(rename(&http, None), Err(ProfileErr::NickTaken { nick }) if nick == "bob"));
+        let http = nick_server(r#"<html><body><p>Your nickname was changed, please log in again.</p></body></html>"#);
+        assert_eq!(rename(&http, Some(&credentials)).unwrap(), SessionAfter::Renewed("ghi".to_owned()));
+        assert!(http.requests.borrow().last().unwrap().body.contains("nick=bob"));
+        let http = server(FORM.to_owned());
+        assert!(matches!(rename(&http, None), Err(ProfileErr::NoField("newnickname"))));
+    }
+}
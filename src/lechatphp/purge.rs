@@ -0,0 +1,340 @@
+use super::conversations;
+use super::error_kind::{Classify, ErrorKind};
+use super::exchange::{self, Exchange};
+use super::messages::FetchErr;
+use super::moderation::{self, ModErr};
+use super::post::{self, Delete, PostErr};
+use super::sent::{self, SentMessage};
+use super::settings::Settings;
+use super::timestamp;
+use super::transport::Transport;
+use chrono::{DateTime, FixedOffset};
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Which of our messages get deleted on their own, and how fast.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PurgeConfig {
+    /// Delete our messages posted longer ago than this.
+    pub max_age: Option<Duration>,
+    /// Delete all but the newest of our messages.
+    pub keep_newest: Option<usize>,
+    /// Time between two purges.
+    pub every: Duration,
+    /// Messages deleted by a purge at most, the rest waits for the next.
+    pub batch: usize,
+    /// Wait between two deletions, deleting is one request after another
+    /// and the chat has flood limits.
+    pub pace: Duration,
+    /// Leave our private messages to nicks we talked with within this.
+    pub spare_conversations: Option<Duration>,
+    /// Where one message can't be deleted, as for chatters who can't see
+    /// message ids, delete all of ours instead. Much blunter: it takes the
+    /// messages not due yet and those spared as well.
+    pub allow_delete_all: bool,
+}
+
+impl Default for PurgeConfig {
+    fn default() -> Self {
+        Self {
+            max_age: None,
+            keep_newest: None,
+            every: Duration::from_secs(5 * 60),
+            batch: 5,
+            pace: Duration::from_secs(2),
+            spare_conversations: None,
+            allow_delete_all: false,
+        }
+    }
+}
+
+/// What a purge deleted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Purged {
+    /// The messages that were due.
+    pub messages: Vec<SentMessage>,
+    /// Every message of ours went, see `PurgeConfig::allow_delete_all`.
+    pub all: bool,
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum PurgeErr {
+    /// Deleting one message isn't for this session and deleting all of
+    /// them isn't allowed.
+    #[error("messages can only be deleted all at once, which isn't allowed")]
+    NoDelete,
+    #[error("{0}")]
+    Delete(#[source] ModErr),
+    #[error("{0}")]
+    DeleteAll(#[source] PostErr),
+}
+
+impl Classify for PurgeErr {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            PurgeErr::NoDelete => ErrorKind::PermissionDenied,
+            PurgeErr::Delete(e) => e.kind(),
+            PurgeErr::DeleteAll(e) => e.kind(),
+        }
+    }
+}
+
+impl PurgeErr {
+    fn session_expired(&self) -> bool {
+        matches!(self, PurgeErr::Delete(ModErr::Fetch(FetchErr::SessionExpired)) | PurgeErr::DeleteAll(PostErr::SessionExpired))
+    }
+}
+
+// When purges run
+#[derive(Debug)]
+pub(crate) struct Purger {
+    config: PurgeConfig,
+    last_run: Option<Instant>,
+    /// The session that expired, nothing runs for it.
+    paused: Option<String>,
+}
+
+impl Purger {
+    pub(super) fn new(config: PurgeConfig) -> Self {
+        Self { config, last_run: None, paused: None }
+    }
+
+    // Whether a purge for `session` is due at `now`. The session after the
+    // paused one takes over its posts and purges at once.
+    fn start(&mut self, settings: &Settings, session: &str, now: Instant) -> bool {
+        if let Some(paused) = &self.paused {
+            if paused == session {
+                return false;
+            }
+            sent::take_over(settings, paused, session);
+            self.paused = None;
+            self.last_run = None;
+        }
+        if self.last_run.is_some_and(|last| now.saturating_duration_since(last) < self.config.every) {
+            return false;
+        }
+        self.last_run = Some(now);
+        true
+    }
+}
+
+/// No purges for `session` anymore, it expired. They go on with the next
+/// session purged.
+pub(super) fn pause(settings: &Settings, session: &str) {
+    if let Some(purger) = settings.purge.lock().unwrap().as_mut() {
+        purger.paused = Some(session.to_owned());
+    }
+}
+
+/// Our messages of `sent`, newest first, that `config` deletes at `now`.
+/// The ids of `spared` stay.
+pub fn due<'a>(sent: impl Iterator<Item = &'a SentMessage>, config: &PurgeConfig, now: Instant, spared: &HashSet<u64>) -> Vec<SentMessage> {
+    sent.enumerate()
+        .filter(|(i, message)| {
+            let too_old = config.max_age.is_some_and(|age| now.saturating_duration_since(message.posted_at) > age);
+            let too_many = config.keep_newest.is_some_and(|keep| *i >= keep);
+            too_old || too_many
+        })
+        .map(|(_, message)| message)
+        .filter(|message| !message.id.is_some_and(|id| spared.contains(&id)))
+        .cloned()
+        .collect()
+}
+
+// The messages of conversations with a message within `active` of `now`
+fn active_conversations(settings: &Settings, active: Duration, now: DateTime<FixedOffset>) -> HashSet<u64> {
+    let active = chrono::Duration::from_std(active).unwrap_or(chrono::Duration::max_value());
+    conversations::conversations(settings)
+        .into_iter()
+        .filter(|c| c.last.time().is_some_and(|t| now.signed_duration_since(t) <= active))
+        .flat_map(|c| conversations::conversation(settings, &c.counterpart))
+        .filter_map(|entry| entry.id)
+        .collect()
+}
+
+/// Delete our messages of `session` due by `config` now, see `due`.
+pub fn purge(transport: &Transport, base_url: &str, page_php: &str, session: &str, config: &PurgeConfig) -> Result<Purged, PurgeErr> {
+    exchange::block_on(purge_with(transport, base_url, page_php, session, config, Instant::now()))
+}
+
+/// `purge` over any `Exchange`, as of `now`. At most `config.batch` are
+/// deleted one by one, `config.pace` apart, and a failure past the first
+/// leaves the rest for the next purge. Without a way to delete one, all
+/// of ours go when allowed.
+pub async fn purge_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    config: &PurgeConfig,
+    now: Instant,
+) -> Result<Purged, PurgeErr> {
+    let settings = http.settings();
+    let spared = config
+        .spare_conversations
+        .map(|active| active_conversations(settings, active, timestamp::server_now(settings.server_offset)))
+        .unwrap_or_default();
+    let due = due(sent::sent_messages(settings, session).iter(), config, now, &spared);
+    let mut purged = vec![];
+    let mut one_by_one = due.iter().any(|m| m.id.is_some());
+    for message in due.iter().filter(|m| m.id.is_some()).take(config.batch) {
+        if !purged.is_empty() {
+            http.sleep(config.pace).await;
+        }
+        match moderation::delete_message_with(http, base_url, page_php, session, message.id.unwrap(), true).await {
+            Ok(()) => purged.push(message.clone()),
+            Err(ModErr::NotStaff | ModErr::Unsupported(_) | ModErr::Refused { .. }) if purged.is_empty() => {
+                one_by_one = false;
+                break;
+            }
+            Err(e) if purged.is_empty() => return Err(PurgeErr::Delete(e)),
+            Err(e) => {
+                log::warn!("purge stopped after {} messages: {}", purged.len(), e);
+                break;
+            }
+        }
+    }
+    if one_by_one || due.is_empty() {
+        return Ok(Purged { messages: purged, all: false });
+    }
+    if !config.allow_delete_all {
+        return Err(PurgeErr::NoDelete);
+    }
+    post::delete_messages_with(http, base_url, page_php, session, Delete::All).await.map_err(PurgeErr::DeleteAll)?;
+    Ok(Purged { messages: due, all: true })
+}
+
+/// Purge as the client was configured, when it is time to, see
+/// `PurgeConfig::every`. `None` when it isn't, or for a session that
+/// expired.
+pub(super) fn purge_due(transport: &Transport, base_url: &str, page_php: &str, session: &str) -> Option<Result<Purged, PurgeErr>> {
+    let config = {
+        let settings = transport.settings();
+        let mut purger = settings.purge.lock().unwrap();
+        let purger = purger.as_mut()?;
+        purger.start(settings, session, Instant::now()).then(|| purger.config.clone())?
+    };
+    let purged = exchange::block_on(purge_with(transport, base_url, page_php, session, &config, Instant::now()));
+    if purged.as_ref().is_err_and(PurgeErr::session_expired) {
+        pause(transport.settings(), session);
+    }
+    Some(purged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lechatphp::messages;
+    use crate::lechatphp::mock::{MockExchange, MockResponse};
+    use select::document::Document;
+
+    const BASE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+
+    fn message(id: Option<u64>, age: u64, now: Instant) -> SentMessage {
+        SentMessage { text: format!("posted {}s ago", age), posted_at: now - Duration::from_secs(age), id, ambiguous: false }
+    }
+
+    // `session` posted `texts`, oldest first, shown with the ids `ids`
+    fn posted(settings: &Settings, session: &str, texts: &[&str], ids: &[u64]) {
+        sent::track(settings, session, "zed");
+        texts.iter().for_each(|text| sent::record(settings, session, text));
+        let divs: String = texts
+            .iter()
+            .zip(ids)
+            .rev()
+            .map(|(text, id)| {
+                format!(r#"<div class="msg"><label><input type="checkbox" name="mid[]" value="{}"></label><span class="usermsg"><span>zed</span> - {}</span></div>"#, id, text)
+            })
+            .collect();
+        let view = format!(r#"<div id="messages">{}</div>"#, divs);
+        sent::correlate(settings, session, &messages::parse_messages(&Settings::default(), &Document::from(view.as_str())).unwrap());
+    }
+
+    #[test]
+    fn due_test() {
+        let now = Instant::now() + Duration::from_secs(3600);
+        // Newest first
+        let sent = [message(Some(4), 60, now), message(Some(3), 600, now), message(None, 1200, now), message(Some(1), 3000, now)];
+        let ids = |config: &PurgeConfig, spared: &[u64]| -> Vec<_> {
+            due(sent.iter(), config, now, &spared.iter().copied().collect()).iter().map(|m| m.id).collect()
+        };
+        assert!(ids(&PurgeConfig::default(), &[]).is_empty());
+
+        let by_age = PurgeConfig { max_age: Some(Duration::from_secs(900)), ..PurgeConfig::default() };
+        assert_eq!(ids(&by_age, &[]), [None, Some(1)]);
+        assert_eq!(ids(&PurgeConfig { max_age: Some(Duration::from_secs(600)), ..by_age.clone() }, &[]), [None, Some(1)]);
+        assert_eq!(ids(&PurgeConfig { max_age: Some(Duration::from_secs(59)), ..by_age.clone() }, &[]), [Some(4), Some(3), None, Some(1)]);
+
+        let by_count = PurgeConfig { keep_newest: Some(1), ..PurgeConfig::default() };
+        assert_eq!(ids(&by_count, &[]), [Some(3), None, Some(1)]);
+        assert_eq!(ids(&PurgeConfig { max_age: Some(Duration::from_secs(900)), keep_newest: Some(3), ..by_count }, &[]), [None, Some(1)]);
+        // In a conversation still going on
+        assert_eq!(ids(&by_age, &[1]), [None]);
+    }
+
+    #[test]
+    fn purge_test() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        let session = "purge-test";
+        let now = Instant::now() + Duration::from_secs(3600);
+        let config = PurgeConfig { keep_newest: Some(1), batch: 2, pace: Duration::from_secs(3), ..PurgeConfig::default() };
+        // Staff until told otherwise
+        let staff = Rc::new(Cell::new(true));
+        let is_staff = staff.clone();
+        let http = MockExchange::new(move |req| match (is_staff.get(), req.method.as_str()) {
+            (true, "GET") if req.path.contains("action=view") => Ok(MockResponse::ok("<html><body><div id=\"messages\"></div></body></html>")),
+            (true, _) => Ok(MockResponse::ok(include_str!("fixtures/admin.html"))),
+            (false, _) if req.path.contains("action=admin") => Ok(MockResponse::ok(include_str!("fixtures/view.html"))),
+            (false, _) => Ok(MockResponse::ok(include_str!("fixtures/post_ok.html"))),
+        });
+        posted(&http.settings, session, &["one", "two", "three", "four"], &[101, 102, 103, 104]);
+        let purge = |config: &PurgeConfig| exchange::block_on(purge_with(&http, BASE_URL, "chat.php", session, config, now));
+        let texts = |purged: &Purged| purged.messages.iter().map(|m| m.text.clone()).collect::<Vec<_>>();
+
+        // Two by two, the newest due first, paced
+        let purged = purge(&config).unwrap();
+        assert_eq!((texts(&purged), purged.all), (vec!["three".to_owned(), "two".to_owned()], false));
+        assert_eq!(http.slept.get(), Duration::from_secs(3));
+        let deleted: Vec<_> = http.requests.borrow().iter().filter(|r| r.method == "POST").map(|r| r.body.rsplit('=').next().unwrap().to_owned()).collect();
+        assert_eq!(deleted, ["103", "102"]);
+        http.slept.set(Duration::ZERO);
+        assert_eq!(texts(&purge(&config).unwrap()), ["one"]);
+        assert_eq!(http.slept.get(), Duration::ZERO);
+        assert!(purge(&config).unwrap().messages.is_empty());
+        let left: Vec<_> = sent::sent_messages(&http.settings, session).iter().map(|m| m.text.clone()).collect();
+        assert_eq!(left, ["four"]);
+
+        // Not staff: all of ours, only when allowed
+        posted(&http.settings, session, &["five", "six"], &[105, 106]);
+        staff.set(false);
+        assert!(matches!(purge(&config), Err(PurgeErr::NoDelete)));
+        let purged = purge(&PurgeConfig { allow_delete_all: true, ..config }).unwrap();
+        assert_eq!((texts(&purged), purged.all), (vec!["five".to_owned()], true));
+        assert!(http.requests.borrow().last().unwrap().body.contains("what=all"));
+        assert_eq!(sent::sent_messages(&http.settings, session).iter().count(), 0);
+    }
+
+    #[test]
+    fn pause_test() {
+        let now = Instant::now();
+        let settings = Settings::default();
+        let mut purger = Purger::new(PurgeConfig::default());
+        assert!(purger.start(&settings, "pause-test", now));
+        assert!(!purger.start(&settings, "pause-test", now + Duration::from_secs(60)));
+        assert!(purger.start(&settings, "pause-test", now + Duration::from_secs(300)));
+
+        // Expired, then a new login
+        posted(&settings, "pause-test", &["before"], &[201]);
+        posted(&settings, "pause-test-2", &["after"], &[202]);
+        purger.paused = Some("pause-test".to_owned());
+        assert!(!purger.start(&settings, "pause-test", now + Duration::from_secs(900)));
+        assert!(purger.start(&settings, "pause-test-2", now + Duration::from_secs(901)));
+        let ids: Vec<_> = sent::sent_messages(&settings, "pause-test-2").iter().map(|m| m.id).collect();
+        assert_eq!(ids, [Some(202), Some(201)]);
+        assert_eq!(sent::sent_messages(&settings, "pause-test").iter().count(), 0);
+    }
+}
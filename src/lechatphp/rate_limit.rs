@@ -0,0 +1,161 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Longest a request is held back, whatever the rate: a tiny one would
+// otherwise mean waiting for ages, or more than a `Duration` holds
+const MAX_WAIT: Duration = Duration::from_secs(3600);
+
+/// Token bucket settings: `rate` tokens per second, at most `burst` saved up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Budget {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+/// Separate budgets for fetches and for state changing requests (posts,
+/// logins), the server is much stricter about the latter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub reads: Budget,
+    pub writes: Budget,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            reads: Budget { rate: 2.0, burst: 10 },
+            writes: Budget { rate: 0.5, burst: 3 },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Read,
+    Write,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    budget: Budget,
+    // Goes negative when callers are queued behind each other
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(budget: Budget, now: Instant) -> Self {
+        Self { budget, tokens: budget.burst as f64, last: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.budget.rate).min(self.budget.burst as f64);
+        self.last = now;
+    }
+
+    /// Take a token and return how long to wait before using it.
+    fn reserve(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= 1.0;
+        self.deficit()
+    }
+
+    /// How long a new request would have to wait.
+    fn wait_time(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= 1.0;
+        let wait = self.deficit();
+        self.tokens += 1.0;
+        wait
+    }
+
+    fn deficit(&self) -> Duration {
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::try_from_secs_f64(-self.tokens / self.budget.rate).map_or(MAX_WAIT, |wait| wait.min(MAX_WAIT))
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    reads: Bucket,
+    writes: Bucket,
+}
+
+impl Buckets {
+    fn new(limit: RateLimit, now: Instant) -> Self {
+        Self { reads: Bucket::new(limit.reads, now), writes: Bucket::new(limit.writes, now) }
+    }
+
+    fn bucket(&mut self, kind: Kind) -> &mut Bucket {
+        match kind {
+            Kind::Read => &mut self.reads,
+            Kind::Write => &mut self.writes,
+        }
+    }
+}
+
+/// The budgets of one client, shared by every request it sends. Unlimited
+/// without a `RateLimit`.
+#[derive(Debug, Default)]
+pub struct Limiter(Mutex<Option<Buckets>>);
+
+impl Limiter {
+    pub fn new(limit: Option<RateLimit>) -> Self {
+        Self(Mutex::new(limit.map(|limit| Buckets::new(limit, Instant::now()))))
+    }
+
+    /// Block until a request of this kind may be sent.
+    pub fn acquire(&self, kind: Kind) {
+        let wait = match self.0.lock().unwrap().as_mut() {
+            Some(buckets) => buckets.bucket(kind).reserve(Instant::now()),
+            None => return,
+        };
+        if !wait.is_zero() {
+            log::debug!("rate limited, {:?} request waits {:?}", kind, wait);
+            thread::sleep(wait);
+        }
+    }
+
+    /// How long a request of this kind would currently wait, for the UI.
+    pub fn wait_time(&self, kind: Kind) -> Duration {
+        self.0.lock().unwrap().as_mut().map_or(Duration::ZERO, |buckets| buckets.bucket(kind).wait_time(Instant::now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_test() {
+        let start = Instant::now();
+        let secs = |s: f64| start + Duration::from_secs_f64(s);
+        let mut bucket = Bucket::new(Budget { rate: 0.5, burst: 2 }, start);
+
+        // Burst goes through, then one request every 2s
+        assert_eq!(bucket.reserve(start), Duration::ZERO);
+        assert_eq!(bucket.reserve(start), Duration::ZERO);
+        assert_eq!(bucket.wait_time(start), Duration::from_secs(2));
+        assert_eq!(bucket.reserve(start), Duration::from_secs(2));
+        // Queued behind the previous one
+        assert_eq!(bucket.reserve(start), Duration::from_secs(4));
+        assert_eq!(bucket.reserve(secs(4.0)), Duration::from_secs(2));
+
+        // Idle time refills up to the burst only
+        assert_eq!(bucket.wait_time(secs(100.0)), Duration::ZERO);
+        assert_eq!(bucket.reserve(secs(100.0)), Duration::ZERO);
+        assert_eq!(bucket.reserve(secs(100.0)), Duration::ZERO);
+        assert_eq!(bucket.reserve(secs(100.0)), Duration::from_secs(2));
+
+        // A rate too small for a `Duration`
+        let mut bucket = Bucket::new(Budget { rate: 1e-20, burst: 1 }, start);
+        assert_eq!(bucket.reserve(start), Duration::ZERO);
+        assert_eq!(bucket.wait_time(start), MAX_WAIT);
+        assert_eq!(bucket.reserve(start), MAX_WAIT);
+    }
+}
@@ -0,0 +1,161 @@
+use super::command::ChatCommand;
+use super::messages::{Message, MessageKind};
+use super::settings::Settings;
+
+/// Default for `ReplyOptions::max_quote_len`.
+pub const DEFAULT_MAX_QUOTE_LEN: usize = 80;
+const ELLIPSIS: char = '…';
+
+/// How `compose_reply` quotes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplyOptions {
+    /// Most characters of the quoted text, the ellipsis included.
+    pub max_quote_len: usize,
+    /// In front of the quote, `> ` by default.
+    pub prefix: String,
+    /// Between the quote and the reply.
+    pub separator: String,
+    /// Reply to everyone even to a private message.
+    pub public: bool,
+}
+
+impl Default for ReplyOptions {
+    fn default() -> Self {
+        Self { max_quote_len: DEFAULT_MAX_QUOTE_LEN, prefix: "> ".to_owned(), separator: " — ".to_owned(), public: false }
+    }
+}
+
+// `text` on one line, each run of blanks a single space
+fn flatten(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// At most `max` characters, cut at the last blank that fits and ended
+// with an ellipsis. A single word longer than that is cut inside.
+fn truncate(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text.to_owned();
+    }
+    let Some(room) = max.checked_sub(1) else {
+        return String::new();
+    };
+    let end = text.char_indices().nth(room).map_or(text.len(), |(i, _)| i);
+    let head = &text[..end];
+    // A blank right after the cut means the last word is whole
+    let whole = text[end..].starts_with(' ');
+    let head = match head.rfind(' ') {
+        Some(blank) if !whole => &head[..blank],
+        _ => head,
+    };
+    format!("{}{}", head.trim_end(), ELLIPSIS)
+}
+
+// The text of a reply without the quote it starts with, so quotes don't
+// nest. A line merely starting with the prefix loses it.
+fn without_quote<'a>(text: &'a str, options: &ReplyOptions) -> &'a str {
+    let prefix = options.prefix.trim();
+    if prefix.is_empty() {
+        return text;
+    }
+    let mut text = text.trim();
+    while let Some(rest) = text.strip_prefix(prefix) {
+        text = match rest.split_once(options.separator.trim()) {
+            Some((_, reply)) if !options.separator.trim().is_empty() => reply,
+            _ => rest,
+        }
+        .trim();
+    }
+    text
+}
+
+/// `reply_text` after a quote of `original`, its sender, its time and its
+/// text on one line, cut to `max_quote_len`. The reply is never cut, a long
+/// one is split when posted like any message. A private message is
+/// answered privately unless `public`, see `ChatCommand::to_line` for the
+/// line.
+pub fn compose_reply(settings: &Settings, original: &Message, reply_text: &str, options: &ReplyOptions) -> ChatCommand {
+    let quoted = truncate(&flatten(without_quote(&original.text, options)), options.max_quote_len);
+    let from = original.from.as_deref().unwrap_or("*");
+    let attribution = match original.timestamp.trim() {
+        "" => from.to_owned(),
+        timestamp => format!("{} ({})", from, timestamp),
+    };
+    let text = format!("{}{}: {}{}{}", options.prefix, attribution, quoted, options.separator, flatten(reply_text));
+    let me = settings.mention.nick();
+    let is_me = |nick: &str| me.as_deref().is_some_and(|me| me.eq_ignore_ascii_case(nick));
+    // The other side of the conversation, whoever sent it
+    let to = match (&original.kind, original.from.as_deref(), original.to.as_deref()) {
+        (MessageKind::Private, Some(from), Some(to)) if !options.public => Some(if is_me(from) { to } else { from }),
+        _ => None,
+    };
+    match to {
+        Some(to) => ChatCommand::Whisper { to: to.to_owned(), text },
+        None => ChatCommand::Plain(text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(kind: MessageKind, from: &str, to: Option<&str>, text: &str) -> Message {
+        Message {
+            id: None,
+            timestamp: "10-17 19:40:02".to_owned(),
+            time: None,
+            from: Some(from.to_owned()),
+            to: to.map(str::to_owned),
+            text: text.to_owned(),
+            spans: vec![],
+            color: None,
+            display_from: None,
+            display_color: None,
+            kind,
+            mentions_me: false,
+            room: None,
+        }
+    }
+
+    #[test]
+    fn truncate_test() {
+        assert_eq!(truncate("hello world", 11), "hello world");
+        assert_eq!(truncate("hello world", 10), "hello…");
+        // The word before the cut is whole
+        assert_eq!(truncate("hello world again", 12), "hello world…");
+        assert_eq!(truncate("supercalifragilistic", 6), "super…");
+        assert_eq!(truncate("hello", 1), "…");
+        assert_eq!(truncate("hello", 0), "");
+        // Characters, never half of one
+        assert_eq!(truncate("日本語 テキスト です", 9), "日本語 テキスト…");
+        assert_eq!(truncate("日本語 テキスト です", 8), "日本語…");
+        assert_eq!(truncate("🦀🦀🦀🦀", 3), "🦀🦀…");
+        assert_eq!(truncate("ça été très ünïcödé", 12), "ça été très…");
+    }
+
+    #[test]
+    fn compose_reply_test() {
+        let settings = Settings::default();
+        let options = ReplyOptions { max_quote_len: 20, ..ReplyOptions::default() };
+        let said = message(MessageKind::Normal, "alice", None, "the meeting\nmoved to  nine, bring the notes please");
+        let reply = compose_reply(&settings, &said, "ok, will do", &options);
+        assert_eq!(reply, ChatCommand::Plain("> alice (10-17 19:40:02): the meeting moved… — ok, will do".to_owned()));
+
+        // A reply to a reply quotes the reply alone
+        let quoting = message(MessageKind::Normal, "bob", None, "> alice (10-17 19:40:02): the meeting moved… — ok, will do");
+        let reply = compose_reply(&settings, &quoting, "me too", &options);
+        assert_eq!(reply, ChatCommand::Plain("> bob (10-17 19:40:02): ok, will do — me too".to_owned()));
+        let greentext = message(MessageKind::Normal, "bob", None, ">> be me");
+        assert_eq!(compose_reply(&settings, &greentext, "lol", &options).to_line(), "> bob (10-17 19:40:02): be me — lol");
+
+        // Privately to the other side, whoever sent it; the reply is never cut
+        settings.mention.set_nick("zed");
+        let long = "word ".repeat(100);
+        let whisper = message(MessageKind::Private, "carol", Some("zed"), "psst");
+        let ChatCommand::Whisper { to, text } = compose_reply(&settings, &whisper, &long, &options) else { panic!() };
+        assert_eq!((to.as_str(), text.split_whitespace().filter(|w| *w == "word").count()), ("carol", 100));
+        let mine = message(MessageKind::Private, "zed", Some("carol"), "psst");
+        assert!(matches!(compose_reply(&settings, &mine, "hi", &options), ChatCommand::Whisper { to, .. } if to == "carol"));
+        let public = ReplyOptions { public: true, ..options };
+        assert!(matches!(compose_reply(&settings, &whisper, "hi", &public), ChatCommand::Plain(_)));
+    }
+}
@@ -0,0 +1,361 @@
+use super::error_kind::{Classify, ErrorKind};
+use super::messages::{Message, MessageKind};
+use super::post;
+use super::transport::Transport;
+use super::users::{Role, User};
+use crossbeam_channel::{unbounded, Sender};
+use lazy_static::lazy_static;
+use regex::{Captures, Regex, RegexBuilder};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+lazy_static! {
+    static ref TEMPLATE_RGX: Regex = Regex::new(r"\$(\$|\d+|\{\w+\}|nick\b)").unwrap();
+    static ref PAUSED: Mutex<bool> = Mutex::new(false);
+}
+
+const HOUR: Duration = Duration::from_secs(3600);
+
+/// A rule of the auto-responder, e.g. in TOML
+/// `[[rules]]` `trigger = '^!seen (\w+)'` `regex = true`
+/// `reply = '$nick: no idea where $1 is'`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Rule {
+    /// The whole message, ignoring case and the spaces around, unless
+    /// `regex`: then anywhere in it, `(?i)` to ignore case.
+    pub trigger: String,
+    pub regex: bool,
+    /// Only messages of these nicks, anyone's when empty.
+    pub from: Vec<String>,
+    /// Only senders with at least this role in the user list.
+    pub min_role: Option<Role>,
+    /// `$1` or `${name}` for the trigger's groups, `$0` the match,
+    /// `$nick` the sender and `$$` a dollar sign.
+    pub reply: String,
+    /// Reply in private to the sender rather than to everyone.
+    pub private: bool,
+    /// Seconds between two replies of the rule.
+    pub cooldown: u64,
+    /// Replies of the rule in any hour, past it the rule is quiet. A loop
+    /// with another bot stops there at the latest.
+    pub max_per_hour: usize,
+}
+
+impl Default for Rule {
+    fn default() -> Self {
+        Self {
+            trigger: String::new(),
+            regex: false,
+            from: vec![],
+            min_role: None,
+            reply: String::new(),
+            private: false,
+            cooldown: 30,
+            max_per_hour: 20,
+        }
+    }
+}
+
+/// The auto-responder of a `MessageStream`, see `StreamConfig::responder`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResponderConfig {
+    pub rules: Vec<Rule>,
+    /// Posted by us or an operator, stops every reply until
+    /// `resume_command`. `set_paused` does the same from code.
+    pub kill_command: Option<String>,
+    pub resume_command: Option<String>,
+    /// Nicks besides ours the commands are taken from.
+    pub operators: Vec<String>,
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ResponderErr {
+    #[error("auto-responder rule {} has no trigger", .0 + 1)]
+    EmptyTrigger(usize),
+    #[error("auto-responder rule {}: {}", .0 + 1, .1)]
+    Regex(usize, #[source] regex::Error),
+}
+
+impl Classify for ResponderErr {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            ResponderErr::EmptyTrigger(_) | ResponderErr::Regex(..) => ErrorKind::InvalidInput,
+        }
+    }
+}
+
+/// A reply to post, to everyone when `to` is `None`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reply {
+    pub to: Option<String>,
+    pub text: String,
+}
+
+struct Compiled {
+    rule: Rule,
+    trigger: Regex,
+    last: Option<Instant>,
+    /// The replies of the last hour.
+    fired: VecDeque<Instant>,
+}
+
+impl Compiled {
+    // Whether the rule may reply now, counted when it may
+    fn take(&mut self, now: Instant) -> bool {
+        if self.last.is_some_and(|last| now.duration_since(last) < Duration::from_secs(self.rule.cooldown)) {
+            return false;
+        }
+        while self.fired.front().is_some_and(|&t| now.duration_since(t) >= HOUR) {
+            self.fired.pop_front();
+        }
+        if self.fired.len() >= self.rule.max_per_hour {
+            log::debug!("auto-responder rule {:?} is at its hourly limit", self.rule.trigger);
+            return false;
+        }
+        self.last = Some(now);
+        self.fired.push_back(now);
+        true
+    }
+}
+
+fn same_nick(a: &str, b: &str) -> bool {
+    a.to_lowercase() == b.to_lowercase()
+}
+
+// `rule.reply` with the groups of the trigger and the sender in it
+fn expand(template: &str, caps: &Captures, nick: &str) -> String {
+    TEMPLATE_RGX
+        .replace_all(template, |t: &Captures| {
+            let name = t[1].trim_start_matches('{').trim_end_matches('}');
+            match name {
+                "$" => "$".to_owned(),
+                "nick" => nick.to_owned(),
+                _ => {
+                    let group = match name.parse::<usize>() {
+                        Ok(i) => caps.get(i),
+                        Err(_) => caps.name(name),
+                    };
+                    group.map_or("", |g| g.as_str()).to_owned()
+                }
+            }
+        })
+        .into_owned()
+}
+
+/// The rules of a `ResponderConfig`, each with its cooldown and hourly
+/// count.
+pub struct Responder {
+    config: ResponderConfig,
+    rules: Vec<Compiled>,
+    /// By lowercase nick, from the last user list.
+    roles: HashMap<String, Role>,
+}
+
+impl Responder {
+    pub fn new(config: ResponderConfig) -> Result<Self, ResponderErr> {
+        let mut rules = vec![];
+        for (i, rule) in config.rules.iter().enumerate() {
+            if rule.trigger.trim().is_empty() {
+                return Err(ResponderErr::EmptyTrigger(i));
+            }
+            let trigger = match rule.regex {
+                true => Regex::new(&rule.trigger),
+                false => RegexBuilder::new(&format!(r"^\s*{}\s*$", regex::escape(rule.trigger.trim()))).case_insensitive(true).build(),
+            };
+            let trigger = trigger.map_err(|e| ResponderErr::Regex(i, e))?;
+            rules.push(Compiled { rule: rule.clone(), trigger, last: None, fired: VecDeque::new() });
+        }
+        Ok(Self { config, rules, roles: HashMap::new() })
+    }
+
+    /// Whether some rule looks at roles, the user list is then needed.
+    pub fn needs_roles(&self) -> bool {
+        self.rules.iter().any(|r| r.rule.min_role.is_some())
+    }
+
+    pub fn set_users(&mut self, users: &[User]) {
+        self.roles = users.iter().map(|u| (u.nick.to_lowercase(), u.role)).collect();
+    }
+
+    // `Some(true)` for the kill command of someone allowed to, `Some(false)`
+    // for the resume command
+    fn switch(&self, message: &Message, me: Option<&str>) -> Option<bool> {
+        let from = message.from.as_deref()?;
+        let allowed = me.is_some_and(|me| same_nick(me, from)) || self.config.operators.iter().any(|o| same_nick(o, from));
+        let is = |command: &Option<String>| command.as_deref().is_some_and(|c| c.trim() == message.text.trim());
+        match allowed {
+            true if is(&self.config.kill_command) => Some(true),
+            true if is(&self.config.resume_command) => Some(false),
+            _ => None,
+        }
+    }
+
+    /// The replies to `message`, `me` being the session's nick. Never to
+    /// our own messages or system ones, nor while paused.
+    pub fn respond(&mut self, message: &Message, me: Option<&str>, now: Instant) -> Vec<Reply> {
+        if let Some(pause) = self.switch(message, me) {
+            log::info!("auto-responder {}", if pause { "paused" } else { "resumed" });
+            set_paused(pause);
+            return vec![];
+        }
+        let Some(from) = message.from.as_deref() else {
+            return vec![];
+        };
+        let own = me.is_some_and(|me| same_nick(me, from));
+        // Whispers between others, seen by staff
+        let not_to_us = message.kind == MessageKind::Private && me.zip(message.to.as_deref()).is_some_and(|(me, to)| !same_nick(me, to));
+        if own || not_to_us || paused() {
+            return vec![];
+        }
+        let role = self.roles.get(&from.to_lowercase()).copied();
+        let mut replies = vec![];
+        for rule in &mut self.rules {
+            if !rule.rule.from.is_empty() && !rule.rule.from.iter().any(|n| same_nick(n, from)) {
+                continue;
+            }
+            if rule.rule.min_role.is_some_and(|min| role.is_none_or(|r| r < min)) {
+                continue;
+            }
+            let Some(caps) = rule.trigger.captures(&message.text) else {
+                continue;
+            };
+            if rule.take(now) {
+                let text = expand(&rule.rule.reply, &caps, from);
+                replies.push(Reply { to: rule.rule.private.then(|| from.to_owned()), text });
+            }
+        }
+        replies
+    }
+}
+
+/// Stop or resume the replies of every auto-responder.
+pub fn set_paused(paused: bool) {
+    *PAUSED.lock().unwrap() = paused;
+}
+
+pub fn paused() -> bool {
+    *PAUSED.lock().unwrap()
+}
+
+/// Posts `Reply`s one after the other on a thread of its own, through the
+/// posting path so they are split and paced like any post. A flooded one
+/// is tried again once. Ends with the sender.
+pub(super) fn spawn_poster(transport: &Transport, base_url: &str, page_php: &str, session: &str) -> Sender<Reply> {
+    let (tx, replies) = unbounded::<Reply>();
+    let (transport, base_url, page_php, session) = (transport.clone(), base_url.to_owned(), page_php.to_owned(), session.to_owned());
+    thread::spawn(move || {
+        for reply in replies {
+            let send = || match &reply.to {
+                Some(nick) => post::post_private(&transport, &base_url, &page_php, &session, nick, &reply.text),
+                None => post::post_message(&transport, &base_url, &page_php, &session, reply.text.as_str()),
+            };
+            let mut result = send();
+            if let Some(wait) = result.as_ref().err().and_then(post::flood_retry_delay) {
+                thread::sleep(wait);
+                result = send();
+            }
+            if let Err(e) = result {
+                log::warn!("auto-reply not posted: {}", e);
+            }
+        }
+    });
+    tx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(from: &str, text: &str) -> Message {
+        Message {
+            id: None,
+            timestamp: String::new(),
+            time: None,
+            from: Some(from.to_owned()),
+            to: None,
+            text: text.to_owned(),
+            spans: vec![],
+            color: None,
+            display_from: None,
+            display_color: None,
+            kind: MessageKind::Normal,
+            mentions_me: false,
+            room: None,
+        }
+    }
+
+    fn responder(rules: Vec<Rule>) -> Responder {
+        let config = ResponderConfig { rules, kill_command: Some("!bot off".to_owned()), operators: vec!["Owner".to_owned()], ..Default::default() };
+        Responder::new(config).unwrap()
+    }
+
+    fn texts(replies: Vec<Reply>) -> Vec<String> {
+        replies.into_iter().map(|r| r.text).collect()
+    }
+
+    #[test]
+    fn template_test() {
+        let seen = Rule { trigger: r"^!seen (?P<who>\w+)(?: (\d+))?".to_owned(), regex: true, reply: "$nick: ${who} not seen ($2) $$5 $0 $9".to_owned(), ..Rule::default() };
+        let help = Rule { trigger: " !HELP ".to_owned(), reply: "see ${nick}'s $1notes".to_owned(), private: true, ..Rule::default() };
+        let mut responder = responder(vec![seen, help]);
+        let now = Instant::now();
+        let replies = responder.respond(&msg("alice", "!seen bob 3 days"), Some("zed"), now);
+        assert_eq!(texts(replies), ["alice: bob not seen (3) $5 !seen bob 3 "]);
+        let replies = responder.respond(&msg("bob", "!help"), Some("zed"), now);
+        assert_eq!(replies, [Reply { to: Some("bob".to_owned()), text: "see bob's notes".to_owned() }]);
+        // The whole message for exact triggers
+        assert!(responder.respond(&msg("carol", "!help me"), Some("zed"), now + HOUR).is_empty());
+
+        assert!(matches!(Responder::new(ResponderConfig { rules: vec![Rule::default()], ..Default::default() }), Err(ResponderErr::EmptyTrigger(0))));
+        let bad = Rule { trigger: "(".to_owned(), regex: true, ..Rule::default() };
+        assert!(matches!(Responder::new(ResponderConfig { rules: vec![bad], ..Default::default() }), Err(ResponderErr::Regex(0, _))));
+    }
+
+    #[test]
+    fn cooldown_test() {
+        let ping = Rule { trigger: "!ping".to_owned(), reply: "pong".to_owned(), cooldown: 60, max_per_hour: 3, ..Rule::default() };
+        let staff = Rule { trigger: "!kick".to_owned(), reply: "ok".to_owned(), min_role: Some(Role::Staff), cooldown: 0, ..Rule::default() };
+        let mut responder = responder(vec![ping, staff]);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut ping = |secs| texts(responder.respond(&msg("alice", "!ping"), Some("zed"), at(secs))).len();
+        let replies: Vec<_> = [0, 30, 59, 60, 120, 180, 240, 3599, 3600, 3660].into_iter().map(&mut ping).collect();
+        // Three an hour, the fourth waits for the first to be an hour old
+        assert_eq!(replies, [1, 0, 0, 1, 1, 0, 0, 0, 1, 1]);
+
+        let user = |nick: &str, role| User { nick: nick.to_owned(), color: None, role, idle_minutes: None, display_nick: nick.to_owned(), display_color: None };
+        assert!(responder.needs_roles());
+        assert!(responder.respond(&msg("Mod", "!kick"), Some("zed"), start).is_empty());
+        responder.set_users(&[user("mod", Role::Staff), user("alice", Role::Member), user("root", Role::Admin)]);
+        assert_eq!(texts(responder.respond(&msg("Mod", "!kick"), Some("zed"), start)), ["ok"]);
+        assert_eq!(texts(responder.respond(&msg("root", "!kick"), Some("zed"), start)), ["ok"]);
+        assert!(responder.respond(&msg("alice", "!kick"), Some("zed"), start).is_empty());
+    }
+
+    #[test]
+    fn own_messages_test() {
+        let echo = Rule { trigger: ".+".to_owned(), regex: true, reply: "$0".to_owned(), cooldown: 0, max_per_hour: 100, ..Rule::default() };
+        let mut responder = responder(vec![echo]);
+        let now = Instant::now();
+        assert!(responder.respond(&msg("Zed", "hello"), Some("zed"), now).is_empty());
+        let system = Message { from: None, kind: MessageKind::System(crate::lechatphp::messages::SystemEvent::Joined("bob".to_owned())), ..msg("", "bob entered the chat.") };
+        assert!(responder.respond(&system, Some("zed"), now).is_empty());
+        let whisper = |to: &str| Message { to: Some(to.to_owned()), kind: MessageKind::Private, ..msg("alice", "psst") };
+        assert!(responder.respond(&whisper("bob"), Some("zed"), now).is_empty());
+        assert_eq!(texts(responder.respond(&whisper("zed"), Some("zed"), now)), ["psst"]);
+        assert_eq!(texts(responder.respond(&msg("bob", "hi"), Some("zed"), now)), ["hi"]);
+
+        // The kill switch, from us or an operator only
+        assert_eq!(responder.switch(&msg("zed", "!bot off"), Some("zed")), Some(true));
+        assert_eq!(responder.switch(&msg("owner", " !bot off "), Some("zed")), Some(true));
+        assert_eq!(responder.switch(&msg("bob", "!bot off"), Some("zed")), None);
+        assert_eq!(responder.switch(&msg("zed", "!bot on"), Some("zed")), None);
+    }
+}
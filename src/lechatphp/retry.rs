@@ -0,0 +1,403 @@
+use super::error_kind::{self, Classify, ErrorKind};
+use super::http_log;
+use super::onion::UrlErr;
+use super::rate_limit::Kind;
+use super::settings::Settings;
+use super::tls::PinMismatch;
+use http::{Method, StatusCode};
+use rand::{thread_rng, Rng};
+use reqwest::blocking::{RequestBuilder, Response};
+use std::{error, io};
+use std::thread;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// How transient failures of idempotent requests are retried.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Total attempts, including the first one. 1 disables retries.
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Randomize each delay between half and all of its value.
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            jitter: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retrying after the `attempt`th failure (1 based).
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let delay = self.base_delay.saturating_mul(factor).min(self.max_delay);
+        if self.jitter {
+            delay.mul_f64(thread_rng().gen_range(0.5..=1.0))
+        } else {
+            delay
+        }
+    }
+}
+
+/// The urls in the messages are redacted, they may have the session in them.
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum SendErr {
+    #[error("{}", http_log::redact(&.0.to_string()))]
+    Reqwest(#[from] reqwest::Error),
+    #[error("{0}")]
+    PinMismatch(#[source] PinMismatch),
+    /// Refused by the onion-only guard, nothing was sent.
+    #[error("{}", http_log::redact(&.0.to_string()))]
+    Clearnet(#[source] UrlErr),
+    /// The body is bigger than the configured maximum, see
+    /// `ClientConfig::max_body_size`.
+    #[error("response body larger than {limit} bytes")]
+    ResponseTooLarge { limit: usize },
+    /// Reading the body failed.
+    #[error("error reading response body: {0}")]
+    Body(#[source] io::Error),
+    /// The connection closed before the `Content-Length` the server gave.
+    #[error("response cut short, {got} of {expected} bytes")]
+    Truncated { expected: u64, got: usize },
+}
+
+impl Classify for SendErr {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            SendErr::Reqwest(e) => error_kind::reqwest_kind(e),
+            SendErr::Body(_) | SendErr::Truncated { .. } => ErrorKind::Transient,
+            SendErr::Clearnet(_) => ErrorKind::InvalidInput,
+            SendErr::PinMismatch(_) | SendErr::ResponseTooLarge { .. } => ErrorKind::Fatal,
+        }
+    }
+}
+
+impl SendErr {
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, SendErr::Reqwest(e) if e.is_timeout())
+    }
+}
+
+/// Time limits `send` applies to each request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timeouts {
+    /// The client's read timeout, attempts never get more than this.
+    pub read: Duration,
+    /// Overall limit for a request, retries included.
+    pub deadline: Option<Duration>,
+}
+
+/// Which phase of a request ran out of time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timeout {
+    /// Connecting, which over Tor includes building the circuit. Worth an
+    /// immediate retry, possibly on a new circuit.
+    Connect,
+    /// Waiting for the response once connected. Retrying rarely helps.
+    Read,
+}
+
+pub fn classify_timeout(err: &reqwest::Error) -> Option<Timeout> {
+    if !err.is_timeout() {
+        None
+    } else if err.is_connect() {
+        Some(Timeout::Connect)
+    } else {
+        Some(Timeout::Read)
+    }
+}
+
+/// Where a connection through the SOCKS proxy failed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectFailure {
+    /// The proxy itself didn't answer, tor is probably not running.
+    ProxyDown,
+    /// The proxy answered but couldn't reach the host, e.g. no circuit to
+    /// the onion service.
+    Circuit,
+}
+
+// SOCKS5 replies meaning the proxy works but the destination didn't answer
+const CIRCUIT_FAILURES: [&str; 6] = [
+    "General SOCKS server failure",
+    "Connection not allowed by ruleset",
+    "Network unreachable",
+    "Host unreachable",
+    "Connection refused",
+    "TTL expired",
+];
+const SOCKS_ERR_PREFIX: &str = "socks connect error: ";
+
+/// `None` for anything but a failed SOCKS connection, a direct connection
+/// failing is the server's.
+pub fn classify_connect(err: &reqwest::Error) -> Option<ConnectFailure> {
+    if !err.is_connect() {
+        return None;
+    }
+    let mut source = error::Error::source(err);
+    while let Some(e) = source {
+        if let Some(reason) = e.to_string().strip_prefix(SOCKS_ERR_PREFIX) {
+            return Some(if CIRCUIT_FAILURES.contains(&reason) {
+                ConnectFailure::Circuit
+            } else {
+                // Refused, reset or not speaking SOCKS
+                ConnectFailure::ProxyDown
+            });
+        }
+        source = e.source();
+    }
+    None
+}
+
+pub fn is_transient_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+/// Errors worth a retry after backing off. Timeouts are handled apart, see
+/// `classify_timeout`.
+pub fn is_transient_err(err: &reqwest::Error) -> bool {
+    !err.is_timeout() && (err.is_connect() || err.is_request())
+}
+
+// Cap the attempt's timeout to what is left of the deadline
+fn within_deadline(req: RequestBuilder, timeouts: Option<Timeouts>, started: Instant) -> RequestBuilder {
+    match timeouts {
+        Some(Timeouts { read, deadline: Some(deadline) }) => {
+            let remaining = deadline.saturating_sub(started.elapsed()).max(Duration::from_millis(1));
+            if remaining < read {
+                req.timeout(remaining)
+            } else {
+                req
+            }
+        }
+        _ => req,
+    }
+}
+
+fn past_deadline(timeouts: Option<Timeouts>, started: Instant, delay: Duration) -> bool {
+    timeouts
+        .and_then(|t| t.deadline)
+        .is_some_and(|deadline| started.elapsed() + delay >= deadline)
+}
+
+fn check_pin(settings: &Settings, res: Result<Response, SendErr>) -> Result<Response, SendErr> {
+    if let Err(mismatch) = settings.pins.check(&res) {
+        log::error!("{}", mismatch);
+        return Err(SendErr::PinMismatch(mismatch));
+    }
+    res
+}
+
+fn is_idempotent(req: &RequestBuilder) -> bool {
+    req.try_clone()
+        .and_then(|r| r.build().ok())
+        .is_some_and(|r| r.method() == Method::GET || r.method() == Method::HEAD)
+}
+
+/// Send the request, retrying with the client's policy if it is a GET/HEAD
+/// that failed transiently. Anything else is sent exactly once.
+/// Every attempt waits for the client's rate limiter, and none starts past
+/// the deadline. Responses from hosts with a TLS pin are checked against it.
+pub fn send(settings: &Settings, req: RequestBuilder) -> Result<Response, SendErr> {
+    send_with(settings, req, thread::sleep)
+}
+
+pub fn send_with<S>(settings: &Settings, req: RequestBuilder, mut sleep: S) -> Result<Response, SendErr>
+where
+    S: FnMut(Duration),
+{
+    let (policy, timeouts) = (&settings.retry, settings.timeouts);
+    let started = Instant::now();
+    if !is_idempotent(&req) {
+        settings.rate_limit.acquire(Kind::Write);
+        return check_pin(settings, http_log::send(settings, within_deadline(req, timeouts, started)));
+    }
+    let mut attempt = 1;
+    loop {
+        settings.rate_limit.acquire(Kind::Read);
+        let res = check_pin(settings, http_log::send(settings, within_deadline(req.try_clone().unwrap(), timeouts, started)));
+        let delay = match &res {
+            Ok(resp) if is_transient_status(resp.status()) => Some(policy.delay(attempt)),
+            Err(SendErr::Reqwest(e)) => match classify_timeout(e) {
+                Some(Timeout::Connect) => Some(Duration::ZERO),
+                Some(Timeout::Read) => None,
+                // Fails the same until tor is back, see Transport::proxy_up
+                None if classify_connect(e) == Some(ConnectFailure::ProxyDown) => None,
+                None if is_transient_err(e) => Some(policy.delay(attempt)),
+                None => None,
+            },
+            Ok(_) | Err(_) => None,
+        };
+        let delay = match delay {
+            Some(delay) if attempt < policy.max_attempts && !past_deadline(timeouts, started, delay) => delay,
+            _ => return res,
+        };
+        log::error!("transient failure, retry {}/{} in {:?}", attempt, policy.max_attempts - 1, delay);
+        sleep(delay);
+        attempt += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lechatphp::mock::{MockResponse, MockServer};
+    use reqwest::blocking::Client;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn flaky_server(failures: usize) -> MockServer {
+        let count = Arc::new(AtomicUsize::new(0));
+        MockServer::start(move |_| {
+            if count.fetch_add(1, Ordering::SeqCst) < failures {
+                MockResponse::new(502, "Bad Gateway")
+            } else {
+                MockResponse::ok("ok")
+            }
+        })
+    }
+
+    #[test]
+    fn send_with_test() {
+        let settings = Settings { retry: RetryPolicy { max_attempts: 4, jitter: false, ..Default::default() }, ..Default::default() };
+        let client = Client::new();
+
+        let server = flaky_server(2);
+        let mut sleeps = vec![];
+        let resp = send_with(&settings, client.get(&server.url), |d| sleeps.push(d)).unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(server.hits(), 3);
+        assert_eq!(sleeps, vec![Duration::from_secs(1), Duration::from_secs(2)]);
+
+        // Gives up after max_attempts
+        let server = flaky_server(10);
+        let mut sleeps = vec![];
+        let resp = send_with(&settings, client.get(&server.url), |d| sleeps.push(d)).unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(server.hits(), 4);
+        assert_eq!(sleeps.len(), 3);
+
+        // State changing requests are never retried
+        let server = flaky_server(2);
+        let resp = send_with(&settings, client.post(&server.url), |_| panic!("slept")).unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(server.hits(), 1);
+    }
+
+    // Accepts connections and never answers
+    fn stall_server() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            let mut held = vec![];
+            for stream in listener.incoming() {
+                held.push(stream);
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn timeout_test() {
+        let short = Duration::from_millis(300);
+        let stalled = stall_server();
+        let client = Client::builder().connect_timeout(short).timeout(short).build().unwrap();
+        let err = client.get(format!("http://{}", stalled)).send().unwrap_err();
+        assert_eq!(classify_timeout(&err), Some(Timeout::Read));
+
+        // A stalled SOCKS proxy never lets the connection through
+        let proxy = reqwest::Proxy::all(format!("socks5h://{}", stalled)).unwrap();
+        let client = Client::builder().proxy(proxy).connect_timeout(short).timeout(Duration::from_secs(10)).build().unwrap();
+        let err = client.get("http://example.onion").send().unwrap_err();
+        assert_eq!(classify_timeout(&err), Some(Timeout::Connect));
+
+        let refused = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let err = Client::new().get(format!("http://{}", refused)).send().unwrap_err();
+        assert_eq!(classify_timeout(&err), None);
+        assert!(err.is_connect());
+
+        // The deadline cuts the read short and leaves no time for retries
+        let settings = Settings {
+            retry: RetryPolicy { max_attempts: 3, jitter: false, ..Default::default() },
+            timeouts: Some(Timeouts { read: Duration::from_secs(10), deadline: Some(short) }),
+            ..Default::default()
+        };
+        let server = flaky_server(10);
+        let start = Instant::now();
+        let err = send_with(&settings, Client::new().get(format!("http://{}", stalled)), |_| {}).unwrap_err();
+        assert!(matches!(&err, SendErr::Reqwest(e) if classify_timeout(e) == Some(Timeout::Read)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        let resp = send_with(&settings, Client::new().get(&server.url), thread::sleep).unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(server.hits(), 1);
+    }
+
+    // SOCKS5 proxy answering every CONNECT with `reply`
+    fn socks_server(reply: u8) -> String {
+        use std::io::{Read, Write};
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut buf = [0u8; 512];
+                let _ = stream.read(&mut buf);
+                let _ = stream.write_all(&[5, 0]);
+                let _ = stream.read(&mut buf);
+                let _ = stream.write_all(&[5, reply, 0, 1, 0, 0, 0, 0, 0, 0]);
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn classify_connect_test() {
+        let via = |proxy: &str| {
+            let proxy = reqwest::Proxy::all(format!("socks5h://{}", proxy)).unwrap();
+            Client::builder().proxy(proxy).build().unwrap()
+        };
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+
+        // Tor isn't running, not worth a retry
+        let settings = Settings { retry: RetryPolicy { max_attempts: 3, jitter: false, ..Default::default() }, ..Default::default() };
+        let err = send_with(&settings, via(&closed).get("http://example.onion"), |_| panic!("slept")).unwrap_err();
+        assert!(matches!(&err, SendErr::Reqwest(e) if classify_connect(e) == Some(ConnectFailure::ProxyDown)));
+        // Host unreachable and TTL expired come from tor, the proxy is fine
+        for reply in [4, 6] {
+            let err = via(&socks_server(reply)).get("http://example.onion").send().unwrap_err();
+            assert_eq!(classify_connect(&err), Some(ConnectFailure::Circuit), "reply {}", reply);
+        }
+        // Without a proxy a refused connection is the server's
+        let err = Client::new().get(format!("http://{}", closed)).send().unwrap_err();
+        assert_eq!(classify_connect(&err), None);
+
+        // Logged as is by the callers, the session stays out
+        let err = SendErr::from(Client::new().get(format!("http://{}/chat.php?session=s3cr3t", closed)).send().unwrap_err());
+        assert!(err.to_string().contains("session=***)"), "{}", err);
+    }
+
+    #[test]
+    fn delay_test() {
+        let policy = RetryPolicy { jitter: false, ..Default::default() };
+        assert_eq!(policy.delay(1), Duration::from_secs(1));
+        assert_eq!(policy.delay(4), Duration::from_secs(8));
+        assert_eq!(policy.delay(10), Duration::from_secs(10));
+
+        let policy = RetryPolicy::default();
+        for attempt in 1..5 {
+            let delay = policy.delay(attempt);
+            assert!(delay <= policy.max_delay && delay * 2 >= policy.base_delay);
+        }
+    }
+}
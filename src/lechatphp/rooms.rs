@@ -0,0 +1,159 @@
+use super::error_kind::{Classify, ErrorKind};
+use super::exchange::{self, Exchange};
+use super::messages::{fetch_page, view_url_in, FetchErr};
+use super::settings::Settings;
+use super::transport::Transport;
+use super::users::encode;
+use select::document::Document;
+use select::predicate::{Attr, Name, Predicate};
+use thiserror::Error;
+
+/// The query parameter forks with rooms expect on every request.
+pub const ROOM_PARAM: &str = "room";
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum RoomErr {
+    #[error("{0}")]
+    Fetch(#[from] FetchErr),
+    /// The chat lists its rooms and `room` isn't one of them.
+    #[error("no room {room}, there are {}", .rooms.join(", "))]
+    Unknown { room: String, rooms: Vec<String> },
+}
+
+impl Classify for RoomErr {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            RoomErr::Fetch(e) => e.kind(),
+            RoomErr::Unknown { .. } => ErrorKind::InvalidInput,
+        }
+    }
+}
+
+/// The room requests of `session` go to by default, `None` for the chat's
+/// only or default room. Set by `switch_room`, on forks with several.
+pub fn room(settings: &Settings, session: &str) -> Option<String> {
+    settings.rooms.lock().unwrap().get(session).cloned()
+}
+
+/// Send the requests of `session` to `room` from now on, without asking the
+/// chat, see `switch_room` for that.
+pub fn set_room(settings: &Settings, session: &str, room: Option<&str>) {
+    let mut rooms = settings.rooms.lock().unwrap();
+    match room {
+        Some(room) => rooms.insert(session.to_owned(), room.to_owned()),
+        None => rooms.remove(session),
+    };
+}
+
+/// What goes after a query for `room`, nothing without one.
+pub(super) fn query(room: Option<&str>) -> String {
+    room.map_or(String::new(), |room| format!("&{}={}", ROOM_PARAM, encode(room)))
+}
+
+/// The rooms of the chat's room picker, `None` when the page has none.
+pub fn parse_rooms(doc: &Document) -> Option<Vec<String>> {
+    let select = doc.find(Name("select").and(Attr("name", ROOM_PARAM))).next()?;
+    let rooms = select.find(Name("option")).map(|o| o.attr("value").map_or_else(|| o.text(), str::to_owned)).map(|r| r.trim().to_owned());
+    Some(rooms.filter(|r| !r.is_empty()).collect())
+}
+
+/// Open `room` as `session`: its view is fetched with the room, which is
+/// what forks with rooms need to move the session there, and the room is
+/// kept for every later request. A room missing from the view's room picker
+/// is refused and the session stays where it was.
+pub async fn switch_room_with<E: Exchange>(http: &E, base_url: &str, page_php: &str, session: &str, room: &str) -> Result<(), RoomErr> {
+    let doc = fetch_page(http, &view_url_in(base_url, page_php, session, http.lang(), Some(room))).await?;
+    if let Some(rooms) = parse_rooms(&doc).filter(|rooms| !rooms.iter().any(|r| r == room)) {
+        return Err(RoomErr::Unknown { room: room.to_owned(), rooms });
+    }
+    set_room(http.settings(), session, Some(room));
+    Ok(())
+}
+
+/// Move `session` to `room`, see `switch_room_with`.
+pub fn switch_room(transport: &Transport, base_url: &str, page_php: &str, session: &str, room: &str) -> Result<(), RoomErr> {
+    exchange::block_on(switch_room_with(transport, base_url, page_php, session, room))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lechatphp::feed::Feed;
+    use crate::lechatphp::messages::{fetch_messages, fetch_messages_in};
+    use crate::lechatphp::mirrors::Session;
+    use crate::lechatphp::mock::{MockResponse, MockServer};
+    use crate::lechatphp::post::{post_message, post_message_in};
+    use crate::lechatphp::stream::{ChatEvent, MessageStream, StreamConfig};
+    use crate::lechatphp::LANG;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    // A chat with the rooms main, the default, and lounge, each with one
+    // message, and the requests it was sent
+    fn two_rooms() -> (MockServer, Arc<Mutex<Vec<String>>>) {
+        let sent = Arc::new(Mutex::new(vec![]));
+        let kept = Arc::clone(&sent);
+        let server = MockServer::start(move |req| {
+            kept.lock().unwrap().push(format!("{} {} {}", req.method, req.path, req.body));
+            if !req.path.contains("action=view") {
+                return MockResponse::ok(include_str!("fixtures/post_ok.html"));
+            }
+            let room = if req.path.contains("room=lounge") { "lounge" } else { "main" };
+            MockResponse::ok(&format!(
+                r#"<html><body><select name="room"><option value="main">main</option><option value="lounge">lounge</option></select><div id="messages"><div class="msg"><small>10-17 19:40:02 - </small><span class="usermsg"><span style="color:#FF0000;">alice</span> - in {}</span></div></div></body></html>"#,
+                room
+            ))
+        });
+        (server, sent)
+    }
+
+    #[test]
+    fn rooms_test() {
+        let (server, sent) = two_rooms();
+        let transport = Transport::direct();
+        let mut session = Session { id: "rooms-test".to_owned(), base_url: server.url.clone(), feed: Feed::View, room: None, lang: LANG.to_owned() };
+        let last = || sent.lock().unwrap().last().cloned().unwrap();
+
+        // The default room, no parameter
+        let messages = fetch_messages(&transport, &server.url, "chat.php", &session.id).unwrap();
+        assert_eq!((messages[0].text.as_str(), messages[0].room.as_deref()), ("in main", None));
+        assert!(!last().contains("room="));
+
+        session.switch_room(&transport, "chat.php", "lounge").unwrap();
+        assert_eq!((session.room.as_deref(), room(transport.settings(), &session.id).as_deref()), (Some("lounge"), Some("lounge")));
+        let messages = fetch_messages(&transport, &server.url, "chat.php", &session.id).unwrap();
+        assert_eq!((messages[0].text.as_str(), messages[0].room.as_deref()), ("in lounge", Some("lounge")));
+        assert!(last().contains("action=view&session=rooms-test&lang=en&room=lounge"));
+        post_message(&transport, &server.url, "chat.php", &session.id, "hi").unwrap();
+        let posts: Vec<_> = sent.lock().unwrap().iter().filter(|r| r.contains("action=post")).cloned().collect();
+        assert!(posts[0].starts_with("GET") && posts[0].contains("room=lounge"));
+        assert!(posts[1].starts_with("POST") && posts[1].contains("room=lounge") && posts[1].contains("message=hi"));
+
+        // Another room for one request, the session stays
+        let messages = fetch_messages_in(&transport, &server.url, "chat.php", &session.id, "main").unwrap();
+        assert_eq!(messages[0].room.as_deref(), Some("main"));
+        assert!(last().contains("room=main"));
+        post_message_in(&transport, &server.url, "chat.php", &session.id, "main", "hello main").unwrap();
+        assert!(last().contains("room=main") && !last().contains("room=lounge"));
+        assert_eq!(room(transport.settings(), &session.id).as_deref(), Some("lounge"));
+
+        // A room the chat doesn't have
+        let err = session.switch_room(&transport, "chat.php", "attic").unwrap_err();
+        assert!(matches!(err, RoomErr::Unknown { room, rooms } if room == "attic" && rooms == ["main", "lounge"]));
+        assert_eq!(session.room.as_deref(), Some("lounge"));
+
+        // A stream per room, and one following the session
+        let config = StreamConfig { refresh: Some(Duration::from_millis(10)), jitter: 0.0, ..StreamConfig::default() };
+        let first = |room: Option<&str>| {
+            let config = StreamConfig { room: room.map(str::to_owned), ..config.clone() };
+            match MessageStream::new(&transport, &server.url, "chat.php", &session.id, config).next() {
+                Some(ChatEvent::NewMessage(message)) => (message.text, message.room),
+                other => panic!("{:?}", other),
+            }
+        };
+        assert_eq!(first(Some("main")), ("in main".to_owned(), Some("main".to_owned())));
+        assert_eq!(first(None), ("in lounge".to_owned(), Some("lounge".to_owned())));
+        set_room(transport.settings(), &session.id, None);
+    }
+}
@@ -0,0 +1,325 @@
+use super::command::{ChatCommand, SEND_TO_ALL};
+use super::error_kind::{Classify, ErrorKind};
+use super::exchange::{self, Exchange};
+use super::outbox;
+use super::post::{self, PostErr};
+use super::settings::Settings;
+use super::timestamp;
+use super::transport::Transport;
+use chrono::{DateTime, Duration, FixedOffset, NaiveDateTime, NaiveTime};
+use serde_derive::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+use thiserror::Error;
+
+/// A message waiting for its time.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Scheduled {
+    pub id: u64,
+    /// RFC 3339, in the chat's offset.
+    pub at: String,
+    /// A nick, or a group like `SEND_TO_ALL`.
+    pub send_to: String,
+    /// For everyone it is read like a line of the input, `/me` and all.
+    pub text: String,
+    /// Posted at the next start when its time passed while the client
+    /// wasn't running, rather than dropped.
+    #[serde(default)]
+    pub post_if_missed: bool,
+}
+
+impl Scheduled {
+    pub fn at(&self) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc3339(&self.at).ok()
+    }
+
+    fn command(&self) -> ChatCommand {
+        match self.send_to.as_str() {
+            SEND_TO_ALL => ChatCommand::parse(&self.text),
+            to => ChatCommand::Whisper { to: to.to_owned(), text: self.text.clone() },
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ScheduleErr {
+    /// No file for the schedule, see `ClientConfig::schedule`.
+    #[error("no schedule configured")]
+    NotConfigured,
+    #[error("that time has passed")]
+    Past,
+    #[error("nothing to post")]
+    EmptyMessage,
+    #[error("schedule {}: {}", .0.display(), .1)]
+    Io(PathBuf, #[source] io::Error),
+    #[error("schedule {}: {}", .0.display(), .1)]
+    Json(PathBuf, #[source] serde_json::Error),
+}
+
+impl Classify for ScheduleErr {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            ScheduleErr::NotConfigured | ScheduleErr::Past | ScheduleErr::EmptyMessage => ErrorKind::InvalidInput,
+            ScheduleErr::Io(..) | ScheduleErr::Json(..) => ErrorKind::Fatal,
+        }
+    }
+}
+
+/// The messages of a profile to post later, by time, saved after every
+/// change.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    path: PathBuf,
+    entries: Vec<Scheduled>,
+}
+
+impl Schedule {
+    /// The schedule saved at `path`, empty when there is no file yet. What
+    /// was due before `now` is posted right away when `post_if_missed`,
+    /// the rest is out of it and returned.
+    pub fn load(path: &Path, now: DateTime<FixedOffset>) -> Result<(Self, Vec<Scheduled>), ScheduleErr> {
+        let entries: Vec<Scheduled> = match fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json).map_err(|e| ScheduleErr::Json(path.to_owned(), e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(ScheduleErr::Io(path.to_owned(), e)),
+        };
+        let (entries, missed): (Vec<_>, Vec<_>) =
+            entries.into_iter().partition(|entry| entry.post_if_missed || entry.at().is_some_and(|at| at > now));
+        let schedule = Self { path: path.to_owned(), entries };
+        if !missed.is_empty() {
+            schedule.changed();
+        }
+        Ok((schedule, missed))
+    }
+
+    pub fn save(&self) -> Result<(), ScheduleErr> {
+        let json = serde_json::to_string(&self.entries).expect("schedule serializes");
+        fs::write(&self.path, json).map_err(|e| ScheduleErr::Io(self.path.clone(), e))
+    }
+
+    // Saved, or the chat goes on with what is in memory
+    fn changed(&self) {
+        if let Err(e) = self.save() {
+            log::warn!("{}", e);
+        }
+    }
+
+    /// Post `text` to `send_to` at `at`, later than `now`. Returns its id.
+    pub fn add(
+        &mut self,
+        at: DateTime<FixedOffset>,
+        send_to: &str,
+        text: &str,
+        post_if_missed: bool,
+        now: DateTime<FixedOffset>,
+    ) -> Result<u64, ScheduleErr> {
+        if text.trim().is_empty() {
+            return Err(ScheduleErr::EmptyMessage);
+        }
+        if at <= now {
+            return Err(ScheduleErr::Past);
+        }
+        let id = self.entries.iter().map(|e| e.id + 1).max().unwrap_or_default();
+        let entry = Scheduled { id, at: at.to_rfc3339(), send_to: send_to.to_owned(), text: text.to_owned(), post_if_missed };
+        let i = self.entries.partition_point(|e| e.at().is_some_and(|t| t <= at));
+        self.entries.insert(i, entry);
+        self.changed();
+        Ok(id)
+    }
+
+    pub fn entries(&self) -> &[Scheduled] {
+        &self.entries
+    }
+
+    /// Whether there was an entry `id`.
+    pub fn cancel(&mut self, id: u64) -> bool {
+        let Some(i) = self.entries.iter().position(|e| e.id == id) else {
+            return false;
+        };
+        self.entries.remove(i);
+        self.changed();
+        true
+    }
+
+    // Out of the schedule before they are posted, so none is posted twice.
+    // Oldest first.
+    fn take_due(&mut self, now: DateTime<FixedOffset>) -> Vec<Scheduled> {
+        let due = self.entries.iter().take_while(|e| e.at().is_none_or(|at| at <= now)).count();
+        if due == 0 {
+            return vec![];
+        }
+        let due = self.entries.drain(..due).collect();
+        self.changed();
+        due
+    }
+}
+
+/// The schedule kept at `path`, `None` without one. Entries missed as of
+/// `now` are dropped and logged, see `Schedule::load`.
+pub(super) fn open(path: Option<PathBuf>, now: DateTime<FixedOffset>) -> Option<Schedule> {
+    path.map(|path| match Schedule::load(&path, now) {
+        Ok((schedule, missed)) => {
+            for entry in missed {
+                log::warn!("scheduled message {} for {} missed, dropped: {}", entry.id, entry.at, entry.text);
+            }
+            schedule
+        }
+        Err(e) => {
+            log::warn!("{}, starting with an empty schedule", e);
+            Schedule { path, entries: vec![] }
+        }
+    })
+}
+
+/// `text` "HH:MM", "HH:MM:SS" or "YYYY-MM-DD HH:MM" in the chat's time, see
+/// `ClientConfig::server_utc_offset`. A bare time already past `now` is
+/// tomorrow's.
+pub fn chat_time(text: &str, now: DateTime<FixedOffset>) -> Option<DateTime<FixedOffset>> {
+    let text = text.trim();
+    let local = now.naive_local();
+    let at = match ["%Y-%m-%d %H:%M", "%Y-%m-%d %H:%M:%S"].iter().find_map(|f| NaiveDateTime::parse_from_str(text, f).ok()) {
+        Some(at) => at,
+        None => {
+            let time = ["%H:%M", "%H:%M:%S"].iter().find_map(|f| NaiveTime::parse_from_str(text, f).ok())?;
+            let at = local.date().and_time(time);
+            if at <= local {
+                at + Duration::days(1)
+            } else {
+                at
+            }
+        }
+    };
+    at.and_local_timezone(*now.offset()).single()
+}
+
+/// Post `text` to `send_to` at `at`, see `chat_time` for reading one.
+/// Posted by the message stream once due, through `post::post_message`.
+pub fn schedule_message(
+    settings: &Settings,
+    at: DateTime<FixedOffset>,
+    send_to: &str,
+    text: &str,
+    post_if_missed: bool,
+) -> Result<u64, ScheduleErr> {
+    let mut schedule = settings.schedule.lock().unwrap();
+    let schedule = schedule.as_mut().ok_or(ScheduleErr::NotConfigured)?;
+    schedule.add(at, send_to, text, post_if_missed, timestamp::server_now(settings.server_offset))
+}
+
+/// The messages waiting, soonest first.
+pub fn scheduled(settings: &Settings) -> Vec<Scheduled> {
+    settings.schedule.lock().unwrap().as_ref().map(|s| s.entries().to_vec()).unwrap_or_default()
+}
+
+/// Whether there was a message `id` waiting.
+pub fn cancel_scheduled(settings: &Settings, id: u64) -> bool {
+    settings.schedule.lock().unwrap().as_mut().is_some_and(|s| s.cancel(id))
+}
+
+/// Post what is due, done by the message stream after each fetch. Returns
+/// the messages that failed and aren't kept by the outbox either.
+pub fn post_due(transport: &Transport, base_url: &str, page_php: &str, session: &str) -> Vec<(Scheduled, PostErr)> {
+    exchange::block_on(post_due_with(transport, base_url, page_php, session, timestamp::server_now(transport.settings().server_offset)))
+}
+
+/// `post_due` over any `Exchange`, as of `now`.
+pub async fn post_due_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    now: DateTime<FixedOffset>,
+) -> Vec<(Scheduled, PostErr)> {
+    // Not locked while posting, messages may be scheduled meanwhile
+    let due = match http.settings().schedule.lock().unwrap().as_mut() {
+        Some(schedule) => schedule.take_due(now),
+        None => return vec![],
+    };
+    let mut failed = vec![];
+    for entry in due {
+        if let Err(e) = post::post_message_with(http, base_url, page_php, session, entry.command()).await {
+            if !outbox::keeps(http.settings(), &e) {
+                failed.push((entry, e));
+            }
+        }
+    }
+    failed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lechatphp::mock::{MockExchange, MockResponse};
+    use std::sync::Mutex;
+
+    const BASE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+
+    fn at(time: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_rfc3339(&format!("2099-10-18T{}+02:00", time)).unwrap()
+    }
+
+    fn path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("bhcli-schedule-{}-{}.json", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn chat_time_test() {
+        let now = at("20:00:00");
+        assert_eq!(chat_time("21:00", now), Some(at("21:00:00")));
+        assert_eq!(chat_time(" 20:00:30 ", now), Some(at("20:00:30")));
+        // Past today, so tomorrow
+        assert_eq!(chat_time("08:15", now), DateTime::parse_from_rfc3339("2099-10-19T08:15:00+02:00").ok());
+        assert_eq!(chat_time("2099-10-18 19:00", now), Some(at("19:00:00")));
+        assert_eq!(chat_time("9pm", now), None);
+    }
+
+    #[test]
+    fn schedule_test() {
+        let path = path("due");
+        let (mut schedule, _) = Schedule::load(&path, at("20:00:00")).unwrap();
+        let announce = schedule.add(at("21:00:00"), SEND_TO_ALL, "/me is back at 22:00", false, at("20:00:00")).unwrap();
+        let reminder = schedule.add(at("20:30:00"), "bob", "the notes", true, at("20:00:00")).unwrap();
+        let dropped = schedule.add(at("20:45:00"), SEND_TO_ALL, "never mind", false, at("20:00:00")).unwrap();
+        assert!(matches!(schedule.add(at("19:00:00"), SEND_TO_ALL, "late", false, at("20:00:00")), Err(ScheduleErr::Past)));
+        assert!(matches!(schedule.add(at("21:00:00"), SEND_TO_ALL, " ", false, at("20:00:00")), Err(ScheduleErr::EmptyMessage)));
+        // Soonest first
+        assert_eq!(schedule.entries().iter().map(|e| e.id).collect::<Vec<_>>(), [reminder, dropped, announce]);
+        assert!(schedule.cancel(dropped) && !schedule.cancel(dropped));
+
+        assert!(schedule.take_due(at("20:29:59")).is_empty());
+        let due = schedule.take_due(at("21:00:00"));
+        assert_eq!(due.iter().map(|e| e.id).collect::<Vec<_>>(), [reminder, announce]);
+        assert_eq!(due[0].command(), ChatCommand::Whisper { to: "bob".to_owned(), text: "the notes".to_owned() });
+        assert_eq!(due[1].command(), ChatCommand::Action("is back at 22:00".to_owned()));
+        // Taken out for good
+        let (schedule, missed) = Schedule::load(&path, at("20:00:00")).unwrap();
+        assert!(schedule.entries().is_empty() && missed.is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn missed_test() {
+        let path = path("missed");
+        let (mut schedule, _) = Schedule::load(&path, at("20:00:00")).unwrap();
+        schedule.add(at("20:10:00"), SEND_TO_ALL, "posted late", true, at("20:00:00")).unwrap();
+        schedule.add(at("20:20:00"), SEND_TO_ALL, "too late", false, at("20:00:00")).unwrap();
+        schedule.add(at("23:00:00"), SEND_TO_ALL, "still ahead", false, at("20:00:00")).unwrap();
+
+        // The client was off from 20:05 to 22:00
+        let (schedule, missed) = Schedule::load(&path, at("22:00:00")).unwrap();
+        assert_eq!(missed.iter().map(|e| e.text.as_str()).collect::<Vec<_>>(), ["too late"]);
+        assert_eq!(schedule.entries().iter().map(|e| e.text.as_str()).collect::<Vec<_>>(), ["posted late", "still ahead"]);
+        // Posted through the post box once the stream polls
+        let mut http = MockExchange::new(|_| Ok(MockResponse::ok(include_str!("fixtures/post_ok.html"))));
+        http.settings.schedule = Mutex::new(Some(schedule));
+        assert!(exchange::block_on(post_due_with(&http, BASE_URL, "chat.php", "schedule-test", at("22:00:05"))).is_empty());
+        let posted: Vec<_> = http.requests.borrow().iter().filter(|r| r.method == "POST").map(|r| r.body.clone()).collect();
+        assert_eq!(posted.len(), 1);
+        assert!(posted[0].contains("&message=posted late&"));
+        assert_eq!(scheduled(&http.settings).iter().map(|e| e.text.as_str()).collect::<Vec<_>>(), ["still ahead"]);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,226 @@
+use super::command::ACTION_PREFIX;
+use super::messages::Message;
+use super::settings::Settings;
+use std::collections::{HashSet, VecDeque};
+use std::time::Instant;
+
+/// Posts remembered per session, the oldest are forgotten.
+const CAPACITY: usize = 100;
+
+/// One of our posts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SentMessage {
+    /// As posted, `/me ` included.
+    pub text: String,
+    pub posted_at: Instant,
+    /// The server's, from the first fetch showing the message after the
+    /// post. Only those who can delete messages see ids.
+    pub id: Option<u64>,
+    /// More than one of our messages in that fetch had the text, the
+    /// newest was taken.
+    pub ambiguous: bool,
+}
+
+/// Our posts of a session and the ids the chat gave them.
+#[derive(Debug, Clone, Default)]
+pub struct SentMessages {
+    nick: String,
+    /// Newest first.
+    sent: VecDeque<SentMessage>,
+}
+
+// How the view shows a post: no `/me `, whitespace collapsed
+fn normalize(text: &str) -> String {
+    let text = text.strip_prefix(ACTION_PREFIX).unwrap_or(text);
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+impl SentMessages {
+    pub fn new(nick: &str) -> Self {
+        Self { nick: nick.to_owned(), sent: VecDeque::new() }
+    }
+
+    pub fn record(&mut self, text: &str) {
+        self.sent.push_front(SentMessage { text: text.to_owned(), posted_at: Instant::now(), id: None, ambiguous: false });
+        self.sent.truncate(CAPACITY);
+    }
+
+    /// Give the posts still without an id the one of our newest message
+    /// with the same text in `messages`, newest first like the view. The
+    /// newest posts are matched first, to the newest messages.
+    pub fn correlate(&mut self, messages: &[Message]) {
+        let mut taken: HashSet<u64> = self.sent.iter().filter_map(|s| s.id).collect();
+        let ours: Vec<_> = messages
+            .iter()
+            .filter(|m| m.from.as_deref() == Some(self.nick.as_str()))
+            .filter_map(|m| Some((m.id?, normalize(&m.text))))
+            .collect();
+        for sent in self.sent.iter_mut().filter(|s| s.id.is_none()) {
+            let text = normalize(&sent.text);
+            let mut matches = ours.iter().filter(|(id, t)| *t == text && !taken.contains(id)).map(|(id, _)| *id);
+            if let Some(id) = matches.next() {
+                sent.id = Some(id);
+                sent.ambiguous = matches.next().is_some();
+                taken.insert(id);
+            }
+        }
+    }
+
+    /// Newest first.
+    pub fn iter(&self) -> impl Iterator<Item = &SentMessage> {
+        self.sent.iter()
+    }
+
+    /// The newest post with this text.
+    pub fn find(&self, text: &str) -> Option<&SentMessage> {
+        let text = normalize(text);
+        self.sent.iter().find(|s| normalize(&s.text) == text)
+    }
+
+    pub fn forget(&mut self, id: u64) {
+        self.sent.retain(|s| s.id != Some(id));
+    }
+}
+
+/// Start remembering the posts of `session`, done by `login`.
+pub fn track(settings: &Settings, session: &str, nick: &str) {
+    settings.sent.lock().unwrap().insert(session.to_owned(), SentMessages::new(nick));
+}
+
+pub fn untrack(settings: &Settings, session: &str) {
+    settings.sent.lock().unwrap().remove(session);
+}
+
+pub(super) fn record(settings: &Settings, session: &str, text: &str) {
+    if let Some(sent) = settings.sent.lock().unwrap().get_mut(session) {
+        sent.record(text);
+    }
+}
+
+pub(super) fn correlate(settings: &Settings, session: &str, messages: &[Message]) {
+    if let Some(sent) = settings.sent.lock().unwrap().get_mut(session) {
+        sent.correlate(messages);
+    }
+}
+
+pub(super) fn forget(settings: &Settings, session: &str, id: u64) {
+    if let Some(sent) = settings.sent.lock().unwrap().get_mut(session) {
+        sent.forget(id);
+    }
+}
+
+// After deleting all our messages
+pub(super) fn forget_all(settings: &Settings, session: &str) {
+    if let Some(sent) = settings.sent.lock().unwrap().get_mut(session) {
+        sent.sent.clear();
+    }
+}
+
+/// The posts of `from` go on as posts of `to`, a new login of the same
+/// nick. Left as they are for another nick.
+pub(super) fn take_over(settings: &Settings, from: &str, to: &str) {
+    let mut tracked = settings.sent.lock().unwrap();
+    let Some(old) = tracked.get(from).cloned() else {
+        return;
+    };
+    if let Some(new) = tracked.get_mut(to).filter(|new| new.nick == old.nick) {
+        new.sent.extend(old.sent);
+        new.sent.truncate(CAPACITY);
+        tracked.remove(from);
+    }
+}
+
+/// What was posted in `session` so far, empty for sessions not tracked.
+pub fn sent_messages(settings: &Settings, session: &str) -> SentMessages {
+    settings.sent.lock().unwrap().get(session).cloned().unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lechatphp::exchange;
+    use crate::lechatphp::messages::fetch_messages_with;
+    use crate::lechatphp::mock::{MockExchange, MockResponse};
+    use crate::lechatphp::post::post_message_with;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const BASE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+
+    fn view(messages: &[(u64, &str, &str)]) -> String {
+        let divs: String = messages
+            .iter()
+            .map(|(id, from, text)| {
+                format!(
+                    r#"<div class="msg"><label><input type="checkbox" name="mid[]" value="{}"></label><small>10-17 19:40:02 - </small><span class="usermsg"><span style="color:#FF0000;">{}</span> - {}</span></div>"#,
+                    id, from, text
+                )
+            })
+            .collect();
+        format!(r#"<html><body><div id="messages">{}</div></body></html>"#, divs)
+    }
+
+    // The post box, and the view `view` holds once posted
+    fn chat(view: Rc<RefCell<String>>) -> MockExchange {
+        MockExchange::new(move |req| {
+            let page = if req.path.contains("action=view") { view.borrow().clone() } else { include_str!("fixtures/post_ok.html").to_owned() };
+            Ok(MockResponse::ok(&page))
+        })
+    }
+
+    #[test]
+    fn sent_messages_test() {
+        let session = "sent-test";
+        let page = Rc::new(RefCell::new(view(&[(12, "alice", "hello world"), (11, "bob", "hello world"), (10, "alice", "earlier")])));
+        let http = chat(page.clone());
+        let post = |text: &str| exchange::block_on(post_message_with(&http, BASE_URL, "chat.php", session, text)).unwrap();
+        let fetch = || exchange::block_on(fetch_messages_with(&http, BASE_URL, "chat.php", session)).unwrap();
+        let sent = || sent_messages(&http.settings, session);
+        track(&http.settings, session, "alice");
+
+        post("hello   world");
+        assert_eq!(sent().find("hello world").unwrap().id, None);
+        fetch();
+        let hello = sent().find("hello world").cloned().unwrap();
+        assert_eq!((hello.id, hello.ambiguous), (Some(12), false));
+        // Another client's posts
+        assert_eq!(sent_messages(&Settings::default(), session).iter().count(), 0);
+
+        // Said twice, the newest post gets the newest message
+        *page.borrow_mut() = view(&[(15, "alice", "same"), (14, "bob", "x"), (13, "alice", "same"), (12, "alice", "hello world")]);
+        post("same");
+        fetch();
+        let ids: Vec<_> = sent().iter().map(|s| (s.id, s.ambiguous)).collect();
+        assert_eq!(ids, [(Some(15), true), (Some(12), false)]);
+        post("same");
+        fetch();
+        let ids: Vec<_> = sent().iter().map(|s| (s.id, s.ambiguous)).collect();
+        assert_eq!(ids, [(Some(13), false), (Some(15), true), (Some(12), false)]);
+
+        // Not in the view yet, or to someone who can't see ids
+        *page.borrow_mut() = r#"<html><body><div id="messages"><div class="msg"><small>10-17 19:40:02 - </small><span class="usermsg"><span style="color:#FF0000;">alice</span> - later</span></div></div></body></html>"#.to_owned();
+        post("later");
+        fetch();
+        assert_eq!(sent().find("later").unwrap().id, None);
+
+        // An action
+        *page.borrow_mut() = r#"<html><body><div id="messages"><div class="msg"><label><input type="checkbox" name="mid[]" value="20"></label><small>10-17 19:40:02 - </small><span class="usermsg"><span style="color:#FF0000;">alice</span> waves</span></div></div></body></html>"#.to_owned();
+        post("/me waves");
+        fetch();
+        assert_eq!(sent().find("/me waves").unwrap().id, Some(20));
+
+        untrack(&http.settings, session);
+        assert_eq!(sent().iter().count(), 0);
+    }
+
+    #[test]
+    fn capacity_test() {
+        let mut sent = SentMessages::new("alice");
+        for i in 0..CAPACITY + 5 {
+            sent.record(&i.to_string());
+        }
+        assert_eq!(sent.iter().count(), CAPACITY);
+        assert_eq!(sent.iter().next().unwrap().text, (CAPACITY + 4).to_string());
+        assert!(sent.find("4").is_none());
+    }
+}
@@ -0,0 +1,218 @@
+// What a client was built with, and the state that goes with it: limits,
+// hooks, stores. Kept on its `Transport` and read from there, so clients
+// built with different configs never see each other's.
+use super::captcha_prompt::Prompt;
+use super::capture::Capture;
+use super::catalog::Catalog;
+use super::client::ClientConfig;
+use super::conversations::Conversations;
+use super::display::DisplayOverrides;
+use super::emoji::{EmojiConfig, Table};
+use super::feed::{self, Feed, FeedEndpoint};
+use super::filter::{FilterCheck, Filters};
+use super::hook::Hook;
+use super::history::{self, History};
+use super::http_log::HttpLog;
+use super::mention::Mentions;
+use super::messages::{SessionRoom, Topic};
+use super::metrics::Metrics;
+use super::outbox::{self, Outbox};
+use super::post::{self, MultiLine, PostBox, ReplayGuard};
+use super::purge::Purger;
+use super::rate_limit::Limiter;
+use super::retry::{RetryPolicy, Timeouts};
+use super::schedule::{self, Schedule};
+use super::sent::SentMessages;
+use super::share::ShareTarget;
+use super::stream::Seen;
+use super::timestamp;
+use super::tls::{LoadedPin, Pins};
+use super::transport;
+use super::unread::ReadMarkers;
+use super::Progress;
+use chrono::FixedOffset;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// A client's settings, see `Transport::settings`. Each field is the
+/// `ClientConfig` one of the same name, ready to use.
+#[non_exhaustive]
+pub struct Settings {
+    pub retry: RetryPolicy,
+    /// `ClientConfig::read_timeout` and `ClientConfig::deadline`, `None`
+    /// for no deadline nor cap on attempts.
+    pub timeouts: Option<Timeouts>,
+    pub http_log: HttpLog,
+    pub rate_limit: Limiter,
+    pub pins: Pins,
+    pub max_body_size: usize,
+    pub max_upload_size: u64,
+    pub max_message_len: usize,
+    pub max_message_parts: usize,
+    pub multi_line: MultiLine,
+    pub replay_guard: ReplayGuard,
+    pub emoji: Table,
+    /// `ClientConfig::server_utc_offset`.
+    pub server_offset: FixedOffset,
+    /// `ClientConfig::mention`, and the nick of the last login.
+    pub mention: Mentions,
+    pub filter_check: FilterCheck,
+    /// The chat's filters `filter_check` goes by, none until set.
+    pub filters: Filters,
+    pub metrics: Metrics,
+    pub capture: Capture,
+    pub history: Option<History>,
+    pub(crate) read_markers: ReadMarkers,
+    /// `ClientConfig::pm_aliases`, and the private messages streamed.
+    pub(crate) conversations: Conversations,
+    pub feed_endpoints: Vec<FeedEndpoint>,
+    pub(crate) outbox: Mutex<Option<Outbox>>,
+    pub(crate) schedule: Mutex<Option<Schedule>>,
+    /// `ClientConfig::auto_purge`, and when it last ran.
+    pub(crate) purge: Mutex<Option<Purger>>,
+    /// `ClientConfig::display`, empty when its rules don't compile.
+    pub display: DisplayOverrides,
+    /// `ClientConfig::share`, `None` when it can't be used.
+    pub share: Option<ShareTarget>,
+    pub data_dir: Option<PathBuf>,
+    /// Read from `ClientConfig::catalog`, empty without one.
+    pub catalog: Catalog,
+    pub(crate) prompt: Hook<Prompt>,
+    pub(crate) progress: Hook<Progress>,
+    /// `messages::set_ignored`.
+    pub(crate) ignored: Mutex<Vec<String>>,
+    // By session, from login to logout
+    pub(crate) rooms: Mutex<HashMap<String, String>>,
+    pub(crate) topics: Mutex<HashMap<SessionRoom, Option<Topic>>>,
+    pub(crate) feeds: Mutex<HashMap<String, Feed>>,
+    pub(crate) post_boxes: Mutex<HashMap<String, Option<PostBox>>>,
+    pub(crate) sent: Mutex<HashMap<String, SentMessages>>,
+    /// By chat, for the streams after a new login.
+    pub(crate) seen: Mutex<HashMap<String, Seen>>,
+}
+
+impl Settings {
+    /// The settings of `config`. The stores it names are opened, those that
+    /// can't be are logged and left out.
+    pub(super) fn new(config: &ClientConfig, pins: &[LoadedPin], catalog: Option<Catalog>) -> Self {
+        let server_offset = config.server_utc_offset;
+        let display = DisplayOverrides::new(&config.display).unwrap_or_else(|e| {
+            log::warn!("{}, nicks are shown as they are", e);
+            DisplayOverrides::default()
+        });
+        let share = match config.share.as_ref().map(ShareTarget::check) {
+            Some(Err(e)) => {
+                log::warn!("{}, nothing is shared", e);
+                None
+            }
+            _ => config.share.clone(),
+        };
+        let now = timestamp::server_now(server_offset);
+        Self {
+            retry: config.retry.clone(),
+            timeouts: Some(Timeouts { read: config.read_timeout, deadline: config.deadline }),
+            http_log: config.http_log,
+            rate_limit: Limiter::new(config.rate_limit),
+            pins: Pins::new(pins),
+            max_body_size: config.max_body_size,
+            max_upload_size: config.max_upload_size,
+            max_message_len: config.max_message_len,
+            max_message_parts: config.max_message_parts,
+            multi_line: config.multi_line.clone(),
+            replay_guard: config.replay_guard.clone(),
+            emoji: Table::new(&config.emoji),
+            server_offset,
+            mention: Mentions::new(&config.mention, display.targets()),
+            filter_check: config.filter_check,
+            filters: Filters::default(),
+            metrics: Metrics::new(config.metrics, Some(config.pool.effective(&config.protocol))),
+            capture: Capture::new(config.capture.clone()),
+            history: history::open(config.history.clone()),
+            read_markers: ReadMarkers::new(config.read_markers.clone()),
+            conversations: Conversations::new(&config.pm_aliases),
+            feed_endpoints: config.feed_endpoints.clone(),
+            outbox: Mutex::new(outbox::open(config.outbox.clone())),
+            schedule: Mutex::new(schedule::open(config.schedule.clone(), now)),
+            purge: Mutex::new(config.auto_purge.clone().map(Purger::new)),
+            display,
+            share,
+            data_dir: config.data_dir.clone(),
+            catalog: catalog.unwrap_or_default(),
+            prompt: Hook::new(),
+            progress: Hook::new(),
+            ignored: Mutex::default(),
+            rooms: Mutex::default(),
+            topics: Mutex::default(),
+            feeds: Mutex::default(),
+            post_boxes: Mutex::default(),
+            sent: Mutex::default(),
+            seen: Mutex::default(),
+        }
+    }
+}
+
+// Those of `ClientConfig::default`, without any store
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            retry: RetryPolicy::default(),
+            timeouts: None,
+            http_log: HttpLog::Off,
+            rate_limit: Limiter::default(),
+            pins: Pins::default(),
+            max_body_size: transport::DEFAULT_MAX_BODY_SIZE,
+            max_upload_size: post::DEFAULT_MAX_UPLOAD_SIZE,
+            max_message_len: post::DEFAULT_MAX_MESSAGE_LEN,
+            max_message_parts: post::DEFAULT_MAX_MESSAGE_PARTS,
+            multi_line: MultiLine::default(),
+            replay_guard: ReplayGuard::default(),
+            emoji: Table::new(&EmojiConfig::default()),
+            server_offset: FixedOffset::east_opt(0).unwrap(),
+            mention: Mentions::default(),
+            filter_check: FilterCheck::default(),
+            filters: Filters::default(),
+            metrics: Metrics::default(),
+            capture: Capture::default(),
+            history: None,
+            read_markers: ReadMarkers::default(),
+            conversations: Conversations::default(),
+            feed_endpoints: feed::default_endpoints(),
+            outbox: Mutex::new(None),
+            schedule: Mutex::new(None),
+            purge: Mutex::new(None),
+            display: DisplayOverrides::default(),
+            share: None,
+            data_dir: None,
+            catalog: Catalog::default(),
+            prompt: Hook::new(),
+            progress: Hook::new(),
+            ignored: Mutex::default(),
+            rooms: Mutex::default(),
+            topics: Mutex::default(),
+            feeds: Mutex::default(),
+            post_boxes: Mutex::default(),
+            sent: Mutex::default(),
+            seen: Mutex::default(),
+        }
+    }
+}
+
+// The stores and hooks have nothing worth printing
+impl Debug for Settings {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Settings")
+            .field("retry", &self.retry)
+            .field("timeouts", &self.timeouts)
+            .field("http_log", &self.http_log)
+            .field("rate_limit", &self.rate_limit)
+            .field("pins", &self.pins)
+            .field("max_body_size", &self.max_body_size)
+            .field("max_message_len", &self.max_message_len)
+            .field("server_offset", &self.server_offset)
+            .field("metrics", &self.metrics)
+            .field("data_dir", &self.data_dir)
+            .finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,286 @@
+use super::error_kind::{Classify, ErrorKind};
+use super::exchange::{self, Exchange, Upload};
+use super::metrics::Operation;
+use super::post::{self, PostErr};
+use super::retry::SendErr;
+use super::transport::Transport;
+use http::StatusCode;
+use regex::Regex;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+use std::io;
+use thiserror::Error;
+
+/// Largest file `share` uploads unless configured otherwise.
+pub const DEFAULT_MAX_SHARE_SIZE: u64 = 5 * 1024 * 1024;
+
+/// The image host `share` uploads to. Each takes a `multipart/form-data`
+/// POST and answers with the link somewhere, `url_regex` or `json_pointer`
+/// finds it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShareTarget {
+    pub url: String,
+    /// The file's field in the form.
+    pub field: String,
+    /// Sent along, e.g. an API key.
+    pub params: BTreeMap<String, String>,
+    /// The link in the answer, the first group when it has one.
+    pub url_regex: Option<String>,
+    /// Or the link in a JSON answer, like `/data/url`.
+    pub json_pointer: Option<String>,
+    pub max_size: u64,
+    /// The types uploaded, by the file's extension.
+    pub allowed_types: Vec<String>,
+}
+
+impl Default for ShareTarget {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            field: "file".to_owned(),
+            params: BTreeMap::new(),
+            url_regex: None,
+            json_pointer: None,
+            max_size: DEFAULT_MAX_SHARE_SIZE,
+            allowed_types: ["image/png", "image/jpeg", "image/gif", "image/webp"].map(str::to_owned).to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ShareErr {
+    /// The client has no `ClientConfig::share`.
+    #[error("no upload target configured")]
+    NotConfigured,
+    /// The target is missing its url, or has no way or two ways to find
+    /// the link.
+    #[error("upload target: {0}")]
+    Config(String),
+    #[error("cannot read the file: {0}")]
+    File(#[source] io::Error),
+    #[error("file too large, {max} bytes at most")]
+    TooLarge { max: u64 },
+    #[error("{mime} files are not shared")]
+    Type { mime: String },
+    #[error("{0}")]
+    Send(#[from] SendErr),
+    /// The host refused the file.
+    #[error("the upload host answered {0}")]
+    Status(StatusCode),
+    /// Nothing in the host's answer looks like the link.
+    #[error("no link in the upload host's answer")]
+    NoUrl,
+    /// Uploaded to `url`, but posting the link failed.
+    #[error("uploaded to {url} but not posted: {error}")]
+    Post { url: String, #[source] error: PostErr },
+}
+
+impl Classify for ShareErr {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            ShareErr::Send(e) => e.kind(),
+            ShareErr::Post { error, .. } => error.kind(),
+            ShareErr::Status(status) if status.is_server_error() => ErrorKind::Transient,
+            ShareErr::NotConfigured
+            | ShareErr::Config(_)
+            | ShareErr::File(_)
+            | ShareErr::TooLarge { .. }
+            | ShareErr::Type { .. }
+            | ShareErr::Status(_) => ErrorKind::InvalidInput,
+            ShareErr::NoUrl => ErrorKind::Fatal,
+        }
+    }
+}
+
+// How the link is found in the host's answer
+enum Extract {
+    Regex(Regex),
+    Pointer(String),
+}
+
+impl ShareTarget {
+    fn extract(&self) -> Result<Extract, ShareErr> {
+        if self.url.trim().is_empty() {
+            return Err(ShareErr::Config("no url".to_owned()));
+        }
+        match (&self.url_regex, &self.json_pointer) {
+            (Some(rgx), None) => Regex::new(rgx).map(Extract::Regex).map_err(|e| ShareErr::Config(e.to_string())),
+            (None, Some(pointer)) => Ok(Extract::Pointer(pointer.clone())),
+            _ => Err(ShareErr::Config("set one of url_regex and json_pointer".to_owned())),
+        }
+    }
+
+    /// Whether the target can be used, for checking a config early.
+    pub fn check(&self) -> Result<(), ShareErr> {
+        self.extract().map(|_| ())
+    }
+}
+
+impl Extract {
+    fn url(&self, body: &str) -> Option<String> {
+        let url = match self {
+            Extract::Regex(rgx) => {
+                let caps = rgx.captures(body)?;
+                caps.get(1).or(caps.get(0))?.as_str().to_owned()
+            }
+            Extract::Pointer(pointer) => {
+                let json: serde_json::Value = serde_json::from_str(body).ok()?;
+                json.pointer(pointer)?.as_str()?.to_owned()
+            }
+        };
+        let url = url.trim().replace("\\/", "/");
+        (url.starts_with("http://") || url.starts_with("https://")).then_some(url)
+    }
+}
+
+/// Upload the file at `file_path` to `target` and post its link, after
+/// `caption` when there is one. Both go through `http`, the session's Tor
+/// client. Nothing is sent for a file too large or of a type not allowed.
+/// Returns the link.
+pub async fn share_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    target: &ShareTarget,
+    file_path: &Path,
+    caption: Option<&str>,
+) -> Result<String, ShareErr> {
+    let extract = target.extract()?;
+    let file = File::open(file_path).map_err(ShareErr::File)?;
+    let len = file.metadata().map_err(ShareErr::File)?.len();
+    if len > target.max_size {
+        return Err(ShareErr::TooLarge { max: target.max_size });
+    }
+    let mime = post::mime_type(file_path);
+    if !target.allowed_types.iter().any(|allowed| allowed.eq_ignore_ascii_case(mime)) {
+        return Err(ShareErr::Type { mime: mime.to_owned() });
+    }
+
+    let file_name = file_path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let params: Vec<_> = target.params.iter().map(|(name, value)| (name.as_str(), value.clone())).collect();
+    let upload = Upload { field: target.field.clone(), file_name, mime: mime.to_owned(), len, file };
+    let page = http.post_multipart(Operation::Post, &target.url, &params, upload).await?;
+    if !page.status.is_success() {
+        return Err(ShareErr::Status(page.status));
+    }
+    let url = extract.url(&page.body?).ok_or(ShareErr::NoUrl)?;
+
+    let text = match caption.map(str::trim).filter(|c| !c.is_empty()) {
+        Some(caption) => format!("{} {}", caption, url),
+        None => url.clone(),
+    };
+    match post::post_message_with(http, base_url, page_php, session, text).await {
+        Ok(()) => Ok(url),
+        Err(error) => Err(ShareErr::Post { url, error }),
+    }
+}
+
+/// Upload to `target` and post the link, see `share_with`.
+pub fn share(
+    transport: &Transport,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    target: &ShareTarget,
+    file_path: &Path,
+    caption: Option<&str>,
+) -> Result<String, ShareErr> {
+    exchange::block_on(share_with(transport, base_url, page_php, session, target, file_path, caption))
+}
+
+/// `share` to the client's `ClientConfig::share`.
+pub fn share_file(
+    transport: &Transport,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    file_path: &Path,
+    caption: Option<&str>,
+) -> Result<String, ShareErr> {
+    let target = transport.settings().share.as_ref().ok_or(ShareErr::NotConfigured)?;
+    share(transport, base_url, page_php, session, target, file_path, caption)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lechatphp::mock::{MockResponse, MockServer};
+    use std::sync::{Arc, Mutex};
+
+    // Path, content type and body of each POST
+    type Sent = Arc<Mutex<Vec<(String, String, String)>>>;
+
+    // The image host at `/upload` answering `answer`, and the chat, with
+    // what each was sent
+    fn servers(answer: &'static str) -> (MockServer, Sent) {
+        let sent = Arc::new(Mutex::new(vec![]));
+        let kept = Arc::clone(&sent);
+        let server = MockServer::start(move |req| {
+            if req.method == "POST" {
+                kept.lock().unwrap().push((req.path.clone(), req.header("content-type").unwrap_or_default(), req.body.clone()));
+            }
+            match (req.method.as_str(), req.path.as_str()) {
+                ("POST", "/upload") => MockResponse::ok(answer),
+                _ => MockResponse::ok(include_str!("fixtures/post_ok.html")),
+            }
+        });
+        (server, sent)
+    }
+
+    #[test]
+    fn share_test() {
+        let dir = std::env::temp_dir().join(format!("bhcli-share-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let shot = dir.join("shot.png");
+        std::fs::write(&shot, "\u{89}PNG fake image").unwrap();
+
+        // A host answering with a page
+        let (server, sent) = servers(r#"<html><body>Done! <input value="https://img.example/i/a1b2.png" readonly></body></html>"#);
+        let target = ShareTarget {
+            url: format!("{}/upload", server.url),
+            field: "image".to_owned(),
+            params: BTreeMap::from([("expire".to_owned(), "1d".to_owned())]),
+            url_regex: Some(r#"value="(https://img\.example/[^"]+)""#.to_owned()),
+            ..ShareTarget::default()
+        };
+        let url = share(&Transport::direct(), &server.url, "chat.php", "share-test", &target, &shot, Some("my desk")).unwrap();
+        assert_eq!(url, "https://img.example/i/a1b2.png");
+        let sent = sent.lock().unwrap();
+        let (path, content_type, body) = &sent[0];
+        assert_eq!(path, "/upload");
+        let boundary = content_type.strip_prefix("multipart/form-data; boundary=").unwrap();
+        let parts: Vec<_> = body.split(&format!("--{}", boundary)).map(str::trim).filter(|p| !p.is_empty() && *p != "--").collect();
+        assert!(parts[0].starts_with(r#"Content-Disposition: form-data; name="expire""#) && parts[0].ends_with("1d"));
+        assert!(parts[1].starts_with(r#"Content-Disposition: form-data; name="image"; filename="shot.png""#));
+        assert!(parts[1].contains("Content-Type: image/png") && parts[1].ends_with("PNG fake image"));
+        // Then the link, through the post box
+        assert_eq!(sent[1].0, "/chat.php");
+        assert!(sent[1].2.contains("message=my+desk+https%3A%2F%2Fimg.example%2Fi%2Fa1b2.png"));
+        drop(sent);
+
+        // A JSON API, escaped slashes and all
+        let (server, _) = servers(r#"{"status":200,"data":{"link":"https:\/\/img.example\/i\/c3d4.png"}}"#);
+        let json = ShareTarget { url: format!("{}/upload", server.url), json_pointer: Some("/data/link".to_owned()), ..ShareTarget::default() };
+        assert_eq!(share(&Transport::direct(), &server.url, "chat.php", "share-test", &json, &shot, None).unwrap(), "https://img.example/i/c3d4.png");
+        let missing = ShareTarget { json_pointer: Some("/data/url".to_owned()), ..json.clone() };
+        assert!(matches!(share(&Transport::direct(), &server.url, "chat.php", "share-test", &missing, &shot, None), Err(ShareErr::NoUrl)));
+
+        // Refused before anything is sent
+        let (server, sent) = servers("");
+        let strict = ShareTarget { url: format!("{}/upload", server.url), max_size: 8, ..json };
+        let refuse = |target: &ShareTarget, path: &Path| share(&Transport::direct(), &server.url, "chat.php", "share-test", target, path, None);
+        assert!(matches!(refuse(&strict, &shot), Err(ShareErr::TooLarge { max: 8 })));
+        let notes = dir.join("notes.txt");
+        std::fs::write(&notes, "text").unwrap();
+        assert!(matches!(refuse(&strict, &notes), Err(ShareErr::Type { mime }) if mime == "text/plain"));
+        let both = ShareTarget { url_regex: Some("x".to_owned()), ..strict.clone() };
+        assert!(matches!(refuse(&both, &shot), Err(ShareErr::Config(_))));
+        assert!(sent.lock().unwrap().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,30 @@
+// Tracing spans of the protocol operations: login and under it the login
+// form, the captcha and each waitroom hop; post, fetch and logout. Their fields are the mirror, attempt
+// numbers and HTTP statuses, never a password, captcha answer or session.
+use http::StatusCode;
+use std::time::Instant;
+use tracing::Span;
+
+/// Records `elapsed_ms` on the span it was started in when dropped, at the
+/// end of the operation.
+pub(crate) struct Timer {
+    span: Span,
+    started: Instant,
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.span.record("elapsed_ms", self.started.elapsed().as_millis() as u64);
+    }
+}
+
+/// Time the current span, see `Timer`. The span needs an `elapsed_ms` field.
+pub(crate) fn timer() -> Timer {
+    Timer { span: Span::current(), started: Instant::now() }
+}
+
+/// The status of the answer, on the current span when it has a `status`
+/// field.
+pub(crate) fn record_status(status: StatusCode) {
+    Span::current().record("status", status.as_u16());
+}
@@ -0,0 +1,91 @@
+// Writing the files the client keeps. None of them is worth a panic: a
+// directory mounted read-only only means they aren't kept.
+use super::error_kind::{Classify, ErrorKind};
+use lazy_static::lazy_static;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::{fs, io};
+use thiserror::Error;
+
+lazy_static! {
+    // What failed to be written once, skipped since
+    static ref SKIPPED: Mutex<HashSet<&'static str>> = Mutex::new(HashSet::new());
+}
+
+/// A file that couldn't be written, `what` says which.
+#[derive(Debug, Error)]
+#[error("can't write the {what} to {}: {source}", path.display())]
+pub struct StorageError {
+    pub what: &'static str,
+    pub path: PathBuf,
+    #[source]
+    pub source: io::Error,
+}
+
+impl Classify for StorageError {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Fatal
+    }
+}
+
+/// Write `path` with `write`, creating its directory first.
+pub fn write(what: &'static str, path: &Path, write: impl FnOnce(&Path) -> io::Result<()>) -> Result<(), StorageError> {
+    let parent = path.parent().unwrap_or(Path::new(""));
+    fs::create_dir_all(parent)
+        .and_then(|_| write(path))
+        .map_err(|source| StorageError { what, path: path.to_owned(), source })
+}
+
+/// Like `write`, for a file the client does without. The first failure
+/// is a warning, and later writes of the same `what` are skipped. Whether
+/// it was written.
+pub fn write_optional(what: &'static str, path: &Path, write: impl FnOnce(&Path) -> io::Result<()>) -> bool {
+    if skipped(what) {
+        return false;
+    }
+    match self::write(what, path, write) {
+        Ok(()) => true,
+        Err(e) => {
+            log::warn!("{}, not writing it again", e);
+            SKIPPED.lock().unwrap_or_else(|e| e.into_inner()).insert(what);
+            false
+        }
+    }
+}
+
+/// Whether writes of `what` are skipped after a failure.
+pub fn skipped(what: &str) -> bool {
+    SKIPPED.lock().unwrap_or_else(|e| e.into_inner()).contains(what)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_optional_test() {
+        let dir = std::env::temp_dir().join(format!("bhcli-storage-{}", std::process::id()));
+        // A file where the directory should be, even root can't write under it
+        let blocker = dir.join("blocker");
+        write("test blocker", &blocker, |path| fs::write(path, "")).unwrap();
+
+        let err = write("test essential", &blocker.join("a.txt"), |path| fs::write(path, "a")).unwrap_err();
+        assert_eq!((err.what, err.path.clone()), ("test essential", blocker.join("a.txt")));
+        assert!(err.to_string().starts_with("can't write the test essential to "), "{}", err);
+        assert!(!skipped("test essential"));
+
+        let mut calls = 0;
+        for _ in 0..3 {
+            assert!(!write_optional("test optional", &blocker.join("b.txt"), |path| {
+                calls += 1;
+                fs::write(path, "b")
+            }));
+        }
+        assert!(skipped("test optional"));
+        assert_eq!(calls, 0, "the write itself is never reached");
+        assert!(write_optional("test other", &dir.join("c.txt"), |path| fs::write(path, "c")));
+        assert_eq!(fs::read_to_string(dir.join("c.txt")).unwrap(), "c");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,738 @@
+use super::conversations;
+use super::exchange;
+use super::history;
+use super::messages::{self, FetchErr, Message, MessageKind, SystemEvent, Topic};
+use super::outbox;
+use super::post::PostErr;
+use super::preview::{LinkPreview, PreviewConfig, Previewer};
+use super::profile;
+use super::purge::{self, PurgeErr, Purged};
+use super::responder::{self, Reply, Responder, ResponderConfig};
+use super::rooms;
+use super::schedule;
+use super::sent::{self, SentMessages};
+use super::settings::Settings;
+use super::transport::Transport;
+use super::unread;
+use super::users;
+use chrono::{DateTime, FixedOffset};
+use crossbeam_channel::{select, Receiver, Sender};
+use rand::{thread_rng, Rng};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// le-chat's own default, when the profile doesn't tell.
+pub const DEFAULT_REFRESH: Duration = Duration::from_secs(20);
+/// Messages remembered as delivered, far more than a view shows.
+const SEEN_CAPACITY: usize = 2000;
+
+/// What happened in the chat since the last look.
+#[derive(Debug)]
+pub enum ChatEvent {
+    NewMessage(Message),
+    /// Right after the `NewMessage` of a message that `mentions_me`, for
+    /// those who only want these.
+    Mention(Message),
+    /// Right after the `NewMessage` of a system message, to keep a list of
+    /// who is in the chat without fetching it.
+    System(SystemEvent),
+    /// Messages delivered before and gone from the view since. Only told
+    /// when the view sends the whole page, not just what is new.
+    MessagesDeleted(Vec<Message>),
+    /// The stream ends after it.
+    SessionExpired,
+    /// The stream ends after it.
+    Kicked { reason: Option<String> },
+    /// The fetch is tried again at the next refresh.
+    FetchError(FetchErr),
+    /// A link's title, or its type and size when not a page, some time
+    /// after its message. `message_id` when the view shows ids.
+    LinkPreview { message_id: Option<u64>, url: String, title: String },
+    /// The topic above the messages is another, `None` when taken away.
+    /// Told for the first page too, when the chat has one.
+    TopicChanged(Option<Topic>),
+    /// A message of the outbox refused for good when sent again, it is out
+    /// of the outbox, or a scheduled one that failed. See `outbox::retry`
+    /// and `schedule::post_due`.
+    NotDelivered { to: String, text: String, error: PostErr },
+    /// Our messages deleted by the purge the client was built with, see
+    /// `purge::PurgeConfig`.
+    Purged(Purged),
+    /// The purge is tried again when it is next due.
+    PurgeFailed(PurgeErr),
+}
+
+#[derive(Debug, Clone)]
+pub struct StreamConfig {
+    /// Time between fetches, `None` takes the profile's refresh rate.
+    pub refresh: Option<Duration>,
+    /// How much each wait may differ from `refresh`, 0.2 for ±20%.
+    pub jitter: f64,
+    /// Fetch the links of new messages for `ChatEvent::LinkPreview`. Off
+    /// by default, it means requests to whatever site is posted.
+    pub previews: Option<PreviewConfig>,
+    /// Reply to new messages by rules, posting as the session. A config
+    /// with a bad rule leaves it off, see `Responder::new`.
+    pub responder: Option<ResponderConfig>,
+    /// Watch this room, `None` follows the room the session is in, see
+    /// `rooms::switch_room`.
+    pub room: Option<String>,
+}
+
+impl Default for StreamConfig {
+    fn default() -> Self {
+        Self { refresh: None, jitter: 0.2, previews: None, responder: None, room: None }
+    }
+}
+
+/// Ends a `MessageStream` from anywhere, even while it waits.
+impl From<LinkPreview> for ChatEvent {
+    fn from(preview: LinkPreview) -> Self {
+        ChatEvent::LinkPreview { message_id: preview.message_id, url: preview.url, title: preview.title }
+    }
+}
+
+/// A private message to us, see `MessageStream::private_messages`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrivateMessage {
+    pub id: Option<u64>,
+    pub time: Option<DateTime<FixedOffset>>,
+    pub from: String,
+    pub text: String,
+}
+
+impl PrivateMessage {
+    // To the session's nick, or any when it isn't known
+    fn to_me(message: &Message, me: Option<&str>) -> Option<Self> {
+        if message.kind != MessageKind::Private {
+            return None;
+        }
+        let to_me = match (me, &message.to) {
+            (Some(nick), Some(to)) => nick.to_lowercase() == to.to_lowercase(),
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        let from = message.from.clone().filter(|_| to_me)?;
+        Some(Self { id: message.id, time: message.time, from, text: message.text.clone() })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CancelHandle(Sender<()>);
+
+impl CancelHandle {
+    pub fn cancel(&self) {
+        let _ = self.0.try_send(());
+    }
+}
+
+// Ids when the view shows them, the message itself otherwise
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Key {
+    Id(u64),
+    Hash(u64),
+}
+
+// The message whatever its id: when, who and what, blanks aside
+fn fingerprint(message: &Message) -> u64 {
+    let text = message.text.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mut hasher = DefaultHasher::new();
+    (message.timestamp.trim(), &message.from, &message.to, text).hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The messages a chat delivered, kept across streams so the backlog sent
+/// again after a new login isn't new. Kept by chat, see `Settings::seen`.
+#[derive(Debug, Default)]
+pub(crate) struct Seen {
+    ids: HashSet<u64>,
+    fingerprints: HashSet<u64>,
+    /// The fingerprints of messages without ids.
+    anonymous: HashSet<u64>,
+    order: VecDeque<(Option<u64>, u64)>,
+    last_id: u64,
+}
+
+impl Seen {
+    // A message with an id is only the same as one without, so the same
+    // text twice in a second is told apart when ids are shown
+    fn remember(&mut self, message: &Message) -> bool {
+        let fingerprint = fingerprint(message);
+        let seen = match message.id {
+            Some(id) => self.ids.contains(&id) || self.anonymous.contains(&fingerprint),
+            None => self.fingerprints.contains(&fingerprint),
+        };
+        if seen {
+            return false;
+        }
+        match message.id {
+            Some(id) => self.ids.insert(id),
+            None => self.anonymous.insert(fingerprint),
+        };
+        self.fingerprints.insert(fingerprint);
+        self.order.push_back((message.id, fingerprint));
+        if self.order.len() > SEEN_CAPACITY {
+            let (id, fingerprint) = self.order.pop_front().unwrap();
+            match id {
+                Some(id) => self.ids.remove(&id),
+                None => self.anonymous.remove(&fingerprint),
+            };
+            self.fingerprints.remove(&fingerprint);
+        }
+        true
+    }
+}
+
+fn chat(base_url: &str, page_php: &str, room: Option<&str>) -> String {
+    let chat = format!("{}/{}", base_url.trim_end_matches('/'), page_php).to_lowercase();
+    match room {
+        Some(room) => format!("{}#{}", chat, room),
+        None => chat,
+    }
+}
+
+// Where the last stream of the room stopped
+fn last_id(settings: &Settings, base_url: &str, page_php: &str, room: Option<&str>) -> u64 {
+    settings.seen.lock().unwrap().get(&chat(base_url, page_php, room)).map_or(0, |seen| seen.last_id)
+}
+
+fn key(message: &Message) -> Key {
+    match message.id {
+        Some(id) => Key::Id(id),
+        None => {
+            let mut hasher = DefaultHasher::new();
+            (&message.timestamp, &message.from, &message.to, &message.text).hash(&mut hasher);
+            Key::Hash(hasher.finish())
+        }
+    }
+}
+
+/// The chat as events, each message delivered once. Fetches with
+/// `messages::fetch_messages_since` every refresh, blocking in `next`
+/// meanwhile, until the session ends or it is cancelled. A new stream of
+/// the same chat, after a new login, goes on where the last one stopped.
+pub struct MessageStream {
+    transport: Transport,
+    base_url: String,
+    page_php: String,
+    session: String,
+    config: StreamConfig,
+    /// Read from the profile before the first fetch, unless configured.
+    refresh: Option<Duration>,
+    /// The room watched, the session's until it moves when not configured.
+    room: Option<String>,
+    /// Where the last stream of the room stopped, at first.
+    last_id: u64,
+    /// The last page, newest first, to tell what was deleted.
+    page: Vec<(Key, Message)>,
+    topic: Option<Topic>,
+    pending: VecDeque<ChatEvent>,
+    polled: bool,
+    /// When the wait for the next fetch ends, once started.
+    next_poll: Option<Instant>,
+    done: bool,
+    cancel: (Sender<()>, Receiver<()>),
+    previewer: Option<Previewer>,
+    private: Vec<Sender<PrivateMessage>>,
+    /// With where its replies go to be posted.
+    responder: Option<(Responder, Sender<Reply>)>,
+}
+
+impl MessageStream {
+    pub fn new(transport: &Transport, base_url: &str, page_php: &str, session: &str, config: StreamConfig) -> Self {
+        let room = config.room.clone().or_else(|| rooms::room(transport.settings(), session));
+        Self {
+            transport: transport.clone(),
+            base_url: base_url.to_owned(),
+            page_php: page_php.to_owned(),
+            session: session.to_owned(),
+            refresh: config.refresh,
+            previewer: config.previews.clone().map(|previews| Previewer::new(transport, previews)),
+            responder: config.responder.clone().and_then(|responder| {
+                let responder = Responder::new(responder).map_err(|e| log::warn!("{}, no auto-replies", e)).ok()?;
+                Some((responder, responder::spawn_poster(transport, base_url, page_php, session)))
+            }),
+            config,
+            last_id: last_id(transport.settings(), base_url, page_php, room.as_deref()),
+            room,
+            page: vec![],
+            topic: None,
+            pending: VecDeque::new(),
+            polled: false,
+            next_poll: None,
+            done: false,
+            cancel: crossbeam_channel::bounded(1),
+            private: vec![],
+        }
+    }
+
+    /// Our posts in the session, with the ids the stream's fetches found.
+    pub fn sent(&self) -> SentMessages {
+        sent::sent_messages(self.transport.settings(), &self.session)
+    }
+
+    /// The topic as the last fetch found it.
+    pub fn topic(&self) -> Option<&Topic> {
+        self.topic.as_ref()
+    }
+
+    pub fn cancel_handle(&self) -> CancelHandle {
+        CancelHandle(self.cancel.0.clone())
+    }
+
+    /// The private messages to us from now on, each once, the ones waiting
+    /// in the first page after login too. Sent as they are fetched, the
+    /// stream never waits for the receiver.
+    pub fn private_messages(&mut self) -> Receiver<PrivateMessage> {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        self.private.push(tx);
+        rx
+    }
+
+    /// `callback` for each of `private_messages`, on a thread of its own so
+    /// a slow one doesn't hold up the fetches.
+    pub fn on_private_message(&mut self, mut callback: impl FnMut(PrivateMessage) + Send + 'static) {
+        let messages = self.private_messages();
+        thread::spawn(move || {
+            for message in messages {
+                callback(message);
+            }
+        });
+    }
+
+    /// The events sent from a thread of their own, until the stream ends
+    /// or the receiver is dropped.
+    pub fn spawn(self) -> Receiver<ChatEvent> {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            for event in self {
+                if tx.send(event).is_err() {
+                    return;
+                }
+            }
+        });
+        rx
+    }
+
+    fn refresh(&mut self) -> Duration {
+        let transport = &self.transport;
+        let (base_url, page_php, session) = (&self.base_url, &self.page_php, &self.session);
+        *self.refresh.get_or_insert_with(|| {
+            let profile = profile::get_profile(transport, base_url, page_php, session);
+            let refresh = profile.ok().and_then(|p| p.refresh).filter(|&r| r > 0);
+            refresh.map_or(DEFAULT_REFRESH, |r| Duration::from_secs(r.into()))
+        })
+    }
+
+    fn delay(&mut self) -> Duration {
+        let refresh = self.refresh();
+        let jitter = self.config.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return refresh;
+        }
+        refresh.mul_f64(1.0 + thread_rng().gen_range(-jitter..=jitter))
+    }
+
+    // Gone from the page while newer than its oldest message, not just
+    // scrolled out of it
+    fn deleted(&self, page: &[(Key, Message)]) -> Vec<Message> {
+        let keys: HashSet<_> = page.iter().map(|(k, _)| *k).collect();
+        let Some(oldest_kept) = self.page.iter().rposition(|(k, _)| keys.contains(k)) else {
+            return vec![];
+        };
+        self.page[..oldest_kept].iter().filter(|(k, _)| !keys.contains(k)).map(|(_, m)| m.clone()).collect()
+    }
+
+    fn poll(&mut self) {
+        let room = self.config.room.clone().or_else(|| rooms::room(self.transport.settings(), &self.session));
+        if room != self.room {
+            // Another room's ids, and none of its messages was deleted
+            self.last_id = last_id(self.transport.settings(), &self.base_url, &self.page_php, room.as_deref());
+            self.page.clear();
+            self.room = room;
+        }
+        let room = self.room.clone();
+        let room = room.as_deref();
+        let fetch = messages::fetch_since_with(&self.transport, &self.base_url, &self.page_php, &self.session, room, self.last_id);
+        let fetched = exchange::block_on(fetch);
+        let (fetched, last_id) = match fetched {
+            Ok(fetched) => fetched,
+            Err(FetchErr::SessionExpired) => {
+                purge::pause(self.transport.settings(), &self.session);
+                self.pending.push_back(ChatEvent::SessionExpired);
+                self.done = true;
+                return;
+            }
+            Err(FetchErr::Kicked { reason }) => {
+                self.pending.push_back(ChatEvent::Kicked { reason });
+                self.done = true;
+                return;
+            }
+            Err(e) => {
+                self.pending.push_back(ChatEvent::FetchError(e));
+                return;
+            }
+        };
+        self.last_id = last_id;
+        let topic = messages::topic_in(self.transport.settings(), &self.session, room);
+        if topic != self.topic {
+            self.topic = topic.clone();
+            self.pending.push_back(ChatEvent::TopicChanged(topic));
+        }
+        let page: Vec<_> = fetched.into_iter().map(|m| (key(&m), m)).collect();
+        let deleted = self.deleted(&page);
+        if !deleted.is_empty() {
+            unread::forget(self.transport.settings(), &deleted);
+            self.pending.push_back(ChatEvent::MessagesDeleted(deleted));
+        }
+        // Oldest first. Moved on even when nothing is new
+        let new: Vec<_> = {
+            let mut seen = self.transport.settings().seen.lock().unwrap();
+            let seen = seen.entry(chat(&self.base_url, &self.page_php, room)).or_default();
+            seen.last_id = last_id;
+            page.iter().rev().map(|(_, m)| m).filter(|m| seen.remember(m)).cloned().collect()
+        };
+        let settings = self.transport.settings();
+        let me = settings.mention.nick();
+        for message in &new {
+            self.pending.push_back(ChatEvent::NewMessage(message.clone()));
+            if message.mentions_me {
+                self.pending.push_back(ChatEvent::Mention(message.clone()));
+            }
+            if let MessageKind::System(event) = &message.kind {
+                self.pending.push_back(ChatEvent::System(event.clone()));
+            }
+            if let Some(previewer) = &mut self.previewer {
+                let ready = previewer.request(message);
+                self.pending.extend(ready.into_iter().map(ChatEvent::from));
+            }
+            if let Some(private) = PrivateMessage::to_me(message, me.as_deref()) {
+                self.private.retain(|tx| tx.send(private.clone()).is_ok());
+            }
+        }
+        if let Some((responder, replies)) = &mut self.responder {
+            if responder.needs_roles() && !new.is_empty() {
+                match users::fetch_online_users(&self.transport, &self.base_url, &self.page_php, &self.session) {
+                    Ok(users) => responder.set_users(&users),
+                    Err(e) => log::warn!("no user list for the auto-responder: {}", e),
+                }
+            }
+            for message in &new {
+                for reply in responder.respond(message, me.as_deref(), Instant::now()) {
+                    let _ = replies.send(reply);
+                }
+            }
+        }
+        history::record(settings, &self.base_url, &new);
+        unread::record(settings, &new);
+        conversations::record(settings, &self.base_url, &new);
+        // The chat answers again, what failed to send before goes out now
+        for (message, error) in outbox::retry(&self.transport, &self.base_url, &self.page_php, &self.session) {
+            self.pending.push_back(ChatEvent::NotDelivered { to: message.send_to, text: message.text, error });
+        }
+        for (message, error) in schedule::post_due(&self.transport, &self.base_url, &self.page_php, &self.session) {
+            self.pending.push_back(ChatEvent::NotDelivered { to: message.send_to, text: message.text, error });
+        }
+        match purge::purge_due(&self.transport, &self.base_url, &self.page_php, &self.session) {
+            Some(Ok(purged)) if !purged.messages.is_empty() => self.pending.push_back(ChatEvent::Purged(purged)),
+            Some(Err(e)) => self.pending.push_back(ChatEvent::PurgeFailed(e)),
+            _ => {}
+        }
+        self.page = page;
+    }
+}
+
+impl Iterator for MessageStream {
+    type Item = ChatEvent;
+
+    fn next(&mut self) -> Option<ChatEvent> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(event);
+            }
+            if self.done {
+                return None;
+            }
+            let next_poll = match self.next_poll {
+                Some(next_poll) => next_poll,
+                None => {
+                    let wait = if self.polled { self.delay() } else { Duration::ZERO };
+                    *self.next_poll.insert(Instant::now() + wait)
+                }
+            };
+            let previews = self.previewer.as_ref().map_or_else(crossbeam_channel::never, |p| p.results().clone());
+            select! {
+                recv(self.cancel.1) -> _ => {
+                    self.done = true;
+                    return None;
+                }
+                // Told while waiting for the next fetch
+                recv(previews) -> result => {
+                    if let (Ok((url, title)), Some(previewer)) = (result, &mut self.previewer) {
+                        let done = previewer.finish(url, title);
+                        self.pending.extend(done.into_iter().map(ChatEvent::from));
+                    }
+                    continue;
+                }
+                default(next_poll.saturating_duration_since(Instant::now())) => {}
+            }
+            self.next_poll = None;
+            self.polled = true;
+            self.poll();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lechatphp::mock::{MockResponse, MockServer};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn view(messages: &[(Option<u64>, &str)]) -> String {
+        let divs: String = messages
+            .iter()
+            .map(|(id, text)| {
+                let mid = id.map(|id| format!(r#"<label><input type="checkbox" name="mid[]" value="{}"></label>"#, id)).unwrap_or_default();
+                format!(r#"<div class="msg">{}<small>10-17 19:40:02 - </small><span class="usermsg"><span style="color:#FF0000;">alice</span> - {}</span></div>"#, mid, text)
+            })
+            .collect();
+        format!(r#"<html><body><div id="messages">{}</div></body></html>"#, divs)
+    }
+
+    // A server answering each fetch with the next page of the script
+    fn scripted(pages: Vec<MockResponse>) -> MockServer {
+        let calls = AtomicUsize::new(0);
+        MockServer::start(move |_| {
+            let call = calls.fetch_add(1, Ordering::SeqCst);
+            pages.get(call).cloned().unwrap_or_else(|| pages.last().cloned().unwrap())
+        })
+    }
+
+    fn summary(event: &ChatEvent) -> String {
+        match event {
+            ChatEvent::NewMessage(m) => format!("new {}", m.text),
+            ChatEvent::Mention(m) => format!("mention {}", m.text),
+            ChatEvent::System(e) => format!("system {:?}", e),
+            ChatEvent::MessagesDeleted(gone) => format!("deleted {}", gone.iter().map(|m| m.text.as_str()).collect::<Vec<_>>().join(",")),
+            ChatEvent::SessionExpired => "expired".to_owned(),
+            ChatEvent::Kicked { reason } => format!("kicked {:?}", reason),
+            ChatEvent::FetchError(e) => format!("error {}", e),
+            ChatEvent::LinkPreview { message_id, url, title } => format!("preview {:?} {} {}", message_id, url, title),
+            ChatEvent::NotDelivered { to, text, error } => format!("not delivered {} {} {}", to, text, error),
+            ChatEvent::TopicChanged(topic) => format!("topic {:?}", topic.as_ref().map(|t| t.text.as_str())),
+            ChatEvent::Purged(purged) => format!("purged {} {}", purged.messages.len(), purged.all),
+            ChatEvent::PurgeFailed(e) => format!("purge failed {}", e),
+        }
+    }
+
+    #[test]
+    fn message_stream_test() {
+        let config = StreamConfig { refresh: Some(Duration::from_millis(10)), jitter: 0.0, previews: None, responder: None, room: None };
+
+        // A guest's view: whole pages without ids, overlapping
+        let expired = r#"<body class="error"><h2>Error: Invalid/expired session</h2></body>"#;
+        let server = scripted(vec![
+            MockResponse::ok(&view(&[(None, "two"), (None, "one")])),
+            MockResponse::ok(&view(&[(None, "three"), (None, "two"), (None, "one")])),
+            MockResponse::new(500, "oops"),
+            MockResponse::ok(&view(&[(None, "four"), (None, "three"), (None, "one")])),
+            // `one` scrolled out, not deleted
+            MockResponse::ok(&view(&[(None, "five"), (None, "four"), (None, "three")])),
+            MockResponse::ok(expired),
+        ]);
+        let stream = MessageStream::new(&Transport::direct(), &server.url, "chat.php", "abc", config.clone());
+        let events: Vec<_> = stream.map(|e| summary(&e)).collect();
+        let expected = [
+            "new one",
+            "new two",
+            "new three",
+            "error 500 Internal Server Error, server down",
+            "deleted two",
+            "new four",
+            "new five",
+            "expired",
+        ];
+        assert_eq!(events, expected);
+
+        // A moderator's view with ids, from a fork sending whole pages
+        // anyway, read through the channel
+        let transport = Transport::direct();
+        transport.settings().mention.set_nick("zed");
+        let server = scripted(vec![
+            MockResponse::ok(&view(&[(Some(5), "five"), (Some(4), "four")])),
+            MockResponse::ok(&view(&[(Some(7), "seven"), (Some(6), "zed: six"), (Some(5), "five"), (Some(4), "four")])),
+            MockResponse::ok(&view(&[(Some(7), "seven"), (Some(6), "zed: six")])),
+            MockResponse::ok(r#"<html><body><h2>You have been kicked! Reason: spam</h2></body></html>"#),
+        ]);
+        let stream = MessageStream::new(&transport, &server.url, "chat.php", "abc", config.clone());
+        let events: Vec<_> = stream.spawn().iter().map(|e| summary(&e)).collect();
+        let expected = ["new four", "new five", "new zed: six", "mention zed: six", "new seven", r#"kicked Some("spam")"#];
+        assert_eq!(events, expected);
+
+        // System messages
+        let joined = r#"<html><body><div id="messages"><div class="msg"><small>10-17 19:40:02 - </small><span class="sysmsg">bob entered the chat.</span></div></div></body></html>"#;
+        let server = scripted(vec![MockResponse::ok(joined), MockResponse::ok(expired)]);
+        let stream = MessageStream::new(&Transport::direct(), &server.url, "chat.php", "abc", config.clone());
+        let events: Vec<_> = stream.map(|e| summary(&e)).collect();
+        assert_eq!(events, ["new bob entered the chat.", r#"system Joined("bob")"#, "expired"]);
+
+        // Cancelled while waiting for the next refresh
+        let server = scripted(vec![MockResponse::ok(&view(&[(None, "one")]))]);
+        let config = StreamConfig { refresh: Some(Duration::from_secs(60)), ..config };
+        let mut stream = MessageStream::new(&Transport::direct(), &server.url, "chat.php", "abc", config);
+        let handle = stream.cancel_handle();
+        assert_eq!(stream.next().map(|e| summary(&e)).as_deref(), Some("new one"));
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            handle.cancel();
+        });
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn relogin_test() {
+        let config = StreamConfig { refresh: Some(Duration::from_millis(10)), jitter: 0.0, previews: None, responder: None, room: None };
+        let page = |messages: &[(Option<u64>, &str, &str)]| {
+            let divs: String = messages
+                .iter()
+                .map(|(id, time, text)| {
+                    let mid = id.map(|id| format!(r#"<label><input type="checkbox" name="mid[]" value="{}"></label>"#, id)).unwrap_or_default();
+                    format!(r#"<div class="msg">{}<small>10-17 {} - </small><span class="usermsg"><span style="color:#FF0000;">alice</span> - {}</span></div>"#, mid, time, text)
+                })
+                .collect();
+            MockResponse::ok(&format!(r#"<html><body><div id="messages">{}</div></body></html>"#, divs))
+        };
+        let kicked = MockResponse::ok(r#"<html><body><h2>You have been kicked! Reason: spam</h2></body></html>"#);
+        let expired = MockResponse::ok(r#"<body class="error"><h2>Error: Invalid/expired session</h2></body>"#);
+        // The same client for both logins
+        let transport = Transport::direct();
+        let events = |server: &MockServer, session: &str| -> Vec<String> {
+            MessageStream::new(&transport, &server.url, "chat.php", session, config.clone()).map(|e| summary(&e)).collect()
+        };
+
+        // A guest's view, the backlog again after the new login
+        let server = scripted(vec![
+            page(&[(None, "19:40:02", "hi"), (None, "19:40:01", "hello there")]),
+            kicked.clone(),
+            page(&[(None, "19:40:09", "hi"), (None, "19:40:05", "new"), (None, "19:40:02", "hi"), (None, "19:40:01", "hello  there")]),
+            expired.clone(),
+        ]);
+        assert_eq!(events(&server, "relogin-guest-1"), ["new hello there", "new hi", r#"kicked Some("spam")"#]);
+        // The same text again later is a message of its own
+        assert_eq!(events(&server, "relogin-guest-2"), ["new new", "new hi", "expired"]);
+
+        // With ids, from a fork sending the whole page
+        let server = scripted(vec![
+            page(&[(Some(5), "19:40:02", "five"), (Some(4), "19:40:01", "four")]),
+            kicked,
+            page(&[(Some(6), "19:40:02", "five"), (Some(5), "19:40:02", "five"), (Some(4), "19:40:01", "four")]),
+            expired,
+        ]);
+        assert_eq!(events(&server, "relogin-member-1"), ["new four", "new five", r#"kicked Some("spam")"#]);
+        // Twice in the same second, told apart by the id
+        assert_eq!(events(&server, "relogin-member-2"), ["new five", "expired"]);
+    }
+
+    #[test]
+    fn topic_changed_test() {
+        let config = StreamConfig { refresh: Some(Duration::from_millis(10)), jitter: 0.0, previews: None, responder: None, room: None };
+        let with_topic = |topic: &str| view(&[(None, "one")]).replace("<body>", &format!(r#"<body><div id="topic">Topic: {}</div>"#, topic));
+        let server = scripted(vec![
+            MockResponse::ok(&with_topic("welcome")),
+            MockResponse::ok(&with_topic("welcome")),
+            MockResponse::ok(&with_topic("movie night")),
+            MockResponse::ok(&view(&[(None, "one")])),
+            MockResponse::ok(r#"<body class="error"><h2>Error: Invalid/expired session</h2></body>"#),
+        ]);
+        let mut stream = MessageStream::new(&Transport::direct(), &server.url, "chat.php", "topic-stream", config);
+        let mut events = vec![];
+        for event in stream.by_ref() {
+            events.push(summary(&event));
+        }
+        let expected = [r#"topic Some("welcome")"#, "new one", r#"topic Some("movie night")"#, "topic None", "expired"];
+        assert_eq!(events, expected);
+        assert_eq!(stream.topic(), None);
+    }
+
+    #[test]
+    fn private_message_test() {
+        let pm = |from: &str, to: &str, text: &str| {
+            format!(r#"<div class="msg"><small>10-17 19:40:02 - </small><span class="usermsg">[<span style="color:#FFA500;">{}</span> to <span style="color:#00FF00;">{}</span>] - <span style="color:#FFA500;">{}</span></span></div>"#, from, to, text)
+        };
+        let page = |divs: &[String]| format!(r#"<html><body><div id="messages">{}</div></body></html>"#, divs.concat());
+        let away = pm("alice", "zed", "are you there?");
+        let ours = pm("zed", "alice", "yes");
+        let again = pm("alice", "Zed", "still there?");
+        let server = scripted(vec![
+            // Waiting since before the login
+            MockResponse::ok(&page(&[ours.clone(), away.clone()])),
+            MockResponse::ok(&page(&[ours.clone(), away.clone()])),
+            MockResponse::ok(&page(&[again.clone(), pm("bob", "carol", "not for us"), ours, away])),
+            MockResponse::ok(r#"<body class="error"><h2>Error: Invalid/expired session</h2></body>"#),
+        ]);
+        let transport = Transport::direct();
+        transport.settings().mention.set_nick("zed");
+        let config = StreamConfig { refresh: Some(Duration::from_millis(10)), jitter: 0.0, previews: None, responder: None, room: None };
+        let mut stream = MessageStream::new(&transport, &server.url, "chat.php", "abc", config);
+        let (tx, called) = crossbeam_channel::unbounded();
+        stream.on_private_message(move |pm| {
+            // Slow, the stream doesn't wait for it
+            thread::sleep(Duration::from_millis(20));
+            tx.send(format!("{}: {}", pm.from, pm.text)).unwrap();
+        });
+        let received = stream.private_messages();
+        assert!(matches!(stream.last(), Some(ChatEvent::SessionExpired)));
+        assert_eq!(called.iter().collect::<Vec<_>>(), ["alice: are you there?", "alice: still there?"]);
+        assert_eq!(received.iter().map(|pm| pm.text).collect::<Vec<_>>(), ["are you there?", "still there?"]);
+    }
+
+    #[test]
+    fn link_preview_test() {
+        let page_hits = Arc::new(AtomicUsize::new(0));
+        let views = AtomicUsize::new(0);
+        let hits = Arc::clone(&page_hits);
+        let server = MockServer::start(move |req| {
+            if req.path == "/page" {
+                hits.fetch_add(1, Ordering::SeqCst);
+                return MockResponse::ok("<html><head><title>Hidden Wiki</title></head></html>").with_header("Content-Type", "text/html");
+            }
+            if req.path == "/file.png" {
+                return MockResponse::bytes(200, vec![0; 2048]).with_header("Content-Type", "image/png");
+            }
+            let url = req.header("host").map(|host| format!("http://{}", host)).unwrap();
+            let link = |path: &str| format!(r#"<a href="{0}{1}" target="_blank">{0}{1}</a>"#, url, path);
+            let first = format!("see {} and {} and {}", link("/page"), link("/file.png"), r#"<a href="http://denied.onion/">denied</a>"#);
+            let second = format!("again {}", link("/page"));
+            match views.fetch_add(1, Ordering::SeqCst) {
+                0 => MockResponse::ok(&view(&[(Some(1), &first)])),
+                _ => MockResponse::ok(&view(&[(Some(2), &second), (Some(1), &first)])),
+            }
+        });
+        let previews = PreviewConfig { deny: vec!["denied.onion".to_owned()], ..PreviewConfig::default() };
+        let config = StreamConfig { refresh: Some(Duration::from_millis(10)), jitter: 0.0, previews: Some(previews), responder: None, room: None };
+        let stream = MessageStream::new(&Transport::direct(), &server.url, "chat.php", "abc", config);
+        let handle = stream.cancel_handle();
+        let events = stream.spawn();
+        let mut previews = vec![];
+        while previews.len() < 3 {
+            if let ChatEvent::LinkPreview { message_id, url, title } = events.recv_timeout(Duration::from_secs(10)).unwrap() {
+                previews.push((message_id, url.rsplit('/').next().unwrap().to_owned(), title));
+            }
+        }
+        handle.cancel();
+        previews.sort();
+        let expected = [
+            (Some(1), "file.png".to_owned(), "image/png, 2 KB".to_owned()),
+            (Some(1), "page".to_owned(), "Hidden Wiki".to_owned()),
+            (Some(2), "page".to_owned(), "Hidden Wiki".to_owned()),
+        ];
+        assert_eq!(previews, expected);
+        // Once per url
+        assert_eq!(page_hits.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,180 @@
+use chrono::{DateTime, Datelike, Duration, FixedOffset, NaiveDateTime, NaiveTime, Utc};
+
+/// How far ahead of our clock the server's may be. A bare time later than
+/// now by more than this is taken for yesterday's.
+const CLOCK_SKEW_MINUTES: i64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Has {
+    Date,
+    /// Month and day, no year.
+    MonthDay,
+    Time,
+}
+
+/// The formats le-chat prints depending on its settings, the most
+/// precise first.
+const FORMATS: &[(&str, Has)] = &[
+    ("%Y-%m-%d %H:%M:%S", Has::Date),
+    ("%Y-%m-%d %H:%M", Has::Date),
+    ("%d.%m.%Y %H:%M:%S", Has::Date),
+    ("%d.%m.%Y %H:%M", Has::Date),
+    ("%m-%d %H:%M:%S", Has::MonthDay),
+    ("%m-%d %H:%M", Has::MonthDay),
+    ("%d.%m. %H:%M:%S", Has::MonthDay),
+    ("%d.%m. %H:%M", Has::MonthDay),
+    ("%H:%M:%S", Has::Time),
+    ("%H:%M", Has::Time),
+];
+
+/// `+02:00`, `-0530`, `+2` or `UTC`.
+pub fn parse_offset(s: &str) -> Result<FixedOffset, String> {
+    let s = s.trim();
+    if s.eq_ignore_ascii_case("utc") || s == "Z" {
+        return Ok(FixedOffset::east_opt(0).unwrap());
+    }
+    let invalid = || format!("invalid UTC offset {:?}, expected like +02:00", s);
+    let (sign, rest) = match s.as_bytes().first() {
+        Some(b'+') => (1, &s[1..]),
+        Some(b'-') => (-1, &s[1..]),
+        _ => return Err(invalid()),
+    };
+    let (hours, minutes) = match rest.split_once(':') {
+        Some((h, m)) => (h, m),
+        None if rest.len() == 4 => rest.split_at(2),
+        None => (rest, "0"),
+    };
+    let hours: i32 = hours.parse().map_err(|_| invalid())?;
+    let minutes: i32 = minutes.parse().map_err(|_| invalid())?;
+    if minutes >= 60 {
+        return Err(invalid());
+    }
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60)).ok_or_else(invalid)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stamp {
+    Full(NaiveDateTime),
+    /// In the year 2000, which has every day.
+    NoYear(NaiveDateTime),
+    Time(NaiveTime),
+}
+
+fn parse_stamp(raw: &str) -> Option<Stamp> {
+    let raw = raw.trim();
+    FORMATS.iter().find_map(|&(format, has)| match has {
+        Has::Date => NaiveDateTime::parse_from_str(raw, format).ok().map(Stamp::Full),
+        Has::MonthDay => {
+            let dated = NaiveDateTime::parse_from_str(&format!("2000 {}", raw), &format!("%Y {}", format));
+            dated.ok().map(Stamp::NoYear)
+        }
+        Has::Time => NaiveTime::parse_from_str(raw, format).ok().map(Stamp::Time),
+    })
+}
+
+// The latest time `stamp` can be, not after `newer`
+fn resolve(stamp: Stamp, newer: NaiveDateTime) -> Option<NaiveDateTime> {
+    match stamp {
+        Stamp::Full(time) => Some(time),
+        Stamp::NoYear(time) => match time.with_year(newer.year()) {
+            Some(this_year) if this_year <= newer => Some(this_year),
+            _ => time.with_year(newer.year() - 1),
+        },
+        Stamp::Time(time) if time <= newer.time() => Some(newer.date().and_time(time)),
+        Stamp::Time(time) => Some(newer.date().pred_opt()?.and_time(time)),
+    }
+}
+
+/// The times of `stamps`, newest first like the view, as of `now` in the
+/// server's offset. Missing dates are the latest that keep every message
+/// older than the one after it: a bare time later than the next message's
+/// is from the day before. `None` for what isn't a time.
+pub fn message_times(stamps: &[&str], now: DateTime<FixedOffset>) -> Vec<Option<DateTime<FixedOffset>>> {
+    let offset = *now.offset();
+    let mut newer = now.naive_local() + Duration::minutes(CLOCK_SKEW_MINUTES);
+    stamps
+        .iter()
+        .map(|raw| {
+            let time = resolve(parse_stamp(raw)?, newer)?;
+            newer = time;
+            time.and_local_timezone(offset).single()
+        })
+        .collect()
+}
+
+/// Now, in the server's `offset`, `Settings::server_offset`.
+pub fn server_now(offset: FixedOffset) -> DateTime<FixedOffset> {
+    Utc::now().with_timezone(&offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(offset: &str, time: &str) -> DateTime<FixedOffset> {
+        DateTime::parse_from_str(&format!("{} {}", time, offset), "%Y-%m-%d %H:%M:%S %z").unwrap()
+    }
+
+    #[test]
+    fn message_times_test() {
+        let now = at("+0200", "2026-10-17 20:00:00");
+        let cases = [
+            // le-chat's default, and what other date settings print
+            ("10-17 19:40:02", Some("2026-10-17 19:40:02")),
+            ("2026-10-16 08:12:45", Some("2026-10-16 08:12:45")),
+            ("16.10.2026 08:12", Some("2026-10-16 08:12:00")),
+            ("17.10. 19:41:10", Some("2026-10-17 19:41:10")),
+            ("10-17 19:41", Some("2026-10-17 19:41:00")),
+            ("19:40:02", Some("2026-10-17 19:40:02")),
+            ("19:40", Some("2026-10-17 19:40:00")),
+            // Later than now, yesterday's or last year's
+            ("23:59:59", Some("2026-10-16 23:59:59")),
+            ("12-31 23:59:59", Some("2025-12-31 23:59:59")),
+            // Within the clock skew, still today
+            ("20:05", Some("2026-10-17 20:05:00")),
+            ("", None),
+            ("yesterday", None),
+            ("25:61", None),
+        ];
+        for (raw, expected) in cases {
+            let time = message_times(&[raw], now)[0];
+            assert_eq!(time, expected.map(|e| at("+0200", e)), "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn midnight_rollover_test() {
+        let now = at("+0000", "2026-10-18 00:20:00");
+        // Newest first: past midnight, then the evening before
+        let stamps = ["00:15:00", "00:01:30", "", "23:58:10", "23:30", "22:00:00", "23:59:00"];
+        let expected = [
+            Some("2026-10-18 00:15:00"),
+            Some("2026-10-18 00:01:30"),
+            None,
+            Some("2026-10-17 23:58:10"),
+            Some("2026-10-17 23:30:00"),
+            Some("2026-10-17 22:00:00"),
+            // Later than the message after it, a day older
+            Some("2026-10-16 23:59:00"),
+        ];
+        let times = message_times(&stamps, now);
+        assert_eq!(times, expected.map(|e| e.map(|e| at("+0000", e))));
+
+        // The year rolls over the same way
+        let now = at("+0000", "2027-01-01 00:05:00");
+        let times = message_times(&["01-01 00:01:00", "12-31 23:59:00"], now);
+        assert_eq!(times, [Some(at("+0000", "2027-01-01 00:01:00")), Some(at("+0000", "2026-12-31 23:59:00"))]);
+    }
+
+    #[test]
+    fn parse_offset_test() {
+        let secs = |s| parse_offset(s).map(|o| o.local_minus_utc());
+        assert_eq!(secs("+02:00"), Ok(7200));
+        assert_eq!(secs("-0530"), Ok(-19800));
+        assert_eq!(secs("+2"), Ok(7200));
+        assert_eq!(secs("UTC"), Ok(0));
+        assert!(secs("02:00").is_err());
+        assert!(secs("+02:75").is_err());
+        assert!(secs("+25:00").is_err());
+    }
+}
@@ -0,0 +1,444 @@
+use super::error_kind::{Classify, ErrorKind};
+use super::retry::SendErr;
+use base64::engine::general_purpose;
+use base64::Engine;
+use reqwest::blocking::Response;
+use reqwest::tls::TlsInfo;
+use reqwest::Url;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{CertificateError, DigitallySignedStruct, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::error;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use thiserror::Error;
+
+const SPKI_PREFIX: &str = "sha256/";
+
+/// What a pinned server must present.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pin {
+    /// PEM certificate file. Its key is pinned, and the certificate is
+    /// trusted, so self-signed mirrors work.
+    Certificate(PathBuf),
+    /// Base64 SHA-256 of the SubjectPublicKeyInfo, as printed by
+    /// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256 -binary | base64`.
+    Spki(String),
+}
+
+/// TLS pin for the chat at `base_url`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsPin {
+    pub base_url: String,
+    pub pin: Pin,
+}
+
+/// Parses `<base_url>=sha256/<base64>` or `<base_url>=<pem file>`.
+impl FromStr for TlsPin {
+    type Err = TlsErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (base_url, pin) = s.split_once('=').ok_or_else(|| TlsErr::InvalidPin(s.to_owned()))?;
+        let pin = match pin.strip_prefix(SPKI_PREFIX) {
+            Some(hash) => Pin::Spki(hash.to_owned()),
+            None if !pin.is_empty() => Pin::Certificate(PathBuf::from(pin)),
+            None => return Err(TlsErr::InvalidPin(s.to_owned())),
+        };
+        Ok(Self { base_url: base_url.to_owned(), pin })
+    }
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum TlsErr {
+    #[error("invalid tls pin {0}, expected <https url>=sha256/<base64> or <https url>=<pem file>")]
+    InvalidPin(String),
+    #[error("invalid pinned certificate {}: {}", .0.display(), .1)]
+    InvalidCertificate(PathBuf, String),
+}
+
+impl Classify for TlsErr {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            TlsErr::InvalidPin(_) | TlsErr::InvalidCertificate(..) => ErrorKind::InvalidInput,
+        }
+    }
+}
+
+/// A pinned server presented another key, or none that could be checked.
+/// Never retried nor treated as the server being down.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PinMismatch {
+    pub host: String,
+    pub expected: String,
+    /// `None` when the handshake was refused or no certificate was seen.
+    pub got: Option<String>,
+}
+
+impl Display for PinMismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TLS PIN MISMATCH for {}: expected {}{}, ", self.host, SPKI_PREFIX, self.expected)?;
+        match &self.got {
+            Some(got) => write!(f, "server presented {}{}", SPKI_PREFIX, got)?,
+            None => write!(f, "server presented no matching certificate")?,
+        }
+        write!(f, ". The connection may be intercepted, refusing to send anything")
+    }
+}
+
+impl error::Error for PinMismatch {}
+
+/// A pin read from disk, ready to be installed.
+pub struct LoadedPin {
+    host: String,
+    spki: [u8; 32],
+}
+
+impl LoadedPin {
+    /// TLS settings for the pinned host's own client. The handshake fails
+    /// unless the server proves it holds the pinned key, so nothing is sent
+    /// to any other server; who signed the certificate doesn't matter.
+    pub fn tls_config(&self, http1_only: bool) -> rustls::ClientConfig {
+        let provider = Arc::new(ring::default_provider());
+        let verifier = PinVerifier { spki: self.spki, algorithms: provider.signature_verification_algorithms };
+        let mut config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .expect("ring supports the default TLS versions")
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        // reqwest leaves a config of its own as it is
+        config.alpn_protocols = if http1_only {
+            vec![b"http/1.1".to_vec()]
+        } else {
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        };
+        config
+    }
+}
+
+// Accepts the server's certificate when its key is the pinned one
+#[derive(Debug)]
+struct PinVerifier {
+    spki: [u8; 32],
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for PinVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match spki_hash(end_entity) {
+            Some(spki) if spki == self.spki => Ok(ServerCertVerified::assertion()),
+            _ => Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure)),
+        }
+    }
+
+    // The signatures prove the server has the key of the certificate
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// `host:port` of the url, what pins and per-mirror settings are keyed by.
+pub(super) fn host_key(url: &Url) -> Option<String> {
+    Some(format!("{}:{}", url.host_str()?.to_ascii_lowercase(), url.port_or_known_default()?))
+}
+
+// Tag, content and the whole element, then what follows
+type Element<'a> = (u8, &'a [u8], &'a [u8], &'a [u8]);
+
+fn der_next(data: &[u8]) -> Option<Element<'_>> {
+    let (&tag, rest) = data.split_first()?;
+    let (&first, mut rest) = rest.split_first()?;
+    let len = if first & 0x80 == 0 {
+        first as usize
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 || rest.len() < n {
+            return None;
+        }
+        let len = rest[..n].iter().fold(0usize, |len, &b| (len << 8) | b as usize);
+        rest = &rest[n..];
+        len
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let header_len = data.len() - rest.len();
+    Some((tag, &rest[..len], &data[..header_len + len], &rest[len..]))
+}
+
+/// The DER SubjectPublicKeyInfo of a DER certificate.
+fn spki_der(cert: &[u8]) -> Option<&[u8]> {
+    let (_, cert, _, _) = der_next(cert)?;
+    let (_, mut tbs, _, _) = der_next(cert)?;
+    // Optional [0] version
+    if tbs.first() == Some(&0xa0) {
+        tbs = der_next(tbs)?.3;
+    }
+    // serialNumber, signature, issuer, validity, subject
+    for _ in 0..5 {
+        tbs = der_next(tbs)?.3;
+    }
+    let (tag, _, spki, _) = der_next(tbs)?;
+    (tag == 0x30).then_some(spki)
+}
+
+/// SHA-256 of the certificate's SubjectPublicKeyInfo.
+pub fn spki_hash(cert_der: &[u8]) -> Option<[u8; 32]> {
+    spki_der(cert_der).map(|spki| Sha256::digest(spki).into())
+}
+
+fn encode(hash: &[u8; 32]) -> String {
+    general_purpose::STANDARD.encode(hash)
+}
+
+fn pem_to_der(pem: &str) -> Option<Vec<u8>> {
+    let body: String = pem
+        .lines()
+        .skip_while(|l| !l.starts_with("-----BEGIN CERTIFICATE-----"))
+        .skip(1)
+        .take_while(|l| !l.starts_with("-----END"))
+        .collect();
+    general_purpose::STANDARD.decode(body.trim()).ok()
+}
+
+pub fn load(pin: &TlsPin) -> Result<LoadedPin, TlsErr> {
+    let invalid = || TlsErr::InvalidPin(pin.base_url.clone());
+    let url = Url::parse(&pin.base_url).map_err(|_| invalid())?;
+    if url.scheme() != "https" {
+        return Err(invalid());
+    }
+    let host = host_key(&url).ok_or_else(invalid)?;
+    match &pin.pin {
+        Pin::Spki(hash) => {
+            let spki = general_purpose::STANDARD
+                .decode(hash)
+                .ok()
+                .and_then(|h| <[u8; 32]>::try_from(h).ok())
+                .ok_or_else(|| TlsErr::InvalidPin(format!("{}={}{}", pin.base_url, SPKI_PREFIX, hash)))?;
+            Ok(LoadedPin { host, spki })
+        }
+        Pin::Certificate(path) => {
+            let invalid_cert = |e: String| TlsErr::InvalidCertificate(path.clone(), e);
+            let pem = fs::read_to_string(path).map_err(|e| invalid_cert(e.to_string()))?;
+            let spki = pem_to_der(&pem)
+                .and_then(|der| spki_hash(&der))
+                .ok_or_else(|| invalid_cert("no certificate found".to_owned()))?;
+            Ok(LoadedPin { host, spki })
+        }
+    }
+}
+
+/// The pins a client enforces, `host:port` -> SHA-256 of the expected
+/// SubjectPublicKeyInfo.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Pins(HashMap<String, [u8; 32]>);
+
+impl Pins {
+    pub fn new(pins: &[LoadedPin]) -> Self {
+        Self(pins.iter().map(|pin| (pin.host.clone(), pin.spki)).collect())
+    }
+
+    /// The pin of the url's host, base64 encoded.
+    pub fn get(&self, url: &str) -> Option<String> {
+        Url::parse(url).ok().and_then(|url| self.expected(&url)).map(|(_, spki)| encode(&spki))
+    }
+
+    fn expected(&self, url: &Url) -> Option<(String, [u8; 32])> {
+        let host = host_key(url)?;
+        let spki = *self.0.get(&host)?;
+        Some((host, spki))
+    }
+
+    /// Check the certificate the response came with against the pin of its
+    /// host, if any. The pinned hosts' own clients checked it in the
+    /// handshake already, this catches a redirect sent through another.
+    fn check_response(&self, resp: &Response) -> Result<(), PinMismatch> {
+        let (host, spki) = match self.expected(resp.url()) {
+            Some(expected) => expected,
+            None => return Ok(()),
+        };
+        let got = resp.extensions().get::<TlsInfo>().and_then(|info| info.peer_certificate()).and_then(spki_hash);
+        if got == Some(spki) {
+            return Ok(());
+        }
+        Err(PinMismatch { host, expected: encode(&spki), got: got.as_ref().map(encode) })
+    }
+
+    /// A failed handshake with a pinned host means it didn't present the
+    /// pinned certificate.
+    fn check_err(&self, err: &reqwest::Error) -> Option<PinMismatch> {
+        let (host, spki) = self.expected(err.url()?)?;
+        let mut source = error::Error::source(err);
+        while let Some(e) = source {
+            if e.to_string().to_ascii_lowercase().contains("certificate") {
+                return Some(PinMismatch { host, expected: encode(&spki), got: None });
+            }
+            source = e.source();
+        }
+        None
+    }
+
+    /// Check the outcome of a request to a possibly pinned host.
+    pub fn check(&self, res: &Result<Response, SendErr>) -> Result<(), PinMismatch> {
+        match res {
+            Ok(resp) => self.check_response(resp),
+            Err(SendErr::Reqwest(e)) => self.check_err(e).map_or(Ok(()), Err),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lechatphp::client::{self, ClientConfig, ProxySetting};
+    use crate::lechatphp::retry::SendErr;
+    use native_tls::{Identity, TlsAcceptor};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+    use std::thread;
+
+    const PIN_CERT: &str = include_str!("fixtures/pin_cert.pem");
+    // Printed by openssl for fixtures/pin_cert.pem and fixtures/other_cert.pem
+    const PIN_SPKI: &str = "rwwghLCnkUe+W89yMA1LIAaPDknqPd9PUJm1pd/2hcQ=";
+    const OTHER_SPKI: &str = "y18/Md9jiffE6fx7bqj0jV9g+1nuNCoCpnY9JlJa2dw=";
+
+    fn fixture(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/lechatphp/fixtures").join(name)
+    }
+
+    // Self-signed https server for localhost, answering every request with
+    // 200. Also what it received once the handshake was done.
+    fn tls_server() -> (u16, Arc<Mutex<Vec<u8>>>) {
+        let identity = Identity::from_pkcs8(PIN_CERT.as_bytes(), include_bytes!("fixtures/pin_key.pem")).unwrap();
+        let acceptor = TlsAcceptor::new(identity).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let received = Arc::new(Mutex::new(vec![]));
+        let log = Arc::clone(&received);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                // The whole request, the body may come in a record of its own
+                let _ = stream.set_read_timeout(Some(std::time::Duration::from_millis(200)));
+                let mut stream = match acceptor.accept(stream) {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let mut buf = [0u8; 4096];
+                while let Ok(n @ 1..) = stream.read(&mut buf) {
+                    log.lock().unwrap().extend_from_slice(&buf[..n]);
+                }
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok");
+            }
+        });
+        (port, received)
+    }
+
+    #[test]
+    fn spki_hash_test() {
+        assert_eq!(encode(&spki_hash(&pem_to_der(PIN_CERT).unwrap()).unwrap()), PIN_SPKI);
+        let pin: TlsPin = format!("https://a.example=sha256/{}", OTHER_SPKI).parse().unwrap();
+        assert_eq!(pin.pin, Pin::Spki(OTHER_SPKI.to_owned()));
+        let pin: TlsPin = "https://a.example=/etc/a.pem".parse().unwrap();
+        assert_eq!(pin.pin, Pin::Certificate(PathBuf::from("/etc/a.pem")));
+        assert!("https://a.example".parse::<TlsPin>().is_err());
+        assert!(load(&TlsPin { base_url: "http://a.example".to_owned(), pin: Pin::Spki(OTHER_SPKI.to_owned()) }).is_err());
+    }
+
+    #[test]
+    fn pinning_test() {
+        let (port, _) = tls_server();
+        // The server answers both names, each test case pins one of them
+        let by_name = format!("https://localhost:{}", port);
+        let by_ip = format!("https://127.0.0.1:{}", port);
+        let send = |pins: Vec<TlsPin>, url: &str| {
+            let config = ClientConfig { proxy: ProxySetting::Direct, allow_clearnet: Some(true), tls_pins: pins, ..Default::default() };
+            let transport = client::build(&config).unwrap();
+            transport.send(transport.get(url))
+        };
+
+        // Matching certificate pin, self-signed is fine
+        let pin = TlsPin { base_url: by_name.clone(), pin: Pin::Certificate(fixture("pin_cert.pem")) };
+        let resp = send(vec![pin], &by_name).unwrap();
+        assert_eq!(resp.text().unwrap(), "ok");
+
+        // Matching key pin, by address
+        let pin = TlsPin { base_url: by_ip.clone(), pin: Pin::Spki(PIN_SPKI.to_owned()) };
+        assert_eq!(send(vec![pin], &by_ip).unwrap().text().unwrap(), "ok");
+
+        // Another key pinned, the handshake fails
+        let pin = TlsPin { base_url: by_ip.clone(), pin: Pin::Spki(OTHER_SPKI.to_owned()) };
+        let err = send(vec![pin], &by_ip).unwrap_err();
+        assert!(matches!(&err, SendErr::PinMismatch(m) if m.got.is_none()), "unexpected {:?}", err);
+        assert!(err.to_string().contains("TLS PIN MISMATCH"));
+
+        // Pinned certificate not presented
+        let pin = TlsPin { base_url: by_name.clone(), pin: Pin::Certificate(fixture("other_cert.pem")) };
+        let err = send(vec![pin], &by_name).unwrap_err();
+        assert!(matches!(&err, SendErr::PinMismatch(m) if m.got.is_none()), "unexpected {:?}", err);
+
+        // A pinned certificate is trusted for its own host only
+        let pin = TlsPin { base_url: by_name.clone(), pin: Pin::Certificate(fixture("pin_cert.pem")) };
+        let err = send(vec![pin], &by_ip).unwrap_err();
+        assert!(matches!(err, SendErr::Reqwest(_)), "unexpected {:?}", err);
+
+        // Without a pin, a self-signed certificate is an ordinary tls error
+        let err = send(vec![], &by_name).unwrap_err();
+        assert!(matches!(err, SendErr::Reqwest(_)), "unexpected {:?}", err);
+    }
+
+    #[test]
+    fn mismatch_sends_nothing_test() {
+        let login = |pins: Vec<TlsPin>, url: &str| {
+            let config = ClientConfig { proxy: ProxySetting::Direct, allow_clearnet: Some(true), tls_pins: pins, ..Default::default() };
+            let transport = client::build(&config).unwrap();
+            transport.send(transport.post(url).form(&[("nick", "alice"), ("pass", "hunter2")]))
+        };
+        for pin in [Pin::Spki(OTHER_SPKI.to_owned()), Pin::Certificate(fixture("other_cert.pem"))] {
+            let (port, received) = tls_server();
+            let url = format!("https://localhost:{}", port);
+            let err = login(vec![TlsPin { base_url: url.clone(), pin }], &url).unwrap_err();
+            assert!(matches!(err, SendErr::PinMismatch(_)), "unexpected {:?}", err);
+            assert!(received.lock().unwrap().is_empty());
+        }
+
+        // The server does see the password when the pin matches
+        let (port, received) = tls_server();
+        let url = format!("https://localhost:{}", port);
+        login(vec![TlsPin { base_url: url.clone(), pin: Pin::Spki(PIN_SPKI.to_owned()) }], &url).unwrap();
+        assert!(String::from_utf8_lossy(&received.lock().unwrap()).contains("pass=hunter2"));
+    }
+}
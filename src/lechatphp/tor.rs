@@ -0,0 +1,299 @@
+use super::error_kind::{Classify, ErrorKind};
+use super::onion_auth::ClientAuthKey;
+use super::transport::Transport;
+use super::{progress, LoginErr, LoginProgress};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::{fs, io, thread};
+use thiserror::Error;
+
+// Tor rate limits NEWNYM and needs a moment to build fresh circuits.
+const NEWNYM_SETTLE: Duration = Duration::from_secs(10);
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub enum TorAuth {
+    None,
+    Password(String),
+    CookieFile(PathBuf),
+}
+
+/// Where to reach the Tor control port, and how many times a login may be
+/// retried on a fresh circuit when the server looks down.
+#[derive(Debug, Clone)]
+pub struct TorControlConfig {
+    pub addr: String,
+    pub auth: TorAuth,
+    pub max_retries: usize,
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum TorCtlErr {
+    #[error("tor control port: {0}")]
+    Io(#[from] io::Error),
+    #[error("tor control authentication failed: {0}")]
+    Auth(String),
+    #[error("unexpected tor control reply: {0}")]
+    Protocol(String),
+}
+
+impl Classify for TorCtlErr {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            TorCtlErr::Io(_) => ErrorKind::Transient,
+            TorCtlErr::Auth(_) => ErrorKind::InvalidInput,
+            TorCtlErr::Protocol(_) => ErrorKind::Fatal,
+        }
+    }
+}
+
+fn auth_command(auth: &TorAuth) -> Result<String, TorCtlErr> {
+    Ok(match auth {
+        TorAuth::None => "AUTHENTICATE".to_owned(),
+        TorAuth::Password(password) => {
+            let escaped = password.replace('\\', "\\\\").replace('"', "\\\"");
+            format!("AUTHENTICATE \"{}\"", escaped)
+        }
+        TorAuth::CookieFile(path) => {
+            let cookie = fs::read(path)?;
+            let hex: String = cookie.iter().map(|b| format!("{:02X}", b)).collect();
+            format!("AUTHENTICATE {}", hex)
+        }
+    })
+}
+
+// Replies end with a "NNN " line, "NNN-" and "NNN+" lines continue it.
+fn read_reply(reader: &mut BufReader<TcpStream>) -> Result<Vec<String>, TorCtlErr> {
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(TorCtlErr::Protocol(lines.pop().unwrap_or_else(|| "connection closed".to_owned())));
+        }
+        let line = line.trim_end().to_owned();
+        let last = line.as_bytes().get(3).is_none_or(|&b| b == b' ');
+        lines.push(line);
+        if last {
+            return Ok(lines);
+        }
+    }
+}
+
+fn send_command_lines(
+    stream: &mut TcpStream,
+    reader: &mut BufReader<TcpStream>,
+    command: &str,
+) -> Result<Vec<String>, TorCtlErr> {
+    stream.write_all(format!("{}\r\n", command).as_bytes())?;
+    read_reply(reader)
+}
+
+fn send_command(
+    stream: &mut TcpStream,
+    reader: &mut BufReader<TcpStream>,
+    command: &str,
+) -> Result<String, TorCtlErr> {
+    Ok(send_command_lines(stream, reader, command)?.pop().unwrap_or_default())
+}
+
+// Connected and authenticated
+fn open(config: &TorControlConfig) -> Result<(TcpStream, BufReader<TcpStream>), TorCtlErr> {
+    let mut stream = TcpStream::connect(&config.addr)?;
+    stream.set_read_timeout(Some(CONTROL_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let reply = send_command(&mut stream, &mut reader, &auth_command(&config.auth)?)?;
+    if !reply.starts_with("250") {
+        return Err(TorCtlErr::Auth(reply));
+    }
+    Ok((stream, reader))
+}
+
+/// Ask Tor for new circuits (SIGNAL NEWNYM).
+pub fn new_identity(config: &TorControlConfig) -> Result<(), TorCtlErr> {
+    let (mut stream, mut reader) = open(config)?;
+    let reply = send_command(&mut stream, &mut reader, "SIGNAL NEWNYM")?;
+    if !reply.starts_with("250") {
+        return Err(TorCtlErr::Protocol(reply));
+    }
+    let _ = send_command(&mut stream, &mut reader, "QUIT");
+    Ok(())
+}
+
+fn client_auth_view_command(key: &ClientAuthKey) -> String {
+    format!("ONION_CLIENT_AUTH_VIEW {}", key.onion())
+}
+
+// Permanent puts it in ClientOnionAuthDir, so tor keeps it across restarts
+fn client_auth_add_command(key: &ClientAuthKey) -> String {
+    format!("ONION_CLIENT_AUTH_ADD {} {} Flags=Permanent", key.onion(), key.control_blob())
+}
+
+/// Make sure tor holds the client authorization `key`, adding it when it
+/// is missing or different. Tor writes permanent keys to its
+/// `ClientOnionAuthDir`, when it has none the key only lasts until tor
+/// restarts and a warning is logged.
+pub fn add_client_auth(config: &TorControlConfig, key: &ClientAuthKey) -> Result<(), TorCtlErr> {
+    let (mut stream, mut reader) = open(config)?;
+    let lines = send_command_lines(&mut stream, &mut reader, &client_auth_view_command(key))?;
+    if !lines.last().is_some_and(|l| l.starts_with("250")) {
+        return Err(TorCtlErr::Protocol(lines.join(" ")));
+    }
+    // 250-CLIENT <onion> x25519:<base64> [ClientName=...] [Flags=...]
+    let blob = key.control_blob();
+    let known = lines.iter().any(|l| {
+        let mut fields = l.split(' ').skip(1);
+        l.starts_with("250-CLIENT ") && fields.next() == Some(key.onion()) && fields.next() == Some(blob.as_str())
+    });
+    if !known {
+        let reply = send_command(&mut stream, &mut reader, &client_auth_add_command(key))?;
+        match reply.get(..3) {
+            // 251: replaced an older key
+            Some("250" | "251") => {}
+            Some("252") => log::warn!("tor has no ClientOnionAuthDir, the key for {} is kept in memory only", key.onion()),
+            _ => return Err(TorCtlErr::Protocol(reply)),
+        }
+    }
+    let _ = send_command(&mut stream, &mut reader, "QUIT");
+    Ok(())
+}
+
+/// Run `clb`, and when it fails because the server looks down or the
+/// circuit couldn't connect (in time), rotate the Tor circuit and try again,
+/// up to `config.max_retries` times. Each rotation is a `LoginProgress` of
+/// `transport`.
+/// Without a config this is a plain call.
+pub fn with_circuit_rotation<T, F>(transport: &Transport, config: Option<&TorControlConfig>, mut clb: F) -> Result<T, LoginErr>
+where
+    F: FnMut() -> Result<T, LoginErr>,
+{
+    let mut attempt = 0;
+    loop {
+        let res = clb();
+        let config = match (&res, config) {
+            (
+                Err(
+                    LoginErr::ServerDownErr(_)
+                    | LoginErr::ServerDown500Err(_)
+                    | LoginErr::ConnectTimeout(..)
+                    | LoginErr::CircuitFailed(..),
+                ),
+                Some(config),
+            ) => config,
+            _ => return res,
+        };
+        if attempt >= config.max_retries {
+            return res;
+        }
+        attempt += 1;
+        if let Err(e) = new_identity(config) {
+            log::error!("{}", e);
+            return res;
+        }
+        progress(transport.settings(), LoginProgress::NewCircuit { wait: NEWNYM_SETTLE });
+        thread::sleep(NEWNYM_SETTLE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn new_identity_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut received = Vec::new();
+            for _ in 0..3 {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                received.push(line.clone());
+                let reply = if line.starts_with("QUIT") { "250 closing connection\r\n" } else { "250 OK\r\n" };
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+            received
+        });
+
+        let config = TorControlConfig {
+            addr,
+            auth: TorAuth::Password("p\"w".to_owned()),
+            max_retries: 1,
+        };
+        new_identity(&config).unwrap();
+        let received = server.join().unwrap();
+        assert_eq!(received, vec![
+            "AUTHENTICATE \"p\\\"w\"\r\n".to_owned(),
+            "SIGNAL NEWNYM\r\n".to_owned(),
+            "QUIT\r\n".to_owned(),
+        ]);
+    }
+
+    // Answers each command with the next reply, returns what was received
+    fn control_server(replies: Vec<String>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut received = Vec::new();
+            for reply in replies {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                received.push(line.trim_end().to_owned());
+                stream.write_all(reply.as_bytes()).unwrap();
+            }
+            received
+        });
+        (addr, server)
+    }
+
+    #[test]
+    fn add_client_auth_test() {
+        let onion = "duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad";
+        let key: ClientAuthKey =
+            format!("{}:descriptor:x25519:GBDD3ZFBWKZ5NKUKZRUDGR45JAQ7HJI6KBUS5SCV6SYDZUPTJLTA", onion).parse().unwrap();
+        let view = format!("ONION_CLIENT_AUTH_VIEW {}", onion);
+        let add = format!("ONION_CLIENT_AUTH_ADD {} {} Flags=Permanent", onion, key.control_blob());
+        let config = |addr| TorControlConfig { addr, auth: TorAuth::None, max_retries: 1 };
+
+        // Unknown to tor, added
+        let (addr, server) = control_server(vec![
+            "250 OK\r\n".to_owned(),
+            format!("250-ONION_CLIENT_AUTH_VIEW {}\r\n250 OK\r\n", onion),
+            "252 Registered client and not persisted\r\n".to_owned(),
+            "250 closing connection\r\n".to_owned(),
+        ]);
+        add_client_auth(&config(addr), &key).unwrap();
+        assert_eq!(server.join().unwrap(), vec!["AUTHENTICATE".to_owned(), view.clone(), add.clone(), "QUIT".to_owned()]);
+
+        // Already there, nothing to do
+        let (addr, server) = control_server(vec![
+            "250 OK\r\n".to_owned(),
+            format!(
+                "250-ONION_CLIENT_AUTH_VIEW {}\r\n250-CLIENT {} {} Flags=Permanent\r\n250 OK\r\n",
+                onion,
+                onion,
+                key.control_blob()
+            ),
+            "250 closing connection\r\n".to_owned(),
+        ]);
+        add_client_auth(&config(addr), &key).unwrap();
+        assert_eq!(server.join().unwrap(), vec!["AUTHENTICATE".to_owned(), view.clone(), "QUIT".to_owned()]);
+
+        // Rejected
+        let (addr, server) = control_server(vec![
+            "250 OK\r\n".to_owned(),
+            "250 OK\r\n".to_owned(),
+            "553 Unable to store creds\r\n".to_owned(),
+        ]);
+        let err = add_client_auth(&config(addr), &key).unwrap_err();
+        assert!(matches!(err, TorCtlErr::Protocol(ref r) if r.starts_with("553")), "{}", err);
+        assert_eq!(server.join().unwrap(), vec!["AUTHENTICATE".to_owned(), view, add]);
+    }
+}
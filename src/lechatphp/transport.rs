@@ -0,0 +1,421 @@
+use super::captcha_prompt::CaptchaImage;
+use super::client::{self, Protocol};
+use super::metrics::Operation;
+use super::onion::{self, UrlErr};
+use super::retry::{self, SendErr};
+use super::settings::Settings;
+use super::{charset, http_log, tls, LoginProgress, LANG};
+use reqwest::blocking::{multipart, Client, RequestBuilder, Response};
+use reqwest::cookie::Jar;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Url;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Read;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{io, iter};
+
+/// Enough for any chat page, small enough that a front-end streaming junk
+/// can't exhaust memory.
+pub const DEFAULT_MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+const PROXY_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Read the body, giving up as soon as it grows past `limit` bytes. One
+/// shorter than its `Content-Length` is `SendErr::Truncated`.
+pub fn read_body(resp: Response, limit: usize) -> Result<Vec<u8>, SendErr> {
+    let expected = resp.content_length();
+    if expected.is_some_and(|len| len > limit as u64) {
+        return Err(SendErr::ResponseTooLarge { limit });
+    }
+    let mut body = Vec::new();
+    let read = resp.take(limit as u64 + 1).read_to_end(&mut body);
+    match (read, expected) {
+        (Err(e), Some(expected)) if cut_short(&e) => return Err(SendErr::Truncated { expected, got: body.len() }),
+        (Err(e), _) => return Err(SendErr::Body(e)),
+        (Ok(_), _) => {}
+    }
+    if body.len() > limit {
+        return Err(SendErr::ResponseTooLarge { limit });
+    }
+    if let Some(expected) = expected.filter(|&expected| (body.len() as u64) < expected) {
+        return Err(SendErr::Truncated { expected, got: body.len() });
+    }
+    Ok(body)
+}
+
+/// Whether reading a body failed because the connection closed before its
+/// end, somewhere down the error's sources.
+pub(crate) fn cut_short(err: &(dyn Error + 'static)) -> bool {
+    iter::successors(Some(err), |&e| e.source())
+        .any(|e| e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::UnexpectedEof))
+}
+
+#[derive(Debug, Clone)]
+struct Route {
+    client: Client,
+    protocol: Protocol,
+}
+
+/// The configured, proxied client. Built by `client::build` only, and the
+/// only thing this module sends requests with, so nothing can go around
+/// the proxy.
+#[derive(Debug, Clone)]
+pub struct Transport {
+    default: Route,
+    /// Mirrors with their own protocol options, by `host:port`.
+    mirrors: HashMap<String, Route>,
+    onion_only: bool,
+    /// `host:port` of the SOCKS proxy, if any.
+    socks_proxy: Option<String>,
+    /// Cookie store shared by every route, `None` when cookies are off.
+    jar: Option<Arc<Jar>>,
+    /// The language pages are asked in.
+    lang: String,
+    /// What else the client was built with, shared by its clones.
+    settings: Arc<Settings>,
+}
+
+impl Transport {
+    pub(super) fn new(client: Client, protocol: Protocol, onion_only: bool) -> Self {
+        Self {
+            default: Route { client, protocol },
+            mirrors: HashMap::new(),
+            onion_only,
+            socks_proxy: None,
+            jar: None,
+            lang: LANG.to_owned(),
+            settings: Arc::new(Settings::default()),
+        }
+    }
+
+    /// The settings this client was built with, and its state.
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub(super) fn set_settings(&mut self, settings: Settings) {
+        self.settings = Arc::new(settings);
+    }
+
+    /// Ask for the captchas the solver doesn't read, or all of them with
+    /// `manual_captcha` or without the `solver` feature. Without a prompt,
+    /// such a login fails with `LoginErr::CaptchaPrompt`.
+    pub fn set_prompt(&self, prompt: impl Fn(&CaptchaImage) -> io::Result<String> + Send + Sync + 'static) {
+        self.settings.prompt.set(Arc::new(prompt));
+    }
+
+    /// Called with what the login waits for, on top of the log.
+    pub fn set_progress(&self, progress: impl Fn(&LoginProgress) + Send + Sync + 'static) {
+        self.settings.progress.set(Arc::new(progress));
+    }
+
+    /// The language the chat's pages are asked in, `ClientConfig::lang`.
+    pub fn lang(&self) -> &str {
+        &self.lang
+    }
+
+    pub fn set_lang(&mut self, lang: &str) {
+        self.lang = lang.to_owned();
+    }
+
+    pub(super) fn set_jar(&mut self, jar: Option<Arc<Jar>>) {
+        self.jar = jar;
+    }
+
+    /// Store a cookie for `url` as if its response had set it, for pages
+    /// that set cookies themselves. Dropped when cookies are off.
+    pub fn add_cookie(&self, cookie: &str, url: &str) {
+        match (&self.jar, Url::parse(url)) {
+            (Some(jar), Ok(url)) => jar.add_cookie_str(cookie, &url),
+            _ => log::warn!("cookie for {} dropped, the cookie store is off", url),
+        }
+    }
+
+    pub(super) fn set_proxy(&mut self, proxy_url: Option<&str>) {
+        self.socks_proxy = proxy_url
+            .and_then(|url| Url::parse(url).ok())
+            .filter(|url| url.scheme().starts_with("socks"))
+            .and_then(|url| Some(format!("{}:{}", url.host_str()?, url.port_or_known_default().unwrap_or(1080))));
+    }
+
+    /// Whether the SOCKS proxy answers, to tell when it is back after a
+    /// `LoginErr::ProxyDown`. True without one.
+    pub fn proxy_up(&self) -> bool {
+        self.socks_proxy.as_ref().is_none_or(|addr| client::probe_socks5(addr, PROXY_PROBE_TIMEOUT))
+    }
+
+    pub(super) fn host_key(base_url: &str) -> Option<String> {
+        Url::parse(base_url).ok().and_then(|url| tls::host_key(&url))
+    }
+
+    /// Use `client` for every request to the host of `base_url`.
+    pub(super) fn add_mirror(&mut self, base_url: &str, client: Client, protocol: Protocol) {
+        if let Some(host) = Self::host_key(base_url) {
+            self.mirrors.insert(host, Route { client, protocol });
+        }
+    }
+
+    fn route(&self, url: &str) -> &Route {
+        Self::host_key(url).and_then(|host| self.mirrors.get(&host)).unwrap_or(&self.default)
+    }
+
+    /// Start a GET, to be sent with `send`.
+    pub fn get<U: AsRef<str>>(&self, url: U) -> RequestBuilder {
+        self.route(url.as_ref()).client.get(url.as_ref())
+    }
+
+    /// Start a POST, to be sent with `send`.
+    pub fn post<U: AsRef<str>>(&self, url: U) -> RequestBuilder {
+        self.route(url.as_ref()).client.post(url.as_ref())
+    }
+
+    // In onion-only mode, clearnet hosts are refused before connecting
+    fn guard(&self, req: &RequestBuilder) -> Result<(), SendErr> {
+        if !self.onion_only {
+            return Ok(());
+        }
+        let url = req.try_clone().and_then(|r| r.build().ok()).map(|r| r.url().to_string());
+        // Unparsable or streaming requests are refused too
+        self.guard_url(url.as_deref().unwrap_or_default())
+    }
+
+    fn guard_url(&self, url: &str) -> Result<(), SendErr> {
+        if !self.onion_only || (!url.is_empty() && !onion::is_clearnet(url)) {
+            return Ok(());
+        }
+        log::error!("refusing request to {} in onion-only mode", url);
+        Err(SendErr::Clearnet(UrlErr::Clearnet(url.to_owned())))
+    }
+
+    /// Send the request with retries, rate limiting and pin checks, see
+    /// `retry::send`.
+    pub fn send(&self, req: RequestBuilder) -> Result<Response, SendErr> {
+        self.send_as(Operation::Other, req)
+    }
+
+    /// Like `send`, recording the request's outcome and duration under
+    /// `operation`.
+    pub fn send_as(&self, operation: Operation, req: RequestBuilder) -> Result<Response, SendErr> {
+        self.guard(&req)?;
+        self.send_guarded(operation, req)
+    }
+
+    /// POST `form` to `url`, once. The form's parts may be streamed from
+    /// files, the url is checked by itself since such a request can't be
+    /// looked into before it is sent.
+    pub fn send_multipart_as(&self, operation: Operation, url: &str, form: multipart::Form) -> Result<Response, SendErr> {
+        self.guard_url(url)?;
+        self.send_guarded(operation, self.post(url).multipart(form))
+    }
+
+    fn send_guarded(&self, operation: Operation, req: RequestBuilder) -> Result<Response, SendErr> {
+        let start = Instant::now();
+        let res = retry::send(&self.settings, req);
+        self.settings.metrics.record(operation, start.elapsed(), &res);
+        if let Err(SendErr::Reqwest(e)) = &res {
+            self.hint_http1(e);
+        }
+        res
+    }
+
+    // HTTP/2 trouble usually means a front-end that only really speaks 1.1
+    fn hint_http1(&self, err: &reqwest::Error) {
+        let url = match err.url() {
+            Some(url) if !self.route(url.as_str()).protocol.http1_only => url,
+            _ => return,
+        };
+        let mut source = std::error::Error::source(err);
+        while let Some(e) = source {
+            if e.to_string().contains("http2") {
+                log::warn!("{} failed over HTTP/2, consider http1_only for this mirror", url);
+                return;
+            }
+            source = e.source();
+        }
+    }
+
+    /// Send the request once, bypassing retries and the rate limiter, for
+    /// probes.
+    pub fn send_once(&self, req: RequestBuilder) -> Result<Response, SendErr> {
+        self.guard(&req)?;
+        let res = http_log::send(&self.settings, req);
+        self.settings.pins.check(&res).map_err(SendErr::PinMismatch)?;
+        res
+    }
+
+    /// The body as text, within the client's maximum size and decoded with
+    /// the page's charset, see `charset::decode`. Use this instead of
+    /// `Response::text`, which only knows the header.
+    pub fn text(&self, resp: Response) -> Result<String, SendErr> {
+        let content_type = resp.headers().get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).map(str::to_owned);
+        let body = read_body(resp, self.settings.max_body_size)?;
+        Ok(charset::decode(&body, content_type.as_deref()))
+    }
+}
+
+#[cfg(test)]
+impl Transport {
+    /// Direct connections and default settings, for tests against local
+    /// mock servers.
+    pub fn direct() -> Self {
+        let jar = Arc::new(Jar::default());
+        let client = Client::builder().no_proxy().cookie_provider(Arc::clone(&jar)).build().unwrap();
+        let mut transport = Self::new(client, Protocol::default(), false);
+        transport.set_jar(Some(jar));
+        transport
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lechatphp::client::{self, ClientConfig, ProxySetting};
+    use crate::lechatphp::mock::{MockResponse, MockServer};
+    use http::Version;
+    use crate::lechatphp::LoginErr;
+    use std::io::ErrorKind;
+    use std::net::TcpListener;
+
+    #[test]
+    fn onion_only_test() {
+        // Stands in for a clearnet host, it must never see a connection
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let url = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+
+        let config = ClientConfig { proxy: ProxySetting::Direct, allow_clearnet: Some(false), ..Default::default() };
+        let transport = client::build(&config).unwrap();
+        let err = transport.send(transport.post(&url).form(&[("pass", "x")])).unwrap_err();
+        assert!(matches!(err, SendErr::Clearnet(UrlErr::Clearnet(_))), "unexpected {:?}", err);
+        assert!(transport.send_once(transport.get(&url)).is_err());
+        let err = crate::lechatphp::login(&transport, &url, "chat.php", "nick", "pass", "", true).unwrap_err();
+        assert!(matches!(err, LoginErr::InvalidUrl(UrlErr::Clearnet(_))), "unexpected {:?}", err);
+        assert_eq!(listener.accept().unwrap_err().kind(), ErrorKind::WouldBlock);
+
+        // The default for an onion chat, as the library's users build it
+        let onion = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+        let config = ClientConfig { proxy: ProxySetting::Direct, ..Default::default() };
+        let chat = crate::lechatphp::chat::LeChatClient::new(config, onion, "chat.php").unwrap();
+        let err = chat.transport().send(chat.transport().post(&url).form(&[("pass", "x")])).unwrap_err();
+        assert!(matches!(err, SendErr::Clearnet(UrlErr::Clearnet(_))), "unexpected {:?}", err);
+        assert_eq!(listener.accept().unwrap_err().kind(), ErrorKind::WouldBlock);
+
+        // The listener does work when clearnet is allowed
+        let transport = Transport::direct();
+        let _ = transport.send_once(transport.get(&url).timeout(std::time::Duration::from_millis(200)));
+        assert!(listener.accept().is_ok());
+    }
+
+    #[test]
+    fn max_body_size_test() {
+        use std::io::Write;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        // Streams chunks without a content length until the client hangs up
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let sent = Arc::new(AtomicUsize::new(0));
+        let sent_clone = Arc::clone(&sent);
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut buf = [0u8; 1024];
+                let _ = std::io::Read::read(&mut stream, &mut buf);
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n");
+                let chunk = format!("{:x}\r\n{}\r\n", 1024, "x".repeat(1024));
+                while sent_clone.load(Ordering::SeqCst) < 100 * 1024 * 1024 && stream.write_all(chunk.as_bytes()).is_ok() {
+                    sent_clone.fetch_add(1024, Ordering::SeqCst);
+                }
+            }
+        });
+
+        let transport = Transport::direct();
+        let resp = transport.send_once(transport.get(&url)).unwrap();
+        let err = read_body(resp, 64 * 1024).unwrap_err();
+        assert!(matches!(err, SendErr::ResponseTooLarge { limit: 65536 }), "unexpected {:?}", err);
+        // Aborted early, not after reading everything
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        assert!(sent.load(Ordering::SeqCst) < 10 * 1024 * 1024);
+
+        let server = MockServer::start(|_| MockResponse::ok("small"));
+        let resp = transport.send_once(transport.get(&server.url)).unwrap();
+        assert_eq!(transport.text(resp).unwrap(), "small");
+        let resp = transport.send_once(transport.get(&server.url)).unwrap();
+        assert!(matches!(read_body(resp, 3), Err(SendErr::ResponseTooLarge { limit: 3 })));
+    }
+
+    #[test]
+    fn truncated_body_test() {
+        use std::io::Write;
+        // Promises 100 bytes, closes after 12
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://127.0.0.1:{}", listener.local_addr().unwrap().port());
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut buf = [0u8; 1024];
+                let _ = std::io::Read::read(&mut stream, &mut buf);
+                let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\nConnection: close\r\n\r\n<html><body>");
+            }
+        });
+
+        let transport = Transport::direct();
+        let resp = transport.send_once(transport.get(&url)).unwrap();
+        let err = read_body(resp, 1000).unwrap_err();
+        assert!(matches!(err, SendErr::Truncated { expected: 100, got: 12 }), "unexpected {:?}", err);
+
+        // Logging the body reads it before the caller does, and tells the same
+        let mut transport = Transport::direct();
+        transport.set_settings(Settings { http_log: http_log::HttpLog::Bodies, ..Default::default() });
+        let err = transport.send_once(transport.get(&url)).unwrap_err();
+        assert!(matches!(err, SendErr::Truncated { expected: 100, got: 12 }), "unexpected {:?}", err);
+    }
+
+    // Regression: a mirror whose front-end resets h2 attempts must keep
+    // getting an http1-only client
+    #[test]
+    fn protocol_test() {
+        let server = MockServer::start(|_| MockResponse::ok("ok"));
+        let http1 = Protocol { http1_only: true, reuse_connections: false, ..Default::default() };
+        let config = ClientConfig {
+            proxy: ProxySetting::Direct,
+            mirror_protocols: HashMap::from([(server.url.clone(), http1)]),
+            ..Default::default()
+        };
+        let transport = client::build(&config).unwrap();
+        assert_eq!(transport.route(&format!("{}/chat.php", server.url)).protocol, http1);
+        assert_eq!(transport.route("http://other.onion/chat.php").protocol, Protocol::default());
+        let resp = transport.send(transport.get(&server.url)).unwrap();
+        assert_eq!(resp.version(), Version::HTTP_11);
+
+        let config = ClientConfig { mirror_protocols: HashMap::from([("nope".to_owned(), http1)]), ..config };
+        assert!(matches!(client::build(&config), Err(client::BuildErr::Config(client::ConfigError::InvalidMirrorUrl(_)))));
+    }
+
+    // Soak: a session's requests keep going over the same connection
+    #[test]
+    fn keep_alive_test() {
+        let server = MockServer::start_keep_alive(|_| MockResponse::ok("ok"));
+        let config = ClientConfig { proxy: ProxySetting::Direct, target_url: Some(server.url.clone()), rate_limit: None, ..Default::default() };
+        let transport = client::build(&config).unwrap();
+        for i in 0..50 {
+            let resp = if i % 2 == 0 {
+                transport.send_as(Operation::Fetch, transport.get(&server.url))
+            } else {
+                transport.send_as(Operation::Post, transport.post(&server.url).form(&[("message", "hi")]))
+            };
+            assert_eq!(transport.text(resp.unwrap()).unwrap(), "ok");
+        }
+        assert_eq!(server.hits(), 50);
+        assert_eq!(server.connections(), 1);
+        assert_eq!(transport.settings().metrics.snapshot().pool, Some(config.pool));
+
+        let server = MockServer::start_keep_alive(|_| MockResponse::ok("ok"));
+        let config = ClientConfig { protocol: Protocol { reuse_connections: false, ..Default::default() }, ..config };
+        let transport = client::build(&config).unwrap();
+        for _ in 0..5 {
+            transport.text(transport.send(transport.get(&server.url)).unwrap()).unwrap();
+        }
+        assert_eq!(server.connections(), 5);
+    }
+}
@@ -0,0 +1,361 @@
+use super::error_kind::{Classify, ErrorKind};
+use super::messages::{Message, MessageKind};
+use super::settings::Settings;
+use chrono::{DateTime, FixedOffset};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::{fs, io};
+use thiserror::Error;
+
+/// Unread messages kept per scope, the oldest are dropped past it and the
+/// count stays there.
+const MAX_UNREAD: usize = 10_000;
+
+/// What `mark_read` marks.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ReadScope {
+    /// The chat itself, what isn't private.
+    Chat,
+    /// What isn't private in that room, on forks with several. `Chat` is
+    /// the default room then.
+    Room(String),
+    /// The private messages with that nick, both ways.
+    Private(String),
+    /// The chat and every conversation.
+    All,
+}
+
+/// Unread messages, to show as badges.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UnreadCounts {
+    pub chat: usize,
+    /// By the nick on the other side, only those with unread messages.
+    pub private: HashMap<String, usize>,
+    /// By room, only those with unread messages.
+    pub rooms: HashMap<String, usize>,
+}
+
+impl UnreadCounts {
+    pub fn total(&self) -> usize {
+        self.chat + self.private.values().sum::<usize>() + self.rooms.values().sum::<usize>()
+    }
+}
+
+/// The last message read in a scope.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct Marker {
+    id: Option<u64>,
+    /// RFC 3339, for views without ids and ids that started over.
+    time: Option<String>,
+}
+
+impl Marker {
+    fn new(message: &Message) -> Self {
+        Self { id: message.id, time: message.time.map(|t| t.to_rfc3339()) }
+    }
+
+    fn time(&self) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc3339(self.time.as_deref()?).ok()
+    }
+
+    // `newest_id` is the newest of the messages delivered with it. Older
+    // than the marker, the chat was cleaned and its ids started over:
+    // they tell nothing then, the times do.
+    fn is_unread(&self, message: &Message, newest_id: Option<u64>) -> bool {
+        match (message.id, self.id) {
+            (Some(id), Some(marked)) if newest_id.is_some_and(|newest| newest >= marked) => id > marked,
+            // Without both times it is taken as read, rather than counting
+            // what may be old
+            _ => message.time.zip(self.time()).is_some_and(|(time, marked)| time > marked),
+        }
+    }
+}
+
+/// The markers as saved.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct Markers {
+    chat: Option<Marker>,
+    private: HashMap<String, Marker>,
+    #[serde(default)]
+    rooms: HashMap<String, Marker>,
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum UnreadErr {
+    #[error("read markers {}: {}", .0.display(), .1)]
+    Io(PathBuf, #[source] io::Error),
+    #[error("read markers {}: {}", .0.display(), .1)]
+    Json(PathBuf, #[source] serde_json::Error),
+}
+
+impl Classify for UnreadErr {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            UnreadErr::Io(..) | UnreadErr::Json(..) => ErrorKind::Fatal,
+        }
+    }
+}
+
+/// Read markers and the messages delivered past them.
+#[derive(Debug, Clone, Default)]
+pub struct Tracker {
+    markers: Markers,
+    /// Per scope, `ReadScope::All` never.
+    unread: HashMap<ReadScope, Vec<Message>>,
+    /// The newest message delivered per scope, what `mark_read` marks.
+    last: HashMap<ReadScope, Message>,
+}
+
+// Where a message counts, `None` for ours and the system's
+fn scope(message: &Message, me: Option<&str>) -> Option<ReadScope> {
+    let is_me = |nick: &Option<String>| me.is_some_and(|me| nick.as_deref().is_some_and(|n| n.eq_ignore_ascii_case(me)));
+    match message.kind {
+        MessageKind::System(_) => None,
+        _ if is_me(&message.from) => None,
+        MessageKind::Private => message.from.clone().map(ReadScope::Private),
+        _ => Some(message.room.clone().map_or(ReadScope::Chat, ReadScope::Room)),
+    }
+}
+
+impl Tracker {
+    /// The markers saved at `path`, none when there is no file yet.
+    pub fn load(path: &Path) -> Result<Self, UnreadErr> {
+        let markers = match fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json).map_err(|e| UnreadErr::Json(path.to_owned(), e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Markers::default(),
+            Err(e) => return Err(UnreadErr::Io(path.to_owned(), e)),
+        };
+        Ok(Self { markers, ..Self::default() })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), UnreadErr> {
+        let json = serde_json::to_string(&self.markers).expect("markers serialize");
+        fs::write(path, json).map_err(|e| UnreadErr::Io(path.to_owned(), e))
+    }
+
+    fn marker(&self, scope: &ReadScope) -> Option<&Marker> {
+        match scope {
+            ReadScope::Chat => self.markers.chat.as_ref(),
+            ReadScope::Private(nick) => self.markers.private.get(nick),
+            ReadScope::Room(room) => self.markers.rooms.get(room),
+            ReadScope::All => None,
+        }
+    }
+
+    /// Count `messages`, new ones oldest first, past their scope's marker.
+    /// `me` is our nick, our own messages are never unread. Without a
+    /// marker yet, everything counts.
+    pub fn record(&mut self, messages: &[Message], me: Option<&str>) {
+        let newest_id = messages.iter().filter_map(|m| m.id).max();
+        for message in messages {
+            let Some(scope) = scope(message, me) else {
+                continue;
+            };
+            if self.marker(&scope).is_none_or(|marker| marker.is_unread(message, newest_id)) {
+                let unread = self.unread.entry(scope.clone()).or_default();
+                if unread.len() == MAX_UNREAD {
+                    unread.remove(0);
+                }
+                unread.push(message.clone());
+            }
+            self.last.insert(scope, message.clone());
+        }
+    }
+
+    /// Messages gone from the chat are no longer unread.
+    pub fn forget(&mut self, deleted: &[Message]) {
+        for unread in self.unread.values_mut() {
+            unread.retain(|m| !deleted.contains(m));
+        }
+    }
+
+    /// Everything delivered in `scope` so far is read.
+    pub fn mark_read(&mut self, scope: &ReadScope) {
+        let scopes: Vec<_> = match scope {
+            ReadScope::All => self.last.keys().cloned().collect(),
+            scope => vec![scope.clone()],
+        };
+        for scope in scopes {
+            self.unread.remove(&scope);
+            // The last delivered rather than the highest id, so a marker
+            // from before a clean is replaced
+            let Some(marker) = self.last.get(&scope).map(Marker::new) else {
+                continue;
+            };
+            match scope {
+                ReadScope::Chat => self.markers.chat = Some(marker),
+                ReadScope::Private(nick) => {
+                    self.markers.private.insert(nick, marker);
+                }
+                ReadScope::Room(room) => {
+                    self.markers.rooms.insert(room, marker);
+                }
+                ReadScope::All => {}
+            }
+        }
+    }
+
+    pub fn counts(&self) -> UnreadCounts {
+        let mut counts = UnreadCounts::default();
+        for (scope, unread) in self.unread.iter().filter(|(_, unread)| !unread.is_empty()) {
+            match scope {
+                ReadScope::Chat => counts.chat = unread.len(),
+                ReadScope::Private(nick) => {
+                    counts.private.insert(nick.clone(), unread.len());
+                }
+                ReadScope::Room(room) => {
+                    counts.rooms.insert(room.clone(), unread.len());
+                }
+                ReadScope::All => {}
+            }
+        }
+        counts
+    }
+}
+
+/// A client's `Tracker`, and where its markers are saved.
+#[derive(Debug, Default)]
+pub struct ReadMarkers {
+    tracker: Mutex<Tracker>,
+    // One file per profile
+    path: Option<PathBuf>,
+}
+
+impl ReadMarkers {
+    /// Track what is read with the markers saved at `path`, or only for
+    /// the process with `None`.
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let tracker = match path.as_deref().map(Tracker::load) {
+            Some(Ok(tracker)) => tracker,
+            Some(Err(e)) => {
+                log::warn!("{}, starting without", e);
+                Tracker::default()
+            }
+            None => Tracker::default(),
+        };
+        ReadMarkers { tracker: Mutex::new(tracker), path }
+    }
+}
+
+/// The messages new to the stream, oldest first.
+pub(super) fn record(settings: &Settings, messages: &[Message]) {
+    settings.read_markers.tracker.lock().unwrap().record(messages, settings.mention.nick().as_deref());
+}
+
+pub(super) fn forget(settings: &Settings, deleted: &[Message]) {
+    settings.read_markers.tracker.lock().unwrap().forget(deleted);
+}
+
+/// Mark `scope` read, and save the markers when there is a file for them.
+pub fn mark_read(settings: &Settings, scope: ReadScope) -> Result<(), UnreadErr> {
+    let markers = &settings.read_markers;
+    let mut tracker = markers.tracker.lock().unwrap();
+    tracker.mark_read(&scope);
+    match markers.path.as_deref() {
+        Some(path) => tracker.save(path),
+        None => Ok(()),
+    }
+}
+
+pub fn unread_counts(settings: &Settings) -> UnreadCounts {
+    settings.read_markers.tracker.lock().unwrap().counts()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: u64, time: &str, kind: MessageKind, from: &str, to: Option<&str>) -> Message {
+        Message {
+            id: Some(id),
+            timestamp: format!("10-17 {}", time),
+            time: Some(DateTime::parse_from_rfc3339(&format!("2026-10-17T{}+00:00", time)).unwrap()),
+            from: Some(from.to_owned()),
+            to: to.map(str::to_owned),
+            text: format!("message {}", id),
+            spans: vec![],
+            color: None,
+            display_from: None,
+            display_color: None,
+            kind,
+            mentions_me: false,
+            room: None,
+        }
+    }
+
+    fn said(id: u64, time: &str, from: &str) -> Message {
+        message(id, time, MessageKind::Normal, from, None)
+    }
+
+    fn whisper(id: u64, time: &str, from: &str, to: &str) -> Message {
+        message(id, time, MessageKind::Private, from, Some(to))
+    }
+
+    fn counts(chat: usize, private: &[(&str, usize)]) -> UnreadCounts {
+        UnreadCounts { chat, private: private.iter().map(|(n, c)| (n.to_string(), *c)).collect(), ..UnreadCounts::default() }
+    }
+
+    #[test]
+    fn unread_test() {
+        let me = Some("zed");
+        let mut tracker = Tracker::default();
+        tracker.record(&[said(1, "10:00:00", "alice"), said(2, "10:00:01", "zed"), whisper(3, "10:00:02", "bob", "zed")], me);
+        let joined = Message { kind: MessageKind::System(crate::lechatphp::messages::SystemEvent::Joined("carol".to_owned())), from: None, ..said(4, "10:00:03", "") };
+        tracker.record(&[joined, whisper(5, "10:00:04", "zed", "bob"), whisper(6, "10:00:05", "carol", "zed")], me);
+        // Ours and the system's don't count
+        assert_eq!(tracker.counts(), counts(1, &[("bob", 1), ("carol", 1)]));
+        assert_eq!(tracker.counts().total(), 3);
+
+        tracker.mark_read(&ReadScope::Private("bob".to_owned()));
+        assert_eq!(tracker.counts(), counts(1, &[("carol", 1)]));
+        tracker.record(&[said(7, "10:00:06", "alice"), whisper(8, "10:00:07", "bob", "zed")], me);
+        assert_eq!(tracker.counts(), counts(2, &[("bob", 1), ("carol", 1)]));
+        tracker.forget(&[said(7, "10:00:06", "alice")]);
+        assert_eq!(tracker.counts(), counts(1, &[("bob", 1), ("carol", 1)]));
+        tracker.mark_read(&ReadScope::All);
+        assert_eq!(tracker.counts(), UnreadCounts::default());
+
+        // After a restart, the page is delivered again with what came meanwhile
+        let path = std::env::temp_dir().join(format!("bhcli-unread-{}.json", std::process::id()));
+        tracker.save(&path).unwrap();
+        let mut tracker = Tracker::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        tracker.record(&[said(1, "10:00:00", "alice"), whisper(8, "10:00:07", "bob", "zed"), said(9, "10:00:08", "alice"), whisper(10, "10:00:09", "dave", "zed")], me);
+        assert_eq!(tracker.counts(), counts(1, &[("dave", 1)]));
+
+        // Another room's ids are its own, the chat's marker doesn't apply
+        tracker.record(&[Message { room: Some("lounge".to_owned()), ..said(5, "10:00:04", "carol") }], me);
+        assert_eq!(tracker.counts().rooms, HashMap::from([("lounge".to_owned(), 1)]));
+        tracker.mark_read(&ReadScope::Room("lounge".to_owned()));
+        assert_eq!(tracker.counts(), counts(1, &[("dave", 1)]));
+    }
+
+    #[test]
+    fn cleaned_test() {
+        let mut tracker = Tracker::default();
+        tracker.record(&[said(500, "10:00:00", "alice"), said(501, "10:00:01", "alice")], None);
+        tracker.mark_read(&ReadScope::Chat);
+        // Cleaned, the ids start over: told by the times, not the ids
+        tracker.record(&[said(1, "09:00:00", "alice"), said(2, "10:05:00", "alice")], None);
+        assert_eq!(tracker.counts(), counts(1, &[]));
+        tracker.record(&[said(3, "10:06:00", "alice")], None);
+        assert_eq!(tracker.counts(), counts(2, &[]));
+        // Marked again, the ids are the new ones
+        tracker.mark_read(&ReadScope::Chat);
+        tracker.record(&[said(4, "10:07:00", "alice")], None);
+        assert_eq!(tracker.counts(), counts(1, &[]));
+
+        // Deleted messages after a mark, a newer one still counts
+        let mut tracker = Tracker::default();
+        tracker.record(&[said(10, "10:00:00", "alice")], None);
+        tracker.mark_read(&ReadScope::Chat);
+        tracker.record(&[said(11, "10:00:01", "alice")], None);
+        tracker.forget(&[said(11, "10:00:01", "alice"), said(12, "10:00:02", "alice")]);
+        assert_eq!(tracker.counts(), UnreadCounts::default());
+        tracker.record(&[said(13, "10:00:03", "alice")], None);
+        assert_eq!(tracker.counts(), counts(1, &[]));
+    }
+}
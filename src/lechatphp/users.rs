@@ -0,0 +1,309 @@
+use super::color::ChatColor;
+use super::exchange::{self, Exchange};
+use super::messages::{fetch_page, view_url, FetchErr};
+use super::page_url;
+use super::settings::Settings;
+use super::timestamp;
+use super::transport::Transport;
+use chrono::{DateTime, FixedOffset};
+use lazy_static::lazy_static;
+use regex::Regex;
+use select::document::Document;
+use select::node::Node;
+use select::predicate::{Attr, Name};
+use serde_derive::{Deserialize, Serialize};
+
+lazy_static! {
+    static ref COLOR_RGX: Regex = Regex::new(r"color:\s*([#\w]+)").unwrap();
+    static ref SECTION_RGX: Regex = Regex::new(r"(?i)^\s*(admins?|staff|members?|guests?)\s*:?\s*$").unwrap();
+    static ref IDLE_RGX: Regex = Regex::new(r"(?i)(\d+)\s*(m|min|mins|minutes?)\b").unwrap();
+    static ref DENIED_RGX: Regex = Regex::new(r"(?i)access denied|not allowed|permission denied|staff only|admins? only").unwrap();
+}
+
+/// Elements a fork may head a group of the list with.
+const SECTION_TAGS: &[&str] = &["th", "b", "strong", "h2", "h3", "h4", "dt"];
+
+/// Lowest first, `Role::Staff >= Role::Member`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Guest,
+    Member,
+    Staff,
+    Admin,
+}
+
+impl Role {
+    // `Staff:`, `Guests` or a class like `member`
+    fn parse(s: &str) -> Option<Self> {
+        let s = s.to_ascii_lowercase();
+        if s.starts_with("admin") {
+            Some(Role::Admin)
+        } else if s.starts_with("staff") {
+            Some(Role::Staff)
+        } else if s.starts_with("member") {
+            Some(Role::Member)
+        } else if s.starts_with("guest") {
+            Some(Role::Guest)
+        } else {
+            None
+        }
+    }
+}
+
+/// Someone in the chat's user list.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct User {
+    pub nick: String,
+    /// `None` when the list styles the nick with something not a color.
+    pub color: Option<ChatColor>,
+    pub role: Role,
+    /// When the server shows it.
+    pub idle_minutes: Option<u32>,
+    /// `nick` as the profile shows it, see `ClientConfig::display`.
+    pub display_nick: String,
+    pub display_color: Option<ChatColor>,
+}
+
+fn color(node: &Node) -> Option<String> {
+    match node.name()? {
+        "font" => node.attr("color").map(str::to_owned),
+        "span" => node.attr("style").and_then(|s| COLOR_RGX.captures(s)).map(|c| c[1].to_owned()),
+        _ => None,
+    }
+}
+
+/// The user list of a view page. Roles come from the section a nick is
+/// listed under (`Staff:`...) or from its class, nicks with neither are
+/// guests.
+pub fn parse_users(settings: &Settings, doc: &Document) -> Result<Vec<User>, FetchErr> {
+    let chatters = doc.find(Attr("id", "chatters")).next().ok_or(FetchErr::NoUserList)?;
+    let mut section = None;
+    let mut users = vec![];
+    for node in chatters.descendants() {
+        let Some(name) = node.name() else {
+            continue;
+        };
+        if SECTION_TAGS.contains(&name) {
+            let text = node.text();
+            if SECTION_RGX.is_match(&text) {
+                section = Role::parse(text.trim());
+            }
+            continue;
+        }
+        let Some(color) = color(&node) else {
+            continue;
+        };
+        let role = node.attr("class").and_then(|c| c.split_whitespace().find_map(Role::parse));
+        let idle_minutes = node.attr("title").and_then(|t| IDLE_RGX.captures(t)).and_then(|c| c[1].parse().ok());
+        let mut user = User {
+            nick: node.text().trim().to_owned(),
+            color: ChatColor::parse(&color),
+            role: role.or(section).unwrap_or(Role::Guest),
+            idle_minutes,
+            display_nick: String::new(),
+            display_color: None,
+        };
+        settings.display.apply_user(&mut user);
+        users.push(user);
+    }
+    Ok(users)
+}
+
+/// Who is in the chat now.
+pub fn fetch_online_users(transport: &Transport, base_url: &str, page_php: &str, session: &str) -> Result<Vec<User>, FetchErr> {
+    exchange::block_on(fetch_online_users_with(transport, base_url, page_php, session))
+}
+
+/// `fetch_online_users` over any `Exchange`.
+pub async fn fetch_online_users_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+) -> Result<Vec<User>, FetchErr> {
+    parse_users(http.settings(), &fetch_page(http, &view_url(http, base_url, page_php, session)).await?)
+}
+
+/// A profile as another user sees it. What the session's role may see,
+/// staff see more.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UserInfo {
+    pub nick: String,
+    pub color: Option<ChatColor>,
+    pub role: Option<Role>,
+    pub registered: Option<DateTime<FixedOffset>>,
+    pub last_seen: Option<DateTime<FixedOffset>>,
+    /// The staff's notes on them.
+    pub notes: Option<String>,
+    /// The other rows, label and value as shown.
+    pub other: Vec<(String, String)>,
+}
+
+// Percent-encoded for a query, all but the unreserved characters
+pub(super) fn encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| if b.is_ascii_alphanumeric() || b"-._~".contains(&b) { (b as char).to_string() } else { format!("%{:02X}", b) })
+        .collect()
+}
+
+fn user_info_url(base_url: &str, page_php: &str, session: &str, lang: &str, nick: &str) -> String {
+    format!("{}?action=userinfo&name={}&session={}&lang={}", page_url(base_url, page_php), encode(nick), session, lang)
+}
+
+fn parse_time(offset: FixedOffset, raw: &str) -> Option<DateTime<FixedOffset>> {
+    timestamp::message_times(&[raw], timestamp::server_now(offset)).pop().flatten()
+}
+
+/// The profile page's table, rows of a label and a value. Without one the
+/// page is a notice: no such nick, or access denied.
+pub fn parse_user_info(settings: &Settings, doc: &Document) -> Result<UserInfo, FetchErr> {
+    let Some(table) = doc.find(Attr("id", "userinfo")).next() else {
+        let text = doc.find(Name("body")).next().map(|b| b.text()).unwrap_or_default();
+        return Err(if DENIED_RGX.is_match(&text) { FetchErr::PermissionDenied } else { FetchErr::NotFound });
+    };
+    let mut info = UserInfo { nick: String::new(), color: None, role: None, registered: None, last_seen: None, notes: None, other: vec![] };
+    for row in table.find(Name("tr")) {
+        let (Some(label), Some(value)) = (row.find(Name("th")).next(), row.find(Name("td")).next()) else {
+            continue;
+        };
+        let label = label.text().trim().trim_end_matches(':').trim().to_owned();
+        let text = value.text().trim().to_owned();
+        match label.to_lowercase().as_str() {
+            "nickname" | "nick" => {
+                info.color = value.descendants().find_map(|n| color(&n)).and_then(|c| ChatColor::parse(&c));
+                info.nick = text;
+            }
+            "status" | "role" | "rank" if Role::parse(&text).is_some() => info.role = Role::parse(&text),
+            "registered" | "member since" if parse_time(settings.server_offset, &text).is_some() => info.registered = parse_time(settings.server_offset, &text),
+            "last seen" if parse_time(settings.server_offset, &text).is_some() => info.last_seen = parse_time(settings.server_offset, &text),
+            "notes" | "staff notes" => info.notes = Some(text),
+            _ => info.other.push((label, text)),
+        }
+    }
+    if info.nick.is_empty() {
+        return Err(FetchErr::NotFound);
+    }
+    Ok(info)
+}
+
+/// `nick`'s profile, `FetchErr::NotFound` when there is no such nick and
+/// `FetchErr::PermissionDenied` when the session can't see profiles.
+pub fn fetch_user_info(transport: &Transport, base_url: &str, page_php: &str, session: &str, nick: &str) -> Result<UserInfo, FetchErr> {
+    exchange::block_on(fetch_user_info_with(transport, base_url, page_php, session, nick))
+}
+
+/// `fetch_user_info` over any `Exchange`.
+pub async fn fetch_user_info_with<E: Exchange>(
+    http: &E,
+    base_url: &str,
+    page_php: &str,
+    session: &str,
+    nick: &str,
+) -> Result<UserInfo, FetchErr> {
+    parse_user_info(http.settings(), &fetch_page(http, &user_info_url(base_url, page_php, session, http.lang(), nick)).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lechatphp::mock::{MockExchange, MockResponse};
+
+    fn summary(html: &str) -> Vec<(String, Role, Option<u32>)> {
+        let users = parse_users(&Settings::default(), &Document::from(html)).unwrap();
+        users.into_iter().map(|u| (u.nick, u.role, u.idle_minutes)).collect()
+    }
+
+    #[test]
+    fn parse_users_test() {
+        use Role::*;
+        let expected = |idle: [Option<u32>; 4]| {
+            vec![
+                ("boss".to_owned(), Admin, idle[0]),
+                ("mod one".to_owned(), Staff, idle[1]),
+                ("dark knight".to_owned(), Member, idle[2]),
+                ("night owl".to_owned(), Guest, idle[3]),
+            ]
+        };
+        let mut grouped = expected([None; 4]);
+        grouped.insert(2, ("mod two".to_owned(), Staff, None));
+        grouped.push(("alice".to_owned(), Guest, None));
+        assert_eq!(summary(include_str!("fixtures/users_grouped.html")), grouped);
+        assert_eq!(summary(include_str!("fixtures/users_flat.html")), expected([None, Some(3), None, Some(12)]));
+
+        let users = parse_users(&Settings::default(), &Document::from(include_str!("fixtures/view_utf8.html"))).unwrap();
+        let color = ChatColor::parse("#FF0000");
+        let jurgen = User { nick: "Jürgen".to_owned(), color, role: Member, idle_minutes: None, display_nick: "Jürgen".to_owned(), display_color: color };
+        assert_eq!(users[0], jurgen);
+        assert!(matches!(parse_users(&Settings::default(), &Document::from("<html></html>")), Err(FetchErr::NoUserList)));
+    }
+
+    #[test]
+    fn serde_test() {
+        let users = parse_users(&Settings::default(), &Document::from(include_str!("fixtures/users_grouped.html"))).unwrap();
+        let json = serde_json::to_string(&users).unwrap();
+        assert_eq!(serde_json::from_str::<Vec<User>>(&json).unwrap(), users);
+        assert!(json.contains(r##""nick":"mod one","color":"#"##) && json.contains(r#""role":"staff""#), "{}", json);
+        let info = parse_user_info(&Settings::default(), &Document::from(include_str!("fixtures/userinfo_member.html"))).unwrap();
+        assert_eq!(serde_json::from_str::<UserInfo>(&serde_json::to_string(&info).unwrap()).unwrap(), info);
+    }
+
+    #[test]
+    fn fetch_online_users_test() {
+        const BASE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+        let fetch = |http: &MockExchange| exchange::block_on(fetch_online_users_with(http, BASE_URL, "chat.php", "abc"));
+
+        let http = MockExchange::new(|_| Ok(MockResponse::ok(include_str!("fixtures/users_grouped.html"))));
+        assert_eq!(fetch(&http).unwrap().len(), 6);
+        // A stale session gets the login form, not an empty list
+        let http = MockExchange::new(|_| {
+            Ok(MockResponse::ok(r#"<form><input name="nick"><input name="pass" type="password"></form>"#))
+        });
+        assert!(matches!(fetch(&http), Err(FetchErr::SessionExpired)));
+    }
+    #[test]
+    fn parse_user_info_test() {
+        let at = |s| DateTime::parse_from_rfc3339(s).ok();
+        let member = parse_user_info(&Settings::default(), &Document::from(include_str!("fixtures/userinfo_member.html"))).unwrap();
+        let expected = UserInfo {
+            nick: "dark knight".to_owned(),
+            color: ChatColor::parse("#00FF00"),
+            role: Some(Role::Member),
+            registered: at("2025-03-14T18:22:05+00:00"),
+            last_seen: at("2026-10-17T19:40:02+00:00"),
+            notes: None,
+            other: vec![("Font".to_owned(), "Courier, bold".to_owned())],
+        };
+        assert_eq!(member, expected);
+
+        // The same profile, as staff see it
+        let staff = parse_user_info(&Settings::default(), &Document::from(include_str!("fixtures/userinfo_staff.html"))).unwrap();
+        assert_eq!(staff.notes.as_deref(), Some("Warned for flooding\ntwice, see the staff notes."));
+        let other: Vec<_> = staff.other.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        assert_eq!(other, [("Registered by", "boss"), ("Last login", "2026-10-17 18:02:44"), ("Font", "Courier, bold"), ("Kicks", "2")]);
+        assert_eq!(UserInfo { notes: None, other: expected.other.clone(), ..staff }, expected);
+    }
+
+    #[test]
+    fn fetch_user_info_test() {
+        const BASE_URL: &str = "http://duckduckgogg42xjoc72x3sjasowoarfbgcmvfimaftt6twagswzczad.onion";
+        let fetch = |http: &MockExchange, nick| exchange::block_on(fetch_user_info_with(http, BASE_URL, "chat.php", "abc", nick));
+
+        let http = MockExchange::new(|_| Ok(MockResponse::ok(include_str!("fixtures/userinfo_member.html"))));
+        assert_eq!(fetch(&http, "dark knight").unwrap().nick, "dark knight");
+        fetch(&http, "a&b=c/ü+").unwrap();
+        let paths: Vec<_> = http.requests.borrow().iter().map(|r| r.path.clone()).collect();
+        assert_eq!(
+            paths,
+            [
+                "/chat.php?action=userinfo&name=dark%20knight&session=abc&lang=en",
+                "/chat.php?action=userinfo&name=a%26b%3Dc%2F%C3%BC%2B&session=abc&lang=en",
+            ]
+        );
+
+        let notice = |text: &'static str| MockExchange::new(move |_| Ok(MockResponse::ok(&format!("<html><body><h2>{}</h2></body></html>", text))));
+        assert!(matches!(fetch(&notice("No such user: ghost"), "ghost"), Err(FetchErr::NotFound)));
+        assert!(matches!(fetch(&notice("Access denied, staff only"), "dark knight"), Err(FetchErr::PermissionDenied)));
+    }
+}
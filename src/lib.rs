@@ -0,0 +1,20 @@
+//! The le-chat-php protocol, as the `DantcaBot` TUI speaks it: building a
+//! client for the chat's onion, logging in and out, solving the captcha,
+//! fetching, posting and moderating. See `examples/simple_bot.rs`.
+//!
+//! Sessions, messages, users and the settings they go with are `Serialize`
+//! and `Deserialize`, to be kept or handed to another process. Their field
+//! and variant names are part of the API and don't change between minor
+//! versions; unknown fields are ignored, so newer data reads with older
+//! code. Enums that will grow, `MessageKind` and the errors, are
+//! `#[non_exhaustive]`; `lechatphp::error_kind::Classify` sorts them all
+//! into retry, log in again or give up.
+//!
+//! Cargo features:
+//! - `solver`, on by default: the captcha solver, `lechatphp::captcha`, and
+//!   the image crates it needs. Without it every captcha is asked of
+//!   `Transport::set_prompt`'s prompt. The TUI needs it.
+//! - `async`: `lechatphp::exchange::AsyncTransport`, the protocol over tokio.
+//! - `arti`: an embedded Tor client, `lechatphp::arti`.
+
+pub mod lechatphp;
@@ -1,13 +1,32 @@
 mod bhc;
-mod lechatphp;
 mod util;
-use crate::lechatphp::LoginErr;
+use bhcli::lechatphp;
+use lechatphp::capture::CaptureConfig;
+use lechatphp::catalog::Catalog;
+use lechatphp::client::{ClientConfig, Pool, Protocol, ProxySetting, SocksAuth};
+use lechatphp::color::ChatColor;
+use lechatphp::display::DisplayRule;
+use lechatphp::settings::Settings;
+use lechatphp::share::ShareTarget;
+use lechatphp::emoji::EmojiConfig;
+use lechatphp::mention::MentionConfig;
+use lechatphp::http_log::HttpLog;
+use lechatphp::metrics::Operation;
+use lechatphp::mirrors::Mirrors;
+use lechatphp::moderation::CleanTarget;
+use lechatphp::post::{MultiLine, PostErr, ReplayGuard};
+use lechatphp::rate_limit::RateLimit;
+use lechatphp::retry::RetryPolicy;
+use lechatphp::onion_auth::{ClientAuthKey, FailureWatch};
+use lechatphp::tls::TlsPin;
+use lechatphp::tor::{TorAuth, TorControlConfig};
+use lechatphp::transport::Transport;
+use lechatphp::LoginErr;
 use anyhow::{anyhow, Context};
-use chrono::{ Datelike, NaiveDateTime, Utc};
+use chrono::{ Datelike, FixedOffset, NaiveDateTime, Utc};
 use clap::Parser;
 use clipboard::ClipboardContext;
 use clipboard::ClipboardProvider;
-use colors_transform::{Color, Rgb};
 use crossbeam_channel::{self, after, select};
 use crossterm::event;
 use crossterm::event::Event as CEvent;
@@ -26,9 +45,6 @@ use log4rs::encode::pattern::PatternEncoder;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use regex::Regex;
-use reqwest::blocking::multipart;
-use reqwest::blocking::Client;
-use reqwest::redirect::Policy;
 use rodio::{source::Source, Decoder, OutputStream};
 use select::document::Document;
 use select::predicate::{Attr, Name};
@@ -36,7 +52,8 @@ use serde_derive::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::io::Cursor;
 use std::io::{self, Write};
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
 use std::sync::Mutex;
 use std::sync::{Arc, MutexGuard};
 use std::thread;
@@ -55,7 +72,8 @@ use tui::{
 use unicode_width::UnicodeWidthStr;
 use util::StatefulList;
 
-const LANG: &str = "en";
+const DEFAULT_URL: &str = "http://blkhatjxlrvc5aevqzz5t6kxldayog6jlx5h7glnu44euzongl4fh5ad.onion/index.php";
+const DEFAULT_PAGE_PHP: &str = "chat.php";
 const SEND_TO_ALL: &str = "s *";
 const SEND_TO_MEMBERS: &str = "s ?";
 static mut BOT_ACTIVE: bool = false;
@@ -99,7 +117,6 @@ lazy_static! {
     static ref PREVIOUS_MEMBERS: Mutex<Option<Vec<String>>> = Mutex::new(None);
     
     // static mut INBOX_CONTENT: Option<String> = None;
-    static ref SESSION_RGX: Regex = Regex::new(r#"session=([^&]+)"#).unwrap();
     static ref COLOR_RGX: Regex = Regex::new(r#"color:\s*([#\w]+)\s*;"#).unwrap();
     static ref COLOR1_RGX: Regex = Regex::new(r#"^#([0-9A-Fa-f]{6})$"#).unwrap();
     static ref PM_RGX: Regex = Regex::new(r#"^/pm ([^\s]+) (.*)"#).unwrap();
@@ -109,7 +126,9 @@ lazy_static! {
     static ref IGNORE_RGX: Regex = Regex::new(r#"^/ignore ([^\s]+)"#).unwrap();
     static ref UNIGNORE_RGX: Regex = Regex::new(r#"^/unignore ([^\s]+)"#).unwrap();
     static ref DLX_RGX: Regex = Regex::new(r#"^/dl([\d]+)$"#).unwrap();
+    static ref PURGE_RGX: Regex = Regex::new(r#"^/purge (.+?)( --yes)?$"#).unwrap();
     static ref UPLOAD_RGX: Regex = Regex::new(r#"^/u\s([^\s]+)\s?(?:@([^\s]+)\s)?(.*)$"#).unwrap();
+    static ref SHARE_RGX: Regex = Regex::new(r#"^/share\s([^\s]+)\s?(.*)$"#).unwrap();
     static ref FIND_RGX: Regex = Regex::new(r#"^/f\s(.*)$"#).unwrap();
     static ref NEW_NICKNAME_RGX: Regex = Regex::new(r#"^/nick\s(.*)$"#).unwrap();
     static ref NEW_COLOR_RGX: Regex = Regex::new(r#"^/color\s(.*)$"#).unwrap();
@@ -134,6 +153,49 @@ struct Profile {
     members_tag: String,
     #[serde(default = "default_empty_str")]
     keepalive_send_to: String,
+    #[serde(default)]
+    mirrors: Vec<String>,
+    /// Same format as --tls-pin.
+    #[serde(default)]
+    tls_pins: Vec<String>,
+    /// Same format as --onion-auth.
+    #[serde(default)]
+    onion_auth: Vec<String>,
+    /// Same as --proxy-chain, e.g. `["http://proxy.corp:3128", "socks5h://127.0.0.1:9050"]`.
+    #[serde(default)]
+    proxy_chain: Vec<String>,
+    /// Protocol options for some of the urls, e.g.
+    /// `[profiles.default.mirror_protocols."http://x.onion"]` `http1_only = true`
+    #[serde(default)]
+    mirror_protocols: HashMap<String, Protocol>,
+    /// Same format as --server-utc-offset.
+    #[serde(default)]
+    server_utc_offset: Option<String>,
+    /// Same as --mention-alias.
+    #[serde(default)]
+    mention_aliases: Vec<String>,
+    /// Same as --multi-line-separator.
+    #[serde(default)]
+    multi_line_separator: Option<String>,
+    /// More `:shortcodes:` for posts, or other values for built-in ones,
+    /// e.g. `lenny = "( ͡° ͜ʖ ͡°)"`.
+    #[serde(default)]
+    emoji_shortcodes: HashMap<String, String>,
+    /// Aliases and colors to show nicks with, e.g.
+    /// `[[profiles.default.display_overrides]]` `nick = "^anon\\d+$"`
+    /// `regex = true` `alias = "anon"` `color = "gray"`
+    #[serde(default)]
+    display_overrides: Vec<DisplayRule>,
+    /// The image host for `/share`, e.g. `[profiles.default.share_target]`
+    /// `url = "http://host.onion/upload"` `json_pointer = "/data/url"`
+    #[serde(default)]
+    share_target: Option<ShareTarget>,
+    /// Same as --lang.
+    #[serde(default)]
+    lang: Option<String>,
+    /// Same as --catalog.
+    #[serde(default)]
+    catalog: Option<std::path::PathBuf>,
 }
 
 #[derive(Default, Debug, Serialize, Deserialize)]
@@ -153,14 +215,139 @@ struct Opts {
     password: Option<String>,
     #[arg(short, long, env = "BHC_MANUAL_CAPTCHA")]
     manual_captcha: bool,
+    #[arg(long, env = "BHC_CAPTCHA_INK_MARGIN")]
+    captcha_ink_margin: Option<f32>,
+    #[arg(long, env = "BHC_CAPTCHA_STATS")]
+    captcha_stats: bool,
     #[arg(short, long, env = "BHC_GUEST_COLOR")]
     guest_color: Option<String>,
     #[arg(short, long, env = "BHC_REFRESH_RATE", default_value = "5")]
     refresh_rate: u64,
     #[arg(long, env = "BHC_MAX_LOGIN_RETRY", default_value = "100")]
     max_login_retry: isize,
+    #[arg(long, env = "BHC_RETRY_ATTEMPTS", default_value = "3")]
+    retry_attempts: u32,
+    /// Can be repeated, one is picked per session.
+    #[arg(long = "user-agent")]
+    user_agents: Vec<String>,
+    /// Log requests to bhcli.log, credentials are redacted.
+    #[arg(long)]
+    http_log: bool,
+    /// Like --http-log, with request and response bodies.
+    #[arg(long)]
+    http_log_bodies: bool,
+    /// Seconds to connect, including building the tor circuit.
+    #[arg(long, env = "BHC_CONNECT_TIMEOUT", default_value = "30")]
+    connect_timeout: u64,
+    /// Seconds to wait for a response once connected.
+    #[arg(long, env = "BHC_READ_TIMEOUT", default_value = "60")]
+    read_timeout: u64,
+    /// Overall seconds for a request, retries included. 0 disables it.
+    #[arg(long, env = "BHC_DEADLINE", default_value = "180")]
+    deadline: u64,
+    /// Requests per second for fetches, 0 disables rate limiting.
+    #[arg(long, env = "BHC_READ_RATE", default_value = "2")]
+    read_rate: f64,
+    /// Requests per second for posts and logins.
+    #[arg(long, env = "BHC_WRITE_RATE", default_value = "0.5")]
+    write_rate: f64,
+    /// Probe the chat and its mirrors, print their health and exit.
+    #[arg(long)]
+    check_server: bool,
     #[arg(long)]
     url: Option<String>,
+    #[arg(long = "mirror")]
+    mirrors: Vec<String>,
+    /// Don't health check the mirrors at startup to try the fastest one first.
+    #[arg(long, env = "BHC_NO_MIRROR_PROBE")]
+    no_mirror_probe: bool,
+    /// Seconds the startup mirror probe waits for answers.
+    #[arg(long, env = "BHC_MIRROR_PROBE_TIMEOUT", default_value = "10")]
+    mirror_probe_timeout: u64,
+    /// Pin a https url to a key, `<url>=sha256/<base64>`, or to a certificate
+    /// which is then trusted even if self-signed, `<url>=<pem file>`. Can be repeated.
+    #[arg(long = "tls-pin")]
+    tls_pins: Vec<TlsPin>,
+    /// Client authorization key of a restricted onion, a tor `.auth_private`
+    /// line `<onion>:descriptor:x25519:<key>` or the path of such a file.
+    /// Handed to tor through the control port, or to the embedded tor. Can be repeated.
+    #[arg(long = "onion-auth")]
+    onion_auth: Vec<String>,
+    /// Tor's ClientOnionAuthDir, where --onion-auth keys are written when
+    /// there is no control port to hand them over.
+    #[arg(long, env = "BHC_ONION_AUTH_DIR")]
+    onion_auth_dir: Option<std::path::PathBuf>,
+    /// Allow clearnet urls. Defaults to true only when one is configured.
+    #[arg(long, env = "BHC_ALLOW_CLEARNET")]
+    allow_clearnet: Option<bool>,
+    /// Never negotiate HTTP/2, for front-ends that reset the connection when offered h2.
+    #[arg(long, env = "BHC_HTTP1_ONLY")]
+    http1_only: bool,
+    #[arg(long, env = "BHC_NO_TCP_NODELAY")]
+    no_tcp_nodelay: bool,
+    /// Open a new connection for every request.
+    #[arg(long, env = "BHC_NO_CONNECTION_REUSE")]
+    no_connection_reuse: bool,
+    /// Seconds an idle connection is kept for reuse, 0 keeps it until the server closes it.
+    #[arg(long, env = "BHC_POOL_IDLE_TIMEOUT", default_value = "120")]
+    pool_idle_timeout: u64,
+    #[arg(long, env = "BHC_POOL_MAX_IDLE", default_value = "4")]
+    pool_max_idle: usize,
+    /// Seconds between TCP keep-alive probes, 0 disables them.
+    #[arg(long, env = "BHC_TCP_KEEPALIVE", default_value = "60")]
+    tcp_keepalive: u64,
+    /// Don't record request counts and latencies (shown by /stats).
+    #[arg(long, env = "BHC_NO_METRICS")]
+    no_metrics: bool,
+    /// Largest response read, in KB. Anything bigger is refused.
+    #[arg(long, env = "BHC_MAX_BODY_KB", default_value = "4096")]
+    max_body_kb: usize,
+    /// Largest file sent with /u, in KB. The server may take less.
+    #[arg(long, env = "BHC_MAX_UPLOAD_KB", default_value = "1024")]
+    max_upload_kb: u64,
+    /// Longest message posted at once, in characters. Longer ones are sent
+    /// in numbered parts, the post form's own limit applies too.
+    #[arg(long, env = "BHC_MAX_MESSAGE_LEN", default_value = "2000")]
+    max_message_len: usize,
+    /// Most parts a long message is split into, longer ones aren't sent.
+    #[arg(long, env = "BHC_MAX_MESSAGE_PARTS", default_value = "10")]
+    max_message_parts: usize,
+    /// The offset the server prints message times in, e.g. `+02:00`.
+    #[arg(long, env = "BHC_SERVER_UTC_OFFSET", value_parser = lechatphp::timestamp::parse_offset)]
+    server_utc_offset: Option<FixedOffset>,
+    /// Other names a message addressing you may use, can be repeated.
+    #[arg(long = "mention-alias", env = "BHC_MENTION_ALIASES", value_delimiter = ',')]
+    mention_aliases: Vec<String>,
+    /// Post a message with line breaks as one, each break written as this,
+    /// e.g. ` ⏎ `. bhcli with the same separator shows the breaks again.
+    /// Without, each line is posted on its own.
+    #[arg(long, env = "BHC_MULTI_LINE_SEPARATOR")]
+    multi_line_separator: Option<String>,
+    /// The language of the chat's pages, `en` by default.
+    #[arg(long, env = "BHC_LANG")]
+    lang: Option<String>,
+    /// A TOML file of `key = "text"` replacing the prompts and notices
+    /// shown here, keys left out stay English.
+    #[arg(long, env = "BHC_CATALOG")]
+    catalog: Option<std::path::PathBuf>,
+    /// Post `:shrug:` and other shortcodes as they are.
+    #[arg(long, env = "BHC_NO_EMOJI_SHORTCODES")]
+    no_emoji_shortcodes: bool,
+    /// Show the emoji of messages as `:shortcodes:`, for terminals without
+    /// emoji fonts.
+    #[arg(long, env = "BHC_EMOJI_AS_SHORTCODES")]
+    emoji_as_shortcodes: bool,
+    /// Record every request and response, with passwords, captchas and
+    /// sessions redacted, in a timestamped directory under this one.
+    #[arg(long, env = "BHC_CAPTURE_DIR")]
+    capture_dir: Option<std::path::PathBuf>,
+    /// Stop recording once this many MB are captured.
+    #[arg(long, env = "BHC_CAPTURE_MAX_MB", default_value = "50")]
+    capture_max_mb: u64,
+    /// Seconds back a timed out message is looked for in the chat before
+    /// sending it again, 0 always sends it again.
+    #[arg(long, env = "BHC_REPLAY_WINDOW", default_value = "10")]
+    replay_window: u64,
     #[arg(long)]
     page_php: Option<String>,
     #[arg(long)]
@@ -168,15 +355,31 @@ struct Opts {
     #[arg(long)]
     members_tag: Option<String>,
 
-    #[arg(
-        short,
-        long,
-        env = "BHC_PROXY_URL",
-        default_value = "socks5h://127.0.0.1:9050"
-    )]
-    socks_proxy_url: String,
+    /// Detected when not given: ALL_PROXY, then tor on 9050, then Tor Browser on 9150.
+    #[arg(short, long, env = "BHC_PROXY_URL")]
+    socks_proxy_url: Option<String>,
+    /// Upstream proxy for clearnet urls, then Tor's SOCKS proxy for everything else.
+    /// Repeat in that order, this replaces --socks-proxy-url.
+    #[arg(long = "proxy-chain")]
+    proxy_chain: Vec<String>,
+    /// Refuse socks5:// proxy urls instead of upgrading them to socks5h://.
+    #[arg(long, env = "BHC_STRICT_PROXY")]
+    strict_proxy: bool,
     #[arg(long)]
     no_proxy: bool,
+    #[arg(long, env = "BHC_NO_STREAM_ISOLATION")]
+    no_stream_isolation: bool,
+    #[cfg(feature = "arti")]
+    #[arg(long, env = "BHC_ARTI")]
+    arti: bool,
+    #[arg(long, env = "BHC_TOR_CONTROL_ADDR")]
+    tor_control_addr: Option<String>,
+    #[arg(long, env = "BHC_TOR_CONTROL_PASSWORD")]
+    tor_control_password: Option<String>,
+    #[arg(long, env = "BHC_TOR_CONTROL_COOKIE")]
+    tor_control_cookie: Option<std::path::PathBuf>,
+    #[arg(long, env = "BHC_NEWNYM_RETRIES", default_value = "3")]
+    newnym_retries: usize,
     #[arg(long, env = "DNMX_USERNAME")]
     dnmx_username: Option<String>,
     #[arg(long, env = "DNMX_PASSWORD")]
@@ -226,12 +429,16 @@ struct KickedUser {
 struct LeChatPHPClient {
     base_client: BaseClient,
     guest_color: String,
-    client: Client,
+    client: Transport,
     session: Option<String>,
     config: LeChatPHPConfig,
     last_key_event: Option<KeyCode>,
     refresh_rate: u64,
     max_login_retry: isize,
+    manual_captcha: bool,
+    tor_control: Option<TorControlConfig>,
+    mirrors: Mirrors,
+    onion_auth: FailureWatch,
 
     is_muted: Arc<Mutex<bool>>,
     show_sys: bool,
@@ -245,6 +452,10 @@ struct LeChatPHPClient {
     color_rx: Arc<Mutex<crossbeam_channel::Receiver<()>>>,
 }
 
+// A user-facing string, from the client's catalog
+fn tr(client: &Transport, key: &str, args: &[(&str, &dyn std::fmt::Display)]) -> String {
+    client.settings().catalog.get(key, args)
+}
 
 impl LeChatPHPClient {
     fn run_forever(&mut self) {
@@ -256,33 +467,95 @@ impl LeChatPHPClient {
                     LoginErr::KickedErr
                     | LoginErr::RegErr
                     | LoginErr::NicknameErr
-                    | LoginErr::UnknownErr => {
+                    | LoginErr::UnknownErr
+                    | LoginErr::NoChatFrame(_)
+                    | LoginErr::SessionNotFound { .. } => {
+                        log::error!("{}", e);
+                        println!("{}", tr(&self.client, "error-login", &[("error", &e)]));
+                        break;
+                    }
+                    LoginErr::InvalidUrl(_) => {
+                        log::error!("{}", e);
+                        println!("{}", tr(&self.client, "error-config", &[("error", &e)]));
+                        break;
+                    }
+                    LoginErr::PinMismatch(..) => {
                         log::error!("{}", e);
-                        println!("Login error: {}", e); // Print error message
+                        println!("\n!!! {} !!!\n", e);
                         break;
                     }
                     LoginErr::CaptchaWgErr | LoginErr::CaptchaUsedErr => {}
-                    LoginErr::ServerDownErr | LoginErr::ServerDown500Err => {
+                    LoginErr::ConnectTimeout(..) => {
                         log::error!("{}", e);
-                        println!("Server is down: {}", e); // Print error message
+                        println!("{}", tr(&self.client, "error-timeout", &[("error", &e)]));
+                        if let Some(hint) = self.onion_auth.failed() {
+                            println!("{}", hint);
+                        }
+                    }
+                    LoginErr::ReadTimeout(..) => {
+                        log::error!("{}", e);
+                        println!("{}", tr(&self.client, "error-timeout", &[("error", &e)]));
                     }
-                    LoginErr::Reqwest(err) => {
+                    LoginErr::InterstitialBlocked { .. } => {
+                        log::error!("{}", e);
+                        println!("{}", tr(&self.client, "error-blocked", &[("error", &e)]));
+                    }
+                    LoginErr::CaptchaPrompt(_) => {
+                        log::error!("{}", e);
+                        println!("{}", tr(&self.client, "error-captcha", &[("error", &e)]));
+                        break;
+                    }
+                    LoginErr::ResponseTooLarge { .. }
+                    | LoginErr::MalformedResponse { .. }
+                    | LoginErr::Body(..)
+                    | LoginErr::TruncatedResponse(_) => {
+                        log::error!("{}", e);
+                        println!("{}", tr(&self.client, "error-response", &[("error", &e)]));
+                    }
+                    LoginErr::ServerDownErr(_) | LoginErr::ServerDown500Err(_) => {
+                        log::error!("{}", e);
+                        println!("{}", tr(&self.client, "error-server-down", &[("error", &e)]));
+                    }
+                    LoginErr::ProxyDown(..) => {
+                        log::error!("{}", e);
+                        println!("{}", tr(&self.client, "proxy-waiting", &[("error", &e)]));
+                        // Trying mirrors or counting attempts is pointless until it does
+                        while !self.client.proxy_up() {
+                            thread::sleep(Duration::from_secs(5));
+                        }
+                        println!("{}", tr(&self.client, "proxy-back", &[]));
+                        continue;
+                    }
+                    LoginErr::CircuitFailed(..) => {
+                        log::error!("{}", e);
+                        println!("{}", tr(&self.client, "error-connection", &[("error", &e)]));
+                        if let Some(hint) = self.onion_auth.failed() {
+                            println!("{}", hint);
+                        }
+                    }
+                    LoginErr::Reqwest(_, err) => {
                         if err.is_connect() {
                             log::error!("{}\nIs tor proxy enabled ?", err);
-                            println!("Connection error: {}\nIs tor proxy enabled ?", err); // Print error message
+                            println!("{}", tr(&self.client, "error-no-proxy", &[("error", &err)]));
                             break;
                         } else if err.is_timeout() {
                             log::error!("timeout: {}", err);
-                            println!("Timeout error: {}", err); // Print error message
+                            println!("{}", tr(&self.client, "error-timeout", &[("error", &err)]));
                         } else {
                             log::error!("{}", err);
-                            println!("Reqwest error: {}", err); // Print error message
+                            println!("{}", tr(&self.client, "error-request", &[("error", &err)]));
                         }
                     }
+                    _ => {
+                        log::error!("{}", e);
+                        println!("{}", tr(&self.client, "error-login", &[("error", &e)]));
+                        break;
+                    }
                 },
 
                 Ok(()) => {
                     attempt = 0;
+                    self.onion_auth.reset();
                     match self.get_msgs() {
                         Ok(ExitSignal::NeedLogin) => {}
                         Ok(ExitSignal::Terminate) => return,
@@ -296,10 +569,12 @@ impl LeChatPHPClient {
             }
             self.session = None;
             let retry_in = Duration::from_secs(2);
-            let mut msg = format!("retry login in {:?}, attempt: {}", retry_in, attempt);
-            if max_retry > 0 {
-                msg += &format!("/{}", max_retry);
-            }
+            let wait = format!("{:?}", retry_in);
+            let msg = if max_retry > 0 {
+                tr(&self.client, "login-retry-max", &[("wait", &wait), ("attempt", &attempt), ("max", &max_retry)])
+            } else {
+                tr(&self.client, "login-retry", &[("wait", &wait), ("attempt", &attempt)])
+            };
             println!("{}", msg);
             thread::sleep(retry_in);
         }
@@ -413,18 +688,90 @@ impl LeChatPHPClient {
         let full_url = format!("{}/{}", &self.config.url, &self.config.page_php);
         let session = self.session.clone().unwrap();
         let url = format!("{}?action=post&session={}", &full_url, &session);
+        let username = self.base_client.username.clone();
+        let tx = self.tx.clone();
+        let base_url = self.config.url.clone();
+        let page_php = self.config.page_php.clone();
         thread::spawn(move || {
             loop {
                 let clb = |v: Result<PostType, crossbeam_channel::RecvError>| match v {
+                    Ok(PostType::DeleteLast) => {
+                        if let Err(e) = lechatphp::post::delete_last_message(&client, &base_url, &page_php, &session) {
+                            log::error!("failed to delete last message: {}", e);
+                        }
+                    }
+                    Ok(PostType::DeleteAll) => {
+                        if let Err(e) = lechatphp::post::delete_all_my_messages(&client, &base_url, &page_php, &session) {
+                            log::error!("failed to delete messages: {}", e);
+                        }
+                    }
+                    Ok(PostType::Upload(file_path, send_to, msg)) => {
+                        let path = std::path::Path::new(&file_path);
+                        let res = lechatphp::post::post_with_upload_to(&client, &base_url, &page_php, &session, &send_to, &msg, path);
+                        if let Err(e) = res {
+                            log::error!("failed to upload {}: {}", file_path, e);
+                        }
+                    }
+                    Ok(PostType::Share(file_path, caption)) => {
+                        let path = std::path::Path::new(&file_path);
+                        match lechatphp::share::share_file(&client, &base_url, &page_php, &session, path, Some(&caption)) {
+                            Ok(url) => log::info!("shared {} as {}", file_path, url),
+                            Err(e) => log::error!("failed to share {}: {}", file_path, e),
+                        }
+                    }
+                    Ok(PostType::Ignore(nick)) => {
+                        if let Err(e) = lechatphp::profile::ignore(&client, &base_url, &page_php, &session, &nick) {
+                            log::error!("failed to ignore {}: {}", nick, e);
+                        }
+                    }
+                    Ok(PostType::Unignore(nick)) => {
+                        if let Err(e) = lechatphp::profile::unignore(&client, &base_url, &page_php, &session, &nick) {
+                            log::error!("failed to unignore {}: {}", nick, e);
+                        }
+                    }
+                    Ok(PostType::Purge(target)) => {
+                        // `--yes` was the confirmation
+                        if let Err(e) = lechatphp::moderation::clean_messages(&client, &base_url, &page_php, &session, target, true) {
+                            log::error!("failed to clean messages: {}", e);
+                        }
+                    }
+                    Ok(PostType::Post(msg, send_to))
+                        if msg.chars().count() > client.settings().max_message_len || msg.trim_end().contains('\n') =>
+                    {
+                        let (max_len, max_parts) = (client.settings().max_message_len, client.settings().max_message_parts);
+                        match lechatphp::post::message_parts(&msg, &client.settings().multi_line, max_len, max_parts) {
+                            // In order, a part that fails stops the rest
+                            Ok(parts) => {
+                                for part in parts {
+                                    let post_type = PostType::Post(part, send_to.clone());
+                                    if let Err(e) = post_msg(&client, post_type, &full_url, session.clone(), &url, &username, &last_post_tx) {
+                                        log::error!("failed to post the rest of a long message: {}", e);
+                                        break;
+                                    }
+                                }
+                            }
+                            Err(e) => log::error!("not sent: {}", e),
+                        }
+                    }
                     Ok(post_type_recv) => {
-                        let _ = post_msg(
+                        let res = post_msg(
                             &client,
-                            post_type_recv,
+                            post_type_recv.clone(),
                             &full_url,
                             session.clone(),
                             &url,
+                            &username,
                             &last_post_tx,
                         );
+                        // Too long to wait here, put it back in the queue later
+                        if let Err(PostErr::Flood { wait }) = res {
+                            let delay = wait.unwrap_or(lechatphp::post::MAX_FLOOD_WAIT);
+                            let tx = tx.clone();
+                            thread::spawn(move || {
+                                thread::sleep(delay);
+                                let _ = tx.send(post_type_recv);
+                            });
+                        }
                     },
                     Err(_) => return,
                 };
@@ -543,7 +890,7 @@ impl LeChatPHPClient {
             // process()
             // Draw UI
             terminal.draw(|f| {
-                draw_terminal_frame(f, &mut app, &messages, &users, &self.base_client.username);
+                draw_terminal_frame(f, &mut app, &messages, &users, &self.base_client.username, self.client.settings());
             })?;
 
             // Handle input
@@ -594,14 +941,20 @@ impl LeChatPHPClient {
         }
         // println!("self.session is not Some");
         // println!("self.sxiv = {:?}", self.sxiv);
-        self.session = Some(lechatphp::login(
-            &self.client,
-            &self.config.url,
-            &self.config.page_php,
-            &self.base_client.username,
-            &self.base_client.password,
-            &self.guest_color,
-        )?);
+        let session = lechatphp::tor::with_circuit_rotation(&self.client, self.tor_control.as_ref(), || {
+            lechatphp::mirrors::login_with_mirrors(
+                &self.client,
+                &self.mirrors,
+                &self.config.page_php,
+                &self.base_client.username,
+                &self.base_client.password,
+                &self.guest_color,
+                self.manual_captcha,
+            )
+        })?;
+        // Stick to the mirror that worked
+        self.config.url = session.base_url;
+        self.session = Some(session.id);
         Ok(())
     }
 
@@ -1423,6 +1776,22 @@ fn handle_remove_name(&mut self, _app: &mut App) {
             }
         } else if input == "/dall" {
             self.post_msg(PostType::DeleteAll).unwrap();
+        } else if let Some(captures) = PURGE_RGX.captures(&input) {
+            let target = match &captures[1] {
+                "all" => CleanTarget::All,
+                t => match t.strip_prefix("room ") {
+                    Some(room) => CleanTarget::Room(room.to_owned()),
+                    None => CleanTarget::Nick(t.to_owned()),
+                },
+            };
+            // Deletes for everyone, nothing to undo it
+            if captures.get(2).is_some() {
+                self.post_msg(PostType::Purge(target)).unwrap();
+            } else {
+                log::error!("{} deletes messages for everyone, add --yes to confirm", input);
+            }
+        } else if input == "/stats" {
+            log::error!("transport metrics:\n{}", self.client.settings().metrics.snapshot());
         } else if input == "/cycles" {
             self.color_tx.send(()).unwrap();
         } else if input == "/cycle1" {
@@ -1490,6 +1859,8 @@ fn handle_remove_name(&mut self, _app: &mut App) {
                 None => "".to_owned(),
             };
             self.post_msg(PostType::Upload(file_path, send_to, msg)).unwrap();
+        } else if let Some(captures) = SHARE_RGX.captures(&input) {
+            self.post_msg(PostType::Share(captures[1].to_owned(), captures[2].to_owned())).unwrap();
         } else if input.starts_with("/clean ") {
             let username = remove_prefix(&input, "/clean ").to_owned();
             self.post_msg(PostType::HapusPesan(username.clone())).unwrap();
@@ -1560,7 +1931,7 @@ fn handle_remove_name(&mut self, _app: &mut App) {
                 // Check if command requires username autocomplete
                 let is_username_command = parts.len() == 1 && matches!(
                     parts[0],
-                    "/kick" | "/k" | "/pm" | "/clean" | "/ignore" | "/unignore" | "/logout"
+                    "/kick" | "/k" | "/pm" | "/clean" | "/ignore" | "/unignore" | "/logout" | "/purge"
                 );
                 
                 if is_username_command {
@@ -1739,13 +2110,13 @@ fn autocomplete_username(users: &Arc<Mutex<Users>>, prefix: &str) -> Option<Stri
 }
 
 fn set_profile_base_info(
-    client: &Client,
+    client: &Transport,
     full_url: &str,
     params: &mut Vec<(&str, String)>,
 ) -> anyhow::Result<()> {
     params.extend(vec![("action", "profile".to_owned())]);
-    let profile_resp = client.post(full_url).form(&params).send()?;
-    let profile_resp_txt = profile_resp.text().unwrap();
+    let profile_resp = client.send(client.post(full_url).form(&params))?;
+    let profile_resp_txt = client.text(profile_resp).unwrap();
     let doc = Document::from(profile_resp_txt.as_str());
     let bold = doc.find(Attr("id", "bold")).next().unwrap();
     let italic = doc.find(Attr("id", "italic")).next().unwrap();
@@ -1791,17 +2162,21 @@ where
     }
 }
 fn post_msg(
-    client: &Client,
+    client: &Transport,
     post_type_recv: PostType,
     full_url: &str, 
     session: String,
     url: &str,
+    username: &str,
     last_post_tx: &crossbeam_channel::Sender<()>,
-) {
+) -> Result<(), PostErr> {
     let mut should_reset_keepalive_timer = false;
+    let mut flood_retried = false;
+    let mut flood = None;
+    let view_url = format!("{}?action=view&session={}&lang={}", full_url, session, client.lang());
     retry_fn(|| -> anyhow::Result<RetryErr> {
         let post_type = post_type_recv.clone();
-        let resp_text = client.get(url).send()?.text()?;
+        let resp_text = client.text(client.send_as(Operation::Post, client.get(url))?)?;
         let doc = Document::from(resp_text.as_str());
         let nc = doc
             .find(Attr("name", "nc"))
@@ -1817,7 +2192,7 @@ fn post_msg(
             .context("failed to get postid value")?
             .to_owned();
         let mut params: Vec<(&str, String)> = vec![
-            ("lang", LANG.to_owned()),
+            ("lang", client.lang().to_owned()),
             ("nc", nc_value.to_owned()),
             ("session", session.clone()),
         ];
@@ -1830,15 +2205,13 @@ fn post_msg(
             return Ok(RetryErr::Exit);
         }
 
-        let mut req = client.post(full_url);
-        let mut form: Option<multipart::Form> = None;
 
         match post_type {
             PostType::Unban(username) => {
                 params.extend(vec![
                     ("action", "admin".to_owned()),
                     ("session", session.clone()),
-                    ("lang", LANG.to_owned()),
+                    ("lang", client.lang().to_owned()),
                     ("nc", nc_value.to_owned()),
                     ("do", "sessions".to_owned()),
                     ("logout", "1".to_owned()),
@@ -1853,7 +2226,7 @@ fn post_msg(
                     ("do", "guestaccess".to_owned()),
                     ("guestaccess", mode.to_owned()),
                     ("session", session.clone()),
-                    ("lang", LANG.to_owned()),
+                    ("lang", client.lang().to_owned()),
                     ("nc", nc_value.to_owned()),
                 ]);
             }
@@ -1862,14 +2235,12 @@ fn post_msg(
                     ("action", "inbox".to_owned()),
                     ("do", "clean".to_owned()),
                     ("session", session.clone()),
-                    ("lang", LANG.to_owned()),
+                    ("lang", client.lang().to_owned()),
                     ("nc", nc_value.to_owned()),
                 ]);
                 
-                let resp = client.get(full_url)
-                    .query(&params)
-                    .send()?;
-                let inbox_content = resp.text()?;
+                let resp = client.send(client.get(full_url).query(&params))?;
+                let inbox_content = client.text(resp)?;
                 let doc = Document::from(inbox_content.as_str());
                 
                 for checkbox in doc.find(Attr("name", "mid[]")) {
@@ -1878,9 +2249,7 @@ fn post_msg(
                     }
                 }
                 
-                let resp = client.post(full_url)
-                    .form(&params)
-                    .send()?;
+                let resp = client.send(client.post(full_url).form(&params))?;
                 
                 if resp.status().is_success() {
                     log::info!("Semua pesan di inbox berhasil dihapus");
@@ -1898,13 +2267,11 @@ fn post_msg(
                 params.extend(vec![
                     ("action", "logout".to_owned()),
                     ("session", session.clone()),
-                    ("lang", LANG.to_owned()),
+                    ("lang", client.lang().to_owned()),
                     ("nc", nc_value.to_owned()),
                 ]);
 
-                let resp = client.post(full_url)
-                    .form(&params)
-                    .send()?;
+                let resp = client.send(client.post(full_url).form(&params))?;
 
                 if resp.status().is_success() {
                     log::info!("Berhasil keluar");
@@ -1918,15 +2285,13 @@ fn post_msg(
                 params.extend(vec![
                     ("action", "inbox".to_owned()),
                     ("session", session.clone()),
-                    ("lang", LANG.to_owned()),
+                    ("lang", client.lang().to_owned()),
                     ("nc", nc_value.to_owned()),
                 ]);
                 
-                let resp = client.post(full_url)
-                    .form(&params)
-                    .send()?;
+                let resp = client.send(client.post(full_url).form(&params))?;
                 
-                let inbox_content = resp.text()?;
+                let inbox_content = client.text(resp)?;
                 
                 if let Some(messages) = extract_inbox_message(&inbox_content) {
                     unsafe {
@@ -1943,7 +2308,7 @@ fn post_msg(
                 params.extend(vec![
                     ("action", "admin".to_owned()),
                     ("session", session.clone()),
-                    ("lang", LANG.to_owned()),
+                    ("lang", client.lang().to_owned()),
                     ("nc", nc_value.to_owned()),
                     ("do", "clean".to_owned()),
                     ("what", "nick".to_owned()),
@@ -1954,7 +2319,7 @@ fn post_msg(
                 params.extend(vec![
                     ("action", "admin".to_owned()),
                     ("session", session.clone()),
-                    ("lang", LANG.to_owned()),
+                    ("lang", client.lang().to_owned()),
                     ("nc", nc_value.to_owned()),
                     ("do", "logout".to_owned()),
                     ("name[]", username),
@@ -1973,7 +2338,8 @@ fn post_msg(
                 } else {
                     msg
                 };
-                
+                let message = client.settings().emoji.expand(&message);
+
                 params.extend(vec![
                     ("action", "post".to_owned()),
                     ("postid", postid_value.to_owned()),
@@ -1998,22 +2364,6 @@ fn post_msg(
                     ("colour", new_color),
                 ]);
             }
-            PostType::Ignore(username) => {
-                set_profile_base_info(client, full_url, &mut params)?;
-                params.extend(vec![
-                    ("do", "save".to_owned()),
-                    ("timestamps", "on".to_owned()),
-                    ("ignore", username),
-                ]);
-            }
-            PostType::Unignore(username) => {
-                set_profile_base_info(client, full_url, &mut params)?;
-                params.extend(vec![
-                    ("do", "save".to_owned()),
-                    ("timestamps", "on".to_owned()),
-                    ("unignore", username),
-                ]);
-            }
             PostType::Profile(new_color, new_nickname) => {
                 set_profile_base_info(client, full_url, &mut params)?;
                 params.extend(vec![
@@ -2035,18 +2385,16 @@ fn post_msg(
             }
             PostType::DanUa => {
                 params.extend(vec![
-                    ("lang", "en".to_owned()),
+                    ("lang", client.lang().to_owned()),
                     ("nc", nc_value.to_owned()),
                     ("action", "admin".to_owned()),
                     ("session", session.to_owned()),
                     ("do", "sessions".to_owned()),
                 ]);
 
-                let resp = client.post(full_url)
-                    .form(&params)
-                    .send()?;
+                let resp = client.send(client.post(full_url).form(&params))?;
 
-                let content = resp.text()?;
+                let content = client.text(resp)?;
                 let doc = Document::from(content.as_str());
 
                 let mut user_agents = HashMap::new();
@@ -2114,9 +2462,7 @@ fn post_msg(
                         ("what", "purge".to_owned()),
                     ]);
 
-                    let _ = client.post(full_url)
-                        .form(&kick_params)
-                        .send();
+                    let _ = client.send(client.post(full_url).form(&kick_params));
                 }
 
                 // Format output with blocked users and their agents
@@ -2130,52 +2476,50 @@ fn post_msg(
 
                 return Ok(RetryErr::Exit);
             }
-            PostType::DeleteLast | PostType::DeleteAll => {
-                params.extend(vec![("action", "delete".to_owned())]);
-                if let PostType::DeleteAll = post_type {
-                    params.extend(vec![
-                        ("sendto", SEND_TO_ALL.to_owned()),
-                        ("confirm", "yes".to_owned()),
-                        ("what", "all".to_owned()),
-                    ]);
-                } else {
-                    params.extend(vec![("sendto", "".to_owned()), ("what", "last".to_owned())]);
-                }
-            }
-            PostType::Upload(file_path, send_to, msg) => {
-                form = Some(
-                    match multipart::Form::new()
-                        .text("lang", LANG.to_owned())
-                        .text("nc", nc_value.to_owned())
-                        .text("session", session.clone())
-                        .text("action", "post".to_owned())
-                        .text("postid", postid_value.to_owned())
-                        .text("message", msg)
-                        .text("sendto", send_to.to_owned())
-                        .text("what", "purge".to_owned())
-                        .file("file", file_path)
-                    {
-                        Ok(f) => f,
-                        Err(e) => {
-                            log::error!("{:?}", e);
-                            return Ok(RetryErr::Exit);
-                        }
-                    },
-                );
-            }
+            // Sent with the post module's helpers, see start_post_msg_thread
+            PostType::DeleteLast
+            | PostType::DeleteAll
+            | PostType::Purge(_)
+            | PostType::Upload(..)
+            | PostType::Share(..)
+            | PostType::Ignore(_)
+            | PostType::Unignore(_) => return Ok(RetryErr::Exit),
             PostType::Clean(_, _) => {}
         }
 
-        if let Some(form_content) = form {
-            req = req.multipart(form_content);
-        } else {
-            req = req.form(&params);
-        }
-
-        if let Err(err) = req.send() {
-            log::error!("{:?}", err.to_string());
-            if err.is_timeout() {
-                return Ok(RetryErr::Retry);
+        let PostType::Post(msg, _) = &post_type_recv else {
+            if let Err(err) = client.send_as(Operation::Post, client.post(full_url).form(&params)) {
+                log::error!("{:?}", err.to_string());
+                return Ok(if err.is_timeout() { RetryErr::Retry } else { RetryErr::Exit });
+            }
+            return Ok(RetryErr::Exit);
+        };
+        // Sent again after a timeout, unless the server stored it anyway
+        let resp_text = match lechatphp::post::send_post(client, full_url, &view_url, msg, &params) {
+            Ok(Some(page)) => page.body?,
+            Ok(None) => return Ok(RetryErr::Exit),
+            Err(err) => {
+                log::error!("{:?}", err.to_string());
+                return Ok(RetryErr::Exit);
+            }
+        };
+        if let Err(err) = lechatphp::post::check_post_response(&Document::from(resp_text.as_str())) {
+            log::error!("{}", err);
+            match lechatphp::post::flood_retry_delay(&err) {
+                Some(delay) if !flood_retried => {
+                    flood_retried = true;
+                    thread::sleep(delay);
+                    // Don't post twice if the first one went through after all
+                    let view_text = client.text(client.send(client.get(&view_url))?)?;
+                    if lechatphp::post::message_delivered(&Document::from(view_text.as_str()), username, msg) {
+                        return Ok(RetryErr::Exit);
+                    }
+                    return Ok(RetryErr::Retry);
+                }
+                _ => {
+                    flood = Some(err);
+                    return Ok(RetryErr::Exit);
+                }
             }
         }
         Ok(RetryErr::Exit)
@@ -2184,6 +2528,10 @@ fn post_msg(
     if should_reset_keepalive_timer {
         last_post_tx.send(()).unwrap();
     }
+    match flood {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
 }
 
 fn parse_date(date: &str, datetime_fmt: &str) -> Option<NaiveDateTime> {
@@ -2235,7 +2583,7 @@ fn translate_id_to_en(text: &str) -> anyhow::Result<String> {
 }
 
 fn get_msgs(
-    client: &Client,
+    client: &Transport,
     base_url: &str,
     page_php: &str,
     session: &str,
@@ -2251,14 +2599,14 @@ fn get_msgs(
 ) -> anyhow::Result<()> {
     let url = format!(
         "{}/{}?action=view&session={}&lang={}",
-        base_url, page_php, session, LANG
+        base_url, page_php, session, client.lang()
     );
     // Menyimpan base_url ke variabel statis
 
-    let resp_text = client.get(url).send()?.text()?;
+    let resp_text = client.text(client.send_as(Operation::Fetch, client.get(url))?)?;
     let resp_text = resp_text.replace("<br>", "\n");
     let doc = Document::from(resp_text.as_str());
-    let new_messages = match extract_messages(&doc) {
+    let new_messages = match extract_messages(client.settings(), &doc) {
         Ok(messages) => messages,
         Err(_) => {
             // Gagal mendapatkan pesan, mungkin perlu login ulang
@@ -3404,7 +3752,7 @@ fn update_messages(
 }
 
 fn delete_message(
-    client: &Client,
+    client: &Transport,
     full_url: &str,
     params: &mut Vec<(&str, String)>,
     date: String,
@@ -3415,14 +3763,14 @@ fn delete_message(
         ("do", "clean".to_owned()),
         ("what", "choose".to_owned()),
     ]);
-    let clean_resp_txt = client.post(full_url).form(&params).send()?.text()?;
+    let clean_resp_txt = client.text(client.send(client.post(full_url).form(&params))?)?;
     let doc = Document::from(clean_resp_txt.as_str());
     let nc = doc
         .find(Attr("name", "nc"))
         .next()
         .context("nc not found")?;
     let nc_value = nc.attr("value").context("nc value not found")?.to_owned();
-    let msgs = extract_messages(&doc)?;
+    let msgs = extract_messages(client.settings(), &doc)?;
     if let Some(msg) = msgs
         .iter()
         .find(|m| m.date == date && m.text.text() == text)
@@ -3433,7 +3781,7 @@ fn delete_message(
             ("what", "selected".to_owned()),
             ("mid[]", format!("{}", msg_id)),
         ]);
-        client.post(full_url).form(&params).send()?;
+        client.send(client.post(full_url).form(&params))?;
     }
     Ok(())
 }
@@ -3442,14 +3790,22 @@ impl ChatClient {
     fn new(params: Params) -> Self {
         // println!("session[2026] : {:?}",params.session);
         let mut c = new_default_le_chat_php_client(params.clone());
-        c.config.url = params.url.unwrap_or(
-            "http://blkhatjxlrvc5aevqzz5t6kxldayog6jlx5h7glnu44euzongl4fh5ad.onion/index.php"
-                .to_owned(),
-        );
-        c.config.page_php = params.page_php.unwrap_or("chat.php".to_owned());
+        c.config.url = params.url.unwrap_or(DEFAULT_URL.to_owned());
+        c.config.page_php = params.page_php.unwrap_or(DEFAULT_PAGE_PHP.to_owned());
         c.config.datetime_fmt = params.datetime_fmt.unwrap_or("%m-%d %H:%M:%S".to_owned());
         c.config.members_tag = params.members_tag.unwrap_or("[M] ".to_owned());
         c.config.keepalive_send_to = params.keepalive_send_to.unwrap_or("0".to_owned());
+        let mut urls = vec![c.config.url.clone()];
+        urls.extend(params.mirrors);
+        c.mirrors = Mirrors::new(urls.clone());
+        c.onion_auth = FailureWatch::new(&params.onion_auth, &urls);
+        // Not worth a few requests with a single url, or a session to resume
+        if let Some(deadline) = params.mirror_probe.filter(|_| urls.len() > 1 && c.session.is_none()) {
+            println!("Probing {} mirrors...", urls.len());
+            for probe in c.mirrors.probe(&c.client, &c.config.page_php, deadline) {
+                println!("  {}", probe);
+            }
+        }
         // c.session = params.session;
         Self {
             le_chat_php_client: c,
@@ -3472,6 +3828,10 @@ fn new_default_le_chat_php_client(params: Params) -> LeChatPHPClient {
             password: params.password,
         },
         max_login_retry: params.max_login_retry,
+        manual_captcha: params.manual_captcha,
+        tor_control: params.tor_control,
+        mirrors: Mirrors::new(vec![]),
+        onion_auth: FailureWatch::default(),
         guest_color: params.guest_color,
         // session: params.session,
         session,
@@ -3504,9 +3864,15 @@ struct Params {
     username: String,
     password: String,
     guest_color: String,
-    client: Client,
+    client: Transport,
     refresh_rate: u64,
     max_login_retry: isize,
+    manual_captcha: bool,
+    tor_control: Option<TorControlConfig>,
+    mirrors: Vec<String>,
+    onion_auth: Vec<ClientAuthKey>,
+    /// Deadline of the startup mirror probe, `None` skips it.
+    mirror_probe: Option<Duration>,
     keepalive_send_to: Option<String>,
     session: Option<String>,
 }
@@ -3541,6 +3907,37 @@ impl Sig {
     }
 }
 
+// Show the captcha 4 times bigger in sxiv, read the answer on stdin
+fn sxiv_prompt(catalog: &Catalog, captcha: &lechatphp::captcha_prompt::CaptchaImage) -> io::Result<String> {
+    let img = captcha.decode().map_err(io::Error::other)?;
+    let img_buf = image::imageops::resize(&img, img.width() * 4, img.height() * 4, image::imageops::FilterType::Nearest);
+    // Save captcha as file on disk, in the temp dir when the current one is read-only
+    let save = |path: &Path| img_buf.save(path).map_err(io::Error::other);
+    let mut path = PathBuf::from("captcha.gif");
+    if let Err(e) = lechatphp::storage::write("captcha image", &path, save) {
+        path = std::env::temp_dir().join("bhcli-captcha.gif");
+        log::warn!("{}, using {}", e, path.display());
+        lechatphp::storage::write("captcha image", &path, save).map_err(io::Error::other)?;
+    }
+
+    let mut sxiv_process = Command::new("sxiv")
+        .arg(&path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    // Prompt the user to enter the CAPTCHA
+    print!("{}", catalog.get("captcha-prompt", &[]));
+    let mut captcha_input = String::new();
+    let read = io::stdout().flush().and_then(|_| io::stdin().read_line(&mut captcha_input));
+    trim_newline(&mut captcha_input);
+
+    // Close the sxiv window, already gone if the user closed it
+    let _ = sxiv_process.kill();
+    read?;
+    Ok(captcha_input)
+}
+
 fn trim_newline(s: &mut String) {
     if s.ends_with('\n') {
         s.pop();
@@ -3587,17 +3984,79 @@ fn get_guest_color(wanted: Option<String>) -> String {
     .to_owned()
 }
 
-fn get_tor_client(socks_proxy_url: &str, no_proxy: bool) -> Client {
-    let ua = "im ghost no one know, who am i?? the ghost";
-    let mut builder = reqwest::blocking::ClientBuilder::new()
-        .redirect(Policy::none())
-        .cookie_store(true)
-        .user_agent(ua);
-    if !no_proxy {
-        let proxy = reqwest::Proxy::all(socks_proxy_url).unwrap();
-        builder = builder.proxy(proxy);
-    }
-    builder.build().unwrap()
+#[allow(clippy::too_many_arguments)]
+fn get_tor_client(
+    opts: &Opts,
+    socks_auth: Option<SocksAuth>,
+    http_log: HttpLog,
+    rate_limit: Option<RateLimit>,
+    mirror_protocols: HashMap<String, Protocol>,
+    emoji_shortcodes: HashMap<String, String>,
+    display: Vec<DisplayRule>,
+    share: Option<ShareTarget>,
+) -> anyhow::Result<Transport> {
+    let mut config = ClientConfig::builder()
+        .proxy(match &opts.socks_proxy_url {
+            _ if opts.no_proxy => ProxySetting::Direct,
+            _ if !opts.proxy_chain.is_empty() => ProxySetting::Chain(opts.proxy_chain.clone()),
+            Some(url) => ProxySetting::Url(url.to_owned()),
+            None => ProxySetting::Auto,
+        })
+        .strict_proxy(opts.strict_proxy)
+        .target_url(Some(opts.url.clone().unwrap_or(DEFAULT_URL.to_owned())))
+        .connect_timeout(Duration::from_secs(opts.connect_timeout))
+        .read_timeout(Duration::from_secs(opts.read_timeout))
+        .deadline((opts.deadline > 0).then(|| Duration::from_secs(opts.deadline)))
+        .socks_auth(socks_auth)
+        .retry(RetryPolicy { max_attempts: opts.retry_attempts, ..Default::default() })
+        .http_log(http_log)
+        .rate_limit(rate_limit)
+        .tls_pins(opts.tls_pins.clone())
+        .allow_clearnet(opts.allow_clearnet)
+        .protocol(Protocol {
+            http1_only: opts.http1_only,
+            tcp_nodelay: !opts.no_tcp_nodelay,
+            reuse_connections: !opts.no_connection_reuse,
+        })
+        .pool(Pool {
+            idle_timeout: (opts.pool_idle_timeout > 0).then(|| Duration::from_secs(opts.pool_idle_timeout)),
+            max_idle_per_host: opts.pool_max_idle,
+            tcp_keepalive: (opts.tcp_keepalive > 0).then(|| Duration::from_secs(opts.tcp_keepalive)),
+        })
+        .max_body_size(opts.max_body_kb.saturating_mul(1024))
+        .max_upload_size(opts.max_upload_kb.saturating_mul(1024))
+        .max_message_len(opts.max_message_len)
+        .max_message_parts(opts.max_message_parts)
+        .multi_line(opts.multi_line_separator.clone().map_or(MultiLine::Separate, MultiLine::Separator))
+        .replay_guard(ReplayGuard {
+            window: (opts.replay_window > 0).then(|| Duration::from_secs(opts.replay_window)),
+            datetime_fmt: opts.datetime_fmt.clone().unwrap_or_else(|| ReplayGuard::default().datetime_fmt),
+        })
+        .emoji(EmojiConfig { expand: !opts.no_emoji_shortcodes, shortcodes: emoji_shortcodes, shorten: opts.emoji_as_shortcodes })
+        .capture(
+            opts.capture_dir
+                .clone()
+                .map(|dir| CaptureConfig { dir, max_bytes: opts.capture_max_mb.saturating_mul(1024 * 1024) }),
+        )
+        .metrics(!opts.no_metrics)
+        .mirrors(opts.mirrors.clone())
+        .mirror_protocols(mirror_protocols)
+        .display(display)
+        .share(share)
+        // The captcha cache and templates next to the binary, as always
+        .data_dir(Some(PathBuf::from(".")))
+        .catalog(opts.catalog.clone())
+        .mention(MentionConfig { aliases: opts.mention_aliases.clone(), ..Default::default() });
+    if let Some(offset) = opts.server_utc_offset {
+        config = config.server_utc_offset(offset);
+    }
+    if let Some(lang) = &opts.lang {
+        config = config.lang(lang.as_str());
+    }
+    if !opts.user_agents.is_empty() {
+        config = config.user_agents(opts.user_agents.clone());
+    }
+    Ok(lechatphp::client::build(&config.build()?)?)
 }
 fn ask_username(username: Option<String>) -> String {
     username.unwrap_or_else(|| {
@@ -3631,10 +4090,10 @@ pub struct DkfNotifierResp {
 
 
 // Start thread that looks for new emails on DNMX every minutes.
-fn start_dnmx_mail_notifier(client: &Client, username: &str, password: &str) {
+fn start_dnmx_mail_notifier(client: &Transport, username: &str, password: &str) {
     let params: Vec<(&str, &str)> = vec![("login_username", username), ("secretkey", password)];
     let login_url = format!("{}/src/redirect.php", DNMX_URL);
-    client.post(login_url).form(&params).send().unwrap();
+    client.send(client.post(login_url).form(&params)).unwrap();
 
     let client_clone = client.clone();
     thread::spawn(move || loop {
@@ -3642,9 +4101,9 @@ fn start_dnmx_mail_notifier(client: &Client, username: &str, password: &str) {
         let source = Decoder::new_mp3(Cursor::new(SOUND1)).unwrap();
 
         let right_url = format!("{}/src/right_main.php", DNMX_URL);
-        if let Ok(resp) = client_clone.get(right_url).send() {
+        if let Ok(resp) = client_clone.send(client_clone.get(right_url)) {
             let mut nb_mails = 0;
-            let doc = Document::from(resp.text().unwrap().as_str());
+            let doc = Document::from(client_clone.text(resp).unwrap().as_str());
             if let Some(table) = doc.find(Name("table")).nth(7) {
                 table.find(Name("tr")).skip(1).for_each(|n| {
                     if let Some(td) = n.find(Name("td")).nth(2) {
@@ -3702,12 +4161,47 @@ fn main() -> anyhow::Result<()> {
     if let Ok(config_path) = confy::get_configuration_file_path("bhcli", None) {
         println!("Config path: {:?}", config_path);
     }
+    let mut mirror_protocols = HashMap::new();
+    let mut emoji_shortcodes = HashMap::new();
+    let mut display_overrides = vec![];
+    let mut share_target = None;
     if let Ok(cfg) = confy::load::<MyConfig>("bhcli", None) {
         if let Some(default_profile) = cfg.profiles.get(&opts.profile) {
             if opts.username.is_none() {
                 opts.username = Some(default_profile.username.clone());
                 opts.password = Some(default_profile.password.clone());
             }
+            if opts.mirrors.is_empty() {
+                opts.mirrors = default_profile.mirrors.clone();
+            }
+            mirror_protocols = default_profile.mirror_protocols.clone();
+            emoji_shortcodes = default_profile.emoji_shortcodes.clone();
+            display_overrides = default_profile.display_overrides.clone();
+            share_target = default_profile.share_target.clone();
+            if opts.proxy_chain.is_empty() {
+                opts.proxy_chain = default_profile.proxy_chain.clone();
+            }
+            if opts.tls_pins.is_empty() {
+                opts.tls_pins = default_profile.tls_pins.iter().map(|p| p.parse()).collect::<Result<_, _>>()?;
+            }
+            if opts.onion_auth.is_empty() {
+                opts.onion_auth = default_profile.onion_auth.clone();
+            }
+            if let (None, Some(offset)) = (opts.server_utc_offset, &default_profile.server_utc_offset) {
+                opts.server_utc_offset = Some(lechatphp::timestamp::parse_offset(offset).map_err(anyhow::Error::msg)?);
+            }
+            if opts.mention_aliases.is_empty() {
+                opts.mention_aliases = default_profile.mention_aliases.clone();
+            }
+            if opts.multi_line_separator.is_none() {
+                opts.multi_line_separator = default_profile.multi_line_separator.clone();
+            }
+            if opts.lang.is_none() {
+                opts.lang = default_profile.lang.clone();
+            }
+            if opts.catalog.is_none() {
+                opts.catalog = default_profile.catalog.clone();
+            }
         }
     }
 
@@ -3715,8 +4209,21 @@ fn main() -> anyhow::Result<()> {
         .encoder(Box::new(PatternEncoder::new("{d} {l} {t} - {m}{n}")))
         .build("bhcli.log")?;
 
+    let http_log = if opts.http_log_bodies {
+        HttpLog::Bodies
+    } else if opts.http_log {
+        HttpLog::Requests
+    } else {
+        HttpLog::Off
+    };
+    let http_log_level = match http_log {
+        HttpLog::Off => LevelFilter::Error,
+        HttpLog::Requests => LevelFilter::Debug,
+        HttpLog::Bodies => LevelFilter::Trace,
+    };
     let config = log4rs::config::Config::builder()
         .appender(log4rs::config::Appender::builder().build("logfile", Box::new(logfile)))
+        .logger(log4rs::config::Logger::builder().build(lechatphp::http_log::TARGET, http_log_level))
         .build(
             log4rs::config::Root::builder()
                 .appender("logfile")
@@ -3725,7 +4232,103 @@ fn main() -> anyhow::Result<()> {
 
     log4rs::init_config(config)?;
 
-    let client = get_tor_client(&opts.socks_proxy_url, opts.no_proxy);
+    let mut solver = lechatphp::captcha::SolverConfig::builder().persist_stats(opts.captcha_stats);
+    if let Some(margin) = opts.captcha_ink_margin {
+        solver = solver.ink_margin(margin);
+    }
+    lechatphp::captcha::configure(&solver.build()?);
+
+    let onion_auth = opts.onion_auth.iter().map(|k| lechatphp::onion_auth::load(k)).collect::<Result<Vec<_>, _>>()?;
+
+    // Embedded tor replaces the external proxy, it must outlive the client
+    #[cfg(feature = "arti")]
+    let embedded_tor = opts.arti;
+    #[cfg(not(feature = "arti"))]
+    let embedded_tor = false;
+    #[cfg(feature = "arti")]
+    let _embedded_tor = if opts.arti {
+        let tor = lechatphp::arti::start(|pct| println!("Bootstrapping Tor {}%", pct), &onion_auth)?;
+        opts.socks_proxy_url = Some(tor.socks_url().to_owned());
+        Some(tor)
+    } else {
+        None
+    };
+
+    // Catch typos in onion addresses before waiting on connection timeouts,
+    // clearnet urls are checked when the client is built
+    for url in opts.url.iter().chain(opts.mirrors.iter()) {
+        lechatphp::onion::validate_base_url(url)?;
+    }
+
+    let rate_limit = if opts.read_rate > 0.0 && opts.write_rate > 0.0 {
+        let mut limit = RateLimit::default();
+        limit.reads.rate = opts.read_rate;
+        limit.writes.rate = opts.write_rate;
+        Some(limit)
+    } else {
+        None
+    };
+
+    // Each profile gets its own tor circuits
+    let socks_auth = if opts.no_stream_isolation {
+        None
+    } else {
+        let salt_file = confy::get_configuration_file_path("bhcli", None)?.with_file_name("isolation_salt");
+        Some(SocksAuth::for_profile(&opts.profile, &salt_file)?)
+    };
+    let client = get_tor_client(&opts, socks_auth, http_log, rate_limit, mirror_protocols, emoji_shortcodes, display_overrides, share_target)?;
+    let catalog = client.settings().catalog.clone();
+    client.set_prompt(move |captcha| sxiv_prompt(&catalog, captcha));
+    let catalog = client.settings().catalog.clone();
+    client.set_progress(move |progress| println!("{}", progress.localized(&catalog)));
+
+    // Optional tor control port, used to rotate circuits when the server looks down
+    let tor_control = opts.tor_control_addr.map(|addr| TorControlConfig {
+        addr,
+        auth: match (opts.tor_control_password, opts.tor_control_cookie) {
+            (Some(password), _) => TorAuth::Password(password),
+            (None, Some(cookie)) => TorAuth::CookieFile(cookie),
+            (None, None) => TorAuth::None,
+        },
+        max_retries: opts.newnym_retries,
+    });
+
+    // Restricted onions, the embedded tor already has the keys
+    if let Some(tor_control) = tor_control.as_ref().filter(|_| !embedded_tor) {
+        for key in &onion_auth {
+            lechatphp::tor::add_client_auth(tor_control, key)?;
+        }
+    } else if let Some(dir) = opts.onion_auth_dir.as_ref().filter(|_| !embedded_tor) {
+        let mut written = false;
+        for key in &onion_auth {
+            written |= lechatphp::onion_auth::write_auth_private(dir, key)?;
+        }
+        if written {
+            println!("Client auth keys written to {}, reload tor (SIGHUP) for them to take effect", dir.display());
+        }
+    } else if !onion_auth.is_empty() && !embedded_tor {
+        println!("--onion-auth needs --tor-control-addr or --onion-auth-dir, make sure the keys are in tor's ClientOnionAuthDir");
+    }
+
+    if opts.check_server {
+        let url = opts.url.clone().unwrap_or(DEFAULT_URL.to_owned());
+        let page_php = opts.page_php.clone().unwrap_or(DEFAULT_PAGE_PHP.to_owned());
+        let mut all_up = true;
+        for url in std::iter::once(&url).chain(opts.mirrors.iter()) {
+            let health = lechatphp::check_server(&client, url, &page_php);
+            let status = health.status.map_or("-".to_owned(), |s| s.as_u16().to_string());
+            let state = if !health.reachable {
+                "down"
+            } else if health.waitroom_active {
+                "waitroom"
+            } else {
+                "up"
+            };
+            println!("{} {} {} {}ms", url, state, status, health.latency.as_millis());
+            all_up &= health.reachable;
+        }
+        std::process::exit(if all_up { 0 } else { 1 });
+    }
 
     // If dnmx username is set, start mail notifier thread
     if let Some(dnmx_username) = opts.dnmx_username {
@@ -3748,6 +4351,11 @@ fn main() -> anyhow::Result<()> {
         client: client.clone(),
         refresh_rate: opts.refresh_rate,
         max_login_retry: opts.max_login_retry,
+        manual_captcha: opts.manual_captcha,
+        tor_control,
+        mirrors: opts.mirrors,
+        onion_auth,
+        mirror_probe: (!opts.no_mirror_probe).then(|| Duration::from_secs(opts.mirror_probe_timeout)),
         keepalive_send_to: opts.keepalive_send_to,
         session: opts.session.clone(),
     };
@@ -3768,6 +4376,7 @@ enum PostType {
     Post(String, Option<String>),   // Message, SendTo
     Kick(String, String),           // Message, Username
     Upload(String, String, String), // FileLocation, SendTo, Message
+    Share(String, String),          // FileLocation, Caption
     DeleteLast,                     // DeleteLast
     DeleteAll,                      // DeleteAll
     NewNickname(String),            // NewUsername
@@ -3779,6 +4388,7 @@ enum PostType {
     Keluar,      // Inbox
     Unignore(String),               // Username
     Clean(String, String),          // CleanMessage
+    Purge(CleanTarget),             // Staff clean
 }
 
 // Get username of other user (or ours if it's the only one)
@@ -3947,21 +4557,19 @@ impl StyledText {
 }
 
 fn parse_color(color_str: &str) -> tuiColor {
-    let mut color = tuiColor::White;
     if color_str == "red" {
         return tuiColor::Red;
     }
-    if let Ok(rgb) = Rgb::from_hex_str(color_str) {
-        color = tuiColor::Rgb(
-            rgb.get_red() as u8,
-            rgb.get_green() as u8,
-            rgb.get_blue() as u8,
-        );
+    match ChatColor::parse(color_str) {
+        Some(color) => {
+            let (r, g, b) = color.to_rgb();
+            tuiColor::Rgb(r, g, b)
+        }
+        None => tuiColor::White,
     }
-    color
 }
 
-fn process_node(e: select::node::Node, mut color: tuiColor) -> (StyledText, Option<String>) {
+fn process_node(settings: &Settings, e: select::node::Node, mut color: tuiColor) -> (StyledText, Option<String>) {
     match e.data() {
         select::node::Data::Element(_, _) => {
             let mut upload_link: Option<String> = None;
@@ -3993,7 +4601,7 @@ fn process_node(e: select::node::Node, mut color: tuiColor) -> (StyledText, Opti
             let mut children_texts: Vec<StyledText> = vec![];
             let children = e.children();
             for child in children {
-                let (st, ul) = process_node(child, color);
+                let (st, ul) = process_node(settings, child, color);
                 if ul.is_some() {
                     upload_link = ul;
                 }
@@ -4002,7 +4610,7 @@ fn process_node(e: select::node::Node, mut color: tuiColor) -> (StyledText, Opti
             children_texts.reverse();
             (StyledText::Styled(color, children_texts), upload_link)
         }
-        select::node::Data::Text(t) => (StyledText::Text(t.to_string()), None),
+        select::node::Data::Text(t) => (StyledText::Text(settings.emoji.shorten(&settings.multi_line.restore(t))), None),
         select::node::Data::Comment(_) => (StyledText::None, None),
     }
 }
@@ -4094,7 +4702,7 @@ static mut INBOX_COUNT: usize = 0;
 
 
 
-fn extract_messages(doc: &Document) -> anyhow::Result<Vec<Message>> {
+fn extract_messages(settings: &Settings, doc: &Document) -> anyhow::Result<Vec<Message>> {
     unsafe {
         let (kicked_count, new_username) = count_kicked_users(doc);
         KICKED_COUNT = kicked_count as usize;
@@ -4131,7 +4739,7 @@ fn extract_messages(doc: &Document) -> anyhow::Result<Vec<Message>> {
                 Some("sysmsg") => MessageType::SysMsg,
                 _ => return None,
             };
-            let (text, upload_link) = process_node(msg_span, tuiColor::White);
+            let (text, upload_link) = process_node(settings, msg_span, tuiColor::White);
             let message = Message::new(id, typ, date, upload_link, text);
         
             Some(message)
@@ -4216,6 +4824,7 @@ fn draw_terminal_frame(
     messages: &Arc<Mutex<Vec<Message>>>,
     users: &Arc<Mutex<Users>>,
     username: &str,
+    settings: &Settings,
 ) {
     if app.long_message.is_none() {
         let vchunks = Layout::default()
@@ -4241,7 +4850,7 @@ fn draw_terminal_frame(
                 )
                 .split(hchunks[0]);
 
-            render_help_txt(f, app, chunks[0], username);
+            render_help_txt(f, app, chunks[0], username, settings);
             render_textbox(f, app, chunks[1]);
             render_messages(f, app, chunks[2], messages);
             render_users(f, hchunks[1], users);
@@ -4342,7 +4951,7 @@ fn render_long_message(f: &mut Frame<CrosstermBackend<io::Stdout>>, app: &mut Ap
 
 
 
-fn render_help_txt(f: &mut Frame<CrosstermBackend<io::Stdout>>, app: &mut App, r: Rect, curr_user: &str) {
+fn render_help_txt(f: &mut Frame<CrosstermBackend<io::Stdout>>, app: &mut App, r: Rect, curr_user: &str, settings: &Settings) {
     let (mut msg, style) = match app.input_mode {
         InputMode::Normal => (vec![Span::raw("Press "), Span::styled("shift + q", Style::default().add_modifier(Modifier::BOLD)), Span::raw(" to exit, "), Span::styled("i", Style::default().add_modifier(Modifier::BOLD)), Span::raw(" to start editing.")], Style::default()),
         InputMode::Editing | InputMode::EditingErr => (vec![Span::raw("Press "), Span::styled("Esc", Style::default().add_modifier(Modifier::BOLD)), Span::raw(" to stop editing, "), Span::styled("Enter", Style::default().add_modifier(Modifier::BOLD)), Span::raw(" to record the message")], Style::default()),
@@ -4366,6 +4975,29 @@ fn render_help_txt(f: &mut Frame<CrosstermBackend<io::Stdout>>, app: &mut App, r
     let inbox_text = format!("Inbox: {}", inbox_count);
     let inbox_style = Style::default().fg(tuiColor::Yellow).add_modifier(Modifier::BOLD);
     msg.extend(vec![Span::raw(" | "), Span::styled(inbox_text, inbox_style)]);
+    // Statistik captcha solver
+    let stats = lechatphp::captcha::stats();
+    let captcha_text = format!(
+        "Captcha: hit {:.0}% ok {:.0}% {}ms manual {}",
+        stats.cache_hit_rate() * 100.0,
+        stats.success_rate() * 100.0,
+        stats.avg_latency().as_millis(),
+        stats.manual_fallbacks()
+    );
+    msg.extend(vec![Span::raw(" | "), Span::raw(captcha_text)]);
+    if let Some(fetch) = settings.metrics.snapshot().get(Operation::Fetch).filter(|s| s.requests > 0) {
+        let fetch_text = format!(
+            "Fetch: p50 {}ms err {}",
+            fetch.p50.map_or(0, |d| d.as_millis()),
+            fetch.errors()
+        );
+        msg.extend(vec![Span::raw(" | "), Span::raw(fetch_text)]);
+    }
+    let send_wait = settings.rate_limit.wait_time(lechatphp::rate_limit::Kind::Write);
+    if !send_wait.is_zero() {
+        let rate_text = format!("rate limited, sending in {}s", send_wait.as_secs_f64().ceil());
+        msg.extend(vec![Span::raw(" | "), Span::styled(rate_text, Style::default().fg(tuiColor::Yellow))]);
+    }
 
     let mut text = Text::from(Spans::from(msg));
     text.patch_style(style);
@@ -4811,6 +5443,24 @@ mod tests {
         let lines = gen_lines(&txt, 71, "");
         assert_eq!(lines.len(), 2);
     }
+
+    #[test]
+    fn charset_test() {
+        use lechatphp::charset;
+
+        let pages = [
+            (include_bytes!("lechatphp/fixtures/view_latin1.html").as_slice(), "text/html; charset=ISO-8859-1"),
+            (include_bytes!("lechatphp/fixtures/view_utf8.html").as_slice(), "text/html; charset=UTF-8"),
+        ];
+        for (body, content_type) in pages {
+            let doc = Document::from(charset::decode(body, Some(content_type)).as_str());
+            let members: Vec<_> = extract_users(&doc).members.into_iter().map(|(_, name)| name).collect();
+            assert_eq!(members, ["Jürgen", "Zoë"], "{}", content_type);
+            let messages = extract_messages(&Settings::default(), &doc).unwrap();
+            assert_eq!(messages[0].text.text(), "Jürgen - Grüße aus Köln, ça va?", "{}", content_type);
+            assert_eq!(messages[1].text.text(), "Zoë has joined the chat.", "{}", content_type);
+        }
+    }
 }
 
 